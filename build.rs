@@ -0,0 +1,81 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Lists the versions of the interface definitions in `EDGEHOG_INTERFACES_DIR`, when set, for
+//! `src/interfaces.rs` to fail the build on a missing or outdated interface.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const INTERFACES_DIR: &str = "EDGEHOG_INTERFACES_DIR";
+
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(edgehog_interfaces)");
+    println!("cargo:rerun-if-env-changed={INTERFACES_DIR}");
+
+    let directory = match env::var(INTERFACES_DIR) {
+        Ok(directory) => directory,
+        Err(_) => return,
+    };
+    println!("cargo:rerun-if-changed={directory}");
+    println!("cargo:rustc-cfg=edgehog_interfaces");
+
+    let entries = installed_interfaces(Path::new(&directory));
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("installed_interfaces.rs");
+    fs::write(
+        out,
+        format!("pub const INSTALLED_INTERFACES: &[(&str, (i32, i32))] = &[\n{entries}];\n"),
+    )
+    .unwrap();
+}
+
+/// The name and version of each interface definition in `directory`, as the array entries.
+fn installed_interfaces(directory: &Path) -> String {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)
+        .unwrap_or_else(|err| panic!("unable to read {INTERFACES_DIR} {directory:?}: {err}"))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == "json")
+        })
+        .collect();
+    paths.sort();
+
+    let mut entries = String::new();
+    for path in paths {
+        let content = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("unable to read the interface {path:?}: {err}"));
+        let interface: serde_json::Value = serde_json::from_str(&content)
+            .unwrap_or_else(|err| panic!("malformed interface {path:?}: {err}"));
+
+        let name = interface["interface_name"].as_str();
+        let major = interface["version_major"].as_i64();
+        let minor = interface["version_minor"].as_i64();
+        match (name, major, minor) {
+            (Some(name), Some(major), Some(minor)) => {
+                entries.push_str(&format!("    ({name:?}, ({major}, {minor})),\n"))
+            }
+            _ => panic!("interface {path:?} without its name and version"),
+        }
+    }
+
+    entries
+}
@@ -0,0 +1,871 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Configuration documents of the applications, pushed by the backend.
+//!
+//! A request names one of the configured targets and carries a JSON or TOML document. The
+//! document is checked against the size cap and the schema of the target, written atomically to
+//! the path of the target and the application is told to reload it. The SHA-256 and the time of
+//! the applied document are published as properties, and published again at every start from the
+//! file on disk, so the backend can tell when the file drifted. The replaced document is kept to
+//! roll back to with the `app-config:rollback <target>` command.
+
+pub(crate) mod schema;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use astarte_sdk::types::AstarteType;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+#[cfg(test)]
+use mockall::automock;
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+#[cfg(feature = "dbus")]
+use zbus::dbus_proxy;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::APP_CONFIG_INTERFACE;
+use crate::repository::file_state_repository::{write_atomically, FileStateRepository};
+use crate::repository::StateRepository;
+#[cfg(not(feature = "dbus"))]
+use crate::wrapper::platform::no_system_bus;
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::system_bus;
+
+pub const ROLLBACK_COMMAND: &str = "app-config:rollback";
+/// Largest document accepted, unless configured otherwise.
+pub const DEFAULT_MAX_DOCUMENT_BYTES: usize = 64 * 1024;
+const PREVIOUS_EXTENSION: &str = "previous";
+#[cfg(feature = "dbus")]
+const DBUS_PATH: &str = "/io/edgehog/Device/AppConfig";
+#[cfg(feature = "dbus")]
+const DBUS_INTERFACE: &str = "io.edgehog.Device.AppConfig";
+const SIGHUP: i32 = 1;
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait Systemd1Manager {
+    /// Send `signal` to the processes of the unit `name` selected by `who`.
+    fn kill_unit(&self, name: &str, who: &str, signal: i32) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AppConfigOptions {
+    #[serde(default)]
+    pub targets: Vec<AppConfigTarget>,
+    pub max_document_bytes: Option<usize>,
+}
+
+/// Where the documents of an application go, and how it is told to reload them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigTarget {
+    /// Name used by the requests, letters, digits, `-` and `_` only.
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: DocumentFormat,
+    /// JSON schema the documents are checked against, in both formats.
+    pub schema: Option<PathBuf>,
+    pub reload: Option<Reload>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentFormat {
+    Json,
+    Toml,
+}
+
+impl Default for DocumentFormat {
+    fn default() -> Self {
+        DocumentFormat::Json
+    }
+}
+
+/// How the application is told to reload its configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum Reload {
+    /// SIGHUP to the main process of a systemd unit.
+    Sighup { unit: String },
+    /// `Changed` signal on the system bus, with the target and the SHA-256 of the document.
+    Dbus,
+    /// Command of this configuration, the backend only picks the target.
+    Command { command: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppConfigRequest {
+    Apply { target: String, document: String },
+    Rollback { target: String },
+}
+
+impl AppConfigRequest {
+    /// Parse the `target` and `document` fields of a request object.
+    pub fn from_object(data: &HashMap<String, AstarteType>) -> Option<Self> {
+        match (data.get("target"), data.get("document")) {
+            (Some(AstarteType::String(target)), Some(AstarteType::String(document))) => {
+                Some(AppConfigRequest::Apply {
+                    target: target.clone(),
+                    document: document.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse `app-config:rollback <target>`.
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [ROLLBACK_COMMAND, target] => Some(AppConfigRequest::Rollback {
+                target: target.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Name of the target of the request.
+    pub fn target(&self) -> &str {
+        match self {
+            AppConfigRequest::Apply { target, .. } | AppConfigRequest::Rollback { target } => {
+                target
+            }
+        }
+    }
+}
+
+/// Document applied to a target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedDocument {
+    pub sha256: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+/// Applied document of each target by name.
+pub type AppliedDocuments = BTreeMap<String, AppliedDocument>;
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait Reloader: Send + Sync {
+    /// Send `signal` to the main process of the systemd `unit`.
+    async fn kill_unit(&self, unit: &str, signal: i32) -> Result<(), DeviceManagerError>;
+    /// Emit the `Changed` signal of `target` on the system bus.
+    async fn emit_changed(&self, target: &str, sha256: &str) -> Result<(), DeviceManagerError>;
+    /// Run `command` through the shell.
+    async fn run_command(&self, command: &str) -> Result<(), DeviceManagerError>;
+}
+
+/// Reloads the applications through systemd, the system bus and the shell.
+pub struct SystemReloader;
+
+#[async_trait]
+impl Reloader for SystemReloader {
+    #[cfg(feature = "dbus")]
+    async fn kill_unit(&self, unit: &str, signal: i32) -> Result<(), DeviceManagerError> {
+        let connection = system_bus().await?;
+        Systemd1ManagerProxy::new(&connection)
+            .await?
+            .kill_unit(unit, "main", signal)
+            .await?;
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    async fn kill_unit(&self, _unit: &str, _signal: i32) -> Result<(), DeviceManagerError> {
+        Err(no_system_bus())
+    }
+
+    #[cfg(feature = "dbus")]
+    async fn emit_changed(&self, target: &str, sha256: &str) -> Result<(), DeviceManagerError> {
+        let connection = system_bus().await?;
+        connection
+            .emit_signal(
+                None::<&str>,
+                DBUS_PATH,
+                DBUS_INTERFACE,
+                "Changed",
+                &(target, sha256),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    async fn emit_changed(&self, _target: &str, _sha256: &str) -> Result<(), DeviceManagerError> {
+        Err(no_system_bus())
+    }
+
+    async fn run_command(&self, command: &str) -> Result<(), DeviceManagerError> {
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(DeviceManagerError::CommandFailed(format!(
+                "{command} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+pub struct AppConfig {
+    clock: Arc<dyn Clock>,
+    targets: Vec<AppConfigTarget>,
+    max_document_bytes: usize,
+    /// Where the replaced documents are kept.
+    directory: PathBuf,
+    repository: Box<dyn StateRepository<AppliedDocuments>>,
+    reloader: Box<dyn Reloader>,
+}
+
+impl AppConfig {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        options: &AppConfigOptions,
+        directory: PathBuf,
+        repository: Box<dyn StateRepository<AppliedDocuments>>,
+        reloader: Box<dyn Reloader>,
+    ) -> Result<Self, DeviceManagerError> {
+        std::fs::create_dir_all(&directory)?;
+
+        let targets = options
+            .targets
+            .iter()
+            .filter(|target| {
+                let valid = !target.name.is_empty()
+                    && target
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                if !valid {
+                    warn!(
+                        "Invalid app config target name {:?}, ignoring it",
+                        target.name
+                    );
+                }
+                valid
+            })
+            .cloned()
+            .collect();
+
+        Ok(AppConfig {
+            clock,
+            targets,
+            max_document_bytes: options
+                .max_document_bytes
+                .unwrap_or(DEFAULT_MAX_DOCUMENT_BYTES),
+            directory,
+            repository,
+            reloader,
+        })
+    }
+
+    fn target(&self, name: &str) -> Result<&AppConfigTarget, String> {
+        self.targets
+            .iter()
+            .find(|target| target.name == name)
+            .ok_or_else(|| format!("unknown target {name}"))
+    }
+
+    fn previous_path(&self, target: &AppConfigTarget) -> PathBuf {
+        self.directory
+            .join(&target.name)
+            .with_extension(PREVIOUS_EXTENSION)
+    }
+
+    /// Check `document` against the size cap, the format and the schema of `target`.
+    fn validate(&self, target: &AppConfigTarget, document: &str) -> Result<(), String> {
+        if document.len() > self.max_document_bytes {
+            return Err(format!(
+                "document of {} bytes, above the cap of {}",
+                document.len(),
+                self.max_document_bytes
+            ));
+        }
+
+        let parsed: Value = match target.format {
+            DocumentFormat::Json => serde_json::from_str(document).map_err(|err| err.to_string()),
+            DocumentFormat::Toml => toml::from_str::<toml::Value>(document)
+                .map_err(|err| err.to_string())
+                .and_then(|value| serde_json::to_value(value).map_err(|err| err.to_string())),
+        }
+        .map_err(|err| format!("invalid document: {err}"))?;
+
+        let schema_path = match &target.schema {
+            Some(schema_path) => schema_path,
+            None => return Ok(()),
+        };
+        let schema: Value = std::fs::read(schema_path)
+            .map_err(|err| err.to_string())
+            .and_then(|schema| serde_json::from_slice(&schema).map_err(|err| err.to_string()))
+            .map_err(|err| format!("unable to read the schema: {err}"))?;
+
+        schema::validate(&schema, &parsed).map_err(|err| format!("schema violation at {err}"))
+    }
+
+    /// Validate and write `document`, keeping the replaced one to roll back to.
+    fn apply(&self, target: &AppConfigTarget, document: &str) -> Result<(), String> {
+        self.validate(target, document)?;
+
+        match std::fs::read(&target.path) {
+            Ok(current) => write_atomically(&self.previous_path(target), &current, None),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                remove_if_exists(&self.previous_path(target))
+            }
+            Err(err) => Err(err),
+        }
+        .map_err(|err| format!("unable to keep the current document: {err}"))?;
+
+        write_atomically(&target.path, document.as_bytes(), None)
+            .map_err(|err| format!("unable to write the document: {err}"))
+    }
+
+    /// Restore the document replaced by the last apply.
+    fn rollback(&self, target: &AppConfigTarget) -> Result<(), String> {
+        let previous_path = self.previous_path(target);
+        let previous = match std::fs::read(&previous_path) {
+            Ok(previous) => previous,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err("no previous document to roll back to".to_owned())
+            }
+            Err(err) => return Err(format!("unable to read the previous document: {err}")),
+        };
+
+        write_atomically(&target.path, &previous, None)
+            .map_err(|err| format!("unable to write the document: {err}"))?;
+        if let Err(err) = remove_if_exists(&previous_path) {
+            warn!("Unable to remove {}: {}", previous_path.display(), err);
+        }
+
+        Ok(())
+    }
+
+    async fn reload(&self, target: &AppConfigTarget, sha256: &str) -> Result<(), String> {
+        let result = match &target.reload {
+            Some(Reload::Sighup { unit }) => self.reloader.kill_unit(unit, SIGHUP).await,
+            Some(Reload::Dbus) => self.reloader.emit_changed(&target.name, sha256).await,
+            Some(Reload::Command { command }) => self.reloader.run_command(command).await,
+            None => Ok(()),
+        };
+
+        result.map_err(|err| format!("unable to reload {}: {err}", target.name))
+    }
+
+    /// Serve `request`, publishing the outcome on the properties of its target.
+    pub async fn handle(&self, publisher: &impl Publisher, request: AppConfigRequest) {
+        let name = request.target().to_owned();
+        let target = match self.target(&name) {
+            Ok(target) => target,
+            Err(err) => {
+                warn!("Rejecting the app config request: {err}");
+                return;
+            }
+        };
+
+        let written = match &request {
+            AppConfigRequest::Apply { document, .. } => self.apply(target, document),
+            AppConfigRequest::Rollback { .. } => self.rollback(target),
+        };
+        if let Err(err) = written {
+            warn!("Rejecting the app config of {name}: {err}");
+            self.publish_error(publisher, &name, &err).await;
+            return;
+        }
+
+        let applied = AppliedDocument {
+            sha256: file_sha256(&target.path).unwrap_or_default(),
+            applied_at: self.clock.now_wall().into(),
+        };
+        info!("Applied the app config {} of {name}", applied.sha256);
+        self.record(&name, applied.clone());
+
+        let error = self
+            .reload(target, &applied.sha256)
+            .await
+            .err()
+            .unwrap_or_default();
+        if !error.is_empty() {
+            warn!("{error}");
+        }
+        self.publish(publisher, &name, &applied, &error).await;
+    }
+
+    fn record(&self, name: &str, applied: AppliedDocument) {
+        let mut documents = self.applied();
+        documents.insert(name.to_owned(), applied);
+        if let Err(err) = self.repository.write(&documents) {
+            warn!("Unable to persist the applied app configs: {:?}", err);
+        }
+    }
+
+    fn applied(&self) -> AppliedDocuments {
+        if !self.repository.exists() {
+            return AppliedDocuments::new();
+        }
+
+        self.repository.read().unwrap_or_else(|err| {
+            warn!("Unable to read the applied app configs: {:?}", err);
+            AppliedDocuments::new()
+        })
+    }
+
+    /// Publish the documents on disk, the hash tells when a file was changed locally.
+    pub async fn publish_current(&self, publisher: &impl Publisher) {
+        let documents = self.applied();
+        for target in &self.targets {
+            let applied = match documents.get(&target.name) {
+                Some(applied) => applied,
+                None => continue,
+            };
+            let current = AppliedDocument {
+                sha256: file_sha256(&target.path).unwrap_or_default(),
+                applied_at: applied.applied_at,
+            };
+            if current.sha256 != applied.sha256 {
+                warn!("The app config of {} changed on disk", target.name);
+            }
+            self.publish(publisher, &target.name, &current, "").await;
+        }
+    }
+
+    async fn publish(
+        &self,
+        publisher: &impl Publisher,
+        name: &str,
+        applied: &AppliedDocument,
+        error: &str,
+    ) {
+        let fields = [
+            ("sha256", AstarteType::String(applied.sha256.clone())),
+            ("appliedAt", AstarteType::DateTime(applied.applied_at)),
+        ];
+        for (field, data) in fields {
+            if let Err(err) = publisher
+                .send(APP_CONFIG_INTERFACE, &format!("/{name}/{field}"), data)
+                .await
+            {
+                warn!("Unable to publish the app config of {name}: {:?}", err);
+            }
+        }
+        self.publish_error(publisher, name, error).await;
+    }
+
+    async fn publish_error(&self, publisher: &impl Publisher, name: &str, error: &str) {
+        if let Err(err) = publisher
+            .send(
+                APP_CONFIG_INTERFACE,
+                &format!("/{name}/lastError"),
+                AstarteType::String(error.to_owned()),
+            )
+            .await
+        {
+            warn!(
+                "Unable to publish the app config error of {name}: {:?}",
+                err
+            );
+        }
+    }
+
+    pub async fn run(
+        self,
+        publisher: &impl Publisher,
+        mut requests: mpsc::Receiver<AppConfigRequest>,
+    ) {
+        self.publish_current(publisher).await;
+
+        while let Some(request) = requests.recv().await {
+            self.handle(publisher, request).await;
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Hex SHA-256 of the file at `path`, `None` when it can't be read.
+fn file_sha256(path: &Path) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+
+    Some(
+        sha256(&data)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect(),
+    )
+}
+
+/// Start applying the app configs, the returned sender takes the requests.
+pub fn spawn<P>(
+    options: &AppConfigOptions,
+    store_directory: &Path,
+    clock: Arc<dyn Clock>,
+    publisher: P,
+) -> Result<(mpsc::Sender<AppConfigRequest>, JoinHandle<()>), DeviceManagerError>
+where
+    P: Publisher + 'static,
+{
+    let app_config = AppConfig::new(
+        clock,
+        options,
+        store_directory.join("app_config"),
+        Box::new(FileStateRepository::new(
+            store_directory.to_string_lossy().into_owned(),
+            "app_config.json".to_owned(),
+        )),
+        Box::new(SystemReloader),
+    )?;
+    let (requests_tx, requests_rx) = mpsc::channel(8);
+
+    let handle = tokio::spawn(async move { app_config.run(&publisher, requests_rx).await });
+
+    Ok((requests_tx, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::app_config::{
+        AppConfig, AppConfigOptions, AppConfigRequest, AppConfigTarget, AppliedDocuments,
+        DocumentFormat, MockReloader, Reload, SIGHUP,
+    };
+    use crate::interfaces::APP_CONFIG_INTERFACE;
+    use crate::test_utils::{recording_publisher, ManualClock, MemoryStateRepository, Recording};
+
+    type Published = Recording<()>;
+
+    /// Last value published on `path`.
+    fn last(published: &Published, path: &str) -> Option<AstarteType> {
+        published
+            .values(APP_CONFIG_INTERFACE)
+            .into_iter()
+            .rev()
+            .find_map(|(published, value)| (published == path).then(|| value))
+    }
+
+    fn target(directory: &Path, name: &str, reload: Option<Reload>) -> AppConfigTarget {
+        AppConfigTarget {
+            name: name.to_owned(),
+            path: directory.join(format!("{name}.json")),
+            format: DocumentFormat::Json,
+            schema: None,
+            reload,
+        }
+    }
+
+    fn app_config(
+        directory: &Path,
+        targets: Vec<AppConfigTarget>,
+        reloader: MockReloader,
+    ) -> AppConfig {
+        AppConfig::new(
+            Arc::new(ManualClock::new()),
+            &AppConfigOptions {
+                targets,
+                max_document_bytes: Some(64),
+            },
+            directory.join("previous"),
+            Box::new(MemoryStateRepository::<AppliedDocuments>::new()),
+            Box::new(reloader),
+        )
+        .unwrap()
+    }
+
+    fn apply(target: &str, document: &str) -> AppConfigRequest {
+        AppConfigRequest::Apply {
+            target: target.to_owned(),
+            document: document.to_owned(),
+        }
+    }
+
+    fn rollback(target: &str) -> AppConfigRequest {
+        AppConfigRequest::Rollback {
+            target: target.to_owned(),
+        }
+    }
+
+    fn sha256(document: &str) -> String {
+        openssl::sha::sha256(document.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn string(published: &Published, path: &str) -> Option<String> {
+        match last(published, path) {
+            Some(AstarteType::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn requests_parsed() {
+        let data = HashMap::from([
+            (
+                "target".to_owned(),
+                AstarteType::String("gateway".to_owned()),
+            ),
+            ("document".to_owned(), AstarteType::String("{}".to_owned())),
+        ]);
+
+        assert_eq!(
+            AppConfigRequest::from_object(&data),
+            Some(apply("gateway", "{}"))
+        );
+        assert_eq!(AppConfigRequest::from_object(&HashMap::new()), None);
+        assert_eq!(
+            AppConfigRequest::from_command("app-config:rollback gateway"),
+            Some(rollback("gateway"))
+        );
+        assert_eq!(AppConfigRequest::from_command("app-config:rollback"), None);
+    }
+
+    #[tokio::test]
+    async fn document_applied_and_published() {
+        let directory = tempfile::tempdir().unwrap();
+        let app_config = app_config(
+            directory.path(),
+            vec![target(directory.path(), "gateway", None)],
+            MockReloader::new(),
+        );
+
+        let (publisher, published) = recording_publisher();
+        let document = r#"{"port": 80}"#;
+        app_config
+            .handle(&publisher, apply("gateway", document))
+            .await;
+
+        let path = directory.path().join("gateway.json");
+        assert_eq!(std::fs::read_to_string(path).unwrap(), document);
+        assert!(!directory.path().join("gateway.json.tmp").exists());
+        assert_eq!(
+            string(&published, "/gateway/sha256"),
+            Some(sha256(document))
+        );
+        assert_eq!(
+            string(&published, "/gateway/lastError"),
+            Some(String::new())
+        );
+        assert!(matches!(
+            last(&published, "/gateway/appliedAt"),
+            Some(AstarteType::DateTime(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_documents_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        let schema = directory.path().join("schema.json");
+        let port = r#"{"port": {"type": "integer"}}"#;
+        std::fs::write(
+            &schema,
+            format!(r#"{{"type": "object", "required": ["port"], "properties": {port}}}"#),
+        )
+        .unwrap();
+        let mut gateway = target(directory.path(), "gateway", None);
+        gateway.schema = Some(schema);
+        let mut settings = target(directory.path(), "settings", None);
+        settings.format = DocumentFormat::Toml;
+        settings.schema = gateway.schema.clone();
+        // nothing is reloaded
+        let app_config = app_config(
+            directory.path(),
+            vec![gateway, settings],
+            MockReloader::new(),
+        );
+        let current = r#"{"port": 80}"#;
+        std::fs::write(directory.path().join("gateway.json"), current).unwrap();
+
+        let (publisher, published) = recording_publisher();
+        let oversized = format!(r#"{{"port": 80, "padding": "{}"}}"#, "x".repeat(64));
+        for (document, error) in [
+            (r#"{"host": "a"}"#, "schema violation at /: missing port"),
+            (
+                r#"{"port": "80"}"#,
+                "schema violation at /port: expected integer",
+            ),
+            ("{", "invalid document"),
+            (oversized.as_str(), "above the cap"),
+        ] {
+            app_config
+                .handle(&publisher, apply("gateway", document))
+                .await;
+
+            let published = string(&published, "/gateway/lastError").unwrap();
+            assert!(published.contains(error), "{published}");
+        }
+        assert_eq!(
+            std::fs::read_to_string(directory.path().join("gateway.json")).unwrap(),
+            current
+        );
+        assert_eq!(string(&published, "/gateway/sha256"), None);
+
+        // the TOML documents are checked against the same JSON schema
+        app_config
+            .handle(&publisher, apply("settings", "port = 80"))
+            .await;
+        assert_eq!(
+            string(&published, "/settings/lastError"),
+            Some(String::new())
+        );
+        app_config
+            .handle(&publisher, apply("settings", "host = 'a'"))
+            .await;
+        assert_eq!(
+            string(&published, "/settings/lastError"),
+            Some("schema violation at /: missing port".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn application_reloaded_as_configured() {
+        let directory = tempfile::tempdir().unwrap();
+        let document = "{}";
+        let mut reloader = MockReloader::new();
+        reloader
+            .expect_kill_unit()
+            .withf(|unit, signal| unit == "gateway.service" && *signal == SIGHUP)
+            .times(1)
+            .returning(|_, _| Ok(()));
+        reloader
+            .expect_emit_changed()
+            .withf(move |target, hash| target == "broker" && hash == sha256(document))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        reloader
+            .expect_run_command()
+            .withf(|command| command == "sensorsctl reload")
+            .times(1)
+            .returning(|_| {
+                Err(crate::error::DeviceManagerError::CommandFailed(
+                    "exit status 1".to_owned(),
+                ))
+            });
+        let targets = vec![
+            target(
+                directory.path(),
+                "gateway",
+                Some(Reload::Sighup {
+                    unit: "gateway.service".to_owned(),
+                }),
+            ),
+            target(directory.path(), "broker", Some(Reload::Dbus)),
+            target(
+                directory.path(),
+                "sensors",
+                Some(Reload::Command {
+                    command: "sensorsctl reload".to_owned(),
+                }),
+            ),
+        ];
+        let app_config = app_config(directory.path(), targets, reloader);
+
+        let (publisher, published) = recording_publisher();
+        for target in ["gateway", "broker", "sensors"] {
+            app_config.handle(&publisher, apply(target, document)).await;
+        }
+
+        assert_eq!(
+            string(&published, "/gateway/lastError"),
+            Some(String::new())
+        );
+        assert_eq!(string(&published, "/broker/lastError"), Some(String::new()));
+        // a failed reload leaves the document applied
+        assert_eq!(
+            string(&published, "/sensors/sha256"),
+            Some(sha256(document))
+        );
+        assert!(string(&published, "/sensors/lastError")
+            .unwrap()
+            .starts_with("unable to reload sensors"));
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_the_previous_document() {
+        let directory = tempfile::tempdir().unwrap();
+        let reload = Reload::Sighup {
+            unit: "gateway.service".to_owned(),
+        };
+        let mut reloader = MockReloader::new();
+        reloader
+            .expect_kill_unit()
+            .times(3)
+            .returning(|_, _| Ok(()));
+        let app_config = app_config(
+            directory.path(),
+            vec![target(directory.path(), "gateway", Some(reload))],
+            reloader,
+        );
+
+        let (publisher, published) = recording_publisher();
+        let path = directory.path().join("gateway.json");
+        app_config
+            .handle(&publisher, apply("gateway", r#"{"v": 1}"#))
+            .await;
+        app_config
+            .handle(&publisher, apply("gateway", r#"{"v": 2}"#))
+            .await;
+        app_config.handle(&publisher, rollback("gateway")).await;
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), r#"{"v": 1}"#);
+        assert_eq!(
+            string(&published, "/gateway/sha256"),
+            Some(sha256(r#"{"v": 1}"#))
+        );
+
+        // a single document is kept
+        app_config.handle(&publisher, rollback("gateway")).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), r#"{"v": 1}"#);
+        assert_eq!(
+            string(&published, "/gateway/lastError"),
+            Some("no previous document to roll back to".to_owned())
+        );
+    }
+}
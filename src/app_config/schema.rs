@@ -0,0 +1,217 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Validation of the configuration documents against a local JSON schema.
+//!
+//! Only the keywords describing the shape of a document are supported: `type`, `enum`, `const`,
+//! `properties`, `required`, `additionalProperties`, `items`, the bounds of the numbers and the
+//! lengths of the strings and arrays. Any other keyword is ignored.
+
+use serde_json::{Map, Value};
+
+/// Check `document` against `schema`, returning the path and the reason of the first violation.
+pub fn validate(schema: &Value, document: &Value) -> Result<(), String> {
+    check(schema, document, "")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{}: not allowed", pointer(path))),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+    let fail = |reason: String| Err(format!("{}: {reason}", pointer(path)));
+
+    if let Some(expected) = schema.get("type") {
+        let names: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !names.is_empty() && !names.iter().any(|name| is_type(value, name)) {
+            return fail(format!("expected {}", names.join(" or ")));
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return fail("not one of the allowed values".to_owned());
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return fail(format!("expected {constant}"));
+        }
+    }
+
+    match value {
+        Value::Object(object) => check_object(schema, object, path)?,
+        Value::Array(items) => {
+            check_length(schema, items.len(), "minItems", "maxItems").or_else(fail)?;
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    check(item_schema, item, &format!("{path}/{index}"))?;
+                }
+            }
+        }
+        Value::String(string) => {
+            check_length(schema, string.chars().count(), "minLength", "maxLength").or_else(fail)?
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+                if number < minimum {
+                    return fail(format!("below the minimum {minimum}"));
+                }
+            }
+            if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+                if number > maximum {
+                    return fail(format!("above the maximum {maximum}"));
+                }
+            }
+        }
+        Value::Null | Value::Bool(_) => {}
+    }
+
+    Ok(())
+}
+
+fn check_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+) -> Result<(), String> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        if let Some(missing) = required
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|name| !object.contains_key(*name))
+        {
+            return Err(format!("{}: missing {missing}", pointer(path)));
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+        let property_path = format!("{path}/{name}");
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property_schema) => check(property_schema, value, &property_path)?,
+            None => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    check(additional, value, &property_path)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_length(
+    schema: &Map<String, Value>,
+    length: usize,
+    min: &str,
+    max: &str,
+) -> Result<(), String> {
+    let length = length as u64;
+    if let Some(min) = schema.get(min).and_then(Value::as_u64) {
+        if length < min {
+            return Err(format!("shorter than {min}"));
+        }
+    }
+    if let Some(max) = schema.get(max).and_then(Value::as_u64) {
+        if length > max {
+            return Err(format!("longer than {max}"));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+fn pointer(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::app_config::schema::validate;
+
+    #[test]
+    fn documents_checked_against_the_shape() {
+        let schema = json!({
+            "type": "object",
+            "required": ["port"],
+            "properties": {
+                "port": { "type": "integer", "minimum": 1, "maximum": 65535 },
+                "mode": { "enum": ["fast", "safe"] },
+                "peers": { "type": "array", "maxItems": 2, "items": { "type": "string" } }
+            },
+            "additionalProperties": false
+        });
+
+        assert_eq!(
+            validate(&schema, &json!({ "port": 80, "mode": "safe" })),
+            Ok(())
+        );
+        assert_eq!(
+            validate(&schema, &json!({ "mode": "safe" })),
+            Err("/: missing port".to_owned())
+        );
+        assert_eq!(
+            validate(&schema, &json!({ "port": 0 })),
+            Err("/port: below the minimum 1".to_owned())
+        );
+        assert_eq!(
+            validate(&schema, &json!({ "port": 80, "peers": ["a", 1] })),
+            Err("/peers/1: expected string".to_owned())
+        );
+        assert_eq!(
+            validate(&schema, &json!({ "port": 80, "peers": ["a", "b", "c"] })),
+            Err("/peers: longer than 2".to_owned())
+        );
+        assert_eq!(
+            validate(&schema, &json!({ "port": 80, "debug": true })),
+            Err("/debug: not allowed".to_owned())
+        );
+        assert_eq!(
+            validate(&schema, &json!([80])),
+            Err("/: expected object".to_owned())
+        );
+    }
+}
@@ -0,0 +1,506 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Consistency audit of the persisted state.
+//!
+//! Partial failures can leave the state files in combinations the components do not expect.
+//! The audit runs only at startup, before the components load their state and hold it in memory;
+//! each rule repairs what it can and every finding is reported on the diagnostics interface under
+//! a stable code.
+
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::data::send_stats::SendStatsMap;
+use crate::data::Publisher;
+use crate::disk_guard::DiskGuard;
+use crate::interfaces::{DIAGNOSTICS_INTERFACE, OTA_RESPONSE_INTERFACE};
+use crate::ota::artifacts::artifact_name;
+use crate::ota::ota_handler::{OTAError, OTAStatus, PausedDownload};
+use crate::repository::file_state_repository::FileStateRepository;
+use crate::repository::StateRepository;
+use crate::telemetry::config::TelemetryConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FindingCode {
+    /// A state file could not be read, it was removed.
+    UnreadableState,
+    /// A download is paused while the deploy of an update is pending, it was dropped.
+    OtaPausedDuringDeploy,
+    /// The partial artifact of a paused download is gone, the download restarts from scratch.
+    OtaPartialArtifactMissing,
+    /// The telemetry config of an interface no longer loaded, it was pruned.
+    TelemetryConfigUnknownInterface,
+    /// The send stats of an interface no longer loaded, they were pruned.
+    SendStatsUnknownInterface,
+}
+
+impl FindingCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FindingCode::UnreadableState => "unreadableState",
+            FindingCode::OtaPausedDuringDeploy => "otaPausedDuringDeploy",
+            FindingCode::OtaPartialArtifactMissing => "otaPartialArtifactMissing",
+            FindingCode::TelemetryConfigUnknownInterface => "telemetryConfigUnknownInterface",
+            FindingCode::SendStatsUnknownInterface => "sendStatsUnknownInterface",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub code: FindingCode,
+    pub detail: String,
+}
+
+impl Finding {
+    fn new(code: FindingCode, detail: impl Into<String>) -> Self {
+        Finding {
+            code,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.as_str(), self.detail)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    pub findings: Vec<Finding>,
+    /// OTA requests dropped by the repairs, reported as failed.
+    pub failed_ota_requests: Vec<Uuid>,
+}
+
+/// A paused download is dropped while a deploy is pending, and restarted when its partial
+/// artifact no longer has the persisted length. The updates in progress are left to the OTA
+/// handler.
+pub(crate) fn audit_paused_download(
+    paused: &mut Option<PausedDownload>,
+    deploy_pending: bool,
+    artifact_len: Option<u64>,
+) -> Vec<Finding> {
+    let uuid = match paused {
+        Some(paused) if paused.in_flight.is_none() => paused.request.uuid,
+        _ => return Vec::new(),
+    };
+
+    if deploy_pending {
+        *paused = None;
+        return vec![Finding::new(
+            FindingCode::OtaPausedDuringDeploy,
+            format!("dropped the paused download of {uuid}"),
+        )];
+    }
+
+    match paused {
+        Some(paused) if paused.resume.offset > 0 && artifact_len != Some(paused.resume.offset) => {
+            let offset = paused.resume.offset;
+            paused.resume = Default::default();
+            vec![Finding::new(
+                FindingCode::OtaPartialArtifactMissing,
+                format!(
+                    "partial artifact of {uuid} is {} bytes instead of {offset}",
+                    artifact_len.unwrap_or_default()
+                ),
+            )]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The config of the interfaces not `known` is pruned.
+pub fn audit_telemetry_config(
+    config: &mut TelemetryConfig,
+    known: &HashSet<String>,
+) -> Vec<Finding> {
+    let unknown: Vec<String> = config
+        .keys()
+        .filter(|interface_name| !known.contains(*interface_name))
+        .cloned()
+        .collect();
+
+    unknown
+        .into_iter()
+        .map(|interface_name| {
+            config.remove(&interface_name);
+            Finding::new(FindingCode::TelemetryConfigUnknownInterface, interface_name)
+        })
+        .collect()
+}
+
+/// The stats of the interfaces not `known` are pruned.
+pub fn audit_send_stats(stats: &mut SendStatsMap, known: &HashSet<String>) -> Vec<Finding> {
+    let unknown: Vec<String> = stats
+        .keys()
+        .filter(|interface_name| !known.contains(*interface_name))
+        .cloned()
+        .collect();
+
+    unknown
+        .into_iter()
+        .map(|interface_name| {
+            stats.remove(&interface_name);
+            Finding::new(FindingCode::SendStatsUnknownInterface, interface_name)
+        })
+        .collect()
+}
+
+/// Runs the audit rules on the state files of the store directory.
+pub struct StateAudit {
+    store_directory: String,
+    download_directory: PathBuf,
+    known_interfaces: HashSet<String>,
+    disk_guard: Option<Arc<DiskGuard>>,
+}
+
+impl StateAudit {
+    pub fn new(
+        store_directory: &str,
+        download_directory: &str,
+        known_interfaces: HashSet<String>,
+        disk_guard: Option<Arc<DiskGuard>>,
+    ) -> Self {
+        StateAudit {
+            store_directory: store_directory.to_owned(),
+            download_directory: download_directory.into(),
+            known_interfaces,
+            disk_guard,
+        }
+    }
+
+    fn repository(&self, name: &str) -> FileStateRepository {
+        FileStateRepository::new(self.store_directory.clone(), name.to_owned())
+            .with_disk_guard(self.disk_guard.clone())
+    }
+
+    /// Read the state `name`, removing it when unreadable.
+    fn load<T>(&self, name: &str, findings: &mut Vec<Finding>) -> Option<T>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync,
+    {
+        let repository = self.repository(name);
+        if !StateRepository::<T>::exists(&repository) {
+            return None;
+        }
+
+        match StateRepository::<T>::read(&repository) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                // a corrupt file is already moved aside by the repository
+                if StateRepository::<T>::exists(&repository) {
+                    if let Err(err) = StateRepository::<T>::clear(&repository) {
+                        warn!("Unable to remove the unreadable {name}: {:?}", err);
+                    }
+                }
+                findings.push(Finding::new(
+                    FindingCode::UnreadableState,
+                    format!("{name}: {err}"),
+                ));
+                None
+            }
+        }
+    }
+
+    /// Persist the state `name` repaired by a rule, `None` removes it.
+    fn store<T>(&self, name: &str, state: Option<&T>)
+    where
+        T: Serialize + DeserializeOwned + Send + Sync,
+    {
+        let repository = self.repository(name);
+        let result = match state {
+            Some(state) => repository.write(state),
+            None => StateRepository::<T>::clear(&repository),
+        };
+
+        if let Err(err) = result {
+            warn!("Unable to store the repaired {name}: {:?}", err);
+        }
+    }
+
+    /// Run every rule, persisting the repaired state.
+    pub fn run_once(&self) -> AuditReport {
+        let mut report = AuditReport::default();
+        let findings = &mut report.findings;
+
+        let mut paused: Option<PausedDownload> = self.load("ota_download.json", findings);
+        let before = paused.clone();
+        let deploy_pending =
+            StateRepository::<serde_json::Value>::exists(&self.repository("state.json"));
+        let artifact_len = paused.as_ref().and_then(|paused| {
+            let file = artifact_name(paused.request.bundle_type(), paused.request.uuid);
+            std::fs::metadata(self.download_directory.join(file))
+                .ok()
+                .map(|metadata| metadata.len())
+        });
+        let found = audit_paused_download(&mut paused, deploy_pending, artifact_len);
+        if !found.is_empty() {
+            self.store("ota_download.json", paused.as_ref());
+            if let (Some(before), None) = (before, &paused) {
+                report.failed_ota_requests.push(before.request.uuid);
+            }
+            findings.extend(found);
+        }
+
+        if let Some(mut config) = self.load::<TelemetryConfig>("telemetry_config.json", findings) {
+            let found = audit_telemetry_config(&mut config, &self.known_interfaces);
+            if !found.is_empty() {
+                self.store("telemetry_config.json", Some(&config));
+                findings.extend(found);
+            }
+        }
+
+        if let Some(mut stats) = self.load::<SendStatsMap>("send_stats.json", findings) {
+            let found = audit_send_stats(&mut stats, &self.known_interfaces);
+            if !found.is_empty() {
+                self.store("send_stats.json", Some(&stats));
+                findings.extend(found);
+            }
+        }
+
+        for finding in &report.findings {
+            warn!("State audit: {finding}");
+        }
+
+        report
+    }
+
+    /// Publish each finding on `/stateAudit/{code}` of the diagnostics interface, and the
+    /// dropped OTA requests as failed.
+    pub async fn publish(
+        publisher: &impl Publisher,
+        report: &AuditReport,
+    ) -> Result<(), AstarteError> {
+        for finding in &report.findings {
+            publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    &format!("/stateAudit/{}", finding.code.as_str()),
+                    AstarteType::String(finding.detail.clone()),
+                )
+                .await?;
+        }
+
+        for uuid in &report.failed_ota_requests {
+            publisher
+                .send_object(
+                    OTA_RESPONSE_INTERFACE,
+                    "/response",
+                    OTAStatus::Error(OTAError::Failed).to_response(*uuid),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use astarte_sdk::types::AstarteType;
+    use uuid::Uuid;
+
+    use crate::audit::{
+        audit_paused_download, audit_send_stats, audit_telemetry_config, AuditReport, Finding,
+        FindingCode, StateAudit,
+    };
+    use crate::data::send_stats::{InterfaceSendStats, SendStatsMap};
+    use crate::data::MockPublisher;
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::ota::download::ResumePoint;
+    use crate::ota::messages::{OtaRequest, OtaResponse};
+    use crate::ota::ota_handler::{PausedDownload, UpdatePhase};
+    use crate::telemetry::config::{TelemetryConfig, TelemetryInterfaceConfig};
+
+    const SYSTEM_STATUS: &str = "io.edgehog.devicemanager.SystemStatus";
+    const REMOVED: &str = "io.edgehog.devicemanager.Removed";
+
+    fn paused_at(offset: u64) -> PausedDownload {
+        PausedDownload {
+            request: OtaRequest::new(Uuid::new_v4(), "http://ota.bin"),
+            resume: ResumePoint {
+                offset,
+                etag: Some("\"v1\"".to_owned()),
+            },
+            in_flight: None,
+            restarts: 0,
+        }
+    }
+
+    fn known() -> HashSet<String> {
+        HashSet::from([SYSTEM_STATUS.to_owned()])
+    }
+
+    #[test]
+    fn paused_download_dropped_during_deploy() {
+        let mut paused = Some(paused_at(6));
+
+        let findings = audit_paused_download(&mut paused, true, Some(6));
+
+        assert!(paused.is_none());
+        assert_eq!(findings[0].code, FindingCode::OtaPausedDuringDeploy);
+    }
+
+    #[test]
+    fn update_in_flight_left_to_the_handler() {
+        let mut paused = Some(PausedDownload {
+            in_flight: Some(UpdatePhase::Deploying),
+            ..paused_at(6)
+        });
+
+        assert!(audit_paused_download(&mut paused, true, Some(12)).is_empty());
+        assert!(paused.is_some());
+    }
+
+    #[test]
+    fn paused_download_restarted_without_partial_artifact() {
+        let mut paused = Some(paused_at(6));
+        assert!(audit_paused_download(&mut paused, false, Some(6)).is_empty());
+
+        let findings = audit_paused_download(&mut paused, false, None);
+
+        assert_eq!(paused.unwrap().resume, ResumePoint::default());
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].code, FindingCode::OtaPartialArtifactMissing);
+    }
+
+    #[test]
+    fn telemetry_config_of_unknown_interfaces_pruned() {
+        let mut config = TelemetryConfig::from([
+            (
+                SYSTEM_STATUS.to_owned(),
+                TelemetryInterfaceConfig::default(),
+            ),
+            (REMOVED.to_owned(), TelemetryInterfaceConfig::default()),
+        ]);
+
+        let findings = audit_telemetry_config(&mut config, &known());
+
+        assert_eq!(config.keys().collect::<Vec<_>>(), vec![SYSTEM_STATUS]);
+        assert_eq!(
+            findings,
+            vec![Finding {
+                code: FindingCode::TelemetryConfigUnknownInterface,
+                detail: REMOVED.to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn send_stats_of_unknown_interfaces_pruned() {
+        let mut stats = SendStatsMap::from([
+            (SYSTEM_STATUS.to_owned(), InterfaceSendStats::default()),
+            (REMOVED.to_owned(), InterfaceSendStats::default()),
+        ]);
+
+        let findings = audit_send_stats(&mut stats, &known());
+
+        assert_eq!(stats.keys().collect::<Vec<_>>(), vec![SYSTEM_STATUS]);
+        assert_eq!(
+            findings,
+            vec![Finding {
+                code: FindingCode::SendStatsUnknownInterface,
+                detail: REMOVED.to_owned(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn state_files_repaired_and_findings_published() {
+        let store = tempfile::tempdir().unwrap();
+        let download = tempfile::tempdir().unwrap();
+        let paused = paused_at(6);
+        let uuid = paused.request.uuid;
+        std::fs::write(
+            store.path().join("ota_download.json"),
+            serde_json::to_vec(&paused).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(store.path().join("state.json"), b"{}").unwrap();
+        std::fs::write(store.path().join("send_stats.json"), b"not json").unwrap();
+
+        let audit = StateAudit::new(
+            store.path().to_str().unwrap(),
+            download.path().to_str().unwrap(),
+            known(),
+            None,
+        );
+        let report = audit.run_once();
+
+        assert_eq!(
+            report
+                .findings
+                .iter()
+                .map(|finding| finding.code)
+                .collect::<Vec<_>>(),
+            vec![
+                FindingCode::OtaPausedDuringDeploy,
+                FindingCode::UnreadableState
+            ]
+        );
+        assert_eq!(report.failed_ota_requests, vec![uuid]);
+        assert!(!store.path().join("ota_download.json").exists());
+        assert!(!store.path().join("send_stats.json").exists());
+        assert!(store.path().join("send_stats.json.corrupt").exists());
+        // the pending deploy is left to the OTA handler
+        assert!(store.path().join("state.json").exists());
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, data| {
+                interface == DIAGNOSTICS_INTERFACE
+                    && path == "/stateAudit/otaPausedDuringDeploy"
+                    && matches!(data, AstarteType::String(_))
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        publisher
+            .expect_send()
+            .withf(|_, path, _| path == "/stateAudit/unreadableState")
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        publisher
+            .expect_send_object()
+            .withf(move |_, _, response: &OtaResponse| {
+                response.uuid == uuid && response.status == "Error"
+            })
+            .times(1)
+            .returning(|_, _, _: OtaResponse| Ok(()));
+        StateAudit::publish(&publisher, &report).await.unwrap();
+
+        // nothing left to repair
+        assert_eq!(audit.run_once(), AuditReport::default());
+    }
+}
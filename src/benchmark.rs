@@ -0,0 +1,622 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Publisher throughput benchmark, used to qualify new hardware and broker configurations.
+//!
+//! Synthetic samples are published on a dedicated interface straight through the Astarte
+//! publisher, so the send stats, the offline queue and the telemetry are left untouched. The
+//! benchmark is started by the hidden `Benchmark` command or by the `benchmark` subcommand, and
+//! only when enabled in the configuration.
+
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::watch;
+#[cfg(feature = "dbus")]
+use zbus::{dbus_interface, dbus_proxy, fdo, Connection, ConnectionBuilder};
+
+use crate::clock::Clock;
+use crate::data::deadline::DeadlinePublisher;
+use crate::data::Publisher;
+#[cfg(feature = "dbus")]
+use crate::error::DeviceManagerError;
+use crate::interfaces::{BENCHMARK_INTERFACE, DIAGNOSTICS_INTERFACE};
+use crate::ota::messages::OtaResponse;
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::system_bus;
+
+pub const MAX_SAMPLES: u32 = 10_000;
+pub const MAX_PAYLOAD_BYTES: u32 = 64 * 1024;
+pub const MAX_CONCURRENCY: u32 = 16;
+#[cfg(feature = "dbus")]
+pub const BENCHMARK_SERVICE_NAME: &str = "io.edgehog.Benchmark";
+#[cfg(feature = "dbus")]
+const BENCHMARK_SERVICE_PATH: &str = "/io/edgehog/Benchmark";
+/// Command starting a benchmark, followed by the optional `key=value` parameters.
+pub const BENCHMARK_COMMAND: &str = "Benchmark";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BenchmarkError {
+    #[error("benchmarks are disabled")]
+    Disabled,
+    #[error("an OTA update is in progress")]
+    OtaActive,
+    #[error("another benchmark is running")]
+    AlreadyRunning,
+    #[error("{0} is above the cap of {1}")]
+    AboveCap(&'static str, u32),
+    #[error("invalid benchmark parameter {0}")]
+    InvalidParameter(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchmarkRequest {
+    pub samples: u32,
+    pub payload_bytes: u32,
+    pub concurrency: u32,
+}
+
+impl Default for BenchmarkRequest {
+    fn default() -> Self {
+        BenchmarkRequest {
+            samples: 100,
+            payload_bytes: 64,
+            concurrency: 1,
+        }
+    }
+}
+
+impl BenchmarkRequest {
+    /// Parse `Benchmark [samples=N] [payloadBytes=N] [concurrency=N]`.
+    pub fn from_command(command: &str) -> Result<Self, BenchmarkError> {
+        let mut words = command.split_whitespace();
+        if words.next() != Some(BENCHMARK_COMMAND) {
+            return Err(BenchmarkError::InvalidParameter(command.to_owned()));
+        }
+
+        let mut request = BenchmarkRequest::default();
+        for parameter in words {
+            let (name, value) = parameter
+                .split_once('=')
+                .ok_or_else(|| BenchmarkError::InvalidParameter(parameter.to_owned()))?;
+            let value = value
+                .parse()
+                .map_err(|_| BenchmarkError::InvalidParameter(parameter.to_owned()))?;
+
+            match name {
+                "samples" => request.samples = value,
+                "payloadBytes" => request.payload_bytes = value,
+                "concurrency" => request.concurrency = value,
+                _ => return Err(BenchmarkError::InvalidParameter(parameter.to_owned())),
+            }
+        }
+
+        Ok(request)
+    }
+
+    fn check_caps(&self) -> Result<(), BenchmarkError> {
+        for (name, value, cap) in [
+            ("samples", self.samples, MAX_SAMPLES),
+            ("payloadBytes", self.payload_bytes, MAX_PAYLOAD_BYTES),
+            ("concurrency", self.concurrency, MAX_CONCURRENCY),
+        ] {
+            if value == 0 {
+                return Err(BenchmarkError::InvalidParameter(format!("{name}=0")));
+            }
+            if value > cap {
+                return Err(BenchmarkError::AboveCap(name, cap));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Outcome of a benchmark, the latencies are in microseconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchmarkReport {
+    pub samples: u32,
+    pub failures: u32,
+    pub elapsed_millis: u64,
+    pub samples_per_second: f64,
+    pub p50_micros: u64,
+    pub p90_micros: u64,
+    pub p99_micros: u64,
+    pub max_micros: u64,
+}
+
+impl BenchmarkReport {
+    /// Compute the statistics of the successful publishes.
+    pub fn new(mut latencies: Vec<Duration>, failures: u32, elapsed: Duration) -> Self {
+        latencies.sort();
+
+        // nearest rank percentile
+        let percentile = |percent: usize| {
+            let rank = (latencies.len() * percent + 99) / 100;
+            latencies
+                .get(rank.saturating_sub(1))
+                .map_or(0, |latency| latency.as_micros() as u64)
+        };
+        let samples = latencies.len() as u32 + failures;
+        let samples_per_second = if elapsed.is_zero() {
+            0.0
+        } else {
+            latencies.len() as f64 / elapsed.as_secs_f64()
+        };
+
+        BenchmarkReport {
+            samples,
+            failures,
+            elapsed_millis: elapsed.as_millis() as u64,
+            samples_per_second,
+            p50_micros: percentile(50),
+            p90_micros: percentile(90),
+            p99_micros: percentile(99),
+            max_micros: percentile(100),
+        }
+    }
+}
+
+impl Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Samples: {} ({} failed) in {} ms, {:.1} samples/s",
+            self.samples, self.failures, self.elapsed_millis, self.samples_per_second
+        )?;
+        writeln!(
+            f,
+            "Latency: p50 {} us, p90 {} us, p99 {} us, max {} us",
+            self.p50_micros, self.p90_micros, self.p99_micros, self.max_micros
+        )
+    }
+}
+
+pub struct Benchmark {
+    clock: Arc<dyn Clock>,
+    enabled: bool,
+    ota: watch::Receiver<Option<OtaResponse>>,
+    running: AtomicBool,
+}
+
+impl Benchmark {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        enabled: bool,
+        ota: watch::Receiver<Option<OtaResponse>>,
+    ) -> Self {
+        Benchmark {
+            clock,
+            enabled,
+            ota,
+            running: AtomicBool::new(false),
+        }
+    }
+
+    fn ota_active(&self) -> bool {
+        self.ota.borrow().as_ref().map_or(false, |ota| {
+            ota.status == "InProgress" || ota.status == "Paused"
+        })
+    }
+
+    /// Publish the requested samples on `publisher`, refusing when the guardrails are not met.
+    pub async fn run<P>(
+        &self,
+        publisher: &P,
+        request: BenchmarkRequest,
+    ) -> Result<BenchmarkReport, BenchmarkError>
+    where
+        P: Publisher + Clone + 'static,
+    {
+        if !self.enabled {
+            return Err(BenchmarkError::Disabled);
+        }
+        request.check_caps()?;
+        if self.ota_active() {
+            return Err(BenchmarkError::OtaActive);
+        }
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(BenchmarkError::AlreadyRunning);
+        }
+
+        info!("Running benchmark {:?}", request);
+        let report = self.publish_samples(publisher, request).await;
+        self.running.store(false, Ordering::SeqCst);
+
+        Ok(report)
+    }
+
+    async fn publish_samples<P>(&self, publisher: &P, request: BenchmarkRequest) -> BenchmarkReport
+    where
+        P: Publisher + Clone + 'static,
+    {
+        let next = Arc::new(AtomicU32::new(0));
+        let payload = vec![0xa5; request.payload_bytes as usize];
+        let start = self.clock.now_monotonic();
+
+        let workers: Vec<_> = (0..request.concurrency)
+            .map(|_| {
+                let publisher = publisher.clone();
+                let clock = self.clock.clone();
+                let next = next.clone();
+                let payload = payload.clone();
+
+                tokio::spawn(async move {
+                    let mut latencies = Vec::new();
+                    let mut failures = 0;
+                    while next.fetch_add(1, Ordering::SeqCst) < request.samples {
+                        let sent_at = clock.now_monotonic();
+                        let result = publisher
+                            .send(
+                                BENCHMARK_INTERFACE,
+                                "/payload",
+                                AstarteType::BinaryBlob(payload.clone()),
+                            )
+                            .await;
+                        match result {
+                            Ok(()) => latencies.push(clock.now_monotonic() - sent_at),
+                            Err(_) => failures += 1,
+                        }
+                    }
+
+                    (latencies, failures)
+                })
+            })
+            .collect();
+
+        let mut latencies = Vec::new();
+        let mut failures = 0;
+        for worker in workers {
+            match worker.await {
+                Ok((worker_latencies, worker_failures)) => {
+                    latencies.extend(worker_latencies);
+                    failures += worker_failures;
+                }
+                Err(err) => warn!("Benchmark worker failed: {:?}", err),
+            }
+        }
+
+        BenchmarkReport::new(latencies, failures, self.clock.now_monotonic() - start)
+    }
+}
+
+/// Run a benchmark on the Astarte publisher behind `publisher`, publishing the report on
+/// `/benchmark` of the diagnostics interface, or the refusal on `/benchmarkRefused`.
+pub async fn run_and_publish<P>(
+    benchmark: &Benchmark,
+    publisher: &DeadlinePublisher<P>,
+    request: BenchmarkRequest,
+) -> Result<BenchmarkReport, BenchmarkError>
+where
+    P: Publisher + Clone + 'static,
+{
+    let result = benchmark.run(publisher.inner(), request).await;
+
+    let (path, value) = match &result {
+        Ok(report) => {
+            info!("Benchmark completed\n{report}");
+            (
+                "/benchmark",
+                serde_json::to_string(report).unwrap_or_default(),
+            )
+        }
+        Err(err) => {
+            warn!("Benchmark refused: {err}");
+            ("/benchmarkRefused", err.to_string())
+        }
+    };
+    if let Err(err) = publisher
+        .send(DIAGNOSTICS_INTERFACE, path, AstarteType::String(value))
+        .await
+    {
+        warn!("Unable to publish the benchmark outcome: {:?}", err);
+    }
+
+    result
+}
+
+#[cfg(feature = "dbus")]
+struct BenchmarkService<P> {
+    benchmark: Arc<Benchmark>,
+    publisher: DeadlinePublisher<P>,
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_interface(name = "io.edgehog.Benchmark1")]
+impl<P> BenchmarkService<P>
+where
+    P: Publisher + Clone + 'static,
+{
+    /// Run a benchmark, returning the report as a JSON object.
+    async fn run(&self, samples: u32, payload_bytes: u32, concurrency: u32) -> fdo::Result<String> {
+        let request = BenchmarkRequest {
+            samples,
+            payload_bytes,
+            concurrency,
+        };
+
+        let report = run_and_publish(&self.benchmark, &self.publisher, request)
+            .await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+
+        serde_json::to_string(&report).map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "io.edgehog.Benchmark1",
+    default_service = "io.edgehog.Benchmark",
+    default_path = "/io/edgehog/Benchmark"
+)]
+trait BenchmarkApi {
+    fn run(&self, samples: u32, payload_bytes: u32, concurrency: u32) -> zbus::Result<String>;
+}
+
+/// Serve the benchmark API on the system bus, until the returned connection is dropped.
+#[cfg(feature = "dbus")]
+pub async fn serve<P>(
+    benchmark: Arc<Benchmark>,
+    publisher: DeadlinePublisher<P>,
+) -> Result<Connection, DeviceManagerError>
+where
+    P: Publisher + Clone + 'static,
+{
+    let connection = ConnectionBuilder::system()?
+        .name(BENCHMARK_SERVICE_NAME)?
+        .serve_at(
+            BENCHMARK_SERVICE_PATH,
+            BenchmarkService {
+                benchmark,
+                publisher,
+            },
+        )?
+        .build()
+        .await?;
+    info!("Benchmark API available as {BENCHMARK_SERVICE_NAME}");
+
+    Ok(connection)
+}
+
+/// Ask the running instance to run a benchmark.
+#[cfg(feature = "dbus")]
+pub async fn request(request: BenchmarkRequest) -> Result<BenchmarkReport, DeviceManagerError> {
+    let connection = system_bus().await?;
+    let report = BenchmarkApiProxy::new(&connection)
+        .await?
+        .run(request.samples, request.payload_bytes, request.concurrency)
+        .await?;
+
+    Ok(serde_json::from_str(&report)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use astarte_sdk::AstarteError;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use serde::Serialize;
+    use tokio::sync::watch;
+    use uuid::Uuid;
+
+    use crate::benchmark::{
+        Benchmark, BenchmarkError, BenchmarkReport, BenchmarkRequest, MAX_SAMPLES,
+    };
+    use crate::data::Publisher;
+    use crate::interfaces::BENCHMARK_INTERFACE;
+    use crate::ota::messages::OtaResponse;
+    use crate::test_utils::ManualClock;
+
+    /// Publisher taking 2 ms for each send, failing every tenth one. The other publishes are
+    /// accepted right away.
+    #[derive(Clone)]
+    struct TimedPublisher {
+        clock: Arc<ManualClock>,
+        sent: Arc<AtomicU32>,
+    }
+
+    #[async_trait]
+    impl Publisher for TimedPublisher {
+        async fn send_object<T>(&self, _: &str, _: &str, _: T) -> Result<(), AstarteError>
+        where
+            T: Serialize + Send + 'static,
+        {
+            Ok(())
+        }
+
+        async fn send(
+            &self,
+            interface_name: &str,
+            _: &str,
+            data: AstarteType,
+        ) -> Result<(), AstarteError> {
+            assert_eq!(interface_name, BENCHMARK_INTERFACE);
+            assert_eq!(data, AstarteType::BinaryBlob(vec![0xa5; 32]));
+
+            self.clock.advance(Duration::from_millis(2));
+            if self.sent.fetch_add(1, Ordering::SeqCst) % 10 == 9 {
+                return Err(AstarteError::SendError("broker busy".to_owned()));
+            }
+
+            Ok(())
+        }
+
+        async fn unset(&self, _: &str, _: &str) -> Result<(), AstarteError> {
+            Ok(())
+        }
+
+        async fn send_object_with_timestamp<T>(
+            &self,
+            _: &str,
+            _: &str,
+            _: T,
+            _: DateTime<Utc>,
+        ) -> Result<(), AstarteError>
+        where
+            T: Serialize + Send + 'static,
+        {
+            Ok(())
+        }
+
+        async fn send_with_timestamp(
+            &self,
+            _: &str,
+            _: &str,
+            _: AstarteType,
+            _: DateTime<Utc>,
+        ) -> Result<(), AstarteError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parse_command() {
+        assert_eq!(
+            BenchmarkRequest::from_command("Benchmark"),
+            Ok(BenchmarkRequest::default())
+        );
+        assert_eq!(
+            BenchmarkRequest::from_command("Benchmark samples=500 concurrency=4"),
+            Ok(BenchmarkRequest {
+                samples: 500,
+                payload_bytes: 64,
+                concurrency: 4,
+            })
+        );
+        assert_eq!(
+            BenchmarkRequest::from_command("Benchmark samples=many"),
+            Err(BenchmarkError::InvalidParameter("samples=many".to_owned()))
+        );
+    }
+
+    #[test]
+    fn statistics_computed() {
+        let latencies = (1..=100).map(Duration::from_millis).collect();
+
+        let report = BenchmarkReport::new(latencies, 5, Duration::from_secs(2));
+
+        assert_eq!(
+            report,
+            BenchmarkReport {
+                samples: 105,
+                failures: 5,
+                elapsed_millis: 2000,
+                samples_per_second: 50.0,
+                p50_micros: 50_000,
+                p90_micros: 90_000,
+                p99_micros: 99_000,
+                max_micros: 100_000,
+            }
+        );
+        assert_eq!(
+            BenchmarkReport::new(Vec::new(), 3, Duration::ZERO).max_micros,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn samples_published_and_measured() {
+        let clock = Arc::new(ManualClock::new());
+        let publisher = TimedPublisher {
+            clock: clock.clone(),
+            sent: Arc::new(AtomicU32::new(0)),
+        };
+        let (_ota_tx, ota) = watch::channel(None);
+        let benchmark = Benchmark::new(clock, true, ota);
+
+        let report = benchmark
+            .run(
+                &publisher,
+                BenchmarkRequest {
+                    samples: 50,
+                    payload_bytes: 32,
+                    concurrency: 1,
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(publisher.sent.load(Ordering::SeqCst), 50);
+        assert_eq!(
+            report,
+            BenchmarkReport {
+                samples: 50,
+                failures: 5,
+                elapsed_millis: 100,
+                samples_per_second: 450.0,
+                p50_micros: 2000,
+                p90_micros: 2000,
+                p99_micros: 2000,
+                max_micros: 2000,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn guardrails_refuse_benchmark() {
+        let clock = Arc::new(ManualClock::new());
+        let publisher = TimedPublisher {
+            clock: clock.clone(),
+            sent: Arc::new(AtomicU32::new(0)),
+        };
+        let (ota_tx, ota) = watch::channel(None);
+
+        let disabled = Benchmark::new(clock.clone(), false, ota.clone());
+        assert_eq!(
+            disabled.run(&publisher, BenchmarkRequest::default()).await,
+            Err(BenchmarkError::Disabled)
+        );
+
+        let enabled = Benchmark::new(clock, true, ota);
+        let too_many = BenchmarkRequest {
+            samples: MAX_SAMPLES + 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            enabled.run(&publisher, too_many).await,
+            Err(BenchmarkError::AboveCap("samples", MAX_SAMPLES))
+        );
+
+        ota_tx.send_replace(Some(OtaResponse {
+            uuid: Uuid::new_v4(),
+            status: "InProgress".to_owned(),
+            status_code: String::new(),
+            error_code: None,
+            message: None,
+        }));
+        assert_eq!(
+            enabled.run(&publisher, BenchmarkRequest::default()).await,
+            Err(BenchmarkError::OtaActive)
+        );
+        assert_eq!(publisher.sent.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Latency from the process start to the first successful publish, across restarts.
+
+use std::time::{Duration, SystemTime};
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use serde::{Deserialize, Serialize};
+
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::repository::StateRepository;
+
+/// Number of startups kept in the rolling window.
+const HISTORY_SIZE: usize = 20;
+
+const RESTART_STORM_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Startup {
+    started_at: SystemTime,
+    latency_millis: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StartupHistory {
+    startups: Vec<Startup>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BootLatency {
+    pub last_millis: u64,
+    pub min_millis: u64,
+    pub avg_millis: u64,
+    pub max_millis: u64,
+    pub startups_last_hour: usize,
+}
+
+impl StartupHistory {
+    fn push(&mut self, startup: Startup) {
+        self.startups.push(startup);
+
+        let excess = self.startups.len().saturating_sub(HISTORY_SIZE);
+        self.startups.drain(..excess);
+    }
+
+    fn latency(&self, now: SystemTime) -> Option<BootLatency> {
+        let last = self.startups.last()?;
+        let latencies = self.startups.iter().map(|startup| startup.latency_millis);
+
+        let startups_last_hour = self
+            .startups
+            .iter()
+            .filter(|startup| {
+                now.duration_since(startup.started_at)
+                    .map_or(true, |elapsed| elapsed <= RESTART_STORM_WINDOW)
+            })
+            .count();
+
+        Some(BootLatency {
+            last_millis: last.latency_millis,
+            min_millis: latencies.clone().min().unwrap_or_default(),
+            avg_millis: latencies.clone().sum::<u64>() / self.startups.len() as u64,
+            max_millis: latencies.max().unwrap_or_default(),
+            startups_last_hour,
+        })
+    }
+}
+
+/// Time since the process started, from its start time in `/proc/self/stat`.
+pub(crate) fn process_age() -> Option<Duration> {
+    let starttime = procfs::process::Process::myself().ok()?.stat.starttime;
+    let ticks_per_second = procfs::ticks_per_second().ok()?;
+    let uptime = std::fs::read_to_string("/proc/uptime").ok()?;
+
+    age_from_uptime(&uptime, starttime, ticks_per_second)
+}
+
+/// The start time is in clock ticks since boot, `/proc/uptime` in seconds since boot.
+fn age_from_uptime(uptime: &str, starttime: u64, ticks_per_second: i64) -> Option<Duration> {
+    let uptime: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    let started = starttime as f64 / ticks_per_second as f64;
+
+    let age = uptime - started;
+
+    (age.is_finite() && age >= 0.0).then(|| Duration::from_secs_f64(age))
+}
+
+/// Add the current startup, which reached the first publish after `latency`, to the history.
+pub(crate) fn record_startup(
+    repository: &dyn StateRepository<StartupHistory>,
+    now: SystemTime,
+    latency: Duration,
+) -> Result<BootLatency, DeviceManagerError> {
+    let mut history = if repository.exists() {
+        repository.read().unwrap_or_default()
+    } else {
+        StartupHistory::default()
+    };
+
+    history.push(Startup {
+        started_at: now - latency,
+        latency_millis: latency.as_millis() as u64,
+    });
+    repository.write(&history)?;
+
+    Ok(history
+        .latency(now)
+        .expect("the history contains the current startup"))
+}
+
+pub(crate) async fn publish(
+    publisher: &impl Publisher,
+    latency: &BootLatency,
+) -> Result<(), AstarteError> {
+    let values = [
+        ("lastMillis", latency.last_millis as i64),
+        ("minMillis", latency.min_millis as i64),
+        ("avgMillis", latency.avg_millis as i64),
+        ("maxMillis", latency.max_millis as i64),
+        ("startupsLastHour", latency.startups_last_hour as i64),
+    ];
+
+    for (name, value) in values {
+        publisher
+            .send(
+                DIAGNOSTICS_INTERFACE,
+                &format!("/bootToConnected/{name}"),
+                AstarteType::LongInteger(value),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::boot_latency::{
+        age_from_uptime, publish, record_startup, BootLatency, StartupHistory, HISTORY_SIZE,
+    };
+    use crate::data::MockPublisher;
+    use crate::test_utils::MemoryStateRepository;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_650_000_000 + secs)
+    }
+
+    #[test]
+    fn aggregates_across_startups() {
+        let repository = MemoryStateRepository::<StartupHistory>::new();
+
+        let first = record_startup(&repository, at(0), Duration::from_millis(4000)).unwrap();
+        assert_eq!(
+            first,
+            BootLatency {
+                last_millis: 4000,
+                min_millis: 4000,
+                avg_millis: 4000,
+                max_millis: 4000,
+                startups_last_hour: 1,
+            }
+        );
+
+        record_startup(&repository, at(7200), Duration::from_millis(2000)).unwrap();
+        record_startup(&repository, at(7230), Duration::from_millis(1500)).unwrap();
+        let latest = record_startup(&repository, at(7260), Duration::from_millis(2500)).unwrap();
+
+        assert_eq!(
+            latest,
+            BootLatency {
+                last_millis: 2500,
+                min_millis: 1500,
+                avg_millis: 2500,
+                max_millis: 4000,
+                startups_last_hour: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn history_is_a_rolling_window() {
+        let repository = MemoryStateRepository::<StartupHistory>::new();
+
+        record_startup(&repository, at(0), Duration::from_secs(100)).unwrap();
+        let mut latency = None;
+        for i in 1..=HISTORY_SIZE as u64 {
+            latency =
+                Some(record_startup(&repository, at(i * 10), Duration::from_secs(1)).unwrap());
+        }
+
+        // the slow startup fell out of the window
+        let latency = latency.unwrap();
+        assert_eq!(latency.max_millis, 1000);
+        assert_eq!(latency.startups_last_hour, HISTORY_SIZE);
+        assert_eq!(repository.value().unwrap().startups.len(), HISTORY_SIZE);
+    }
+
+    #[test]
+    fn process_age_from_the_start_time() {
+        assert_eq!(
+            age_from_uptime("350.25 1200.00\n", 25_000, 100),
+            Some(Duration::from_millis(100_250))
+        );
+        // started after the reported uptime
+        assert_eq!(age_from_uptime("10.00 20.00\n", 2_000, 100), None);
+        assert_eq!(age_from_uptime("", 2_000, 100), None);
+    }
+
+    #[tokio::test]
+    async fn latency_published_on_diagnostics() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut publisher = MockPublisher::new();
+        let sent_cloned = sent.clone();
+        publisher.expect_send().returning(move |_, path, data| {
+            sent_cloned.lock().unwrap().push((path.to_owned(), data));
+            Ok(())
+        });
+
+        let latency = BootLatency {
+            last_millis: 10,
+            min_millis: 5,
+            avg_millis: 8,
+            max_millis: 12,
+            startups_last_hour: 2,
+        };
+        publish(&publisher, &latency).await.unwrap();
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                (
+                    "/bootToConnected/lastMillis".to_owned(),
+                    AstarteType::LongInteger(10)
+                ),
+                (
+                    "/bootToConnected/minMillis".to_owned(),
+                    AstarteType::LongInteger(5)
+                ),
+                (
+                    "/bootToConnected/avgMillis".to_owned(),
+                    AstarteType::LongInteger(8)
+                ),
+                (
+                    "/bootToConnected/maxMillis".to_owned(),
+                    AstarteType::LongInteger(12)
+                ),
+                (
+                    "/bootToConnected/startupsLastHour".to_owned(),
+                    AstarteType::LongInteger(2)
+                ),
+            ]
+        );
+    }
+}
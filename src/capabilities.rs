@@ -0,0 +1,293 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Startup audit of the permissions needed by the enabled features.
+//!
+//! Running unprivileged, some features can only fail at use time: they are probed without side
+//! effects at startup and disabled when denied, unless denials are configured to be fatal.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use async_trait::async_trait;
+use log::{info, warn};
+#[cfg(test)]
+use mockall::automock;
+use nix::unistd::AccessFlags;
+
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::wrapper::platform::platform;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Reboot,
+    Ota,
+    NetworkSockets,
+    Onboarding,
+}
+
+impl Feature {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Reboot => "reboot",
+            Feature::Ota => "ota",
+            Feature::NetworkSockets => "networkSockets",
+            Feature::Onboarding => "onboarding",
+        }
+    }
+}
+
+/// Non-destructive checks of the permissions.
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait Probe: Send + Sync {
+    /// Ask the power backend whether the reboot is allowed without interaction.
+    async fn can_reboot(&self) -> Result<(), String>;
+    /// Whether the D-Bus services, like RAUC for the OTA, can be reached.
+    fn has_system_bus(&self) -> Result<(), String>;
+    fn can_write(&self, path: &Path) -> Result<(), String>;
+    /// Whether the file descriptors of the other processes can be listed.
+    fn can_read_process_fds(&self) -> Result<(), String>;
+    fn find_executable(&self, name: &str) -> Result<(), String>;
+}
+
+pub struct SystemProbe;
+
+#[async_trait]
+impl Probe for SystemProbe {
+    async fn can_reboot(&self) -> Result<(), String> {
+        platform().power().can_reboot().await
+    }
+
+    fn has_system_bus(&self) -> Result<(), String> {
+        if platform().has_system_bus() {
+            Ok(())
+        } else {
+            Err("no D-Bus system bus".to_owned())
+        }
+    }
+
+    fn can_write(&self, path: &Path) -> Result<(), String> {
+        nix::unistd::access(path, AccessFlags::W_OK)
+            .map_err(|err| format!("{} is not writable: {}", path.display(), err))
+    }
+
+    fn can_read_process_fds(&self) -> Result<(), String> {
+        std::fs::read_dir("/proc/1/fd")
+            .map(|_| ())
+            .map_err(|err| format!("/proc/1/fd is not readable: {err}"))
+    }
+
+    fn find_executable(&self, name: &str) -> Result<(), String> {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        std::env::split_paths(&path)
+            .map(|directory| directory.join(name))
+            .find(|candidate| nix::unistd::access(candidate, AccessFlags::X_OK).is_ok())
+            .map(|_| ())
+            .ok_or_else(|| format!("{name} not found in PATH"))
+    }
+}
+
+/// What the audit has to check.
+pub struct AuditTarget {
+    pub store_directory: PathBuf,
+    pub download_directory: PathBuf,
+    pub onboarding: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapabilityReport {
+    entries: Vec<(Feature, Result<(), String>)>,
+}
+
+impl CapabilityReport {
+    /// Whether `feature` is enabled and usable.
+    pub fn is_available(&self, feature: Feature) -> bool {
+        self.entries
+            .iter()
+            .any(|(entry, result)| *entry == feature && result.is_ok())
+    }
+
+    pub fn denied(&self) -> impl Iterator<Item = (Feature, &str)> {
+        self.entries.iter().filter_map(|(feature, result)| {
+            result.as_ref().err().map(|err| (*feature, err.as_str()))
+        })
+    }
+
+    pub fn table(&self) -> String {
+        let mut table = String::from("Capabilities:");
+        for (feature, result) in &self.entries {
+            match result {
+                Ok(()) => write!(table, "\n  {:<16} available", feature.name()),
+                Err(reason) => write!(table, "\n  {:<16} denied ({reason})", feature.name()),
+            }
+            .unwrap();
+        }
+
+        table
+    }
+
+    /// Publish the availability of each feature on `/capabilities/{feature}`.
+    pub async fn publish(&self, publisher: &impl Publisher) -> Result<(), AstarteError> {
+        for (feature, result) in &self.entries {
+            publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    &format!("/capabilities/{}", feature.name()),
+                    AstarteType::Boolean(result.is_ok()),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl CapabilityReport {
+    /// Report with only `features` available.
+    pub(crate) fn available(features: &[Feature]) -> Self {
+        CapabilityReport {
+            entries: features.iter().map(|feature| (*feature, Ok(()))).collect(),
+        }
+    }
+}
+
+/// Probe the enabled features, failing on the first denial when `fatal` is set.
+pub async fn audit(
+    probe: &dyn Probe,
+    target: &AuditTarget,
+    fatal: bool,
+) -> Result<CapabilityReport, DeviceManagerError> {
+    let mut entries = vec![
+        (Feature::Reboot, probe.can_reboot().await),
+        (
+            Feature::Ota,
+            probe
+                .can_write(&target.store_directory)
+                .and_then(|()| probe.can_write(&target.download_directory))
+                .and_then(|()| probe.has_system_bus()),
+        ),
+        (Feature::NetworkSockets, probe.can_read_process_fds()),
+    ];
+    if target.onboarding {
+        entries.push((Feature::Onboarding, probe.find_executable("nmcli")));
+    }
+
+    let report = CapabilityReport { entries };
+    info!("{}", report.table());
+
+    for (feature, reason) in report.denied() {
+        if fatal {
+            return Err(DeviceManagerError::CapabilityDenied(format!(
+                "{}: {reason}",
+                feature.name()
+            )));
+        }
+
+        warn!("Disabling {}: {reason}", feature.name());
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::capabilities::{audit, AuditTarget, Feature, MockProbe};
+    use crate::error::DeviceManagerError;
+
+    fn target(onboarding: bool) -> AuditTarget {
+        AuditTarget {
+            store_directory: PathBuf::from("/var/lib/edgehog"),
+            download_directory: PathBuf::from("/var/tmp/edgehog-updates"),
+            onboarding,
+        }
+    }
+
+    fn unprivileged_probe() -> MockProbe {
+        let mut probe = MockProbe::new();
+        probe
+            .expect_can_reboot()
+            .returning(|| Err("logind requires an interactive authorization".to_owned()));
+        probe.expect_can_write().returning(|path: &Path| {
+            if path == Path::new("/var/tmp/edgehog-updates") {
+                Err("not writable".to_owned())
+            } else {
+                Ok(())
+            }
+        });
+        probe.expect_can_read_process_fds().returning(|| Ok(()));
+        probe.expect_has_system_bus().returning(|| Ok(()));
+        probe
+    }
+
+    #[tokio::test]
+    async fn denied_features_disabled() {
+        let mut probe = unprivileged_probe();
+        probe.expect_find_executable().never();
+
+        let report = audit(&probe, &target(false), false).await.unwrap();
+
+        assert!(!report.is_available(Feature::Reboot));
+        assert!(!report.is_available(Feature::Ota));
+        assert!(report.is_available(Feature::NetworkSockets));
+        // not enabled, not probed
+        assert!(!report.is_available(Feature::Onboarding));
+        assert_eq!(
+            report
+                .denied()
+                .map(|(feature, _)| feature)
+                .collect::<Vec<_>>(),
+            vec![Feature::Reboot, Feature::Ota]
+        );
+        assert!(report
+            .table()
+            .contains("ota              denied (not writable)"));
+    }
+
+    #[tokio::test]
+    async fn enabled_onboarding_probed() {
+        let mut probe = unprivileged_probe();
+        probe
+            .expect_find_executable()
+            .withf(|name| name == "nmcli")
+            .returning(|_| Ok(()));
+
+        let report = audit(&probe, &target(true), false).await.unwrap();
+        assert!(report.is_available(Feature::Onboarding));
+    }
+
+    #[tokio::test]
+    async fn denial_fatal_when_configured() {
+        let probe = unprivileged_probe();
+
+        let err = audit(&probe, &target(false), true).await.unwrap_err();
+        assert!(matches!(
+            err,
+            DeviceManagerError::CapabilityDenied(reason) if reason.starts_with("reboot:")
+        ));
+    }
+}
@@ -0,0 +1,596 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Resumable uploads of large files in chunks.
+//!
+//! A single PUT of a few hundred MB never completes on a flaky link. The file is sent in chunks,
+//! either with sequential PUTs carrying the `Content-Range` of each chunk or as the parts of an
+//! S3 multipart upload, one presigned URL each. Every chunk is retried with a backoff and the
+//! acknowledged ones are persisted, so that an upload interrupted by a restart goes on from the
+//! last of them.
+
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use openssl::sha::Sha256;
+use reqwest::header::{CONTENT_RANGE, ETAG};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::clock::Clock;
+use crate::error::DeviceManagerError;
+use crate::redaction::redactor;
+use crate::repository::StateRepository;
+
+/// Attempts of each chunk, the waits between them double from a second.
+const CHUNK_ATTEMPTS: u32 = 5;
+
+/// Where the chunks of a file go.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "mode")]
+pub enum UploadTarget {
+    /// Sequential PUTs to `url`, each with the `Content-Range` of its chunk.
+    ContentRange { url: String, chunk_bytes: u64 },
+    /// One PUT to each presigned URL, all the parts but the last are `part_bytes` long.
+    Multipart {
+        part_urls: Vec<String>,
+        part_bytes: u64,
+    },
+}
+
+impl UploadTarget {
+    /// Split `total_bytes` over `part_urls`, in parts of `part_bytes` when set.
+    pub fn multipart(
+        part_urls: Vec<String>,
+        part_bytes: Option<u64>,
+        total_bytes: u64,
+    ) -> Result<Self, DeviceManagerError> {
+        let parts = part_urls.len() as u64;
+        if parts == 0 {
+            return Err(DeviceManagerError::UploadError("no part URLs".to_owned()));
+        }
+
+        let part_bytes = part_bytes
+            .filter(|bytes| *bytes > 0)
+            .unwrap_or_else(|| div_ceil(total_bytes, parts).max(1));
+        let needed = div_ceil(total_bytes, part_bytes).max(1);
+        if needed != parts {
+            return Err(DeviceManagerError::UploadError(format!(
+                "{parts} part URLs for {needed} parts"
+            )));
+        }
+
+        Ok(UploadTarget::Multipart {
+            part_urls,
+            part_bytes,
+        })
+    }
+
+    fn chunk_bytes(&self) -> u64 {
+        match self {
+            UploadTarget::ContentRange { chunk_bytes, .. } => *chunk_bytes,
+            UploadTarget::Multipart { part_bytes, .. } => *part_bytes,
+        }
+        .max(1)
+    }
+}
+
+fn div_ceil(numerator: u64, denominator: u64) -> u64 {
+    numerator / denominator + u64::from(numerator % denominator != 0)
+}
+
+/// Upload of a file, persisted after each acknowledged chunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSession {
+    pub id: String,
+    pub target: UploadTarget,
+    pub total_bytes: u64,
+    /// Chunks acknowledged by the server, in order.
+    pub acknowledged: u64,
+    /// ETags of the acknowledged parts, needed to complete a multipart upload.
+    pub etags: Vec<String>,
+}
+
+impl UploadSession {
+    fn chunks(&self) -> u64 {
+        div_ceil(self.total_bytes, self.target.chunk_bytes())
+    }
+
+    fn range(&self, chunk: u64) -> Range<u64> {
+        let start = chunk * self.target.chunk_bytes();
+
+        start..(start + self.target.chunk_bytes()).min(self.total_bytes)
+    }
+
+    pub fn uploaded_bytes(&self) -> u64 {
+        (self.acknowledged * self.target.chunk_bytes()).min(self.total_bytes)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.acknowledged >= self.chunks()
+    }
+}
+
+/// Outcome of a completed upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedFile {
+    /// Hex SHA-256 digest of the whole file, for the server to check the reassembly.
+    pub sha256: String,
+    pub etags: Vec<String>,
+}
+
+/// Failure of a chunk, only the transient ones are retried.
+enum ChunkFailure {
+    Transient(String),
+    Rejected(String),
+}
+
+pub struct ChunkedUploader {
+    client: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    sessions: Box<dyn StateRepository<UploadSession>>,
+}
+
+impl ChunkedUploader {
+    pub fn new(
+        client: reqwest::Client,
+        clock: Arc<dyn Clock>,
+        sessions: Box<dyn StateRepository<UploadSession>>,
+    ) -> Self {
+        ChunkedUploader {
+            client,
+            clock,
+            sessions,
+        }
+    }
+
+    /// The upload interrupted by the last shutdown, if any.
+    pub fn pending(&self) -> Option<UploadSession> {
+        if !self.sessions.exists() {
+            return None;
+        }
+
+        match self.sessions.read() {
+            Ok(session) => Some(session),
+            Err(err) => {
+                warn!("Unable to read the upload session: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Start the upload `id` of `total_bytes` to `target`, going on from the persisted session
+    /// of the same upload.
+    pub fn session(&self, id: &str, total_bytes: u64, target: UploadTarget) -> UploadSession {
+        match self.pending() {
+            Some(session)
+                if session.id == id
+                    && session.total_bytes == total_bytes
+                    && session.target == target =>
+            {
+                info!(
+                    "Resuming the upload of {id} from {} bytes",
+                    session.uploaded_bytes()
+                );
+                session
+            }
+            _ => UploadSession {
+                id: id.to_owned(),
+                target,
+                total_bytes,
+                acknowledged: 0,
+                etags: Vec::new(),
+            },
+        }
+    }
+
+    /// Send the next chunk of `session` from the file at `path`, persisting the session once the
+    /// server acknowledged it. A session whose chunk was rejected is dropped.
+    pub async fn send_chunk(
+        &self,
+        session: &mut UploadSession,
+        path: &Path,
+    ) -> Result<(), DeviceManagerError> {
+        let chunk = session.acknowledged;
+        let range = session.range(chunk);
+        let body = read_range(path, range.clone()).await?;
+
+        let mut attempt = 0;
+        let etag = loop {
+            match self.put(session, chunk, &range, body.clone()).await {
+                Ok(etag) => break etag,
+                Err(ChunkFailure::Transient(reason)) if attempt + 1 < CHUNK_ATTEMPTS => {
+                    let wait = Duration::from_secs(2_u64.pow(attempt));
+                    warn!(
+                        "Chunk {chunk} of {} failed ({reason}), next attempt in {}s",
+                        session.id,
+                        wait.as_secs()
+                    );
+                    self.clock.sleep(wait).await;
+                    attempt += 1;
+                }
+                Err(ChunkFailure::Transient(reason)) => {
+                    return Err(DeviceManagerError::UploadError(format!(
+                        "chunk {chunk} failed: {reason}"
+                    )));
+                }
+                Err(ChunkFailure::Rejected(reason)) => {
+                    self.clear();
+                    return Err(DeviceManagerError::UploadError(format!(
+                        "chunk {chunk} rejected: {reason}"
+                    )));
+                }
+            }
+        };
+
+        session.acknowledged += 1;
+        if let UploadTarget::Multipart { .. } = session.target {
+            session.etags.push(etag.unwrap_or_default());
+        }
+        if let Err(err) = self.sessions.write(session) {
+            warn!("Unable to persist the upload session: {:?}", err);
+        }
+
+        Ok(())
+    }
+
+    /// Digest the uploaded file and drop the session.
+    pub async fn complete(
+        &self,
+        session: UploadSession,
+        path: &Path,
+    ) -> Result<UploadedFile, DeviceManagerError> {
+        let sha256 = digest(path).await?;
+        self.clear();
+
+        Ok(UploadedFile {
+            sha256,
+            etags: session.etags,
+        })
+    }
+
+    /// Drop the persisted session, e.g. once the file is gone.
+    pub fn clear(&self) {
+        if self.sessions.exists() {
+            if let Err(err) = self.sessions.clear() {
+                warn!("Unable to clear the upload session: {:?}", err);
+            }
+        }
+    }
+
+    /// PUT the chunk `chunk` of `session`, returning the ETag of the part.
+    async fn put(
+        &self,
+        session: &UploadSession,
+        chunk: u64,
+        range: &Range<u64>,
+        body: Vec<u8>,
+    ) -> Result<Option<String>, ChunkFailure> {
+        let request = match &session.target {
+            UploadTarget::ContentRange { url, .. } => self.client.put(url).header(
+                CONTENT_RANGE,
+                format!(
+                    "bytes {}-{}/{}",
+                    range.start,
+                    range.end - 1,
+                    session.total_bytes
+                ),
+            ),
+            UploadTarget::Multipart { part_urls, .. } => {
+                let url = part_urls.get(chunk as usize).ok_or_else(|| {
+                    ChunkFailure::Rejected(format!("no URL for the part {chunk}"))
+                })?;
+                self.client.put(url)
+            }
+        };
+
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| ChunkFailure::Transient(redactor().text(&err.to_string())))?;
+
+        let status = response.status();
+        // 308 is how the resumable upload APIs acknowledge a chunk but the last
+        if status.is_success() || status == StatusCode::PERMANENT_REDIRECT {
+            Ok(response
+                .headers()
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(str::to_owned))
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(ChunkFailure::Transient(status.to_string()))
+        } else {
+            Err(ChunkFailure::Rejected(status.to_string()))
+        }
+    }
+}
+
+async fn read_range(path: &Path, range: Range<u64>) -> Result<Vec<u8>, DeviceManagerError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(std::io::SeekFrom::Start(range.start)).await?;
+
+    let mut chunk = vec![0; (range.end - range.start) as usize];
+    file.read_exact(&mut chunk).await?;
+
+    Ok(chunk)
+}
+
+async fn digest(path: &Path) -> Result<String, DeviceManagerError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use openssl::sha::sha256;
+
+    use crate::chunked_upload::{ChunkedUploader, UploadSession, UploadTarget};
+    use crate::repository::StateRepository;
+    use crate::test_utils::{settle, ManualClock, MemoryStateRepository};
+
+    /// Parts received by the server, by their offset or their part number.
+    pub(crate) type Received = Arc<Mutex<BTreeMap<u64, Vec<u8>>>>;
+
+    /// Accept the chunks on any path, failing the requests whose 0-based index is listed in
+    /// `failures` with a 503. The multipart parts are numbered by the last segment of the path.
+    pub(crate) fn parts_server(failures: Vec<usize>) -> (SocketAddr, Received) {
+        let received = Received::default();
+        let requests = Arc::new(Mutex::new(0));
+
+        let stored = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let stored = stored.clone();
+            let requests = requests.clone();
+            let failures = failures.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let stored = stored.clone();
+                    let index = {
+                        let mut requests = requests.lock().unwrap();
+                        *requests += 1;
+                        *requests - 1
+                    };
+                    let failing = failures.contains(&index);
+                    async move {
+                        let key = match request.headers().get("Content-Range") {
+                            Some(range) => range
+                                .to_str()
+                                .unwrap()
+                                .trim_start_matches("bytes ")
+                                .split('-')
+                                .next()
+                                .unwrap()
+                                .parse()
+                                .unwrap(),
+                            None => request
+                                .uri()
+                                .path()
+                                .rsplit('/')
+                                .next()
+                                .unwrap()
+                                .parse()
+                                .unwrap(),
+                        };
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        if failing {
+                            let response = Response::builder()
+                                .status(StatusCode::SERVICE_UNAVAILABLE)
+                                .body(Body::empty());
+                            return Ok::<_, Infallible>(response.unwrap());
+                        }
+
+                        stored.lock().unwrap().insert(key, body.to_vec());
+                        let response = Response::builder()
+                            .header("ETag", format!("\"part-{key}\""))
+                            .body(Body::empty());
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, received)
+    }
+
+    fn artifact(directory: &tempfile::TempDir, length: usize) -> (std::path::PathBuf, Vec<u8>) {
+        let content: Vec<u8> = (0..length).map(|index| (index % 251) as u8).collect();
+        let path = directory.path().join("core");
+        std::fs::write(&path, &content).unwrap();
+
+        (path, content)
+    }
+
+    fn hex(digest: [u8; 32]) -> String {
+        digest.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn multipart_split_over_the_urls() {
+        let urls = |count: usize| (0..count).map(|part| format!("http://s3/{part}")).collect();
+
+        assert_eq!(
+            UploadTarget::multipart(urls(3), None, 10).unwrap(),
+            UploadTarget::Multipart {
+                part_urls: urls(3),
+                part_bytes: 4,
+            }
+        );
+        assert!(UploadTarget::multipart(urls(3), Some(5), 10).is_err());
+        assert!(UploadTarget::multipart(Vec::new(), None, 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_chunks_retried_and_reassembled() {
+        let clock = Arc::new(ManualClock::new());
+        let directory = tempfile::tempdir().unwrap();
+        let (path, content) = artifact(&directory, 10_000);
+        // the second chunk fails twice
+        let (address, received) = parts_server(vec![1, 2]);
+        let sessions = Arc::new(MemoryStateRepository::<UploadSession>::new());
+        let uploader = ChunkedUploader::new(
+            reqwest::Client::new(),
+            clock.clone(),
+            Box::new(sessions.clone()),
+        );
+
+        let mut session = uploader.session(
+            "report",
+            content.len() as u64,
+            UploadTarget::ContentRange {
+                url: format!("http://{address}/core"),
+                chunk_bytes: 4096,
+            },
+        );
+        let uploading = tokio::spawn(async move {
+            let mut uploaded = Vec::new();
+            while !session.is_complete() {
+                uploader.send_chunk(&mut session, &path).await.unwrap();
+                uploaded.push(session.uploaded_bytes());
+            }
+            (uploaded, uploader.complete(session, &path).await.unwrap())
+        });
+        for _ in 0..2 {
+            settle().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            clock.advance(Duration::from_secs(2));
+        }
+        let (uploaded, file) = uploading.await.unwrap();
+
+        assert_eq!(uploaded, [4096, 8192, 10_000]);
+        let reassembled: Vec<u8> = received
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(reassembled, content);
+        assert_eq!(file.sha256, hex(sha256(&content)));
+        assert!(file.etags.is_empty());
+        assert!(!sessions.exists());
+    }
+
+    #[tokio::test]
+    async fn restart_resumes_from_the_acknowledged_part() {
+        let clock = Arc::new(ManualClock::new());
+        let directory = tempfile::tempdir().unwrap();
+        let (path, content) = artifact(&directory, 10_000);
+        let (address, received) = parts_server(Vec::new());
+        let sessions = Arc::new(MemoryStateRepository::<UploadSession>::new());
+        let target = UploadTarget::multipart(
+            (1..=3)
+                .map(|part| format!("http://{address}/parts/{part}"))
+                .collect(),
+            Some(4096),
+            content.len() as u64,
+        )
+        .unwrap();
+
+        let uploader = ChunkedUploader::new(
+            reqwest::Client::new(),
+            clock.clone(),
+            Box::new(sessions.clone()),
+        );
+        let mut session = uploader.session("report", content.len() as u64, target.clone());
+        uploader.send_chunk(&mut session, &path).await.unwrap();
+        drop(uploader);
+        received.lock().unwrap().clear();
+
+        // after the restart only the missing parts are sent
+        let uploader =
+            ChunkedUploader::new(reqwest::Client::new(), clock, Box::new(sessions.clone()));
+        let mut session = uploader.pending().unwrap();
+        assert_eq!(
+            uploader.session("report", content.len() as u64, target),
+            session
+        );
+        assert_eq!(session.uploaded_bytes(), 4096);
+        while !session.is_complete() {
+            uploader.send_chunk(&mut session, &path).await.unwrap();
+        }
+        let file = uploader.complete(session, &path).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.keys().copied().collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(received[&3], content[8192..]);
+        assert_eq!(file.etags, ["\"part-1\"", "\"part-2\"", "\"part-3\""]);
+        assert_eq!(file.sha256, hex(sha256(&content)));
+    }
+
+    #[tokio::test]
+    async fn rejected_chunk_drops_the_session() {
+        let clock = Arc::new(ManualClock::new());
+        let directory = tempfile::tempdir().unwrap();
+        let (path, content) = artifact(&directory, 100);
+        let sessions = Arc::new(MemoryStateRepository::<UploadSession>::new());
+        let uploader =
+            ChunkedUploader::new(reqwest::Client::new(), clock, Box::new(sessions.clone()));
+        let mut session = uploader.session(
+            "report",
+            content.len() as u64,
+            UploadTarget::Multipart {
+                part_urls: Vec::new(),
+                part_bytes: 100,
+            },
+        );
+        sessions.write(&session).unwrap();
+
+        let err = uploader.send_chunk(&mut session, &path).await.unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "upload failed: chunk 0 rejected: no URL for the part 0"
+        );
+        assert!(!sessions.exists());
+    }
+}
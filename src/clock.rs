@@ -0,0 +1,63 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+/// Source of time used by every scheduler in the runtime, so that timers can be driven
+/// deterministically in tests.
+#[async_trait]
+pub trait Clock: Send + Sync {
+    /// Current wall clock time, used for timestamps and calendar based decisions.
+    fn now_wall(&self) -> SystemTime;
+
+    /// Current monotonic time, used to measure intervals and compute deadlines.
+    fn now_monotonic(&self) -> Instant;
+
+    /// Wait until the monotonic clock reaches `deadline`.
+    async fn sleep_until(&self, deadline: Instant);
+
+    /// Wait for `duration` to elapse on the monotonic clock.
+    async fn sleep(&self, duration: Duration) {
+        let deadline = self.now_monotonic() + duration;
+        self.sleep_until(deadline).await
+    }
+}
+
+/// Production clock backed by the system time and the tokio timer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now_wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn now_monotonic(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline).await
+    }
+}
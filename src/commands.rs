@@ -18,16 +18,306 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use log::error;
+//! Commands of `io.edgehog.devicemanager.Commands` and the acknowledgement of their results.
+//!
+//! A command can end with `requestId=<id>`, echoed in its result on
+//! `io.edgehog.devicemanager.CommandResult` so that the backend can match the two. The failures
+//! are what matters, a successful reboot can take the device down before its result is sent.
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::custom_commands::CommandOutput;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::COMMAND_RESULT_INTERFACE;
+use crate::power_management::PowerActions;
+use crate::redaction::redactor;
+
+const REQUEST_ID_PREFIX: &str = "requestId=";
+/// Built in commands waiting for the runner, the ones beyond are rejected.
+pub const COMMAND_QUEUE_CAPACITY: usize = 4;
+/// Run by the device manager, the other commands can be custom ones.
+pub const BUILTIN_COMMANDS: [&str; 2] = ["Reboot", "Shutdown"];
+
+/// A command as received, without its request id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandRequest {
+    pub command: String,
+    pub request_id: Option<String>,
+}
+
+impl CommandRequest {
+    /// Split `<command> [args] [requestId=<id>]`.
+    pub fn parse(command: &str) -> Self {
+        let mut words: Vec<&str> = command.split_whitespace().collect();
+        let request_id = match words.last() {
+            Some(last) if last.starts_with(REQUEST_ID_PREFIX) => words
+                .pop()
+                .and_then(|last| last.strip_prefix(REQUEST_ID_PREFIX))
+                .filter(|request_id| !request_id.is_empty())
+                .map(str::to_owned),
+            _ => None,
+        };
+
+        CommandRequest {
+            command: words.join(" "),
+            request_id,
+        }
+    }
+
+    /// Name of the command, without its arguments.
+    pub fn name(&self) -> &str {
+        self.command.split_whitespace().next().unwrap_or_default()
+    }
+}
+
+/// Published on `/result` of `io.edgehog.devicemanager.CommandResult`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandResult {
+    pub command: String,
+    pub success: bool,
+    /// Why the command failed, empty on success.
+    pub error: String,
+    /// The request id of the command, empty without one.
+    pub request_id: String,
+    /// Set for the custom commands that ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+}
+
+impl CommandResult {
+    pub fn new(request: &CommandRequest, result: &Result<(), DeviceManagerError>) -> Self {
+        CommandResult {
+            command: request.name().to_owned(),
+            success: result.is_ok(),
+            error: result
+                .as_ref()
+                .err()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            request_id: request.request_id.clone().unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_output(mut self, output: CommandOutput) -> Self {
+        self.exit_code = output.exit_code;
+        self.stdout = Some(output.stdout);
+        self.stderr = Some(output.stderr);
+        self
+    }
+}
+
+/// Runner of the built in commands, off the dispatch of the messages so that a slow power
+/// action, e.g. a reboot waiting on polkit, doesn't hold back the other requests.
+pub struct CommandRunner {
+    power: Box<dyn PowerActions>,
+}
+
+impl CommandRunner {
+    pub fn new(power: Box<dyn PowerActions>) -> Self {
+        CommandRunner { power }
+    }
+
+    /// Run the commands of `requests` one at a time, acknowledging each on `results`.
+    pub async fn run(
+        self,
+        mut requests: mpsc::Receiver<CommandRequest>,
+        results: mpsc::Sender<CommandResult>,
+    ) {
+        while let Some(request) = requests.recv().await {
+            let result = execute_command(&request.command, self.power.as_ref()).await;
+            results
+                .send(CommandResult::new(&request, &result))
+                .await
+                .unwrap_or_else(|_| warn!("The command results stopped"));
+        }
+    }
+}
 
 /// handle io.edgehog.devicemanager.Commands
-pub(crate) fn execute_command(command: &str) {
+pub(crate) async fn execute_command(
+    command: &str,
+    power: &dyn PowerActions,
+) -> Result<(), DeviceManagerError> {
     match command {
-        "Reboot" => {
-            crate::power_management::reboot().unwrap();
-        }
+        "Reboot" => power.reboot().await,
+        // refused while an update writes to flash
+        "Shutdown" => power.shutdown().await,
         _ => {
-            error!("command not recognized");
+            error!("command {} not recognized", redactor().text(command));
+
+            Err(DeviceManagerError::InvalidCommand(command.to_owned()))
+        }
+    }
+}
+
+/// Publish the results of the commands, a failed publish is only logged.
+pub async fn publish_results(
+    publisher: &impl Publisher,
+    mut results: mpsc::Receiver<CommandResult>,
+) {
+    while let Some(result) = results.recv().await {
+        if result.success {
+            info!("Command {} succeeded", result.command);
+        } else {
+            warn!("Command {} failed: {}", result.command, result.error);
         }
+
+        if let Err(err) = publisher
+            .send_object(COMMAND_RESULT_INTERFACE, "/result", result)
+            .await
+        {
+            warn!("Unable to publish the command result: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use astarte_sdk::AstarteError;
+    use tokio::sync::mpsc;
+
+    use crate::commands::{
+        execute_command, publish_results, CommandRequest, CommandResult, CommandRunner,
+    };
+    use crate::data::MockPublisher;
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::COMMAND_RESULT_INTERFACE;
+    use crate::power_management::MockPowerActions;
+
+    #[test]
+    fn request_id_split() {
+        assert_eq!(
+            CommandRequest::parse("Reboot 600 requestId=42"),
+            CommandRequest {
+                command: "Reboot 600".to_owned(),
+                request_id: Some("42".to_owned()),
+            }
+        );
+        assert_eq!(
+            CommandRequest::parse("Shutdown"),
+            CommandRequest {
+                command: "Shutdown".to_owned(),
+                request_id: None,
+            }
+        );
+        assert_eq!(CommandRequest::parse("Reboot requestId=").request_id, None);
+        assert_eq!(
+            CommandRequest::parse("Reboot 600 requestId=42").name(),
+            "Reboot"
+        );
+    }
+
+    #[tokio::test]
+    async fn power_commands_requested() {
+        let mut power = MockPowerActions::new();
+        power.expect_reboot().times(1).returning(|| Ok(()));
+        power
+            .expect_shutdown()
+            .times(1)
+            .returning(|| Err(DeviceManagerError::FlashWriteInProgress));
+
+        assert!(execute_command("Reboot", &power).await.is_ok());
+        assert!(matches!(
+            execute_command("Shutdown", &power).await,
+            Err(DeviceManagerError::FlashWriteInProgress)
+        ));
+        assert!(matches!(
+            execute_command("PowerCycle", &power).await,
+            Err(DeviceManagerError::InvalidCommand(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn runner_acknowledges_commands() {
+        let mut power = MockPowerActions::new();
+        power.expect_reboot().times(1).returning(|| Ok(()));
+        let (requests_tx, requests) = mpsc::channel(2);
+        let (results_tx, mut results) = mpsc::channel(2);
+        requests_tx
+            .send(CommandRequest::parse("Reboot requestId=42"))
+            .await
+            .unwrap();
+        requests_tx
+            .send(CommandRequest::parse("PowerCycle"))
+            .await
+            .unwrap();
+        drop(requests_tx);
+
+        CommandRunner::new(Box::new(power))
+            .run(requests, results_tx)
+            .await;
+
+        let reboot = results.recv().await.unwrap();
+        assert!(reboot.success);
+        assert_eq!(reboot.request_id, "42");
+        let unknown = results.recv().await.unwrap();
+        assert_eq!(unknown.error, "invalid command: PowerCycle");
+        assert!(results.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn results_published() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object()
+            .withf(|interface: &str, path: &str, _: &CommandResult| {
+                interface == COMMAND_RESULT_INTERFACE && path == "/result"
+            })
+            .returning(move |_, _, result: CommandResult| {
+                recorded.lock().unwrap().push(result);
+                // a failed publish doesn't stop the next ones
+                Err(AstarteError::SendError("broker unreachable".to_owned()))
+            });
+
+        let (results_tx, results) = mpsc::channel(2);
+        results_tx
+            .send(CommandResult::new(
+                &CommandRequest::parse("Reboot requestId=42"),
+                &Ok(()),
+            ))
+            .await
+            .unwrap();
+        results_tx
+            .send(CommandResult::new(
+                &CommandRequest::parse("Shutdown"),
+                &Err(DeviceManagerError::FlashWriteInProgress),
+            ))
+            .await
+            .unwrap();
+        drop(results_tx);
+        publish_results(&publisher, results).await;
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                CommandResult {
+                    command: "Reboot".to_owned(),
+                    success: true,
+                    error: String::new(),
+                    request_id: "42".to_owned(),
+                    ..Default::default()
+                },
+                CommandResult {
+                    command: "Shutdown".to_owned(),
+                    success: false,
+                    error: "an OTA update is writing to flash".to_owned(),
+                    request_id: String::new(),
+                    ..Default::default()
+                },
+            ]
+        );
     }
 }
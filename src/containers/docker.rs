@@ -0,0 +1,232 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Container engine reached through the local Docker socket.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use bollard::auth::DockerCredentials;
+use bollard::container::{
+    Config, CreateContainerOptions, InspectContainerOptions, ListContainersOptions,
+    RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
+};
+use bollard::image::CreateImageOptions;
+use bollard::models::{HostConfig, RestartPolicyNameEnum};
+use bollard::Docker;
+use futures_util::StreamExt;
+use tokio::sync::watch;
+
+use crate::containers::manager::{ContainerEngine, ContainerInfo, ContainerSpec};
+use crate::containers::{RegistryCredentials, RestartPolicy};
+use crate::error::DeviceManagerError;
+
+/// Label of the containers created by the runtime, the only ones it acts on.
+const MANAGED_LABEL: &str = "io.edgehog.managed";
+
+pub struct DockerEngine {
+    docker: Docker,
+}
+
+impl DockerEngine {
+    pub fn connect() -> Result<Self, DeviceManagerError> {
+        let docker = Docker::connect_with_local_defaults().map_err(container_error)?;
+        Ok(DockerEngine { docker })
+    }
+}
+
+#[async_trait]
+impl ContainerEngine for DockerEngine {
+    async fn pull(
+        &self,
+        image: &str,
+        credentials: Option<RegistryCredentials>,
+        progress: watch::Sender<u8>,
+    ) -> Result<(), DeviceManagerError> {
+        let options = CreateImageOptions {
+            from_image: image,
+            ..Default::default()
+        };
+        let credentials = credentials.map(|credentials| DockerCredentials {
+            username: Some(credentials.username),
+            password: Some(credentials.password),
+            ..Default::default()
+        });
+
+        // bytes pulled and total of each layer
+        let mut layers: HashMap<String, (i64, i64)> = HashMap::new();
+        let mut pull = self.docker.create_image(Some(options), None, credentials);
+        while let Some(info) = pull.next().await {
+            let info = info.map_err(container_error)?;
+            let layer = match info.id {
+                Some(layer) => layer,
+                None => continue,
+            };
+            let detail = info.progress_detail.unwrap_or_default();
+            match (info.status.as_deref(), detail.current, detail.total) {
+                (Some("Pull complete" | "Already exists"), _, _) => {
+                    if let Some((current, total)) = layers.get_mut(&layer) {
+                        *current = *total;
+                    }
+                }
+                (_, Some(current), Some(total)) if total > 0 => {
+                    layers.insert(layer, (current, total));
+                }
+                _ => {}
+            }
+
+            let (current, total) = layers.values().fold((0, 0), |(current, total), layer| {
+                (current + layer.0, total + layer.1)
+            });
+            if total > 0 {
+                progress.send_replace((current.min(total) * 100 / total) as u8);
+            }
+        }
+
+        progress.send_replace(100);
+        Ok(())
+    }
+
+    async fn inspect(&self, name: &str) -> Result<Option<ContainerInfo>, DeviceManagerError> {
+        let container = match self
+            .docker
+            .inspect_container(name, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(container) => container,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(None),
+            Err(err) => return Err(container_error(err)),
+        };
+
+        let config = container.config.unwrap_or_default();
+        Ok(Some(ContainerInfo {
+            name: name.to_owned(),
+            image: config.image.unwrap_or_default(),
+            state: container
+                .state
+                .and_then(|state| state.status)
+                .map(|status| status.to_string())
+                .unwrap_or_default(),
+            managed: is_managed(config.labels.as_ref()),
+        }))
+    }
+
+    async fn create(&self, spec: &ContainerSpec) -> Result<(), DeviceManagerError> {
+        let restart_policy = match spec.restart_policy {
+            RestartPolicy::No => RestartPolicyNameEnum::NO,
+            RestartPolicy::Always => RestartPolicyNameEnum::ALWAYS,
+            RestartPolicy::UnlessStopped => RestartPolicyNameEnum::UNLESS_STOPPED,
+            RestartPolicy::OnFailure => RestartPolicyNameEnum::ON_FAILURE,
+        };
+        let config = Config {
+            image: Some(spec.image.clone()),
+            env: Some(spec.env.clone()),
+            labels: Some(HashMap::from([(
+                MANAGED_LABEL.to_owned(),
+                "true".to_owned(),
+            )])),
+            host_config: Some(HostConfig {
+                restart_policy: Some(bollard::models::RestartPolicy {
+                    name: Some(restart_policy),
+                    maximum_retry_count: None,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        self.docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: spec.name.as_str(),
+                }),
+                config,
+            )
+            .await
+            .map_err(container_error)?;
+        Ok(())
+    }
+
+    async fn start(&self, name: &str) -> Result<(), DeviceManagerError> {
+        self.docker
+            .start_container(name, None::<StartContainerOptions<String>>)
+            .await
+            .map_err(container_error)
+    }
+
+    async fn stop(&self, name: &str) -> Result<(), DeviceManagerError> {
+        self.docker
+            .stop_container(name, None::<StopContainerOptions>)
+            .await
+            .map_err(container_error)
+    }
+
+    async fn remove(&self, name: &str) -> Result<(), DeviceManagerError> {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        self.docker
+            .remove_container(name, Some(options))
+            .await
+            .map_err(container_error)
+    }
+
+    async fn list_managed(&self) -> Result<Vec<ContainerInfo>, DeviceManagerError> {
+        let label = format!("{MANAGED_LABEL}=true");
+        let options = ListContainersOptions {
+            all: true,
+            filters: HashMap::from([("label", vec![label.as_str()])]),
+            ..Default::default()
+        };
+        let containers = self
+            .docker
+            .list_containers(Some(options))
+            .await
+            .map_err(container_error)?;
+
+        Ok(containers
+            .into_iter()
+            .filter(|container| is_managed(container.labels.as_ref()))
+            .filter_map(|container| {
+                // the names are reported with a leading slash
+                let name = container.names?.first()?.trim_start_matches('/').to_owned();
+                Some(ContainerInfo {
+                    name,
+                    image: container.image.unwrap_or_default(),
+                    state: container.state.unwrap_or_default(),
+                    managed: true,
+                })
+            })
+            .collect())
+    }
+}
+
+fn is_managed(labels: Option<&HashMap<String, String>>) -> bool {
+    labels
+        .and_then(|labels| labels.get(MANAGED_LABEL))
+        .map_or(false, |value| value == "true")
+}
+
+fn container_error(err: bollard::errors::Error) -> DeviceManagerError {
+    DeviceManagerError::ContainerError(err.to_string())
+}
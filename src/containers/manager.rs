@@ -0,0 +1,708 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Deployments run one at a time against the container engine.
+//!
+//! Each deployment publishes its status on `DeploymentEvent`, the pull progress throttled like the
+//! OTA progress. The image and state of the managed containers are published as properties on
+//! `ContainerStatus`, after every request and periodically.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+#[cfg(test)]
+use mockall::automock;
+use serde::Serialize;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::containers::{ContainerRequest, Deployment, RegistryCredentials, RestartPolicy};
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::{CONTAINER_STATUS_INTERFACE, DEPLOYMENT_EVENT_INTERFACE};
+use crate::ota::progress::ProgressThrottle;
+
+/// A container known to the engine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerInfo {
+    pub name: String,
+    pub image: String,
+    /// State reported by the engine, like `running` or `exited`.
+    pub state: String,
+    /// Whether the container was created by the runtime.
+    pub managed: bool,
+}
+
+/// A container to create, marked as managed by the runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerSpec {
+    pub name: String,
+    pub image: String,
+    pub env: Vec<String>,
+    pub restart_policy: RestartPolicy,
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait ContainerEngine: Send + Sync {
+    /// Pull `image`, sending the percentage pulled on `progress` until it is dropped.
+    async fn pull(
+        &self,
+        image: &str,
+        credentials: Option<RegistryCredentials>,
+        progress: watch::Sender<u8>,
+    ) -> Result<(), DeviceManagerError>;
+    async fn inspect(&self, name: &str) -> Result<Option<ContainerInfo>, DeviceManagerError>;
+    async fn create(&self, spec: &ContainerSpec) -> Result<(), DeviceManagerError>;
+    async fn start(&self, name: &str) -> Result<(), DeviceManagerError>;
+    async fn stop(&self, name: &str) -> Result<(), DeviceManagerError>;
+    /// Remove the container `name`, stopping it first when running.
+    async fn remove(&self, name: &str) -> Result<(), DeviceManagerError>;
+    /// The containers created by the runtime.
+    async fn list_managed(&self) -> Result<Vec<ContainerInfo>, DeviceManagerError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeploymentStatus {
+    Pulling,
+    Starting,
+    Running,
+    Error,
+}
+
+impl Display for DeploymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeploymentStatus::Pulling => write!(f, "Pulling"),
+            DeploymentStatus::Starting => write!(f, "Starting"),
+            DeploymentStatus::Running => write!(f, "Running"),
+            DeploymentStatus::Error => write!(f, "Error"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentEvent {
+    pub deployment_id: String,
+    pub status: String,
+    /// Percentage of the image pulled, 0 for the other statuses.
+    pub progress: i32,
+    /// What went wrong, empty unless the status is `Error`.
+    pub message: String,
+}
+
+/// Publishes the status of the deployment `deployment_id`.
+struct DeploymentReporter<'a, P> {
+    publisher: &'a P,
+    clock: Arc<dyn Clock>,
+    throttle: ProgressThrottle,
+    deployment_id: String,
+    status: DeploymentStatus,
+    /// When the last percentage of the pull was published.
+    published: Option<(Instant, u8)>,
+}
+
+impl<'a, P: Publisher> DeploymentReporter<'a, P> {
+    async fn status(&mut self, status: DeploymentStatus) {
+        self.status = status;
+        self.published = None;
+        self.publish(0, String::new()).await;
+    }
+
+    async fn error(&mut self, message: String) {
+        self.status = DeploymentStatus::Error;
+        self.publish(0, message).await;
+    }
+
+    /// Publish the progress of the pull until it completes.
+    async fn follow(&mut self, mut progress: watch::Receiver<u8>) {
+        while progress.changed().await.is_ok() {
+            let percentage = (*progress.borrow()).min(100);
+            let now = self.clock.now_monotonic();
+            if self.throttle.due(self.published, percentage, now) {
+                self.published = Some((now, percentage));
+                self.publish(percentage, String::new()).await;
+            }
+        }
+    }
+
+    async fn publish(&self, percentage: u8, message: String) {
+        let event = DeploymentEvent {
+            deployment_id: self.deployment_id.clone(),
+            status: self.status.to_string(),
+            progress: percentage.into(),
+            message,
+        };
+        let timestamp = DateTime::<Utc>::from(self.clock.now_wall());
+        if let Err(err) = self
+            .publisher
+            .send_object_with_timestamp(DEPLOYMENT_EVENT_INTERFACE, "/event", event, timestamp)
+            .await
+        {
+            warn!("Unable to publish the deployment status: {:?}", err);
+        }
+    }
+}
+
+pub struct ContainerManager {
+    clock: Arc<dyn Clock>,
+    engine: Box<dyn ContainerEngine>,
+    status_period: Duration,
+    throttle: ProgressThrottle,
+    /// Image and state of the managed containers last published.
+    published: BTreeMap<String, (String, String)>,
+}
+
+impl ContainerManager {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        engine: Box<dyn ContainerEngine>,
+        status_period: Duration,
+    ) -> Self {
+        ContainerManager {
+            clock,
+            engine,
+            status_period,
+            throttle: ProgressThrottle::default(),
+            published: BTreeMap::new(),
+        }
+    }
+
+    pub async fn run(
+        mut self,
+        publisher: &impl Publisher,
+        mut requests: mpsc::Receiver<ContainerRequest>,
+    ) {
+        let mut next_status = self.clock.now_monotonic();
+        loop {
+            let clock = self.clock.clone();
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(request) => {
+                        self.handle(publisher, request).await;
+                        self.publish_status(publisher).await;
+                    }
+                    None => break,
+                },
+                _ = clock.sleep_until(next_status) => {
+                    self.publish_status(publisher).await;
+                    next_status = self.clock.now_monotonic() + self.status_period;
+                }
+            }
+        }
+    }
+
+    async fn handle(&self, publisher: &impl Publisher, request: ContainerRequest) {
+        match request {
+            ContainerRequest::Deploy(deployment) => {
+                let mut reporter = DeploymentReporter {
+                    publisher,
+                    clock: self.clock.clone(),
+                    throttle: self.throttle,
+                    deployment_id: deployment.deployment_id.clone(),
+                    status: DeploymentStatus::Pulling,
+                    published: None,
+                };
+                match self.deploy(&mut reporter, deployment).await {
+                    Ok(()) => reporter.status(DeploymentStatus::Running).await,
+                    Err(err) => {
+                        warn!("Deployment {} failed: {}", reporter.deployment_id, err);
+                        reporter.error(err.to_string()).await;
+                    }
+                }
+            }
+            ContainerRequest::Stop(name) => {
+                let stopped = match self.managed(&name).await {
+                    Ok(()) => self.engine.stop(&name).await,
+                    Err(err) => Err(err),
+                };
+                match stopped {
+                    Ok(()) => info!("Container {} stopped", name),
+                    Err(err) => warn!("Unable to stop the container {}: {}", name, err),
+                }
+            }
+            ContainerRequest::Remove(name) => {
+                let removed = match self.managed(&name).await {
+                    Ok(()) => self.engine.remove(&name).await,
+                    Err(err) => Err(err),
+                };
+                match removed {
+                    Ok(()) => info!("Container {} removed", name),
+                    Err(err) => warn!("Unable to remove the container {}: {}", name, err),
+                }
+            }
+        }
+    }
+
+    async fn deploy<P: Publisher>(
+        &self,
+        reporter: &mut DeploymentReporter<'_, P>,
+        deployment: Deployment,
+    ) -> Result<(), DeviceManagerError> {
+        let existing = self.engine.inspect(&deployment.container_name).await?;
+        if let Some(ContainerInfo { managed: false, .. }) = existing {
+            return Err(not_managed(&deployment.container_name));
+        }
+
+        reporter.status(DeploymentStatus::Pulling).await;
+        let (progress_tx, progress_rx) = watch::channel(0);
+        let pull = self
+            .engine
+            .pull(&deployment.image, deployment.credentials, progress_tx);
+        let (pulled, ()) = tokio::join!(pull, reporter.follow(progress_rx));
+        pulled?;
+
+        reporter.status(DeploymentStatus::Starting).await;
+        if existing.is_some() {
+            self.engine.remove(&deployment.container_name).await?;
+        }
+        self.engine
+            .create(&ContainerSpec {
+                name: deployment.container_name.clone(),
+                image: deployment.image,
+                env: deployment.env,
+                restart_policy: deployment.restart_policy,
+            })
+            .await?;
+        self.engine.start(&deployment.container_name).await
+    }
+
+    /// Fails unless `name` is a container created by the runtime.
+    async fn managed(&self, name: &str) -> Result<(), DeviceManagerError> {
+        match self.engine.inspect(name).await? {
+            Some(ContainerInfo { managed: true, .. }) => Ok(()),
+            Some(_) => Err(not_managed(name)),
+            None => Err(DeviceManagerError::ContainerError(format!(
+                "no container {name}"
+            ))),
+        }
+    }
+
+    /// Publish the managed containers that changed, unset the ones that are gone.
+    async fn publish_status(&mut self, publisher: &impl Publisher) {
+        let containers = match self.engine.list_managed().await {
+            Ok(containers) => containers,
+            Err(err) => {
+                warn!("Unable to list the managed containers: {}", err);
+                return;
+            }
+        };
+        let current: BTreeMap<String, (String, String)> = containers
+            .into_iter()
+            .map(|info| (info.name, (info.image, info.state)))
+            .collect();
+
+        for (name, (image, state)) in &current {
+            let published = self.published.get(name);
+            if published.map(|(image, _)| image) != Some(image) {
+                send(publisher, &format!("/{name}/image"), image).await;
+            }
+            if published.map(|(_, state)| state) != Some(state) {
+                send(publisher, &format!("/{name}/state"), state).await;
+            }
+        }
+        for name in self.published.keys() {
+            if !current.contains_key(name) {
+                for path in [format!("/{name}/image"), format!("/{name}/state")] {
+                    if let Err(err) = publisher.unset(CONTAINER_STATUS_INTERFACE, &path).await {
+                        warn!("Unable to unset {}: {:?}", path, err);
+                    }
+                }
+            }
+        }
+
+        self.published = current;
+    }
+}
+
+fn not_managed(name: &str) -> DeviceManagerError {
+    DeviceManagerError::ContainerError(format!("{name} is not managed by the runtime"))
+}
+
+async fn send(publisher: &impl Publisher, path: &str, value: &str) {
+    let value = AstarteType::String(value.to_owned());
+    if let Err(err) = publisher
+        .send(CONTAINER_STATUS_INTERFACE, path, value)
+        .await
+    {
+        warn!("Unable to publish {}: {:?}", path, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use mockall::predicate::eq;
+    use mockall::Sequence;
+    use tokio::sync::mpsc;
+
+    use crate::containers::manager::{
+        ContainerInfo, ContainerManager, ContainerSpec, DeploymentEvent, MockContainerEngine,
+    };
+    use crate::containers::{ContainerRequest, Deployment, RegistryCredentials, RestartPolicy};
+    use crate::data::MockPublisher;
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::{CONTAINER_STATUS_INTERFACE, DEPLOYMENT_EVENT_INTERFACE};
+    use crate::test_utils::{recording_publisher, settle, ManualClock, Recorded, Recording};
+
+    /// Property set, or unset when `None`.
+    type Property = (String, Option<String>);
+
+    fn events(recording: &Recording<DeploymentEvent>) -> Vec<DeploymentEvent> {
+        recording.objects(DEPLOYMENT_EVENT_INTERFACE)
+    }
+
+    /// Properties published since the last call.
+    fn take_properties(recording: &Recording<DeploymentEvent>) -> Vec<Property> {
+        let properties = recording
+            .on(CONTAINER_STATUS_INTERFACE)
+            .into_iter()
+            .map(|(path, data)| match data {
+                Recorded::Individual(AstarteType::String(value)) => (path, Some(value)),
+                Recorded::Unset => (path, None),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+        recording.clear();
+
+        properties
+    }
+
+    fn deployment() -> Deployment {
+        Deployment {
+            deployment_id: "d-1".to_owned(),
+            image: "nginx:1.23".to_owned(),
+            container_name: "web".to_owned(),
+            env: vec!["PORT=8080".to_owned()],
+            restart_policy: RestartPolicy::Always,
+            credentials: None,
+        }
+    }
+
+    fn info(name: &str, state: &str, managed: bool) -> ContainerInfo {
+        ContainerInfo {
+            name: name.to_owned(),
+            image: "nginx:1.23".to_owned(),
+            state: state.to_owned(),
+            managed,
+        }
+    }
+
+    fn statuses(recording: &Recording<DeploymentEvent>) -> Vec<(String, i32)> {
+        events(recording)
+            .into_iter()
+            .map(|event| (event.status, event.progress))
+            .collect()
+    }
+
+    /// Run `requests` to completion.
+    async fn run(
+        engine: MockContainerEngine,
+        publisher: &MockPublisher,
+        requests: Vec<ContainerRequest>,
+    ) {
+        let manager = ContainerManager::new(
+            Arc::new(ManualClock::new()),
+            Box::new(engine),
+            Duration::from_secs(60),
+        );
+        let (tx, rx) = mpsc::channel(requests.len());
+        for request in requests {
+            tx.send(request).await.unwrap();
+        }
+        drop(tx);
+        manager.run(publisher, rx).await;
+    }
+
+    #[tokio::test]
+    async fn deployment_mapped_to_engine_calls() {
+        let mut engine = MockContainerEngine::new();
+        let mut seq = Sequence::new();
+        engine
+            .expect_inspect()
+            .with(eq("web"))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(Some(info("web", "exited", true))));
+        engine
+            .expect_pull()
+            .withf(|image, credentials, _| {
+                image == "nginx:1.23"
+                    && credentials
+                        == &Some(RegistryCredentials {
+                            username: "deployer".to_owned(),
+                            password: "s3cret".to_owned(),
+                        })
+            })
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, progress| {
+                progress.send_replace(100);
+                Ok(())
+            });
+        engine
+            .expect_remove()
+            .with(eq("web"))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        engine
+            .expect_create()
+            .with(eq(ContainerSpec {
+                name: "web".to_owned(),
+                image: "nginx:1.23".to_owned(),
+                env: vec!["PORT=8080".to_owned()],
+                restart_policy: RestartPolicy::Always,
+            }))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        engine
+            .expect_start()
+            .with(eq("web"))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        engine
+            .expect_list_managed()
+            .returning(|| Ok(vec![info("web", "running", true)]));
+        let (publisher, recording) = recording_publisher();
+
+        let deployment = Deployment {
+            credentials: Some(RegistryCredentials {
+                username: "deployer".to_owned(),
+                password: "s3cret".to_owned(),
+            }),
+            ..deployment()
+        };
+        run(
+            engine,
+            &publisher,
+            vec![ContainerRequest::Deploy(deployment)],
+        )
+        .await;
+
+        assert_eq!(
+            statuses(&recording),
+            [
+                ("Pulling".to_owned(), 0),
+                ("Pulling".to_owned(), 100),
+                ("Starting".to_owned(), 0),
+                ("Running".to_owned(), 0),
+            ]
+        );
+        assert!(events(&recording)
+            .iter()
+            .all(|event| event.deployment_id == "d-1" && event.message.is_empty()));
+        assert_eq!(
+            take_properties(&recording),
+            [
+                ("/web/image".to_owned(), Some("nginx:1.23".to_owned())),
+                ("/web/state".to_owned(), Some("running".to_owned())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unmanaged_containers_untouched() {
+        let mut engine = MockContainerEngine::new();
+        engine
+            .expect_inspect()
+            .with(eq("web"))
+            .returning(|_| Ok(Some(info("web", "running", false))));
+        engine
+            .expect_inspect()
+            .with(eq("db"))
+            .returning(|_| Ok(Some(info("db", "running", true))));
+        engine.expect_pull().never();
+        engine.expect_create().never();
+        engine.expect_start().never();
+        engine.expect_remove().never();
+        engine
+            .expect_stop()
+            .with(eq("db"))
+            .times(1)
+            .returning(|_| Ok(()));
+        engine.expect_list_managed().returning(|| Ok(Vec::new()));
+        let (publisher, recording) = recording_publisher();
+
+        run(
+            engine,
+            &publisher,
+            vec![
+                ContainerRequest::Deploy(deployment()),
+                ContainerRequest::Stop("web".to_owned()),
+                ContainerRequest::Remove("web".to_owned()),
+                ContainerRequest::Stop("db".to_owned()),
+            ],
+        )
+        .await;
+
+        let events = events(&recording);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].status, "Error");
+        assert_eq!(
+            events[0].message,
+            "container error: web is not managed by the runtime"
+        );
+    }
+
+    #[tokio::test]
+    async fn pull_failure_reported() {
+        let mut engine = MockContainerEngine::new();
+        engine.expect_inspect().returning(|_| Ok(None));
+        engine.expect_pull().returning(|_, _, _| {
+            Err(DeviceManagerError::ContainerError(
+                "manifest unknown".to_owned(),
+            ))
+        });
+        engine.expect_create().never();
+        engine.expect_list_managed().returning(|| Ok(Vec::new()));
+        let (publisher, recording) = recording_publisher();
+
+        run(
+            engine,
+            &publisher,
+            vec![ContainerRequest::Deploy(deployment())],
+        )
+        .await;
+
+        assert_eq!(
+            statuses(&recording),
+            [("Pulling".to_owned(), 0), ("Error".to_owned(), 0)]
+        );
+        assert_eq!(
+            events(&recording)[1].message,
+            "container error: manifest unknown"
+        );
+    }
+
+    #[tokio::test]
+    async fn pull_progress_throttled() {
+        let clock = Arc::new(ManualClock::new());
+        let mut engine = MockContainerEngine::new();
+        engine.expect_inspect().returning(|_| Ok(None));
+        let pull_clock = clock.clone();
+        engine.expect_pull().returning(move |_, _, progress| {
+            // 1% every 100ms, the sender is dropped once the pull completes
+            let clock = pull_clock.clone();
+            tokio::spawn(async move {
+                for percentage in 1..=100 {
+                    clock.advance(Duration::from_millis(100));
+                    progress.send_replace(percentage);
+                    settle().await;
+                }
+            });
+            Ok(())
+        });
+        engine.expect_create().returning(|_| Ok(()));
+        engine.expect_start().returning(|_| Ok(()));
+        engine.expect_list_managed().returning(|| Ok(Vec::new()));
+        let (publisher, recording) = recording_publisher();
+
+        let manager = ContainerManager::new(clock, Box::new(engine), Duration::from_secs(3600));
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(ContainerRequest::Deploy(deployment()))
+            .await
+            .unwrap();
+        drop(tx);
+        manager.run(&publisher, rx).await;
+
+        let pulled: Vec<i32> = statuses(&recording)
+            .into_iter()
+            .filter(|(status, _)| status == "Pulling")
+            .map(|(_, progress)| progress)
+            .collect();
+        // every 10%, the whole pull takes less than a period per step
+        assert_eq!(pulled, (0..=100).step_by(10).collect::<Vec<_>>());
+        assert_eq!(
+            statuses(&recording).last(),
+            Some(&("Running".to_owned(), 0))
+        );
+    }
+
+    #[tokio::test]
+    async fn status_follows_managed_containers() {
+        let clock = Arc::new(ManualClock::new());
+        let listed = Arc::new(Mutex::new(VecDeque::from([
+            vec![info("web", "running", true)],
+            vec![info("web", "exited", true), info("db", "running", true)],
+            Vec::new(),
+        ])));
+        let mut engine = MockContainerEngine::new();
+        engine
+            .expect_list_managed()
+            .returning(move || Ok(listed.lock().unwrap().pop_front().unwrap_or_default()));
+        let (publisher, recording) = recording_publisher();
+
+        let manager =
+            ContainerManager::new(clock.clone(), Box::new(engine), Duration::from_secs(60));
+        let (_tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(async move { manager.run(&publisher, rx).await });
+
+        let set = |path: &str, value: &str| (path.to_owned(), Some(value.to_owned()));
+        let unset = |path: &str| (path.to_owned(), None);
+        settle().await;
+        assert_eq!(
+            take_properties(&recording),
+            [
+                set("/web/image", "nginx:1.23"),
+                set("/web/state", "running")
+            ]
+        );
+
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(
+            take_properties(&recording),
+            [
+                set("/db/image", "nginx:1.23"),
+                set("/db/state", "running"),
+                set("/web/state", "exited"),
+            ]
+        );
+
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(
+            take_properties(&recording),
+            [
+                unset("/db/image"),
+                unset("/db/state"),
+                unset("/web/image"),
+                unset("/web/state"),
+            ]
+        );
+
+        handle.abort();
+    }
+}
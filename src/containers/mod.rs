@@ -0,0 +1,260 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Docker containers deployed on request of the backend.
+//!
+//! The deployments are only run when built with the `containers` feature and enabled in the
+//! configuration. The runtime labels the containers it creates and never stops, removes nor
+//! replaces the other ones.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use serde::Deserialize;
+
+#[cfg(feature = "containers")]
+pub(crate) mod docker;
+#[cfg(any(test, feature = "containers"))]
+pub(crate) mod manager;
+
+pub const DEFAULT_CONTAINERS_STATUS_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ContainersOptions {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Period of the status of the managed containers, 60 seconds by default.
+    pub status_period_secs: Option<u64>,
+}
+
+impl ContainersOptions {
+    pub fn status_period(&self) -> Duration {
+        self.status_period_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CONTAINERS_STATUS_PERIOD)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    No,
+    Always,
+    UnlessStopped,
+    OnFailure,
+}
+
+impl RestartPolicy {
+    /// The Docker names of the policies.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "no" => Some(RestartPolicy::No),
+            "always" => Some(RestartPolicy::Always),
+            "unless-stopped" => Some(RestartPolicy::UnlessStopped),
+            "on-failure" => Some(RestartPolicy::OnFailure),
+            _ => None,
+        }
+    }
+}
+
+/// Credentials of a private registry, the password is never logged.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "containers"), allow(dead_code))]
+pub struct RegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryCredentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "containers"), allow(dead_code))]
+pub struct Deployment {
+    pub deployment_id: String,
+    pub image: String,
+    pub container_name: String,
+    /// `NAME=value` entries.
+    pub env: Vec<String>,
+    pub restart_policy: RestartPolicy,
+    pub credentials: Option<RegistryCredentials>,
+}
+
+impl Deployment {
+    /// The deployment on `/request` of `io.edgehog.devicemanager.containers.DeploymentRequest`.
+    pub fn from_object(data: &HashMap<String, AstarteType>) -> Option<Self> {
+        let string = |name: &str| match data.get(name) {
+            Some(AstarteType::String(value)) if !value.is_empty() => Some(value.clone()),
+            _ => None,
+        };
+
+        let env = match data.get("env") {
+            Some(AstarteType::StringArray(env)) => env.clone(),
+            None => Vec::new(),
+            Some(_) => return None,
+        };
+        if env.iter().any(|entry| !entry.contains('=')) {
+            return None;
+        }
+
+        let restart_policy = match string("restartPolicy") {
+            Some(name) => RestartPolicy::from_name(&name)?,
+            None => RestartPolicy::UnlessStopped,
+        };
+        let credentials = match (string("registryUsername"), string("registryPassword")) {
+            (Some(username), Some(password)) => Some(RegistryCredentials { username, password }),
+            (None, None) => None,
+            _ => return None,
+        };
+
+        Some(Deployment {
+            deployment_id: string("deploymentId")?,
+            image: string("image")?,
+            container_name: string("containerName")?,
+            env,
+            restart_policy,
+            credentials,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "containers"), allow(dead_code))]
+pub enum ContainerRequest {
+    Deploy(Deployment),
+    Stop(String),
+    Remove(String),
+}
+
+impl ContainerRequest {
+    /// The action set on `/<container_name>/action` of
+    /// `io.edgehog.devicemanager.containers.ContainerRequest`.
+    pub fn from_property(path: &[&str], value: &AstarteType) -> Option<Self> {
+        match (path, value) {
+            ([name, "action"], AstarteType::String(action)) => match action.as_str() {
+                "Stop" => Some(ContainerRequest::Stop((*name).to_owned())),
+                "Remove" => Some(ContainerRequest::Remove((*name).to_owned())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::containers::{ContainerRequest, Deployment, RegistryCredentials, RestartPolicy};
+
+    fn request() -> HashMap<String, AstarteType> {
+        [
+            ("deploymentId", AstarteType::String("d-1".to_owned())),
+            ("image", AstarteType::String("nginx:1.23".to_owned())),
+            ("containerName", AstarteType::String("web".to_owned())),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value))
+        .collect()
+    }
+
+    #[test]
+    fn deployment_parsed() {
+        let mut data = request();
+        assert_eq!(
+            Deployment::from_object(&data),
+            Some(Deployment {
+                deployment_id: "d-1".to_owned(),
+                image: "nginx:1.23".to_owned(),
+                container_name: "web".to_owned(),
+                env: Vec::new(),
+                restart_policy: RestartPolicy::UnlessStopped,
+                credentials: None,
+            })
+        );
+
+        data.insert(
+            "env".to_owned(),
+            AstarteType::StringArray(vec!["PORT=8080".to_owned()]),
+        );
+        data.insert(
+            "restartPolicy".to_owned(),
+            AstarteType::String("on-failure".to_owned()),
+        );
+        data.insert(
+            "registryUsername".to_owned(),
+            AstarteType::String("deployer".to_owned()),
+        );
+        data.insert(
+            "registryPassword".to_owned(),
+            AstarteType::String("s3cret".to_owned()),
+        );
+        let deployment = Deployment::from_object(&data).unwrap();
+        assert_eq!(deployment.env, ["PORT=8080"]);
+        assert_eq!(deployment.restart_policy, RestartPolicy::OnFailure);
+        assert_eq!(
+            deployment.credentials,
+            Some(RegistryCredentials {
+                username: "deployer".to_owned(),
+                password: "s3cret".to_owned(),
+            })
+        );
+        assert!(!format!("{deployment:?}").contains("s3cret"));
+
+        let invalid = [
+            ("restartPolicy", AstarteType::String("sometimes".to_owned())),
+            ("env", AstarteType::StringArray(vec!["PORT".to_owned()])),
+            ("registryPassword", AstarteType::String(String::new())),
+            ("image", AstarteType::Integer(1)),
+        ];
+        for (name, value) in invalid {
+            let mut data = data.clone();
+            data.insert(name.to_owned(), value);
+            assert_eq!(Deployment::from_object(&data), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn container_actions_parsed() {
+        let action = |path: &[&str], action: &str| {
+            ContainerRequest::from_property(path, &AstarteType::String(action.to_owned()))
+        };
+
+        assert_eq!(
+            action(&["web", "action"], "Stop"),
+            Some(ContainerRequest::Stop("web".to_owned()))
+        );
+        assert_eq!(
+            action(&["web", "action"], "Remove"),
+            Some(ContainerRequest::Remove("web".to_owned()))
+        );
+        assert_eq!(action(&["web", "action"], "Restart"), None);
+        assert_eq!(action(&["web"], "Stop"), None);
+    }
+}
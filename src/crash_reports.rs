@@ -0,0 +1,1000 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Crash reports of the managed applications.
+//!
+//! The crashes logged by systemd-coredump for the allowlisted processes are stored in the store
+//! directory, with the core when it fits the size cap, and their metadata is published. The
+//! cores are only uploaded when the backend requests one with a presigned URL. The stored
+//! reports are kept under a quota, pruning the oldest first. The large cores can be uploaded in
+//! chunks, resumed after a restart from the last chunk acknowledged.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use astarte_sdk::types::AstarteType;
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use log::{error, info, warn};
+#[cfg(test)]
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::chunked_upload::{ChunkedUploader, UploadSession, UploadTarget, UploadedFile};
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::CRASH_REPORT_INTERFACE;
+use crate::redaction::redactor;
+use crate::repository::StateRepository;
+
+/// Cores larger than this are not captured, unless configured otherwise.
+pub const DEFAULT_MAX_CORE_BYTES: u64 = 64 * 1024 * 1024;
+/// Space taken by the stored reports, unless configured otherwise.
+pub const DEFAULT_QUOTA_BYTES: u64 = 256 * 1024 * 1024;
+/// Message id of the journal entries logged by systemd-coredump.
+const COREDUMP_MESSAGE_ID: &str = "fc2e22bc6ee647b6b90729ab34a250b1";
+const CORE_EXTENSION: &str = "core";
+const REPORT_EXTENSION: &str = "json";
+/// Name of the file keeping the chunked upload in progress in the store directory.
+pub const UPLOAD_SESSION_FILE: &str = "crash_upload.json";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CrashReportOptions {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Names of the processes whose crashes are reported.
+    #[serde(default)]
+    pub processes: Vec<String>,
+    /// Store the cores too, not only the metadata.
+    #[serde(default)]
+    pub capture_core: bool,
+    pub max_core_bytes: Option<u64>,
+    pub quota_bytes: Option<u64>,
+}
+
+/// Crash logged by systemd-coredump.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoredumpEntry {
+    pub process: String,
+    pub executable: String,
+    pub signal: i32,
+    pub timestamp: DateTime<Utc>,
+    pub build_id: Option<String>,
+    /// Core saved by systemd-coredump, already compressed.
+    pub core_file: Option<PathBuf>,
+}
+
+impl CoredumpEntry {
+    /// Parse an entry of `journalctl --output=json`.
+    pub fn from_journal(fields: &HashMap<String, Value>) -> Option<Self> {
+        let field = |name: &str| fields.get(name).and_then(Value::as_str);
+
+        let micros: i64 = field("COREDUMP_TIMESTAMP")?.parse().ok()?;
+        // the first module listed in the message is the executable
+        let build_id = field("MESSAGE").and_then(|message| {
+            message
+                .split("with build-id ")
+                .nth(1)
+                .and_then(|rest| rest.split_whitespace().next())
+                .map(str::to_owned)
+        });
+
+        Some(CoredumpEntry {
+            process: field("COREDUMP_COMM")?.to_owned(),
+            executable: field("COREDUMP_EXE").unwrap_or_default().to_owned(),
+            signal: field("COREDUMP_SIGNAL")?.parse().ok()?,
+            timestamp: Utc.timestamp_nanos(micros * 1000),
+            build_id,
+            core_file: field("COREDUMP_FILENAME").map(PathBuf::from),
+        })
+    }
+}
+
+/// Metadata of a stored crash, published on `/report` of the crash report interface.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashReport {
+    pub report_id: String,
+    pub process: String,
+    pub executable: String,
+    pub signal: i32,
+    pub timestamp: DateTime<Utc>,
+    /// Empty when unknown.
+    pub build_id: String,
+    /// Size of the stored core, 0 when not captured.
+    pub core_bytes: i64,
+}
+
+/// Outcome of an upload request, published on `/upload` of the crash report interface.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadResult {
+    pub report_id: String,
+    /// `Uploaded`, `Failed`, `Unavailable` when the core is not stored or `InProgress` after
+    /// each chunk of a chunked upload.
+    pub status: String,
+    pub message: String,
+    /// Bytes acknowledged by the server, for the chunked uploads.
+    pub uploaded_bytes: i64,
+    pub total_bytes: i64,
+    /// Hex SHA-256 of the core, set once a chunked upload completed.
+    pub sha256: String,
+    /// ETags of the parts of a multipart upload, in order.
+    pub etags: Vec<String>,
+}
+
+/// Upload asked by the backend.
+#[derive(Debug, Clone, PartialEq)]
+enum UploadRequest {
+    /// A single PUT to the presigned URL.
+    Single { url: String },
+    /// PUTs of `chunk_bytes` to the URL, each with its `Content-Range`.
+    ContentRange { url: String, chunk_bytes: u64 },
+    /// The parts of a multipart upload, one presigned URL each.
+    Multipart {
+        part_urls: Vec<String>,
+        part_bytes: Option<u64>,
+    },
+}
+
+impl UploadRequest {
+    fn parse(request: &HashMap<String, AstarteType>) -> Option<(String, Self)> {
+        let report_id = match request.get("reportId") {
+            Some(AstarteType::String(report_id)) => report_id.clone(),
+            _ => return None,
+        };
+        let bytes = |name: &str| match request.get(name) {
+            Some(AstarteType::Integer(bytes)) => u64::try_from(*bytes).ok(),
+            Some(AstarteType::LongInteger(bytes)) => u64::try_from(*bytes).ok(),
+            _ => None,
+        };
+
+        let upload = match (request.get("url"), request.get("partUrls")) {
+            (_, Some(AstarteType::StringArray(part_urls))) if !part_urls.is_empty() => {
+                UploadRequest::Multipart {
+                    part_urls: part_urls.clone(),
+                    part_bytes: bytes("partBytes"),
+                }
+            }
+            (Some(AstarteType::String(url)), _) => match bytes("chunkBytes") {
+                Some(chunk_bytes) if chunk_bytes > 0 => UploadRequest::ContentRange {
+                    url: url.clone(),
+                    chunk_bytes,
+                },
+                _ => UploadRequest::Single { url: url.clone() },
+            },
+            _ => return None,
+        };
+
+        Some((report_id, upload))
+    }
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait CoreUploader: Send + Sync {
+    async fn upload(&self, url: &str, core: &Path) -> Result<(), DeviceManagerError>;
+}
+
+/// Uploads the files with a PUT to the presigned URL.
+pub struct HttpUploader {
+    client: reqwest::Client,
+}
+
+impl HttpUploader {
+    pub fn new(client: reqwest::Client) -> Self {
+        HttpUploader { client }
+    }
+}
+
+#[async_trait]
+impl CoreUploader for HttpUploader {
+    async fn upload(&self, url: &str, core: &Path) -> Result<(), DeviceManagerError> {
+        let body = tokio::fs::read(core).await?;
+
+        self.client
+            .put(url)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+pub struct CrashReporter {
+    directory: PathBuf,
+    processes: Vec<String>,
+    capture_core: bool,
+    max_core_bytes: u64,
+    quota_bytes: u64,
+    uploader: Box<dyn CoreUploader>,
+    chunked: Option<ChunkedUploader>,
+}
+
+impl CrashReporter {
+    pub fn new(
+        options: &CrashReportOptions,
+        directory: PathBuf,
+        uploader: Box<dyn CoreUploader>,
+    ) -> Result<Self, DeviceManagerError> {
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(CrashReporter {
+            directory,
+            processes: options.processes.clone(),
+            capture_core: options.capture_core,
+            max_core_bytes: options.max_core_bytes.unwrap_or(DEFAULT_MAX_CORE_BYTES),
+            quota_bytes: options.quota_bytes.unwrap_or(DEFAULT_QUOTA_BYTES),
+            uploader,
+            chunked: None,
+        })
+    }
+
+    /// Accept the chunked upload requests, resuming the interrupted one.
+    pub fn with_chunked_uploader(mut self, chunked: Option<ChunkedUploader>) -> Self {
+        self.chunked = chunked;
+        self
+    }
+
+    fn path(&self, report_id: &str, extension: &str) -> PathBuf {
+        self.directory.join(report_id).with_extension(extension)
+    }
+
+    /// Store the crash of an allowlisted process, with its core when it fits the cap.
+    async fn capture(
+        &self,
+        entry: CoredumpEntry,
+    ) -> Result<Option<CrashReport>, DeviceManagerError> {
+        if !self.processes.contains(&entry.process) {
+            return Ok(None);
+        }
+
+        let report_id = Uuid::new_v4().to_string();
+        let mut core_bytes = 0;
+        if let Some(core_file) = entry.core_file.as_ref().filter(|_| self.capture_core) {
+            match tokio::fs::metadata(core_file).await {
+                Ok(metadata) if metadata.len() <= self.max_core_bytes => {
+                    core_bytes =
+                        tokio::fs::copy(core_file, self.path(&report_id, CORE_EXTENSION)).await?;
+                }
+                Ok(metadata) => warn!(
+                    "The core of {} is {} bytes, above the cap",
+                    entry.process,
+                    metadata.len()
+                ),
+                Err(err) => warn!("Unable to read the core of {}: {}", entry.process, err),
+            }
+        }
+
+        let report = CrashReport {
+            report_id,
+            process: entry.process,
+            executable: entry.executable,
+            signal: entry.signal,
+            timestamp: entry.timestamp,
+            build_id: entry.build_id.unwrap_or_default(),
+            core_bytes: core_bytes as i64,
+        };
+        tokio::fs::write(
+            self.path(&report.report_id, REPORT_EXTENSION),
+            serde_json::to_vec(&report)?,
+        )
+        .await?;
+
+        Ok(Some(report))
+    }
+
+    /// The stored reports, oldest first.
+    fn stored(&self) -> Vec<CrashReport> {
+        let mut reports: Vec<CrashReport> = std::fs::read_dir(&self.directory)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |ext| ext == REPORT_EXTENSION)
+            })
+            .filter_map(|path| serde_json::from_slice(&std::fs::read(path).ok()?).ok())
+            .collect();
+        reports.sort_by_key(|report| report.timestamp);
+
+        reports
+    }
+
+    fn stored_bytes(&self, report: &CrashReport) -> u64 {
+        [REPORT_EXTENSION, CORE_EXTENSION]
+            .iter()
+            .filter_map(|extension| std::fs::metadata(self.path(&report.report_id, extension)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    fn remove(&self, report_id: &str) {
+        for extension in [REPORT_EXTENSION, CORE_EXTENSION] {
+            let path = self.path(report_id, extension);
+            if let Err(err) = std::fs::remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Unable to remove {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+
+    /// Remove the oldest reports until the stored ones fit the quota.
+    fn prune(&self) {
+        let reports = self.stored();
+        let mut total: u64 = reports.iter().map(|report| self.stored_bytes(report)).sum();
+
+        for report in reports {
+            if total <= self.quota_bytes {
+                break;
+            }
+
+            info!("Pruning the crash report {}", report.report_id);
+            total -= self.stored_bytes(&report);
+            self.remove(&report.report_id);
+        }
+    }
+
+    pub async fn handle_entry(&self, publisher: &impl Publisher, entry: CoredumpEntry) {
+        let report = match self.capture(entry).await {
+            Ok(Some(report)) => report,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Unable to store the crash report: {:?}", err);
+                return;
+            }
+        };
+        info!(
+            "{} crashed with signal {}, report {}",
+            report.process, report.signal, report.report_id
+        );
+        self.prune();
+
+        if let Err(err) = publisher
+            .send_object(CRASH_REPORT_INTERFACE, "/report", report)
+            .await
+        {
+            warn!("Unable to publish the crash report: {:?}", err);
+        }
+    }
+
+    /// Upload the core requested by the backend, removing it once uploaded.
+    pub async fn handle_upload(
+        &self,
+        publisher: &impl Publisher,
+        request: HashMap<String, AstarteType>,
+    ) {
+        let (report_id, upload) = match UploadRequest::parse(&request) {
+            Some(parsed) => parsed,
+            None => {
+                warn!(
+                    "Invalid crash report upload request: {}",
+                    redactor().object(&request)
+                );
+                return;
+            }
+        };
+
+        let core = self.path(&report_id, CORE_EXTENSION);
+        if !core.exists() {
+            let result = UploadResult {
+                report_id,
+                status: "Unavailable".to_owned(),
+                ..Default::default()
+            };
+            self.publish_result(publisher, result).await;
+            return;
+        }
+
+        let result = match upload {
+            UploadRequest::Single { url } => {
+                info!(
+                    "Uploading the core of {report_id} to {}",
+                    redactor().url(&url)
+                );
+                self.uploader
+                    .upload(&url, &core)
+                    .await
+                    .map(|()| UploadedFile {
+                        sha256: String::new(),
+                        etags: Vec::new(),
+                    })
+            }
+            UploadRequest::ContentRange { url, chunk_bytes } => {
+                info!(
+                    "Uploading the core of {report_id} to {} in chunks",
+                    redactor().url(&url)
+                );
+                let target = UploadTarget::ContentRange { url, chunk_bytes };
+                self.upload_chunked(publisher, &report_id, target).await
+            }
+            UploadRequest::Multipart {
+                part_urls,
+                part_bytes,
+            } => {
+                info!(
+                    "Uploading the core of {report_id} in {} parts",
+                    part_urls.len()
+                );
+                let target = tokio::fs::metadata(&core)
+                    .await
+                    .map_err(DeviceManagerError::from)
+                    .and_then(|metadata| {
+                        UploadTarget::multipart(part_urls, part_bytes, metadata.len())
+                    });
+                match target {
+                    Ok(target) => self.upload_chunked(publisher, &report_id, target).await,
+                    Err(err) => Err(err),
+                }
+            }
+        };
+
+        self.finish_upload(publisher, report_id, result).await;
+    }
+
+    /// Upload the core of `report_id` in chunks to `target`, going on from the chunks already
+    /// acknowledged before a restart.
+    async fn upload_chunked(
+        &self,
+        publisher: &impl Publisher,
+        report_id: &str,
+        target: UploadTarget,
+    ) -> Result<UploadedFile, DeviceManagerError> {
+        let chunked = self.chunked.as_ref().ok_or_else(|| {
+            DeviceManagerError::UploadError("chunked uploads not enabled".to_owned())
+        })?;
+        let core = self.path(report_id, CORE_EXTENSION);
+        let total_bytes = tokio::fs::metadata(&core).await?.len();
+
+        let session = chunked.session(report_id, total_bytes, target);
+        self.send_chunks(publisher, chunked, session).await
+    }
+
+    async fn send_chunks(
+        &self,
+        publisher: &impl Publisher,
+        chunked: &ChunkedUploader,
+        mut session: UploadSession,
+    ) -> Result<UploadedFile, DeviceManagerError> {
+        let core = self.path(&session.id, CORE_EXTENSION);
+
+        while !session.is_complete() {
+            chunked.send_chunk(&mut session, &core).await?;
+
+            let progress = UploadResult {
+                report_id: session.id.clone(),
+                status: "InProgress".to_owned(),
+                uploaded_bytes: session.uploaded_bytes() as i64,
+                total_bytes: session.total_bytes as i64,
+                ..Default::default()
+            };
+            self.publish_result(publisher, progress).await;
+        }
+
+        chunked.complete(session, &core).await
+    }
+
+    /// Publish the outcome of the upload of `report_id`, removing the core once uploaded.
+    async fn finish_upload(
+        &self,
+        publisher: &impl Publisher,
+        report_id: String,
+        uploaded: Result<UploadedFile, DeviceManagerError>,
+    ) {
+        let core = self.path(&report_id, CORE_EXTENSION);
+        let total_bytes = std::fs::metadata(&core).map_or(0, |metadata| metadata.len() as i64);
+
+        let result = match uploaded {
+            Ok(file) => {
+                if let Err(err) = std::fs::remove_file(&core) {
+                    warn!("Unable to remove the uploaded core: {}", err);
+                }
+                UploadResult {
+                    report_id,
+                    status: "Uploaded".to_owned(),
+                    uploaded_bytes: total_bytes,
+                    total_bytes,
+                    sha256: file.sha256,
+                    etags: file.etags,
+                    ..Default::default()
+                }
+            }
+            Err(err) => {
+                warn!("Unable to upload the core of {report_id}: {:?}", err);
+                UploadResult {
+                    report_id,
+                    status: "Failed".to_owned(),
+                    message: err.to_string(),
+                    total_bytes,
+                    ..Default::default()
+                }
+            }
+        };
+        self.publish_result(publisher, result).await;
+    }
+
+    /// Go on with the chunked upload interrupted by the last shutdown.
+    pub async fn resume_upload(&self, publisher: &impl Publisher) {
+        let chunked = match &self.chunked {
+            Some(chunked) => chunked,
+            None => return,
+        };
+        let session = match chunked.pending() {
+            Some(session) => session,
+            None => return,
+        };
+        if !self.path(&session.id, CORE_EXTENSION).exists() {
+            chunked.clear();
+            return;
+        }
+
+        info!(
+            "Resuming the upload of the core of {} from {} bytes",
+            session.id,
+            session.uploaded_bytes()
+        );
+        let report_id = session.id.clone();
+        let uploaded = self.send_chunks(publisher, chunked, session).await;
+        self.finish_upload(publisher, report_id, uploaded).await;
+    }
+
+    async fn publish_result(&self, publisher: &impl Publisher, result: UploadResult) {
+        if let Err(err) = publisher
+            .send_object(CRASH_REPORT_INTERFACE, "/upload", result)
+            .await
+        {
+            warn!("Unable to publish the upload result: {:?}", err);
+        }
+    }
+
+    pub async fn run(
+        self,
+        publisher: &impl Publisher,
+        mut entries: mpsc::Receiver<CoredumpEntry>,
+        mut uploads: mpsc::Receiver<HashMap<String, AstarteType>>,
+    ) {
+        self.resume_upload(publisher).await;
+
+        loop {
+            tokio::select! {
+                Some(entry) = entries.recv() => self.handle_entry(publisher, entry).await,
+                Some(request) = uploads.recv() => self.handle_upload(publisher, request).await,
+                else => return,
+            }
+        }
+    }
+}
+
+/// Send the coredump entries logged from now on.
+async fn follow_journal(entries: mpsc::Sender<CoredumpEntry>) {
+    let child = tokio::process::Command::new("journalctl")
+        .args([
+            "--follow",
+            "--lines=0",
+            "--output=json",
+            &format!("MESSAGE_ID={COREDUMP_MESSAGE_ID}"),
+        ])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            error!("Unable to follow the coredump journal entries: {}", err);
+            return;
+        }
+    };
+
+    let stdout = match child.stdout.take() {
+        Some(stdout) => stdout,
+        None => return,
+    };
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let entry = serde_json::from_str(&line)
+            .ok()
+            .and_then(|fields| CoredumpEntry::from_journal(&fields));
+
+        match entry {
+            Some(entry) => {
+                if entries.send(entry).await.is_err() {
+                    return;
+                }
+            }
+            None => warn!("Unable to parse the coredump journal entry"),
+        }
+    }
+}
+
+/// Start reporting the crashes, the returned sender takes the upload requests.
+pub fn spawn<P>(
+    options: &CrashReportOptions,
+    store_directory: &Path,
+    client: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    sessions: Box<dyn StateRepository<UploadSession>>,
+    publisher: P,
+) -> Result<(mpsc::Sender<HashMap<String, AstarteType>>, JoinHandle<()>), DeviceManagerError>
+where
+    P: Publisher + 'static,
+{
+    let reporter = CrashReporter::new(
+        options,
+        store_directory.join("crash_reports"),
+        Box::new(HttpUploader::new(client.clone())),
+    )?
+    .with_chunked_uploader(Some(ChunkedUploader::new(client, clock, sessions)));
+    let (entries_tx, entries_rx) = mpsc::channel(8);
+    let (uploads_tx, uploads_rx) = mpsc::channel(8);
+
+    let handle = tokio::spawn(async move {
+        tokio::join!(
+            follow_journal(entries_tx),
+            reporter.run(&publisher, entries_rx, uploads_rx)
+        );
+    });
+
+    Ok((uploads_tx, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use astarte_sdk::types::AstarteType;
+    use chrono::{TimeZone, Utc};
+    use serde_json::Value;
+
+    use crate::chunked_upload::tests::parts_server;
+    use crate::chunked_upload::{ChunkedUploader, UploadSession, UploadTarget};
+    use crate::crash_reports::{
+        CoredumpEntry, CrashReport, CrashReportOptions, CrashReporter, MockCoreUploader,
+        UploadRequest, UploadResult,
+    };
+    use crate::data::MockPublisher;
+    use crate::interfaces::CRASH_REPORT_INTERFACE;
+    use crate::repository::StateRepository;
+    use crate::test_utils::{recording_publisher, ManualClock, MemoryStateRepository, Recording};
+
+    const JOURNAL_ENTRY: &str = r#"{
+        "MESSAGE_ID": "fc2e22bc6ee647b6b90729ab34a250b1",
+        "MESSAGE": "Process 4242 (sensord) of user 0 dumped core.\n\nModule /usr/bin/sensord with build-id 8f3a9c0d1e2b\nStack trace of thread 4242:\n#0  0x00007f raise (libc.so.6 + 0x3c)",
+        "COREDUMP_PID": "4242",
+        "COREDUMP_COMM": "sensord",
+        "COREDUMP_EXE": "/usr/bin/sensord",
+        "COREDUMP_SIGNAL": "11",
+        "COREDUMP_TIMESTAMP": "1654077600000000",
+        "COREDUMP_FILENAME": "/var/lib/systemd/coredump/core.sensord.0.b0071d.4242.1654077600000000.zst"
+    }"#;
+
+    fn entry(process: &str, minutes: u32, core_file: Option<&Path>) -> CoredumpEntry {
+        CoredumpEntry {
+            process: process.to_owned(),
+            executable: format!("/usr/bin/{process}"),
+            signal: 6,
+            timestamp: Utc.ymd(2022, 6, 1).and_hms(10, minutes, 0),
+            build_id: None,
+            core_file: core_file.map(Path::to_path_buf),
+        }
+    }
+
+    fn options(quota_bytes: u64) -> CrashReportOptions {
+        CrashReportOptions {
+            enabled: true,
+            processes: vec!["sensord".to_owned()],
+            capture_core: true,
+            max_core_bytes: Some(1024),
+            quota_bytes: Some(quota_bytes),
+        }
+    }
+
+    fn reporter(directory: &Path, quota_bytes: u64, uploader: MockCoreUploader) -> CrashReporter {
+        CrashReporter::new(
+            &options(quota_bytes),
+            directory.join("crash_reports"),
+            Box::new(uploader),
+        )
+        .unwrap()
+    }
+
+    /// Crash reports published on `/report`.
+    fn reports(recording: &Recording<CrashReport>) -> Vec<CrashReport> {
+        let published = recording.on(CRASH_REPORT_INTERFACE);
+        assert!(published.iter().all(|(path, _)| path == "/report"));
+
+        recording.objects(CRASH_REPORT_INTERFACE)
+    }
+
+    #[test]
+    fn journal_entry_parsed() {
+        let fields: HashMap<String, Value> = serde_json::from_str(JOURNAL_ENTRY).unwrap();
+
+        assert_eq!(
+            CoredumpEntry::from_journal(&fields),
+            Some(CoredumpEntry {
+                process: "sensord".to_owned(),
+                executable: "/usr/bin/sensord".to_owned(),
+                signal: 11,
+                timestamp: Utc.ymd(2022, 6, 1).and_hms(10, 0, 0),
+                build_id: Some("8f3a9c0d1e2b".to_owned()),
+                core_file: Some(
+                    "/var/lib/systemd/coredump/core.sensord.0.b0071d.4242.1654077600000000.zst"
+                        .into()
+                ),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn allowlisted_crash_captured_and_published() {
+        let store = tempfile::tempdir().unwrap();
+        let core = store.path().join("core.zst");
+        std::fs::write(&core, [7; 100]).unwrap();
+        let reporter = reporter(store.path(), 1 << 20, MockCoreUploader::new());
+        let (publisher, recording) = recording_publisher();
+
+        reporter
+            .handle_entry(&publisher, entry("sensord", 0, Some(&core)))
+            .await;
+        reporter
+            .handle_entry(&publisher, entry("bash", 1, Some(&core)))
+            .await;
+
+        let sent = reports(&recording);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].process, "sensord");
+        assert_eq!(sent[0].signal, 6);
+        assert_eq!(sent[0].core_bytes, 100);
+        let stored = store.path().join("crash_reports").join(&sent[0].report_id);
+        assert_eq!(
+            std::fs::read(stored.with_extension("core")).unwrap(),
+            [7; 100]
+        );
+        assert!(stored.with_extension("json").exists());
+    }
+
+    #[tokio::test]
+    async fn core_above_cap_not_captured() {
+        let store = tempfile::tempdir().unwrap();
+        let core = store.path().join("core.zst");
+        std::fs::write(&core, [7; 2048]).unwrap();
+        let reporter = reporter(store.path(), 1 << 20, MockCoreUploader::new());
+        let (publisher, recording) = recording_publisher();
+
+        reporter
+            .handle_entry(&publisher, entry("sensord", 0, Some(&core)))
+            .await;
+
+        assert_eq!(reports(&recording)[0].core_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn oldest_reports_pruned_under_quota() {
+        let store = tempfile::tempdir().unwrap();
+        let core = store.path().join("core.zst");
+        std::fs::write(&core, [7; 1000]).unwrap();
+        // room for two reports with their cores
+        let reporter = reporter(store.path(), 2 * 1000 + 1000, MockCoreUploader::new());
+        let (publisher, recording) = recording_publisher();
+
+        for minutes in 0..3 {
+            reporter
+                .handle_entry(&publisher, entry("sensord", minutes, Some(&core)))
+                .await;
+        }
+
+        let sent = reports(&recording);
+        let stored: Vec<String> = reporter
+            .stored()
+            .into_iter()
+            .map(|report| report.report_id)
+            .collect();
+        assert_eq!(
+            stored,
+            vec![sent[1].report_id.clone(), sent[2].report_id.clone()]
+        );
+        assert!(!reporter.path(&sent[0].report_id, "core").exists());
+    }
+
+    #[tokio::test]
+    async fn core_uploaded_on_request() {
+        let store = tempfile::tempdir().unwrap();
+        let core = store.path().join("core.zst");
+        std::fs::write(&core, [7; 100]).unwrap();
+        let mut uploader = MockCoreUploader::new();
+        uploader
+            .expect_upload()
+            .withf(|url, core| {
+                url == "https://storage.example.com/core?signature=abc"
+                    && std::fs::read(core).unwrap() == [7; 100]
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let reporter = reporter(store.path(), 1 << 20, uploader);
+        let (mut publisher, recording) = recording_publisher();
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let recorded = results.clone();
+        publisher
+            .expect_send_object()
+            .withf(|interface: &str, path: &str, _: &UploadResult| {
+                interface == CRASH_REPORT_INTERFACE && path == "/upload"
+            })
+            .returning(move |_, _, result: UploadResult| {
+                recorded.lock().unwrap().push(result);
+                Ok(())
+            });
+
+        reporter
+            .handle_entry(&publisher, entry("sensord", 0, Some(&core)))
+            .await;
+        let report_id = reports(&recording)[0].report_id.clone();
+        let request = |report_id: &str| {
+            HashMap::from([
+                (
+                    "reportId".to_owned(),
+                    AstarteType::String(report_id.to_owned()),
+                ),
+                (
+                    "url".to_owned(),
+                    AstarteType::String(
+                        "https://storage.example.com/core?signature=abc".to_owned(),
+                    ),
+                ),
+            ])
+        };
+        reporter
+            .handle_upload(&publisher, request(&report_id))
+            .await;
+        // the uploaded core is removed, the metadata is kept
+        reporter
+            .handle_upload(&publisher, request(&report_id))
+            .await;
+
+        let results = results.lock().unwrap();
+        assert_eq!(results[0].status, "Uploaded");
+        assert_eq!(results[1].status, "Unavailable");
+        assert!(!reporter.path(&report_id, "core").exists());
+        assert_eq!(reporter.stored().len(), 1);
+    }
+
+    #[test]
+    fn upload_modes_parsed() {
+        let request = |fields: Vec<(&str, AstarteType)>| {
+            let mut request: HashMap<String, AstarteType> = fields
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect();
+            request.insert("reportId".to_owned(), AstarteType::String("r1".to_owned()));
+            UploadRequest::parse(&request).map(|(_, upload)| upload)
+        };
+        let url = || AstarteType::String("https://storage/core".to_owned());
+
+        assert_eq!(
+            request(vec![("url", url())]),
+            Some(UploadRequest::Single {
+                url: "https://storage/core".to_owned()
+            })
+        );
+        assert_eq!(
+            request(vec![
+                ("url", url()),
+                ("chunkBytes", AstarteType::Integer(4096))
+            ]),
+            Some(UploadRequest::ContentRange {
+                url: "https://storage/core".to_owned(),
+                chunk_bytes: 4096,
+            })
+        );
+        assert_eq!(
+            request(vec![
+                (
+                    "partUrls",
+                    AstarteType::StringArray(vec!["https://s3/1".to_owned()])
+                ),
+                ("partBytes", AstarteType::LongInteger(5 << 20)),
+            ]),
+            Some(UploadRequest::Multipart {
+                part_urls: vec!["https://s3/1".to_owned()],
+                part_bytes: Some(5 << 20),
+            })
+        );
+        assert_eq!(request(vec![("chunkBytes", AstarteType::Integer(1))]), None);
+    }
+
+    #[tokio::test]
+    async fn interrupted_upload_resumed_on_start() {
+        let store = tempfile::tempdir().unwrap();
+        let (address, received) = parts_server(Vec::new());
+        let sessions = Arc::new(MemoryStateRepository::<UploadSession>::new());
+        // the first chunk was acknowledged before the restart
+        sessions
+            .write(&UploadSession {
+                id: "r1".to_owned(),
+                target: UploadTarget::ContentRange {
+                    url: format!("http://{address}/core"),
+                    chunk_bytes: 4096,
+                },
+                total_bytes: 10_000,
+                acknowledged: 1,
+                etags: Vec::new(),
+            })
+            .unwrap();
+        let reporter = reporter(store.path(), 1 << 20, MockCoreUploader::new())
+            .with_chunked_uploader(Some(ChunkedUploader::new(
+                reqwest::Client::new(),
+                Arc::new(ManualClock::new()),
+                Box::new(sessions.clone()),
+            )));
+        let core = reporter.path("r1", "core");
+        std::fs::write(&core, [7; 10_000]).unwrap();
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let recorded = results.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object()
+            .withf(|interface: &str, path: &str, _: &UploadResult| {
+                interface == CRASH_REPORT_INTERFACE && path == "/upload"
+            })
+            .returning(move |_, _, result: UploadResult| {
+                recorded.lock().unwrap().push(result);
+                Ok(())
+            });
+
+        reporter.resume_upload(&publisher).await;
+
+        let results = results.lock().unwrap();
+        let progress: Vec<(&str, i64)> = results
+            .iter()
+            .map(|result| (result.status.as_str(), result.uploaded_bytes))
+            .collect();
+        assert_eq!(
+            progress,
+            [
+                ("InProgress", 8192),
+                ("InProgress", 10_000),
+                ("Uploaded", 10_000)
+            ]
+        );
+        assert_eq!(
+            results[2].sha256,
+            "24c1c39d7cc002b6fae3656dff2e6d5f9b912a5a85c4ab2ba9d2a5e577adbc78"
+        );
+        assert_eq!(
+            received.lock().unwrap().keys().copied().collect::<Vec<_>>(),
+            [4096, 8192]
+        );
+        assert!(!core.exists());
+        assert!(!sessions.exists());
+    }
+}
@@ -0,0 +1,353 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Maintenance commands of the integrator, listed in the configuration.
+//!
+//! Only the configured names are run, each with its executable and fixed arguments: nothing of the
+//! received command reaches the process and no shell is involved. The runs of a command are
+//! serialized, different commands run concurrently. The exit code and the start of the output are
+//! sent with the result of the command.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::{CommandRequest, CommandResult};
+use crate::error::DeviceManagerError;
+use crate::redaction::redactor;
+
+pub const DEFAULT_CUSTOM_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bytes of stdout and of stderr sent with the result.
+const OUTPUT_LIMIT: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomCommandOptions {
+    /// Absolute path of the executable.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Time after which the process is killed, 60 seconds by default.
+    pub timeout_secs: Option<u64>,
+}
+
+/// What a process left once exited.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Unset when killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+struct CustomCommand {
+    options: CustomCommandOptions,
+    /// Held for the whole run.
+    running: Mutex<()>,
+}
+
+pub struct CustomCommands {
+    commands: HashMap<String, Arc<CustomCommand>>,
+}
+
+fn truncated(output: &[u8]) -> String {
+    String::from_utf8_lossy(&output[..output.len().min(OUTPUT_LIMIT)]).into_owned()
+}
+
+impl CustomCommands {
+    /// The commands of `options`, skipping the names that are not a single word and the relative
+    /// paths.
+    pub fn new(options: &HashMap<String, CustomCommandOptions>) -> Self {
+        let commands = options
+            .iter()
+            .filter(|(name, options)| {
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    warn!("Invalid custom command name {name:?}, ignoring it");
+                    return false;
+                }
+                if !options.path.is_absolute() {
+                    warn!(
+                        "The custom command {name} needs an absolute path, not {}",
+                        options.path.display()
+                    );
+                    return false;
+                }
+                true
+            })
+            .map(|(name, options)| {
+                let command = CustomCommand {
+                    options: options.clone(),
+                    running: Mutex::new(()),
+                };
+                (name.clone(), Arc::new(command))
+            })
+            .collect();
+
+        CustomCommands { commands }
+    }
+
+    /// Run the requested commands until `requests` is closed, sending their results on `results`.
+    pub async fn run(
+        &self,
+        mut requests: mpsc::Receiver<CommandRequest>,
+        results: mpsc::Sender<CommandResult>,
+    ) {
+        while let Some(request) = requests.recv().await {
+            let command = match self.commands.get(&request.command) {
+                Some(command) => command.clone(),
+                None => {
+                    warn!(
+                        "Command {} not in the custom commands, rejecting it",
+                        redactor().text(&request.command)
+                    );
+                    let rejected = Err(DeviceManagerError::InvalidCommand(request.command.clone()));
+                    results
+                        .send(CommandResult::new(&request, &rejected))
+                        .await
+                        .unwrap_or_else(|_| warn!("The command results stopped"));
+                    continue;
+                }
+            };
+
+            let results = results.clone();
+            tokio::spawn(async move {
+                let result = execute(&command, &request).await;
+                results
+                    .send(result)
+                    .await
+                    .unwrap_or_else(|_| warn!("The command results stopped"));
+            });
+        }
+    }
+}
+
+async fn execute(command: &CustomCommand, request: &CommandRequest) -> CommandResult {
+    let _running = command.running.lock().await;
+    let timeout = command
+        .options
+        .timeout_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CUSTOM_COMMAND_TIMEOUT);
+
+    info!("Running the custom command {}", request.command);
+    match spawn(&command.options, timeout).await {
+        Ok((true, output)) => CommandResult::new(request, &Ok(())).with_output(output),
+        Ok((false, output)) => {
+            let failed = Err(DeviceManagerError::CommandFailed(match output.exit_code {
+                Some(exit_code) => format!("exited with {exit_code}"),
+                None => "killed by a signal".to_owned(),
+            }));
+            CommandResult::new(request, &failed).with_output(output)
+        }
+        Err(err) => CommandResult::new(request, &Err(err)),
+    }
+}
+
+/// Run the process of `options`, returns whether it succeeded and its output.
+async fn spawn(
+    options: &CustomCommandOptions,
+    timeout: Duration,
+) -> Result<(bool, CommandOutput), DeviceManagerError> {
+    let child = tokio::process::Command::new(&options.path)
+        .args(&options.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // dropped on the timeout
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| DeviceManagerError::CommandFailed(format!("timed out after {timeout:?}")))??;
+
+    Ok((
+        output.status.success(),
+        CommandOutput {
+            exit_code: output.status.code(),
+            stdout: truncated(&output.stdout),
+            stderr: truncated(&output.stderr),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use tokio::sync::mpsc;
+
+    use crate::commands::{CommandRequest, CommandResult};
+    use crate::custom_commands::{CustomCommandOptions, CustomCommands, OUTPUT_LIMIT};
+
+    fn options(path: &str, args: &[&str], timeout_secs: Option<u64>) -> CustomCommandOptions {
+        CustomCommandOptions {
+            path: path.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout_secs,
+        }
+    }
+
+    fn script(dir: &Path, name: &str, content: &str) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Run `commands` requesting each of `requested`, returns the results by request id.
+    async fn run(
+        commands: HashMap<String, CustomCommandOptions>,
+        requested: &[&str],
+    ) -> HashMap<String, CommandResult> {
+        let commands = CustomCommands::new(&commands);
+        let (requests_tx, requests) = mpsc::channel(requested.len());
+        let (results_tx, mut results) = mpsc::channel(requested.len());
+        for (request_id, command) in requested.iter().enumerate() {
+            requests_tx
+                .send(CommandRequest::parse(&format!(
+                    "{command} requestId={request_id}"
+                )))
+                .await
+                .unwrap();
+        }
+        drop(requests_tx);
+
+        commands.run(requests, results_tx).await;
+
+        let mut received = HashMap::new();
+        while let Some(result) = results.recv().await {
+            received.insert(result.request_id.clone(), result);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn exit_code_reported() {
+        let results = run(
+            HashMap::from([
+                ("check".to_owned(), options("/bin/true", &[], None)),
+                ("calibrate".to_owned(), options("/bin/false", &[], None)),
+            ]),
+            &["check", "calibrate"],
+        )
+        .await;
+
+        assert!(results["0"].success);
+        assert_eq!(results["0"].exit_code, Some(0));
+        assert!(!results["1"].success);
+        assert_eq!(results["1"].exit_code, Some(1));
+        assert_eq!(results["1"].error, "command failed: exited with 1");
+    }
+
+    #[tokio::test]
+    async fn unlisted_commands_rejected() {
+        let results = run(
+            HashMap::from([
+                ("check".to_owned(), options("/bin/true", &[], None)),
+                ("relative".to_owned(), options("true", &[], None)),
+            ]),
+            &["check; reboot", "check extra", "relative", "rotate_logs"],
+        )
+        .await;
+
+        assert_eq!(results.len(), 4);
+        for result in results.values() {
+            assert!(!result.success);
+            assert!(result.error.starts_with("invalid command"), "{result:?}");
+            assert_eq!(result.exit_code, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn output_truncated() {
+        let results = run(
+            HashMap::from([(
+                "logs".to_owned(),
+                options(
+                    "/bin/sh",
+                    &[
+                        "-c",
+                        "head -c 5000 /dev/zero | tr '\\0' a; echo rotated >&2",
+                    ],
+                    None,
+                ),
+            )]),
+            &["logs"],
+        )
+        .await;
+
+        assert!(results["0"].success);
+        assert_eq!(
+            results["0"].stdout.as_deref(),
+            Some("a".repeat(OUTPUT_LIMIT).as_str())
+        );
+        assert_eq!(results["0"].stderr.as_deref(), Some("rotated\n"));
+    }
+
+    #[tokio::test]
+    async fn slow_command_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let sleeping = script(dir.path(), "sleeping.sh", "#!/bin/sh\nsleep 10\n");
+
+        let results = run(
+            HashMap::from([("sleep".to_owned(), options(&sleeping, &[], Some(1)))]),
+            &["sleep"],
+        )
+        .await;
+
+        assert!(!results["0"].success);
+        assert_eq!(results["0"].error, "command failed: timed out after 1s");
+    }
+
+    #[tokio::test]
+    async fn runs_of_a_command_serialized() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("runs.log");
+        let restart = script(
+            dir.path(),
+            "restart.sh",
+            "#!/bin/sh\necho start >> \"$1\"\nsleep 0.2\necho end >> \"$1\"\n",
+        );
+
+        let results = run(
+            HashMap::from([(
+                "restart".to_owned(),
+                options(&restart, &[log.to_str().unwrap()], None),
+            )]),
+            &["restart", "restart"],
+        )
+        .await;
+
+        assert!(results.values().all(|result| result.success));
+        assert_eq!(
+            std::fs::read_to_string(log).unwrap(),
+            "start\nend\nstart\nend\n"
+        );
+    }
+}
@@ -19,15 +19,25 @@
  */
 
 use astarte_sdk::builder::AstarteOptions;
-use astarte_sdk::{AstarteError, AstarteSdk};
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::{AstarteError, AstarteSdk, Clientbound};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::info;
 use serde::Serialize;
+use std::sync::{Arc, Mutex};
 
-use crate::data::Publisher;
+use crate::data::properties::{PropertyCache, PropertyOp, ReplayReport, REPLAY_CONCURRENCY};
+use crate::data::validation::PayloadValidator;
+use crate::data::{Publisher, Session, Subscriber};
 
 #[derive(Clone)]
 pub struct Astarte {
-    pub device_sdk: AstarteSdk,
+    device_sdk: Arc<Mutex<AstarteSdk>>,
+    /// Options of the SDK, to connect again.
+    opts: Arc<AstarteOptions>,
+    pub validator: Arc<PayloadValidator>,
+    properties: Arc<PropertyCache>,
 }
 
 #[async_trait]
@@ -41,15 +51,156 @@ impl Publisher for Astarte {
     where
         T: Serialize + Send,
     {
-        self.device_sdk
+        self.validator
+            .check_object(interface_name, interface_path, &data)?;
+
+        self.sdk()
             .send_object(interface_name, interface_path, data)
             .await
     }
+
+    async fn send(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+    ) -> Result<(), AstarteError> {
+        self.validator
+            .check_individual(interface_name, interface_path, &data)?;
+
+        let cached = self
+            .validator
+            .is_property(interface_name)
+            .then(|| PropertyOp::Set(data.clone()));
+
+        self.sdk()
+            .send(interface_name, interface_path, data)
+            .await?;
+
+        if let Some(op) = cached {
+            self.properties.record(interface_name, interface_path, op);
+        }
+
+        Ok(())
+    }
+
+    async fn unset(&self, interface_name: &str, interface_path: &str) -> Result<(), AstarteError> {
+        self.sdk().unset(interface_name, interface_path).await?;
+
+        if self.validator.is_property(interface_name) {
+            self.properties
+                .record(interface_name, interface_path, PropertyOp::Unset);
+        }
+
+        Ok(())
+    }
+
+    async fn send_object_with_timestamp<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        if !self
+            .validator
+            .has_explicit_timestamp(interface_name, interface_path)
+        {
+            return self.send_object(interface_name, interface_path, data).await;
+        }
+
+        self.validator
+            .check_object(interface_name, interface_path, &data)?;
+
+        self.sdk()
+            .send_object_with_timestamp(interface_name, interface_path, data, timestamp)
+            .await
+    }
+
+    async fn send_with_timestamp(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError> {
+        // the properties have no explicit timestamp, they are never cached here
+        if !self
+            .validator
+            .has_explicit_timestamp(interface_name, interface_path)
+        {
+            return self.send(interface_name, interface_path, data).await;
+        }
+
+        self.validator
+            .check_individual(interface_name, interface_path, &data)?;
+
+        self.sdk()
+            .send_with_timestamp(interface_name, interface_path, data, timestamp)
+            .await
+    }
 }
 
 impl Astarte {
-    pub async fn new(opts: &AstarteOptions) -> Result<Astarte, AstarteError> {
+    pub async fn new(
+        opts: AstarteOptions,
+        validator: Arc<PayloadValidator>,
+    ) -> Result<Astarte, AstarteError> {
         let device = AstarteSdk::new(&opts).await?;
-        Ok(Astarte { device_sdk: device })
+        Ok(Astarte {
+            device_sdk: Arc::new(Mutex::new(device)),
+            opts: Arc::new(opts),
+            validator,
+            properties: Arc::new(PropertyCache::default()),
+        })
+    }
+
+    /// The SDK used for the publishes.
+    pub fn sdk(&self) -> AstarteSdk {
+        self.device_sdk.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Session for Astarte {
+    type Subscriber = AstarteSdk;
+
+    fn subscriber(&self) -> AstarteSdk {
+        self.sdk()
+    }
+
+    async fn reconnect(&self) -> Result<AstarteSdk, AstarteError> {
+        let device = AstarteSdk::new(&self.opts).await?;
+        *self.device_sdk.lock().unwrap() = device.clone();
+        Ok(device)
+    }
+
+    async fn replay_properties(&self) -> ReplayReport {
+        info!("Replaying {} cached properties", self.properties.len());
+        let sdk = self.sdk();
+
+        self.properties
+            .replay(REPLAY_CONCURRENCY, |interface_name, interface_path, op| {
+                let sdk = sdk.clone();
+                async move {
+                    match op {
+                        PropertyOp::Set(data) => {
+                            sdk.send(&interface_name, &interface_path, data).await
+                        }
+                        PropertyOp::Unset => sdk.unset(&interface_name, &interface_path).await,
+                    }
+                }
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl Subscriber for AstarteSdk {
+    async fn poll(&mut self) -> Result<Clientbound, AstarteError> {
+        AstarteSdk::poll(self).await
     }
 }
@@ -0,0 +1,175 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Session of the runtime, on the connection of the SDK or on the one of the Astarte Message Hub.
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::{AstarteError, AstarteSdk, Clientbound};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::data::astarte::Astarte;
+use crate::data::message_hub::{AstarteMessageHubNode, MessageHubOptions, MessageHubSubscriber};
+use crate::data::properties::ReplayReport;
+use crate::data::{Publisher, Session, Subscriber};
+
+/// Library connecting to Astarte selected with `astarte_library`, the SDK when unset.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum AstarteLibrary {
+    /// MQTT connection of the runtime, with its own credentials.
+    AstarteDeviceSdk,
+    /// Node of the Astarte Message Hub, sharing the connection of the hub.
+    AstarteMessageHub(MessageHubOptions),
+}
+
+#[derive(Clone)]
+pub enum AstarteConnection {
+    Sdk(Astarte),
+    MessageHub(AstarteMessageHubNode),
+}
+
+#[async_trait]
+impl Publisher for AstarteConnection {
+    async fn send_object<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        match self {
+            AstarteConnection::Sdk(sdk) => {
+                sdk.send_object(interface_name, interface_path, data).await
+            }
+            AstarteConnection::MessageHub(node) => {
+                node.send_object(interface_name, interface_path, data).await
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+    ) -> Result<(), AstarteError> {
+        match self {
+            AstarteConnection::Sdk(sdk) => sdk.send(interface_name, interface_path, data).await,
+            AstarteConnection::MessageHub(node) => {
+                node.send(interface_name, interface_path, data).await
+            }
+        }
+    }
+
+    async fn unset(&self, interface_name: &str, interface_path: &str) -> Result<(), AstarteError> {
+        match self {
+            AstarteConnection::Sdk(sdk) => sdk.unset(interface_name, interface_path).await,
+            AstarteConnection::MessageHub(node) => node.unset(interface_name, interface_path).await,
+        }
+    }
+
+    async fn send_object_with_timestamp<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        match self {
+            AstarteConnection::Sdk(sdk) => {
+                sdk.send_object_with_timestamp(interface_name, interface_path, data, timestamp)
+                    .await
+            }
+            AstarteConnection::MessageHub(node) => {
+                node.send_object_with_timestamp(interface_name, interface_path, data, timestamp)
+                    .await
+            }
+        }
+    }
+
+    async fn send_with_timestamp(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError> {
+        match self {
+            AstarteConnection::Sdk(sdk) => {
+                sdk.send_with_timestamp(interface_name, interface_path, data, timestamp)
+                    .await
+            }
+            AstarteConnection::MessageHub(node) => {
+                node.send_with_timestamp(interface_name, interface_path, data, timestamp)
+                    .await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Session for AstarteConnection {
+    type Subscriber = AstarteSubscriber;
+
+    fn subscriber(&self) -> AstarteSubscriber {
+        match self {
+            AstarteConnection::Sdk(sdk) => AstarteSubscriber::Sdk(sdk.subscriber()),
+            AstarteConnection::MessageHub(node) => AstarteSubscriber::MessageHub(node.subscriber()),
+        }
+    }
+
+    async fn reconnect(&self) -> Result<AstarteSubscriber, AstarteError> {
+        Ok(match self {
+            AstarteConnection::Sdk(sdk) => AstarteSubscriber::Sdk(sdk.reconnect().await?),
+            AstarteConnection::MessageHub(node) => {
+                AstarteSubscriber::MessageHub(node.reconnect().await?)
+            }
+        })
+    }
+
+    async fn replay_properties(&self) -> ReplayReport {
+        match self {
+            AstarteConnection::Sdk(sdk) => sdk.replay_properties().await,
+            AstarteConnection::MessageHub(node) => node.replay_properties().await,
+        }
+    }
+}
+
+pub enum AstarteSubscriber {
+    Sdk(AstarteSdk),
+    MessageHub(MessageHubSubscriber),
+}
+
+#[async_trait]
+impl Subscriber for AstarteSubscriber {
+    async fn poll(&mut self) -> Result<Clientbound, AstarteError> {
+        match self {
+            AstarteSubscriber::Sdk(sdk) => Subscriber::poll(sdk).await,
+            AstarteSubscriber::MessageHub(subscriber) => subscriber.poll().await,
+        }
+    }
+}
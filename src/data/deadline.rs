@@ -0,0 +1,1290 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Deadline on the publishes, detecting the sends stalled on a half-open connection.
+
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::clock::Clock;
+use crate::data::mute::{InterfaceMutes, MuteMode};
+use crate::data::properties::ReplayReport;
+use crate::data::send_stats::SendStats;
+use crate::data::store_forward::{StoreForward, StoredSample, STORE_FORWARD_RETRY_PERIOD};
+use crate::data::Publisher;
+use crate::disk_guard::DiskGuard;
+use crate::interfaces::CONNECTION_STATS_INTERFACE;
+#[cfg(any(test, feature = "simulator"))]
+use crate::simulator::endpoint::OutboundDump;
+use crate::telemetry::backoff::TelemetryBackoff;
+
+/// Time granted to each publish, unless configured otherwise.
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+/// A reconnection is requested after this many consecutive timed out publishes.
+pub const RECONNECT_AFTER_TIMEOUTS: u32 = 3;
+/// Messages kept while offline, the oldest ones are dropped first.
+const OFFLINE_QUEUE_CAPACITY: usize = 256;
+
+pub(crate) enum QueuedPayload {
+    Individual(AstarteType),
+    Object(serde_json::Value),
+    Unset,
+}
+
+pub(crate) struct QueuedMessage {
+    interface_name: String,
+    interface_path: String,
+    payload: QueuedPayload,
+    /// Collection time of the payload, kept so the sent again messages are not dated at the flush.
+    timestamp: Option<DateTime<Utc>>,
+}
+
+/// Messages whose publish timed out, sent again once the connection is restored.
+#[derive(Default)]
+pub struct OfflineQueue {
+    messages: Mutex<VecDeque<QueuedMessage>>,
+}
+
+impl OfflineQueue {
+    fn push(&self, message: QueuedMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == OFFLINE_QUEUE_CAPACITY {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    fn pop(&self) -> Option<QueuedMessage> {
+        self.messages.lock().unwrap().pop_front()
+    }
+
+    fn push_front(&self, message: QueuedMessage) {
+        self.messages.lock().unwrap().push_front(message);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStats {
+    pub send_timeouts: u64,
+    pub queued_on_timeout: u64,
+    pub reconnects: u64,
+    pub last_replay_millis: u64,
+    pub replay_failures: u64,
+}
+
+/// Health of the connection as seen by the publishes.
+#[derive(Default)]
+pub struct ConnectionHealth {
+    send_timeouts: AtomicU64,
+    queued_on_timeout: AtomicU64,
+    reconnects: AtomicU64,
+    last_replay_millis: AtomicU64,
+    replay_failures: AtomicU64,
+    consecutive_timeouts: AtomicU32,
+    reconnect: Notify,
+}
+
+impl ConnectionHealth {
+    fn record_success(&self) {
+        self.consecutive_timeouts.store(0, Ordering::SeqCst);
+    }
+
+    fn record_timeout(&self) {
+        self.send_timeouts.fetch_add(1, Ordering::SeqCst);
+        let consecutive = self.consecutive_timeouts.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if consecutive >= RECONNECT_AFTER_TIMEOUTS {
+            warn!("{consecutive} consecutive publishes timed out, requesting a reconnection");
+            self.consecutive_timeouts.store(0, Ordering::SeqCst);
+            self.reconnect.notify_one();
+        }
+    }
+
+    pub fn record_replay(&self, report: &ReplayReport) {
+        self.last_replay_millis
+            .store(report.duration.as_millis() as u64, Ordering::SeqCst);
+        self.replay_failures
+            .fetch_add(report.failed_paths as u64, Ordering::SeqCst);
+    }
+
+    /// Wait until a reconnection is needed.
+    pub async fn reconnect_requested(&self) {
+        self.reconnect.notified().await;
+        self.reconnects.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Whether no publish timed out since the last successful one.
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_timeouts.load(Ordering::SeqCst) == 0
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            send_timeouts: self.send_timeouts.load(Ordering::SeqCst),
+            queued_on_timeout: self.queued_on_timeout.load(Ordering::SeqCst),
+            reconnects: self.reconnects.load(Ordering::SeqCst),
+            last_replay_millis: self.last_replay_millis.load(Ordering::SeqCst),
+            replay_failures: self.replay_failures.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Publish the counters on the ConnectionStats interface.
+    pub async fn publish(&self, publisher: &impl Publisher) -> Result<(), AstarteError> {
+        let stats = self.stats();
+
+        for (name, value) in [
+            ("sendTimeouts", stats.send_timeouts),
+            ("queuedOnTimeout", stats.queued_on_timeout),
+            ("reconnects", stats.reconnects),
+            ("lastReplayMillis", stats.last_replay_millis),
+            ("replayFailures", stats.replay_failures),
+        ] {
+            publisher
+                .send(
+                    CONNECTION_STATS_INTERFACE,
+                    &format!("/{name}"),
+                    AstarteType::LongInteger(value as i64),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Publisher giving up on the sends not completed within the deadline.
+///
+/// Timed out messages of the queue eligible interfaces are moved into the [`OfflineQueue`],
+/// the failed objects of the stored interfaces into the [`StoreForward`], the others are
+/// reported to the caller as a send error.
+#[derive(Clone)]
+pub struct DeadlinePublisher<P> {
+    inner: P,
+    clock: Arc<dyn Clock>,
+    timeout: Duration,
+    queue_eligible: Arc<HashSet<String>>,
+    health: Arc<ConnectionHealth>,
+    queue: Arc<OfflineQueue>,
+    disk_guard: Option<Arc<DiskGuard>>,
+    send_stats: Arc<SendStats>,
+    mutes: Option<Arc<InterfaceMutes>>,
+    backoff: Option<Arc<TelemetryBackoff>>,
+    store_forward: Option<Arc<StoreForward>>,
+    #[cfg(any(test, feature = "simulator"))]
+    outbound_dump: Option<Arc<OutboundDump>>,
+}
+
+impl<P: Publisher> DeadlinePublisher<P> {
+    pub fn new(
+        inner: P,
+        clock: Arc<dyn Clock>,
+        timeout: Duration,
+        queue_eligible: HashSet<String>,
+    ) -> Self {
+        DeadlinePublisher {
+            inner,
+            timeout,
+            queue_eligible: Arc::new(queue_eligible),
+            health: Arc::new(ConnectionHealth::default()),
+            queue: Arc::new(OfflineQueue::default()),
+            disk_guard: None,
+            send_stats: Arc::new(SendStats::new(clock.clone())),
+            mutes: None,
+            backoff: None,
+            store_forward: None,
+            #[cfg(any(test, feature = "simulator"))]
+            outbound_dump: None,
+            clock,
+        }
+    }
+
+    /// Stop queueing while `disk_guard` reports the store filesystem as full.
+    pub fn with_disk_guard(mut self, disk_guard: Arc<DiskGuard>) -> Self {
+        self.disk_guard = Some(disk_guard);
+        self
+    }
+
+    /// Record the outcome of the publishes in `send_stats`.
+    pub fn with_send_stats(mut self, send_stats: Arc<SendStats>) -> Self {
+        self.send_stats = send_stats;
+        self
+    }
+
+    /// Hold back the publishes on the interfaces muted in `mutes`.
+    pub fn with_mutes(mut self, mutes: Arc<InterfaceMutes>) -> Self {
+        self.mutes = Some(mutes);
+        self
+    }
+
+    /// Report the outcome of the publishes to `backoff`, when enabled.
+    pub fn with_backoff(mut self, backoff: Option<Arc<TelemetryBackoff>>) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Keep the failed objects of the telemetry in `store_forward`, when enabled.
+    pub fn with_store_forward(mut self, store_forward: Option<Arc<StoreForward>>) -> Self {
+        self.store_forward = store_forward;
+        self
+    }
+
+    /// Append every attempted publish to `outbound_dump`.
+    #[cfg(any(test, feature = "simulator"))]
+    pub fn with_outbound_dump(mut self, outbound_dump: Option<Arc<OutboundDump>>) -> Self {
+        self.outbound_dump = outbound_dump;
+        self
+    }
+
+    fn queueing(&self, interface_name: &str) -> bool {
+        self.queue_eligible.contains(interface_name)
+            && !self
+                .disk_guard
+                .as_ref()
+                .map_or(false, |disk_guard| disk_guard.is_degraded())
+    }
+
+    fn storing(&self, interface_name: &str) -> Option<&Arc<StoreForward>> {
+        self.store_forward
+            .as_ref()
+            .filter(|store| store.is_stored(interface_name))
+            .filter(|_| {
+                !self
+                    .disk_guard
+                    .as_ref()
+                    .map_or(false, |disk_guard| disk_guard.is_degraded())
+            })
+    }
+
+    /// Hand a publish on a muted interface over to the mutes, returning whether it was muted.
+    fn held(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        timestamp: Option<DateTime<Utc>>,
+        payload: impl FnOnce() -> Option<QueuedPayload>,
+    ) -> bool {
+        let mutes = match &self.mutes {
+            Some(mutes) if mutes.is_muted(interface_name) => mutes,
+            _ => return false,
+        };
+
+        let message = (mutes.mode() == MuteMode::Queue)
+            .then(payload)
+            .flatten()
+            .map(|payload| QueuedMessage {
+                interface_name: interface_name.to_owned(),
+                interface_path: interface_path.to_owned(),
+                payload,
+                timestamp,
+            });
+        mutes.hold(interface_name, message);
+
+        true
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn health(&self) -> &Arc<ConnectionHealth> {
+        &self.health
+    }
+
+    pub fn queue(&self) -> &OfflineQueue {
+        &self.queue
+    }
+
+    /// Send the publishes held while their interface was muted, through the offline queue.
+    pub(crate) async fn release(&self, messages: Vec<QueuedMessage>) -> Result<(), AstarteError> {
+        for message in messages {
+            self.queue.push(message);
+        }
+
+        self.flush_queue().await
+    }
+
+    /// Send the queued messages, stopping at the first failure or timeout.
+    pub async fn flush_queue(&self) -> Result<(), AstarteError> {
+        while let Some(message) = self.queue.pop() {
+            let send = async {
+                match (&message.payload, message.timestamp) {
+                    (QueuedPayload::Individual(data), Some(timestamp)) => {
+                        self.inner
+                            .send_with_timestamp(
+                                &message.interface_name,
+                                &message.interface_path,
+                                data.clone(),
+                                timestamp,
+                            )
+                            .await
+                    }
+                    (QueuedPayload::Individual(data), None) => {
+                        self.inner
+                            .send(
+                                &message.interface_name,
+                                &message.interface_path,
+                                data.clone(),
+                            )
+                            .await
+                    }
+                    (QueuedPayload::Object(data), Some(timestamp)) => {
+                        self.inner
+                            .send_object_with_timestamp(
+                                &message.interface_name,
+                                &message.interface_path,
+                                data.clone(),
+                                timestamp,
+                            )
+                            .await
+                    }
+                    (QueuedPayload::Object(data), None) => {
+                        self.inner
+                            .send_object(
+                                &message.interface_name,
+                                &message.interface_path,
+                                data.clone(),
+                            )
+                            .await
+                    }
+                    (QueuedPayload::Unset, _) => {
+                        self.inner
+                            .unset(&message.interface_name, &message.interface_path)
+                            .await
+                    }
+                }
+            };
+
+            let result = tokio::select! {
+                result = send => result,
+                _ = self.clock.sleep(self.timeout) => Err(AstarteError::SendError(
+                    "flush of the offline queue timed out".to_owned(),
+                )),
+            };
+
+            if let Err(err) = result {
+                self.queue.push_front(message);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send the stored telemetry oldest first, with its capture time, stopping at the first
+    /// failure or timeout.
+    pub async fn flush_stored(&self) -> Result<(), AstarteError> {
+        let store = match &self.store_forward {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        let mut result = Ok(());
+        let mut sent = 0;
+        while let Some(sample) = store.pop() {
+            let send = self.inner.send_object_with_timestamp(
+                &sample.interface_name,
+                &sample.interface_path,
+                sample.data.clone(),
+                sample.captured,
+            );
+            let sample_result = tokio::select! {
+                result = send => result,
+                _ = self.clock.sleep(self.timeout) => Err(AstarteError::SendError(
+                    "send of the stored telemetry timed out".to_owned(),
+                )),
+            };
+
+            if let Err(err) = sample_result {
+                store.push_front(sample);
+                result = Err(err);
+                break;
+            }
+            sent += 1;
+        }
+
+        if sent > 0 {
+            info!("Sent {sent} stored telemetry samples, {} left", store.len());
+            store.persist();
+        }
+
+        result
+    }
+
+    /// Send the stored telemetry after each successful publish, and periodically in case no
+    /// other publish happens.
+    pub async fn forward_stored(&self) {
+        let store = match &self.store_forward {
+            Some(store) => store.clone(),
+            None => return,
+        };
+
+        loop {
+            tokio::select! {
+                _ = store.delivered() => {}
+                _ = self.clock.sleep(STORE_FORWARD_RETRY_PERIOD) => {}
+            }
+
+            if store.is_empty() {
+                continue;
+            }
+            if let Err(err) = self.flush_stored().await {
+                debug!("Stored telemetry not sent yet: {err}");
+            }
+        }
+    }
+
+    fn record(&self, interface_name: &str, success: bool) {
+        self.send_stats.record(interface_name, success);
+        if let Some(backoff) = &self.backoff {
+            backoff.record(interface_name, success);
+        }
+        if let Some(store) = self.store_forward.as_ref().filter(|_| success) {
+            store.notify_delivered();
+        }
+    }
+
+    async fn with_deadline<F>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        timestamp: Option<DateTime<Utc>>,
+        send: F,
+        payload: impl FnOnce() -> Option<QueuedPayload>,
+    ) -> Result<(), AstarteError>
+    where
+        F: Future<Output = Result<(), AstarteError>>,
+    {
+        let result = tokio::select! {
+            result = send => Some(result),
+            _ = self.clock.sleep(self.timeout) => None,
+        };
+
+        if let Some(result) = result {
+            self.health.record_success();
+            self.record(interface_name, result.is_ok());
+            return result;
+        }
+
+        warn!("Publish on {interface_name}{interface_path} timed out");
+        self.health.record_timeout();
+        self.record(interface_name, false);
+
+        match payload().filter(|_| self.queueing(interface_name)) {
+            Some(payload) => {
+                self.health.queued_on_timeout.fetch_add(1, Ordering::SeqCst);
+                self.queue.push(QueuedMessage {
+                    interface_name: interface_name.to_owned(),
+                    interface_path: interface_path.to_owned(),
+                    payload,
+                    timestamp,
+                });
+                Ok(())
+            }
+            None => Err(AstarteError::SendError(format!(
+                "publish on {interface_name}{interface_path} timed out"
+            ))),
+        }
+    }
+
+    async fn publish_object<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        #[cfg(any(test, feature = "simulator"))]
+        {
+            if let Some(dump) = &self.outbound_dump {
+                dump.record(
+                    interface_name,
+                    interface_path,
+                    serde_json::to_value(&data).unwrap_or_default(),
+                );
+            }
+        }
+
+        if self.held(interface_name, interface_path, timestamp, || {
+            serde_json::to_value(&data).ok().map(QueuedPayload::Object)
+        }) {
+            return Ok(());
+        }
+
+        let value = (self.queueing(interface_name) || self.storing(interface_name).is_some())
+            .then(|| serde_json::to_value(&data).ok())
+            .flatten();
+        let queued = value.clone();
+
+        let send = async {
+            match timestamp {
+                Some(timestamp) => {
+                    self.inner
+                        .send_object_with_timestamp(interface_name, interface_path, data, timestamp)
+                        .await
+                }
+                None => {
+                    self.inner
+                        .send_object(interface_name, interface_path, data)
+                        .await
+                }
+            }
+        };
+
+        let result = self
+            .with_deadline(interface_name, interface_path, timestamp, send, || {
+                queued.map(QueuedPayload::Object)
+            })
+            .await;
+
+        match (result, value, self.storing(interface_name)) {
+            (Err(err), Some(data), Some(store)) => {
+                warn!("Publish on {interface_name}{interface_path} failed, stored: {err}");
+                store.store(StoredSample {
+                    interface_name: interface_name.to_owned(),
+                    interface_path: interface_path.to_owned(),
+                    data,
+                    captured: timestamp
+                        .unwrap_or_else(|| DateTime::<Utc>::from(self.clock.now_wall())),
+                });
+                Ok(())
+            }
+            (result, _, _) => result,
+        }
+    }
+
+    async fn publish_individual(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Result<(), AstarteError> {
+        #[cfg(any(test, feature = "simulator"))]
+        {
+            if let Some(dump) = &self.outbound_dump {
+                dump.record_individual(interface_name, interface_path, &data);
+            }
+        }
+
+        if self.held(interface_name, interface_path, timestamp, || {
+            Some(QueuedPayload::Individual(data.clone()))
+        }) {
+            return Ok(());
+        }
+
+        let queued = QueuedPayload::Individual(data.clone());
+
+        let send = async {
+            match timestamp {
+                Some(timestamp) => {
+                    self.inner
+                        .send_with_timestamp(interface_name, interface_path, data, timestamp)
+                        .await
+                }
+                None => self.inner.send(interface_name, interface_path, data).await,
+            }
+        };
+
+        self.with_deadline(interface_name, interface_path, timestamp, send, || {
+            Some(queued)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for DeadlinePublisher<P> {
+    async fn send_object<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        self.publish_object(interface_name, interface_path, data, None)
+            .await
+    }
+
+    async fn send(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+    ) -> Result<(), AstarteError> {
+        self.publish_individual(interface_name, interface_path, data, None)
+            .await
+    }
+
+    async fn unset(&self, interface_name: &str, interface_path: &str) -> Result<(), AstarteError> {
+        #[cfg(any(test, feature = "simulator"))]
+        {
+            if let Some(dump) = &self.outbound_dump {
+                dump.record(interface_name, interface_path, serde_json::Value::Null);
+            }
+        }
+
+        if self.held(interface_name, interface_path, None, || {
+            Some(QueuedPayload::Unset)
+        }) {
+            return Ok(());
+        }
+
+        self.with_deadline(
+            interface_name,
+            interface_path,
+            None,
+            self.inner.unset(interface_name, interface_path),
+            || None,
+        )
+        .await
+    }
+
+    async fn send_object_with_timestamp<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        self.publish_object(interface_name, interface_path, data, Some(timestamp))
+            .await
+    }
+
+    async fn send_with_timestamp(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError> {
+        self.publish_individual(interface_name, interface_path, data, Some(timestamp))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use astarte_sdk::AstarteError;
+    use async_trait::async_trait;
+    use chrono::{DateTime, TimeZone, Utc};
+    use serde::Serialize;
+
+    use crate::clock::Clock;
+    use crate::data::deadline::{
+        ConnectionHealth, ConnectionStats, DeadlinePublisher, RECONNECT_AFTER_TIMEOUTS,
+    };
+    use crate::data::store_forward::{StoreForward, STORE_FORWARD_RETRY_PERIOD};
+    use crate::data::{MockPublisher, Publisher};
+    use crate::disk_guard::tests::FakeSpace;
+    use crate::disk_guard::DiskGuard;
+    use crate::interfaces::CONNECTION_STATS_INTERFACE;
+    use crate::test_utils::{settle, ManualClock};
+
+    const TELEMETRY: &str = "io.edgehog.devicemanager.SystemStatus";
+    const COMMANDS: &str = "io.edgehog.devicemanager.Commands";
+
+    /// Publisher whose sends never complete while `stalled` is set.
+    #[derive(Clone, Default)]
+    struct StalledPublisher {
+        stalled: Arc<AtomicBool>,
+        sent: Arc<AtomicUsize>,
+        timestamps: Arc<Mutex<Vec<DateTime<Utc>>>>,
+    }
+
+    impl StalledPublisher {
+        async fn send_any(&self) -> Result<(), AstarteError> {
+            if self.stalled.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Publisher for StalledPublisher {
+        async fn send_object<T>(&self, _: &str, _: &str, _: T) -> Result<(), AstarteError>
+        where
+            T: Serialize + Send + 'static,
+        {
+            self.send_any().await
+        }
+
+        async fn send(&self, _: &str, _: &str, _: AstarteType) -> Result<(), AstarteError> {
+            self.send_any().await
+        }
+
+        async fn unset(&self, _: &str, _: &str) -> Result<(), AstarteError> {
+            self.send_any().await
+        }
+
+        async fn send_object_with_timestamp<T>(
+            &self,
+            _: &str,
+            _: &str,
+            _: T,
+            timestamp: DateTime<Utc>,
+        ) -> Result<(), AstarteError>
+        where
+            T: Serialize + Send + 'static,
+        {
+            self.send_any().await?;
+            self.timestamps.lock().unwrap().push(timestamp);
+            Ok(())
+        }
+
+        async fn send_with_timestamp(
+            &self,
+            _: &str,
+            _: &str,
+            _: AstarteType,
+            timestamp: DateTime<Utc>,
+        ) -> Result<(), AstarteError> {
+            self.send_any().await?;
+            self.timestamps.lock().unwrap().push(timestamp);
+            Ok(())
+        }
+    }
+
+    /// Object sent with its path, value and timestamp.
+    type SentObject = (String, serde_json::Value, Option<DateTime<Utc>>);
+
+    /// Publisher failing its first `failures` sends.
+    #[derive(Clone, Default)]
+    struct FlakyPublisher {
+        failures: Arc<AtomicUsize>,
+        sent: Arc<Mutex<Vec<SentObject>>>,
+    }
+
+    impl FlakyPublisher {
+        fn failing(failures: usize) -> Self {
+            let publisher = FlakyPublisher::default();
+            publisher.failures.store(failures, Ordering::SeqCst);
+            publisher
+        }
+
+        fn send_any<T: Serialize>(
+            &self,
+            path: &str,
+            data: T,
+            timestamp: Option<DateTime<Utc>>,
+        ) -> Result<(), AstarteError> {
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok();
+            if failing {
+                return Err(AstarteError::SendError("connection down".to_owned()));
+            }
+
+            let value = serde_json::to_value(data).unwrap();
+            self.sent
+                .lock()
+                .unwrap()
+                .push((path.to_owned(), value, timestamp));
+            Ok(())
+        }
+
+        fn sent(&self) -> Vec<SentObject> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Publisher for FlakyPublisher {
+        async fn send_object<T>(&self, _: &str, path: &str, data: T) -> Result<(), AstarteError>
+        where
+            T: Serialize + Send + 'static,
+        {
+            self.send_any(path, data, None)
+        }
+
+        async fn send(&self, _: &str, path: &str, _: AstarteType) -> Result<(), AstarteError> {
+            self.send_any(path, (), None)
+        }
+
+        async fn unset(&self, _: &str, path: &str) -> Result<(), AstarteError> {
+            self.send_any(path, (), None)
+        }
+
+        async fn send_object_with_timestamp<T>(
+            &self,
+            _: &str,
+            path: &str,
+            data: T,
+            timestamp: DateTime<Utc>,
+        ) -> Result<(), AstarteError>
+        where
+            T: Serialize + Send + 'static,
+        {
+            self.send_any(path, data, Some(timestamp))
+        }
+
+        async fn send_with_timestamp(
+            &self,
+            _: &str,
+            path: &str,
+            _: AstarteType,
+            timestamp: DateTime<Utc>,
+        ) -> Result<(), AstarteError> {
+            self.send_any(path, (), Some(timestamp))
+        }
+    }
+
+    fn storing_publisher(
+        clock: Arc<ManualClock>,
+        inner: FlakyPublisher,
+        directory: &std::path::Path,
+    ) -> (DeadlinePublisher<FlakyPublisher>, Arc<StoreForward>) {
+        let store = Arc::new(StoreForward::load(
+            directory,
+            100,
+            HashSet::from([TELEMETRY.to_owned()]),
+        ));
+        let publisher =
+            DeadlinePublisher::new(inner, clock, Duration::from_secs(10), HashSet::new())
+                .with_store_forward(Some(store.clone()));
+
+        (publisher, store)
+    }
+
+    fn deadline_publisher(
+        clock: Arc<ManualClock>,
+        inner: StalledPublisher,
+    ) -> DeadlinePublisher<StalledPublisher> {
+        DeadlinePublisher::new(
+            inner,
+            clock,
+            Duration::from_secs(10),
+            HashSet::from([TELEMETRY.to_owned()]),
+        )
+    }
+
+    #[tokio::test]
+    async fn stalled_send_unblocks_at_deadline() {
+        let clock = Arc::new(ManualClock::new());
+        let inner = StalledPublisher::default();
+        inner.stalled.store(true, Ordering::SeqCst);
+        let publisher = deadline_publisher(clock.clone(), inner.clone());
+
+        let task_publisher = publisher.clone();
+        let handle = tokio::spawn(async move {
+            task_publisher
+                .send(
+                    COMMANDS,
+                    "/request",
+                    AstarteType::String("Reboot".to_owned()),
+                )
+                .await
+        });
+
+        settle().await;
+        clock.advance(Duration::from_secs(9));
+        settle().await;
+        assert!(!handle.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(AstarteError::SendError(_))
+        ));
+        assert_eq!(publisher.queue().len(), 0);
+        assert_eq!(publisher.health().stats().send_timeouts, 1);
+    }
+
+    #[tokio::test]
+    async fn timed_out_telemetry_queued_and_flushed() {
+        let clock = Arc::new(ManualClock::new());
+        let inner = StalledPublisher::default();
+        inner.stalled.store(true, Ordering::SeqCst);
+        let publisher = deadline_publisher(clock.clone(), inner.clone());
+
+        let task_publisher = publisher.clone();
+        let handle = tokio::spawn(async move {
+            task_publisher
+                .send(TELEMETRY, "/uptime", AstarteType::LongInteger(1))
+                .await
+        });
+
+        settle().await;
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert!(handle.await.unwrap().is_ok());
+        assert_eq!(publisher.queue().len(), 1);
+
+        inner.stalled.store(false, Ordering::SeqCst);
+        publisher.flush_queue().await.unwrap();
+        assert_eq!(publisher.queue().len(), 0);
+        assert_eq!(inner.sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn queued_telemetry_keeps_its_timestamp() {
+        let clock = Arc::new(ManualClock::new());
+        let inner = StalledPublisher::default();
+        inner.stalled.store(true, Ordering::SeqCst);
+        let publisher = deadline_publisher(clock.clone(), inner.clone());
+        let collected = Utc.timestamp_opt(1_650_000_000, 0).unwrap();
+
+        let task_publisher = publisher.clone();
+        let handle = tokio::spawn(async move {
+            task_publisher
+                .send_with_timestamp(TELEMETRY, "/uptime", AstarteType::LongInteger(1), collected)
+                .await
+        });
+
+        settle().await;
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert!(handle.await.unwrap().is_ok());
+        assert_eq!(publisher.queue().len(), 1);
+
+        // sent again later, still dated at the collection
+        clock.advance(Duration::from_secs(60));
+        inner.stalled.store(false, Ordering::SeqCst);
+        publisher.flush_queue().await.unwrap();
+        assert_eq!(*inner.timestamps.lock().unwrap(), vec![collected]);
+    }
+
+    #[tokio::test]
+    async fn nothing_queued_while_disk_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let space = FakeSpace(Arc::new(std::sync::Mutex::new(0)));
+        let guard = Arc::new(DiskGuard::new(
+            Box::new(space),
+            dir.path().to_str().unwrap(),
+            100,
+            clock.clone(),
+        ));
+
+        let inner = StalledPublisher::default();
+        inner.stalled.store(true, Ordering::SeqCst);
+        let publisher = deadline_publisher(clock.clone(), inner).with_disk_guard(guard);
+
+        let task_publisher = publisher.clone();
+        let handle = tokio::spawn(async move {
+            task_publisher
+                .send(TELEMETRY, "/uptime", AstarteType::LongInteger(1))
+                .await
+        });
+
+        settle().await;
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert!(handle.await.unwrap().is_err());
+        assert_eq!(publisher.queue().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn reconnect_requested_after_consecutive_timeouts() {
+        let clock = Arc::new(ManualClock::new());
+        let inner = StalledPublisher::default();
+        inner.stalled.store(true, Ordering::SeqCst);
+        let publisher = deadline_publisher(clock.clone(), inner.clone());
+
+        let health_publisher = publisher.clone();
+        let reconnect = tokio::spawn(async move {
+            health_publisher.health().reconnect_requested().await;
+        });
+
+        for attempt in 1..=RECONNECT_AFTER_TIMEOUTS {
+            let task_publisher = publisher.clone();
+            let handle = tokio::spawn(async move {
+                task_publisher
+                    .send(TELEMETRY, "/uptime", AstarteType::LongInteger(1))
+                    .await
+            });
+            settle().await;
+            clock.advance(Duration::from_secs(10));
+            settle().await;
+            handle.await.unwrap().unwrap();
+
+            assert_eq!(reconnect.is_finished(), attempt == RECONNECT_AFTER_TIMEOUTS);
+        }
+
+        reconnect.await.unwrap();
+        assert_eq!(
+            publisher.health().stats(),
+            ConnectionStats {
+                send_timeouts: 3,
+                queued_on_timeout: 3,
+                reconnects: 1,
+                last_replay_millis: 0,
+                replay_failures: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_published_on_connection_stats() {
+        let health = ConnectionHealth::default();
+        health.record_timeout();
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sent_cloned = sent.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .returning(move |interface, path, data| {
+                sent_cloned
+                    .lock()
+                    .unwrap()
+                    .push((interface.to_owned(), path.to_owned(), data));
+                Ok(())
+            });
+
+        health.publish(&publisher).await.unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 5);
+        assert!(sent
+            .iter()
+            .all(|(interface, _, _)| interface == CONNECTION_STATS_INTERFACE));
+        assert_eq!(
+            (sent[0].1.as_str(), &sent[0].2),
+            ("/sendTimeouts", &AstarteType::LongInteger(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn successful_send_resets_timeouts() {
+        let clock = Arc::new(ManualClock::new());
+        let inner = StalledPublisher::default();
+        let publisher = deadline_publisher(clock.clone(), inner.clone());
+
+        for _ in 1..RECONNECT_AFTER_TIMEOUTS {
+            inner.stalled.store(true, Ordering::SeqCst);
+            let task_publisher = publisher.clone();
+            let handle = tokio::spawn(async move {
+                task_publisher
+                    .send(TELEMETRY, "/uptime", AstarteType::LongInteger(1))
+                    .await
+            });
+            settle().await;
+            clock.advance(Duration::from_secs(10));
+            settle().await;
+            handle.await.unwrap().unwrap();
+
+            inner.stalled.store(false, Ordering::SeqCst);
+            publisher
+                .send(TELEMETRY, "/uptime", AstarteType::LongInteger(1))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            publisher
+                .health()
+                .consecutive_timeouts
+                .load(Ordering::SeqCst),
+            0
+        );
+        assert_eq!(publisher.health().stats().reconnects, 0);
+    }
+
+    #[tokio::test]
+    async fn failed_telemetry_stored_and_replayed_in_order() {
+        let directory = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let inner = FlakyPublisher::failing(3);
+        let (publisher, store) = storing_publisher(clock, inner.clone(), directory.path());
+        let captured: Vec<DateTime<Utc>> = (0..3)
+            .map(|index| Utc.timestamp_opt(1_650_000_000 + index * 60, 0).unwrap())
+            .collect();
+
+        for (index, timestamp) in captured.iter().enumerate() {
+            publisher
+                .send_object_with_timestamp(TELEMETRY, "/systemStatus", index, *timestamp)
+                .await
+                .unwrap();
+        }
+        assert!(inner.sent().is_empty());
+        assert_eq!(store.len(), 3);
+
+        publisher.flush_stored().await.unwrap();
+
+        let expected: Vec<_> = captured
+            .iter()
+            .enumerate()
+            .map(|(index, timestamp)| {
+                (
+                    "/systemStatus".to_owned(),
+                    serde_json::json!(index),
+                    Some(*timestamp),
+                )
+            })
+            .collect();
+        assert_eq!(inner.sent(), expected);
+        assert!(store.is_empty());
+        // nothing left for the next start
+        let reloaded = StoreForward::load(directory.path(), 100, HashSet::new());
+        assert!(reloaded.is_empty());
+    }
+
+    #[tokio::test]
+    async fn untimed_telemetry_stored_at_its_failure() {
+        let directory = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let inner = FlakyPublisher::failing(1);
+        let (publisher, _) = storing_publisher(clock.clone(), inner.clone(), directory.path());
+        let failed_at = DateTime::<Utc>::from(clock.now_wall());
+
+        publisher
+            .send_object(TELEMETRY, "/systemStatus", 1)
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(300));
+        publisher.flush_stored().await.unwrap();
+
+        assert_eq!(
+            inner.sent(),
+            [(
+                "/systemStatus".to_owned(),
+                serde_json::json!(1),
+                Some(failed_at)
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_replay_keeps_the_remaining_samples() {
+        let directory = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let inner = FlakyPublisher::failing(2);
+        let (publisher, store) = storing_publisher(clock, inner.clone(), directory.path());
+        let collected = Utc.timestamp_opt(1_650_000_000, 0).unwrap();
+
+        for index in 0..2 {
+            publisher
+                .send_object_with_timestamp(TELEMETRY, "/systemStatus", index, collected)
+                .await
+                .unwrap();
+        }
+        inner.failures.store(1, Ordering::SeqCst);
+
+        assert!(publisher.flush_stored().await.is_err());
+        assert_eq!(store.len(), 2);
+        publisher.flush_stored().await.unwrap();
+        let values: Vec<_> = inner
+            .sent()
+            .into_iter()
+            .map(|(_, value, _)| value)
+            .collect();
+        assert_eq!(values, [serde_json::json!(0), serde_json::json!(1)]);
+    }
+
+    #[tokio::test]
+    async fn other_interfaces_not_stored() {
+        let directory = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let inner = FlakyPublisher::failing(1);
+        let (publisher, store) = storing_publisher(clock, inner, directory.path());
+
+        let result = publisher.send_object(COMMANDS, "/request", 1).await;
+
+        assert!(matches!(result, Err(AstarteError::SendError(_))));
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stored_telemetry_forwarded_once_sends_succeed() {
+        let directory = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let inner = FlakyPublisher::failing(3);
+        let (publisher, store) = storing_publisher(clock.clone(), inner.clone(), directory.path());
+        let collected = Utc.timestamp_opt(1_650_000_000, 0).unwrap();
+
+        let forwarding = publisher.clone();
+        let forward = tokio::spawn(async move { forwarding.forward_stored().await });
+        for index in 0..3 {
+            publisher
+                .send_object_with_timestamp(TELEMETRY, "/systemStatus", index, collected)
+                .await
+                .unwrap();
+        }
+        settle().await;
+        assert_eq!(store.len(), 3);
+
+        // the first successful publish wakes up the forwarding
+        publisher
+            .send_object_with_timestamp(TELEMETRY, "/systemStatus", 3, collected)
+            .await
+            .unwrap();
+        settle().await;
+
+        let values: Vec<_> = inner
+            .sent()
+            .into_iter()
+            .map(|(_, value, _)| value)
+            .collect();
+        // the live sample first, then the stored ones in order
+        assert_eq!(values, [3, 0, 1, 2].map(|index| serde_json::json!(index)));
+        assert!(store.is_empty());
+
+        // retried periodically even without other publishes
+        inner.failures.store(1, Ordering::SeqCst);
+        publisher
+            .send_object_with_timestamp(TELEMETRY, "/systemStatus", 4, collected)
+            .await
+            .unwrap();
+        settle().await;
+        assert_eq!(store.len(), 1);
+        clock.advance(STORE_FORWARD_RETRY_PERIOD);
+        settle().await;
+        assert!(store.is_empty());
+        assert_eq!(inner.sent().last().unwrap().1, serde_json::json!(4));
+
+        forward.abort();
+    }
+}
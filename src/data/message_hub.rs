@@ -0,0 +1,910 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Node of the Astarte Message Hub, sharing the connection to Astarte the hub opened for the
+//! processes of the device instead of opening one.
+//!
+//! The node attaches with the interfaces of the interfaces directory as its introspection and the
+//! hub streams back what Astarte sends on them. Each publish is a call of the gRPC API, the
+//! objects are typed with the mappings of their interface.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use astarte_message_hub_proto::astarte_data_type::Data;
+use astarte_message_hub_proto::astarte_data_type_individual::IndividualData;
+use astarte_message_hub_proto::astarte_message::Payload;
+use astarte_message_hub_proto::message_hub_client::MessageHubClient;
+use astarte_message_hub_proto::{
+    AstarteBinaryBlobArray, AstarteBooleanArray, AstarteDataType, AstarteDataTypeIndividual,
+    AstarteDataTypeObject, AstarteDateTimeArray, AstarteDoubleArray, AstarteIntegerArray,
+    AstarteLongIntegerArray, AstarteMessage, AstarteStringArray, AstarteUnset, Node,
+};
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::{Aggregation, AstarteError, Clientbound};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use log::{info, warn};
+use prost_types::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Streaming;
+use uuid::Uuid;
+
+use crate::data::properties::{PropertyCache, PropertyOp, ReplayReport, REPLAY_CONCURRENCY};
+use crate::data::validation::PayloadValidator;
+use crate::data::{Publisher, Session, Subscriber};
+use crate::error::DeviceManagerError;
+
+/// Endpoint of the hub, unless configured otherwise.
+pub const DEFAULT_MESSAGE_HUB_ENDPOINT: &str = "http://[::1]:50051";
+/// Id the runtime attaches as, unless configured otherwise: only one runs on a device.
+pub const DEFAULT_NODE_ID: &str = "e8a1c4a2-5b0f-5d4e-9a3c-6f2d1b7e0c95";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MessageHubOptions {
+    /// gRPC endpoint of the hub, `http://[::1]:50051` unless set.
+    pub endpoint: Option<String>,
+    /// UUID of the node of the runtime.
+    pub node_id: Option<String>,
+}
+
+impl MessageHubOptions {
+    pub(crate) fn endpoint(&self) -> Result<Endpoint, DeviceManagerError> {
+        let endpoint = self
+            .endpoint
+            .as_deref()
+            .unwrap_or(DEFAULT_MESSAGE_HUB_ENDPOINT);
+        let invalid = || DeviceManagerError::InvalidOption {
+            option: "astarte_library.endpoint".to_owned(),
+            reason: format!("{endpoint} is not an http URL"),
+        };
+
+        let parsed = Endpoint::from_shared(endpoint.to_owned()).map_err(|_| invalid())?;
+        if parsed.uri().scheme_str() != Some("http") || parsed.uri().host().is_none() {
+            return Err(invalid());
+        }
+
+        Ok(parsed)
+    }
+
+    pub(crate) fn node_id(&self) -> Result<Uuid, DeviceManagerError> {
+        let node_id = self.node_id.as_deref().unwrap_or(DEFAULT_NODE_ID);
+
+        Uuid::parse_str(node_id).map_err(|err| DeviceManagerError::InvalidOption {
+            option: "astarte_library.node_id".to_owned(),
+            reason: format!("{node_id} is not a UUID: {err}"),
+        })
+    }
+}
+
+/// Contents of the `.json` interfaces in `directory`, by file name.
+fn read_interfaces(directory: &Path) -> Result<Vec<Vec<u8>>, DeviceManagerError> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "json")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    paths.iter().map(|path| Ok(std::fs::read(path)?)).collect()
+}
+
+#[derive(Clone)]
+pub struct AstarteMessageHubNode {
+    client: MessageHubClient<Channel>,
+    node: Node,
+    pub validator: Arc<PayloadValidator>,
+    properties: Arc<PropertyCache>,
+    /// Messages of the attachment made at startup, until a subscriber takes them.
+    attached: Arc<Mutex<Option<Streaming<AstarteMessage>>>>,
+}
+
+impl AstarteMessageHubNode {
+    /// Attach to the hub as a node with the interfaces of `interfaces_directory`.
+    pub async fn attach(
+        opts: &MessageHubOptions,
+        interfaces_directory: &Path,
+        validator: Arc<PayloadValidator>,
+    ) -> Result<Self, DeviceManagerError> {
+        let endpoint = opts.endpoint()?;
+        let node = Node {
+            uuid: opts.node_id()?.to_string(),
+            interface_jsons: read_interfaces(interfaces_directory)?,
+        };
+        let hub = AstarteMessageHubNode {
+            client: MessageHubClient::new(endpoint.connect_lazy()),
+            node,
+            validator,
+            properties: Arc::new(PropertyCache::default()),
+            attached: Arc::new(Mutex::new(None)),
+        };
+
+        let stream = hub
+            .open()
+            .await
+            .map_err(|source| DeviceManagerError::MessageHub {
+                endpoint: endpoint.uri().to_string(),
+                source,
+            })?;
+        info!(
+            "Attached to the Astarte Message Hub at {} as node {} with {} interfaces",
+            endpoint.uri(),
+            hub.node.uuid,
+            hub.node.interface_jsons.len()
+        );
+        *hub.attached.lock().unwrap() = Some(stream);
+
+        Ok(hub)
+    }
+
+    async fn open(&self) -> Result<Streaming<AstarteMessage>, tonic::Status> {
+        let response = self.client.clone().attach(self.node.clone()).await?;
+
+        Ok(response.into_inner())
+    }
+
+    async fn publish(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        payload: Payload,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Result<(), AstarteError> {
+        let message = AstarteMessage {
+            interface_name: interface_name.to_owned(),
+            path: interface_path.to_owned(),
+            timestamp: timestamp.map(proto_timestamp),
+            payload: Some(payload),
+        };
+
+        self.client
+            .clone()
+            .send(message)
+            .await
+            .map_err(|status| AstarteError::SendError(format!("Astarte Message Hub: {status}")))?;
+
+        Ok(())
+    }
+
+    /// `data` with the types of the mappings of `interface_path`.
+    fn object_payload(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: &impl Serialize,
+    ) -> Result<Payload, AstarteError> {
+        let fields = match serde_json::to_value(data) {
+            Ok(Value::Object(fields)) => fields,
+            _ => {
+                return Err(AstarteError::SendError(format!(
+                    "{interface_name}{interface_path} is not sent an object"
+                )))
+            }
+        };
+
+        let mut object_data = HashMap::new();
+        for (field, value) in fields {
+            // the unset optional fields, left out like the SDK does
+            if value.is_null() {
+                continue;
+            }
+
+            let field_path = format!("{}/{field}", interface_path.trim_end_matches('/'));
+            let individual = self
+                .validator
+                .mapping_type(interface_name, &field_path)
+                .and_then(|mapping_type| typed_value(mapping_type, &value))
+                .and_then(proto_individual)
+                .ok_or_else(|| {
+                    AstarteError::SendError(format!(
+                        "{value} doesn't match a mapping of {interface_name}{field_path}"
+                    ))
+                })?;
+            object_data.insert(field, individual);
+        }
+
+        Ok(Payload::AstarteData(AstarteDataType {
+            data: Some(Data::AstarteObject(AstarteDataTypeObject { object_data })),
+        }))
+    }
+}
+
+fn individual_payload(data: AstarteType) -> Payload {
+    match proto_individual(data) {
+        Some(individual) => Payload::AstarteData(AstarteDataType {
+            data: Some(Data::AstarteIndividual(individual)),
+        }),
+        None => Payload::AstarteUnset(AstarteUnset {}),
+    }
+}
+
+#[async_trait]
+impl Publisher for AstarteMessageHubNode {
+    async fn send_object<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send,
+    {
+        self.validator
+            .check_object(interface_name, interface_path, &data)?;
+
+        let payload = self.object_payload(interface_name, interface_path, &data)?;
+        self.publish(interface_name, interface_path, payload, None)
+            .await
+    }
+
+    async fn send(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+    ) -> Result<(), AstarteError> {
+        self.validator
+            .check_individual(interface_name, interface_path, &data)?;
+
+        let cached = self
+            .validator
+            .is_property(interface_name)
+            .then(|| PropertyOp::Set(data.clone()));
+
+        self.publish(
+            interface_name,
+            interface_path,
+            individual_payload(data),
+            None,
+        )
+        .await?;
+
+        if let Some(op) = cached {
+            self.properties.record(interface_name, interface_path, op);
+        }
+
+        Ok(())
+    }
+
+    async fn unset(&self, interface_name: &str, interface_path: &str) -> Result<(), AstarteError> {
+        self.publish(
+            interface_name,
+            interface_path,
+            Payload::AstarteUnset(AstarteUnset {}),
+            None,
+        )
+        .await?;
+
+        if self.validator.is_property(interface_name) {
+            self.properties
+                .record(interface_name, interface_path, PropertyOp::Unset);
+        }
+
+        Ok(())
+    }
+
+    async fn send_object_with_timestamp<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        if !self
+            .validator
+            .has_explicit_timestamp(interface_name, interface_path)
+        {
+            return self.send_object(interface_name, interface_path, data).await;
+        }
+
+        self.validator
+            .check_object(interface_name, interface_path, &data)?;
+
+        let payload = self.object_payload(interface_name, interface_path, &data)?;
+        self.publish(interface_name, interface_path, payload, Some(timestamp))
+            .await
+    }
+
+    async fn send_with_timestamp(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError> {
+        // the properties have no explicit timestamp, they are never cached here
+        if !self
+            .validator
+            .has_explicit_timestamp(interface_name, interface_path)
+        {
+            return self.send(interface_name, interface_path, data).await;
+        }
+
+        self.validator
+            .check_individual(interface_name, interface_path, &data)?;
+
+        self.publish(
+            interface_name,
+            interface_path,
+            individual_payload(data),
+            Some(timestamp),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Session for AstarteMessageHubNode {
+    type Subscriber = MessageHubSubscriber;
+
+    fn subscriber(&self) -> MessageHubSubscriber {
+        MessageHubSubscriber {
+            node: self.clone(),
+            stream: self.attached.lock().unwrap().take(),
+        }
+    }
+
+    async fn reconnect(&self) -> Result<MessageHubSubscriber, AstarteError> {
+        let stream = self.open().await.map_err(receive_error)?;
+
+        Ok(MessageHubSubscriber {
+            node: self.clone(),
+            stream: Some(stream),
+        })
+    }
+
+    async fn replay_properties(&self) -> ReplayReport {
+        info!("Replaying {} cached properties", self.properties.len());
+
+        self.properties
+            .replay(REPLAY_CONCURRENCY, |interface_name, interface_path, op| {
+                let node = self.clone();
+                async move {
+                    let payload = match op {
+                        PropertyOp::Set(data) => individual_payload(data),
+                        PropertyOp::Unset => Payload::AstarteUnset(AstarteUnset {}),
+                    };
+                    node.publish(&interface_name, &interface_path, payload, None)
+                        .await
+                }
+            })
+            .await
+    }
+}
+
+fn receive_error(status: tonic::Status) -> AstarteError {
+    AstarteError::ReceiveError(format!("Astarte Message Hub: {status}"))
+}
+
+/// Messages streamed by the hub, attaching again after the stream broke.
+pub struct MessageHubSubscriber {
+    node: AstarteMessageHubNode,
+    stream: Option<Streaming<AstarteMessage>>,
+}
+
+#[async_trait]
+impl Subscriber for MessageHubSubscriber {
+    async fn poll(&mut self) -> Result<Clientbound, AstarteError> {
+        loop {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                attached @ None => {
+                    let stream = self.node.open().await.map_err(receive_error)?;
+                    attached.insert(stream)
+                }
+            };
+
+            let message = match stream.message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    self.stream = None;
+                    return Err(AstarteError::ReceiveError(
+                        "the Astarte Message Hub closed the stream".to_owned(),
+                    ));
+                }
+                Err(status) => {
+                    self.stream = None;
+                    return Err(receive_error(status));
+                }
+            };
+
+            match clientbound(message) {
+                Ok(clientbound) => return Ok(clientbound),
+                Err(reason) => warn!("Dropping a message of the Astarte Message Hub: {reason}"),
+            }
+        }
+    }
+}
+
+/// The message as polled from the SDK.
+fn clientbound(message: AstarteMessage) -> Result<Clientbound, String> {
+    let data = match message.payload {
+        Some(Payload::AstarteUnset(_)) => Aggregation::Individual(AstarteType::Unset),
+        Some(Payload::AstarteData(AstarteDataType {
+            data: Some(Data::AstarteIndividual(individual)),
+        })) => Aggregation::Individual(astarte_type(individual)?),
+        Some(Payload::AstarteData(AstarteDataType {
+            data: Some(Data::AstarteObject(object)),
+        })) => Aggregation::Object(
+            object
+                .object_data
+                .into_iter()
+                .map(|(field, individual)| Ok((field, astarte_type(individual)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        _ => {
+            return Err(format!(
+                "no payload on {}{}",
+                message.interface_name, message.path
+            ))
+        }
+    };
+
+    Ok(Clientbound {
+        interface: message.interface_name,
+        path: message.path,
+        data,
+    })
+}
+
+/// The JSON `value` of an object field as the type of its mapping, `None` when it doesn't match.
+fn typed_value(mapping_type: &str, value: &Value) -> Option<AstarteType> {
+    fn items<T>(value: &Value, item: impl Fn(&Value) -> Option<T>) -> Option<Vec<T>> {
+        value.as_array()?.iter().map(item).collect()
+    }
+    fn integer(value: &Value) -> Option<i32> {
+        value.as_i64().and_then(|value| i32::try_from(value).ok())
+    }
+    fn string(value: &Value) -> Option<String> {
+        value.as_str().map(str::to_owned)
+    }
+    fn date_time(value: &Value) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(value.as_str()?)
+            .ok()
+            .map(|date_time| date_time.with_timezone(&Utc))
+    }
+    fn blob(value: &Value) -> Option<Vec<u8>> {
+        items(value, |byte| {
+            byte.as_u64().and_then(|byte| u8::try_from(byte).ok())
+        })
+    }
+
+    match mapping_type {
+        "double" => value.as_f64().map(AstarteType::Double),
+        "integer" => integer(value).map(AstarteType::Integer),
+        "boolean" => value.as_bool().map(AstarteType::Boolean),
+        "longinteger" => value.as_i64().map(AstarteType::LongInteger),
+        "string" => string(value).map(AstarteType::String),
+        "binaryblob" => blob(value).map(AstarteType::BinaryBlob),
+        "datetime" => date_time(value).map(AstarteType::DateTime),
+        "doublearray" => items(value, Value::as_f64).map(AstarteType::DoubleArray),
+        "integerarray" => items(value, integer).map(AstarteType::IntegerArray),
+        "booleanarray" => items(value, Value::as_bool).map(AstarteType::BooleanArray),
+        "longintegerarray" => items(value, Value::as_i64).map(AstarteType::LongIntegerArray),
+        "stringarray" => items(value, string).map(AstarteType::StringArray),
+        "binaryblobarray" => items(value, blob).map(AstarteType::BinaryBlobArray),
+        "datetimearray" => items(value, date_time).map(AstarteType::DateTimeArray),
+        _ => None,
+    }
+}
+
+fn proto_timestamp(date_time: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: date_time.timestamp(),
+        // below 2 seconds, for the leap seconds
+        nanos: date_time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn date_time(timestamp: Timestamp) -> Result<DateTime<Utc>, String> {
+    u32::try_from(timestamp.nanos)
+        .ok()
+        .and_then(|nanos| Utc.timestamp_opt(timestamp.seconds, nanos).single())
+        .ok_or_else(|| format!("invalid timestamp {timestamp:?}"))
+}
+
+/// `data` as sent to the hub, `None` for [`AstarteType::Unset`].
+fn proto_individual(data: AstarteType) -> Option<AstarteDataTypeIndividual> {
+    let individual_data = match data {
+        AstarteType::Double(value) => IndividualData::AstarteDouble(value),
+        AstarteType::Integer(value) => IndividualData::AstarteInteger(value),
+        AstarteType::Boolean(value) => IndividualData::AstarteBoolean(value),
+        AstarteType::LongInteger(value) => IndividualData::AstarteLongInteger(value),
+        AstarteType::String(value) => IndividualData::AstarteString(value),
+        AstarteType::BinaryBlob(value) => IndividualData::AstarteBinaryBlob(value),
+        AstarteType::DateTime(value) => IndividualData::AstarteDateTime(proto_timestamp(value)),
+        AstarteType::DoubleArray(values) => {
+            IndividualData::AstarteDoubleArray(AstarteDoubleArray { values })
+        }
+        AstarteType::IntegerArray(values) => {
+            IndividualData::AstarteIntegerArray(AstarteIntegerArray { values })
+        }
+        AstarteType::BooleanArray(values) => {
+            IndividualData::AstarteBooleanArray(AstarteBooleanArray { values })
+        }
+        AstarteType::LongIntegerArray(values) => {
+            IndividualData::AstarteLongIntegerArray(AstarteLongIntegerArray { values })
+        }
+        AstarteType::StringArray(values) => {
+            IndividualData::AstarteStringArray(AstarteStringArray { values })
+        }
+        AstarteType::BinaryBlobArray(values) => {
+            IndividualData::AstarteBinaryBlobArray(AstarteBinaryBlobArray { values })
+        }
+        AstarteType::DateTimeArray(values) => {
+            IndividualData::AstarteDateTimeArray(AstarteDateTimeArray {
+                values: values.into_iter().map(proto_timestamp).collect(),
+            })
+        }
+        AstarteType::Unset => return None,
+    };
+
+    Some(AstarteDataTypeIndividual {
+        individual_data: Some(individual_data),
+    })
+}
+
+fn astarte_type(individual: AstarteDataTypeIndividual) -> Result<AstarteType, String> {
+    let data = match individual.individual_data {
+        Some(data) => data,
+        None => return Err("empty value".to_owned()),
+    };
+
+    Ok(match data {
+        IndividualData::AstarteDouble(value) => AstarteType::Double(value),
+        IndividualData::AstarteInteger(value) => AstarteType::Integer(value),
+        IndividualData::AstarteBoolean(value) => AstarteType::Boolean(value),
+        IndividualData::AstarteLongInteger(value) => AstarteType::LongInteger(value),
+        IndividualData::AstarteString(value) => AstarteType::String(value),
+        IndividualData::AstarteBinaryBlob(value) => AstarteType::BinaryBlob(value),
+        IndividualData::AstarteDateTime(value) => AstarteType::DateTime(date_time(value)?),
+        IndividualData::AstarteDoubleArray(array) => AstarteType::DoubleArray(array.values),
+        IndividualData::AstarteIntegerArray(array) => AstarteType::IntegerArray(array.values),
+        IndividualData::AstarteBooleanArray(array) => AstarteType::BooleanArray(array.values),
+        IndividualData::AstarteLongIntegerArray(array) => {
+            AstarteType::LongIntegerArray(array.values)
+        }
+        IndividualData::AstarteStringArray(array) => AstarteType::StringArray(array.values),
+        IndividualData::AstarteBinaryBlobArray(array) => AstarteType::BinaryBlobArray(array.values),
+        IndividualData::AstarteDateTimeArray(array) => AstarteType::DateTimeArray(
+            array
+                .values
+                .into_iter()
+                .map(date_time)
+                .collect::<Result<_, _>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+
+    use astarte_message_hub_proto::astarte_data_type::Data;
+    use astarte_message_hub_proto::astarte_data_type_individual::IndividualData;
+    use astarte_message_hub_proto::astarte_message::Payload;
+    use astarte_message_hub_proto::message_hub_server::{MessageHub, MessageHubServer};
+    use astarte_message_hub_proto::{
+        AstarteDataType, AstarteDataTypeIndividual, AstarteDataTypeObject, AstarteMessage, Node,
+    };
+    use astarte_sdk::Aggregation;
+    use chrono::{TimeZone, Utc};
+    use futures_util::stream::{self, Stream, StreamExt};
+    use tokio::net::TcpListener;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+    use uuid::Uuid;
+
+    use crate::data::message_hub::{
+        proto_timestamp, AstarteMessageHubNode, MessageHubOptions, DEFAULT_NODE_ID,
+    };
+    use crate::data::validation::{InterfaceIndex, PayloadValidator};
+    use crate::data::{Publisher, Session, Subscriber};
+    use crate::error::DeviceManagerError;
+    use crate::ota::messages::OtaRequest;
+    use crate::telemetry::system_status::SystemStatus;
+
+    const OTA_REQUEST: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.OTARequest",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "datastream",
+        "ownership": "server",
+        "aggregation": "object",
+        "mappings": [
+            { "endpoint": "/request/uuid", "type": "string" },
+            { "endpoint": "/request/url", "type": "string" }
+        ]
+    }"#;
+
+    const SYSTEM_STATUS: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.SystemStatus",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "datastream",
+        "ownership": "device",
+        "aggregation": "object",
+        "mappings": [
+            {
+                "endpoint": "/systemStatus/availMemoryBytes",
+                "type": "longinteger",
+                "explicit_timestamp": true
+            },
+            { "endpoint": "/systemStatus/bootId", "type": "string", "explicit_timestamp": true },
+            {
+                "endpoint": "/systemStatus/taskCount",
+                "type": "integer",
+                "explicit_timestamp": true
+            },
+            {
+                "endpoint": "/systemStatus/uptimeMillis",
+                "type": "longinteger",
+                "explicit_timestamp": true
+            }
+        ]
+    }"#;
+
+    type AttachStream = Pin<Box<dyn Stream<Item = Result<AstarteMessage, Status>> + Send>>;
+
+    /// Hub recording the attached nodes and the sent messages, streaming `clientbound` to the
+    /// nodes attaching.
+    #[derive(Clone, Default)]
+    struct MockHub {
+        nodes: Arc<Mutex<Vec<Node>>>,
+        sent: Arc<Mutex<Vec<AstarteMessage>>>,
+        clientbound: Arc<Mutex<Vec<AstarteMessage>>>,
+    }
+
+    #[tonic::async_trait]
+    impl MessageHub for MockHub {
+        type AttachStream = AttachStream;
+
+        async fn attach(&self, request: Request<Node>) -> Result<Response<AttachStream>, Status> {
+            self.nodes.lock().unwrap().push(request.into_inner());
+            let messages: Vec<_> = self.clientbound.lock().unwrap().drain(..).collect();
+
+            let stream = stream::iter(messages.into_iter().map(Ok)).chain(stream::pending());
+            Ok(Response::new(Box::pin(stream)))
+        }
+
+        async fn send(&self, request: Request<AstarteMessage>) -> Result<Response<()>, Status> {
+            self.sent.lock().unwrap().push(request.into_inner());
+            Ok(Response::new(()))
+        }
+
+        async fn detach(&self, _request: Request<Node>) -> Result<Response<()>, Status> {
+            Ok(Response::new(()))
+        }
+    }
+
+    /// Serve `hub` on a free port, returning its endpoint.
+    async fn serve(hub: MockHub) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| stream);
+            Some((accepted, listener))
+        });
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(MessageHubServer::new(hub))
+                .serve_with_incoming(incoming),
+        );
+
+        format!("http://{address}")
+    }
+
+    fn write_interfaces(directory: &Path) {
+        std::fs::write(directory.join("ota_request.json"), OTA_REQUEST).unwrap();
+        std::fs::write(directory.join("system_status.json"), SYSTEM_STATUS).unwrap();
+        std::fs::write(directory.join("README.md"), "not an interface").unwrap();
+    }
+
+    async fn attach(endpoint: String, directory: &Path) -> AstarteMessageHubNode {
+        let validator = PayloadValidator::new(InterfaceIndex::load(directory).unwrap(), true);
+        let opts = MessageHubOptions {
+            endpoint: Some(endpoint),
+            node_id: None,
+        };
+
+        AstarteMessageHubNode::attach(&opts, directory, Arc::new(validator))
+            .await
+            .unwrap()
+    }
+
+    fn string(value: &str) -> AstarteDataTypeIndividual {
+        AstarteDataTypeIndividual {
+            individual_data: Some(IndividualData::AstarteString(value.to_owned())),
+        }
+    }
+
+    #[test]
+    fn options_validated() {
+        let opts = MessageHubOptions::default();
+        assert_eq!(opts.endpoint().unwrap().uri(), "http://[::1]:50051/");
+        assert_eq!(
+            opts.node_id().unwrap(),
+            Uuid::parse_str(DEFAULT_NODE_ID).unwrap()
+        );
+
+        for endpoint in [
+            "unix:/run/astarte-message-hub.sock",
+            "https://hub:50051",
+            "hub",
+        ] {
+            let opts = MessageHubOptions {
+                endpoint: Some(endpoint.to_owned()),
+                node_id: None,
+            };
+            assert!(
+                matches!(
+                    opts.endpoint(),
+                    Err(DeviceManagerError::InvalidOption { ref option, .. })
+                        if option == "astarte_library.endpoint"
+                ),
+                "{endpoint}"
+            );
+        }
+
+        let opts = MessageHubOptions {
+            endpoint: None,
+            node_id: Some("edgehog".to_owned()),
+        };
+        assert!(opts.node_id().is_err());
+    }
+
+    #[tokio::test]
+    async fn interfaces_attached_as_node_introspection() {
+        let hub = MockHub::default();
+        let endpoint = serve(hub.clone()).await;
+        let directory = tempfile::tempdir().unwrap();
+        write_interfaces(directory.path());
+
+        attach(endpoint, directory.path()).await;
+
+        let nodes = hub.nodes.lock().unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].uuid, DEFAULT_NODE_ID);
+        assert_eq!(
+            nodes[0].interface_jsons,
+            vec![OTA_REQUEST.as_bytes(), SYSTEM_STATUS.as_bytes()]
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_hub_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let directory = tempfile::tempdir().unwrap();
+        write_interfaces(directory.path());
+        let validator =
+            PayloadValidator::new(InterfaceIndex::load(directory.path()).unwrap(), true);
+        let opts = MessageHubOptions {
+            endpoint: Some(endpoint),
+            node_id: None,
+        };
+
+        let attached =
+            AstarteMessageHubNode::attach(&opts, directory.path(), Arc::new(validator)).await;
+        assert!(matches!(
+            attached,
+            Err(DeviceManagerError::MessageHub { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn ota_request_and_telemetry_round_trip() {
+        let uuid = Uuid::new_v4();
+        let hub = MockHub::default();
+        hub.clientbound.lock().unwrap().push(AstarteMessage {
+            interface_name: "io.edgehog.devicemanager.OTARequest".to_owned(),
+            path: "/request".to_owned(),
+            timestamp: None,
+            payload: Some(Payload::AstarteData(AstarteDataType {
+                data: Some(Data::AstarteObject(AstarteDataTypeObject {
+                    object_data: HashMap::from([
+                        ("uuid".to_owned(), string(&uuid.to_string())),
+                        ("url".to_owned(), string("http://ota.bin")),
+                    ]),
+                })),
+            })),
+        });
+        let endpoint = serve(hub.clone()).await;
+        let directory = tempfile::tempdir().unwrap();
+        write_interfaces(directory.path());
+
+        let node = attach(endpoint, directory.path()).await;
+
+        let clientbound = node.subscriber().poll().await.unwrap();
+        assert_eq!(clientbound.interface, "io.edgehog.devicemanager.OTARequest");
+        assert_eq!(clientbound.path, "/request");
+        let request = match &clientbound.data {
+            Aggregation::Object(data) => OtaRequest::try_from(data).unwrap(),
+            Aggregation::Individual(data) => panic!("not an object: {data:?}"),
+        };
+        assert_eq!(request, OtaRequest::new(uuid, "http://ota.bin"));
+
+        let collected = Utc.timestamp_opt(1_660_000_000, 0).unwrap();
+        node.send_object_with_timestamp(
+            "io.edgehog.devicemanager.SystemStatus",
+            "/systemStatus",
+            SystemStatus {
+                avail_memory_bytes: 1 << 33,
+                boot_id: "d3ab7e4b".to_owned(),
+                task_count: 42,
+                uptime_millis: 3_600_000,
+            },
+            collected,
+        )
+        .await
+        .unwrap();
+
+        let sent = hub.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].interface_name,
+            "io.edgehog.devicemanager.SystemStatus"
+        );
+        assert_eq!(sent[0].path, "/systemStatus");
+        assert_eq!(sent[0].timestamp, Some(proto_timestamp(collected)));
+        let individual = |individual_data| AstarteDataTypeIndividual {
+            individual_data: Some(individual_data),
+        };
+        assert_eq!(
+            sent[0].payload,
+            Some(Payload::AstarteData(AstarteDataType {
+                data: Some(Data::AstarteObject(AstarteDataTypeObject {
+                    object_data: HashMap::from([
+                        (
+                            "availMemoryBytes".to_owned(),
+                            individual(IndividualData::AstarteLongInteger(1 << 33)),
+                        ),
+                        ("bootId".to_owned(), string("d3ab7e4b")),
+                        (
+                            "taskCount".to_owned(),
+                            individual(IndividualData::AstarteInteger(42)),
+                        ),
+                        (
+                            "uptimeMillis".to_owned(),
+                            individual(IndividualData::AstarteLongInteger(3_600_000)),
+                        ),
+                    ]),
+                })),
+            }))
+        );
+    }
+}
@@ -18,23 +18,83 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use astarte_sdk::AstarteError;
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::{AstarteError, Clientbound};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 #[cfg(test)]
 use mockall::automock;
 
+use crate::data::properties::ReplayReport;
+
 pub(crate) mod astarte;
+pub(crate) mod connection;
+pub(crate) mod deadline;
+pub(crate) mod message_hub;
+pub(crate) mod mute;
+pub(crate) mod properties;
+pub(crate) mod reconnect;
+pub(crate) mod send_stats;
+#[cfg(feature = "dbus")]
+pub(crate) mod service;
+pub(crate) mod store_forward;
+pub(crate) mod validation;
 
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Publisher: Send + Sync {
-    async fn send_object<T: 'static>(
+    async fn send_object<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+    ) -> Result<(), AstarteError>
+    where
+        T: serde::Serialize + Send + 'static;
+    async fn send(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+    ) -> Result<(), AstarteError>;
+    async fn unset(&self, interface_name: &str, interface_path: &str) -> Result<(), AstarteError>;
+    /// Send `data` collected at `timestamp`, the timestamp is dropped when the interface mapping
+    /// has no `explicit_timestamp`.
+    async fn send_object_with_timestamp<T>(
         &self,
         interface_name: &str,
         interface_path: &str,
         data: T,
+        timestamp: DateTime<Utc>,
     ) -> Result<(), AstarteError>
     where
-        T: serde::Serialize + Send;
-    //TODO add send and send_object_with_timestamp to this trait
+        T: serde::Serialize + Send + 'static;
+    /// Send `data` collected at `timestamp`, like [`Publisher::send_object_with_timestamp`].
+    async fn send_with_timestamp(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError>;
+}
+
+/// Source of the data sent by Astarte to the device.
+#[async_trait]
+pub trait Subscriber: Send {
+    /// Wait for the next message received from Astarte.
+    async fn poll(&mut self) -> Result<Clientbound, AstarteError>;
+}
+
+/// Connection to Astarte, publishing through [`Publisher`] and receiving through its subscriber.
+#[async_trait]
+pub trait Session: Publisher + Clone + 'static {
+    type Subscriber: Subscriber;
+
+    /// Source of the messages received on the current connection.
+    fn subscriber(&self) -> Self::Subscriber;
+    /// Connect again, replacing the connection used by every clone of the session.
+    async fn reconnect(&self) -> Result<Self::Subscriber, AstarteError>;
+    /// Publish again the cached properties on the current connection.
+    async fn replay_properties(&self) -> ReplayReport;
 }
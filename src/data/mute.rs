@@ -0,0 +1,454 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Interfaces muted by the backend, whose publishes are held back by the [`DeadlinePublisher`].
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::data::deadline::{DeadlinePublisher, QueuedMessage};
+use crate::data::Publisher;
+use crate::interfaces::{DIAGNOSTICS_INTERFACE, OTA_RESPONSE_INTERFACE};
+use crate::repository::StateRepository;
+
+/// Interfaces never muted, whatever the backend asks.
+const EXEMPT_INTERFACES: [&str; 1] = [OTA_RESPONSE_INTERFACE];
+/// Publishes held for each muted interface in the queue mode, the oldest ones are dropped first.
+const HELD_CAPACITY: usize = 256;
+
+/// What becomes of the publishes on a muted interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MuteMode {
+    /// Count and drop them.
+    Drop,
+    /// Hold them and send them once the interface is unmuted.
+    Queue,
+}
+
+impl Default for MuteMode {
+    fn default() -> Self {
+        MuteMode::Drop
+    }
+}
+
+/// Change of the `/request/{interface_name}/muted` property.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MuteEvent {
+    pub interface_name: String,
+    pub muted: bool,
+}
+
+impl MuteEvent {
+    /// Parse a property set on `/request/{interface_name}/muted`, unsetting it unmutes.
+    pub fn from_property(path: &[&str], value: &AstarteType) -> Option<Self> {
+        let interface_name = match path {
+            ["request", interface_name, "muted"] => *interface_name,
+            _ => return None,
+        };
+
+        let muted = match value {
+            AstarteType::Boolean(muted) => *muted,
+            AstarteType::Unset => false,
+            _ => return None,
+        };
+
+        Some(MuteEvent {
+            interface_name: interface_name.to_owned(),
+            muted,
+        })
+    }
+}
+
+/// The muted interfaces, persisted so that they stay muted across the restarts.
+pub struct InterfaceMutes {
+    mode: MuteMode,
+    muted: Mutex<BTreeSet<String>>,
+    dropped: Mutex<BTreeMap<String, u64>>,
+    held: Mutex<BTreeMap<String, VecDeque<QueuedMessage>>>,
+    repository: Box<dyn StateRepository<BTreeSet<String>>>,
+}
+
+impl InterfaceMutes {
+    pub fn new(mode: MuteMode, repository: Box<dyn StateRepository<BTreeSet<String>>>) -> Self {
+        let mut muted = BTreeSet::new();
+        if repository.exists() {
+            match repository.read() {
+                Ok(persisted) => muted = persisted,
+                Err(err) => warn!("Unable to read the muted interfaces: {:?}", err),
+            }
+        }
+        if !muted.is_empty() {
+            info!("Muted interfaces: {:?}", muted);
+        }
+
+        InterfaceMutes {
+            mode,
+            muted: Mutex::new(muted),
+            dropped: Mutex::new(BTreeMap::new()),
+            held: Mutex::new(BTreeMap::new()),
+            repository,
+        }
+    }
+
+    pub fn mode(&self) -> MuteMode {
+        self.mode
+    }
+
+    pub fn is_muted(&self, interface_name: &str) -> bool {
+        self.muted.lock().unwrap().contains(interface_name)
+    }
+
+    /// Publishes dropped on `interface_name` since the start.
+    pub fn dropped(&self, interface_name: &str) -> u64 {
+        self.dropped
+            .lock()
+            .unwrap()
+            .get(interface_name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn count_dropped(&self, interface_name: &str) {
+        *self
+            .dropped
+            .lock()
+            .unwrap()
+            .entry(interface_name.to_owned())
+            .or_default() += 1;
+    }
+
+    /// Take over a publish on a muted interface, holding it in the queue mode when it has a
+    /// payload to send later.
+    pub(crate) fn hold(&self, interface_name: &str, message: Option<QueuedMessage>) {
+        let message = match (self.mode, message) {
+            (MuteMode::Queue, Some(message)) => message,
+            _ => return self.count_dropped(interface_name),
+        };
+
+        let mut held = self.held.lock().unwrap();
+        let messages = held.entry(interface_name.to_owned()).or_default();
+        if messages.len() == HELD_CAPACITY {
+            messages.pop_front();
+            self.count_dropped(interface_name);
+        }
+        messages.push_back(message);
+    }
+
+    /// Apply `event`, returning whether the interface was unmuted and the publishes held for it.
+    pub(crate) fn apply(&self, event: &MuteEvent) -> Option<Vec<QueuedMessage>> {
+        if EXEMPT_INTERFACES.contains(&event.interface_name.as_str()) {
+            warn!("{} can't be muted", event.interface_name);
+            return None;
+        }
+
+        let mut muted = self.muted.lock().unwrap();
+        let changed = if event.muted {
+            muted.insert(event.interface_name.clone())
+        } else {
+            muted.remove(&event.interface_name)
+        };
+        if !changed {
+            return None;
+        }
+
+        if let Err(err) = self.repository.write(&muted) {
+            warn!("Unable to persist the muted interfaces: {:?}", err);
+        }
+
+        if event.muted {
+            info!("{} muted", event.interface_name);
+            return None;
+        }
+
+        info!(
+            "{} unmuted, {} publishes dropped",
+            event.interface_name,
+            self.dropped(&event.interface_name)
+        );
+        let held = self
+            .held
+            .lock()
+            .unwrap()
+            .remove(&event.interface_name)
+            .unwrap_or_default();
+
+        Some(held.into())
+    }
+}
+
+/// Apply the mute changes received from the backend.
+///
+/// When an interface is unmuted the publishes held for it are sent, the drop count is published
+/// on `/mutedSends/{interface_name}` of the diagnostics interface and `refresh` is called with the
+/// interface name, so that a fresh value is sent without waiting for the next period.
+pub async fn run<P, F, Fut>(
+    mutes: Arc<InterfaceMutes>,
+    publisher: &DeadlinePublisher<P>,
+    mut events: mpsc::Receiver<MuteEvent>,
+    refresh: F,
+) where
+    P: Publisher,
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    while let Some(event) = events.recv().await {
+        let held = match mutes.apply(&event) {
+            Some(held) => held,
+            None => continue,
+        };
+
+        if let Err(err) = publisher.release(held).await {
+            warn!("Unable to send the held publishes: {:?}", err);
+        }
+
+        if let Err(err) = publisher
+            .send(
+                DIAGNOSTICS_INTERFACE,
+                &format!("/mutedSends/{}", event.interface_name),
+                AstarteType::LongInteger(mutes.dropped(&event.interface_name) as i64),
+            )
+            .await
+        {
+            warn!("Unable to publish the muted sends: {:?}", err);
+        }
+
+        refresh(event.interface_name).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use crate::data::deadline::DeadlinePublisher;
+    use crate::data::mute::{self, InterfaceMutes, MuteEvent, MuteMode};
+    use crate::data::{MockPublisher, Publisher};
+    use crate::interfaces::{
+        DIAGNOSTICS_INTERFACE, OS_INFO_INTERFACE, OTA_RESPONSE_INTERFACE, SYSTEM_STATUS_INTERFACE,
+    };
+    use crate::ota::messages::OtaResponse;
+    use crate::test_utils::{recording_publisher, ManualClock, MemoryStateRepository, Recorded};
+
+    fn event(interface_name: &str, muted: bool) -> MuteEvent {
+        MuteEvent {
+            interface_name: interface_name.to_owned(),
+            muted,
+        }
+    }
+
+    fn publisher(
+        inner: MockPublisher,
+        mutes: Arc<InterfaceMutes>,
+    ) -> DeadlinePublisher<MockPublisher> {
+        DeadlinePublisher::new(
+            inner,
+            Arc::new(ManualClock::new()),
+            Duration::from_secs(10),
+            Default::default(),
+        )
+        .with_mutes(mutes)
+    }
+
+    #[test]
+    fn parse_property() {
+        assert_eq!(
+            MuteEvent::from_property(
+                &["request", OS_INFO_INTERFACE, "muted"],
+                &AstarteType::Boolean(true)
+            ),
+            Some(event(OS_INFO_INTERFACE, true))
+        );
+        assert_eq!(
+            MuteEvent::from_property(
+                &["request", OS_INFO_INTERFACE, "muted"],
+                &AstarteType::Unset
+            ),
+            Some(event(OS_INFO_INTERFACE, false))
+        );
+        assert_eq!(
+            MuteEvent::from_property(
+                &["request", OS_INFO_INTERFACE, "muted"],
+                &AstarteType::Integer(1)
+            ),
+            None
+        );
+        assert_eq!(
+            MuteEvent::from_property(&["request", OS_INFO_INTERFACE], &AstarteType::Unset),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn muted_sends_dropped_and_counted() {
+        let repository = Arc::new(MemoryStateRepository::<BTreeSet<String>>::new());
+        let mutes = Arc::new(InterfaceMutes::new(
+            MuteMode::Drop,
+            Box::new(repository.clone()),
+        ));
+        mutes.apply(&event(OS_INFO_INTERFACE, true));
+        mutes.apply(&event(OTA_RESPONSE_INTERFACE, true));
+
+        let mut inner = MockPublisher::new();
+        inner
+            .expect_send()
+            .withf(|interface, _, _| interface == SYSTEM_STATUS_INTERFACE)
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        inner
+            .expect_send_object()
+            .withf(|interface: &str, _: &str, _: &OtaResponse| interface == OTA_RESPONSE_INTERFACE)
+            .times(1)
+            .returning(|_, _, _: OtaResponse| Ok(()));
+        let publisher = publisher(inner, mutes.clone());
+
+        for _ in 0..3 {
+            publisher
+                .send(
+                    OS_INFO_INTERFACE,
+                    "/osName",
+                    AstarteType::String("Linux".to_owned()),
+                )
+                .await
+                .unwrap();
+        }
+        publisher
+            .unset(OS_INFO_INTERFACE, "/osVersion")
+            .await
+            .unwrap();
+        publisher
+            .send(SYSTEM_STATUS_INTERFACE, "/uptime", AstarteType::Integer(1))
+            .await
+            .unwrap();
+        // the OTA responses are exempt
+        publisher
+            .send_object(
+                OTA_RESPONSE_INTERFACE,
+                "/response",
+                OtaResponse {
+                    uuid: Uuid::nil(),
+                    status: "Done".to_owned(),
+                    status_code: String::new(),
+                    sequence: None,
+                    error_code: None,
+                    message: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(mutes.dropped(OS_INFO_INTERFACE), 4);
+        assert_eq!(mutes.dropped(SYSTEM_STATUS_INTERFACE), 0);
+        assert_eq!(
+            repository.value(),
+            Some(BTreeSet::from([OS_INFO_INTERFACE.to_owned()]))
+        );
+    }
+
+    #[test]
+    fn mutes_survive_restart() {
+        let repository = Arc::new(MemoryStateRepository::<BTreeSet<String>>::new());
+        let mutes = InterfaceMutes::new(MuteMode::Drop, Box::new(repository.clone()));
+        mutes.apply(&event(OS_INFO_INTERFACE, true));
+        mutes.apply(&event(SYSTEM_STATUS_INTERFACE, true));
+        mutes.apply(&event(SYSTEM_STATUS_INTERFACE, false));
+
+        let restarted = InterfaceMutes::new(MuteMode::Drop, Box::new(repository));
+
+        assert!(restarted.is_muted(OS_INFO_INTERFACE));
+        assert!(!restarted.is_muted(SYSTEM_STATUS_INTERFACE));
+    }
+
+    #[tokio::test]
+    async fn held_sends_released_and_refreshed_on_unmute() {
+        let mutes = Arc::new(InterfaceMutes::new(
+            MuteMode::Queue,
+            Box::new(MemoryStateRepository::new()),
+        ));
+        mutes.apply(&event(OS_INFO_INTERFACE, true));
+
+        let (inner, sent) = recording_publisher::<()>();
+        let publisher = publisher(inner, mutes.clone());
+
+        publisher
+            .send(
+                OS_INFO_INTERFACE,
+                "/osName",
+                AstarteType::String("Linux".to_owned()),
+            )
+            .await
+            .unwrap();
+        assert!(sent.all().is_empty());
+
+        let refreshed = Arc::new(Mutex::new(Vec::new()));
+        let (events_tx, events_rx) = mpsc::channel(4);
+        events_tx
+            .send(event(OS_INFO_INTERFACE, false))
+            .await
+            .unwrap();
+        // already unmuted, nothing to refresh
+        events_tx
+            .send(event(OS_INFO_INTERFACE, false))
+            .await
+            .unwrap();
+        drop(events_tx);
+        let recorded = refreshed.clone();
+        mute::run(
+            mutes.clone(),
+            &publisher,
+            events_rx,
+            move |interface_name| {
+                recorded.lock().unwrap().push(interface_name);
+                async {}
+            },
+        )
+        .await;
+
+        assert_eq!(
+            sent.all(),
+            vec![
+                (
+                    OS_INFO_INTERFACE.to_owned(),
+                    "/osName".to_owned(),
+                    Recorded::Individual(AstarteType::String("Linux".to_owned()))
+                ),
+                (
+                    DIAGNOSTICS_INTERFACE.to_owned(),
+                    format!("/mutedSends/{OS_INFO_INTERFACE}"),
+                    Recorded::Individual(AstarteType::LongInteger(0))
+                ),
+            ]
+        );
+        assert_eq!(*refreshed.lock().unwrap(), vec![OS_INFO_INTERFACE]);
+        assert!(!mutes.is_muted(OS_INFO_INTERFACE));
+    }
+}
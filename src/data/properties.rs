@@ -0,0 +1,357 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Cache of the device owned properties, replayed in batches on a new connection.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use futures_util::{stream, StreamExt};
+use log::{info, warn};
+use tokio::time::Instant;
+
+/// Paths replayed at the same time.
+pub const REPLAY_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyOp {
+    Set(AstarteType),
+    Unset,
+}
+
+#[derive(Debug)]
+struct CachedPath {
+    ops: Vec<PropertyOp>,
+    /// Operations replayed in the current round.
+    replayed: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub duration: Duration,
+    pub replayed_paths: usize,
+    pub failed_paths: usize,
+}
+
+/// Operations published on the properties, by interface and path.
+///
+/// Outside of a replay only the last operation of each path is kept. During a replay, or while a
+/// failed one is to be resumed, the operation at the replay point is kept too and the new one is
+/// sent after it.
+#[derive(Debug, Default)]
+pub struct PropertyCache {
+    paths: Mutex<BTreeMap<(String, String), CachedPath>>,
+    /// A replay failed and has to resume from where it stopped.
+    resuming: AtomicBool,
+    replaying: AtomicBool,
+}
+
+impl PropertyCache {
+    pub fn record(&self, interface_name: &str, interface_path: &str, op: PropertyOp) {
+        let mut paths = self.paths.lock().unwrap();
+        let cached = paths
+            .entry((interface_name.to_owned(), interface_path.to_owned()))
+            .or_insert_with(|| CachedPath {
+                ops: Vec::new(),
+                replayed: 0,
+            });
+
+        let busy = self.replaying.load(Ordering::SeqCst) || self.resuming.load(Ordering::SeqCst);
+        if busy {
+            // the operations before the replay point are sent, the ones after it are superseded
+            let replay_point = cached.replayed;
+            cached.ops.truncate(replay_point + 1);
+            cached.ops.drain(..replay_point);
+        } else {
+            cached.ops.clear();
+        }
+        cached.replayed = 0;
+        cached.ops.push(op);
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.lock().unwrap().len()
+    }
+
+    /// Send the cached operations through `send`, at most `concurrency` paths at a time and in
+    /// order within each path.
+    ///
+    /// The paths already replayed are skipped when resuming after a failed replay.
+    pub async fn replay<F, Fut>(&self, concurrency: usize, send: F) -> ReplayReport
+    where
+        F: Fn(String, String, PropertyOp) -> Fut,
+        Fut: Future<Output = Result<(), AstarteError>>,
+    {
+        let start = Instant::now();
+        self.replaying.store(true, Ordering::SeqCst);
+
+        let resuming = self.resuming.load(Ordering::SeqCst);
+        let pending: Vec<(String, String)> = {
+            let mut paths = self.paths.lock().unwrap();
+            if !resuming {
+                paths.values_mut().for_each(|cached| cached.replayed = 0);
+            }
+
+            // sorted by interface, so the paths of an interface are sent together
+            paths
+                .iter()
+                .filter(|(_, cached)| cached.replayed < cached.ops.len())
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+
+        let results: Vec<bool> = stream::iter(pending.iter())
+            .map(|(interface_name, interface_path)| {
+                self.replay_path(interface_name, interface_path, &send)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+
+        let failed_paths = results.iter().filter(|replayed| !**replayed).count();
+        self.resuming.store(failed_paths > 0, Ordering::SeqCst);
+        self.replaying.store(false, Ordering::SeqCst);
+
+        let report = ReplayReport {
+            duration: start.elapsed(),
+            replayed_paths: results.len() - failed_paths,
+            failed_paths,
+        };
+
+        if failed_paths > 0 {
+            warn!("Property replay incomplete: {:?}", report);
+        } else {
+            info!("Property replay completed: {:?}", report);
+        }
+
+        report
+    }
+
+    async fn replay_path<F, Fut>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        send: &F,
+    ) -> bool
+    where
+        F: Fn(String, String, PropertyOp) -> Fut,
+        Fut: Future<Output = Result<(), AstarteError>>,
+    {
+        let key = (interface_name.to_owned(), interface_path.to_owned());
+
+        loop {
+            let op = {
+                let paths = self.paths.lock().unwrap();
+                match paths.get(&key) {
+                    Some(cached) if cached.replayed < cached.ops.len() => {
+                        cached.ops[cached.replayed].clone()
+                    }
+                    _ => return true,
+                }
+            };
+
+            if let Err(err) = send(interface_name.to_owned(), interface_path.to_owned(), op).await {
+                warn!(
+                    "Unable to replay {interface_name}{interface_path}: {:?}",
+                    err
+                );
+                return false;
+            }
+
+            if let Some(cached) = self.paths.lock().unwrap().get_mut(&key) {
+                cached.replayed += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use astarte_sdk::types::AstarteType;
+    use astarte_sdk::AstarteError;
+
+    use crate::data::properties::{PropertyCache, PropertyOp};
+
+    const TAGS: &str = "io.edgehog.devicemanager.Tags";
+
+    fn large_cache() -> PropertyCache {
+        let cache = PropertyCache::default();
+        for i in 0..200 {
+            cache.record(
+                TAGS,
+                &format!("/tags/tag{i}"),
+                PropertyOp::Set(AstarteType::Boolean(true)),
+            );
+        }
+        cache
+    }
+
+    #[tokio::test]
+    async fn failed_replay_resumes() {
+        let cache = large_cache();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+
+        // every tenth path fails
+        let failing: HashSet<String> = (0..200)
+            .step_by(10)
+            .map(|i| format!("/tags/tag{i}"))
+            .collect();
+        let report = cache
+            .replay(8, |_, path, _| {
+                let sent = sent.clone();
+                let fail = failing.contains(&path);
+                async move {
+                    if fail {
+                        return Err(AstarteError::SendError("timeout".to_owned()));
+                    }
+                    sent.lock().unwrap().push(path);
+                    Ok(())
+                }
+            })
+            .await;
+
+        assert_eq!(report.replayed_paths, 180);
+        assert_eq!(report.failed_paths, 20);
+        assert_eq!(sent.lock().unwrap().len(), 180);
+
+        sent.lock().unwrap().clear();
+        let report = cache
+            .replay(8, |_, path, _| {
+                let sent = sent.clone();
+                async move {
+                    sent.lock().unwrap().push(path);
+                    Ok(())
+                }
+            })
+            .await;
+
+        assert_eq!(report.replayed_paths, 20);
+        assert_eq!(report.failed_paths, 0);
+        let resent: HashSet<String> = sent.lock().unwrap().iter().cloned().collect();
+        assert_eq!(resent, failing);
+
+        // a completed replay starts from zero on the next connection
+        let count = AtomicUsize::new(0);
+        cache
+            .replay(8, |_, _, _| {
+                count.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            })
+            .await;
+        assert_eq!(count.load(Ordering::SeqCst), 200);
+    }
+
+    #[tokio::test]
+    async fn operations_bounded_while_resuming() {
+        let cache = PropertyCache::default();
+        cache.record(TAGS, "/tags/pilot", PropertyOp::Unset);
+        let report = cache
+            .replay(8, |_, _, _| async {
+                Err(AstarteError::SendError("timeout".to_owned()))
+            })
+            .await;
+        assert_eq!(report.failed_paths, 1);
+
+        for i in 0..100 {
+            cache.record(
+                TAGS,
+                "/tags/pilot",
+                PropertyOp::Set(AstarteType::Boolean(i % 2 == 0)),
+            );
+        }
+
+        // the pending unset at the replay point and the last operation
+        let paths = cache.paths.lock().unwrap();
+        let cached = &paths[&(TAGS.to_owned(), "/tags/pilot".to_owned())];
+        assert_eq!(
+            cached.ops,
+            vec![
+                PropertyOp::Unset,
+                PropertyOp::Set(AstarteType::Boolean(false))
+            ]
+        );
+        assert_eq!(cached.replayed, 0);
+    }
+
+    #[tokio::test]
+    async fn ordering_within_a_path_preserved() {
+        let cache = PropertyCache::default();
+        cache.record(
+            TAGS,
+            "/tags/pilot",
+            PropertyOp::Set(AstarteType::Boolean(true)),
+        );
+        cache.record(TAGS, "/tags/pilot", PropertyOp::Unset);
+        // outside of a replay only the last operation matters
+        assert_eq!(cache.len(), 1);
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let first = Arc::new(AtomicUsize::new(0));
+        let report = cache
+            .replay(8, |_, _, op| {
+                let sent = sent.clone();
+                let first = first.clone();
+                async move {
+                    // fail the first attempt, then record what was sent
+                    if first.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return Err(AstarteError::SendError("timeout".to_owned()));
+                    }
+                    sent.lock().unwrap().push(op);
+                    Ok(())
+                }
+            })
+            .await;
+        assert_eq!(report.failed_paths, 1);
+
+        // recorded while resuming, appended after the pending unset
+        cache.record(
+            TAGS,
+            "/tags/pilot",
+            PropertyOp::Set(AstarteType::Boolean(true)),
+        );
+
+        let report = cache
+            .replay(8, |_, _, op| {
+                let sent = sent.clone();
+                async move {
+                    sent.lock().unwrap().push(op);
+                    Ok(())
+                }
+            })
+            .await;
+        assert_eq!(report.failed_paths, 0);
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                PropertyOp::Unset,
+                PropertyOp::Set(AstarteType::Boolean(true))
+            ]
+        );
+    }
+}
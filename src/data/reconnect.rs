@@ -0,0 +1,148 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Backoff between the failed polls of the Astarte connection, and the failures not worth
+//! retrying.
+
+use std::time::Duration;
+
+use astarte_sdk::AstarteError;
+
+/// Wait after the first failed poll.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest wait between two polls, however many failed.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Failures that retrying can't fix: the broker refused the credentials of the device, or its
+/// certificate expired. The connection errors are only exposed through their text.
+const FATAL_ERRORS: [&str; 5] = [
+    "NotAuthorized",
+    "BadUserNamePassword",
+    "CertExpired",
+    "InvalidCertificate(Expired)",
+    "certificate has expired",
+];
+
+/// Delay before polling again after a failure, doubling at each failure in a row.
+#[derive(Debug)]
+pub(crate) struct ReconnectBackoff {
+    min: Duration,
+    max: Duration,
+    /// Failures since the last successful poll.
+    attempt: u32,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        ReconnectBackoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY)
+    }
+}
+
+impl ReconnectBackoff {
+    pub(crate) fn new(min: Duration, max: Duration) -> Self {
+        ReconnectBackoff {
+            min,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Record a failed poll, returning how long to wait before the next one.
+    pub(crate) fn failed(&mut self) -> Duration {
+        let factor = 2_u32.saturating_pow(self.attempt);
+        self.attempt = self.attempt.saturating_add(1);
+
+        self.min.saturating_mul(factor).min(self.max)
+    }
+
+    /// Failures since the last successful poll.
+    pub(crate) fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Forget the failures once a poll succeeds, returning whether it was reconnecting.
+    pub(crate) fn reset(&mut self) -> bool {
+        std::mem::take(&mut self.attempt) > 0
+    }
+}
+
+/// Whether the poll failed for a reason that retrying won't fix.
+pub(crate) fn is_fatal(err: &AstarteError) -> bool {
+    let text = format!("{err:?}");
+
+    FATAL_ERRORS.iter().any(|fatal| text.contains(fatal))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use astarte_sdk::AstarteError;
+
+    use crate::data::reconnect::{is_fatal, ReconnectBackoff};
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let mut backoff = ReconnectBackoff::default();
+
+        let delays: Vec<u64> = (0..12).map(|_| backoff.failed().as_secs()).collect();
+
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 64, 128, 256, 300, 300, 300]);
+        assert_eq!(backoff.attempt(), 12);
+    }
+
+    #[test]
+    fn delay_capped_after_many_failures() {
+        let mut backoff = ReconnectBackoff::default();
+        for _ in 0..100 {
+            backoff.failed();
+        }
+
+        assert_eq!(backoff.failed(), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn reset_on_success() {
+        let mut backoff = ReconnectBackoff::new(Duration::from_millis(500), Duration::from_secs(3));
+        assert!(!backoff.reset());
+
+        backoff.failed();
+        backoff.failed();
+        assert_eq!(backoff.failed(), Duration::from_secs(2));
+
+        assert!(backoff.reset());
+        assert_eq!(backoff.attempt(), 0);
+        assert!(!backoff.reset());
+        assert_eq!(backoff.failed(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn fatal_errors_classified() {
+        let error = |text: &str| AstarteError::SendError(text.to_owned());
+
+        assert!(is_fatal(&error("ConnectionRefused(NotAuthorized)")));
+        assert!(is_fatal(&error(
+            "Tls(Io(Custom { kind: InvalidData, error: InvalidCertificate(Expired) }))"
+        )));
+        assert!(!is_fatal(&error(
+            "Io(Os { code: 111, kind: ConnectionRefused })"
+        )));
+        assert!(!is_fatal(&error("ConnectionRefused(ServiceUnavailable)")));
+    }
+}
@@ -0,0 +1,360 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Outcome of the publishes of each interface, telling when an interface last reached Astarte.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::repository::StateRepository;
+
+/// Consecutive failed publishes of an interface raising a diagnostics warning.
+pub const FAILURE_STREAK_WARNING: u32 = 5;
+/// How often the stats are persisted and published.
+pub const SEND_STATS_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceSendStats {
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    /// Publishes failed since the last successful one.
+    pub failure_streak: u32,
+    /// Whether the warning for the current streak was published.
+    #[serde(default)]
+    pub streak_warned: bool,
+}
+
+/// Send stats by interface name.
+pub type SendStatsMap = BTreeMap<String, InterfaceSendStats>;
+
+/// Tracks the publishes of every interface, persisted periodically and on shutdown so that
+/// they survive the restarts.
+pub struct SendStats {
+    clock: Arc<dyn Clock>,
+    stats: Mutex<SendStatsMap>,
+    repository: Option<Box<dyn StateRepository<SendStatsMap>>>,
+    dirty: AtomicBool,
+}
+
+impl SendStats {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        SendStats {
+            clock,
+            stats: Mutex::new(SendStatsMap::new()),
+            repository: None,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Persist the stats in `repository`, starting from the ones it holds.
+    pub fn with_repository(mut self, repository: Box<dyn StateRepository<SendStatsMap>>) -> Self {
+        if repository.exists() {
+            match repository.read() {
+                Ok(stats) => self.stats = Mutex::new(stats),
+                Err(err) => warn!("Unable to read the send stats: {:?}", err),
+            }
+        }
+
+        self.repository = Some(repository);
+        self
+    }
+
+    /// Record the outcome of a publish on `interface_name`.
+    pub fn record(&self, interface_name: &str, success: bool) {
+        let now = DateTime::<Utc>::from(self.clock.now_wall());
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(interface_name.to_owned()).or_default();
+
+        if success {
+            entry.last_success = Some(now);
+            entry.failure_streak = 0;
+            entry.streak_warned = false;
+        } else {
+            entry.last_failure = Some(now);
+            entry.failure_streak += 1;
+        }
+
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    pub fn get(&self, interface_name: &str) -> Option<InterfaceSendStats> {
+        self.stats.lock().unwrap().get(interface_name).cloned()
+    }
+
+    pub fn snapshot(&self) -> SendStatsMap {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Write the stats changed since the last call.
+    pub fn persist(&self) {
+        let repository = match &self.repository {
+            Some(repository) => repository,
+            None => return,
+        };
+
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Err(err) = repository.write(&self.snapshot()) {
+            warn!("Unable to persist the send stats: {:?}", err);
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Publish a warning for every interface whose failure streak reached
+    /// [`FAILURE_STREAK_WARNING`], once per streak.
+    pub async fn publish_warnings(&self, publisher: &impl Publisher) -> Result<(), AstarteError> {
+        let streaks: Vec<(String, u32)> = self
+            .stats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, stats)| {
+                stats.failure_streak >= FAILURE_STREAK_WARNING && !stats.streak_warned
+            })
+            .map(|(interface_name, stats)| (interface_name.clone(), stats.failure_streak))
+            .collect();
+
+        for (interface_name, streak) in streaks {
+            warn!("{streak} consecutive publishes failed on {interface_name}");
+            publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    "/sendFailureStreak/interface",
+                    AstarteType::String(interface_name.clone()),
+                )
+                .await?;
+            publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    "/sendFailureStreak/streak",
+                    AstarteType::LongInteger(streak.into()),
+                )
+                .await?;
+
+            if let Some(stats) = self.stats.lock().unwrap().get_mut(&interface_name) {
+                stats.streak_warned = true;
+            }
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    /// Publish the stats of every interface on `/sendStats` of the diagnostics interface.
+    pub async fn publish(&self, publisher: &impl Publisher) -> Result<(), AstarteError> {
+        let stats = serde_json::to_string(&self.snapshot())
+            .map_err(|err| AstarteError::SendError(err.to_string()))?;
+
+        publisher
+            .send(
+                DIAGNOSTICS_INTERFACE,
+                "/sendStats",
+                AstarteType::String(stats),
+            )
+            .await
+    }
+
+    /// Persist and publish the stats every period, never returns.
+    pub async fn run(&self, publisher: &impl Publisher, period: Duration) {
+        loop {
+            self.clock.sleep(period).await;
+
+            self.persist();
+            if let Err(err) = self.publish_warnings(publisher).await {
+                warn!("Unable to publish the send failure warnings: {:?}", err);
+            }
+            if let Err(err) = self.publish(publisher).await {
+                warn!("Unable to publish the send stats: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use astarte_sdk::AstarteError;
+    use chrono::{DateTime, Utc};
+
+    use crate::clock::Clock;
+    use crate::data::deadline::DeadlinePublisher;
+    use crate::data::send_stats::{
+        InterfaceSendStats, SendStats, SendStatsMap, FAILURE_STREAK_WARNING,
+    };
+    use crate::data::{MockPublisher, Publisher};
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::repository::StateRepository;
+    use crate::test_utils::{ManualClock, MemoryStateRepository};
+
+    const SYSTEM_STATUS: &str = "io.edgehog.devicemanager.SystemStatus";
+    const OS_INFO: &str = "io.edgehog.devicemanager.OSInfo";
+
+    /// SDK failing the SystemStatus publishes while `failing` is set.
+    fn flaky_sdk(failing: Arc<Mutex<bool>>) -> MockPublisher {
+        let mut sdk = MockPublisher::new();
+        sdk.expect_send().returning(move |interface, _, _| {
+            if interface == SYSTEM_STATUS && *failing.lock().unwrap() {
+                Err(AstarteError::SendError("test".to_owned()))
+            } else {
+                Ok(())
+            }
+        });
+        sdk
+    }
+
+    async fn send(publisher: &impl Publisher, interface_name: &str) -> Result<(), AstarteError> {
+        publisher
+            .send(interface_name, "/value", AstarteType::Boolean(true))
+            .await
+    }
+
+    #[tokio::test]
+    async fn mixed_sends_tracked_by_interface() {
+        let clock = Arc::new(ManualClock::new());
+        let failing = Arc::new(Mutex::new(false));
+        let stats = Arc::new(SendStats::new(clock.clone()));
+        let publisher = DeadlinePublisher::new(
+            flaky_sdk(failing.clone()),
+            clock.clone(),
+            Duration::from_secs(10),
+            HashSet::new(),
+        )
+        .with_send_stats(stats.clone());
+
+        send(&publisher, SYSTEM_STATUS).await.unwrap();
+        let succeeded = DateTime::<Utc>::from(clock.now_wall());
+
+        *failing.lock().unwrap() = true;
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(60));
+            assert!(send(&publisher, SYSTEM_STATUS).await.is_err());
+            send(&publisher, OS_INFO).await.unwrap();
+        }
+        let failed = DateTime::<Utc>::from(clock.now_wall());
+
+        assert_eq!(
+            stats.get(SYSTEM_STATUS),
+            Some(InterfaceSendStats {
+                last_success: Some(succeeded),
+                last_failure: Some(failed),
+                failure_streak: 3,
+                streak_warned: false,
+            })
+        );
+        assert_eq!(
+            stats.get(OS_INFO),
+            Some(InterfaceSendStats {
+                last_success: Some(failed),
+                ..Default::default()
+            })
+        );
+
+        *failing.lock().unwrap() = false;
+        send(&publisher, SYSTEM_STATUS).await.unwrap();
+        assert_eq!(stats.get(SYSTEM_STATUS).unwrap().failure_streak, 0);
+        assert_eq!(stats.get(SYSTEM_STATUS).unwrap().last_failure, Some(failed));
+    }
+
+    #[tokio::test]
+    async fn streak_warning_published_once() {
+        let clock = Arc::new(ManualClock::new());
+        let stats = SendStats::new(clock.clone());
+
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let recorded = warnings.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, _| {
+                interface == DIAGNOSTICS_INTERFACE && path == "/sendFailureStreak/interface"
+            })
+            .returning(move |_, _, data| {
+                recorded.lock().unwrap().push(data);
+                Ok(())
+            });
+        publisher
+            .expect_send()
+            .withf(|_, path, _| path == "/sendFailureStreak/streak")
+            .returning(|_, _, _| Ok(()));
+
+        for _ in 1..FAILURE_STREAK_WARNING {
+            stats.record(SYSTEM_STATUS, false);
+        }
+        stats.publish_warnings(&publisher).await.unwrap();
+        assert!(warnings.lock().unwrap().is_empty());
+
+        stats.record(SYSTEM_STATUS, false);
+        stats.publish_warnings(&publisher).await.unwrap();
+        stats.record(SYSTEM_STATUS, false);
+        stats.publish_warnings(&publisher).await.unwrap();
+        assert_eq!(
+            *warnings.lock().unwrap(),
+            vec![AstarteType::String(SYSTEM_STATUS.to_owned())]
+        );
+
+        // a new streak warns again
+        stats.record(SYSTEM_STATUS, true);
+        for _ in 0..FAILURE_STREAK_WARNING {
+            stats.record(SYSTEM_STATUS, false);
+        }
+        stats.publish_warnings(&publisher).await.unwrap();
+        assert_eq!(warnings.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn persisted_stats_restored() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::<SendStatsMap>::new());
+
+        let stats = SendStats::new(clock.clone()).with_repository(Box::new(repository.clone()));
+        stats.record(SYSTEM_STATUS, true);
+        stats.record(SYSTEM_STATUS, false);
+        stats.persist();
+        let persisted = repository.value().unwrap();
+        assert_eq!(persisted.get(SYSTEM_STATUS).unwrap().failure_streak, 1);
+
+        // nothing changed, nothing written
+        StateRepository::<SendStatsMap>::clear(repository.as_ref()).unwrap();
+        stats.persist();
+        assert!(repository.value().is_none());
+
+        repository.write(&persisted).unwrap();
+        let restored = SendStats::new(clock).with_repository(Box::new(repository));
+        assert_eq!(restored.snapshot(), persisted);
+    }
+}
@@ -0,0 +1,70 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! D-Bus API letting local tools read the send stats of the interfaces.
+
+use std::sync::Arc;
+
+use log::info;
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder};
+
+use crate::data::send_stats::SendStats;
+use crate::error::DeviceManagerError;
+
+pub const SEND_STATS_SERVICE_NAME: &str = "io.edgehog.SendStats";
+const SEND_STATS_SERVICE_PATH: &str = "/io/edgehog/SendStats";
+
+struct SendStatsService {
+    send_stats: Arc<SendStats>,
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> fdo::Result<String> {
+    serde_json::to_string(value).map_err(|err| fdo::Error::Failed(err.to_string()))
+}
+
+#[dbus_interface(name = "io.edgehog.SendStats1")]
+impl SendStatsService {
+    /// The stats of every interface published at least once, as a JSON object.
+    fn all(&self) -> fdo::Result<String> {
+        to_json(&self.send_stats.snapshot())
+    }
+
+    /// The stats of `interface_name`, as a JSON object.
+    fn interface(&self, interface_name: String) -> fdo::Result<String> {
+        match self.send_stats.get(&interface_name) {
+            Some(stats) => to_json(&stats),
+            None => Err(fdo::Error::InvalidArgs(format!(
+                "nothing published on {interface_name}"
+            ))),
+        }
+    }
+}
+
+/// Serve the send stats API on the system bus, until the returned connection is dropped.
+pub async fn serve(send_stats: Arc<SendStats>) -> Result<Connection, DeviceManagerError> {
+    let connection = ConnectionBuilder::system()?
+        .name(SEND_STATS_SERVICE_NAME)?
+        .serve_at(SEND_STATS_SERVICE_PATH, SendStatsService { send_stats })?
+        .build()
+        .await?;
+    info!("Send stats API available as {SEND_STATS_SERVICE_NAME}");
+
+    Ok(connection)
+}
@@ -0,0 +1,328 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Telemetry kept on disk while it can't be sent, sent again in order once the publishes succeed.
+//!
+//! The samples are appended to a JSON lines file, rewritten once they are sent or when the
+//! evicted ones make up half of it. The lines that can't be read are skipped at startup, and a
+//! sample sent right before a crash may be sent again at the next start.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+pub const STORE_FORWARD_FILE: &str = "store_forward.jsonl";
+/// Samples kept, unless configured otherwise.
+pub const DEFAULT_MAX_SAMPLES: usize = 10_000;
+/// Wait between the attempts to send the stored samples, besides the one after each successful
+/// publish.
+pub const STORE_FORWARD_RETRY_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StoreForwardOptions {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Directory of the stored samples, the store directory when unset.
+    pub directory: Option<String>,
+    /// Samples kept, the oldest ones are dropped first.
+    pub max_samples: Option<usize>,
+}
+
+/// Object publish that failed, with the time its data was collected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredSample {
+    pub interface_name: String,
+    pub interface_path: String,
+    pub data: serde_json::Value,
+    pub captured: DateTime<Utc>,
+}
+
+struct Samples {
+    queue: VecDeque<StoredSample>,
+    /// Lines of the file, the evicted samples included.
+    lines: usize,
+}
+
+/// Failed telemetry publishes, oldest first.
+pub struct StoreForward {
+    path: PathBuf,
+    max_samples: usize,
+    interfaces: HashSet<String>,
+    samples: Mutex<Samples>,
+    delivered: Notify,
+}
+
+impl StoreForward {
+    /// Load the samples left in `directory` by the previous runs, storing the failed publishes of
+    /// `interfaces`.
+    pub fn load(directory: &Path, max_samples: usize, interfaces: HashSet<String>) -> Self {
+        let path = directory.join(STORE_FORWARD_FILE);
+        let max_samples = max_samples.max(1);
+        let mut queue = VecDeque::new();
+        let mut lines = 0;
+        let mut corrupted = 0;
+
+        match fs::read(&path) {
+            Ok(content) => {
+                for line in String::from_utf8_lossy(&content).lines() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    lines += 1;
+                    match serde_json::from_str(line) {
+                        Ok(sample) => {
+                            if queue.len() == max_samples {
+                                queue.pop_front();
+                            }
+                            queue.push_back(sample);
+                        }
+                        Err(_) => corrupted += 1,
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Unable to read {}: {}", path.display(), err),
+        }
+
+        if corrupted > 0 {
+            warn!(
+                "Skipped {corrupted} unreadable samples of {}",
+                path.display()
+            );
+        }
+        if !queue.is_empty() {
+            info!("{} telemetry samples left to send", queue.len());
+        }
+
+        let store = StoreForward {
+            path,
+            max_samples,
+            interfaces,
+            samples: Mutex::new(Samples { queue, lines }),
+            delivered: Notify::new(),
+        };
+        let mut samples = store.samples.lock().unwrap();
+        if samples.lines != samples.queue.len() {
+            store.rewrite(&mut samples);
+        }
+        drop(samples);
+
+        store
+    }
+
+    /// Whether the failed publishes of `interface_name` are stored.
+    pub fn is_stored(&self, interface_name: &str) -> bool {
+        self.interfaces.contains(interface_name)
+    }
+
+    /// Append `sample`, dropping the oldest one when full.
+    pub fn store(&self, sample: StoredSample) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.queue.len() == self.max_samples {
+            debug!("Stored telemetry full, dropping the oldest sample");
+            samples.queue.pop_front();
+        }
+        let line = serde_json::to_string(&sample);
+        samples.queue.push_back(sample);
+
+        if samples.lines >= 2 * self.max_samples {
+            self.rewrite(&mut samples);
+            return;
+        }
+        let appended = line
+            .map_err(io::Error::from)
+            .and_then(|line| self.append(&line));
+        match appended {
+            Ok(()) => samples.lines += 1,
+            Err(err) => warn!(
+                "Unable to store the sample in {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn pop(&self) -> Option<StoredSample> {
+        self.samples.lock().unwrap().queue.pop_front()
+    }
+
+    pub(crate) fn push_front(&self, sample: StoredSample) {
+        self.samples.lock().unwrap().queue.push_front(sample);
+    }
+
+    /// Write the samples left, once some of them were sent.
+    pub(crate) fn persist(&self) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.lines != samples.queue.len() {
+            self.rewrite(&mut samples);
+        }
+    }
+
+    /// Record a successful publish, the connection is likely up again.
+    pub(crate) fn notify_delivered(&self) {
+        self.delivered.notify_one();
+    }
+
+    /// Wait for a successful publish.
+    pub(crate) async fn delivered(&self) {
+        self.delivered.notified().await;
+    }
+
+    fn append(&self, line: &str) -> io::Result<()> {
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        writeln!(file, "{line}")
+    }
+
+    fn rewrite(&self, samples: &mut Samples) {
+        let written = self.write_all(&samples.queue);
+        match written {
+            Ok(()) => samples.lines = samples.queue.len(),
+            Err(err) => warn!("Unable to write {}: {}", self.path.display(), err),
+        }
+    }
+
+    fn write_all(&self, queue: &VecDeque<StoredSample>) -> io::Result<()> {
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let temporary = self.path.with_extension("jsonl.tmp");
+        let mut content = String::new();
+        for sample in queue {
+            content += &serde_json::to_string(sample)?;
+            content.push('\n');
+        }
+        fs::write(&temporary, content)?;
+
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use crate::data::store_forward::{StoreForward, StoredSample, STORE_FORWARD_FILE};
+
+    const TELEMETRY: &str = "io.edgehog.devicemanager.SystemStatus";
+
+    fn sample(index: i64) -> StoredSample {
+        StoredSample {
+            interface_name: TELEMETRY.to_owned(),
+            interface_path: "/systemStatus".to_owned(),
+            data: json!({ "taskCount": index }),
+            captured: Utc.timestamp_opt(1_700_000_000 + index, 0).unwrap(),
+        }
+    }
+
+    fn load(directory: &Path, max_samples: usize) -> StoreForward {
+        StoreForward::load(
+            directory,
+            max_samples,
+            HashSet::from([TELEMETRY.to_owned()]),
+        )
+    }
+
+    fn drain(store: &StoreForward) -> Vec<StoredSample> {
+        std::iter::from_fn(|| store.pop()).collect()
+    }
+
+    #[test]
+    fn samples_survive_a_restart() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = load(directory.path(), 10);
+        store.store(sample(0));
+        store.store(sample(1));
+
+        let restarted = load(directory.path(), 10);
+
+        assert_eq!(drain(&restarted), [sample(0), sample(1)]);
+        restarted.persist();
+        assert!(load(directory.path(), 10).is_empty());
+    }
+
+    #[test]
+    fn oldest_samples_evicted() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = load(directory.path(), 2);
+        for index in 0..5 {
+            store.store(sample(index));
+        }
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(drain(&load(directory.path(), 2)), [sample(3), sample(4)]);
+        assert_eq!(drain(&store), [sample(3), sample(4)]);
+    }
+
+    #[test]
+    fn unreadable_lines_skipped() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(STORE_FORWARD_FILE);
+        let mut content = serde_json::to_vec(&sample(0)).unwrap();
+        content.extend_from_slice(b"\n{\"interfaceName\": \"trunc\n\xff\xfe\n");
+        content.extend(serde_json::to_vec(&sample(1)).unwrap());
+        // torn write of the last sample before a crash
+        content.extend_from_slice(b"\n{\"interfaceName\":\"io.edge");
+        std::fs::write(&path, content).unwrap();
+
+        let store = load(directory.path(), 10);
+
+        assert_eq!(store.len(), 2);
+        // the file is cleaned up at once
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert_eq!(drain(&store), [sample(0), sample(1)]);
+    }
+
+    #[test]
+    fn only_listed_interfaces_stored() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = load(directory.path(), 10);
+
+        assert!(store.is_stored(TELEMETRY));
+        assert!(!store.is_stored("io.edgehog.devicemanager.OTAResponse"));
+    }
+}
@@ -0,0 +1,837 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Validation of the outgoing payloads against the interfaces loaded from the interfaces
+//! directory, to catch mapping mismatches before they reach the SDK.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::path::Path;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use log::warn;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::error::DeviceManagerError;
+use crate::interfaces::InterfaceSpec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Individual,
+    Object,
+}
+
+impl Default for Aggregation {
+    fn default() -> Self {
+        Aggregation::Individual
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum InterfaceType {
+    Datastream,
+    Properties,
+}
+
+impl Default for InterfaceType {
+    fn default() -> Self {
+        InterfaceType::Datastream
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ownership {
+    Device,
+    Server,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Mapping {
+    endpoint: String,
+    #[serde(rename = "type")]
+    mapping_type: String,
+    #[serde(default)]
+    explicit_timestamp: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Interface {
+    interface_name: String,
+    version_major: i32,
+    version_minor: i32,
+    #[serde(rename = "type", default)]
+    interface_type: InterfaceType,
+    ownership: Ownership,
+    #[serde(default)]
+    aggregation: Aggregation,
+    mappings: Vec<Mapping>,
+}
+
+impl Interface {
+    fn mapping(&self, path: &str) -> Option<&Mapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| endpoint_matches(&mapping.endpoint, path))
+    }
+
+    /// Whether `path` is the endpoint of `mapping`, or its common path for an object.
+    fn covers(&self, mapping: &Mapping, path: &str) -> bool {
+        match self.aggregation {
+            Aggregation::Individual => endpoint_matches(&mapping.endpoint, path),
+            Aggregation::Object => mapping
+                .endpoint
+                .rsplit_once('/')
+                .map_or(false, |(parent, _)| endpoint_matches(parent, path)),
+        }
+    }
+
+    /// Whether `path` is a mapping endpoint, or the common path of the object mappings.
+    fn has_path(&self, path: &str) -> bool {
+        self.mappings
+            .iter()
+            .any(|mapping| self.covers(mapping, path))
+    }
+}
+
+fn endpoint_matches(endpoint: &str, path: &str) -> bool {
+    let endpoint = endpoint.trim_start_matches('/').split('/');
+    let path = path.trim_start_matches('/').split('/');
+
+    endpoint.clone().count() == path.clone().count()
+        && endpoint.zip(path).all(|(endpoint, path)| {
+            !path.is_empty() && (endpoint == path || endpoint.starts_with("%{"))
+        })
+}
+
+/// Mismatch between a payload and the interface mappings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    UnknownInterface(String),
+    NotDeviceOwned(String),
+    WrongOwnership(String),
+    Outdated {
+        interface: String,
+        version: (i32, i32),
+        minimum: (i32, i32),
+    },
+    WrongMajor {
+        interface: String,
+        version: (i32, i32),
+        major: i32,
+    },
+    WrongAggregation {
+        interface: String,
+    },
+    UnknownPath {
+        interface: String,
+        path: String,
+    },
+    WrongType {
+        interface: String,
+        path: String,
+        expected: String,
+        found: String,
+    },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::UnknownInterface(interface) => {
+                write!(f, "interface {interface} is not loaded")
+            }
+            ValidationError::NotDeviceOwned(interface) => {
+                write!(f, "interface {interface} is not device owned")
+            }
+            ValidationError::WrongOwnership(interface) => {
+                write!(f, "wrong ownership for interface {interface}")
+            }
+            ValidationError::Outdated {
+                interface,
+                version: (major, minor),
+                minimum: (minimum_major, minimum_minor),
+            } => write!(
+                f,
+                "interface {interface} is version {major}.{minor}, at least \
+                 {minimum_major}.{minimum_minor} is needed"
+            ),
+            ValidationError::WrongMajor {
+                interface,
+                version: (found_major, minor),
+                major,
+            } => write!(
+                f,
+                "interface {interface} is version {found_major}.{minor}, major {major} is needed"
+            ),
+            ValidationError::WrongAggregation { interface } => {
+                write!(f, "wrong aggregation for interface {interface}")
+            }
+            ValidationError::UnknownPath { interface, path } => {
+                write!(f, "no mapping for {interface}{path}")
+            }
+            ValidationError::WrongType {
+                interface,
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "mapping {interface}{path} is {expected}, the payload is {found}"
+            ),
+        }
+    }
+}
+
+fn astarte_type_name(data: &AstarteType) -> &'static str {
+    match data {
+        AstarteType::Double(_) => "double",
+        AstarteType::Integer(_) => "integer",
+        AstarteType::Boolean(_) => "boolean",
+        AstarteType::LongInteger(_) => "longinteger",
+        AstarteType::String(_) => "string",
+        AstarteType::BinaryBlob(_) => "binaryblob",
+        AstarteType::DateTime(_) => "datetime",
+        AstarteType::DoubleArray(_) => "doublearray",
+        AstarteType::IntegerArray(_) => "integerarray",
+        AstarteType::BooleanArray(_) => "booleanarray",
+        AstarteType::LongIntegerArray(_) => "longintegerarray",
+        AstarteType::StringArray(_) => "stringarray",
+        AstarteType::BinaryBlobArray(_) => "binaryblobarray",
+        AstarteType::DateTimeArray(_) => "datetimearray",
+        AstarteType::Unset => "unset",
+    }
+}
+
+/// Whether the serialized object field `value` fits the mapping type.
+fn json_matches(mapping_type: &str, value: &Value) -> bool {
+    if let Some(item_type) = mapping_type.strip_suffix("array") {
+        return match value {
+            Value::Array(items) => items.iter().all(|item| json_matches(item_type, item)),
+            _ => false,
+        };
+    }
+
+    match mapping_type {
+        "double" => value.is_f64(),
+        "integer" => value
+            .as_i64()
+            .map_or(false, |value| i32::try_from(value).is_ok()),
+        "longinteger" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "string" | "datetime" => value.is_string(),
+        "binaryblob" => value.as_array().map_or(false, |bytes| {
+            bytes
+                .iter()
+                .all(|byte| byte.as_u64().map_or(false, |byte| byte <= 255))
+        }),
+        _ => false,
+    }
+}
+
+fn json_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "double",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Index of the interfaces mappings, built once at startup.
+#[derive(Debug, Default)]
+pub struct InterfaceIndex {
+    interfaces: HashMap<String, Interface>,
+}
+
+impl InterfaceIndex {
+    pub fn load(directory: &Path) -> Result<Self, DeviceManagerError> {
+        let mut interfaces = HashMap::new();
+
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+
+            let interface: Interface = serde_json::from_str(&std::fs::read_to_string(&path)?)
+                .map_err(|err| DeviceManagerError::MalformedData {
+                    path: path.display().to_string(),
+                    reason: err.to_string(),
+                })?;
+            interfaces.insert(interface.interface_name.clone(), interface);
+        }
+
+        Ok(InterfaceIndex { interfaces })
+    }
+
+    /// Names of the loaded interfaces.
+    pub fn interface_names(&self) -> HashSet<String> {
+        self.interfaces.keys().cloned().collect()
+    }
+
+    /// `(major, minor)` version of every loaded interface.
+    pub fn versions(&self) -> BTreeMap<String, (i32, i32)> {
+        self.interfaces
+            .values()
+            .map(|interface| {
+                (
+                    interface.interface_name.clone(),
+                    (interface.version_major, interface.version_minor),
+                )
+            })
+            .collect()
+    }
+
+    /// Whether `interface_name` is a loaded properties interface.
+    pub fn is_property(&self, interface_name: &str) -> bool {
+        self.interfaces
+            .get(interface_name)
+            .map_or(false, |interface| {
+                interface.interface_type == InterfaceType::Properties
+            })
+    }
+
+    /// Type of the mapping of `path` in `interface_name`, like `longinteger`, whoever owns it.
+    pub fn mapping_type(&self, interface_name: &str, path: &str) -> Option<&str> {
+        self.interfaces
+            .get(interface_name)?
+            .mapping(path)
+            .map(|mapping| mapping.mapping_type.as_str())
+    }
+
+    /// Whether the values sent on `path` of `interface_name` carry the time they were collected.
+    pub fn has_explicit_timestamp(&self, interface_name: &str, path: &str) -> bool {
+        self.interfaces
+            .get(interface_name)
+            .map_or(false, |interface| {
+                interface
+                    .mappings
+                    .iter()
+                    .any(|mapping| mapping.explicit_timestamp && interface.covers(mapping, path))
+            })
+    }
+
+    /// Mismatches between the loaded interfaces and the ones the runtime uses.
+    pub fn check_expected(&self, expected: &[InterfaceSpec]) -> Vec<ValidationError> {
+        let mut mismatches = Vec::new();
+
+        for spec in expected {
+            let interface = match self.interfaces.get(spec.name) {
+                Some(interface) => interface,
+                None => {
+                    mismatches.push(ValidationError::UnknownInterface(spec.name.to_owned()));
+                    continue;
+                }
+            };
+
+            let version = (interface.version_major, interface.version_minor);
+            if version.0 != spec.minimum_version.0 {
+                mismatches.push(ValidationError::WrongMajor {
+                    interface: spec.name.to_owned(),
+                    version,
+                    major: spec.minimum_version.0,
+                });
+            } else if version < spec.minimum_version {
+                mismatches.push(ValidationError::Outdated {
+                    interface: spec.name.to_owned(),
+                    version,
+                    minimum: spec.minimum_version,
+                });
+            }
+            if interface.ownership != spec.ownership {
+                mismatches.push(ValidationError::WrongOwnership(spec.name.to_owned()));
+            }
+            if interface.aggregation != spec.aggregation {
+                mismatches.push(ValidationError::WrongAggregation {
+                    interface: spec.name.to_owned(),
+                });
+                continue;
+            }
+
+            mismatches.extend(
+                spec.paths
+                    .iter()
+                    .filter(|path| !interface.has_path(path))
+                    .map(|path| ValidationError::UnknownPath {
+                        interface: spec.name.to_owned(),
+                        path: (*path).to_owned(),
+                    }),
+            );
+        }
+
+        mismatches
+    }
+
+    /// Why the required interfaces of `expected` can't be used: missing, or loaded in another major
+    /// version than the runtime speaks.
+    pub fn check_required(&self, expected: &[InterfaceSpec]) -> Vec<String> {
+        expected
+            .iter()
+            .filter(|spec| spec.required)
+            .filter_map(|spec| {
+                let major = spec.minimum_version.0;
+                match self.interfaces.get(spec.name) {
+                    None => Some(format!("{} v{major} is missing", spec.name)),
+                    Some(interface) if interface.version_major != major => Some(format!(
+                        "{} is v{}, v{major} expected",
+                        spec.name, interface.version_major
+                    )),
+                    Some(_) => None,
+                }
+            })
+            .collect()
+    }
+
+    fn interface(&self, interface_name: &str) -> Result<&Interface, ValidationError> {
+        let interface = self
+            .interfaces
+            .get(interface_name)
+            .ok_or_else(|| ValidationError::UnknownInterface(interface_name.to_owned()))?;
+
+        if interface.ownership != Ownership::Device {
+            return Err(ValidationError::NotDeviceOwned(interface_name.to_owned()));
+        }
+
+        Ok(interface)
+    }
+
+    pub fn validate_individual(
+        &self,
+        interface_name: &str,
+        path: &str,
+        data: &AstarteType,
+    ) -> Result<(), ValidationError> {
+        let interface = self.interface(interface_name)?;
+        if interface.aggregation != Aggregation::Individual {
+            return Err(ValidationError::WrongAggregation {
+                interface: interface_name.to_owned(),
+            });
+        }
+
+        let mapping = interface
+            .mapping(path)
+            .ok_or_else(|| ValidationError::UnknownPath {
+                interface: interface_name.to_owned(),
+                path: path.to_owned(),
+            })?;
+
+        let found = astarte_type_name(data);
+        if found != mapping.mapping_type {
+            return Err(ValidationError::WrongType {
+                interface: interface_name.to_owned(),
+                path: path.to_owned(),
+                expected: mapping.mapping_type.clone(),
+                found: found.to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn validate_object(
+        &self,
+        interface_name: &str,
+        path: &str,
+        data: &impl serde::Serialize,
+    ) -> Result<(), ValidationError> {
+        let interface = self.interface(interface_name)?;
+        if interface.aggregation != Aggregation::Object {
+            return Err(ValidationError::WrongAggregation {
+                interface: interface_name.to_owned(),
+            });
+        }
+
+        let fields = match serde_json::to_value(data) {
+            Ok(Value::Object(fields)) => fields,
+            found => {
+                return Err(ValidationError::WrongType {
+                    interface: interface_name.to_owned(),
+                    path: path.to_owned(),
+                    expected: "object".to_owned(),
+                    found: found
+                        .map_or("unserializable", |value| json_type_name(&value))
+                        .to_owned(),
+                })
+            }
+        };
+
+        for (field, value) in fields {
+            let field_path = format!("{}/{}", path.trim_end_matches('/'), field);
+            let mapping =
+                interface
+                    .mapping(&field_path)
+                    .ok_or_else(|| ValidationError::UnknownPath {
+                        interface: interface_name.to_owned(),
+                        path: field_path.clone(),
+                    })?;
+
+            if !json_matches(&mapping.mapping_type, &value) {
+                return Err(ValidationError::WrongType {
+                    interface: interface_name.to_owned(),
+                    path: field_path,
+                    expected: mapping.mapping_type.clone(),
+                    found: json_type_name(&value).to_owned(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the payloads before they are sent, rejecting the mismatched ones in strict mode and
+/// only logging them otherwise.
+#[derive(Debug)]
+pub struct PayloadValidator {
+    index: InterfaceIndex,
+    strict: bool,
+}
+
+impl PayloadValidator {
+    pub fn new(index: InterfaceIndex, strict: bool) -> Self {
+        PayloadValidator { index, strict }
+    }
+
+    pub fn is_property(&self, interface_name: &str) -> bool {
+        self.index.is_property(interface_name)
+    }
+
+    pub fn has_explicit_timestamp(&self, interface_name: &str, path: &str) -> bool {
+        self.index.has_explicit_timestamp(interface_name, path)
+    }
+
+    pub fn mapping_type(&self, interface_name: &str, path: &str) -> Option<&str> {
+        self.index.mapping_type(interface_name, path)
+    }
+
+    fn check(&self, result: Result<(), ValidationError>) -> Result<(), AstarteError> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) if self.strict => Err(AstarteError::SendError(format!(
+                "payload validation failed: {err}"
+            ))),
+            Err(err) => {
+                warn!("Payload validation failed: {err}");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn check_individual(
+        &self,
+        interface_name: &str,
+        path: &str,
+        data: &AstarteType,
+    ) -> Result<(), AstarteError> {
+        self.check(self.index.validate_individual(interface_name, path, data))
+    }
+
+    pub fn check_object(
+        &self,
+        interface_name: &str,
+        path: &str,
+        data: &impl serde::Serialize,
+    ) -> Result<(), AstarteError> {
+        self.check(self.index.validate_object(interface_name, path, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use astarte_sdk::types::AstarteType;
+    use serde::Serialize;
+
+    use crate::data::validation::{InterfaceIndex, PayloadValidator, ValidationError};
+
+    const SYSTEM_STATUS: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.SystemStatus",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "datastream",
+        "ownership": "device",
+        "aggregation": "object",
+        "mappings": [
+            {
+                "endpoint": "/systemStatus/availMemoryBytes",
+                "type": "longinteger",
+                "explicit_timestamp": true
+            },
+            { "endpoint": "/systemStatus/bootId", "type": "string", "explicit_timestamp": true },
+            {
+                "endpoint": "/systemStatus/taskCount",
+                "type": "integer",
+                "explicit_timestamp": true
+            },
+            {
+                "endpoint": "/systemStatus/uptimeMillis",
+                "type": "longinteger",
+                "explicit_timestamp": true
+            }
+        ]
+    }"#;
+
+    const SENSORS: &str = r#"{
+        "interface_name": "com.example.Sensors",
+        "version_major": 1,
+        "version_minor": 0,
+        "type": "datastream",
+        "ownership": "device",
+        "mappings": [
+            { "endpoint": "/%{sensor_id}/value", "type": "double" },
+            { "endpoint": "/%{sensor_id}/tags", "type": "stringarray" }
+        ]
+    }"#;
+
+    const COMMANDS: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.Commands",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "datastream",
+        "ownership": "server",
+        "mappings": [
+            { "endpoint": "/request", "type": "string" }
+        ]
+    }"#;
+
+    const TAGS: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.Tags",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "properties",
+        "ownership": "device",
+        "mappings": [
+            { "endpoint": "/tags/%{tag}", "type": "boolean", "allow_unset": true }
+        ]
+    }"#;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct SystemStatus {
+        avail_memory_bytes: i64,
+        boot_id: String,
+        task_count: i32,
+        uptime_millis: i64,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct WrongSystemStatus {
+        avail_memory_bytes: f64,
+        boot_id: String,
+    }
+
+    fn index() -> InterfaceIndex {
+        let directory = tempfile::tempdir().unwrap();
+        for (name, interface) in [
+            ("system_status.json", SYSTEM_STATUS),
+            ("sensors.json", SENSORS),
+            ("commands.json", COMMANDS),
+            ("tags.json", TAGS),
+        ] {
+            std::fs::write(directory.path().join(name), interface).unwrap();
+        }
+
+        InterfaceIndex::load(directory.path()).unwrap()
+    }
+
+    #[test]
+    fn individual_payloads() {
+        let index = index();
+
+        assert!(index
+            .validate_individual(
+                "com.example.Sensors",
+                "/temperature/value",
+                &AstarteType::Double(21.5)
+            )
+            .is_ok());
+        assert!(index
+            .validate_individual(
+                "com.example.Sensors",
+                "/temperature/tags",
+                &AstarteType::StringArray(vec!["indoor".to_owned()])
+            )
+            .is_ok());
+
+        assert_eq!(
+            index.validate_individual(
+                "com.example.Sensors",
+                "/temperature/value",
+                &AstarteType::Integer(21)
+            ),
+            Err(ValidationError::WrongType {
+                interface: "com.example.Sensors".to_owned(),
+                path: "/temperature/value".to_owned(),
+                expected: "double".to_owned(),
+                found: "integer".to_owned(),
+            })
+        );
+        assert_eq!(
+            index.validate_individual(
+                "com.example.Sensors",
+                "/temperature",
+                &AstarteType::Double(21.5)
+            ),
+            Err(ValidationError::UnknownPath {
+                interface: "com.example.Sensors".to_owned(),
+                path: "/temperature".to_owned(),
+            })
+        );
+        assert_eq!(
+            index.validate_individual(
+                "io.edgehog.devicemanager.Commands",
+                "/request",
+                &AstarteType::String("Reboot".to_owned())
+            ),
+            Err(ValidationError::NotDeviceOwned(
+                "io.edgehog.devicemanager.Commands".to_owned()
+            ))
+        );
+        assert_eq!(
+            index.validate_individual("com.example.Missing", "/value", &AstarteType::Boolean(true)),
+            Err(ValidationError::UnknownInterface(
+                "com.example.Missing".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn object_payloads() {
+        let index = index();
+
+        let status = SystemStatus {
+            avail_memory_bytes: 1024,
+            boot_id: "b0071d".to_owned(),
+            task_count: 12,
+            uptime_millis: 5000,
+        };
+        assert!(index
+            .validate_object(
+                "io.edgehog.devicemanager.SystemStatus",
+                "/systemStatus",
+                &status
+            )
+            .is_ok());
+
+        let wrong = WrongSystemStatus {
+            avail_memory_bytes: 1024.0,
+            boot_id: "b0071d".to_owned(),
+        };
+        assert_eq!(
+            index.validate_object(
+                "io.edgehog.devicemanager.SystemStatus",
+                "/systemStatus",
+                &wrong
+            ),
+            Err(ValidationError::WrongType {
+                interface: "io.edgehog.devicemanager.SystemStatus".to_owned(),
+                path: "/systemStatus/availMemoryBytes".to_owned(),
+                expected: "longinteger".to_owned(),
+                found: "double".to_owned(),
+            })
+        );
+
+        assert_eq!(
+            index.validate_object("com.example.Sensors", "/temperature", &status),
+            Err(ValidationError::WrongAggregation {
+                interface: "com.example.Sensors".to_owned()
+            })
+        );
+        assert_eq!(
+            index.validate_object("io.edgehog.devicemanager.SystemStatus", "/status", &status),
+            Err(ValidationError::UnknownPath {
+                interface: "io.edgehog.devicemanager.SystemStatus".to_owned(),
+                path: "/status/availMemoryBytes".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn strict_mode_rejects_mismatches() {
+        let strict = PayloadValidator::new(index(), true);
+        let lenient = PayloadValidator::new(index(), false);
+        let wrong = AstarteType::Integer(21);
+
+        assert!(strict
+            .check_individual("com.example.Sensors", "/temperature/value", &wrong)
+            .is_err());
+        assert!(lenient
+            .check_individual("com.example.Sensors", "/temperature/value", &wrong)
+            .is_ok());
+    }
+
+    #[test]
+    fn properties_interfaces() {
+        let index = index();
+
+        assert!(index.is_property("io.edgehog.devicemanager.Tags"));
+        assert!(!index.is_property("com.example.Sensors"));
+        assert!(!index.is_property("com.example.Unknown"));
+    }
+
+    #[test]
+    fn explicit_timestamp_mappings() {
+        let index = index();
+
+        assert!(
+            index.has_explicit_timestamp("io.edgehog.devicemanager.SystemStatus", "/systemStatus")
+        );
+        assert!(!index.has_explicit_timestamp("io.edgehog.devicemanager.SystemStatus", "/status"));
+        assert!(!index.has_explicit_timestamp("com.example.Sensors", "/temperature/value"));
+        assert!(!index.has_explicit_timestamp("com.example.Unknown", "/value"));
+    }
+
+    #[test]
+    fn mapping_types() {
+        let index = index();
+
+        assert_eq!(
+            index.mapping_type(
+                "io.edgehog.devicemanager.SystemStatus",
+                "/systemStatus/taskCount"
+            ),
+            Some("integer")
+        );
+        assert_eq!(
+            index.mapping_type("com.example.Sensors", "/temperature/tags"),
+            Some("stringarray")
+        );
+        assert_eq!(
+            index.mapping_type("io.edgehog.devicemanager.Commands", "/request"),
+            Some("string")
+        );
+        assert_eq!(
+            index.mapping_type("com.example.Sensors", "/temperature"),
+            None
+        );
+        assert_eq!(index.mapping_type("com.example.Unknown", "/value"), None);
+    }
+}
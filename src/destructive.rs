@@ -0,0 +1,602 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Two-phase execution of the destructive commands.
+//!
+//! A destructive command only arms its action, publishing a confirmation token and a deadline on
+//! the diagnostics interface. The action runs when `Confirm <token>` is received before the
+//! deadline, otherwise it expires. The armed action is persisted, so that a restart does not
+//! forget it.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+#[cfg(test)]
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::event_log::EventLog;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::power_management;
+use crate::repository::StateRepository;
+
+/// Time left to confirm an armed action, unless configured otherwise.
+pub const DEFAULT_CONFIRM_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Command running the armed action, followed by its token.
+pub const CONFIRM_COMMAND: &str = "Confirm";
+/// Name of the file keeping the armed action in the store directory.
+pub const ARMED_ACTION_FILE: &str = "armed_action.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DestructiveAction {
+    /// Remove the persisted state of the runtime, except the credentials, and reboot.
+    ClearState,
+}
+
+impl DestructiveAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DestructiveAction::ClearState => "ClearState",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DestructiveCommand {
+    Arm(DestructiveAction),
+    Confirm(String),
+}
+
+impl DestructiveCommand {
+    /// Parse `ClearState` or `Confirm <token>`.
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["ClearState"] => Some(DestructiveCommand::Arm(DestructiveAction::ClearState)),
+            [CONFIRM_COMMAND, token] => Some(DestructiveCommand::Confirm(token.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Published on `/destructive/armed` and persisted until confirmed or expired.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArmedAction {
+    pub action: DestructiveAction,
+    pub token: String,
+    pub deadline: DateTime<Utc>,
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait ActionExecutor: Send + Sync {
+    async fn execute(&self, action: DestructiveAction) -> Result<(), DeviceManagerError>;
+}
+
+/// Runs the actions on the store directory of the runtime.
+pub struct StoreExecutor {
+    store_directory: PathBuf,
+}
+
+impl StoreExecutor {
+    pub fn new(store_directory: PathBuf) -> Self {
+        StoreExecutor { store_directory }
+    }
+}
+
+#[async_trait]
+impl ActionExecutor for StoreExecutor {
+    async fn execute(&self, action: DestructiveAction) -> Result<(), DeviceManagerError> {
+        match action {
+            DestructiveAction::ClearState => {
+                let removed = clear_state(&self.store_directory)?;
+                info!("State cleared, {removed} files removed, rebooting");
+
+                // the components still hold the cleared state in memory
+                power_management::reboot().await
+            }
+        }
+    }
+}
+
+/// Remove the state files of `store_directory`, keeping the credentials.
+fn clear_state(store_directory: &Path) -> Result<usize, DeviceManagerError> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(store_directory)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        if !name.ends_with(".json") || name.starts_with("credentials_") {
+            continue;
+        }
+
+        std::fs::remove_file(&path)?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+pub struct DestructiveActions {
+    clock: Arc<dyn Clock>,
+    window: Duration,
+    /// Run the actions as soon as they are requested, without confirmation.
+    single_shot: bool,
+    executor: Box<dyn ActionExecutor>,
+    repository: Box<dyn StateRepository<ArmedAction>>,
+    armed: Mutex<Option<ArmedAction>>,
+    event_log: Option<Arc<EventLog>>,
+}
+
+impl DestructiveActions {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        window: Duration,
+        single_shot: bool,
+        executor: Box<dyn ActionExecutor>,
+        repository: Box<dyn StateRepository<ArmedAction>>,
+    ) -> Self {
+        let armed = repository
+            .exists()
+            .then(|| repository.read())
+            .and_then(|read| {
+                read.map_err(|err| warn!("Unable to read the armed action: {:?}", err))
+                    .ok()
+            });
+
+        DestructiveActions {
+            clock,
+            window,
+            single_shot,
+            executor,
+            repository,
+            armed: Mutex::new(armed),
+            event_log: None,
+        }
+    }
+
+    /// Number the outcomes in `event_log`, when enabled.
+    pub fn with_event_log(mut self, event_log: Option<Arc<EventLog>>) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.clock.now_wall().into()
+    }
+
+    /// Time left to confirm the armed action, if any.
+    fn remaining(&self) -> Option<Duration> {
+        self.armed.lock().unwrap().as_ref().map(|armed| {
+            (armed.deadline - self.now())
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+        })
+    }
+
+    fn disarm(&self) -> Option<ArmedAction> {
+        if let Err(err) = self.repository.clear() {
+            warn!("Unable to clear the armed action: {:?}", err);
+        }
+
+        self.armed.lock().unwrap().take()
+    }
+
+    pub async fn handle(&self, publisher: &impl Publisher, command: DestructiveCommand) {
+        match command {
+            DestructiveCommand::Arm(action) if self.single_shot => {
+                self.execute(publisher, action).await;
+            }
+            DestructiveCommand::Arm(action) => self.arm(publisher, action).await,
+            DestructiveCommand::Confirm(token) => self.confirm(publisher, &token).await,
+        }
+    }
+
+    async fn arm(&self, publisher: &impl Publisher, action: DestructiveAction) {
+        let armed = ArmedAction {
+            action,
+            token: Uuid::new_v4().to_string(),
+            deadline: self.now()
+                + chrono::Duration::from_std(self.window)
+                    .unwrap_or_else(|_| chrono::Duration::zero()),
+        };
+        if let Some(previous) = self.armed.lock().unwrap().replace(armed.clone()) {
+            info!("{} replaced by {}", previous.action.name(), action.name());
+        }
+        if let Err(err) = self.repository.write(&armed) {
+            warn!("Unable to persist the armed action: {:?}", err);
+        }
+
+        info!("{} armed until {}", action.name(), armed.deadline);
+        match serde_json::to_string(&armed) {
+            Ok(armed) => {
+                self.send(publisher, "/destructive/armed", AstarteType::String(armed))
+                    .await
+            }
+            Err(err) => warn!("Unable to serialize the armed action: {:?}", err),
+        }
+    }
+
+    async fn confirm(&self, publisher: &impl Publisher, token: &str) {
+        if self.expire_if_due(publisher).await {
+            return;
+        }
+
+        let action = match self.armed.lock().unwrap().as_ref() {
+            Some(armed) if armed.token == token => Ok(armed.action),
+            Some(_) => Err("wrong confirmation token"),
+            None => Err("nothing armed"),
+        };
+        match action {
+            Ok(action) => {
+                self.disarm();
+                self.execute(publisher, action).await;
+            }
+            Err(reason) => {
+                warn!("Confirmation rejected: {reason}");
+                self.send(
+                    publisher,
+                    "/destructive/rejected",
+                    AstarteType::String(reason.to_owned()),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn execute(&self, publisher: &impl Publisher, action: DestructiveAction) {
+        // published first, a successful action reboots the device
+        self.send(
+            publisher,
+            "/destructive/executed",
+            AstarteType::String(action.name().to_owned()),
+        )
+        .await;
+
+        if let Err(err) = self.executor.execute(action).await {
+            warn!("{} failed: {:?}", action.name(), err);
+            self.send(
+                publisher,
+                "/destructive/failed",
+                AstarteType::String(action.name().to_owned()),
+            )
+            .await;
+        }
+    }
+
+    /// Expire the armed action when its deadline has passed.
+    pub async fn expire_if_due(&self, publisher: &impl Publisher) -> bool {
+        if self.remaining() != Some(Duration::ZERO) {
+            return false;
+        }
+
+        if let Some(expired) = self.disarm() {
+            info!("{} expired", expired.action.name());
+            self.send(
+                publisher,
+                "/destructive/expired",
+                AstarteType::String(expired.action.name().to_owned()),
+            )
+            .await;
+        }
+
+        true
+    }
+
+    pub async fn run(
+        &self,
+        publisher: &impl Publisher,
+        mut commands: mpsc::Receiver<DestructiveCommand>,
+    ) {
+        loop {
+            let remaining = self.remaining();
+            let expiry = async {
+                match remaining {
+                    Some(remaining) => self.clock.sleep(remaining).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => self.handle(publisher, command).await,
+                    None => break,
+                },
+                _ = expiry => {
+                    self.expire_if_due(publisher).await;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, publisher: &impl Publisher, path: &str, data: AstarteType) {
+        if let Some(event_log) = &self.event_log {
+            return event_log.acknowledge(publisher, path, data).await;
+        }
+
+        if let Err(err) = publisher.send(DIAGNOSTICS_INTERFACE, path, data).await {
+            warn!("Unable to publish {path}: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+
+    use crate::destructive::{
+        clear_state, ArmedAction, DestructiveAction, DestructiveActions, DestructiveCommand,
+        MockActionExecutor,
+    };
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::test_utils::{
+        recording_publisher, settle, ManualClock, MemoryStateRepository, Recording,
+    };
+
+    type Sent = Recording<()>;
+
+    /// The string values sent on the diagnostics interface, with their path.
+    fn strings(sent: &Sent) -> Vec<(String, String)> {
+        sent.values(DIAGNOSTICS_INTERFACE)
+            .into_iter()
+            .map(|(path, data)| match data {
+                AstarteType::String(data) => (path, data),
+                _ => panic!("not a string"),
+            })
+            .collect()
+    }
+
+    fn executor(times: usize) -> MockActionExecutor {
+        let mut executor = MockActionExecutor::new();
+        executor
+            .expect_execute()
+            .withf(|action| *action == DestructiveAction::ClearState)
+            .times(times)
+            .returning(|_| Ok(()));
+
+        executor
+    }
+
+    fn actions(
+        clock: Arc<ManualClock>,
+        executor: MockActionExecutor,
+        repository: Arc<MemoryStateRepository<ArmedAction>>,
+    ) -> DestructiveActions {
+        DestructiveActions::new(
+            clock,
+            Duration::from_secs(60),
+            false,
+            Box::new(executor),
+            Box::new(repository),
+        )
+    }
+
+    fn armed_token(sent: &Sent) -> String {
+        let sent = strings(sent);
+        let (path, armed) = &sent[0];
+        assert_eq!(path, "/destructive/armed");
+
+        serde_json::from_str::<ArmedAction>(armed).unwrap().token
+    }
+
+    fn paths(sent: &Sent) -> Vec<String> {
+        strings(sent).into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn parse_command() {
+        assert_eq!(
+            DestructiveCommand::from_command("ClearState"),
+            Some(DestructiveCommand::Arm(DestructiveAction::ClearState))
+        );
+        assert_eq!(
+            DestructiveCommand::from_command("Confirm abc"),
+            Some(DestructiveCommand::Confirm("abc".to_owned()))
+        );
+        assert_eq!(DestructiveCommand::from_command("Confirm"), None);
+        assert_eq!(DestructiveCommand::from_command("Reboot"), None);
+    }
+
+    #[tokio::test]
+    async fn armed_action_confirmed() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let actions = actions(clock.clone(), executor(1), repository.clone());
+        let (publisher, sent) = recording_publisher();
+
+        actions
+            .handle(
+                &publisher,
+                DestructiveCommand::Arm(DestructiveAction::ClearState),
+            )
+            .await;
+        let token = armed_token(&sent);
+        assert_eq!(repository.value().unwrap().token, token);
+
+        clock.advance(Duration::from_secs(30));
+        actions
+            .handle(&publisher, DestructiveCommand::Confirm(token))
+            .await;
+
+        assert_eq!(
+            paths(&sent),
+            vec!["/destructive/armed", "/destructive/executed"]
+        );
+        assert!(repository.value().is_none());
+    }
+
+    #[tokio::test]
+    async fn armed_action_expires() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let actions = actions(clock.clone(), executor(0), repository.clone());
+        let (publisher, sent) = recording_publisher();
+        let (commands_tx, commands) = mpsc::channel(4);
+        let handle = tokio::spawn(async move { actions.run(&publisher, commands).await });
+
+        commands_tx
+            .send(DestructiveCommand::Arm(DestructiveAction::ClearState))
+            .await
+            .unwrap();
+        settle().await;
+        let token = armed_token(&sent);
+
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(
+            paths(&sent),
+            vec!["/destructive/armed", "/destructive/expired"]
+        );
+        assert!(repository.value().is_none());
+
+        // too late
+        commands_tx
+            .send(DestructiveCommand::Confirm(token))
+            .await
+            .unwrap();
+        settle().await;
+        assert_eq!(
+            strings(&sent)[2],
+            (
+                "/destructive/rejected".to_owned(),
+                "nothing armed".to_owned()
+            )
+        );
+
+        drop(commands_tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn armed_action_confirmed_after_restart() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let (publisher, sent) = recording_publisher();
+
+        actions(clock.clone(), executor(0), repository.clone())
+            .handle(
+                &publisher,
+                DestructiveCommand::Arm(DestructiveAction::ClearState),
+            )
+            .await;
+        let token = armed_token(&sent);
+
+        clock.advance(Duration::from_secs(20));
+        let restarted = actions(clock.clone(), executor(1), repository.clone());
+        restarted
+            .handle(&publisher, DestructiveCommand::Confirm(token))
+            .await;
+
+        assert_eq!(
+            paths(&sent),
+            vec!["/destructive/armed", "/destructive/executed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_token_rejected_and_action_kept() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let actions = actions(clock.clone(), executor(1), repository.clone());
+        let (publisher, sent) = recording_publisher();
+
+        actions
+            .handle(
+                &publisher,
+                DestructiveCommand::Arm(DestructiveAction::ClearState),
+            )
+            .await;
+        let token = armed_token(&sent);
+
+        actions
+            .handle(&publisher, DestructiveCommand::Confirm("guess".to_owned()))
+            .await;
+        assert!(repository.value().is_some());
+
+        actions
+            .handle(&publisher, DestructiveCommand::Confirm(token))
+            .await;
+        assert_eq!(
+            paths(&sent),
+            vec![
+                "/destructive/armed",
+                "/destructive/rejected",
+                "/destructive/executed"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn single_shot_runs_without_confirmation() {
+        let repository = Arc::new(MemoryStateRepository::new());
+        let actions = DestructiveActions::new(
+            Arc::new(ManualClock::new()),
+            Duration::from_secs(60),
+            true,
+            Box::new(executor(1)),
+            Box::new(repository.clone()),
+        );
+        let (publisher, sent) = recording_publisher();
+
+        actions
+            .handle(
+                &publisher,
+                DestructiveCommand::Arm(DestructiveAction::ClearState),
+            )
+            .await;
+
+        assert_eq!(paths(&sent), vec!["/destructive/executed"]);
+        assert!(repository.value().is_none());
+    }
+
+    #[test]
+    fn credentials_kept_by_state_clear() {
+        let store = tempfile::tempdir().unwrap();
+        for name in [
+            "state.json",
+            "send_stats.json",
+            "credentials_device.json",
+            "edgehog.lock",
+        ] {
+            std::fs::write(store.path().join(name), "{}").unwrap();
+        }
+
+        assert_eq!(clear_state(store.path()).unwrap(), 2);
+        assert!(!store.path().join("state.json").exists());
+        assert!(store.path().join("credentials_device.json").exists());
+        assert!(store.path().join("edgehog.lock").exists());
+    }
+}
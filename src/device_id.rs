@@ -0,0 +1,430 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Device id of the runtime: the configured one, else the hardware id of the `io.edgehog.Device`
+//! D-Bus service, else the systemd machine id.
+//!
+//! The ids that aren't valid Astarte device ids are turned into one, the UUIDv5 of the id in the
+//! configured namespace encoded in base64url, like `astartectl utils device-id
+//! compute-from-string` does. The valid ones are kept as they are, so the devices registered so
+//! far keep their id.
+
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::info;
+use uuid::Uuid;
+
+#[cfg(feature = "dbus")]
+use crate::device::DeviceProxy;
+use crate::error::DeviceManagerError;
+#[cfg(not(feature = "dbus"))]
+use crate::wrapper::platform::no_system_bus;
+use crate::wrapper::platform::platform;
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::system_bus;
+use crate::DeviceManagerOptions;
+
+pub const MACHINE_ID_PATH: &str = "/etc/machine-id";
+/// Longest wait for the hardware id, unless configured otherwise.
+pub const DEFAULT_HARDWARE_ID_TIMEOUT: Duration = Duration::from_secs(10);
+/// Namespace of the derived ids unless configured otherwise, the UUIDv5 of `edgehog.io` in the
+/// DNS namespace.
+pub const DEFAULT_DEVICE_ID_NAMESPACE: &str = "ad1cdd5d-c82d-5d70-9e10-083a6eab3250";
+
+/// Where the device id was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DeviceIdSource {
+    Configured,
+    HardwareId,
+    MachineId,
+}
+
+impl Display for DeviceIdSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceIdSource::Configured => write!(f, "the configuration"),
+            DeviceIdSource::HardwareId => write!(f, "the hardware id"),
+            DeviceIdSource::MachineId => write!(f, "the machine id"),
+        }
+    }
+}
+
+/// Namespace of the derived ids set by `device_id_namespace`.
+pub(crate) fn namespace(opts: &DeviceManagerOptions) -> Result<Uuid, DeviceManagerError> {
+    let namespace = opts
+        .device_id_namespace
+        .as_deref()
+        .unwrap_or(DEFAULT_DEVICE_ID_NAMESPACE);
+
+    Uuid::parse_str(namespace).map_err(|err| DeviceManagerError::InvalidOption {
+        option: "device_id_namespace".to_owned(),
+        reason: format!("{namespace} is not a UUID: {err}"),
+    })
+}
+
+/// Whether `id` is a valid Astarte device id: 128 bits encoded in base64url, without padding.
+pub(crate) fn is_astarte_device_id(id: &str) -> bool {
+    let bytes = id.as_bytes();
+    bytes.len() == 22
+        && bytes[..21]
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_'))
+        // the last character only holds the 2 remaining bits
+        && matches!(bytes[21], b'A' | b'Q' | b'g' | b'w')
+}
+
+/// Astarte device id derived from `name`, the same for the same `namespace` and `name`.
+pub(crate) fn derive(namespace: &Uuid, name: &str) -> String {
+    let uuid = Uuid::new_v5(namespace, name.as_bytes());
+
+    openssl::base64::encode_block(uuid.as_bytes())
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+/// `id` when it's a valid Astarte device id, the id derived from it otherwise.
+pub(crate) fn normalize(namespace: &Uuid, id: &str) -> String {
+    if is_astarte_device_id(id) {
+        id.to_owned()
+    } else {
+        derive(namespace, id)
+    }
+}
+
+/// Service giving the hardware id of the device.
+#[async_trait]
+pub(crate) trait HardwareIdService: Send + Sync {
+    async fn hardware_id(&self) -> Result<String, DeviceManagerError>;
+}
+
+/// The `io.edgehog.Device` service on the system bus, never reachable when built without the
+/// `dbus` feature.
+pub(crate) struct DBusHardwareId;
+
+#[async_trait]
+impl HardwareIdService for DBusHardwareId {
+    #[cfg(not(feature = "dbus"))]
+    async fn hardware_id(&self) -> Result<String, DeviceManagerError> {
+        Err(no_system_bus())
+    }
+
+    #[cfg(feature = "dbus")]
+    async fn hardware_id(&self) -> Result<String, DeviceManagerError> {
+        let connection = system_bus()
+            .await
+            .map_err(DeviceManagerError::HardwareIdUnavailable)?;
+        let proxy = DeviceProxy::new(&connection)
+            .await
+            .map_err(DeviceManagerError::HardwareIdUnavailable)?;
+
+        proxy
+            .get_hardware_id("")
+            .await
+            .map_err(DeviceManagerError::HardwareIdUnavailable)
+    }
+}
+
+/// Hardware id given by `service` within `timeout`, an installed service that hangs must not
+/// hold the startup forever.
+pub(crate) async fn lookup_hardware_id(
+    service: &dyn HardwareIdService,
+    timeout: Duration,
+) -> Result<String, DeviceManagerError> {
+    platform()
+        .notifier()
+        .status("Waiting for hardware id service");
+
+    let hardware_id = tokio::time::timeout(timeout, service.hardware_id())
+        .await
+        .map_err(|_| DeviceManagerError::HardwareIdTimedOut(timeout))??;
+    if hardware_id.is_empty() {
+        return Err(DeviceManagerError::MissingHardwareId);
+    }
+
+    Ok(hardware_id)
+}
+
+fn read_machine_id(path: &Path) -> Result<String, String> {
+    let machine_id = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    match machine_id.trim() {
+        "" => Err("empty".to_owned()),
+        // written at the first boot, before the id is committed
+        "uninitialized" => Err("uninitialized".to_owned()),
+        machine_id => Ok(machine_id.to_owned()),
+    }
+}
+
+/// Device id from the first source having one: `configured`, then `hardware_id`, then the
+/// machine id at `machine_id_path`. When none has one, the error tells why each failed.
+pub(crate) async fn resolve<F, Fut>(
+    configured: Option<&str>,
+    namespace: &Uuid,
+    hardware_id: F,
+    machine_id_path: &Path,
+) -> Result<String, DeviceManagerError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<String, DeviceManagerError>>,
+{
+    let chosen = |source: DeviceIdSource, id: &str| {
+        let device_id = normalize(namespace, id);
+        if device_id == id {
+            info!("Device id {device_id} from {source}");
+        } else {
+            info!("Device id {device_id} derived from {source}");
+        }
+        device_id
+    };
+
+    if let Some(id) = configured {
+        return Ok(chosen(DeviceIdSource::Configured, id));
+    }
+    let mut tried = vec![format!("{}: not set", DeviceIdSource::Configured)];
+
+    match hardware_id().await {
+        Ok(id) => return Ok(chosen(DeviceIdSource::HardwareId, &id)),
+        Err(err) => tried.push(format!("{}: {err}", DeviceIdSource::HardwareId)),
+    }
+
+    match read_machine_id(machine_id_path) {
+        Ok(id) => return Ok(chosen(DeviceIdSource::MachineId, &id)),
+        Err(reason) => tried.push(format!(
+            "{} {}: {reason}",
+            DeviceIdSource::MachineId,
+            machine_id_path.display()
+        )),
+    }
+
+    Err(DeviceManagerError::NoDeviceId(tried))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use uuid::Uuid;
+
+    use crate::device_id::{
+        derive, is_astarte_device_id, lookup_hardware_id, normalize, resolve, HardwareIdService,
+        DEFAULT_DEVICE_ID_NAMESPACE,
+    };
+    use crate::error::DeviceManagerError;
+
+    /// Service answering with its hardware id, never when `None`.
+    struct FakeHardwareId(Option<&'static str>);
+
+    #[async_trait]
+    impl HardwareIdService for FakeHardwareId {
+        async fn hardware_id(&self) -> Result<String, DeviceManagerError> {
+            match self.0 {
+                Some(hardware_id) => Ok(hardware_id.to_owned()),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    const MACHINE_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    fn default_namespace() -> Uuid {
+        Uuid::parse_str(DEFAULT_DEVICE_ID_NAMESPACE).unwrap()
+    }
+
+    fn machine_id_file(content: &str) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), content).unwrap();
+        file
+    }
+
+    #[test]
+    fn derivation_known_vectors() {
+        // RFC 4122 v5 of python.org in the DNS namespace, 886313e1-3b8a-5372-9b90-0c9aee199e5d
+        assert_eq!(
+            derive(&Uuid::NAMESPACE_DNS, "python.org"),
+            "iGMT4TuKU3KbkAya7hmeXQ"
+        );
+        assert_eq!(
+            derive(&default_namespace(), MACHINE_ID),
+            "H16bsypeUtGIUCBNbp5Jnw"
+        );
+        assert_eq!(
+            derive(&default_namespace(), "hw-id"),
+            "_rsyBd76WyWXXjLPDfY7aA"
+        );
+        // the namespace of the built-in default
+        assert_eq!(
+            Uuid::new_v5(&Uuid::NAMESPACE_DNS, b"edgehog.io"),
+            default_namespace()
+        );
+    }
+
+    #[test]
+    fn valid_ids_kept() {
+        for id in ["iGMT4TuKU3KbkAya7hmeXQ", "95rZH8Y4SImudJ0AGjtM-A"] {
+            assert!(is_astarte_device_id(id), "{id}");
+            assert_eq!(normalize(&default_namespace(), id), id);
+        }
+        for id in [
+            "device",
+            "iGMT4TuKU3KbkAya7hmeXR",
+            "iGMT4TuKU3KbkAya7hme+Q",
+            MACHINE_ID,
+        ] {
+            assert!(!is_astarte_device_id(id), "{id}");
+        }
+        assert_eq!(
+            normalize(&default_namespace(), "hw-id"),
+            "_rsyBd76WyWXXjLPDfY7aA"
+        );
+    }
+
+    #[tokio::test]
+    async fn hung_hardware_id_service_timed_out() {
+        let timeout = Duration::from_millis(20);
+
+        let err = lookup_hardware_id(&FakeHardwareId(None), timeout)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, DeviceManagerError::HardwareIdTimedOut(t) if t == timeout));
+        assert_eq!(
+            err.to_string(),
+            "no hardware id from the io.edgehog.Device D-Bus service within 20ms"
+        );
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn empty_hardware_id_rejected() {
+        let timeout = Duration::from_secs(1);
+
+        let err = lookup_hardware_id(&FakeHardwareId(Some("")), timeout)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DeviceManagerError::MissingHardwareId));
+
+        let hardware_id = lookup_hardware_id(&FakeHardwareId(Some("hw-id")), timeout)
+            .await
+            .unwrap();
+        assert_eq!(hardware_id, "hw-id");
+    }
+
+    #[tokio::test]
+    async fn configured_id_first() {
+        let asked = AtomicBool::new(false);
+
+        let device_id = resolve(
+            Some("hw-id"),
+            &default_namespace(),
+            || async {
+                asked.store(true, Ordering::SeqCst);
+                Ok("iGMT4TuKU3KbkAya7hmeXQ".to_owned())
+            },
+            Path::new("/nonexistent"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(device_id, "_rsyBd76WyWXXjLPDfY7aA");
+        assert!(!asked.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn hardware_id_before_machine_id() {
+        let machine_id = machine_id_file(MACHINE_ID);
+
+        let device_id = resolve(
+            None,
+            &default_namespace(),
+            || async { Ok("iGMT4TuKU3KbkAya7hmeXQ".to_owned()) },
+            machine_id.path(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(device_id, "iGMT4TuKU3KbkAya7hmeXQ");
+    }
+
+    #[tokio::test]
+    async fn machine_id_without_hardware_id() {
+        let machine_id = machine_id_file(&format!("{MACHINE_ID}\n"));
+
+        let device_id = resolve(
+            None,
+            &default_namespace(),
+            || async { Err(DeviceManagerError::MissingHardwareId) },
+            machine_id.path(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(device_id, "H16bsypeUtGIUCBNbp5Jnw");
+        // another namespace, another id
+        let other = Uuid::parse_str("f79ad91f-c638-4889-ae74-9d001a3b4cf8").unwrap();
+        let device_id = resolve(
+            None,
+            &other,
+            || async { Err(DeviceManagerError::MissingHardwareId) },
+            machine_id.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(device_id, derive(&other, MACHINE_ID));
+        assert_ne!(device_id, "H16bsypeUtGIUCBNbp5Jnw");
+    }
+
+    #[tokio::test]
+    async fn every_source_failing_reported() {
+        let machine_id = machine_id_file("uninitialized\n");
+
+        let err = resolve(
+            None,
+            &default_namespace(),
+            || async { Err(DeviceManagerError::MissingHardwareId) },
+            machine_id.path(),
+        )
+        .await
+        .unwrap_err();
+
+        match &err {
+            DeviceManagerError::NoDeviceId(tried) => assert_eq!(
+                *tried,
+                [
+                    "the configuration: not set".to_owned(),
+                    "the hardware id: empty hardware id from the io.edgehog.Device D-Bus service"
+                        .to_owned(),
+                    format!(
+                        "the machine id {}: uninitialized",
+                        machine_id.path().display()
+                    ),
+                ]
+            ),
+            err => panic!("unexpected error {err:?}"),
+        }
+        assert!(err.is_retryable());
+    }
+}
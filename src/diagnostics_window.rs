@@ -0,0 +1,335 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Temporary verbose diagnostics, opened by the backend with the `DiagnosticsWindow` command.
+//!
+//! While the window is open the runtime modules log at the debug level and every few seconds
+//! the connection stats and the duration of the one-shot collectors are published on the
+//! diagnostics interface. The window closes by itself and is deliberately not persisted, a
+//! restart closes it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::clock::Clock;
+use crate::data::deadline::ConnectionHealth;
+use crate::data::Publisher;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::logging;
+use crate::telemetry;
+
+/// Longest window, unless configured otherwise.
+pub const DEFAULT_MAX_WINDOW: Duration = Duration::from_secs(30 * 60);
+/// How often the elevated diagnostics are sampled while the window is open.
+pub const SAMPLE_PERIOD: Duration = Duration::from_secs(5);
+/// Command opening a window, followed by its length in minutes.
+pub const WINDOW_COMMAND: &str = "DiagnosticsWindow";
+
+/// Request to keep the window open for `duration` from now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowRequest {
+    pub duration: Duration,
+}
+
+impl WindowRequest {
+    /// Parse `DiagnosticsWindow <minutes>`.
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [WINDOW_COMMAND, minutes] => {
+                let minutes: u64 = minutes.parse().ok().filter(|minutes| *minutes > 0)?;
+                Some(WindowRequest {
+                    duration: Duration::from_secs(minutes * 60),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Published on `/diagnosticsWindow/closed` once the window closes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowSummary {
+    pub open_secs: u64,
+    pub samples: u32,
+    /// Requests received while open, each extending the window.
+    pub extensions: u32,
+}
+
+/// One-shot collectors whose duration is reported while the window is open.
+fn collectors() -> [(&'static str, fn() -> bool); 4] {
+    [
+        ("systemStatus", || {
+            telemetry::system_status::get_system_status().is_ok()
+        }),
+        ("osInfo", || telemetry::os_info::get_os_info().is_ok()),
+        ("hardwareInfo", || {
+            telemetry::hardware_info::get_hardware_info().is_ok()
+        }),
+        ("runtimeInfo", || {
+            telemetry::runtime_info::get_runtime_info().is_ok()
+        }),
+    ]
+}
+
+pub struct DiagnosticsWindow {
+    clock: Arc<dyn Clock>,
+    max_duration: Duration,
+    health: Arc<ConnectionHealth>,
+}
+
+impl DiagnosticsWindow {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        max_duration: Duration,
+        health: Arc<ConnectionHealth>,
+    ) -> Self {
+        DiagnosticsWindow {
+            clock,
+            max_duration,
+            health,
+        }
+    }
+
+    /// Open a window for every request received while closed, extending the open one otherwise.
+    pub async fn run(
+        &self,
+        publisher: &impl Publisher,
+        mut requests: mpsc::Receiver<WindowRequest>,
+    ) {
+        while let Some(request) = requests.recv().await {
+            let summary = self.open(publisher, request, &mut requests).await;
+
+            logging::set_verbose(false);
+            info!("Diagnostics window closed after {} s", summary.open_secs);
+            self.send(
+                publisher,
+                "/diagnosticsWindow/closed",
+                AstarteType::String(serde_json::to_string(&summary).unwrap_or_default()),
+            )
+            .await;
+        }
+    }
+
+    async fn open(
+        &self,
+        publisher: &impl Publisher,
+        request: WindowRequest,
+        requests: &mut mpsc::Receiver<WindowRequest>,
+    ) -> WindowSummary {
+        let opened = self.clock.now_monotonic();
+        let mut end = opened + request.duration.min(self.max_duration);
+        let mut next_sample = opened + SAMPLE_PERIOD;
+        let mut summary = WindowSummary::default();
+
+        logging::set_verbose(true);
+        info!("Diagnostics window open for {:?}", end - opened);
+        self.send(
+            publisher,
+            "/diagnosticsWindow/open",
+            AstarteType::LongInteger((end - opened).as_secs() as i64),
+        )
+        .await;
+
+        loop {
+            tokio::select! {
+                _ = self.clock.sleep_until(next_sample.min(end)) => {
+                    if self.clock.now_monotonic() >= end {
+                        break;
+                    }
+
+                    self.sample(publisher).await;
+                    summary.samples += 1;
+                    next_sample += SAMPLE_PERIOD;
+                }
+                Some(request) = requests.recv() => {
+                    // overlapping requests extend the window, up to the longest one from now
+                    let now = self.clock.now_monotonic();
+                    end = end.max(now + request.duration.min(self.max_duration));
+                    summary.extensions += 1;
+                    info!("Diagnostics window extended, {:?} left", end - now);
+                }
+            }
+        }
+
+        summary.open_secs = (self.clock.now_monotonic() - opened).as_secs();
+        summary
+    }
+
+    /// Publish the connection stats and the duration of each collector, in microseconds.
+    async fn sample(&self, publisher: &impl Publisher) {
+        let stats = self.health.stats();
+        for (name, value) in [
+            ("sendTimeouts", stats.send_timeouts),
+            ("queuedOnTimeout", stats.queued_on_timeout),
+            ("reconnects", stats.reconnects),
+            ("replayFailures", stats.replay_failures),
+        ] {
+            self.send(
+                publisher,
+                &format!("/diagnosticsWindow/connectionStats/{name}"),
+                AstarteType::LongInteger(value as i64),
+            )
+            .await;
+        }
+
+        for (name, collect) in collectors() {
+            let start = self.clock.now_monotonic();
+            if !collect() {
+                warn!("Collector {name} failed");
+            }
+            let micros = (self.clock.now_monotonic() - start).as_micros();
+
+            self.send(
+                publisher,
+                &format!("/diagnosticsWindow/collectorMicros/{name}"),
+                AstarteType::LongInteger(micros as i64),
+            )
+            .await;
+        }
+    }
+
+    async fn send(&self, publisher: &impl Publisher, path: &str, data: AstarteType) {
+        if let Err(err) = publisher.send(DIAGNOSTICS_INTERFACE, path, data).await {
+            warn!("Unable to publish {path}: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+
+    use crate::data::deadline::ConnectionHealth;
+    use crate::data::MockPublisher;
+    use crate::diagnostics_window::{DiagnosticsWindow, WindowRequest, SAMPLE_PERIOD};
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::logging;
+    use crate::test_utils::{settle, ManualClock};
+
+    fn minutes(minutes: u64) -> WindowRequest {
+        WindowRequest {
+            duration: Duration::from_secs(minutes * 60),
+        }
+    }
+
+    #[test]
+    fn parse_command() {
+        assert_eq!(
+            WindowRequest::from_command("DiagnosticsWindow 15"),
+            Some(minutes(15))
+        );
+        assert_eq!(WindowRequest::from_command("DiagnosticsWindow"), None);
+        assert_eq!(WindowRequest::from_command("DiagnosticsWindow 0"), None);
+        assert_eq!(WindowRequest::from_command("DiagnosticsWindow soon"), None);
+    }
+
+    #[tokio::test]
+    async fn window_opens_extends_and_reverts() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, _, _| interface == DIAGNOSTICS_INTERFACE)
+            .returning(move |_, path, data| {
+                recorded.lock().unwrap().push((path.to_owned(), data));
+                Ok(())
+            });
+        // the longest window is 2 minutes
+        let window = DiagnosticsWindow::new(
+            clock.clone(),
+            Duration::from_secs(120),
+            Arc::new(ConnectionHealth::default()),
+        );
+        let (requests_tx, requests) = mpsc::channel(4);
+        let handle = tokio::spawn(async move { window.run(&publisher, requests).await });
+
+        requests_tx.send(minutes(1)).await.unwrap();
+        settle().await;
+        assert!(logging::is_verbose());
+
+        clock.advance(SAMPLE_PERIOD);
+        settle().await;
+        let sampled: Vec<String> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        assert_eq!(
+            sampled,
+            vec![
+                "/diagnosticsWindow/open",
+                "/diagnosticsWindow/connectionStats/sendTimeouts",
+                "/diagnosticsWindow/connectionStats/queuedOnTimeout",
+                "/diagnosticsWindow/connectionStats/reconnects",
+                "/diagnosticsWindow/connectionStats/replayFailures",
+                "/diagnosticsWindow/collectorMicros/systemStatus",
+                "/diagnosticsWindow/collectorMicros/osInfo",
+                "/diagnosticsWindow/collectorMicros/hardwareInfo",
+                "/diagnosticsWindow/collectorMicros/runtimeInfo",
+            ]
+        );
+        assert_eq!(sent.lock().unwrap()[0].1, AstarteType::LongInteger(60));
+
+        // at 25 s the window is extended to the 2 minutes cap, not stacked to 60 + 600 s
+        for _ in 0..4 {
+            clock.advance(SAMPLE_PERIOD);
+            settle().await;
+        }
+        requests_tx.send(minutes(10)).await.unwrap();
+        settle().await;
+
+        // still open after the first minute
+        for _ in 0..8 {
+            clock.advance(SAMPLE_PERIOD);
+            settle().await;
+        }
+        assert!(logging::is_verbose());
+
+        for _ in 0..17 {
+            clock.advance(SAMPLE_PERIOD);
+            settle().await;
+        }
+        assert!(!logging::is_verbose());
+
+        let sent = sent.lock().unwrap();
+        let (path, summary) = sent.last().unwrap();
+        assert_eq!(path, "/diagnosticsWindow/closed");
+        assert_eq!(
+            *summary,
+            AstarteType::String(r#"{"openSecs":145,"samples":28,"extensions":1}"#.to_owned())
+        );
+
+        drop(requests_tx);
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Guard against the store filesystem filling up.
+//!
+//! Below the free space floor the runtime enters a degraded mode: Bulk data is no longer queued,
+//! downloads are refused and the state writes can use the space of a preallocated emergency
+//! reserve. The mode is left automatically once enough space is freed.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use log::{info, warn};
+#[cfg(test)]
+use mockall::automock;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::ota::ota_handler::OTAError;
+
+/// Free space below which the degraded mode is entered, unless configured otherwise.
+pub const DEFAULT_FREE_SPACE_FLOOR: u64 = 64 * 1024 * 1024;
+/// How often the free space is checked in background.
+pub const DISK_CHECK_PERIOD: Duration = Duration::from_secs(30);
+/// Minimum interval between two alarms while degraded.
+const ALARM_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const EMERGENCY_RESERVE_NAME: &str = ".emergency_reserve";
+const EMERGENCY_RESERVE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+#[cfg_attr(test, automock)]
+pub trait SpaceProvider: Send + Sync {
+    fn usage(&self, path: &Path) -> Result<DiskUsage, DeviceManagerError>;
+}
+
+pub struct StatvfsProvider;
+
+impl SpaceProvider for StatvfsProvider {
+    fn usage(&self, path: &Path) -> Result<DiskUsage, DeviceManagerError> {
+        let stat = nix::sys::statvfs::statvfs(path)
+            .map_err(|err| DeviceManagerError::IOError(err.into()))?;
+        let fragment_size = stat.fragment_size() as u64;
+
+        Ok(DiskUsage {
+            total_bytes: stat.blocks() as u64 * fragment_size,
+            available_bytes: stat.blocks_available() as u64 * fragment_size,
+        })
+    }
+}
+
+/// Whether `err` is caused by the filesystem being full.
+pub fn is_storage_full(err: &std::io::Error) -> bool {
+    err.raw_os_error() == Some(nix::errno::Errno::ENOSPC as i32)
+}
+
+#[derive(Default)]
+struct GuardState {
+    degraded: bool,
+    usage: DiskUsage,
+    last_alarm: Option<Instant>,
+    /// The last published alarm reported the degraded mode.
+    alarm_published: bool,
+}
+
+pub struct DiskGuard {
+    provider: Box<dyn SpaceProvider>,
+    path: PathBuf,
+    floor: u64,
+    clock: Arc<dyn Clock>,
+    state: Mutex<GuardState>,
+}
+
+impl DiskGuard {
+    pub fn new(
+        provider: Box<dyn SpaceProvider>,
+        store_directory: &str,
+        floor: u64,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let guard = DiskGuard {
+            provider,
+            path: PathBuf::from(store_directory),
+            floor,
+            clock,
+            state: Mutex::new(GuardState::default()),
+        };
+
+        if !guard.check() {
+            guard.restore_reserve();
+        }
+
+        guard
+    }
+
+    fn reserve_path(&self) -> PathBuf {
+        self.path.join(EMERGENCY_RESERVE_NAME)
+    }
+
+    /// Query the free space, entering or leaving the degraded mode. Returns whether it is
+    /// degraded.
+    pub fn check(&self) -> bool {
+        let usage = match self.provider.usage(&self.path) {
+            Ok(usage) => usage,
+            Err(err) => {
+                warn!(
+                    "Unable to read the free space of {:?}: {:?}",
+                    self.path, err
+                );
+                return self.is_degraded();
+            }
+        };
+
+        let degraded = usage.available_bytes < self.floor;
+        let was_degraded = {
+            let mut state = self.state.lock().unwrap();
+            state.usage = usage;
+            std::mem::replace(&mut state.degraded, degraded)
+        };
+
+        match (was_degraded, degraded) {
+            (false, true) => warn!(
+                "Free space below the floor, {} of {} bytes available: entering degraded mode",
+                usage.available_bytes, usage.total_bytes
+            ),
+            (true, false) => {
+                info!(
+                    "Free space restored, {} of {} bytes available: leaving degraded mode",
+                    usage.available_bytes, usage.total_bytes
+                );
+                self.restore_reserve();
+            }
+            _ => {}
+        }
+
+        degraded
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.state.lock().unwrap().degraded
+    }
+
+    /// Fail with [`OTAError::NotEnoughSpace`] while degraded.
+    pub fn ensure_space(&self) -> Result<(), DeviceManagerError> {
+        if self.check() {
+            return Err(OTAError::NotEnoughSpace.into());
+        }
+
+        Ok(())
+    }
+
+    /// Give back the space of the emergency reserve, returns whether any was released.
+    pub fn release_reserve(&self) -> bool {
+        let path = self.reserve_path();
+        let released = std::fs::metadata(&path).map_or(false, |metadata| metadata.len() > 0);
+
+        if released {
+            warn!("Releasing the emergency reserve");
+            if let Err(err) = OpenOptions::new().write(true).truncate(true).open(&path) {
+                warn!("Unable to release the emergency reserve: {}", err);
+                return false;
+            }
+        }
+
+        released
+    }
+
+    fn restore_reserve(&self) {
+        let path = self.reserve_path();
+        if std::fs::metadata(&path).map_or(false, |metadata| {
+            metadata.len() as usize >= EMERGENCY_RESERVE_SIZE
+        }) {
+            return;
+        }
+
+        // the blocks are written, a sparse file would reserve nothing
+        let written = std::fs::File::create(&path)
+            .and_then(|mut file| file.write_all(&[0; EMERGENCY_RESERVE_SIZE]));
+        if let Err(err) = written {
+            warn!("Unable to allocate the emergency reserve: {}", err);
+        }
+    }
+
+    /// Publish the alarm on the diagnostics interface when entering the degraded mode, at most
+    /// once per [`ALARM_INTERVAL`] while it lasts, and once more on recovery.
+    pub async fn publish_alarm(&self, publisher: &impl Publisher) -> Result<(), AstarteError> {
+        let now = self.clock.now_monotonic();
+        let (degraded, usage) = {
+            let state = self.state.lock().unwrap();
+            let due = if state.degraded {
+                state.last_alarm.map_or(true, |last_alarm| {
+                    now.duration_since(last_alarm) >= ALARM_INTERVAL
+                })
+            } else {
+                state.alarm_published
+            };
+
+            if !due {
+                return Ok(());
+            }
+
+            (state.degraded, state.usage)
+        };
+
+        for (path, value) in [
+            ("/diskGuard/degraded", AstarteType::Boolean(degraded)),
+            (
+                "/diskGuard/availableBytes",
+                AstarteType::LongInteger(usage.available_bytes as i64),
+            ),
+            (
+                "/diskGuard/totalBytes",
+                AstarteType::LongInteger(usage.total_bytes as i64),
+            ),
+        ] {
+            publisher.send(DIAGNOSTICS_INTERFACE, path, value).await?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.alarm_published = degraded;
+        state.last_alarm = degraded.then(|| now);
+
+        Ok(())
+    }
+
+    /// Check the free space every period, publishing the alarms, never returns.
+    pub async fn run(&self, publisher: &impl Publisher, period: Duration) {
+        loop {
+            self.check();
+            if let Err(err) = self.publish_alarm(publisher).await {
+                warn!("Unable to publish the disk alarm: {:?}", err);
+            }
+
+            self.clock.sleep(period).await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::data::MockPublisher;
+    use crate::disk_guard::{
+        DiskGuard, DiskUsage, SpaceProvider, EMERGENCY_RESERVE_NAME, EMERGENCY_RESERVE_SIZE,
+    };
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::test_utils::ManualClock;
+
+    /// Space provider reporting the available space set by the test.
+    #[derive(Clone)]
+    pub(crate) struct FakeSpace(pub(crate) Arc<Mutex<u64>>);
+
+    impl SpaceProvider for FakeSpace {
+        fn usage(&self, _path: &Path) -> Result<DiskUsage, DeviceManagerError> {
+            Ok(DiskUsage {
+                total_bytes: 1000,
+                available_bytes: *self.0.lock().unwrap(),
+            })
+        }
+    }
+
+    fn alarm_publisher(alarms: Arc<Mutex<Vec<bool>>>) -> MockPublisher {
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, _| {
+                interface == DIAGNOSTICS_INTERFACE && path.starts_with("/diskGuard/")
+            })
+            .returning(move |_, path, value| {
+                if let ("/diskGuard/degraded", AstarteType::Boolean(degraded)) = (path, value) {
+                    alarms.lock().unwrap().push(degraded);
+                }
+                Ok(())
+            });
+        publisher
+    }
+
+    #[tokio::test]
+    async fn degraded_mode_entered_and_left() {
+        let dir = tempfile::tempdir().unwrap();
+        let space = FakeSpace(Arc::new(Mutex::new(500)));
+        let clock = Arc::new(ManualClock::new());
+        let guard = DiskGuard::new(
+            Box::new(space.clone()),
+            dir.path().to_str().unwrap(),
+            100,
+            clock.clone(),
+        );
+
+        let reserve = dir.path().join(EMERGENCY_RESERVE_NAME);
+        assert_eq!(
+            std::fs::metadata(&reserve).unwrap().len() as usize,
+            EMERGENCY_RESERVE_SIZE
+        );
+        assert!(guard.ensure_space().is_ok());
+
+        let alarms = Arc::new(Mutex::new(Vec::new()));
+        let publisher = alarm_publisher(alarms.clone());
+        guard.publish_alarm(&publisher).await.unwrap();
+        assert!(alarms.lock().unwrap().is_empty());
+
+        *space.0.lock().unwrap() = 50;
+        assert!(matches!(
+            guard.ensure_space(),
+            Err(DeviceManagerError::OTAError(
+                crate::ota::ota_handler::OTAError::NotEnoughSpace
+            ))
+        ));
+        assert!(guard.release_reserve());
+        assert_eq!(std::fs::metadata(&reserve).unwrap().len(), 0);
+        assert!(!guard.release_reserve());
+
+        // a single alarm within the interval
+        guard.publish_alarm(&publisher).await.unwrap();
+        guard.check();
+        guard.publish_alarm(&publisher).await.unwrap();
+        assert_eq!(*alarms.lock().unwrap(), vec![true]);
+
+        clock.advance(Duration::from_secs(60 * 60));
+        guard.publish_alarm(&publisher).await.unwrap();
+        assert_eq!(*alarms.lock().unwrap(), vec![true, true]);
+
+        *space.0.lock().unwrap() = 500;
+        assert!(!guard.check());
+        assert_eq!(
+            std::fs::metadata(&reserve).unwrap().len() as usize,
+            EMERGENCY_RESERVE_SIZE
+        );
+        guard.publish_alarm(&publisher).await.unwrap();
+        guard.publish_alarm(&publisher).await.unwrap();
+        assert_eq!(*alarms.lock().unwrap(), vec![true, true, false]);
+    }
+}
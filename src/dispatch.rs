@@ -0,0 +1,1662 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Routing of the messages received from Astarte to the components handling them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::{Aggregation, Clientbound};
+use log::warn;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::app_config::{AppConfigRequest, ROLLBACK_COMMAND};
+use crate::benchmark::{BenchmarkRequest, BENCHMARK_COMMAND};
+use crate::capabilities::{CapabilityReport, Feature};
+use crate::commands::{self, CommandRequest, CommandResult};
+use crate::containers::{ContainerRequest, Deployment};
+use crate::data::mute::MuteEvent;
+use crate::destructive::DestructiveCommand;
+use crate::diagnostics_window::{WindowRequest, WINDOW_COMMAND};
+use crate::display_info::{DisplayInfoEvent, DisplayInfoStore};
+use crate::error::DeviceManagerError;
+use crate::event_log::{ExportRequest, EXPORT_COMMAND};
+use crate::forwarder::SessionRequest;
+use crate::interfaces::{
+    APP_CONFIG_REQUEST_INTERFACE, COMMANDS_INTERFACE, CONTAINER_REQUEST_INTERFACE,
+    CRASH_UPLOAD_REQUEST_INTERFACE, DEPLOYMENT_REQUEST_INTERFACE, DISPLAY_INFO_INTERFACE,
+    FORWARDER_SESSION_REQUEST_INTERFACE, LED_BEHAVIOR_INTERFACE, MUTE_CONFIG_INTERFACE,
+    OTA_REQUEST_INTERFACE, QUIET_HOURS_CONFIG_INTERFACE, TELEMETRY_CONFIG_INTERFACE,
+};
+use crate::inventory::{Inventory, INVENTORY_COMMAND};
+use crate::led::{self, LedRequest};
+use crate::led_behavior::LedBehaviorRequest;
+use crate::ota::messages::OtaCancel;
+use crate::ota::worker::OtaWorker;
+use crate::power_management::RebootCommand;
+use crate::quiet_hours::{QuietHours, QuietHoursEvent};
+use crate::redaction::redactor;
+use crate::telemetry::config::TelemetryConfigEvent;
+use crate::telemetry::flush::{FlushRequest, FLUSH_COMMAND};
+use crate::telemetry::schedule::{TelemetrySchedule, SCHEDULE_COMMAND};
+
+/// What became of a received message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    Handled,
+    /// The feature handling the message is disabled or not configured.
+    Ignored,
+    /// The message is addressed to a known endpoint but its content is not valid.
+    Invalid,
+    /// Nothing handles the interface or the path of the message.
+    Unknown,
+}
+
+pub struct Dispatcher {
+    ota: Arc<OtaWorker>,
+    ota_cancel: Option<watch::Sender<Option<Uuid>>>,
+    telemetry_config: Sender<TelemetryConfigEvent>,
+    led: Option<Sender<LedRequest>>,
+    led_behaviors: Option<Sender<LedBehaviorRequest>>,
+    crash_uploads: Option<Sender<HashMap<String, AstarteType>>>,
+    remote_terminal: Option<Sender<SessionRequest>>,
+    containers: Option<Sender<ContainerRequest>>,
+    app_config: Option<Sender<AppConfigRequest>>,
+    mutes: Option<Sender<MuteEvent>>,
+    benchmark: Option<Sender<BenchmarkRequest>>,
+    diagnostics_window: Option<Sender<WindowRequest>>,
+    destructive: Option<Sender<DestructiveCommand>>,
+    reboots: Option<Sender<CommandRequest>>,
+    commands: Option<Sender<CommandRequest>>,
+    custom_commands: Option<Sender<CommandRequest>>,
+    command_results: Option<Sender<CommandResult>>,
+    event_log_exports: Option<Sender<ExportRequest>>,
+    quiet_hours: Option<Arc<QuietHours>>,
+    display_info: Option<Arc<DisplayInfoStore>>,
+    telemetry_schedule: Option<Arc<TelemetrySchedule>>,
+    telemetry_flush: Option<Sender<FlushRequest>>,
+    inventory: Option<Arc<Inventory>>,
+    capabilities: CapabilityReport,
+}
+
+impl Dispatcher {
+    pub fn new(
+        ota: Arc<OtaWorker>,
+        telemetry_config: Sender<TelemetryConfigEvent>,
+        led: Option<Sender<LedRequest>>,
+        capabilities: CapabilityReport,
+    ) -> Self {
+        Dispatcher {
+            ota,
+            ota_cancel: None,
+            telemetry_config,
+            led,
+            led_behaviors: None,
+            crash_uploads: None,
+            remote_terminal: None,
+            containers: None,
+            app_config: None,
+            mutes: None,
+            benchmark: None,
+            diagnostics_window: None,
+            destructive: None,
+            reboots: None,
+            commands: None,
+            custom_commands: None,
+            command_results: None,
+            event_log_exports: None,
+            quiet_hours: None,
+            display_info: None,
+            telemetry_schedule: None,
+            telemetry_flush: None,
+            inventory: None,
+            capabilities,
+        }
+    }
+
+    /// Cancel the running OTA update, the requests can't overtake it in the queue.
+    pub fn with_ota_cancel(mut self, ota_cancel: watch::Sender<Option<Uuid>>) -> Self {
+        self.ota_cancel = Some(ota_cancel);
+        self
+    }
+
+    /// Forward the behaviors of `io.edgehog.devicemanager.LedBehavior`.
+    pub fn with_led_behaviors(mut self, led_behaviors: Sender<LedBehaviorRequest>) -> Self {
+        self.led_behaviors = Some(led_behaviors);
+        self
+    }
+
+    /// Forward the remote terminal sessions, when enabled.
+    pub fn with_remote_terminal(mut self, remote_terminal: Option<Sender<SessionRequest>>) -> Self {
+        self.remote_terminal = remote_terminal;
+        self
+    }
+
+    /// Forward the container deployments and actions, when enabled.
+    pub fn with_containers(mut self, containers: Option<Sender<ContainerRequest>>) -> Self {
+        self.containers = containers;
+        self
+    }
+
+    /// Forward the hidden benchmark command.
+    pub fn with_benchmark(mut self, benchmark: Sender<BenchmarkRequest>) -> Self {
+        self.benchmark = Some(benchmark);
+        self
+    }
+
+    /// Forward the diagnostics window requests.
+    pub fn with_diagnostics_window(mut self, diagnostics_window: Sender<WindowRequest>) -> Self {
+        self.diagnostics_window = Some(diagnostics_window);
+        self
+    }
+
+    /// Forward the destructive commands and their confirmations.
+    pub fn with_destructive(mut self, destructive: Sender<DestructiveCommand>) -> Self {
+        self.destructive = Some(destructive);
+        self
+    }
+
+    /// Forward the reboot commands, to schedule and cancel the delayed reboots.
+    pub fn with_reboots(mut self, reboots: Sender<CommandRequest>) -> Self {
+        self.reboots = Some(reboots);
+        self
+    }
+
+    /// Queue the built in commands for their runner.
+    pub fn with_commands(mut self, commands: Sender<CommandRequest>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Forward the commands that aren't built in, to run the configured custom commands.
+    pub fn with_custom_commands(mut self, custom_commands: Option<Sender<CommandRequest>>) -> Self {
+        self.custom_commands = custom_commands;
+        self
+    }
+
+    /// Acknowledge the results of the commands run by the dispatcher.
+    pub fn with_command_results(mut self, command_results: Sender<CommandResult>) -> Self {
+        self.command_results = Some(command_results);
+        self
+    }
+
+    /// Forward the event log exports, when the event log is enabled.
+    pub fn with_event_log_exports(
+        mut self,
+        event_log_exports: Option<Sender<ExportRequest>>,
+    ) -> Self {
+        self.event_log_exports = event_log_exports;
+        self
+    }
+
+    /// Reject the commands during the quiet hours and apply the ranges set by the backend, when
+    /// the quiet hours are configured.
+    pub fn with_quiet_hours(mut self, quiet_hours: Option<Arc<QuietHours>>) -> Self {
+        self.quiet_hours = quiet_hours;
+        self
+    }
+
+    /// Keep the display name and the notes set by the backend.
+    pub fn with_display_info(mut self, display_info: Arc<DisplayInfoStore>) -> Self {
+        self.display_info = Some(display_info);
+        self
+    }
+
+    /// Publish `telemetry_schedule` on request.
+    pub fn with_telemetry_schedule(mut self, telemetry_schedule: Arc<TelemetrySchedule>) -> Self {
+        self.telemetry_schedule = Some(telemetry_schedule);
+        self
+    }
+
+    /// Forward the on-demand telemetry sends.
+    pub fn with_telemetry_flush(mut self, telemetry_flush: Sender<FlushRequest>) -> Self {
+        self.telemetry_flush = Some(telemetry_flush);
+        self
+    }
+
+    /// Refresh the `inventory` on request.
+    pub fn with_inventory(mut self, inventory: Arc<Inventory>) -> Self {
+        self.inventory = Some(inventory);
+        self
+    }
+
+    /// Forward the mute changes set by the backend.
+    pub fn with_mutes(mut self, mutes: Sender<MuteEvent>) -> Self {
+        self.mutes = Some(mutes);
+        self
+    }
+
+    /// Forward the crash report upload requests, when the crash reports are enabled.
+    pub fn with_crash_uploads(
+        mut self,
+        crash_uploads: Option<Sender<HashMap<String, AstarteType>>>,
+    ) -> Self {
+        self.crash_uploads = crash_uploads;
+        self
+    }
+
+    /// Forward the app config documents and rollbacks, when targets are configured.
+    pub fn with_app_config(mut self, app_config: Option<Sender<AppConfigRequest>>) -> Self {
+        self.app_config = app_config;
+        self
+    }
+
+    /// Hand `clientbound` over to the component handling it.
+    pub async fn dispatch(&self, clientbound: &Clientbound) -> Dispatch {
+        match (
+            clientbound.interface.as_str(),
+            clientbound
+                .path
+                .trim_matches('/')
+                .split('/')
+                .collect::<Vec<&str>>()
+                .as_slice(),
+            &clientbound.data,
+        ) {
+            (OTA_REQUEST_INTERFACE, ["request"], Aggregation::Object(data))
+                if OtaCancel::is_cancel(data) =>
+            {
+                match (OtaCancel::try_from(data), &self.ota_cancel) {
+                    (Ok(cancel), Some(ota_cancel))
+                        if self.capabilities.is_available(Feature::Ota) =>
+                    {
+                        ota_cancel.send_replace(Some(cancel.uuid));
+                        Dispatch::Handled
+                    }
+                    (Ok(_), _) => {
+                        warn!(
+                            "OTA is disabled, ignoring the cancel: {}",
+                            redactor().object(data)
+                        );
+                        Dispatch::Ignored
+                    }
+                    (Err(err), _) => {
+                        warn!("Invalid OTA cancel ({}): {}", redactor().object(data), err);
+                        Dispatch::Invalid
+                    }
+                }
+            }
+            (OTA_REQUEST_INTERFACE, ["request"], Aggregation::Object(data)) => {
+                if self.capabilities.is_available(Feature::Ota) {
+                    self.ota.submit(data.clone());
+                    Dispatch::Handled
+                } else {
+                    warn!(
+                        "OTA is disabled, ignoring the request: {}",
+                        redactor().object(data)
+                    );
+                    Dispatch::Ignored
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if self
+                .quiet_hours
+                .as_ref()
+                .map_or(false, |quiet_hours| !quiet_hours.allows(command)) =>
+            {
+                if let Some(quiet_hours) = &self.quiet_hours {
+                    quiet_hours.reject(command);
+                }
+                Dispatch::Handled
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command == "Identify" => match &self.led {
+                Some(led) => {
+                    led.send(LedRequest::Claim(led::IDENTIFY_ROLE.to_owned()))
+                        .await
+                        .unwrap_or_else(|_| warn!("The LED manager stopped"));
+                    Dispatch::Handled
+                }
+                None => {
+                    warn!("No LED configured, ignoring the identify command");
+                    Dispatch::Ignored
+                }
+            },
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if DestructiveCommand::from_command(command).is_some() => {
+                match (DestructiveCommand::from_command(command), &self.destructive) {
+                    (Some(command), Some(destructive)) => {
+                        destructive
+                            .send(command)
+                            .await
+                            .unwrap_or_else(|_| warn!("The destructive actions stopped"));
+                        Dispatch::Handled
+                    }
+                    _ => Dispatch::Ignored,
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command.split_whitespace().next() == Some(WINDOW_COMMAND) => {
+                match (
+                    WindowRequest::from_command(command),
+                    &self.diagnostics_window,
+                ) {
+                    (Some(request), Some(diagnostics_window)) => {
+                        diagnostics_window
+                            .send(request)
+                            .await
+                            .unwrap_or_else(|_| warn!("The diagnostics window stopped"));
+                        Dispatch::Handled
+                    }
+                    (Some(_), None) => Dispatch::Ignored,
+                    (None, _) => {
+                        warn!(
+                            "Invalid diagnostics window command: {}",
+                            redactor().text(command)
+                        );
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command.split_whitespace().next() == Some(EXPORT_COMMAND) => {
+                match (
+                    ExportRequest::from_command(command),
+                    &self.event_log_exports,
+                ) {
+                    (Some(request), Some(exports)) => {
+                        exports
+                            .send(request)
+                            .await
+                            .unwrap_or_else(|_| warn!("The event log exports stopped"));
+                        Dispatch::Handled
+                    }
+                    (Some(_), None) => Dispatch::Ignored,
+                    (None, _) => {
+                        warn!("Invalid event log export: {}", redactor().text(command));
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command.split_whitespace().next() == Some(ROLLBACK_COMMAND) => {
+                match AppConfigRequest::from_command(command) {
+                    Some(request) => self.forward_app_config(request).await,
+                    None => {
+                        warn!("Invalid app config rollback: {}", redactor().text(command));
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command == SCHEDULE_COMMAND => match &self.telemetry_schedule {
+                Some(telemetry_schedule) => {
+                    telemetry_schedule.request();
+                    Dispatch::Handled
+                }
+                None => Dispatch::Ignored,
+            },
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command == INVENTORY_COMMAND => match &self.inventory {
+                // the rate limited requests are acknowledged as such
+                Some(inventory) => {
+                    inventory.request();
+                    Dispatch::Handled
+                }
+                None => Dispatch::Ignored,
+            },
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command.split_whitespace().next() == Some(FLUSH_COMMAND) => {
+                match (FlushRequest::from_command(command), &self.telemetry_flush) {
+                    (Some(request), Some(telemetry_flush)) => {
+                        // a pending send already collects fresh values
+                        if telemetry_flush.try_send(request).is_err() {
+                            warn!(
+                                "A telemetry send is already pending, ignoring {}",
+                                redactor().text(command)
+                            );
+                        }
+                        Dispatch::Handled
+                    }
+                    (Some(_), None) => Dispatch::Ignored,
+                    (None, _) => {
+                        warn!("Invalid telemetry send: {}", redactor().text(command));
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if command.split_whitespace().next() == Some(BENCHMARK_COMMAND) => {
+                match (BenchmarkRequest::from_command(command), &self.benchmark) {
+                    (Ok(request), Some(benchmark)) => {
+                        // a benchmark is already pending when the channel is full
+                        if benchmark.try_send(request).is_err() {
+                            warn!(
+                                "A benchmark is already pending, ignoring {}",
+                                redactor().text(command)
+                            );
+                        }
+                        Dispatch::Handled
+                    }
+                    (Ok(_), None) => Dispatch::Ignored,
+                    (Err(err), _) => {
+                        warn!("Invalid benchmark command: {err}");
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if self.reboots.is_some()
+                && RebootCommand::from_command(&CommandRequest::parse(command).command)
+                    .is_some() =>
+            {
+                let request = CommandRequest::parse(command);
+                match &self.reboots {
+                    Some(reboots) if self.capabilities.is_available(Feature::Reboot) => {
+                        reboots
+                            .send(request)
+                            .await
+                            .unwrap_or_else(|_| warn!("The reboot scheduler stopped"));
+                        Dispatch::Handled
+                    }
+                    _ => {
+                        warn!(
+                            "Reboot is disabled, ignoring the command {}",
+                            redactor().text(command)
+                        );
+                        self.acknowledge(&request, Err(reboot_disabled())).await;
+                        Dispatch::Ignored
+                    }
+                }
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) if self.custom_commands.is_some()
+                && !commands::BUILTIN_COMMANDS.contains(&CommandRequest::parse(command).name()) =>
+            {
+                if let Some(custom_commands) = &self.custom_commands {
+                    // checked against the allow-list by the runner, which acknowledges it
+                    custom_commands
+                        .send(CommandRequest::parse(command))
+                        .await
+                        .unwrap_or_else(|_| warn!("The custom commands stopped"));
+                }
+                Dispatch::Handled
+            }
+
+            (
+                COMMANDS_INTERFACE,
+                ["request"],
+                Aggregation::Individual(AstarteType::String(command)),
+            ) => {
+                let request = CommandRequest::parse(command);
+                if !self.capabilities.is_available(Feature::Reboot) {
+                    warn!(
+                        "Reboot is disabled, ignoring the command {}",
+                        redactor().text(command)
+                    );
+                    self.acknowledge(&request, Err(reboot_disabled())).await;
+                    return Dispatch::Ignored;
+                }
+
+                // never waited for, a slow command must not hold back the other messages
+                let (rejected, reason) = match &self.commands {
+                    Some(commands) => match commands.try_send(request) {
+                        Ok(()) => return Dispatch::Handled,
+                        Err(TrySendError::Full(request)) => {
+                            warn!(
+                                "The commands queue is full, rejecting {}",
+                                redactor().text(command)
+                            );
+                            (request, "too many commands pending")
+                        }
+                        Err(TrySendError::Closed(request)) => {
+                            warn!("The command runner stopped");
+                            (request, "the command runner stopped")
+                        }
+                    },
+                    None => {
+                        warn!("No command runner, ignoring {}", redactor().text(command));
+                        (request, "no command runner")
+                    }
+                };
+                let err = DeviceManagerError::CommandFailed(reason.to_owned());
+                self.acknowledge(&rejected, Err(err)).await;
+                Dispatch::Ignored
+            }
+
+            (CRASH_UPLOAD_REQUEST_INTERFACE, ["request"], Aggregation::Object(data)) => {
+                match &self.crash_uploads {
+                    Some(crash_uploads) => {
+                        crash_uploads
+                            .send(data.clone())
+                            .await
+                            .unwrap_or_else(|_| warn!("The crash reporter stopped"));
+                        Dispatch::Handled
+                    }
+                    None => {
+                        warn!(
+                            "Crash reports are disabled, ignoring the upload request: {}",
+                            redactor().object(data)
+                        );
+                        Dispatch::Ignored
+                    }
+                }
+            }
+
+            (FORWARDER_SESSION_REQUEST_INTERFACE, ["request"], Aggregation::Object(data)) => {
+                match (SessionRequest::from_object(data), &self.remote_terminal) {
+                    (Some(request), Some(remote_terminal)) => {
+                        remote_terminal
+                            .send(request)
+                            .await
+                            .unwrap_or_else(|_| warn!("The remote terminal stopped"));
+                        Dispatch::Handled
+                    }
+                    (Some(_), None) => {
+                        warn!("The remote terminal is disabled, ignoring the session request");
+                        Dispatch::Ignored
+                    }
+                    // the fields are not logged, they carry the session token
+                    (None, _) => {
+                        warn!("Invalid remote terminal session request");
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            // the fields are not logged, they may carry the registry credentials
+            (DEPLOYMENT_REQUEST_INTERFACE, ["request"], Aggregation::Object(data)) => {
+                match Deployment::from_object(data) {
+                    Some(deployment) => {
+                        self.forward_container_request(ContainerRequest::Deploy(deployment))
+                            .await
+                    }
+                    None => {
+                        warn!("Invalid container deployment request");
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (CONTAINER_REQUEST_INTERFACE, path, Aggregation::Individual(value)) => {
+                match ContainerRequest::from_property(path, value) {
+                    Some(request) => self.forward_container_request(request).await,
+                    None => {
+                        warn!("Invalid container request {:?}: {:?}", path, value);
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (APP_CONFIG_REQUEST_INTERFACE, ["request"], Aggregation::Object(data)) => {
+                match AppConfigRequest::from_object(data) {
+                    Some(request) => self.forward_app_config(request).await,
+                    None => {
+                        warn!("Invalid app config request: {}", redactor().object(data));
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (TELEMETRY_CONFIG_INTERFACE, path, Aggregation::Individual(value)) => {
+                match TelemetryConfigEvent::from_property(path, value) {
+                    Some(event) => {
+                        self.telemetry_config.send(event).await.unwrap();
+                        Dispatch::Handled
+                    }
+                    None => {
+                        warn!(
+                            "Invalid telemetry config: {}",
+                            redactor().clientbound(clientbound)
+                        );
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (MUTE_CONFIG_INTERFACE, path, Aggregation::Individual(value)) => {
+                match (MuteEvent::from_property(path, value), &self.mutes) {
+                    (Some(event), Some(mutes)) => {
+                        mutes
+                            .send(event)
+                            .await
+                            .unwrap_or_else(|_| warn!("The mute worker stopped"));
+                        Dispatch::Handled
+                    }
+                    (Some(_), None) => Dispatch::Ignored,
+                    (None, _) => {
+                        warn!(
+                            "Invalid mute config: {}",
+                            redactor().clientbound(clientbound)
+                        );
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (QUIET_HOURS_CONFIG_INTERFACE, path, Aggregation::Individual(value)) => {
+                match (
+                    QuietHoursEvent::from_property(path, value),
+                    &self.quiet_hours,
+                ) {
+                    (Some(event), Some(quiet_hours)) => match quiet_hours.apply(event) {
+                        Ok(()) => Dispatch::Handled,
+                        Err(err) => {
+                            warn!("Invalid quiet hours: {err}");
+                            Dispatch::Invalid
+                        }
+                    },
+                    (Some(_), None) => Dispatch::Ignored,
+                    (None, _) => {
+                        warn!(
+                            "Invalid quiet hours config: {}",
+                            redactor().clientbound(clientbound)
+                        );
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (LED_BEHAVIOR_INTERFACE, path, Aggregation::Individual(value)) => {
+                match (
+                    LedBehaviorRequest::from_property(path, value),
+                    &self.led_behaviors,
+                ) {
+                    (Some(request), Some(led_behaviors)) => {
+                        led_behaviors
+                            .send(request)
+                            .await
+                            .unwrap_or_else(|_| warn!("The LED behaviors stopped"));
+                        Dispatch::Handled
+                    }
+                    (Some(_), None) => Dispatch::Ignored,
+                    (None, _) => {
+                        warn!(
+                            "Invalid LED behavior: {}",
+                            redactor().clientbound(clientbound)
+                        );
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            (DISPLAY_INFO_INTERFACE, path, Aggregation::Individual(value)) => {
+                match (
+                    DisplayInfoEvent::from_property(path, value),
+                    &self.display_info,
+                ) {
+                    (Some(event), Some(display_info)) => {
+                        display_info.apply(event);
+                        Dispatch::Handled
+                    }
+                    (Some(_), None) => Dispatch::Ignored,
+                    (None, _) => {
+                        warn!(
+                            "Invalid display info: {}",
+                            redactor().clientbound(clientbound)
+                        );
+                        Dispatch::Invalid
+                    }
+                }
+            }
+
+            _ => {
+                warn!(
+                    "Receiving data from an unknown path/interface: {}",
+                    redactor().clientbound(clientbound)
+                );
+                Dispatch::Unknown
+            }
+        }
+    }
+
+    async fn forward_app_config(&self, request: AppConfigRequest) -> Dispatch {
+        match &self.app_config {
+            Some(app_config) => {
+                app_config
+                    .send(request)
+                    .await
+                    .unwrap_or_else(|_| warn!("The app config stopped"));
+                Dispatch::Handled
+            }
+            None => {
+                warn!(
+                    "No app config targets, ignoring the request for {}",
+                    request.target()
+                );
+                Dispatch::Ignored
+            }
+        }
+    }
+
+    async fn forward_container_request(&self, request: ContainerRequest) -> Dispatch {
+        match &self.containers {
+            Some(containers) => {
+                containers
+                    .send(request)
+                    .await
+                    .unwrap_or_else(|_| warn!("The container manager stopped"));
+                Dispatch::Handled
+            }
+            None => {
+                warn!("Containers are disabled, ignoring the container request");
+                Dispatch::Ignored
+            }
+        }
+    }
+
+    async fn acknowledge(&self, request: &CommandRequest, result: Result<(), DeviceManagerError>) {
+        if let Some(command_results) = &self.command_results {
+            command_results
+                .send(CommandResult::new(request, &result))
+                .await
+                .unwrap_or_else(|_| warn!("The command results stopped"));
+        }
+    }
+}
+
+#[cfg(test)]
+impl Dispatcher {
+    /// Replace the capabilities found by the startup audit.
+    pub(crate) fn set_capabilities(&mut self, capabilities: CapabilityReport) {
+        self.capabilities = capabilities;
+    }
+}
+
+/// The commands need the reboot capability, for the power actions.
+fn reboot_disabled() -> DeviceManagerError {
+    DeviceManagerError::CapabilityDenied(Feature::Reboot.name().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use astarte_sdk::{Aggregation, AstarteError};
+    use async_trait::async_trait;
+    use tokio::sync::{mpsc, watch, Notify};
+    use uuid::Uuid;
+
+    use crate::app_config::AppConfigRequest;
+    use crate::capabilities::{CapabilityReport, Feature};
+    use crate::commands::{CommandRequest, CommandResult, CommandRunner};
+    use crate::containers::ContainerRequest;
+    use crate::dispatch::{Dispatch, Dispatcher};
+    use crate::display_info::{DisplayInfo, DisplayInfoStore};
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::{
+        APP_CONFIG_REQUEST_INTERFACE, COMMANDS_INTERFACE, CONTAINER_REQUEST_INTERFACE,
+        DEPLOYMENT_REQUEST_INTERFACE, DISPLAY_INFO_INTERFACE, FORWARDER_SESSION_REQUEST_INTERFACE,
+        LED_BEHAVIOR_INTERFACE, OTA_REQUEST_INTERFACE, QUIET_HOURS_CONFIG_INTERFACE,
+        SYSTEM_STATUS_INTERFACE,
+    };
+    use crate::led_behavior::{Behavior, LedBehaviorRequest};
+    use crate::ota::messages::{OtaCancel, OtaRequest};
+    use crate::power_management::PowerActions;
+    use crate::quiet_hours::{LocalZone, QuietHours, QuietHoursOptions};
+    use crate::repository::MockStateRepository;
+    use crate::telemetry::config::{
+        TelemetryConfig, TelemetryConfigWorker, TelemetryInterfaceConfig,
+    };
+    use crate::telemetry::flush::FlushRequest;
+    use crate::test_utils::harness::{self, ScriptedSession};
+    use crate::test_utils::{settle, ManualClock, MemoryStateRepository};
+
+    #[tokio::test]
+    async fn unknown_interface_reported() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, mut ota_rx) = harness::ota_worker(1);
+        let (config_tx, mut config_rx) = mpsc::channel(1);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default());
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::clientbound(
+                "io.edgehog.devicemanager.Unknown",
+                "/value",
+                Aggregation::Individual(AstarteType::Boolean(true)),
+            ))
+            .poll_error_after(
+                Duration::from_secs(1),
+                AstarteError::SendError("connection lost".to_owned()),
+            )
+            .receive(harness::clientbound(
+                COMMANDS_INTERFACE,
+                "/unknown/path",
+                Aggregation::Individual(AstarteType::String("Reboot".to_owned())),
+            ))
+            .receive(harness::command_message("Identify"))
+            .build();
+
+        let dispatched = tokio::spawn(async move {
+            let dispatched = session.dispatch_all(&dispatcher).await;
+            (session, dispatched)
+        });
+        settle().await;
+        clock.advance(Duration::from_secs(1));
+
+        let (session, dispatched) = dispatched.await.unwrap();
+        assert!(matches!(
+            dispatched.as_slice(),
+            [
+                Ok(Dispatch::Unknown),
+                Err(_),
+                Ok(Dispatch::Unknown),
+                // no LED configured
+                Ok(Dispatch::Ignored)
+            ]
+        ));
+        assert!(ota_rx.try_recv().is_err());
+        assert!(config_rx.try_recv().is_err());
+        assert!(session.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ota_cancel_skips_the_request_queue() {
+        let clock = Arc::new(ManualClock::new());
+        // the queue is full with the running update
+        let (ota_tx, mut ota_rx) = harness::ota_worker(1);
+        let running = OtaRequest::new(Uuid::new_v4(), "http://ota.bin");
+        ota_tx.submit(running.clone().into());
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (cancel_tx, cancel) = watch::channel(None);
+        let dispatcher = Dispatcher::new(
+            ota_tx,
+            config_tx,
+            None,
+            CapabilityReport::available(&[Feature::Ota]),
+        )
+        .with_ota_cancel(cancel_tx);
+
+        let mut invalid: HashMap<String, AstarteType> = OtaCancel { uuid: running.uuid }.into();
+        invalid.insert("uuid".to_owned(), AstarteType::String("42".to_owned()));
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::clientbound(
+                OTA_REQUEST_INTERFACE,
+                "/request",
+                Aggregation::Object(OtaCancel { uuid: running.uuid }.into()),
+            ))
+            .receive(harness::clientbound(
+                OTA_REQUEST_INTERFACE,
+                "/request",
+                Aggregation::Object(invalid),
+            ))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![Ok(Dispatch::Handled), Ok(Dispatch::Invalid)]
+        );
+        assert_eq!(*cancel.borrow(), Some(running.uuid));
+        assert!(ota_rx.try_recv().is_ok());
+        assert!(ota_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn app_config_requests_forwarded() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (app_config_tx, mut app_config_rx) = mpsc::channel(4);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_app_config(Some(app_config_tx));
+
+        let document = HashMap::from([
+            (
+                "target".to_owned(),
+                AstarteType::String("gateway".to_owned()),
+            ),
+            ("document".to_owned(), AstarteType::String("{}".to_owned())),
+        ]);
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::clientbound(
+                APP_CONFIG_REQUEST_INTERFACE,
+                "/request",
+                Aggregation::Object(document),
+            ))
+            .receive(harness::command_message("app-config:rollback gateway"))
+            .receive(harness::clientbound(
+                APP_CONFIG_REQUEST_INTERFACE,
+                "/request",
+                Aggregation::Object(HashMap::new()),
+            ))
+            .receive(harness::command_message("app-config:rollback"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Invalid),
+                Ok(Dispatch::Invalid)
+            ]
+        );
+        assert_eq!(
+            app_config_rx.try_recv().ok(),
+            Some(AppConfigRequest::Apply {
+                target: "gateway".to_owned(),
+                document: "{}".to_owned(),
+            })
+        );
+        assert_eq!(
+            app_config_rx.try_recv().ok(),
+            Some(AppConfigRequest::Rollback {
+                target: "gateway".to_owned(),
+            })
+        );
+        assert!(app_config_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn telemetry_config_change_applied() {
+        let clock = Arc::new(ManualClock::new());
+
+        let mut repository = MockStateRepository::<TelemetryConfig>::new();
+        repository.expect_exists().returning(|| false);
+        repository.expect_write().times(1).returning(|_| Ok(()));
+        let (worker, mut config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(repository),
+            Duration::from_millis(500),
+        );
+        let (config_tx, config_rx) = mpsc::channel(8);
+        let worker = tokio::spawn(async move { worker.run(config_rx).await });
+
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default());
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::telemetry_config_message(
+                SYSTEM_STATUS_INTERFACE,
+                "periodSeconds",
+                AstarteType::LongInteger(60),
+            ))
+            .receive(harness::telemetry_config_message(
+                SYSTEM_STATUS_INTERFACE,
+                "enable",
+                AstarteType::Boolean(false),
+            ))
+            .receive(harness::telemetry_config_message(
+                SYSTEM_STATUS_INTERFACE,
+                "periodSeconds",
+                AstarteType::String("60".to_owned()),
+            ))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Invalid)
+            ]
+        );
+
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+
+        assert!(config.has_changed().unwrap());
+        let applied = config
+            .borrow_and_update()
+            .get(SYSTEM_STATUS_INTERFACE)
+            .cloned();
+        assert_eq!(
+            applied.as_ref().and_then(|config| config.period_secs),
+            Some(60)
+        );
+        assert_eq!(applied.and_then(|config| config.enabled), Some(false));
+
+        drop(dispatcher);
+        worker.await.unwrap();
+    }
+
+    /// Dispatch the SystemStatus `changes` and wait for them to be applied, returning the
+    /// configuration of SystemStatus left.
+    async fn apply_system_status(
+        clock: &Arc<ManualClock>,
+        dispatcher: &Dispatcher,
+        config: &mut watch::Receiver<TelemetryConfig>,
+        changes: Vec<(&str, AstarteType)>,
+    ) -> Option<TelemetryInterfaceConfig> {
+        let session = changes.into_iter().fold(
+            ScriptedSession::builder(clock.clone()),
+            |session, (endpoint, value)| {
+                session.receive(harness::telemetry_config_message(
+                    SYSTEM_STATUS_INTERFACE,
+                    endpoint,
+                    value,
+                ))
+            },
+        );
+        let dispatched = session.build().dispatch_all(dispatcher).await;
+        assert!(dispatched
+            .iter()
+            .all(|result| *result == Ok(Dispatch::Handled)));
+
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+
+        config
+            .borrow_and_update()
+            .get(SYSTEM_STATUS_INTERFACE)
+            .cloned()
+    }
+
+    #[tokio::test]
+    async fn telemetry_config_unset_per_endpoint() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::<TelemetryConfig>::new());
+        let (worker, mut config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(repository.clone()),
+            Duration::from_millis(500),
+        );
+        let (config_tx, config_rx) = mpsc::channel(8);
+        let worker = tokio::spawn(async move { worker.run(config_rx).await });
+
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default());
+
+        let overridden = apply_system_status(
+            &clock,
+            &dispatcher,
+            &mut config,
+            vec![
+                ("periodSeconds", AstarteType::LongInteger(60)),
+                ("enable", AstarteType::Boolean(false)),
+            ],
+        )
+        .await;
+        assert_eq!(
+            overridden,
+            Some(TelemetryInterfaceConfig {
+                enabled: Some(false),
+                period_secs: Some(60),
+                ..Default::default()
+            })
+        );
+
+        // only the period is cleared, the collector stays disabled
+        let partial = apply_system_status(
+            &clock,
+            &dispatcher,
+            &mut config,
+            vec![("periodSeconds", AstarteType::Unset)],
+        )
+        .await;
+        assert_eq!(
+            partial,
+            Some(TelemetryInterfaceConfig {
+                enabled: Some(false),
+                ..Default::default()
+            })
+        );
+
+        // back to the defaults, with nothing left persisted
+        let cleared = apply_system_status(
+            &clock,
+            &dispatcher,
+            &mut config,
+            vec![("enable", AstarteType::Unset)],
+        )
+        .await;
+        assert_eq!(cleared, None);
+        assert_eq!(repository.value(), Some(TelemetryConfig::new()));
+
+        drop(dispatcher);
+        worker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn telemetry_send_forwarded() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (flush_tx, mut flush_rx) = mpsc::channel(1);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_telemetry_flush(flush_tx);
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::command_message(
+                "send_telemetry io.edgehog.devicemanager.SystemStatus",
+            ))
+            // the first one is still pending
+            .receive(harness::command_message("send_telemetry"))
+            .receive(harness::command_message("send_telemetry a b"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Invalid)
+            ]
+        );
+        assert_eq!(
+            flush_rx.try_recv().unwrap(),
+            FlushRequest {
+                interface_name: Some(SYSTEM_STATUS_INTERFACE.to_owned()),
+            }
+        );
+        assert!(flush_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reboot_commands_forwarded() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (reboots_tx, mut reboots_rx) = mpsc::channel(4);
+        let dispatcher = Dispatcher::new(
+            ota_tx,
+            config_tx,
+            None,
+            CapabilityReport::available(&[Feature::Reboot]),
+        )
+        .with_reboots(reboots_tx);
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::command_message("Reboot 600 requestId=7"))
+            .receive(harness::command_message("CancelReboot"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![Ok(Dispatch::Handled), Ok(Dispatch::Handled)]
+        );
+        assert_eq!(
+            reboots_rx.try_recv().unwrap(),
+            CommandRequest {
+                command: "Reboot 600".to_owned(),
+                request_id: Some("7".to_owned()),
+            }
+        );
+        assert_eq!(
+            reboots_rx.try_recv().unwrap(),
+            CommandRequest::parse("CancelReboot")
+        );
+    }
+
+    /// Power actions that never complete, as a reboot stuck on polkit.
+    struct StuckPower {
+        started: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl PowerActions for StuckPower {
+        async fn reboot(&self) -> Result<(), DeviceManagerError> {
+            self.started.notify_one();
+            std::future::pending().await
+        }
+
+        async fn shutdown(&self) -> Result<(), DeviceManagerError> {
+            self.started.notify_one();
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_command_not_blocking() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, mut config_rx) = mpsc::channel(1);
+        let (commands_tx, commands_rx) = mpsc::channel(1);
+        let (results_tx, mut results) = mpsc::channel(4);
+        let started = Arc::new(Notify::new());
+        let runner = CommandRunner::new(Box::new(StuckPower {
+            started: started.clone(),
+        }));
+        tokio::spawn(runner.run(commands_rx, results_tx.clone()));
+        let dispatcher = Dispatcher::new(
+            ota_tx,
+            config_tx,
+            None,
+            CapabilityReport::available(&[Feature::Reboot]),
+        )
+        .with_commands(commands_tx)
+        .with_command_results(results_tx);
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::command_message("Reboot requestId=5"))
+            .receive(harness::telemetry_config_message(
+                SYSTEM_STATUS_INTERFACE,
+                "enable",
+                AstarteType::Boolean(false),
+            ))
+            .build();
+
+        let dispatched =
+            tokio::time::timeout(Duration::from_secs(1), session.dispatch_all(&dispatcher))
+                .await
+                .expect("dispatch held back by the command");
+        assert_eq!(
+            dispatched,
+            vec![Ok(Dispatch::Handled), Ok(Dispatch::Handled)]
+        );
+        assert!(config_rx.try_recv().is_ok());
+        tokio::time::timeout(Duration::from_secs(1), started.notified())
+            .await
+            .expect("reboot not started");
+        assert!(results.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_commands_queue_rejected() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (commands_tx, mut commands_rx) = mpsc::channel(1);
+        let (results_tx, mut results) = mpsc::channel(4);
+        let dispatcher = Dispatcher::new(
+            ota_tx,
+            config_tx,
+            None,
+            CapabilityReport::available(&[Feature::Reboot]),
+        )
+        .with_commands(commands_tx)
+        .with_command_results(results_tx);
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::command_message("Reboot requestId=1"))
+            .receive(harness::command_message("Shutdown requestId=2"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![Ok(Dispatch::Handled), Ok(Dispatch::Ignored)]
+        );
+        assert_eq!(
+            commands_rx.try_recv().unwrap(),
+            CommandRequest::parse("Reboot requestId=1")
+        );
+        let rejected = results.try_recv().unwrap();
+        assert_eq!(rejected.request_id, "2");
+        assert!(!rejected.success);
+        assert_eq!(rejected.error, "command failed: too many commands pending");
+    }
+
+    #[tokio::test]
+    async fn custom_commands_forwarded() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (custom_tx, mut custom_rx) = mpsc::channel(4);
+        let (results_tx, mut results) = mpsc::channel(4);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_custom_commands(Some(custom_tx))
+            .with_command_results(results_tx);
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::command_message("rotate_logs requestId=3"))
+            .receive(harness::command_message("Shutdown"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![Ok(Dispatch::Handled), Ok(Dispatch::Ignored)]
+        );
+        assert_eq!(
+            custom_rx.try_recv().unwrap(),
+            CommandRequest::parse("rotate_logs requestId=3")
+        );
+        // the built-in commands still need the reboot capability
+        assert!(custom_rx.try_recv().is_err());
+        assert!(!results.try_recv().unwrap().success);
+    }
+
+    #[tokio::test]
+    async fn command_failures_acknowledged() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (reboots_tx, _reboots_rx) = mpsc::channel(4);
+        let (results_tx, mut results) = mpsc::channel(4);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_reboots(reboots_tx)
+            .with_command_results(results_tx);
+
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::command_message("Reboot requestId=7"))
+            .receive(harness::command_message("Shutdown"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![Ok(Dispatch::Ignored), Ok(Dispatch::Ignored)]
+        );
+        let result = |command: &str, request_id: &str| CommandResult {
+            command: command.to_owned(),
+            success: false,
+            error: "required capability denied: reboot".to_owned(),
+            request_id: request_id.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(results.try_recv().unwrap(), result("Reboot", "7"));
+        assert_eq!(results.try_recv().unwrap(), result("Shutdown", ""));
+    }
+
+    #[tokio::test]
+    async fn commands_rejected_during_quiet_hours() {
+        let clock = Arc::new(ManualClock::new());
+        let quiet_hours = Arc::new(
+            QuietHours::new(
+                clock.clone(),
+                Box::new(LocalZone),
+                &QuietHoursOptions::default(),
+                Box::new(MemoryStateRepository::new()),
+            )
+            .unwrap(),
+        );
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_quiet_hours(Some(quiet_hours.clone()));
+
+        let ranges = |ranges: &str| {
+            harness::clientbound(
+                QUIET_HOURS_CONFIG_INTERFACE,
+                "/request/ranges",
+                Aggregation::Individual(AstarteType::String(ranges.to_owned())),
+            )
+        };
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive(harness::command_message("Identify"))
+            // the whole day, every day
+            .receive(ranges(r#"[{"start": "00:00", "end": "00:00"}]"#))
+            .receive(harness::command_message("Identify"))
+            .receive(ranges(r#"[{"start": "00:00", "end": "25:00"}]"#))
+            .receive(ranges("00:00-06:00"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![
+                // no LED configured
+                Ok(Dispatch::Ignored),
+                Ok(Dispatch::Handled),
+                // rejected
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Invalid),
+                Ok(Dispatch::Invalid),
+            ]
+        );
+        assert!(quiet_hours.is_active());
+        assert!(!quiet_hours.allows("Identify"));
+    }
+
+    #[tokio::test]
+    async fn display_info_properties_applied() {
+        let clock = Arc::new(ManualClock::new());
+        let display_info = Arc::new(DisplayInfoStore::new(
+            Box::new(MemoryStateRepository::new()),
+            None,
+        ));
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_display_info(display_info.clone());
+
+        let property = |path: &str, value: AstarteType| {
+            harness::clientbound(DISPLAY_INFO_INTERFACE, path, Aggregation::Individual(value))
+        };
+        let mut session = ScriptedSession::builder(clock)
+            .receive(property(
+                "/displayName",
+                AstarteType::String("Press 3".to_owned()),
+            ))
+            .receive(property("/notes", AstarteType::String("Oiled".to_owned())))
+            .receive(property("/notes", AstarteType::Unset))
+            .receive(property("/notes", AstarteType::Boolean(true)))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Invalid),
+            ]
+        );
+        assert_eq!(
+            display_info.current(),
+            DisplayInfo {
+                display_name: Some("Press 3".to_owned()),
+                notes: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn led_behaviors_forwarded() {
+        let clock = Arc::new(ManualClock::new());
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (behaviors_tx, mut behaviors_rx) = mpsc::channel(4);
+        let dispatcher = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_led_behaviors(behaviors_tx);
+
+        let behavior = |path: &str, behavior: &str| {
+            harness::clientbound(
+                LED_BEHAVIOR_INTERFACE,
+                path,
+                Aggregation::Individual(AstarteType::String(behavior.to_owned())),
+            )
+        };
+        let mut session = ScriptedSession::builder(clock)
+            .receive(behavior("/green:status/behavior", "SlowBlink"))
+            .receive(behavior("/green:status/behavior", "Strobe"))
+            .receive(behavior("/green:status", "Blink"))
+            .build();
+
+        assert_eq!(
+            session.dispatch_all(&dispatcher).await,
+            vec![
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Invalid),
+                Ok(Dispatch::Invalid),
+            ]
+        );
+        assert_eq!(
+            behaviors_rx.try_recv().unwrap(),
+            LedBehaviorRequest {
+                led: "green:status".to_owned(),
+                behavior: Behavior::SlowBlink,
+            }
+        );
+        assert!(behaviors_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn remote_terminal_sessions_forwarded_when_enabled() {
+        let clock = Arc::new(ManualClock::new());
+        let session = |port: i32| {
+            let data = HashMap::from([
+                (
+                    "host".to_owned(),
+                    AstarteType::String("relay.example.com".to_owned()),
+                ),
+                ("port".to_owned(), AstarteType::Integer(port)),
+                (
+                    "sessionToken".to_owned(),
+                    AstarteType::String("t0k3n".to_owned()),
+                ),
+                ("ttlSeconds".to_owned(), AstarteType::Integer(600)),
+            ]);
+            harness::clientbound(
+                FORWARDER_SESSION_REQUEST_INTERFACE,
+                "/request",
+                Aggregation::Object(data),
+            )
+        };
+
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let disabled = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_remote_terminal(None);
+        let mut session_a = ScriptedSession::builder(clock.clone())
+            .receive(session(443))
+            .build();
+        assert_eq!(
+            session_a.dispatch_all(&disabled).await,
+            vec![Ok(Dispatch::Ignored)]
+        );
+
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (sessions_tx, mut sessions_rx) = mpsc::channel(4);
+        let enabled = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_remote_terminal(Some(sessions_tx));
+        let mut session_b = ScriptedSession::builder(clock)
+            .receive(session(443))
+            .receive(session(-1))
+            .build();
+        assert_eq!(
+            session_b.dispatch_all(&enabled).await,
+            vec![Ok(Dispatch::Handled), Ok(Dispatch::Invalid)]
+        );
+        let request = sessions_rx.try_recv().unwrap();
+        assert_eq!(request.host, "relay.example.com");
+        assert_eq!(request.ttl, Duration::from_secs(600));
+        assert!(sessions_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn container_requests_forwarded_when_enabled() {
+        let clock = Arc::new(ManualClock::new());
+        let deployment = || {
+            let data = HashMap::from([
+                (
+                    "deploymentId".to_owned(),
+                    AstarteType::String("d-1".to_owned()),
+                ),
+                (
+                    "image".to_owned(),
+                    AstarteType::String("nginx:1.23".to_owned()),
+                ),
+                (
+                    "containerName".to_owned(),
+                    AstarteType::String("web".to_owned()),
+                ),
+            ]);
+            harness::clientbound(
+                DEPLOYMENT_REQUEST_INTERFACE,
+                "/request",
+                Aggregation::Object(data),
+            )
+        };
+        let action = |action: &str| {
+            harness::clientbound(
+                CONTAINER_REQUEST_INTERFACE,
+                "/web/action",
+                Aggregation::Individual(AstarteType::String(action.to_owned())),
+            )
+        };
+
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let disabled = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_containers(None);
+        let mut session_a = ScriptedSession::builder(clock.clone())
+            .receive(deployment())
+            .receive(action("Stop"))
+            .build();
+        assert_eq!(
+            session_a.dispatch_all(&disabled).await,
+            vec![Ok(Dispatch::Ignored), Ok(Dispatch::Ignored)]
+        );
+
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (containers_tx, mut containers_rx) = mpsc::channel(4);
+        let enabled = Dispatcher::new(ota_tx, config_tx, None, CapabilityReport::default())
+            .with_containers(Some(containers_tx));
+        let mut session_b = ScriptedSession::builder(clock)
+            .receive(deployment())
+            .receive(action("Remove"))
+            .receive(action("Restart"))
+            .build();
+        assert_eq!(
+            session_b.dispatch_all(&enabled).await,
+            vec![
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Handled),
+                Ok(Dispatch::Invalid)
+            ]
+        );
+        match containers_rx.try_recv().unwrap() {
+            ContainerRequest::Deploy(deployment) => assert_eq!(deployment.container_name, "web"),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(
+            containers_rx.try_recv().unwrap(),
+            ContainerRequest::Remove("web".to_owned())
+        );
+        assert!(containers_rx.try_recv().is_err());
+    }
+}
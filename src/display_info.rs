@@ -0,0 +1,306 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Display name and notes set by the operators for the device.
+//!
+//! The backend sets them on the `/displayName` and `/notes` properties, they are persisted so
+//! that the technicians at the device see them even offline, on the status API and optionally
+//! in a banner file rendered from a template, e.g. shown on the SSH logins.
+
+use std::path::Path;
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::repository::file_state_repository::write_atomically;
+use crate::repository::StateRepository;
+
+/// Name of the file keeping the display info in the store directory.
+pub const DISPLAY_INFO_FILE: &str = "display_info.json";
+pub const DEFAULT_BANNER_TEMPLATE: &str = "Device: {displayName}\n{notes}\n";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BannerOptions {
+    /// File rendered every time the display info changes, e.g. `/run/edgehog/banner`.
+    pub path: String,
+    /// Text of the banner, `{displayName}` and `{notes}` are replaced by the values set.
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DisplayInfo {
+    pub display_name: Option<String>,
+    pub notes: Option<String>,
+}
+
+impl DisplayInfo {
+    fn is_empty(&self) -> bool {
+        self.display_name.is_none() && self.notes.is_none()
+    }
+}
+
+/// Change of one of the properties, `None` when unset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisplayInfoEvent {
+    DisplayName(Option<String>),
+    Notes(Option<String>),
+}
+
+impl DisplayInfoEvent {
+    pub fn from_property(path: &[&str], value: &AstarteType) -> Option<Self> {
+        let value = match value {
+            AstarteType::String(value) => Some(value.clone()),
+            AstarteType::Unset => None,
+            _ => return None,
+        };
+
+        match path {
+            ["displayName"] => Some(DisplayInfoEvent::DisplayName(value)),
+            ["notes"] => Some(DisplayInfoEvent::Notes(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps the display info set by the backend, with the banner rendered from it.
+pub struct DisplayInfoStore {
+    repository: Box<dyn StateRepository<DisplayInfo>>,
+    banner: Option<BannerOptions>,
+    info: watch::Sender<DisplayInfo>,
+}
+
+impl DisplayInfoStore {
+    /// Load the persisted display info and render the banner, which may live on a tmpfs.
+    pub fn new(
+        repository: Box<dyn StateRepository<DisplayInfo>>,
+        banner: Option<BannerOptions>,
+    ) -> Self {
+        let mut info = DisplayInfo::default();
+        if repository.exists() {
+            match repository.read() {
+                Ok(persisted) => info = persisted,
+                Err(err) => warn!("Unable to read the display info: {:?}", err),
+            }
+        }
+
+        let store = DisplayInfoStore {
+            repository,
+            banner,
+            info: watch::channel(info).0,
+        };
+        store.render(&store.current());
+
+        store
+    }
+
+    pub fn current(&self) -> DisplayInfo {
+        self.info.borrow().clone()
+    }
+
+    /// Follow the display info, for the status API.
+    pub fn subscribe(&self) -> watch::Receiver<DisplayInfo> {
+        self.info.subscribe()
+    }
+
+    /// Apply a property change, persisting it and rendering the banner again.
+    pub fn apply(&self, event: DisplayInfoEvent) {
+        let mut info = self.current();
+        match event {
+            DisplayInfoEvent::DisplayName(display_name) => info.display_name = display_name,
+            DisplayInfoEvent::Notes(notes) => info.notes = notes,
+        }
+        if info == self.current() {
+            return;
+        }
+
+        info!("Display info changed");
+        let persisted = if info.is_empty() {
+            if self.repository.exists() {
+                self.repository.clear()
+            } else {
+                Ok(())
+            }
+        } else {
+            self.repository.write(&info)
+        };
+        if let Err(err) = persisted {
+            warn!("Unable to persist the display info: {:?}", err);
+        }
+
+        self.render(&info);
+        self.info.send_replace(info);
+    }
+
+    /// Write the banner for `info`, removing it once nothing is set.
+    fn render(&self, info: &DisplayInfo) {
+        let banner = match &self.banner {
+            Some(banner) => banner,
+            None => return,
+        };
+        let path = Path::new(&banner.path);
+
+        let rendered = if info.is_empty() {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            }
+        } else {
+            let text = render_template(
+                banner
+                    .template
+                    .as_deref()
+                    .unwrap_or(DEFAULT_BANNER_TEMPLATE),
+                info,
+            );
+            path.parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| write_atomically(path, text.as_bytes(), None))
+        };
+        if let Err(err) = rendered {
+            warn!("Unable to render the banner {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn render_template(template: &str, info: &DisplayInfo) -> String {
+    template
+        .replace("{displayName}", info.display_name.as_deref().unwrap_or(""))
+        .replace("{notes}", info.notes.as_deref().unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::display_info::{
+        BannerOptions, DisplayInfo, DisplayInfoEvent, DisplayInfoStore, DISPLAY_INFO_FILE,
+    };
+    use crate::repository::file_state_repository::FileStateRepository;
+    use crate::repository::StateRepository;
+    use crate::test_utils::MemoryStateRepository;
+
+    #[test]
+    fn events_from_the_properties() {
+        assert_eq!(
+            DisplayInfoEvent::from_property(
+                &["displayName"],
+                &AstarteType::String("Line 3 press".to_owned())
+            ),
+            Some(DisplayInfoEvent::DisplayName(Some(
+                "Line 3 press".to_owned()
+            )))
+        );
+        assert_eq!(
+            DisplayInfoEvent::from_property(&["notes"], &AstarteType::Unset),
+            Some(DisplayInfoEvent::Notes(None))
+        );
+        assert_eq!(
+            DisplayInfoEvent::from_property(&["notes"], &AstarteType::Integer(1)),
+            None
+        );
+        assert_eq!(
+            DisplayInfoEvent::from_property(&["location"], &AstarteType::Unset),
+            None
+        );
+    }
+
+    #[test]
+    fn set_and_unset_persisted_and_rendered() {
+        let dir = tempfile::tempdir().unwrap();
+        let banner = dir.path().join("run/banner");
+        let repository = Arc::new(MemoryStateRepository::<DisplayInfo>::new());
+        let store = DisplayInfoStore::new(
+            Box::new(repository.clone()),
+            Some(BannerOptions {
+                path: banner.to_str().unwrap().to_owned(),
+                template: Some("== {displayName} ==\n{notes}\n".to_owned()),
+            }),
+        );
+        let mut info = store.subscribe();
+        assert!(!banner.exists());
+
+        store.apply(DisplayInfoEvent::DisplayName(Some("Press 3".to_owned())));
+        store.apply(DisplayInfoEvent::Notes(Some("Ask Maria".to_owned())));
+        let expected = DisplayInfo {
+            display_name: Some("Press 3".to_owned()),
+            notes: Some("Ask Maria".to_owned()),
+        };
+        assert_eq!(repository.value(), Some(expected.clone()));
+        assert_eq!(*info.borrow_and_update(), expected);
+        assert_eq!(
+            std::fs::read_to_string(&banner).unwrap(),
+            "== Press 3 ==\nAsk Maria\n"
+        );
+
+        store.apply(DisplayInfoEvent::DisplayName(None));
+        assert_eq!(
+            std::fs::read_to_string(&banner).unwrap(),
+            "==  ==\nAsk Maria\n"
+        );
+        assert!(info.has_changed().unwrap());
+
+        // nothing left to show
+        store.apply(DisplayInfoEvent::Notes(None));
+        assert!(!repository.exists());
+        assert!(!banner.exists());
+        assert_eq!(store.current(), DisplayInfo::default());
+    }
+
+    #[test]
+    fn persisted_across_restarts() {
+        let store_dir = tempfile::tempdir().unwrap();
+        let run_dir = tempfile::tempdir().unwrap();
+        let banner = run_dir.path().join("banner");
+        let start = || {
+            DisplayInfoStore::new(
+                Box::new(FileStateRepository::new(
+                    store_dir.path().to_str().unwrap().to_owned(),
+                    DISPLAY_INFO_FILE.to_owned(),
+                )),
+                Some(BannerOptions {
+                    path: banner.to_str().unwrap().to_owned(),
+                    template: None,
+                }),
+            )
+        };
+
+        start().apply(DisplayInfoEvent::DisplayName(Some("Press 3".to_owned())));
+        // the banner is gone after the reboot, the tmpfs was emptied
+        std::fs::remove_file(&banner).unwrap();
+
+        let store = start();
+        assert_eq!(
+            store.current(),
+            DisplayInfo {
+                display_name: Some("Press 3".to_owned()),
+                notes: None,
+            }
+        );
+        assert_eq!(
+            std::fs::read_to_string(&banner).unwrap(),
+            "Device: Press 3\n\n"
+        );
+    }
+}
@@ -20,6 +20,11 @@
 
 use thiserror::Error;
 
+/// Exit status of a configuration error, `EX_CONFIG` of sysexits.
+pub const EXIT_CONFIG: i32 = 78;
+/// Exit status of an error that may go away by itself, `EX_TEMPFAIL` of sysexits.
+pub const EXIT_TEMPFAIL: i32 = 75;
+
 #[derive(Error, Debug)]
 pub enum DeviceManagerError {
     #[error(transparent)]
@@ -34,14 +39,107 @@ pub enum DeviceManagerError {
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 
+    #[cfg(feature = "dbus")]
     #[error(transparent)]
     ZbusError(#[from] zbus::Error),
 
     #[error("update error")]
     UpdateError(String),
 
-    #[error("unrecoverable error")]
-    FatalError(String),
+    #[error("configuration file {0} not found")]
+    ConfigNotFound(String),
+
+    #[error("invalid {option}: {reason}")]
+    InvalidOption { option: String, reason: String },
+
+    #[error("no credentials, set credentials_secret or pairing_token")]
+    MissingCredentials,
+
+    #[error("registration of {device_id} on {url} failed, attempt {attempts}")]
+    Registration {
+        device_id: String,
+        url: String,
+        attempts: u32,
+        #[source]
+        source: Box<DeviceManagerError>,
+    },
+
+    #[error(
+        "corrupted credentials secret in {path}: set pairing_token to register again and \
+         replace it, or set credentials_secret"
+    )]
+    CorruptedCredentials {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("unable to {action} the {description} key of the persistent kernel keyring")]
+    Keyring {
+        action: &'static str,
+        description: String,
+        #[source]
+        source: nix::errno::Errno,
+    },
+
+    #[cfg(feature = "dbus")]
+    #[error("unable to reach the io.edgehog.Device D-Bus service for the hardware id")]
+    HardwareIdUnavailable(#[source] zbus::Error),
+
+    #[error("no hardware id from the io.edgehog.Device D-Bus service within {0:?}")]
+    HardwareIdTimedOut(std::time::Duration),
+
+    #[error("empty hardware id from the io.edgehog.Device D-Bus service")]
+    MissingHardwareId,
+
+    #[error("no device id found, tried {}", .0.join("; "))]
+    NoDeviceId(Vec<String>),
+
+    #[error("unable to attach to the Astarte Message Hub at {endpoint}")]
+    MessageHub {
+        endpoint: String,
+        #[source]
+        source: tonic::Status,
+    },
+
+    #[error("unable to {action} {path}")]
+    Persistence {
+        action: &'static str,
+        path: String,
+        #[source]
+        source: Box<DeviceManagerError>,
+    },
+
+    #[error("state in {path} is version {version}, written by a newer release: left read-only")]
+    NewerState { path: String, version: u32 },
+
+    #[error("state in {path} doesn't have the structure of version {version}: {reason}")]
+    IncompatibleState {
+        path: String,
+        version: u32,
+        reason: String,
+    },
+
+    #[error("OTA download into {path} failed: {reason}")]
+    OtaDownload { path: String, reason: String },
+
+    #[error("unusable interfaces in {directory}: {}", .problems.join("; "))]
+    RequiredInterfaces {
+        directory: String,
+        problems: Vec<String>,
+    },
+
+    #[error("malformed {path}: {reason}")]
+    MalformedData { path: String, reason: String },
+
+    #[error("onboarding failed: {0}")]
+    Onboarding(String),
+
+    #[error("onboarding timed out")]
+    OnboardingTimedOut,
+
+    #[error("{task} task failed: {reason}")]
+    TaskFailed { task: String, reason: String },
 
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error),
@@ -52,6 +150,252 @@ pub enum DeviceManagerError {
     #[error(transparent)]
     OTAError(#[from] crate::ota::ota_handler::OTAError),
 
-    #[error("configuration file error")]
+    #[error("configuration error: {0}")]
     ConfigFileError(#[from] toml::de::Error),
+
+    #[error("missing configuration keys: {}", .0.join(", "))]
+    MissingConfigKeys(Vec<String>),
+
+    #[error("invalid configuration: {}", .0.join("; "))]
+    Configuration(Vec<String>),
+
+    #[error("another instance is already running with pid {0}")]
+    InstanceLocked(String),
+
+    #[error("required capability denied: {0}")]
+    CapabilityDenied(String),
+
+    #[error("held off by the quiet hours")]
+    QuietHours,
+
+    #[error("an OTA update is writing to flash")]
+    FlashWriteInProgress,
+
+    #[error("invalid command: {0}")]
+    InvalidCommand(String),
+
+    #[error("command failed: {0}")]
+    CommandFailed(String),
+
+    #[error("unknown LED {0}")]
+    UnknownLed(String),
+
+    #[error("remote terminal failed: {0}")]
+    RemoteTerminalError(String),
+
+    #[error("container error: {0}")]
+    ContainerError(String),
+
+    #[error("upload failed: {0}")]
+    UploadError(String),
+
+    #[error("geolocation failed: {0}")]
+    GeolocationError(String),
+}
+
+impl DeviceManagerError {
+    /// Whether what failed may succeed later without any change on the device, e.g. once the
+    /// network, the broker or a D-Bus service is back.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            #[cfg(feature = "dbus")]
+            DeviceManagerError::ZbusError(_) | DeviceManagerError::HardwareIdUnavailable(_) => true,
+            DeviceManagerError::AstarteError(_)
+            | DeviceManagerError::HardwareIdTimedOut(_)
+            | DeviceManagerError::MissingHardwareId
+            | DeviceManagerError::NoDeviceId(_)
+            | DeviceManagerError::MessageHub { .. }
+            | DeviceManagerError::OnboardingTimedOut
+            | DeviceManagerError::UploadError(_)
+            | DeviceManagerError::GeolocationError(_)
+            | DeviceManagerError::QuietHours
+            | DeviceManagerError::FlashWriteInProgress => true,
+            DeviceManagerError::IOError(err) => !matches!(
+                err.kind(),
+                std::io::ErrorKind::NotFound
+                    | std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::InvalidData
+            ),
+            // rejected by the server, the same request is rejected again
+            DeviceManagerError::ReqwestError(err) => !err
+                .status()
+                .map_or(false, |status| status.is_client_error()),
+            DeviceManagerError::Registration { source, .. }
+            | DeviceManagerError::Persistence { source, .. } => source.is_retryable(),
+            DeviceManagerError::AstarteBuilderError(_)
+            | DeviceManagerError::ProcError(_)
+            | DeviceManagerError::UpdateError(_)
+            | DeviceManagerError::SerdeJsonError(_)
+            | DeviceManagerError::OTAError(_)
+            | DeviceManagerError::ConfigFileError(_)
+            | DeviceManagerError::MissingConfigKeys(_)
+            | DeviceManagerError::Configuration(_)
+            | DeviceManagerError::ConfigNotFound(_)
+            | DeviceManagerError::InvalidOption { .. }
+            | DeviceManagerError::MissingCredentials
+            | DeviceManagerError::CorruptedCredentials { .. }
+            | DeviceManagerError::Keyring { .. }
+            | DeviceManagerError::OtaDownload { .. }
+            | DeviceManagerError::NewerState { .. }
+            | DeviceManagerError::IncompatibleState { .. }
+            | DeviceManagerError::RequiredInterfaces { .. }
+            | DeviceManagerError::MalformedData { .. }
+            | DeviceManagerError::Onboarding(_)
+            | DeviceManagerError::TaskFailed { .. }
+            | DeviceManagerError::InstanceLocked(_)
+            | DeviceManagerError::CapabilityDenied(_)
+            | DeviceManagerError::InvalidCommand(_)
+            | DeviceManagerError::CommandFailed(_)
+            | DeviceManagerError::UnknownLed(_)
+            | DeviceManagerError::RemoteTerminalError(_)
+            | DeviceManagerError::ContainerError(_) => false,
+        }
+    }
+
+    fn is_configuration(&self) -> bool {
+        matches!(
+            self,
+            DeviceManagerError::ConfigFileError(_)
+                | DeviceManagerError::MissingConfigKeys(_)
+                | DeviceManagerError::Configuration(_)
+                | DeviceManagerError::ConfigNotFound(_)
+                | DeviceManagerError::InvalidOption { .. }
+                | DeviceManagerError::MissingCredentials
+                | DeviceManagerError::CorruptedCredentials { .. }
+                | DeviceManagerError::RequiredInterfaces { .. }
+        )
+    }
+
+    /// Exit status of the process stopped by this error, telling the service manager whether
+    /// to restart it.
+    pub fn exit_code(&self) -> i32 {
+        if self.is_configuration() {
+            EXIT_CONFIG
+        } else if self.is_retryable() {
+            EXIT_TEMPFAIL
+        } else {
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{DeviceManagerError, EXIT_CONFIG, EXIT_TEMPFAIL};
+
+    #[test]
+    fn retryable_classes() {
+        let cases = [
+            #[cfg(feature = "dbus")]
+            (
+                DeviceManagerError::ZbusError(zbus::Error::InterfaceNotFound),
+                true,
+            ),
+            #[cfg(feature = "dbus")]
+            (
+                DeviceManagerError::HardwareIdUnavailable(zbus::Error::Unsupported),
+                true,
+            ),
+            (
+                DeviceManagerError::HardwareIdTimedOut(std::time::Duration::from_secs(10)),
+                true,
+            ),
+            (DeviceManagerError::MissingHardwareId, true),
+            (DeviceManagerError::NoDeviceId(Vec::new()), true),
+            (
+                DeviceManagerError::MessageHub {
+                    endpoint: "http://[::1]:50051".to_owned(),
+                    source: tonic::Status::unavailable("connection refused"),
+                },
+                true,
+            ),
+            (DeviceManagerError::OnboardingTimedOut, true),
+            (
+                DeviceManagerError::IOError(std::io::ErrorKind::TimedOut.into()),
+                true,
+            ),
+            (
+                DeviceManagerError::IOError(std::io::ErrorKind::PermissionDenied.into()),
+                false,
+            ),
+            (DeviceManagerError::MissingCredentials, false),
+            (
+                DeviceManagerError::ConfigNotFound("/etc/edgehog/config.toml".to_owned()),
+                false,
+            ),
+            (
+                DeviceManagerError::OtaDownload {
+                    path: "/var/tmp/edgehog-updates".to_owned(),
+                    reason: "not valid UTF-8".to_owned(),
+                },
+                false,
+            ),
+            (
+                DeviceManagerError::NewerState {
+                    path: "/var/lib/edgehog/state.json".to_owned(),
+                    version: 2,
+                },
+                false,
+            ),
+            (
+                DeviceManagerError::IncompatibleState {
+                    path: "/var/lib/edgehog/state.json".to_owned(),
+                    version: 1,
+                    reason: "missing field `uuid`".to_owned(),
+                },
+                false,
+            ),
+            (
+                DeviceManagerError::Persistence {
+                    action: "create",
+                    path: "/var/lib/edgehog".to_owned(),
+                    source: Box::new(std::io::Error::from(std::io::ErrorKind::Other).into()),
+                },
+                true,
+            ),
+            (
+                DeviceManagerError::Persistence {
+                    action: "create",
+                    path: "/var/lib/edgehog".to_owned(),
+                    source: Box::new(
+                        std::io::Error::from(std::io::ErrorKind::PermissionDenied).into(),
+                    ),
+                },
+                false,
+            ),
+        ];
+
+        for (error, retryable) in cases {
+            assert_eq!(error.is_retryable(), retryable, "{error:?}");
+        }
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(
+            DeviceManagerError::Configuration(vec!["realm is empty".to_owned()]).exit_code(),
+            EXIT_CONFIG
+        );
+        assert_eq!(
+            DeviceManagerError::MissingCredentials.exit_code(),
+            EXIT_CONFIG
+        );
+        assert_eq!(
+            DeviceManagerError::RequiredInterfaces {
+                directory: "/usr/share/edgehog/interfaces".to_owned(),
+                problems: vec!["io.edgehog.devicemanager.OTARequest v0 is missing".to_owned()],
+            }
+            .exit_code(),
+            EXIT_CONFIG
+        );
+        assert_eq!(
+            DeviceManagerError::MissingHardwareId.exit_code(),
+            EXIT_TEMPFAIL
+        );
+        assert_eq!(
+            DeviceManagerError::InstanceLocked("1".to_owned()).exit_code(),
+            1
+        );
+    }
 }
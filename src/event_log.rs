@@ -0,0 +1,453 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Monotonic sequence of the audited events, to prove none was lost or reordered on the device.
+//!
+//! Every command acknowledgement, OTA status and lifecycle event gets the next number of a
+//! persisted counter, which is written before the event is published: a crash in between leaves
+//! a gap in the sequence, never a duplicate. Each number is also appended to a size-capped log
+//! in the store directory, uploaded to a presigned URL by the `audit:export <url>` command.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use astarte_sdk::types::AstarteType;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::clock::Clock;
+use crate::crash_reports::CoreUploader;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::repository::StateRepository;
+
+/// Size of the log, unless configured otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 256 * 1024;
+/// Name of the file keeping the last sequence number in the store directory.
+pub const SEQUENCE_FILE: &str = "event_sequence.json";
+/// Name of the log in the store directory, not a `.json` file so that clearing the state keeps
+/// it.
+pub const EVENT_LOG_FILE: &str = "event_log.jsonl";
+/// Command uploading the log, followed by the presigned URL.
+pub const EXPORT_COMMAND: &str = "audit:export";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventLogOptions {
+    #[serde(default)]
+    pub enabled: bool,
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditedEvent {
+    CommandAck,
+    OtaStatus,
+    Lifecycle,
+}
+
+/// Line of the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventRecord {
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    pub event_type: AuditedEvent,
+    /// OTA request or lifecycle event the event belongs to, the path of the command
+    /// acknowledgements.
+    pub correlation_id: String,
+}
+
+/// Request to upload the log with a PUT to `url`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportRequest {
+    pub url: String,
+}
+
+impl ExportRequest {
+    /// Parse `audit:export <url>`.
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [EXPORT_COMMAND, url] => Some(ExportRequest {
+                url: url.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct EventLog {
+    clock: Arc<dyn Clock>,
+    sequence: Box<dyn StateRepository<u64>>,
+    path: PathBuf,
+    max_bytes: u64,
+    /// Last number handed out, the lock also keeps the log lines in order.
+    last: Mutex<u64>,
+}
+
+impl EventLog {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        sequence: Box<dyn StateRepository<u64>>,
+        path: PathBuf,
+        max_bytes: u64,
+    ) -> Self {
+        let persisted = sequence
+            .exists()
+            .then(|| sequence.read())
+            .and_then(|read| {
+                read.map_err(|err| warn!("Unable to read the event sequence: {:?}", err))
+                    .ok()
+            })
+            .unwrap_or_default();
+        // the log has the last number when the counter is lost, e.g. with a cleared state
+        let logged = read_records(&path)
+            .map(|records| records.last().map_or(0, |record| record.sequence))
+            .unwrap_or_else(|err| {
+                warn!("Unable to read the event log: {:?}", err);
+                0
+            });
+
+        EventLog {
+            clock,
+            sequence,
+            path,
+            max_bytes,
+            last: Mutex::new(persisted.max(logged)),
+        }
+    }
+
+    /// Number the event about to be published, persisting the number first.
+    pub fn record(
+        &self,
+        event_type: AuditedEvent,
+        correlation_id: &str,
+    ) -> Result<u64, DeviceManagerError> {
+        let mut last = self.last.lock().unwrap();
+        let sequence = *last + 1;
+        self.sequence.write(&sequence)?;
+        *last = sequence;
+
+        let record = EventRecord {
+            sequence,
+            timestamp: self.clock.now_wall().into(),
+            event_type,
+            correlation_id: correlation_id.to_owned(),
+        };
+        if let Err(err) = self.append(&record) {
+            warn!("Unable to log the event {sequence}: {:?}", err);
+        }
+
+        Ok(sequence)
+    }
+
+    /// Like [`record`](Self::record), logging the failure and leaving the event unnumbered.
+    pub fn stamp(&self, event_type: AuditedEvent, correlation_id: &str) -> Option<u64> {
+        self.record(event_type, correlation_id)
+            .map_err(|err| warn!("Unable to number the event: {:?}", err))
+            .ok()
+    }
+
+    /// Append `record`, dropping the oldest ones beyond the size of the log.
+    fn append(&self, record: &EventRecord) -> Result<(), DeviceManagerError> {
+        let mut content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        content.push_str(&serde_json::to_string(record)?);
+        content.push('\n');
+
+        while content.len() as u64 > self.max_bytes {
+            match content.find('\n') {
+                Some(end) if end + 1 < content.len() => {
+                    content.drain(..=end);
+                }
+                _ => break,
+            }
+        }
+
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, content)?;
+        std::fs::rename(temporary, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn records(&self) -> Result<Vec<EventRecord>, DeviceManagerError> {
+        let _last = self.last.lock().unwrap();
+        read_records(&self.path)
+    }
+
+    /// Upload the log for each request, publishing the outcome.
+    pub async fn run_exports(
+        &self,
+        publisher: &impl Publisher,
+        uploader: &dyn CoreUploader,
+        mut requests: mpsc::Receiver<ExportRequest>,
+    ) {
+        while let Some(request) = requests.recv().await {
+            let (path, data) = match self.export(uploader, &request.url).await {
+                Ok(records) => {
+                    info!("Event log exported, {records} records");
+                    (
+                        "/eventLog/exported",
+                        AstarteType::LongInteger(records as i64),
+                    )
+                }
+                Err(err) => {
+                    warn!("Unable to export the event log: {:?}", err);
+                    (
+                        "/eventLog/exportFailed",
+                        AstarteType::String(err.to_string()),
+                    )
+                }
+            };
+
+            self.acknowledge(publisher, path, data).await;
+        }
+    }
+
+    async fn export(
+        &self,
+        uploader: &dyn CoreUploader,
+        url: &str,
+    ) -> Result<usize, DeviceManagerError> {
+        // the copy keeps the log consistent while uploading
+        let records = self.records()?;
+        let copy = self.path.with_extension("export");
+        std::fs::write(
+            &copy,
+            records
+                .iter()
+                .map(|record| serde_json::to_string(record).map(|line| line + "\n"))
+                .collect::<Result<String, _>>()?,
+        )?;
+
+        let uploaded = uploader.upload(url, &copy).await;
+        std::fs::remove_file(&copy).ok();
+
+        uploaded.map(|_| records.len())
+    }
+
+    /// Publish the command acknowledgement `data` on `path` of the diagnostics interface, after
+    /// its number on `/eventLog/sequence`.
+    pub async fn acknowledge(&self, publisher: &impl Publisher, path: &str, data: AstarteType) {
+        self.publish_stamp(publisher, AuditedEvent::CommandAck, path)
+            .await;
+
+        send(publisher, path, data).await;
+    }
+
+    /// Like [`stamp`](Self::stamp), publishing the number on `/eventLog/sequence` of the
+    /// diagnostics interface ahead of the event it numbers.
+    pub async fn publish_stamp(
+        &self,
+        publisher: &impl Publisher,
+        event_type: AuditedEvent,
+        correlation_id: &str,
+    ) {
+        if let Some(sequence) = self.stamp(event_type, correlation_id) {
+            send(
+                publisher,
+                "/eventLog/sequence",
+                AstarteType::LongInteger(sequence as i64),
+            )
+            .await;
+        }
+    }
+}
+
+async fn send(publisher: &impl Publisher, path: &str, data: AstarteType) {
+    if let Err(err) = publisher.send(DIAGNOSTICS_INTERFACE, path, data).await {
+        warn!("Unable to publish {path}: {:?}", err);
+    }
+}
+
+fn read_records(path: &Path) -> Result<Vec<EventRecord>, DeviceManagerError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    // a line cut by a crash is skipped
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+
+    use crate::crash_reports::MockCoreUploader;
+    use crate::data::MockPublisher;
+    use crate::event_log::{AuditedEvent, EventLog, ExportRequest, EVENT_LOG_FILE, SEQUENCE_FILE};
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::repository::file_state_repository::FileStateRepository;
+    use crate::test_utils::{ManualClock, MemoryStateRepository};
+
+    fn event_log(directory: &Path, max_bytes: u64) -> EventLog {
+        EventLog::new(
+            Arc::new(ManualClock::new()),
+            Box::new(FileStateRepository::new(
+                directory.to_str().unwrap().to_owned(),
+                SEQUENCE_FILE.to_owned(),
+            )),
+            directory.join(EVENT_LOG_FILE),
+            max_bytes,
+        )
+    }
+
+    #[test]
+    fn parse_command() {
+        assert_eq!(
+            ExportRequest::from_command("audit:export https://bucket/log?sig=1"),
+            Some(ExportRequest {
+                url: "https://bucket/log?sig=1".to_owned()
+            })
+        );
+        assert_eq!(ExportRequest::from_command("audit:export"), None);
+    }
+
+    #[test]
+    fn crash_before_publish_leaves_a_gap() {
+        let sequence = Arc::new(MemoryStateRepository::<u64>::new());
+        let directory = tempfile::tempdir().unwrap();
+        let log = EventLog::new(
+            Arc::new(ManualClock::new()),
+            Box::new(sequence.clone()),
+            directory.path().join(EVENT_LOG_FILE),
+            1024,
+        );
+        let mut published = vec![log.record(AuditedEvent::OtaStatus, "ota-1").unwrap()];
+
+        // numbered, then the runtime crashes before publishing
+        log.record(AuditedEvent::OtaStatus, "ota-1").unwrap();
+        drop(log);
+        std::fs::remove_file(directory.path().join(EVENT_LOG_FILE)).unwrap();
+
+        let log = EventLog::new(
+            Arc::new(ManualClock::new()),
+            Box::new(sequence.clone()),
+            directory.path().join(EVENT_LOG_FILE),
+            1024,
+        );
+        published.push(log.record(AuditedEvent::Lifecycle, "otaApplied").unwrap());
+
+        assert_eq!(published, [1, 3]);
+        assert_eq!(sequence.value(), Some(3));
+    }
+
+    #[test]
+    fn sequence_recovered_from_the_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = event_log(directory.path(), 1024);
+        for _ in 0..3 {
+            log.record(AuditedEvent::CommandAck, "Reboot").unwrap();
+        }
+
+        // the counter is lost with the cleared state, the log is kept
+        std::fs::remove_file(directory.path().join(SEQUENCE_FILE)).unwrap();
+        let log = event_log(directory.path(), 1024);
+
+        assert_eq!(log.record(AuditedEvent::CommandAck, "Reboot").unwrap(), 4);
+    }
+
+    #[test]
+    fn oldest_records_dropped_beyond_the_size() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = event_log(directory.path(), 400);
+        for _ in 0..10 {
+            log.record(AuditedEvent::Lifecycle, "firstConnection")
+                .unwrap();
+        }
+
+        let records = log.records().unwrap();
+        let kept: Vec<u64> = records.iter().map(|record| record.sequence).collect();
+        assert!(kept.len() < 10);
+        assert_eq!(kept.last(), Some(&10));
+        assert!(kept.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert!(
+            std::fs::metadata(directory.path().join(EVENT_LOG_FILE))
+                .unwrap()
+                .len()
+                <= 400
+        );
+    }
+
+    #[tokio::test]
+    async fn export_acknowledged_with_its_sequence() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = event_log(directory.path(), 1024);
+        log.record(AuditedEvent::OtaStatus, "ota-1").unwrap();
+
+        let mut uploader = MockCoreUploader::new();
+        uploader
+            .expect_upload()
+            .withf(|url, path| {
+                let lines = std::fs::read_to_string(path).unwrap();
+                url == "https://bucket/log" && lines.lines().count() == 1
+            })
+            .returning(|_, _| Ok(()));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, _, _| interface == DIAGNOSTICS_INTERFACE)
+            .returning(move |_, path, data| {
+                recorded.lock().unwrap().push((path.to_owned(), data));
+                Ok(())
+            });
+
+        let (requests_tx, requests) = mpsc::channel(1);
+        requests_tx
+            .send(ExportRequest {
+                url: "https://bucket/log".to_owned(),
+            })
+            .await
+            .unwrap();
+        drop(requests_tx);
+        log.run_exports(&publisher, &uploader, requests).await;
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                ("/eventLog/sequence".to_owned(), AstarteType::LongInteger(2)),
+                ("/eventLog/exported".to_owned(), AstarteType::LongInteger(1)),
+            ]
+        );
+        let records = log.records().unwrap();
+        assert_eq!(records[1].event_type, AuditedEvent::CommandAck);
+        assert_eq!(records[1].correlation_id, "/eventLog/exported");
+        assert!(!directory.path().join("event_log.export").exists());
+    }
+}
@@ -0,0 +1,668 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Changes to the critical files of the system, e.g. `/etc/shadow` or `authorized_keys`.
+//!
+//! The watched files, and the files directly inside the watched directories, are checked when
+//! inotify reports a change in their directory and by a periodic sweep. The SHA-256 of each file
+//! is persisted, so the sweep also finds the changes made while the runtime was down. The first
+//! sweep only records the hashes, each path is published at most `max_per_hour` times an hour.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::FILE_INTEGRITY_INTERFACE;
+use crate::repository::file_state_repository::FileStateRepository;
+use crate::repository::StateRepository;
+
+/// Changes of each path published an hour, unless configured otherwise.
+pub const DEFAULT_MAX_PER_HOUR: u32 = 6;
+pub const DEFAULT_SWEEP_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// How often the watches of the missing directories are armed again.
+const REARM_PERIOD_MILLIS: i32 = 10_000;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FileIntegrityOptions {
+    /// Files and directories to watch, the directories are not watched recursively.
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    pub sweep_period_secs: Option<u64>,
+    pub max_per_hour: Option<u32>,
+}
+
+/// Hash of each checked file by path, unset while the file is missing.
+type Hashes = BTreeMap<String, Option<String>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    Modified,
+    Deleted,
+    /// A watched path that doesn't exist, reported once.
+    Missing,
+}
+
+impl Change {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Change::Created => "created",
+            Change::Modified => "modified",
+            Change::Deleted => "deleted",
+            Change::Missing => "missing",
+        }
+    }
+}
+
+/// Published on `/event`, the fields of a missing file are empty.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileChangeReport {
+    pub path: String,
+    pub change: String,
+    pub sha256: String,
+    /// Last modification, in seconds since the epoch.
+    pub mtime: i64,
+    /// Owner of the file, -1 when unknown.
+    pub uid: i64,
+    /// Changes of the same path dropped by the rate cap since the previous one.
+    pub suppressed: i32,
+}
+
+struct Observation {
+    sha256: String,
+    mtime: i64,
+    uid: u32,
+}
+
+/// Hash and stat `path`, `None` when it doesn't exist.
+fn observe(path: &Path) -> std::io::Result<Option<Observation>> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let metadata = file.metadata()?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+
+    Ok(Some(Observation {
+        sha256: hasher
+            .finish()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect(),
+        mtime: metadata.mtime(),
+        uid: metadata.uid(),
+    }))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateWindow {
+    start: Instant,
+    published: u32,
+    suppressed: u32,
+}
+
+pub struct FileIntegrity {
+    clock: Arc<dyn Clock>,
+    paths: Vec<PathBuf>,
+    max_per_hour: u32,
+    repository: Box<dyn StateRepository<Hashes>>,
+    hashes: Mutex<Hashes>,
+    /// Set until the first sweep recorded the hashes.
+    baseline: Mutex<bool>,
+    windows: Mutex<HashMap<PathBuf, RateWindow>>,
+}
+
+impl FileIntegrity {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        options: &FileIntegrityOptions,
+        repository: Box<dyn StateRepository<Hashes>>,
+    ) -> Self {
+        let hashes = repository
+            .exists()
+            .then(|| repository.read())
+            .and_then(|read| {
+                read.map_err(|err| warn!("Unable to read the file hashes: {:?}", err))
+                    .ok()
+            });
+
+        FileIntegrity {
+            clock,
+            paths: options.paths.clone(),
+            max_per_hour: options.max_per_hour.unwrap_or(DEFAULT_MAX_PER_HOUR),
+            repository,
+            baseline: Mutex::new(hashes.is_none()),
+            hashes: Mutex::new(hashes.unwrap_or_default()),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The files to check for a change of `path`: the files inside it for a directory.
+    fn files(&self, path: &Path) -> BTreeSet<PathBuf> {
+        if !path.is_dir() {
+            return BTreeSet::from([path.to_owned()]);
+        }
+
+        let mut files: BTreeSet<PathBuf> = match std::fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|entry| entry.is_file())
+                .collect(),
+            Err(err) => {
+                warn!("Unable to list {}: {:?}", path.display(), err);
+                BTreeSet::new()
+            }
+        };
+        // the ones deleted since they were checked
+        files.extend(
+            self.hashes
+                .lock()
+                .unwrap()
+                .keys()
+                .map(PathBuf::from)
+                .filter(|file| file.parent() == Some(path)),
+        );
+
+        files
+    }
+
+    /// Whether the changes of `path` are reported.
+    fn is_watched(&self, path: &Path) -> bool {
+        self.paths
+            .iter()
+            .any(|watched| watched == path || (path.parent() == Some(watched) && watched.is_dir()))
+    }
+
+    /// Check `path` and the files inside it, publishing their changes.
+    pub async fn check(&self, publisher: &impl Publisher, path: &Path) {
+        if !self.is_watched(path) {
+            return;
+        }
+
+        for file in self.files(path) {
+            self.check_file(publisher, &file).await;
+        }
+    }
+
+    async fn check_file(&self, publisher: &impl Publisher, path: &Path) {
+        let observation = match observe(path) {
+            Ok(observation) => observation,
+            Err(err) => {
+                warn!("Unable to check {}: {:?}", path.display(), err);
+                return;
+            }
+        };
+        let key = path.to_string_lossy().into_owned();
+        let hash = observation.as_ref().map(|observed| observed.sha256.clone());
+
+        let known = self.hashes.lock().unwrap().get(&key).cloned();
+        let baseline = *self.baseline.lock().unwrap();
+        let change = match (known, &hash) {
+            (None, None) if self.paths.iter().any(|watched| watched == path) => Change::Missing,
+            (None, Some(_)) if baseline => return self.record(key, hash),
+            (None, Some(_)) | (Some(None), Some(_)) => Change::Created,
+            (Some(Some(_)), None) => Change::Deleted,
+            (Some(Some(known)), Some(hash)) if known != *hash => Change::Modified,
+            _ => return,
+        };
+
+        // left unrecorded, the next check finds the change again
+        let suppressed = match self.admit(path) {
+            Some(suppressed) => suppressed,
+            None => return,
+        };
+
+        info!("Watched file {} {}", path.display(), change.as_str());
+        let report = FileChangeReport {
+            path: key.clone(),
+            change: change.as_str().to_owned(),
+            sha256: hash.clone().unwrap_or_default(),
+            mtime: observation.as_ref().map_or(0, |observed| observed.mtime),
+            uid: observation
+                .as_ref()
+                .map_or(-1, |observed| observed.uid.into()),
+            suppressed: suppressed as i32,
+        };
+        match publisher
+            .send_object(FILE_INTEGRITY_INTERFACE, "/event", report)
+            .await
+        {
+            Ok(()) => self.record(key, hash),
+            Err(err) => warn!("Unable to publish the file change: {:?}", err),
+        }
+    }
+
+    fn record(&self, key: String, hash: Option<String>) {
+        let mut hashes = self.hashes.lock().unwrap();
+        hashes.insert(key, hash);
+
+        if let Err(err) = self.repository.write(&hashes) {
+            warn!("Unable to persist the file hashes: {:?}", err);
+        }
+    }
+
+    /// Count of the changes suppressed before this one, `None` when it goes over the cap.
+    fn admit(&self, path: &Path) -> Option<u32> {
+        let now = self.clock.now_monotonic();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(path.to_owned()).or_insert(RateWindow {
+            start: now,
+            published: 0,
+            suppressed: 0,
+        });
+        if now - window.start >= RATE_WINDOW {
+            window.start = now;
+            window.published = 0;
+        }
+
+        if window.published >= self.max_per_hour {
+            window.suppressed += 1;
+            return None;
+        }
+
+        window.published += 1;
+        Some(std::mem::take(&mut window.suppressed))
+    }
+
+    /// Check all the watched paths.
+    pub async fn sweep(&self, publisher: &impl Publisher) {
+        for path in &self.paths {
+            self.check(publisher, path).await;
+        }
+
+        let mut baseline = self.baseline.lock().unwrap();
+        if *baseline {
+            *baseline = false;
+            // recorded even when nothing is watched yet
+            if let Err(err) = self.repository.write(&self.hashes.lock().unwrap()) {
+                warn!("Unable to persist the file hashes: {:?}", err);
+            }
+        }
+    }
+
+    /// Check the paths changed according to `changed`, and all of them every `period`.
+    pub async fn run(
+        &self,
+        publisher: &impl Publisher,
+        mut changed: mpsc::Receiver<PathBuf>,
+        period: Duration,
+    ) {
+        let mut watching = true;
+        loop {
+            self.sweep(publisher).await;
+            let next_sweep = self.clock.now_monotonic() + period;
+
+            loop {
+                let path = tokio::select! {
+                    path = changed.recv(), if watching => path,
+                    _ = self.clock.sleep_until(next_sweep) => break,
+                };
+
+                match path {
+                    Some(path) => self.check(publisher, &path).await,
+                    None => {
+                        warn!("The file watch stopped, only the sweeps check the files");
+                        watching = false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Directories to watch for a change of the watched `paths`.
+fn watched_directories(paths: &[PathBuf]) -> BTreeSet<PathBuf> {
+    let mut directories = BTreeSet::new();
+    for path in paths {
+        // a file replaced by a rename shows up in its directory
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            directories.insert(parent.to_owned());
+        }
+        if path.is_dir() {
+            directories.insert(path.clone());
+        }
+    }
+
+    directories
+}
+
+/// Send the paths changed in the directories of the watched `paths` to `changed`.
+fn watch(paths: Vec<PathBuf>, changed: mpsc::Sender<PathBuf>) {
+    let inotify = match Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK) {
+        Ok(inotify) => inotify,
+        Err(err) => {
+            warn!("Unable to watch the files: {err}");
+            return;
+        }
+    };
+    let flags = AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_ATTRIB
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_DELETE_SELF;
+
+    let mut watches: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    loop {
+        // the directories created since the last pass
+        for directory in watched_directories(&paths) {
+            if watches.values().any(|watched| *watched == directory) || !directory.is_dir() {
+                continue;
+            }
+            match inotify.add_watch(&directory, flags) {
+                Ok(descriptor) => {
+                    watches.insert(descriptor, directory);
+                }
+                Err(err) => warn!("Unable to watch {}: {err}", directory.display()),
+            }
+        }
+
+        let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, REARM_PERIOD_MILLIS) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(err) => {
+                warn!("Unable to wait for the file changes: {err}");
+                return;
+            }
+        }
+
+        let events = match inotify.read_events() {
+            Ok(events) => events,
+            Err(nix::errno::Errno::EAGAIN) => Vec::new(),
+            Err(err) => {
+                warn!("Unable to read the file changes: {err}");
+                return;
+            }
+        };
+        for event in events {
+            let directory = match watches.get(&event.wd) {
+                Some(directory) => directory.clone(),
+                None => continue,
+            };
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                watches.remove(&event.wd);
+            }
+
+            let path = match event.name {
+                Some(name) => directory.join(name),
+                None => directory,
+            };
+            if changed.blocking_send(path).is_err() {
+                return;
+            }
+        }
+
+        if changed.is_closed() {
+            return;
+        }
+    }
+}
+
+/// Start watching the files in `options`.
+pub fn spawn<P>(
+    options: &FileIntegrityOptions,
+    store_directory: &Path,
+    clock: Arc<dyn Clock>,
+    publisher: P,
+) -> JoinHandle<()>
+where
+    P: Publisher + 'static,
+{
+    let integrity = FileIntegrity::new(
+        clock,
+        options,
+        Box::new(FileStateRepository::new(
+            store_directory.to_string_lossy().into_owned(),
+            "file_hashes.json".to_owned(),
+        )),
+    );
+    let period = options
+        .sweep_period_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SWEEP_PERIOD);
+
+    let (changed_tx, changed_rx) = mpsc::channel(32);
+    let paths = options.paths.clone();
+    tokio::task::spawn_blocking(move || watch(paths, changed_tx));
+
+    tokio::spawn(async move { integrity.run(&publisher, changed_rx, period).await })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use crate::file_integrity::{
+        watch, FileChangeReport, FileIntegrity, FileIntegrityOptions, Hashes,
+    };
+    use crate::interfaces::FILE_INTEGRITY_INTERFACE;
+    use crate::test_utils::{
+        recording_publisher, settle, ManualClock, MemoryStateRepository, Recording,
+    };
+
+    /// Reports published on `/event`.
+    fn reports(recording: &Recording<FileChangeReport>) -> Vec<FileChangeReport> {
+        let published = recording.on(FILE_INTEGRITY_INTERFACE);
+        assert!(published.iter().all(|(path, _)| path == "/event"));
+
+        recording.objects(FILE_INTEGRITY_INTERFACE)
+    }
+
+    fn changes(recording: &Recording<FileChangeReport>) -> Vec<(String, String)> {
+        reports(recording)
+            .into_iter()
+            .map(|report| (report.path, report.change))
+            .collect()
+    }
+
+    fn key(path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn offline_changes_found_by_the_first_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let shadow = dir.path().join("shadow");
+        let keys = dir.path().join("authorized_keys.d");
+        let missing = dir.path().join("sudoers");
+        std::fs::write(&shadow, "root:*:19000::::::").unwrap();
+        std::fs::create_dir(&keys).unwrap();
+        std::fs::write(keys.join("admin"), "ssh-ed25519 AAAA admin").unwrap();
+        std::fs::write(keys.join("old"), "ssh-ed25519 BBBB old").unwrap();
+
+        let options = FileIntegrityOptions {
+            paths: vec![shadow.clone(), keys.clone(), missing.clone()],
+            ..Default::default()
+        };
+        let repository: Arc<MemoryStateRepository<Hashes>> = Arc::new(MemoryStateRepository::new());
+        let (publisher, recording) = recording_publisher();
+
+        // the baseline only reports the missing paths
+        let integrity = FileIntegrity::new(
+            Arc::new(ManualClock::new()),
+            &options,
+            Box::new(repository.clone()),
+        );
+        integrity.sweep(&publisher).await;
+        assert_eq!(changes(&recording), [(key(&missing), "missing".to_owned())]);
+        assert_eq!(repository.value().unwrap().len(), 4);
+
+        // changed while the runtime was down
+        std::fs::write(&shadow, "root:$6$salt$hash:19000::::::").unwrap();
+        std::fs::write(keys.join("intruder"), "ssh-ed25519 CCCC intruder").unwrap();
+        std::fs::remove_file(keys.join("old")).unwrap();
+        recording.clear();
+
+        let integrity = FileIntegrity::new(
+            Arc::new(ManualClock::new()),
+            &options,
+            Box::new(repository.clone()),
+        );
+        integrity.sweep(&publisher).await;
+        let mut found = changes(&recording);
+        found.sort();
+        let mut expected = vec![
+            (key(&shadow), "modified".to_owned()),
+            (key(&keys.join("intruder")), "created".to_owned()),
+            (key(&keys.join("old")), "deleted".to_owned()),
+        ];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let reports = reports(&recording);
+        let modified = reports.iter().find(|report| report.path == key(&shadow));
+        assert_eq!(
+            modified.map(|report| report.sha256.len()),
+            Some(64),
+            "{reports:?}"
+        );
+        assert!(reports.iter().all(|report| report.suppressed == 0));
+
+        // each change is reported once
+        recording.clear();
+        integrity.sweep(&publisher).await;
+        assert!(changes(&recording).is_empty());
+    }
+
+    #[tokio::test]
+    async fn sweep_every_period_and_rate_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let shadow = dir.path().join("shadow");
+        std::fs::write(&shadow, "0").unwrap();
+
+        let clock = Arc::new(ManualClock::new());
+        let integrity = Arc::new(FileIntegrity::new(
+            clock.clone(),
+            &FileIntegrityOptions {
+                paths: vec![shadow.clone()],
+                max_per_hour: Some(2),
+                ..Default::default()
+            },
+            Box::new(MemoryStateRepository::new()),
+        ));
+        let (publisher, recording) = recording_publisher();
+        let (_changed_tx, changed_rx) = mpsc::channel(1);
+        let running = integrity.clone();
+        let period = Duration::from_secs(10 * 60);
+        let run = tokio::spawn(async move { running.run(&publisher, changed_rx, period).await });
+        settle().await;
+
+        for content in 1..=3 {
+            std::fs::write(&shadow, content.to_string()).unwrap();
+            settle().await;
+            clock.advance(period);
+            settle().await;
+        }
+        assert_eq!(changes(&recording).len(), 2);
+
+        // the suppressed change is still found once the hour is over
+        clock.advance(period * 4);
+        settle().await;
+        run.abort();
+
+        let reports = reports(&recording);
+        assert_eq!(reports.len(), 3, "{reports:?}");
+        assert!(reports.iter().all(|report| report.change == "modified"));
+        assert_eq!(reports[2].suppressed, 1);
+    }
+
+    #[tokio::test]
+    async fn inotify_change_reported_before_the_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let shadow = dir.path().join("shadow");
+        std::fs::write(&shadow, "0").unwrap();
+
+        let options = FileIntegrityOptions {
+            paths: vec![shadow.clone()],
+            max_per_hour: Some(u32::MAX),
+            ..Default::default()
+        };
+        let integrity = FileIntegrity::new(
+            Arc::new(ManualClock::new()),
+            &options,
+            Box::new(MemoryStateRepository::new()),
+        );
+        let (publisher, recording) = recording_publisher();
+        let (changed_tx, changed_rx) = mpsc::channel(32);
+        let paths = options.paths.clone();
+        std::thread::spawn(move || watch(paths, changed_tx));
+        let run = tokio::spawn(async move {
+            integrity
+                .run(&publisher, changed_rx, Duration::from_secs(24 * 60 * 60))
+                .await
+        });
+
+        // written again until the watch is armed
+        for content in 1..=50 {
+            std::fs::write(&shadow, content.to_string()).unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if !recording.all().is_empty() {
+                break;
+            }
+        }
+        run.abort();
+        settle().await;
+        // stops the watch, the channel is closed
+        std::fs::write(&shadow, "stop").unwrap();
+
+        let reports = reports(&recording);
+        assert!(!reports.is_empty());
+        assert_eq!(reports[0].path, key(&shadow));
+        assert_eq!(reports[0].change, "modified");
+        let owner = std::fs::metadata(dir.path()).unwrap().uid();
+        assert_eq!(reports[0].uid, i64::from(owner));
+        assert!(reports[0].mtime > 0);
+    }
+}
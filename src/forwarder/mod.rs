@@ -0,0 +1,500 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Remote terminal sessions, to reach the devices behind NAT without a VPN.
+//!
+//! On a request of `io.edgehog.devicemanager.ForwarderSessionRequest` the device opens a WebSocket
+//! to the given endpoint and bridges it to a shell on a PTY. Every binary message is a frame whose
+//! first byte is its kind: `0` data, the input of the shell or its output, `1` resize, with the
+//! columns and the rows as big endian `u16`, and `2` close. A session ends when either side closes
+//! it, when the shell exits and at the latest when its TTL expires.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::clock::Clock;
+use crate::error::DeviceManagerError;
+use crate::forwarder::pty::Pty;
+
+mod pty;
+
+pub const DEFAULT_REMOTE_TERMINAL_SHELL: &str = "/bin/sh";
+pub const DEFAULT_REMOTE_TERMINAL_SESSIONS: usize = 1;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_BUFFER_BYTES: usize = 4096;
+
+const DATA_FRAME: u8 = 0;
+const RESIZE_FRAME: u8 = 1;
+const CLOSE_FRAME: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Data(Vec<u8>),
+    Resize { cols: u16, rows: u16 },
+    Close,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Frame::Data(data) => [[DATA_FRAME].as_slice(), data.as_slice()].concat(),
+            Frame::Resize { cols, rows } => [
+                [RESIZE_FRAME].as_slice(),
+                &cols.to_be_bytes(),
+                &rows.to_be_bytes(),
+            ]
+            .concat(),
+            Frame::Close => vec![CLOSE_FRAME],
+        }
+    }
+
+    pub fn decode(frame: &[u8]) -> Option<Self> {
+        match frame {
+            [DATA_FRAME, data @ ..] => Some(Frame::Data(data.to_vec())),
+            [RESIZE_FRAME, cols_high, cols_low, rows_high, rows_low] => Some(Frame::Resize {
+                cols: u16::from_be_bytes([*cols_high, *cols_low]),
+                rows: u16::from_be_bytes([*rows_high, *rows_low]),
+            }),
+            [CLOSE_FRAME] => Some(Frame::Close),
+            _ => None,
+        }
+    }
+}
+
+/// Session asked on `/request`, its token is never logged.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionRequest {
+    pub host: String,
+    pub port: u16,
+    pub session_token: String,
+    pub ttl: Duration,
+    /// TLS, unless disabled by the request.
+    pub secure: bool,
+}
+
+impl fmt::Debug for SessionRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionRequest")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("session_token", &"<redacted>")
+            .field("ttl", &self.ttl)
+            .field("secure", &self.secure)
+            .finish()
+    }
+}
+
+impl SessionRequest {
+    pub fn from_object(data: &HashMap<String, AstarteType>) -> Option<Self> {
+        let string = |name: &str| match data.get(name) {
+            Some(AstarteType::String(value)) if !value.is_empty() => Some(value.clone()),
+            _ => None,
+        };
+        let integer = |name: &str| match data.get(name) {
+            Some(AstarteType::Integer(value)) => Some(i64::from(*value)),
+            Some(AstarteType::LongInteger(value)) => Some(*value),
+            _ => None,
+        };
+
+        Some(SessionRequest {
+            host: string("host")?,
+            port: u16::try_from(integer("port")?).ok()?,
+            session_token: string("sessionToken")?,
+            // a session is always bounded
+            ttl: u64::try_from(integer("ttlSeconds")?)
+                .ok()
+                .filter(|ttl| *ttl > 0)
+                .map(Duration::from_secs)?,
+            secure: !matches!(data.get("secure"), Some(AstarteType::Boolean(false))),
+        })
+    }
+
+    fn url(&self) -> String {
+        let scheme = if self.secure { "wss" } else { "ws" };
+
+        format!(
+            "{scheme}://{}:{}/device/websocket?session={}",
+            self.host, self.port, self.session_token
+        )
+    }
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    Closed,
+    ShellExited,
+    Expired,
+}
+
+pub struct Forwarder {
+    clock: Arc<dyn Clock>,
+    shell: String,
+    max_sessions: usize,
+    sessions: Arc<Semaphore>,
+}
+
+impl Forwarder {
+    pub fn new(clock: Arc<dyn Clock>, shell: String, max_sessions: usize) -> Self {
+        Forwarder {
+            clock,
+            shell,
+            max_sessions,
+            sessions: Arc::new(Semaphore::new(max_sessions)),
+        }
+    }
+
+    /// Open the requested sessions until `requests` is closed, the open ones run to their end.
+    pub async fn run(&self, mut requests: mpsc::Receiver<SessionRequest>) {
+        while let Some(request) = requests.recv().await {
+            let session = match self.sessions.clone().try_acquire_owned() {
+                Ok(session) => session,
+                Err(_) => {
+                    warn!(
+                        "Already {} remote terminal sessions, refusing the one to {}:{}",
+                        self.max_sessions, request.host, request.port
+                    );
+                    continue;
+                }
+            };
+
+            info!(
+                "Opening a remote terminal session to {}:{} for {:?}",
+                request.host, request.port, request.ttl
+            );
+            let clock = self.clock.clone();
+            let shell = self.shell.clone();
+            tokio::spawn(async move {
+                match open(clock.as_ref(), &request, &shell).await {
+                    Ok(end) => info!(
+                        "Remote terminal session to {}:{} ended: {end:?}",
+                        request.host, request.port
+                    ),
+                    Err(err) => warn!(
+                        "Remote terminal session to {}:{} failed: {err}",
+                        request.host, request.port
+                    ),
+                }
+                drop(session);
+            });
+        }
+    }
+}
+
+fn websocket_error(err: tokio_tungstenite::tungstenite::Error) -> DeviceManagerError {
+    DeviceManagerError::RemoteTerminalError(err.to_string())
+}
+
+async fn open(
+    clock: &dyn Clock,
+    request: &SessionRequest,
+    shell: &str,
+) -> Result<SessionEnd, DeviceManagerError> {
+    // the connection counts in the TTL
+    let deadline = clock.now_monotonic() + request.ttl;
+    let connect = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        tokio_tungstenite::connect_async(request.url()),
+    );
+    let connected = tokio::select! {
+        connected = connect => connected,
+        _ = clock.sleep_until(deadline) => return Ok(SessionEnd::Expired),
+    };
+    let (socket, _) = connected
+        .map_err(|_| DeviceManagerError::RemoteTerminalError("connection timed out".to_owned()))?
+        .map_err(websocket_error)?;
+
+    bridge(clock, socket, shell, deadline).await
+}
+
+/// Bridge `socket` to a new shell until the session ends, at the latest at `deadline`.
+async fn bridge<S>(
+    clock: &dyn Clock,
+    socket: WebSocketStream<S>,
+    shell: &str,
+    deadline: Instant,
+) -> Result<SessionEnd, DeviceManagerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pty = Pty::spawn(shell)?;
+    let (mut sink, mut stream) = socket.split();
+    let mut output = vec![0; READ_BUFFER_BYTES];
+
+    let end = loop {
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Binary(frame))) => match Frame::decode(&frame) {
+                    Some(Frame::Data(input)) => pty.input.write(&input).await?,
+                    Some(Frame::Resize { cols, rows }) => pty.input.resize(cols, rows)?,
+                    Some(Frame::Close) => break SessionEnd::Closed,
+                    None => warn!("Invalid remote terminal frame, ignoring it"),
+                },
+                Some(Ok(Message::Close(_))) | None => break SessionEnd::Closed,
+                // the pings are answered by the WebSocket
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(websocket_error(err)),
+            },
+            read = pty.output.read(&mut output) => match read {
+                // the PTY fails once the shell exited
+                Ok(0) | Err(_) => break SessionEnd::ShellExited,
+                Ok(read) => sink
+                    .send(Message::Binary(Frame::Data(output[..read].to_vec()).encode()))
+                    .await
+                    .map_err(websocket_error)?,
+            },
+            _ = clock.sleep_until(deadline) => break SessionEnd::Expired,
+        }
+    };
+
+    // the endpoint may already be gone
+    if end != SessionEnd::Closed {
+        sink.send(Message::Binary(Frame::Close.encode())).await.ok();
+    }
+    sink.close().await.ok();
+
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    use crate::clock::SystemClock;
+    use crate::forwarder::{open, Forwarder, Frame, SessionEnd, SessionRequest};
+    use crate::test_utils::ManualClock;
+
+    fn request(port: u16, ttl_secs: u64) -> SessionRequest {
+        SessionRequest {
+            host: "127.0.0.1".to_owned(),
+            port,
+            session_token: "t0k3n".to_owned(),
+            ttl: Duration::from_secs(ttl_secs),
+            secure: false,
+        }
+    }
+
+    async fn listen() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        (listener, port)
+    }
+
+    async fn accept(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+            assert_eq!(request.uri(), "/device/websocket?session=t0k3n");
+            Ok(response)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn send(socket: &mut WebSocketStream<TcpStream>, frame: Frame) {
+        socket.send(Message::Binary(frame.encode())).await.unwrap();
+    }
+
+    /// The next frame sent by the device, None once the WebSocket is closed.
+    async fn receive(socket: &mut WebSocketStream<TcpStream>) -> Option<Frame> {
+        loop {
+            match socket.next().await? {
+                Ok(Message::Binary(frame)) => return Frame::decode(&frame),
+                Ok(Message::Close(_)) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn frames_encoded() {
+        let frames = [
+            Frame::Data(b"ls\n".to_vec()),
+            Frame::Resize {
+                cols: 132,
+                rows: 43,
+            },
+            Frame::Close,
+        ];
+        for frame in frames {
+            assert_eq!(Frame::decode(&frame.encode()), Some(frame));
+        }
+
+        assert_eq!(
+            Frame::Resize { cols: 258, rows: 1 }.encode(),
+            [1, 1, 2, 0, 1]
+        );
+        assert_eq!(Frame::decode(&[1, 0, 80]), None);
+        assert_eq!(Frame::decode(&[7]), None);
+        assert_eq!(Frame::decode(&[]), None);
+    }
+
+    #[test]
+    fn session_request_parsed() {
+        let mut data = HashMap::from([
+            (
+                "host".to_owned(),
+                AstarteType::String("relay.example.com".to_owned()),
+            ),
+            ("port".to_owned(), AstarteType::Integer(443)),
+            (
+                "sessionToken".to_owned(),
+                AstarteType::String("t0k3n".to_owned()),
+            ),
+            ("ttlSeconds".to_owned(), AstarteType::LongInteger(900)),
+        ]);
+
+        let request = SessionRequest::from_object(&data).unwrap();
+        assert!(request.secure);
+        assert_eq!(request.ttl, Duration::from_secs(900));
+        assert_eq!(
+            request.url(),
+            "wss://relay.example.com:443/device/websocket?session=t0k3n"
+        );
+        assert!(!format!("{request:?}").contains("t0k3n"));
+
+        data.insert("secure".to_owned(), AstarteType::Boolean(false));
+        assert!(!SessionRequest::from_object(&data).unwrap().secure);
+
+        // a session without TTL would never end
+        data.insert("ttlSeconds".to_owned(), AstarteType::Integer(0));
+        assert_eq!(SessionRequest::from_object(&data), None);
+        data.insert("ttlSeconds".to_owned(), AstarteType::Integer(60));
+        data.insert("port".to_owned(), AstarteType::Integer(70000));
+        assert_eq!(SessionRequest::from_object(&data), None);
+        data.insert("port".to_owned(), AstarteType::Integer(443));
+        data.remove("sessionToken");
+        assert_eq!(SessionRequest::from_object(&data), None);
+    }
+
+    #[tokio::test]
+    async fn shell_output_round_trips() {
+        let (listener, port) = listen().await;
+        let session =
+            tokio::spawn(async move { open(&SystemClock, &request(port, 60), "/bin/sh").await });
+
+        let mut socket = accept(&listener).await;
+        send(
+            &mut socket,
+            Frame::Resize {
+                cols: 100,
+                rows: 30,
+            },
+        )
+        .await;
+        send(
+            &mut socket,
+            Frame::Data(b"echo $((40 + 2)) $(stty size)\n".to_vec()),
+        )
+        .await;
+
+        // the typed command is echoed too, without its result
+        let mut output = Vec::new();
+        while !String::from_utf8_lossy(&output).contains("42 30 100") {
+            match tokio::time::timeout(Duration::from_secs(10), receive(&mut socket)).await {
+                Ok(Some(Frame::Data(data))) => output.extend(data),
+                received => panic!("unexpected {received:?}"),
+            }
+        }
+
+        send(&mut socket, Frame::Close).await;
+        assert_eq!(session.await.unwrap().unwrap(), SessionEnd::Closed);
+    }
+
+    #[tokio::test]
+    async fn exited_shell_ends_session() {
+        let (listener, port) = listen().await;
+        let session =
+            tokio::spawn(async move { open(&SystemClock, &request(port, 60), "/bin/sh").await });
+
+        let mut socket = accept(&listener).await;
+        send(&mut socket, Frame::Data(b"exit\n".to_vec())).await;
+
+        while let Some(frame) = receive(&mut socket).await {
+            if frame == Frame::Close {
+                break;
+            }
+        }
+        assert_eq!(session.await.unwrap().unwrap(), SessionEnd::ShellExited);
+    }
+
+    #[tokio::test]
+    async fn session_ends_on_ttl_expiry() {
+        let clock = Arc::new(ManualClock::new());
+        let (listener, port) = listen().await;
+        let session_clock = clock.clone();
+        let session = tokio::spawn(async move {
+            open(session_clock.as_ref(), &request(port, 300), "/bin/sh").await
+        });
+
+        let mut socket = accept(&listener).await;
+        clock.advance(Duration::from_secs(300));
+
+        let mut frames = Vec::new();
+        while let Some(frame) = receive(&mut socket).await {
+            frames.push(frame);
+        }
+        assert_eq!(frames.last(), Some(&Frame::Close));
+        assert_eq!(session.await.unwrap().unwrap(), SessionEnd::Expired);
+    }
+
+    #[tokio::test]
+    async fn concurrent_sessions_limited() {
+        let clock = Arc::new(ManualClock::new());
+        let (listener, port) = listen().await;
+        let forwarder = Forwarder::new(clock.clone(), "/bin/sh".to_owned(), 1);
+        let (requests_tx, requests) = mpsc::channel(2);
+        requests_tx.send(request(port, 300)).await.unwrap();
+        requests_tx.send(request(port, 300)).await.unwrap();
+        drop(requests_tx);
+        forwarder.run(requests).await;
+
+        let mut socket = accept(&listener).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), listener.accept())
+                .await
+                .is_err()
+        );
+
+        clock.advance(Duration::from_secs(300));
+        while receive(&mut socket).await.is_some() {}
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Shell running on a pseudo terminal.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::Stdio;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::pty::{openpty, Winsize};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+
+use crate::error::DeviceManagerError;
+
+nix::ioctl_write_ptr_bad!(set_window_size, nix::libc::TIOCSWINSZ, Winsize);
+
+/// Writing side of the terminal, what the user types.
+pub struct PtyInput {
+    file: File,
+}
+
+impl PtyInput {
+    pub async fn write(&mut self, input: &[u8]) -> Result<(), DeviceManagerError> {
+        self.file.write_all(input).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<(), DeviceManagerError> {
+        let size = Winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: the descriptor is the PTY master and `size` outlives the call
+        unsafe { set_window_size(self.file.as_raw_fd(), &size) }.map_err(io::Error::from)?;
+
+        Ok(())
+    }
+}
+
+/// The shell is killed when dropped.
+pub struct Pty {
+    pub input: PtyInput,
+    /// What the shell prints, an error once it exited.
+    pub output: File,
+    _shell: Child,
+}
+
+impl Pty {
+    pub fn spawn(shell: &str) -> Result<Self, DeviceManagerError> {
+        let pty = openpty(None, None).map_err(io::Error::from)?;
+        // SAFETY: the descriptors were just opened and are owned by nothing else
+        let master = unsafe { std::fs::File::from_raw_fd(pty.master) };
+        let slave = unsafe { std::fs::File::from_raw_fd(pty.slave) };
+        for fd in [pty.master, pty.slave] {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(io::Error::from)?;
+        }
+
+        // the shell keeps its own copies of the slave, closed with it
+        let shell = spawn_on(shell, slave.as_raw_fd())?;
+        drop(slave);
+
+        Ok(Pty {
+            input: PtyInput {
+                file: File::from_std(master.try_clone()?),
+            },
+            output: File::from_std(master),
+            _shell: shell,
+        })
+    }
+}
+
+fn spawn_on(shell: &str, slave: RawFd) -> Result<Child, DeviceManagerError> {
+    let stdio = || -> io::Result<Stdio> {
+        let fd = fcntl(slave, FcntlArg::F_DUPFD_CLOEXEC(0)).map_err(io::Error::from)?;
+        // SAFETY: the duplicate is owned by nothing else
+        Ok(Stdio::from(unsafe { std::fs::File::from_raw_fd(fd) }))
+    };
+
+    let mut command = Command::new(shell);
+    command
+        .stdin(stdio()?)
+        .stdout(stdio()?)
+        .stderr(stdio()?)
+        .env("TERM", "xterm")
+        .kill_on_drop(true);
+    // SAFETY: only async-signal-safe calls between the fork and the exec
+    unsafe {
+        command.pre_exec(|| {
+            // a new session, with the PTY as its controlling terminal
+            nix::unistd::setsid().map_err(io::Error::from)?;
+            if nix::libc::ioctl(0, nix::libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    Ok(command.spawn()?)
+}
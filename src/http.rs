@@ -0,0 +1,440 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Shared factory of the outbound HTTP clients.
+//!
+//! Every client identifies the runtime and the device with its User-Agent and carries the
+//! configured static headers, so that the CDN and the pairing proxy can route and rate-limit
+//! the requests. The headers set on a single request layer on top of them. The clients talking
+//! to a server of a private PKI, like the OTA one, are given its CA and their own certificate.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use log::warn;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Identity};
+use serde::Deserialize;
+
+use crate::error::DeviceManagerError;
+
+/// Time to establish a connection, unless configured otherwise.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HttpOptions {
+    /// Headers sent with every request, e.g. `X-Fleet-Id`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub connect_timeout_secs: Option<u64>,
+    /// Limit of a whole request, body included; none by default since the OTA artifacts can
+    /// take long to download.
+    pub timeout_secs: Option<u64>,
+    /// Proxy for every request, in place of the one from the environment.
+    pub proxy: Option<String>,
+    /// PEM certificate trusted on top of the system ones.
+    pub ca_certificate: Option<String>,
+}
+
+/// TLS settings of the clients of a single server.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM certificates of the CA trusted on top of the system ones.
+    pub ca_certificate: Option<String>,
+    /// PEM certificate and private key presented to the server.
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    /// Skip the validation of the server certificate.
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsOptions {
+    /// Whether the defaults of the shared factory are overridden.
+    pub fn is_set(&self) -> bool {
+        self.ca_certificate.is_some()
+            || self.client_certificate.is_some()
+            || self.client_key.is_some()
+            || self.accept_invalid_certs
+    }
+}
+
+/// `edgehog-device-runtime/<version> (device <hash>)`, the device id is hashed to keep it out
+/// of the server logs.
+pub fn user_agent(device_id: &str) -> String {
+    let hash: String = openssl::sha::sha256(device_id.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    format!(
+        "{}/{} (device {hash})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn default_headers(options: &HttpOptions) -> Result<HeaderMap, DeviceManagerError> {
+    options
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            let invalid = |reason: &str| DeviceManagerError::InvalidOption {
+                option: format!("http.headers.{name}"),
+                reason: reason.to_owned(),
+            };
+            let name =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid("invalid name"))?;
+            let value = HeaderValue::from_str(value).map_err(|_| invalid("invalid value"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Builder configured with `options`, for the callers tuning the client further.
+pub fn builder(
+    options: &HttpOptions,
+    device_id: &str,
+) -> Result<reqwest::ClientBuilder, DeviceManagerError> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(user_agent(device_id))
+        .default_headers(default_headers(options)?)
+        .connect_timeout(
+            options
+                .connect_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        );
+
+    if let Some(timeout) = options.timeout_secs {
+        builder = builder.timeout(Duration::from_secs(timeout));
+    }
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    if let Some(path) = &options.ca_certificate {
+        let certificate = reqwest::Certificate::from_pem(&std::fs::read(path)?)?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    Ok(builder)
+}
+
+fn read_pem(path: &str, what: &str) -> Result<Vec<u8>, DeviceManagerError> {
+    std::fs::read(path).map_err(|err| DeviceManagerError::Persistence {
+        action: "read",
+        path: format!("the {what} {path}"),
+        source: Box::new(err.into()),
+    })
+}
+
+/// Apply `tls` to `builder`, failing on the files missing or invalid so that a wrong
+/// configuration is reported at startup.
+pub fn with_tls(
+    mut builder: reqwest::ClientBuilder,
+    tls: &TlsOptions,
+) -> Result<reqwest::ClientBuilder, DeviceManagerError> {
+    if let Some(path) = &tls.ca_certificate {
+        let invalid = |err: String| DeviceManagerError::InvalidOption {
+            option: format!("CA certificate {path}"),
+            reason: err,
+        };
+        // a bundle may hold the whole chain of the private CA
+        let certificates = X509::stack_from_pem(&read_pem(path, "CA certificate")?)
+            .map_err(|err| invalid(err.to_string()))?;
+        if certificates.is_empty() {
+            return Err(invalid("no certificate found".to_owned()));
+        }
+        for certificate in certificates {
+            let der = certificate
+                .to_der()
+                .map_err(|err| invalid(err.to_string()))?;
+            let certificate =
+                Certificate::from_der(&der).map_err(|err| invalid(err.to_string()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    match (&tls.client_certificate, &tls.client_key) {
+        (Some(certificate), Some(key)) => {
+            let invalid = |err: String| DeviceManagerError::InvalidOption {
+                option: format!("client certificate {certificate} or key {key}"),
+                reason: err,
+            };
+            let certificate = read_pem(certificate, "client certificate")?;
+            // the identity takes a PKCS#8 key, the RSA and EC ones are converted
+            let key = PKey::private_key_from_pem(&read_pem(key, "client key")?)
+                .and_then(|key| key.private_key_to_pem_pkcs8())
+                .map_err(|err| invalid(err.to_string()))?;
+            let identity = Identity::from_pkcs8_pem(&certificate, &key)
+                .map_err(|err| invalid(err.to_string()))?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(DeviceManagerError::InvalidOption {
+                option: "http.tls".to_owned(),
+                reason: "the client certificate and key must be set together".to_owned(),
+            })
+        }
+    }
+
+    if tls.accept_invalid_certs {
+        warn!("Server certificates not validated, the connections can be intercepted");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+/// Client configured with `options`, cheap to clone.
+pub fn client(
+    options: &HttpOptions,
+    device_id: &str,
+) -> Result<reqwest::Client, DeviceManagerError> {
+    Ok(builder(options, device_id)?.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    use crate::http::{builder, client, user_agent, with_tls, HttpOptions, TlsOptions};
+
+    #[test]
+    fn user_agent_hides_the_device_id() {
+        let agent = user_agent("device-1");
+
+        assert!(agent.starts_with(&format!(
+            "edgehog-device-runtime/{} (device ",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(!agent.contains("device-1"));
+        assert_eq!(agent, user_agent("device-1"));
+        assert_ne!(agent, user_agent("device-2"));
+    }
+
+    #[test]
+    fn invalid_header_rejected() {
+        let options = HttpOptions {
+            headers: BTreeMap::from([("X-Fleet Id".to_owned(), "fleet".to_owned())]),
+            ..Default::default()
+        };
+
+        assert!(client(&options, "device-1").is_err());
+    }
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+
+    /// Self-signed certificate of `localhost` and its key.
+    fn self_signed() -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let alt_name = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&certificate.x509v3_context(None, None))
+            .unwrap();
+        certificate.append_extension(alt_name).unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (certificate.build(), key)
+    }
+
+    /// HTTPS server answering `ok` to every request, requiring a certificate signed by `client`
+    /// when set. Returns its port.
+    fn tls_server(certificate: X509, key: PKey<Private>, client: Option<X509>) -> u16 {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&certificate).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        if let Some(client) = client {
+            acceptor.cert_store_mut().add_cert(client).unwrap();
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                // the handshakes refused by the client or the server are dropped
+                let mut stream = match acceptor.accept(stream.unwrap()) {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                stream.write_all(RESPONSE).ok();
+                stream.shutdown().ok();
+            }
+        });
+
+        port
+    }
+
+    async fn get(tls: &TlsOptions, port: u16) -> Result<String, reqwest::Error> {
+        let client = with_tls(builder(&HttpOptions::default(), "device-1").unwrap(), tls)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        client
+            .get(format!("https://localhost:{port}/"))
+            .send()
+            .await?
+            .text()
+            .await
+    }
+
+    /// Write `pem` to `name` in `dir`, returning its path.
+    fn write_pem(dir: &Path, name: &str, pem: Vec<u8>) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn private_ca_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (certificate, key) = self_signed();
+        let ca = write_pem(dir.path(), "ca.pem", certificate.to_pem().unwrap());
+        let port = tls_server(certificate, key, None);
+
+        assert!(get(&TlsOptions::default(), port).await.is_err());
+        let trusted = TlsOptions {
+            ca_certificate: Some(ca),
+            ..Default::default()
+        };
+        assert_eq!(get(&trusted, port).await.unwrap(), "ok");
+        let unchecked = TlsOptions {
+            accept_invalid_certs: true,
+            ..Default::default()
+        };
+        assert_eq!(get(&unchecked, port).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn client_certificate_presented() {
+        let dir = tempfile::tempdir().unwrap();
+        let (certificate, key) = self_signed();
+        let (client_certificate, client_key) = self_signed();
+        let ca = write_pem(dir.path(), "ca.pem", certificate.to_pem().unwrap());
+        let client_pem = write_pem(
+            dir.path(),
+            "client.pem",
+            client_certificate.to_pem().unwrap(),
+        );
+        // a traditional EC key, converted to PKCS#8
+        let client_key_pem = write_pem(
+            dir.path(),
+            "client.key",
+            client_key.ec_key().unwrap().private_key_to_pem().unwrap(),
+        );
+        let port = tls_server(certificate, key, Some(client_certificate));
+
+        let anonymous = TlsOptions {
+            ca_certificate: Some(ca.clone()),
+            ..Default::default()
+        };
+        assert!(get(&anonymous, port).await.is_err());
+        let identified = TlsOptions {
+            ca_certificate: Some(ca),
+            client_certificate: Some(client_pem),
+            client_key: Some(client_key_pem),
+            accept_invalid_certs: false,
+        };
+        assert_eq!(get(&identified, port).await.unwrap(), "ok");
+    }
+
+    #[test]
+    fn invalid_tls_files_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let garbage = write_pem(dir.path(), "garbage.pem", b"not a certificate".to_vec());
+        let (certificate, _) = self_signed();
+        let certificate_pem =
+            write_pem(dir.path(), "certificate.pem", certificate.to_pem().unwrap());
+
+        let cases = [
+            TlsOptions {
+                ca_certificate: Some("/missing/ca.pem".to_owned()),
+                ..Default::default()
+            },
+            TlsOptions {
+                ca_certificate: Some(garbage.clone()),
+                ..Default::default()
+            },
+            TlsOptions {
+                client_certificate: Some(certificate_pem.clone()),
+                client_key: Some(garbage),
+                ..Default::default()
+            },
+            // the key is missing
+            TlsOptions {
+                client_certificate: Some(certificate_pem),
+                ..Default::default()
+            },
+        ];
+        for tls in cases {
+            let builder = builder(&HttpOptions::default(), "device-1").unwrap();
+            assert!(with_tls(builder, &tls).is_err(), "{tls:?}");
+        }
+    }
+}
@@ -0,0 +1,260 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Local HTTP endpoint for the health checks of a supervisor and the Prometheus scrapes.
+//!
+//! - `GET /health` answers 200 while connected to Astarte, 503 otherwise;
+//! - `GET /metrics` returns the [`Metrics`] counters.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::warn;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::error::DeviceManagerError;
+use crate::metrics::Metrics;
+use crate::runtime_api::{ConnectionState, RuntimeState};
+
+/// Address the endpoint listens on, unless configured otherwise.
+pub const DEFAULT_HTTP_STATUS_ADDRESS: &str = "127.0.0.1";
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Address set by `http_status_address` and `http_status_port`, `None` when disabled.
+pub(crate) fn listen_address(
+    address: Option<&str>,
+    port: Option<u16>,
+) -> Result<Option<SocketAddr>, DeviceManagerError> {
+    let port = match port {
+        Some(port) => port,
+        None => return Ok(None),
+    };
+    let address = address.unwrap_or(DEFAULT_HTTP_STATUS_ADDRESS);
+    let ip: IpAddr = address
+        .parse()
+        .map_err(|_| DeviceManagerError::InvalidOption {
+            option: "http_status_address".to_owned(),
+            reason: format!("{address} is not an IP address"),
+        })?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Status, content type and body of the response to a request.
+pub(crate) fn handle_request(
+    method: &Method,
+    path: &str,
+    state: &RuntimeState,
+    metrics: &Metrics,
+) -> (StatusCode, &'static str, String) {
+    match (method, path) {
+        (&Method::GET, "/health") => {
+            let status = match state.connection {
+                ConnectionState::Connected => StatusCode::OK,
+                _ => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            (status, "text/plain", format!("{}\n", state.connection))
+        }
+        (&Method::GET, "/metrics") => (StatusCode::OK, PROMETHEUS_CONTENT_TYPE, metrics.render()),
+        (_, "/health") | (_, "/metrics") => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "method not allowed\n".to_owned(),
+        ),
+        _ => (
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            "not found\n".to_owned(),
+        ),
+    }
+}
+
+/// The running endpoint, stopped with the runtime.
+pub(crate) struct HttpStatusServer {
+    address: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl HttpStatusServer {
+    /// Serve the endpoint on `address`, the port is picked by the system when 0.
+    pub fn spawn(
+        address: SocketAddr,
+        state: watch::Receiver<RuntimeState>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, hyper::Error> {
+        let make_service = make_service_fn(move |_connection| {
+            let state = state.clone();
+            let metrics = metrics.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let (status, content_type, body) = handle_request(
+                        request.method(),
+                        request.uri().path(),
+                        &state.borrow(),
+                        &metrics,
+                    );
+                    let response = Response::builder()
+                        .status(status)
+                        .header("Content-Type", content_type)
+                        .body(Body::from(body))
+                        .unwrap_or_default();
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let server = Server::try_bind(&address)?.serve(make_service);
+        let address = server.local_addr();
+        let server = server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        });
+
+        let task = tokio::spawn(async move {
+            if let Err(err) = server.await {
+                warn!("Health and metrics endpoint error: {err}");
+            }
+        });
+
+        Ok(HttpStatusServer {
+            address,
+            shutdown,
+            task,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stop accepting connections, the task ends once the open ones are answered.
+    pub fn stop(self) -> JoinHandle<()> {
+        self.shutdown.send(()).ok();
+        self.task
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use hyper::{Method, StatusCode};
+
+    use crate::http_status::{handle_request, listen_address, HttpStatusServer};
+    use crate::metrics::Metrics;
+    use crate::runtime_api::{ConnectionState, RuntimeState, SharedState};
+
+    #[test]
+    fn listen_address_from_options() {
+        assert_eq!(listen_address(None, None).unwrap(), None);
+        assert_eq!(listen_address(Some("0.0.0.0"), None).unwrap(), None);
+        assert_eq!(
+            listen_address(None, Some(9100)).unwrap(),
+            Some(SocketAddr::from(([127, 0, 0, 1], 9100)))
+        );
+        assert_eq!(
+            listen_address(Some("::"), Some(9100)).unwrap(),
+            Some("[::]:9100".parse().unwrap())
+        );
+        assert!(listen_address(Some("localhost"), Some(9100)).is_err());
+    }
+
+    #[test]
+    fn health_follows_connection() {
+        let metrics = Metrics::default();
+        let mut state = RuntimeState::default();
+
+        for (connection, expected) in [
+            (ConnectionState::Connecting, StatusCode::SERVICE_UNAVAILABLE),
+            (ConnectionState::Connected, StatusCode::OK),
+            (
+                ConnectionState::Reconnecting,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                ConnectionState::Disconnected,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+        ] {
+            state.connection = connection;
+            let (status, _, body) = handle_request(&Method::GET, "/health", &state, &metrics);
+            assert_eq!(status, expected, "{connection}");
+            assert_eq!(body, format!("{connection}\n"));
+        }
+    }
+
+    #[test]
+    fn unknown_routes() {
+        let metrics = Metrics::default();
+        let state = RuntimeState::default();
+
+        let (status, _, _) = handle_request(&Method::POST, "/metrics", &state, &metrics);
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let (status, _, _) = handle_request(&Method::GET, "/", &state, &metrics);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn endpoints_served_until_stopped() {
+        let state = SharedState::default();
+        let metrics = Arc::new(Metrics::default());
+        let server = HttpStatusServer::spawn(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            state.subscribe(),
+            metrics.clone(),
+        )
+        .unwrap();
+        let url = |path| format!("http://{}{path}", server.address());
+
+        let health = reqwest::get(url("/health")).await.unwrap();
+        assert_eq!(health.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.set_connection(ConnectionState::Connected);
+        metrics.received("io.edgehog.devicemanager.Commands");
+        metrics.reconnect_attempted();
+
+        let health = reqwest::get(url("/health")).await.unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+        let scraped = reqwest::get(url("/metrics")).await.unwrap();
+        assert_eq!(scraped.status(), StatusCode::OK);
+        assert_eq!(
+            scraped.headers()["Content-Type"],
+            "text/plain; version=0.0.4"
+        );
+        let scraped = scraped.text().await.unwrap();
+        assert!(scraped.contains(
+            "edgehog_messages_received_total{interface=\"io.edgehog.devicemanager.Commands\"} 1\n"
+        ));
+        assert!(scraped.contains("edgehog_reconnect_attempts_total 1\n"));
+
+        let address = server.address();
+        server.stop().await.unwrap();
+        assert!(reqwest::get(format!("http://{address}/health"))
+            .await
+            .is_err());
+    }
+}
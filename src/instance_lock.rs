@@ -0,0 +1,120 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use log::debug;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+
+use crate::error::DeviceManagerError;
+
+const LOCK_FILE_NAME: &str = "edgehog-device-runtime.lock";
+
+/// Exclusive lock held by the running instance on a file in the store directory.
+///
+/// The lock is released when dropped, or by the kernel if the process dies.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    pub fn acquire(store_directory: &str) -> Result<Self, DeviceManagerError> {
+        let path = Path::new(store_directory).join(LOCK_FILE_NAME);
+
+        // the file is not truncated on open, it holds the pid of the running instance
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).ok();
+                let pid = match pid.trim() {
+                    "" => "unknown".to_owned(),
+                    pid => pid.to_owned(),
+                };
+
+                return Err(DeviceManagerError::InstanceLocked(pid));
+            }
+            Err(err) => return Err(DeviceManagerError::IOError(err.into())),
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+
+        debug!("Acquired instance lock {}", path.display());
+
+        Ok(InstanceLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeviceManagerError;
+    use crate::instance_lock::{InstanceLock, LOCK_FILE_NAME};
+
+    #[test]
+    fn second_instance_fails() {
+        let store = tempfile::tempdir().unwrap();
+        let store_directory = store.path().to_str().unwrap();
+
+        let first = InstanceLock::acquire(store_directory).unwrap();
+        let second = InstanceLock::acquire(store_directory);
+
+        match second {
+            Err(DeviceManagerError::InstanceLocked(pid)) => {
+                assert_eq!(pid, std::process::id().to_string())
+            }
+            other => panic!("expected the instance to be locked, got {other:?}"),
+        }
+
+        drop(first);
+        assert!(InstanceLock::acquire(store_directory).is_ok());
+    }
+
+    #[test]
+    fn stale_lock_file_is_reused() {
+        let store = tempfile::tempdir().unwrap();
+        let store_directory = store.path().to_str().unwrap();
+        let lock_path = store.path().join(LOCK_FILE_NAME);
+
+        // a crashed instance leaves the file behind without holding the lock
+        std::fs::write(&lock_path, "123456789").unwrap();
+
+        let _lock = InstanceLock::acquire(store_directory).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(lock_path).unwrap(),
+            std::process::id().to_string()
+        );
+    }
+}
@@ -0,0 +1,323 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Versions of the interfaces loaded from the interfaces directory, published so that the
+//! backend knows which devices are ready for an interface rollout.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::data::validation::{InterfaceIndex, ValidationError};
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::{InterfaceSpec, INTERFACE_VERSIONS_INTERFACE};
+use crate::repository::StateRepository;
+
+/// How often the interfaces directory is checked for changes.
+pub const INTERFACES_CHECK_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// Summary published on `/summary`, the maps keep it stable-ordered for the change detection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceSummary {
+    /// `major.minor` of every loaded interface.
+    pub loaded: BTreeMap<String, String>,
+    /// Interfaces used by the runtime and not loaded.
+    pub missing: Vec<String>,
+    /// Minimum `major.minor` of the interfaces loaded in an older version than the runtime needs.
+    pub outdated: BTreeMap<String, String>,
+    /// Nothing missing nor outdated.
+    pub ready: bool,
+}
+
+impl InterfaceSummary {
+    pub fn new(index: &InterfaceIndex, expected: &[InterfaceSpec]) -> Self {
+        let loaded = index
+            .versions()
+            .into_iter()
+            .map(|(name, (major, minor))| (name, format!("{major}.{minor}")))
+            .collect();
+
+        let mut missing = Vec::new();
+        let mut outdated = BTreeMap::new();
+        for mismatch in index.check_expected(expected) {
+            match mismatch {
+                ValidationError::UnknownInterface(interface) => missing.push(interface),
+                ValidationError::Outdated {
+                    interface,
+                    minimum: (major, minor),
+                    ..
+                } => {
+                    outdated.insert(interface, format!("{major}.{minor}"));
+                }
+                _ => {}
+            }
+        }
+        missing.sort();
+
+        InterfaceSummary {
+            ready: missing.is_empty() && outdated.is_empty(),
+            loaded,
+            missing,
+            outdated,
+        }
+    }
+}
+
+/// Publishes the [`InterfaceSummary`] whenever the loaded interfaces change.
+pub struct InterfaceVersions {
+    clock: Arc<dyn Clock>,
+    directory: PathBuf,
+    expected: &'static [InterfaceSpec],
+    repository: Box<dyn StateRepository<InterfaceSummary>>,
+    published: Mutex<Option<InterfaceSummary>>,
+}
+
+impl InterfaceVersions {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        directory: PathBuf,
+        expected: &'static [InterfaceSpec],
+        repository: Box<dyn StateRepository<InterfaceSummary>>,
+    ) -> Self {
+        let published = repository
+            .exists()
+            .then(|| repository.read())
+            .and_then(|read| {
+                read.map_err(|err| warn!("Unable to read the interface summary: {:?}", err))
+                    .ok()
+            });
+
+        InterfaceVersions {
+            clock,
+            directory,
+            expected,
+            repository,
+            published: Mutex::new(published),
+        }
+    }
+
+    /// Publish the summary of the interfaces directory, unless the last published one is the same.
+    pub async fn sync(&self, publisher: &impl Publisher) -> Result<bool, DeviceManagerError> {
+        let index = InterfaceIndex::load(&self.directory)?;
+        let summary = InterfaceSummary::new(&index, self.expected);
+        if self.summaries(&published).as_ref() == Some(&summary) {
+            return Ok(false);
+        }
+
+        info!(
+            "Loaded interfaces changed, {} interfaces, ready: {}",
+            summary.loaded.len(),
+            summary.ready
+        );
+        self.publish(publisher, summary).await?;
+
+        Ok(true)
+    }
+
+    /// Publish the summary of the interfaces directory, even if it was already published.
+    pub async fn republish(&self, publisher: &impl Publisher) -> Result<(), DeviceManagerError> {
+        let index = InterfaceIndex::load(&self.directory)?;
+
+        self.publish(publisher, InterfaceSummary::new(&index, self.expected))
+            .await
+    }
+
+    async fn publish(
+        &self,
+        publisher: &impl Publisher,
+        summary: InterfaceSummary,
+    ) -> Result<(), DeviceManagerError> {
+        publisher
+            .send(
+                INTERFACE_VERSIONS_INTERFACE,
+                "/summary",
+                AstarteType::String(serde_json::to_string(&summary)?),
+            )
+            .await?;
+
+        if let Err(err) = self.repository.write(&summary) {
+            warn!("Unable to persist the interface summary: {:?}", err);
+        }
+        *self.summaries(&published) = Some(summary);
+
+        Ok(())
+    }
+
+    pub async fn run(&self, publisher: &impl Publisher, period: Duration) {
+        loop {
+            if let Err(err) = self.sync(publisher).await {
+                warn!("Unable to publish the interface summary: {:?}", err);
+            }
+
+            self.clock.sleep(period).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::clock::SystemClock;
+    use crate::data::validation::{Aggregation, Ownership};
+    use crate::interface_versions::{InterfaceSummary, InterfaceVersions};
+    use crate::interfaces::{
+        InterfaceSpec, COMMANDS_INTERFACE, INTERFACE_VERSIONS_INTERFACE, OS_INFO_INTERFACE,
+        RUNTIME_INTERFACES,
+    };
+    use crate::test_utils::{recording_publisher, MemoryStateRepository, Recording};
+
+    fn fixture(name: &str, major: i32, minor: i32, ownership: &str) -> String {
+        format!(
+            r#"{{
+                "interface_name": "{name}",
+                "version_major": {major},
+                "version_minor": {minor},
+                "type": "datastream",
+                "ownership": "{ownership}",
+                "mappings": [{{ "endpoint": "/request", "type": "string" }}]
+            }}"#
+        )
+    }
+
+    fn write_fixture(directory: &Path, name: &str, major: i32, minor: i32, ownership: &str) {
+        std::fs::write(
+            directory.join(format!("{name}.json")),
+            fixture(name, major, minor, ownership),
+        )
+        .unwrap();
+    }
+
+    /// Only the commands interface is expected by the tests.
+    const EXPECTED: &[InterfaceSpec] = &[InterfaceSpec {
+        name: COMMANDS_INTERFACE,
+        minimum_version: (0, 1),
+        ownership: Ownership::Server,
+        aggregation: Aggregation::Individual,
+        paths: &["/request"],
+        required: true,
+    }];
+
+    /// Summaries published on `/summary`.
+    fn summaries(recording: &Recording<()>) -> Vec<InterfaceSummary> {
+        recording
+            .values(INTERFACE_VERSIONS_INTERFACE)
+            .into_iter()
+            .map(|(path, data)| match data {
+                AstarteType::String(summary) if path == "/summary" => {
+                    serde_json::from_str(&summary).unwrap()
+                }
+                _ => panic!("summary is not a string"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn summary_published_on_change() {
+        let directory = tempfile::tempdir().unwrap();
+        write_fixture(directory.path(), COMMANDS_INTERFACE, 0, 0, "server");
+        write_fixture(directory.path(), "com.example.Sensor", 1, 2, "device");
+        let versions = InterfaceVersions::new(
+            Arc::new(SystemClock),
+            directory.path().to_path_buf(),
+            EXPECTED,
+            Box::new(MemoryStateRepository::new()),
+        );
+        let (publisher, published) = recording_publisher();
+
+        assert!(versions.sync(&publisher).await.unwrap());
+        // unchanged directory
+        assert!(!versions.sync(&publisher).await.unwrap());
+
+        // an updated interface is installed
+        write_fixture(directory.path(), COMMANDS_INTERFACE, 0, 1, "server");
+        write_fixture(directory.path(), "com.example.Sensor", 2, 0, "device");
+        assert!(versions.sync(&publisher).await.unwrap());
+
+        assert_eq!(
+            summaries(&published),
+            vec![
+                InterfaceSummary {
+                    loaded: BTreeMap::from([
+                        ("com.example.Sensor".to_owned(), "1.2".to_owned()),
+                        (COMMANDS_INTERFACE.to_owned(), "0.0".to_owned()),
+                    ]),
+                    missing: Vec::new(),
+                    outdated: BTreeMap::from([(COMMANDS_INTERFACE.to_owned(), "0.1".to_owned())]),
+                    ready: false,
+                },
+                InterfaceSummary {
+                    loaded: BTreeMap::from([
+                        ("com.example.Sensor".to_owned(), "2.0".to_owned()),
+                        (COMMANDS_INTERFACE.to_owned(), "0.1".to_owned()),
+                    ]),
+                    missing: Vec::new(),
+                    outdated: BTreeMap::new(),
+                    ready: true,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_interfaces_listed_and_persisted_summary_not_sent_again() {
+        let directory = tempfile::tempdir().unwrap();
+        write_fixture(directory.path(), OS_INFO_INTERFACE, 0, 1, "device");
+        let repository = Arc::new(MemoryStateRepository::new());
+        let (publisher, published) = recording_publisher();
+
+        let versions = InterfaceVersions::new(
+            Arc::new(SystemClock),
+            directory.path().to_path_buf(),
+            RUNTIME_INTERFACES,
+            Box::new(repository.clone()),
+        );
+        assert!(versions.sync(&publisher).await.unwrap());
+        let summary = summaries(&published)[0].clone();
+        assert!(!summary.ready);
+        assert!(summary.missing.contains(&COMMANDS_INTERFACE.to_owned()));
+        assert!(!summary.missing.contains(&OS_INFO_INTERFACE.to_owned()));
+        let mut sorted = summary.missing.clone();
+        sorted.sort();
+        assert_eq!(summary.missing, sorted);
+
+        // after a restart with the same interfaces
+        let restarted = InterfaceVersions::new(
+            Arc::new(SystemClock),
+            directory.path().to_path_buf(),
+            RUNTIME_INTERFACES,
+            Box::new(repository),
+        );
+        assert!(!restarted.sync(&publisher).await.unwrap());
+        assert_eq!(summaries(&published).len(), 1);
+    }
+}
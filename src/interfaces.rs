@@ -0,0 +1,613 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! The Astarte interfaces used by the runtime.
+//!
+//! Every module refers to the interfaces through these constants, and [`RUNTIME_INTERFACES`]
+//! lists what the code expects of each one. The interface definitions are installed separately,
+//! so the table is checked at startup against the interfaces directory: the runtime doesn't start
+//! without its required interfaces, the other mismatches are logged. Building with
+//! `EDGEHOG_INTERFACES_DIR` set to the definitions to install fails when one of them is missing or
+//! older than the table expects.
+
+use crate::data::validation::{Aggregation, Ownership};
+
+pub const SYSTEM_STATUS_INTERFACE: &str = "io.edgehog.devicemanager.SystemStatus";
+pub const SYSTEM_LOAD_INTERFACE: &str = "io.edgehog.devicemanager.SystemLoad";
+pub const OS_INFO_INTERFACE: &str = "io.edgehog.devicemanager.OSInfo";
+pub const HARDWARE_INFO_INTERFACE: &str = "io.edgehog.devicemanager.HardwareInfo";
+pub const RUNTIME_INFO_INTERFACE: &str = "io.edgehog.devicemanager.RuntimeInfo";
+pub const BASE_IMAGE_INTERFACE: &str = "io.edgehog.devicemanager.BaseImage";
+pub const SYSTEM_INFO_INTERFACE: &str = "io.edgehog.devicemanager.SystemInfo";
+pub const CONNECTION_STATS_INTERFACE: &str = "io.edgehog.devicemanager.ConnectionStats";
+pub const CELLULAR_PROPERTIES_INTERFACE: &str =
+    "io.edgehog.devicemanager.CellularConnectionProperties";
+pub const CELLULAR_STATUS_INTERFACE: &str = "io.edgehog.devicemanager.CellularConnectionStatus";
+pub const GEOLOCATION_INTERFACE: &str = "io.edgehog.devicemanager.Geolocation";
+pub const NETWORK_INTERFACES_INTERFACE: &str =
+    "io.edgehog.devicemanager.NetworkInterfaceProperties";
+pub const NETWORK_SOCKETS_INTERFACE: &str = "io.edgehog.devicemanager.NetworkSockets";
+pub const STORAGE_USAGE_INTERFACE: &str = "io.edgehog.devicemanager.StorageUsage";
+pub const WIFI_SCAN_RESULTS_INTERFACE: &str = "io.edgehog.devicemanager.WiFiScanResults";
+pub const SENSORS_TEMPERATURE_INTERFACE: &str = "io.edgehog.devicemanager.SensorsTemperature";
+pub const TAGS_INTERFACE: &str = "io.edgehog.devicemanager.Tags";
+pub const LIFECYCLE_INTERFACE: &str = "io.edgehog.devicemanager.LifecycleEvents";
+pub const DIAGNOSTICS_INTERFACE: &str = "io.edgehog.devicemanager.RuntimeDiagnostics";
+pub const COMMANDS_INTERFACE: &str = "io.edgehog.devicemanager.Commands";
+pub const COMMAND_RESULT_INTERFACE: &str = "io.edgehog.devicemanager.CommandResult";
+pub const LED_BEHAVIOR_INTERFACE: &str = "io.edgehog.devicemanager.LedBehavior";
+pub const FORWARDER_SESSION_REQUEST_INTERFACE: &str =
+    "io.edgehog.devicemanager.ForwarderSessionRequest";
+pub const OTA_REQUEST_INTERFACE: &str = "io.edgehog.devicemanager.OTARequest";
+pub const OTA_RESPONSE_INTERFACE: &str = "io.edgehog.devicemanager.OTAResponse";
+pub const OTA_EVENT_INTERFACE: &str = "io.edgehog.devicemanager.OTAEvent";
+pub const INTERFACE_VERSIONS_INTERFACE: &str = "io.edgehog.devicemanager.InterfaceVersions";
+pub const TELEMETRY_CONFIG_INTERFACE: &str = "io.edgehog.devicemanager.config.Telemetry";
+pub const MUTE_CONFIG_INTERFACE: &str = "io.edgehog.devicemanager.config.Mute";
+// opt-in, not part of the runtime interfaces
+pub const CRASH_REPORT_INTERFACE: &str = "io.edgehog.devicemanager.CrashReport";
+pub const CRASH_UPLOAD_REQUEST_INTERFACE: &str =
+    "io.edgehog.devicemanager.CrashReportUploadRequest";
+pub const BENCHMARK_INTERFACE: &str = "io.edgehog.devicemanager.Benchmark";
+pub const KERNEL_EVENTS_INTERFACE: &str = "io.edgehog.devicemanager.KernelEvents";
+pub const FILE_INTEGRITY_INTERFACE: &str = "io.edgehog.devicemanager.FileIntegrity";
+pub const QUIET_HOURS_INTERFACE: &str = "io.edgehog.devicemanager.QuietHours";
+pub const QUIET_HOURS_CONFIG_INTERFACE: &str = "io.edgehog.devicemanager.config.QuietHours";
+pub const APP_CONFIG_INTERFACE: &str = "io.edgehog.devicemanager.apps.AppConfig";
+pub const APP_CONFIG_REQUEST_INTERFACE: &str = "io.edgehog.devicemanager.apps.AppConfigRequest";
+pub const DISPLAY_INFO_INTERFACE: &str = "io.edgehog.devicemanager.config.DisplayInfo";
+pub const DEPLOYMENT_REQUEST_INTERFACE: &str =
+    "io.edgehog.devicemanager.containers.DeploymentRequest";
+pub const DEPLOYMENT_EVENT_INTERFACE: &str = "io.edgehog.devicemanager.containers.DeploymentEvent";
+pub const CONTAINER_REQUEST_INTERFACE: &str =
+    "io.edgehog.devicemanager.containers.ContainerRequest";
+pub const CONTAINER_STATUS_INTERFACE: &str = "io.edgehog.devicemanager.containers.ContainerStatus";
+
+/// The opt-in interfaces, used only when their feature is enabled.
+pub const OPT_IN_INTERFACES: &[&str] = &[
+    CRASH_REPORT_INTERFACE,
+    CRASH_UPLOAD_REQUEST_INTERFACE,
+    BENCHMARK_INTERFACE,
+    KERNEL_EVENTS_INTERFACE,
+    FILE_INTEGRITY_INTERFACE,
+    QUIET_HOURS_INTERFACE,
+    QUIET_HOURS_CONFIG_INTERFACE,
+    APP_CONFIG_INTERFACE,
+    APP_CONFIG_REQUEST_INTERFACE,
+    DISPLAY_INFO_INTERFACE,
+    DEPLOYMENT_REQUEST_INTERFACE,
+    DEPLOYMENT_EVENT_INTERFACE,
+    CONTAINER_REQUEST_INTERFACE,
+    CONTAINER_STATUS_INTERFACE,
+];
+
+/// What the runtime expects of an interface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterfaceSpec {
+    pub name: &'static str,
+    /// Lowest `(major, minor)` version with the mappings below.
+    pub minimum_version: (i32, i32),
+    pub ownership: Ownership,
+    pub aggregation: Aggregation,
+    /// Paths the runtime sends or receives on, the common path for the object interfaces.
+    pub paths: &'static [&'static str],
+    /// The runtime doesn't start without it, in the major version of `minimum_version`.
+    pub required: bool,
+}
+
+impl InterfaceSpec {
+    const fn required(self) -> Self {
+        InterfaceSpec {
+            required: true,
+            ..self
+        }
+    }
+
+    const fn since(self, minimum_version: (i32, i32)) -> Self {
+        InterfaceSpec {
+            minimum_version,
+            ..self
+        }
+    }
+}
+
+const fn device(
+    name: &'static str,
+    aggregation: Aggregation,
+    paths: &'static [&'static str],
+) -> InterfaceSpec {
+    InterfaceSpec {
+        name,
+        minimum_version: (0, 1),
+        ownership: Ownership::Device,
+        aggregation,
+        paths,
+        required: false,
+    }
+}
+
+const fn server(
+    name: &'static str,
+    aggregation: Aggregation,
+    paths: &'static [&'static str],
+) -> InterfaceSpec {
+    InterfaceSpec {
+        name,
+        minimum_version: (0, 1),
+        ownership: Ownership::Server,
+        aggregation,
+        paths,
+        required: false,
+    }
+}
+
+/// The interfaces of the runtime, the required ones being those of the telemetry sent by default,
+/// the OTA updates and the commands.
+pub const RUNTIME_INTERFACES: &[InterfaceSpec] = &[
+    device(
+        SYSTEM_STATUS_INTERFACE,
+        Aggregation::Object,
+        &["/systemStatus"],
+    )
+    .required(),
+    device(SYSTEM_LOAD_INTERFACE, Aggregation::Object, &["/systemLoad"]),
+    device(
+        OS_INFO_INTERFACE,
+        Aggregation::Individual,
+        &["/osName", "/osVersion"],
+    )
+    .required(),
+    device(
+        HARDWARE_INFO_INTERFACE,
+        Aggregation::Individual,
+        &[
+            "/cpu/architecture",
+            "/cpu/model",
+            "/cpu/modelName",
+            "/cpu/vendor",
+            "/cpu/coreCount",
+            "/cpu/maxFrequencyHz",
+            "/mem/totalBytes",
+        ],
+    )
+    .required(),
+    device(
+        RUNTIME_INFO_INTERFACE,
+        Aggregation::Individual,
+        &["/name", "/url", "/version", "/environment"],
+    )
+    .required(),
+    device(
+        BASE_IMAGE_INTERFACE,
+        Aggregation::Individual,
+        &["/name", "/version", "/buildId", "/fingerprint"],
+    ),
+    device(
+        SYSTEM_INFO_INTERFACE,
+        Aggregation::Individual,
+        &["/serialNumber", "/partNumber"],
+    ),
+    device(
+        CONNECTION_STATS_INTERFACE,
+        Aggregation::Individual,
+        &[
+            "/metered",
+            "/sendTimeouts",
+            "/queuedOnTimeout",
+            "/reconnects",
+            "/lastReplayMillis",
+            "/replayFailures",
+        ],
+    )
+    .since((0, 2)),
+    device(
+        CELLULAR_PROPERTIES_INTERFACE,
+        Aggregation::Individual,
+        &[
+            "/%{modemId}/imei",
+            "/%{modemId}/manufacturer",
+            "/%{modemId}/model",
+            "/%{modemId}/supportedTechnologies",
+        ],
+    ),
+    device(
+        CELLULAR_STATUS_INTERFACE,
+        Aggregation::Object,
+        &["/%{modemId}"],
+    ),
+    device(GEOLOCATION_INTERFACE, Aggregation::Object, &["/location"]),
+    device(
+        NETWORK_INTERFACES_INTERFACE,
+        Aggregation::Individual,
+        &[
+            "/%{iface}/macAddress",
+            "/%{iface}/technologyType",
+            "/%{iface}/up",
+        ],
+    ),
+    device(
+        NETWORK_SOCKETS_INTERFACE,
+        Aggregation::Object,
+        &["/listening", "/established", "/summary"],
+    ),
+    device(STORAGE_USAGE_INTERFACE, Aggregation::Object, &["/%{label}"]).required(),
+    device(WIFI_SCAN_RESULTS_INTERFACE, Aggregation::Object, &["/ap"]),
+    device(
+        SENSORS_TEMPERATURE_INTERFACE,
+        Aggregation::Object,
+        &["/%{sensor_id}"],
+    ),
+    device(TAGS_INTERFACE, Aggregation::Individual, &["/tags/%{tag}"]),
+    device(LIFECYCLE_INTERFACE, Aggregation::Object, &["/event"]),
+    // the diagnostics paths are built by each module
+    device(DIAGNOSTICS_INTERFACE, Aggregation::Individual, &[]),
+    // the failed responses carry the errorCode and the message since 0.2
+    device(OTA_RESPONSE_INTERFACE, Aggregation::Object, &["/response"])
+        .required()
+        .since((0, 2)),
+    device(OTA_EVENT_INTERFACE, Aggregation::Object, &["/event"]).required(),
+    device(COMMAND_RESULT_INTERFACE, Aggregation::Object, &["/result"]),
+    device(
+        INTERFACE_VERSIONS_INTERFACE,
+        Aggregation::Individual,
+        &["/summary"],
+    ),
+    server(COMMANDS_INTERFACE, Aggregation::Individual, &["/request"]).required(),
+    server(OTA_REQUEST_INTERFACE, Aggregation::Object, &["/request"]).required(),
+    server(
+        FORWARDER_SESSION_REQUEST_INTERFACE,
+        Aggregation::Object,
+        &["/request"],
+    ),
+    server(
+        LED_BEHAVIOR_INTERFACE,
+        Aggregation::Individual,
+        &["/%{led_id}/behavior"],
+    ),
+    server(
+        TELEMETRY_CONFIG_INTERFACE,
+        Aggregation::Individual,
+        &[
+            "/request/%{interface_name}/enable",
+            "/request/%{interface_name}/periodSeconds",
+            "/request/%{interface_name}/initialDelaySeconds",
+            "/request/%{interface_name}/jitterPercent",
+        ],
+    )
+    .required(),
+    server(
+        MUTE_CONFIG_INTERFACE,
+        Aggregation::Individual,
+        &["/request/%{interface_name}/muted"],
+    ),
+];
+
+/// Whether the runtime uses `interface_name`, when the features it belongs to are enabled.
+pub fn is_known(interface_name: &str) -> bool {
+    RUNTIME_INTERFACES
+        .iter()
+        .any(|spec| spec.name == interface_name)
+        || OPT_IN_INTERFACES.contains(&interface_name)
+}
+
+/// Name of the first interface of `expected` missing from `installed`, or installed in an older
+/// version than its minimum one.
+#[cfg_attr(not(edgehog_interfaces), allow(dead_code))]
+const fn first_unavailable(
+    expected: &[InterfaceSpec],
+    installed: &[(&str, (i32, i32))],
+) -> Option<&'static str> {
+    let mut i = 0;
+    while i < expected.len() {
+        let spec = &expected[i];
+        let (major, minor) = spec.minimum_version;
+
+        let mut available = false;
+        let mut j = 0;
+        while j < installed.len() {
+            let (name, (installed_major, installed_minor)) = installed[j];
+            if same_name(name, spec.name) && installed_major == major && installed_minor >= minor {
+                available = true;
+            }
+            j += 1;
+        }
+
+        if !available {
+            return Some(spec.name);
+        }
+        i += 1;
+    }
+
+    None
+}
+
+#[cfg_attr(not(edgehog_interfaces), allow(dead_code))]
+const fn same_name(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+
+    true
+}
+
+#[cfg(edgehog_interfaces)]
+mod installed {
+    include!(concat!(env!("OUT_DIR"), "/installed_interfaces.rs"));
+}
+
+#[cfg(edgehog_interfaces)]
+const _: () = {
+    if let Some(name) = first_unavailable(RUNTIME_INTERFACES, installed::INSTALLED_INTERFACES) {
+        panic!("{}", name);
+    }
+};
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use crate::data::validation::{InterfaceIndex, ValidationError};
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::{
+        first_unavailable, is_known, COMMANDS_INTERFACE, CRASH_REPORT_INTERFACE, OS_INFO_INTERFACE,
+        OTA_REQUEST_INTERFACE, OTA_RESPONSE_INTERFACE, RUNTIME_INTERFACES, SYSTEM_STATUS_INTERFACE,
+        TAGS_INTERFACE,
+    };
+    use crate::ota::ota_handler::{OTAError, OTAStatus};
+
+    const SYSTEM_STATUS: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.SystemStatus",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "datastream",
+        "ownership": "device",
+        "aggregation": "object",
+        "mappings": [
+            { "endpoint": "/systemStatus/availMemoryBytes", "type": "longinteger" },
+            { "endpoint": "/systemStatus/bootId", "type": "string" }
+        ]
+    }"#;
+
+    const COMMANDS: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.Commands",
+        "version_major": 0,
+        "version_minor": 0,
+        "type": "datastream",
+        "ownership": "server",
+        "mappings": [
+            { "endpoint": "/request", "type": "string" }
+        ]
+    }"#;
+
+    const OTA_RESPONSE: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.OTAResponse",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "datastream",
+        "ownership": "server",
+        "aggregation": "object",
+        "mappings": [
+            { "endpoint": "/result/uuid", "type": "string" }
+        ]
+    }"#;
+
+    fn index(fixtures: &[&str]) -> InterfaceIndex {
+        let directory = tempfile::tempdir().unwrap();
+        for (i, fixture) in fixtures.iter().enumerate() {
+            std::fs::write(directory.path().join(format!("{i}.json")), fixture).unwrap();
+        }
+
+        InterfaceIndex::load(directory.path()).unwrap()
+    }
+
+    #[test]
+    fn runtime_interfaces_listed_once() {
+        let names: HashSet<&str> = RUNTIME_INTERFACES.iter().map(|spec| spec.name).collect();
+
+        assert_eq!(names.len(), RUNTIME_INTERFACES.len());
+        assert!(RUNTIME_INTERFACES.iter().all(|spec| {
+            spec.name.starts_with("io.edgehog.devicemanager.")
+                && spec.paths.iter().all(|path| path.starts_with('/'))
+        }));
+    }
+
+    #[test]
+    fn missing_and_outdated_interfaces_found() {
+        let installed: Vec<(&str, (i32, i32))> = RUNTIME_INTERFACES
+            .iter()
+            .map(|spec| (spec.name, spec.minimum_version))
+            .collect();
+        assert_eq!(first_unavailable(RUNTIME_INTERFACES, &installed), None);
+
+        let missing: Vec<(&str, (i32, i32))> = installed
+            .iter()
+            .copied()
+            .filter(|(name, _)| *name != TAGS_INTERFACE)
+            .collect();
+        assert_eq!(
+            first_unavailable(RUNTIME_INTERFACES, &missing),
+            Some(TAGS_INTERFACE)
+        );
+
+        let outdated: Vec<(&str, (i32, i32))> = installed
+            .iter()
+            .map(|&(name, version)| match name {
+                COMMANDS_INTERFACE => (name, (0, 0)),
+                _ => (name, version),
+            })
+            .collect();
+        assert_eq!(
+            first_unavailable(RUNTIME_INTERFACES, &outdated),
+            Some(COMMANDS_INTERFACE)
+        );
+    }
+
+    #[test]
+    fn fixtures_checked_against_runtime_interfaces() {
+        let index = index(&[SYSTEM_STATUS, COMMANDS, OTA_RESPONSE]);
+
+        let mismatches = index.check_expected(RUNTIME_INTERFACES);
+
+        assert!(!mismatches
+            .iter()
+            .any(|mismatch| mismatch.to_string().contains(SYSTEM_STATUS_INTERFACE)));
+        assert!(mismatches.contains(&ValidationError::Outdated {
+            interface: COMMANDS_INTERFACE.to_owned(),
+            version: (0, 0),
+            minimum: (0, 1),
+        }));
+        assert!(mismatches.contains(&ValidationError::WrongOwnership(
+            OTA_RESPONSE_INTERFACE.to_owned()
+        )));
+        assert!(mismatches.contains(&ValidationError::UnknownPath {
+            interface: OTA_RESPONSE_INTERFACE.to_owned(),
+            path: "/response".to_owned(),
+        }));
+        assert!(mismatches.contains(&ValidationError::UnknownInterface(
+            TAGS_INTERFACE.to_owned()
+        )));
+    }
+
+    /// Copy of the fixture directory holding the required interfaces, to break it.
+    fn required_interfaces() -> tempfile::TempDir {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/interfaces");
+        let directory = tempfile::tempdir().unwrap();
+        for entry in std::fs::read_dir(fixtures).unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::copy(&path, directory.path().join(path.file_name().unwrap())).unwrap();
+        }
+
+        directory
+    }
+
+    fn interface_file(directory: &Path, interface_name: &str) -> std::path::PathBuf {
+        directory.join(format!("{interface_name}.json"))
+    }
+
+    #[test]
+    fn required_interfaces_accepted() {
+        let directory = required_interfaces();
+
+        let index = InterfaceIndex::load(directory.path()).unwrap();
+
+        assert!(index.check_required(RUNTIME_INTERFACES).is_empty());
+        let required: Vec<&str> = RUNTIME_INTERFACES
+            .iter()
+            .filter(|spec| spec.required)
+            .map(|spec| spec.name)
+            .collect();
+        // only the interfaces left out of the fixtures differ
+        for mismatch in index.check_expected(RUNTIME_INTERFACES) {
+            match mismatch {
+                ValidationError::UnknownInterface(name) => {
+                    assert!(!required.contains(&name.as_str()), "{name} missing")
+                }
+                other => panic!("fixture mismatch: {other}"),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_required_interface_reported() {
+        let directory = required_interfaces();
+        std::fs::remove_file(interface_file(directory.path(), OTA_REQUEST_INTERFACE)).unwrap();
+
+        let index = InterfaceIndex::load(directory.path()).unwrap();
+
+        assert_eq!(
+            index.check_required(RUNTIME_INTERFACES),
+            vec![format!("{OTA_REQUEST_INTERFACE} v0 is missing")]
+        );
+    }
+
+    #[test]
+    fn wrong_major_version_reported() {
+        let directory = required_interfaces();
+        let path = interface_file(directory.path(), OS_INFO_INTERFACE);
+        let interface = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("\"version_major\": 0", "\"version_major\": 1");
+        std::fs::write(&path, interface).unwrap();
+
+        let index = InterfaceIndex::load(directory.path()).unwrap();
+
+        assert_eq!(
+            index.check_required(RUNTIME_INTERFACES),
+            vec![format!("{OS_INFO_INTERFACE} is v1, v0 expected")]
+        );
+        assert!(index
+            .check_expected(RUNTIME_INTERFACES)
+            .contains(&ValidationError::WrongMajor {
+                interface: OS_INFO_INTERFACE.to_owned(),
+                version: (1, 1),
+                major: 0,
+            }));
+    }
+
+    #[test]
+    fn malformed_interface_named() {
+        let directory = required_interfaces();
+        let path = interface_file(directory.path(), COMMANDS_INTERFACE);
+        let interface = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &interface[..interface.len() / 2]).unwrap();
+
+        match InterfaceIndex::load(directory.path()) {
+            Err(DeviceManagerError::MalformedData {
+                path: malformed, ..
+            }) => {
+                assert_eq!(Path::new(&malformed), path)
+            }
+            other => panic!("malformed interface loaded: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn failed_ota_response_valid() {
+        let directory = required_interfaces();
+        let index = InterfaceIndex::load(directory.path()).unwrap();
+
+        for status in [OTAStatus::Done, OTAStatus::Error(OTAError::Network)] {
+            let response = status.to_response(Uuid::new_v4());
+            assert_eq!(
+                index.validate_object(OTA_RESPONSE_INTERFACE, "/response", &response),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn opt_in_interfaces_known() {
+        assert!(is_known(OTA_REQUEST_INTERFACE));
+        assert!(is_known(CRASH_REPORT_INTERFACE));
+        assert!(!is_known("com.example.Unknown"));
+    }
+}
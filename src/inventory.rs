@@ -0,0 +1,320 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Refresh of the static inventory of the device on request.
+//!
+//! The `inventory:refresh` command collects every static interface again and publishes it in one
+//! go, e.g. after a board was replaced or flashed again. The collectors run concurrently and a
+//! failing one doesn't stop the others: the acknowledgement tells which interfaces were sent.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::event_log::EventLog;
+use crate::interface_versions::InterfaceVersions;
+use crate::interfaces::{DIAGNOSTICS_INTERFACE, INTERFACE_VERSIONS_INTERFACE};
+
+pub const INVENTORY_COMMAND: &str = "inventory:refresh";
+/// Acknowledgement of a refresh requested too early.
+pub const RATE_LIMITED_CODE: &str = "RateLimited";
+/// Shortest time between two refreshes.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Collects the values of an interface by path.
+pub type Collector =
+    Arc<dyn Fn() -> Result<HashMap<String, AstarteType>, DeviceManagerError> + Send + Sync>;
+
+enum Request {
+    Refresh,
+    RateLimited,
+}
+
+pub struct Inventory {
+    clock: Arc<dyn Clock>,
+    collectors: Vec<(&'static str, Collector)>,
+    interface_versions: Option<Arc<InterfaceVersions>>,
+    event_log: Option<Arc<EventLog>>,
+    last_refresh: Mutex<Option<Instant>>,
+    /// Requests still to serve.
+    requests: Mutex<VecDeque<Request>>,
+    requested: Notify,
+}
+
+impl Inventory {
+    /// Refresh the interfaces collected by `collectors`.
+    pub fn new(clock: Arc<dyn Clock>, collectors: Vec<(&'static str, Collector)>) -> Self {
+        Inventory {
+            clock,
+            collectors,
+            interface_versions: None,
+            event_log: None,
+            last_refresh: Mutex::new(None),
+            requests: Mutex::new(VecDeque::new()),
+            requested: Notify::new(),
+        }
+    }
+
+    /// Publish the summary of `interface_versions` along with the collected interfaces.
+    pub fn with_interface_versions(mut self, interface_versions: Arc<InterfaceVersions>) -> Self {
+        self.interface_versions = Some(interface_versions);
+        self
+    }
+
+    /// Number the acknowledgements in `event_log`, when enabled.
+    pub fn with_event_log(mut self, event_log: Option<Arc<EventLog>>) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    /// Ask [`Inventory::run`] for a refresh, returns whether it isn't rate limited.
+    pub fn request(&self) -> bool {
+        let now = self.clock.now_monotonic();
+        let accepted = {
+            let mut last_refresh = self.last_refresh.lock().unwrap();
+            let accepted =
+                last_refresh.map_or(true, |last| now.duration_since(last) >= REFRESH_INTERVAL);
+            if accepted {
+                *last_refresh = Some(now);
+            }
+            accepted
+        };
+
+        let request = if accepted {
+            Request::Refresh
+        } else {
+            info!("Inventory refreshed less than {REFRESH_INTERVAL:?} ago, rejecting the request");
+            Request::RateLimited
+        };
+        self.requests.lock().unwrap().push_back(request);
+        self.requested.notify_one();
+
+        accepted
+    }
+
+    /// Collect and publish all the interfaces, returns which ones were sent.
+    pub async fn refresh(&self, publisher: &impl Publisher) -> BTreeMap<String, bool> {
+        let collecting: Vec<_> = self
+            .collectors
+            .iter()
+            .map(|(interface_name, collector)| {
+                let collector = collector.clone();
+                (
+                    *interface_name,
+                    tokio::task::spawn_blocking(move || collector()),
+                )
+            })
+            .collect();
+
+        let mut sent = BTreeMap::new();
+        for (interface_name, collected) in collecting {
+            let result = match collected.await {
+                Ok(Ok(fields)) => send_all(publisher, interface_name, fields).await,
+                Ok(Err(err)) => Err(err),
+                Err(err) => Err(DeviceManagerError::TaskFailed {
+                    task: format!("{interface_name} collector"),
+                    reason: err.to_string(),
+                }),
+            };
+            if let Err(err) = &result {
+                warn!("Unable to refresh {interface_name}: {:?}", err);
+            }
+            sent.insert(interface_name.to_owned(), result.is_ok());
+        }
+
+        if let Some(interface_versions) = &self.interface_versions {
+            let result = interface_versions.republish(publisher).await;
+            if let Err(err) = &result {
+                warn!("Unable to refresh the interface summary: {:?}", err);
+            }
+            sent.insert(INTERFACE_VERSIONS_INTERFACE.to_owned(), result.is_ok());
+        }
+
+        sent
+    }
+
+    /// Serve the refresh requests, acknowledging each one on the diagnostics interface.
+    pub async fn run(&self, publisher: &impl Publisher) {
+        loop {
+            self.requested.notified().await;
+
+            let requests: Vec<Request> = self.requests.lock().unwrap().drain(..).collect();
+            for request in requests {
+                let ack = match request {
+                    Request::Refresh => {
+                        let sent = self.refresh(publisher).await;
+                        match serde_json::to_string(&sent) {
+                            Ok(sent) => sent,
+                            Err(err) => {
+                                warn!("Unable to serialize the inventory refresh: {:?}", err);
+                                continue;
+                            }
+                        }
+                    }
+                    Request::RateLimited => RATE_LIMITED_CODE.to_owned(),
+                };
+
+                self.acknowledge(publisher, AstarteType::String(ack)).await;
+            }
+        }
+    }
+
+    async fn acknowledge(&self, publisher: &impl Publisher, data: AstarteType) {
+        let path = format!("/commandAck/{INVENTORY_COMMAND}");
+        if let Some(event_log) = &self.event_log {
+            return event_log.acknowledge(publisher, &path, data).await;
+        }
+
+        if let Err(err) = publisher.send(DIAGNOSTICS_INTERFACE, &path, data).await {
+            warn!("Unable to publish {path}: {:?}", err);
+        }
+    }
+}
+
+async fn send_all(
+    publisher: &impl Publisher,
+    interface_name: &str,
+    fields: HashMap<String, AstarteType>,
+) -> Result<(), DeviceManagerError> {
+    for (path, data) in fields {
+        publisher.send(interface_name, &path, data).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::data::MockPublisher;
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::{
+        DIAGNOSTICS_INTERFACE, HARDWARE_INFO_INTERFACE, OS_INFO_INTERFACE, RUNTIME_INFO_INTERFACE,
+    };
+    use crate::inventory::{Collector, Inventory, RATE_LIMITED_CODE, REFRESH_INTERVAL};
+    use crate::test_utils::{settle, ManualClock};
+
+    /// Collector of a single `/name` field, counting its runs in `runs`.
+    fn collector(runs: &Arc<AtomicUsize>, name: Option<&'static str>) -> Collector {
+        let runs = runs.clone();
+        Arc::new(move || {
+            runs.fetch_add(1, Ordering::SeqCst);
+            match name {
+                Some(name) => Ok(HashMap::from([(
+                    "/name".to_owned(),
+                    AstarteType::String(name.to_owned()),
+                )])),
+                None => Err(DeviceManagerError::IOError(
+                    std::io::ErrorKind::InvalidData.into(),
+                )),
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn all_collectors_run_and_failures_isolated() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let inventory = Inventory::new(
+            Arc::new(ManualClock::new()),
+            vec![
+                (OS_INFO_INTERFACE, collector(&runs, Some("Linux"))),
+                (HARDWARE_INFO_INTERFACE, collector(&runs, None)),
+                (RUNTIME_INFO_INTERFACE, collector(&runs, Some("runtime"))),
+            ],
+        );
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, data| {
+                interface == OS_INFO_INTERFACE
+                    && path == "/name"
+                    && *data == AstarteType::String("Linux".to_owned())
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        publisher
+            .expect_send()
+            .withf(|interface, path, data| {
+                interface == RUNTIME_INFO_INTERFACE
+                    && path == "/name"
+                    && *data == AstarteType::String("runtime".to_owned())
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let sent = inventory.refresh(&publisher).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            sent,
+            BTreeMap::from([
+                (HARDWARE_INFO_INTERFACE.to_owned(), false),
+                (OS_INFO_INTERFACE.to_owned(), true),
+                (RUNTIME_INFO_INTERFACE.to_owned(), true),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn rapid_second_refresh_rate_limited() {
+        let clock = Arc::new(ManualClock::new());
+        let inventory = Arc::new(Inventory::new(clock.clone(), Vec::new()));
+
+        let mut publisher = MockPublisher::new();
+        for (ack, times) in [("{}", 2), (RATE_LIMITED_CODE, 1)] {
+            publisher
+                .expect_send()
+                .withf(move |interface, path, data| {
+                    interface == DIAGNOSTICS_INTERFACE
+                        && path == "/commandAck/inventory:refresh"
+                        && *data == AstarteType::String(ack.to_owned())
+                })
+                .times(times)
+                .returning(|_, _, _| Ok(()));
+        }
+        let running = inventory.clone();
+        let run = tokio::spawn(async move { running.run(&publisher).await });
+
+        assert!(inventory.request());
+        settle().await;
+        clock.advance(REFRESH_INTERVAL / 2);
+        assert!(!inventory.request());
+        settle().await;
+        clock.advance(REFRESH_INTERVAL / 2);
+        assert!(inventory.request());
+        settle().await;
+
+        run.abort();
+    }
+}
@@ -0,0 +1,816 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Kernel events worth reporting: OOM kills, hung tasks, filesystem and I/O errors, thermal
+//! throttling.
+//!
+//! The kernel messages are read from the journal on systemd and from `/dev/kmsg` elsewhere,
+//! starting after the persisted cursor, so that nothing is reported twice across restarts. Each
+//! event type is published at most `max_per_hour` times an hour, the dropped ones are counted in
+//! the next published event of the same type.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::{error, info, warn};
+use nix::sys::time::TimeValLike;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::KERNEL_EVENTS_INTERFACE;
+use crate::repository::file_state_repository::FileStateRepository;
+use crate::repository::StateRepository;
+use crate::wrapper::platform::{platform, PlatformKind};
+
+/// Events of each type published an hour, unless configured otherwise.
+pub const DEFAULT_MAX_PER_HOUR: u32 = 10;
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KernelEventsOptions {
+    #[serde(default)]
+    pub enabled: bool,
+    pub max_per_hour: Option<u32>,
+}
+
+/// Position of the last kernel message read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "camelCase")]
+pub enum KernelCursor {
+    Journal {
+        cursor: String,
+    },
+    /// The sequence numbers of `/dev/kmsg` restart at every boot.
+    Kmsg {
+        boot_id: String,
+        seq: u64,
+    },
+}
+
+/// Message logged by the kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelLine {
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+    pub cursor: KernelCursor,
+}
+
+impl KernelLine {
+    /// Parse an entry of `journalctl --output=json`.
+    pub fn from_journal(fields: &HashMap<String, Value>) -> Option<Self> {
+        let field = |name: &str| fields.get(name).and_then(Value::as_str);
+
+        let micros: i64 = field("__REALTIME_TIMESTAMP")?.parse().ok()?;
+
+        Some(KernelLine {
+            message: field("MESSAGE")?.to_owned(),
+            timestamp: Utc.timestamp_nanos(micros * 1000),
+            cursor: KernelCursor::Journal {
+                cursor: field("__CURSOR")?.to_owned(),
+            },
+        })
+    }
+
+    /// Parse a `/dev/kmsg` record, `priority,sequence,microseconds,flags;message`.
+    pub fn from_kmsg(record: &str, boot_id: &str, boot_time: DateTime<Utc>) -> Option<Self> {
+        let (header, message) = record.split_once(';')?;
+        let mut fields = header.split(',');
+        let _priority = fields.next()?;
+        let seq: u64 = fields.next()?.parse().ok()?;
+        let micros: i64 = fields.next()?.parse().ok()?;
+        // the continuation lines hold the key=value dictionary of the record
+        let message = message.lines().next().unwrap_or_default();
+
+        Some(KernelLine {
+            message: message.to_owned(),
+            timestamp: boot_time + chrono::Duration::microseconds(micros),
+            cursor: KernelCursor::Kmsg {
+                boot_id: boot_id.to_owned(),
+                seq,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelEvent {
+    /// Process killed by the OOM killer, globally or in a memory cgroup.
+    Oom {
+        process: String,
+        pid: i32,
+        /// Resident pages of the victim, freed by the kill.
+        freed_pages: i64,
+    },
+    HungTask {
+        task: String,
+        pid: i32,
+        blocked_secs: i64,
+    },
+    FilesystemError {
+        filesystem: String,
+        device: String,
+        detail: String,
+    },
+    IoError {
+        device: String,
+        detail: String,
+    },
+    ThermalThrottling {
+        detail: String,
+    },
+}
+
+/// Drop the `[ 1234.567890]` timestamp prefixed by dmesg and the `kernel:` syslog tag.
+fn strip_prefixes(message: &str) -> &str {
+    let message = message.trim();
+    let message = match message.strip_prefix('[') {
+        Some(rest) => rest
+            .split_once(']')
+            .map_or(message, |(_, rest)| rest.trim()),
+        None => message,
+    };
+
+    message.strip_prefix("kernel:").unwrap_or(message).trim()
+}
+
+/// Value of the `key:valuekB` fields of the OOM messages, in kB.
+fn kb_field(message: &str, key: &str) -> Option<i64> {
+    message
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .find_map(|field| field.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.trim_end_matches("kB").parse().ok())
+}
+
+/// Parse `<pid> (<name>)`, at the start of `rest`.
+fn pid_and_name(rest: &str) -> Option<(i32, String)> {
+    let (pid, rest) = rest.trim().split_once(' ')?;
+    let name = rest.trim().strip_prefix('(')?.split_once(')')?.0;
+
+    Some((pid.parse().ok()?, name.to_owned()))
+}
+
+/// Device of `... dev <device>, ...` or `... on dev <device>, ...`.
+fn device_after(message: &str, marker: &str) -> Option<String> {
+    let rest = message.split_once(marker)?.1;
+    let device = rest
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .find(|token| !token.is_empty())?;
+
+    Some(device.to_owned())
+}
+
+impl KernelEvent {
+    /// Parse the kernel message, tolerating the wording changes across kernel versions.
+    pub fn parse(message: &str) -> Option<Self> {
+        let message = strip_prefixes(message);
+        let lower = message.to_ascii_lowercase();
+
+        // "Out of memory: Killed process" since 4.19, "Killed process" after the score line before
+        // that; "Kill process ... or sacrifice child" only announces the victim
+        if let Some(rest) = message.split_once("Killed process ").map(|(_, rest)| rest) {
+            let (pid, process) = pid_and_name(rest)?;
+            let rss_kb: i64 = ["anon-rss", "file-rss", "shmem-rss"]
+                .iter()
+                .filter_map(|key| kb_field(rest, key))
+                .sum();
+
+            return Some(KernelEvent::Oom {
+                process,
+                pid,
+                freed_pages: rss_kb / page_kb(),
+            });
+        }
+
+        if lower.contains("blocked for more than") {
+            // "INFO: task <name>:<pid> blocked for more than <n> seconds."
+            let task = message.split_once("task ")?.1.split_whitespace().next()?;
+            let (name, pid) = task.rsplit_once(':')?;
+            let blocked_secs = message
+                .split_once("more than ")?
+                .1
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()?;
+
+            return Some(KernelEvent::HungTask {
+                task: name.to_owned(),
+                pid: pid.parse().ok()?,
+                blocked_secs,
+            });
+        }
+
+        // "EXT4-fs error (device mmcblk0p2): ext4_lookup:1601: ..."
+        if let Some((filesystem, rest)) = message.split_once("-fs error") {
+            let device = rest
+                .split_once("(device ")
+                .and_then(|(_, rest)| rest.split_once(')'))
+                .map(|(device, _)| device.to_owned())
+                .unwrap_or_default();
+            let detail = rest
+                .split_once("):")
+                .map_or(rest, |(_, detail)| detail)
+                .trim();
+
+            return Some(KernelEvent::FilesystemError {
+                filesystem: filesystem.to_ascii_lowercase(),
+                device,
+                detail: detail.to_owned(),
+            });
+        }
+
+        // "I/O error, dev sda, sector 2048 ...", "Buffer I/O error on dev mmcblk0p2, ..."
+        if lower.contains("i/o error") {
+            let device = device_after(message, " dev ")
+                .or_else(|| device_after(message, " device "))
+                .unwrap_or_default();
+
+            return Some(KernelEvent::IoError {
+                device,
+                detail: message.to_owned(),
+            });
+        }
+
+        if (lower.contains("throttl")
+            && (lower.contains("temperature") || lower.contains("thermal")))
+            || lower.contains("critical temperature")
+        {
+            return Some(KernelEvent::ThermalThrottling {
+                detail: message.to_owned(),
+            });
+        }
+
+        None
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KernelEvent::Oom { .. } => "oom",
+            KernelEvent::HungTask { .. } => "hungTask",
+            KernelEvent::FilesystemError { .. } => "filesystemError",
+            KernelEvent::IoError { .. } => "ioError",
+            KernelEvent::ThermalThrottling { .. } => "thermalThrottling",
+        }
+    }
+}
+
+fn page_kb() -> i64 {
+    procfs::page_size().map_or(4, |bytes| (bytes / 1024).max(1))
+}
+
+/// Published on `/event`, the fields not relevant to the kind are empty.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KernelEventReport {
+    pub kind: String,
+    pub timestamp: DateTime<Utc>,
+    /// Victim of the OOM killer or hung task.
+    pub process: String,
+    pub pid: i32,
+    pub freed_pages: i64,
+    pub blocked_secs: i64,
+    pub filesystem: String,
+    pub device: String,
+    pub detail: String,
+    /// Events of the same kind dropped by the rate cap since the previous one.
+    pub suppressed: i32,
+}
+
+impl KernelEventReport {
+    pub fn new(event: KernelEvent, timestamp: DateTime<Utc>, suppressed: u32) -> Self {
+        let mut report = KernelEventReport {
+            kind: event.kind().to_owned(),
+            timestamp,
+            process: String::new(),
+            pid: 0,
+            freed_pages: 0,
+            blocked_secs: 0,
+            filesystem: String::new(),
+            device: String::new(),
+            detail: String::new(),
+            suppressed: suppressed as i32,
+        };
+        match event {
+            KernelEvent::Oom {
+                process,
+                pid,
+                freed_pages,
+            } => {
+                report.process = process;
+                report.pid = pid;
+                report.freed_pages = freed_pages;
+            }
+            KernelEvent::HungTask {
+                task,
+                pid,
+                blocked_secs,
+            } => {
+                report.process = task;
+                report.pid = pid;
+                report.blocked_secs = blocked_secs;
+            }
+            KernelEvent::FilesystemError {
+                filesystem,
+                device,
+                detail,
+            } => {
+                report.filesystem = filesystem;
+                report.device = device;
+                report.detail = detail;
+            }
+            KernelEvent::IoError { device, detail } => {
+                report.device = device;
+                report.detail = detail;
+            }
+            KernelEvent::ThermalThrottling { detail } => report.detail = detail,
+        }
+
+        report
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateWindow {
+    start: Instant,
+    published: u32,
+    suppressed: u32,
+}
+
+pub struct KernelEvents {
+    clock: Arc<dyn Clock>,
+    max_per_hour: u32,
+    repository: Box<dyn StateRepository<KernelCursor>>,
+    windows: Mutex<HashMap<&'static str, RateWindow>>,
+}
+
+impl KernelEvents {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        max_per_hour: u32,
+        repository: Box<dyn StateRepository<KernelCursor>>,
+    ) -> Self {
+        KernelEvents {
+            clock,
+            max_per_hour,
+            repository,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cursor of the last reported event, if any.
+    pub fn cursor(&self) -> Option<KernelCursor> {
+        self.repository
+            .exists()
+            .then(|| self.repository.read())
+            .and_then(|read| {
+                read.map_err(|err| warn!("Unable to read the kernel cursor: {:?}", err))
+                    .ok()
+            })
+    }
+
+    /// Count of the events suppressed before this one, `None` when it goes over the cap.
+    fn admit(&self, kind: &'static str) -> Option<u32> {
+        let now = self.clock.now_monotonic();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(kind).or_insert(RateWindow {
+            start: now,
+            published: 0,
+            suppressed: 0,
+        });
+        if now - window.start >= RATE_WINDOW {
+            window.start = now;
+            window.published = 0;
+        }
+
+        if window.published >= self.max_per_hour {
+            window.suppressed += 1;
+            return None;
+        }
+
+        window.published += 1;
+        Some(std::mem::take(&mut window.suppressed))
+    }
+
+    /// Publish the event of `line`, if it has one and the rate cap allows it.
+    pub async fn handle_line(&self, publisher: &impl Publisher, line: KernelLine) -> bool {
+        let event = match KernelEvent::parse(&line.message) {
+            Some(event) => event,
+            None => return false,
+        };
+        let suppressed = match self.admit(event.kind()) {
+            Some(suppressed) => suppressed,
+            None => return false,
+        };
+
+        // persisted first, a crash may lose an event but never reports it twice
+        if let Err(err) = self.repository.write(&line.cursor) {
+            warn!("Unable to persist the kernel cursor: {:?}", err);
+        }
+
+        info!("Kernel event {}", event.kind());
+        let report = KernelEventReport::new(event, line.timestamp, suppressed);
+        if let Err(err) = publisher
+            .send_object(KERNEL_EVENTS_INTERFACE, "/event", report)
+            .await
+        {
+            warn!("Unable to publish the kernel event: {:?}", err);
+        }
+
+        true
+    }
+
+    pub async fn run(&self, publisher: &impl Publisher, mut lines: mpsc::Receiver<KernelLine>) {
+        while let Some(line) = lines.recv().await {
+            self.handle_line(publisher, line).await;
+        }
+    }
+}
+
+async fn follow_journal(cursor: Option<KernelCursor>, lines: mpsc::Sender<KernelLine>) {
+    let start = match cursor {
+        Some(KernelCursor::Journal { cursor }) => format!("--after-cursor={cursor}"),
+        // the messages logged before the runtime started in this boot are reported too
+        _ => "--boot".to_owned(),
+    };
+    let child = tokio::process::Command::new("journalctl")
+        .args(["--follow", "--output=json", &start, "_TRANSPORT=kernel"])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            error!("Unable to follow the kernel journal entries: {}", err);
+            return;
+        }
+    };
+
+    let stdout = match child.stdout.take() {
+        Some(stdout) => stdout,
+        None => return,
+    };
+    let mut entries = BufReader::new(stdout).lines();
+    while let Ok(Some(entry)) = entries.next_line().await {
+        let line = serde_json::from_str(&entry)
+            .ok()
+            .and_then(|fields| KernelLine::from_journal(&fields));
+
+        match line {
+            Some(line) => {
+                if lines.send(line).await.is_err() {
+                    return;
+                }
+            }
+            None => warn!("Unable to parse the kernel journal entry"),
+        }
+    }
+}
+
+/// Read `/dev/kmsg`, skipping the records up to `cursor` when it is of the current boot.
+fn follow_kmsg(cursor: Option<KernelCursor>, lines: mpsc::Sender<KernelLine>) {
+    let boot_id = std::fs::read_to_string(BOOT_ID_PATH)
+        .map(|boot_id| boot_id.trim().to_owned())
+        .unwrap_or_default();
+    let after = match cursor {
+        Some(KernelCursor::Kmsg { boot_id: last, seq }) if last == boot_id => Some(seq),
+        _ => None,
+    };
+    // the records are timestamped with the monotonic clock
+    let boot_time = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+        .map(|uptime| Utc::now() - chrono::Duration::microseconds(uptime.num_microseconds()))
+        .unwrap_or_else(|_| Utc::now());
+
+    let mut kmsg = match std::fs::File::open("/dev/kmsg") {
+        Ok(kmsg) => kmsg,
+        Err(err) => {
+            error!("Unable to read the kernel messages: {}", err);
+            return;
+        }
+    };
+
+    // every read returns a single record
+    let mut buffer = vec![0; 8192];
+    loop {
+        let read = match kmsg.read(&mut buffer) {
+            Ok(read) => read,
+            // the oldest records were overwritten while reading
+            Err(err) if err.raw_os_error() == Some(nix::libc::EPIPE) => continue,
+            Err(err) => {
+                error!("Unable to read the kernel messages: {}", err);
+                return;
+            }
+        };
+
+        let record = String::from_utf8_lossy(&buffer[..read]);
+        let line = match KernelLine::from_kmsg(&record, &boot_id, boot_time) {
+            Some(line) => line,
+            None => continue,
+        };
+        let skipped = match (&line.cursor, after) {
+            (KernelCursor::Kmsg { seq, .. }, Some(after)) => *seq <= after,
+            _ => false,
+        };
+        if !skipped && lines.blocking_send(line).is_err() {
+            return;
+        }
+    }
+}
+
+/// Start reporting the kernel events, from the journal on systemd.
+pub fn spawn<P>(
+    options: &KernelEventsOptions,
+    store_directory: &Path,
+    clock: Arc<dyn Clock>,
+    publisher: P,
+) -> JoinHandle<()>
+where
+    P: Publisher + 'static,
+{
+    let events = KernelEvents::new(
+        clock,
+        options.max_per_hour.unwrap_or(DEFAULT_MAX_PER_HOUR),
+        Box::new(FileStateRepository::new(
+            store_directory.to_string_lossy().into_owned(),
+            "kernel_events_cursor.json".to_owned(),
+        )),
+    );
+    let cursor = events.cursor();
+    let (lines_tx, lines_rx) = mpsc::channel(32);
+
+    if platform().kind() == PlatformKind::Systemd {
+        tokio::spawn(follow_journal(cursor, lines_tx));
+    } else {
+        tokio::task::spawn_blocking(move || follow_kmsg(cursor, lines_tx));
+    }
+
+    tokio::spawn(async move { events.run(&publisher, lines_rx).await })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::Value;
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::KERNEL_EVENTS_INTERFACE;
+    use crate::kernel_events::{
+        page_kb, KernelCursor, KernelEvent, KernelEventReport, KernelEvents, KernelLine,
+        RATE_WINDOW,
+    };
+    use crate::test_utils::{ManualClock, MemoryStateRepository};
+
+    const OOM_LINES: [&str; 4] = [
+        // 5.x
+        "Out of memory: Killed process 1234 (sensord) total-vm:812340kB, anon-rss:409600kB, \
+         file-rss:2048kB, shmem-rss:0kB, UID:0 pgtables:1024kB oom_score_adj:0",
+        // 4.14, after the "Kill process ... or sacrifice child" line
+        "Killed process 1234 (sensord) total-vm:812340kB, anon-rss:409600kB, file-rss:2048kB, \
+         shmem-rss:0kB",
+        // memory cgroup, read with dmesg
+        "[ 8123.456789] Memory cgroup out of memory: Killed process 1234 (sensord) \
+         total-vm:812340kB, anon-rss:409600kB, file-rss:2048kB, shmem-rss:0kB, UID:1000",
+        // forwarded through syslog, without shmem-rss
+        "kernel: Killed process 1234 (sensord) total-vm:812340kB, anon-rss:409600kB, \
+         file-rss:2048kB",
+    ];
+
+    const EXT4_LINES: [(&str, &str); 3] = [
+        (
+            "EXT4-fs error (device mmcblk0p2): ext4_lookup:1601: inode #1234: comm sensord: \
+             deleted inode referenced: 5678",
+            "mmcblk0p2",
+        ),
+        (
+            "[   42.000001] EXT4-fs error (device sda1) in ext4_reserve_inode_write:5846: \
+             Journal has aborted",
+            "sda1",
+        ),
+        (
+            "EXT4-fs error (device dm-0): ext4_find_entry:1455: inode #2: comm ls: reading \
+             directory lblock 0",
+            "dm-0",
+        ),
+    ];
+
+    #[test]
+    fn oom_kills_parsed() {
+        for line in OOM_LINES {
+            assert_eq!(
+                KernelEvent::parse(line),
+                Some(KernelEvent::Oom {
+                    process: "sensord".to_owned(),
+                    pid: 1234,
+                    freed_pages: (409600 + 2048) / page_kb(),
+                }),
+                "{line}"
+            );
+        }
+
+        // only announces the victim, reported by the following line
+        assert_eq!(
+            KernelEvent::parse(
+                "Out of memory: Kill process 1234 (sensord) score 912 or sacrifice child"
+            ),
+            None
+        );
+        assert_eq!(
+            KernelEvent::parse(
+                "oom_reaper: reaped process 1234 (sensord), now anon-rss:0kB, file-rss:0kB"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn ext4_errors_parsed() {
+        for (line, device) in EXT4_LINES {
+            match KernelEvent::parse(line) {
+                Some(KernelEvent::FilesystemError {
+                    filesystem,
+                    device: parsed,
+                    detail,
+                }) => {
+                    assert_eq!(filesystem, "ext4");
+                    assert_eq!(parsed, device);
+                    assert!(!detail.is_empty());
+                }
+                other => panic!("{line} parsed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn other_events_parsed() {
+        assert_eq!(
+            KernelEvent::parse("INFO: task kworker/0:1:123 blocked for more than 120 seconds."),
+            Some(KernelEvent::HungTask {
+                task: "kworker/0:1".to_owned(),
+                pid: 123,
+                blocked_secs: 120,
+            })
+        );
+
+        for (line, device) in [
+            (
+                "blk_update_request: I/O error, dev mmcblk0, sector 2048 op 0x0:(READ) flags 0x0",
+                "mmcblk0",
+            ),
+            ("I/O error, dev sda, sector 8 op 0x1:(WRITE)", "sda"),
+            (
+                "Buffer I/O error on dev mmcblk0p2, logical block 0, lost async page write",
+                "mmcblk0p2",
+            ),
+        ] {
+            match KernelEvent::parse(line) {
+                Some(KernelEvent::IoError { device: parsed, .. }) => assert_eq!(parsed, device),
+                other => panic!("{line} parsed as {other:?}"),
+            }
+        }
+
+        for line in [
+            "CPU0: Core temperature above threshold, cpu clock throttled (total events = 1)",
+            "thermal thermal_zone0: critical temperature reached (105 C), shutting down",
+        ] {
+            assert_eq!(
+                KernelEvent::parse(line).unwrap().kind(),
+                "thermalThrottling"
+            );
+        }
+
+        assert_eq!(
+            KernelEvent::parse("usb 1-1: new high-speed USB device number 2"),
+            None
+        );
+    }
+
+    #[test]
+    fn lines_parsed_from_both_sources() {
+        let fields: HashMap<String, Value> = serde_json::from_str(
+            r#"{
+                "__CURSOR": "s=abc;i=42",
+                "__REALTIME_TIMESTAMP": "1650000000000000",
+                "_TRANSPORT": "kernel",
+                "MESSAGE": "EXT4-fs error (device sda1): ext4_lookup:1601: inode #12"
+            }"#,
+        )
+        .unwrap();
+        let line = KernelLine::from_journal(&fields).unwrap();
+        assert_eq!(
+            line.cursor,
+            KernelCursor::Journal {
+                cursor: "s=abc;i=42".to_owned()
+            }
+        );
+        assert_eq!(line.timestamp, Utc.timestamp(1_650_000_000, 0));
+
+        let boot_time = Utc.timestamp(1_650_000_000, 0);
+        let line = KernelLine::from_kmsg(
+            "3,812,5000000,-;Out of memory: Killed process 1 (init)\n SUBSYSTEM=mm\n",
+            "boot",
+            boot_time,
+        )
+        .unwrap();
+        assert_eq!(line.message, "Out of memory: Killed process 1 (init)");
+        assert_eq!(line.timestamp, Utc.timestamp(1_650_000_005, 0));
+        assert_eq!(
+            line.cursor,
+            KernelCursor::Kmsg {
+                boot_id: "boot".to_owned(),
+                seq: 812
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn events_rate_capped_and_cursor_persisted() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let events = KernelEvents::new(clock.clone(), 2, Box::new(repository.clone()));
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let recorded = published.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object()
+            .withf(|interface: &str, path: &str, _: &KernelEventReport| {
+                interface == KERNEL_EVENTS_INTERFACE && path == "/event"
+            })
+            .returning(move |_, _, report: KernelEventReport| {
+                recorded.lock().unwrap().push(report);
+                Ok(())
+            });
+
+        let line = |seq: u64| KernelLine {
+            message: OOM_LINES[0].to_owned(),
+            timestamp: Utc.timestamp(1_650_000_000, 0),
+            cursor: KernelCursor::Kmsg {
+                boot_id: "boot".to_owned(),
+                seq,
+            },
+        };
+        for seq in 1..=4 {
+            events.handle_line(&publisher, line(seq)).await;
+        }
+        assert!(
+            !events
+                .handle_line(
+                    &publisher,
+                    KernelLine {
+                        message: "eth0: link up".to_owned(),
+                        ..line(5)
+                    }
+                )
+                .await
+        );
+        assert_eq!(published.lock().unwrap().len(), 2);
+        // the cursor only moves with the reported events
+        assert_eq!(
+            repository.value(),
+            Some(KernelCursor::Kmsg {
+                boot_id: "boot".to_owned(),
+                seq: 2
+            })
+        );
+
+        clock.advance(RATE_WINDOW + Duration::from_secs(1));
+        assert!(events.handle_line(&publisher, line(6)).await);
+        let published = published.lock().unwrap();
+        assert_eq!(published[0].suppressed, 0);
+        assert_eq!(published[2].suppressed, 2);
+        assert_eq!(published[2].process, "sensord");
+        assert_eq!(events.cursor(), repository.value());
+    }
+}
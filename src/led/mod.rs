@@ -0,0 +1,511 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Status LED shared between roles with priorities.
+//!
+//! Every role plays a declarative on/off pattern. The highest priority role claiming the LED
+//! drives it, the others are queued and resume once it is released; finite patterns, like the
+//! cloud identify request, release the role when they complete. Without any claim the trigger
+//! found at startup is restored.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::error::DeviceManagerError;
+use crate::led::sysfs::{LedDevice, SysfsLed};
+
+#[cfg(feature = "dbus")]
+pub(crate) mod service;
+pub(crate) mod sysfs;
+
+/// How long an empty pattern waits before checking its repetitions.
+const IDLE_STEP: Duration = Duration::from_secs(60);
+
+/// Role claimed by the cloud identify command.
+pub const IDENTIFY_ROLE: &str = "identify";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Step {
+    pub on: bool,
+    pub millis: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Pattern {
+    pub steps: Vec<Step>,
+    /// Times the steps are played, forever if unset.
+    pub repeat: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LedRole {
+    pub priority: u32,
+    pub pattern: Pattern,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LedOptions {
+    /// Sysfs directory of the LED, like `/sys/class/leds/status`.
+    pub path: String,
+    #[serde(default)]
+    pub roles: HashMap<String, LedRole>,
+}
+
+impl LedOptions {
+    /// The configured roles, with a default for the identify role.
+    pub fn roles(&self) -> HashMap<String, LedRole> {
+        let mut roles = self.roles.clone();
+        roles
+            .entry(IDENTIFY_ROLE.to_owned())
+            .or_insert_with(|| LedRole {
+                priority: 100,
+                pattern: Pattern {
+                    steps: vec![
+                        Step {
+                            on: true,
+                            millis: 500,
+                        },
+                        Step {
+                            on: false,
+                            millis: 500,
+                        },
+                    ],
+                    repeat: Some(30),
+                },
+            });
+
+        roles
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LedRequest {
+    Claim(String),
+    /// Only sent by the D-Bus API.
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    Release(String),
+}
+
+/// Start driving the LED configured in `options`, until every request sender is dropped.
+pub fn spawn(
+    options: &LedOptions,
+    clock: Arc<dyn Clock>,
+) -> Result<(mpsc::Sender<LedRequest>, JoinHandle<()>), DeviceManagerError> {
+    let manager = LedManager::new(
+        Box::new(SysfsLed::new(&options.path)),
+        clock,
+        options.roles(),
+    )?;
+    let (requests_tx, requests_rx) = mpsc::channel(16);
+
+    Ok((requests_tx, tokio::spawn(manager.run(requests_rx))))
+}
+
+/// Position in the pattern of the role driving the LED.
+struct Playback {
+    role: String,
+    step: usize,
+    iteration: u32,
+    deadline: Instant,
+}
+
+pub struct LedManager {
+    device: Box<dyn LedDevice>,
+    clock: Arc<dyn Clock>,
+    roles: HashMap<String, LedRole>,
+    /// Claimed roles with the order of their claim, ties in priority go to the first claim.
+    claims: HashMap<String, u64>,
+    next_claim: u64,
+    initial_trigger: String,
+    max_brightness: u32,
+}
+
+impl LedManager {
+    pub fn new(
+        device: Box<dyn LedDevice>,
+        clock: Arc<dyn Clock>,
+        roles: HashMap<String, LedRole>,
+    ) -> Result<Self, DeviceManagerError> {
+        let initial_trigger = device.trigger()?;
+        let max_brightness = device.max_brightness()?.max(1);
+
+        Ok(LedManager {
+            device,
+            clock,
+            roles,
+            claims: HashMap::new(),
+            next_claim: 0,
+            initial_trigger,
+            max_brightness,
+        })
+    }
+
+    /// Drive the LED following the `requests` until the channel is closed.
+    pub async fn run(mut self, mut requests: mpsc::Receiver<LedRequest>) {
+        let mut playback: Option<Playback> = None;
+
+        loop {
+            let active = self.active_role();
+            if playback.as_ref().map(|playback| &playback.role) != active.as_ref() {
+                playback = self.transition(playback.map(|playback| playback.role), active);
+            }
+
+            let request = match playback.as_mut() {
+                None => requests.recv().await,
+                Some(current) => tokio::select! {
+                    request = requests.recv() => request,
+                    _ = self.clock.sleep_until(current.deadline) => {
+                        if !self.advance(current) {
+                            info!("LED pattern of role {} completed", current.role);
+                            self.claims.remove(&current.role);
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            match request {
+                Some(LedRequest::Claim(role)) => {
+                    if !self.roles.contains_key(&role) {
+                        warn!("Ignoring claim of unknown LED role {role}");
+                        continue;
+                    }
+                    info!("LED role {role} claimed");
+                    if let Some(current) = playback.as_mut().filter(|current| current.role == role)
+                    {
+                        // claiming the playing role again restarts its pattern
+                        self.start(current);
+                    } else if !self.claims.contains_key(&role) {
+                        self.claims.insert(role, self.next_claim);
+                        self.next_claim += 1;
+                    }
+                }
+                Some(LedRequest::Release(role)) => {
+                    if self.claims.remove(&role).is_some() {
+                        info!("LED role {role} released");
+                    }
+                }
+                None => break,
+            }
+        }
+
+        if let Some(playback) = playback {
+            self.transition(Some(playback.role), None);
+        }
+    }
+
+    fn active_role(&self) -> Option<String> {
+        self.claims
+            .iter()
+            .max_by_key(|(role, claim)| (self.roles[*role].priority, std::cmp::Reverse(**claim)))
+            .map(|(role, _)| role.clone())
+    }
+
+    fn transition(&self, from: Option<String>, to: Option<String>) -> Option<Playback> {
+        match (&from, &to) {
+            (Some(from), Some(to)) => {
+                let queued = if self.claims.contains_key(from) {
+                    ", queued"
+                } else {
+                    ""
+                };
+                info!("LED switched from role {from}{queued} to role {to}");
+            }
+            (None, Some(to)) => info!("LED taken by role {to}"),
+            (Some(from), None) => info!(
+                "LED released by role {from}, restoring trigger {}",
+                self.initial_trigger
+            ),
+            (None, None) => {}
+        }
+
+        let role = match to {
+            Some(role) => role,
+            None => {
+                self.log_error(self.device.set_trigger(&self.initial_trigger));
+                return None;
+            }
+        };
+
+        if from.is_none() {
+            self.log_error(self.device.set_trigger("none"));
+        }
+
+        let mut playback = Playback {
+            role,
+            step: 0,
+            iteration: 0,
+            deadline: self.clock.now_monotonic(),
+        };
+        self.start(&mut playback);
+
+        Some(playback)
+    }
+
+    fn start(&self, playback: &mut Playback) {
+        playback.step = 0;
+        playback.iteration = 0;
+        playback.deadline = self.clock.now_monotonic();
+        self.play(playback);
+    }
+
+    /// Move to the next step, returns false once the pattern completed.
+    fn advance(&self, playback: &mut Playback) -> bool {
+        let pattern = &self.roles[&playback.role].pattern;
+
+        playback.step += 1;
+        if playback.step >= pattern.steps.len() {
+            playback.step = 0;
+            playback.iteration += 1;
+            if pattern
+                .repeat
+                .map_or(false, |repeat| playback.iteration >= repeat)
+            {
+                return false;
+            }
+        }
+
+        self.play(playback);
+        true
+    }
+
+    fn play(&self, playback: &mut Playback) {
+        let pattern = &self.roles[&playback.role].pattern;
+
+        match pattern.steps.get(playback.step) {
+            Some(step) => {
+                let brightness = if step.on { self.max_brightness } else { 0 };
+                self.log_error(self.device.set_brightness(brightness));
+                playback.deadline += Duration::from_millis(step.millis.max(1));
+            }
+            // an empty pattern keeps the LED off
+            None => {
+                self.log_error(self.device.set_brightness(0));
+                playback.deadline = self.clock.now_monotonic() + IDLE_STEP;
+            }
+        }
+    }
+
+    fn log_error(&self, result: Result<(), DeviceManagerError>) {
+        if let Err(err) = result {
+            warn!("Unable to drive the LED: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use crate::error::DeviceManagerError;
+    use crate::led::sysfs::LedDevice;
+    use crate::led::{LedManager, LedRequest, LedRole, Pattern, Step, IDENTIFY_ROLE};
+    use crate::test_utils::{settle, ManualClock};
+
+    /// Sysfs LED recording the attributes written.
+    struct FakeLed {
+        writes: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl LedDevice for FakeLed {
+        fn trigger(&self) -> Result<String, DeviceManagerError> {
+            Ok("heartbeat".to_owned())
+        }
+
+        fn set_trigger(&self, trigger: &str) -> Result<(), DeviceManagerError> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push(format!("trigger={trigger}"));
+            Ok(())
+        }
+
+        fn max_brightness(&self) -> Result<u32, DeviceManagerError> {
+            Ok(255)
+        }
+
+        fn set_brightness(&self, brightness: u32) -> Result<(), DeviceManagerError> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push(format!("brightness={brightness}"));
+            Ok(())
+        }
+    }
+
+    fn blink(millis: u64, repeat: Option<u32>) -> Pattern {
+        Pattern {
+            steps: vec![Step { on: true, millis }, Step { on: false, millis }],
+            repeat,
+        }
+    }
+
+    struct Harness {
+        clock: Arc<ManualClock>,
+        writes: Arc<Mutex<Vec<String>>>,
+        requests: mpsc::Sender<LedRequest>,
+        handle: tokio::task::JoinHandle<()>,
+    }
+
+    impl Harness {
+        fn start() -> Self {
+            let roles = HashMap::from([
+                (
+                    "application".to_owned(),
+                    LedRole {
+                        priority: 10,
+                        pattern: blink(1000, None),
+                    },
+                ),
+                (
+                    IDENTIFY_ROLE.to_owned(),
+                    LedRole {
+                        priority: 100,
+                        pattern: blink(100, Some(2)),
+                    },
+                ),
+            ]);
+
+            let clock = Arc::new(ManualClock::new());
+            let writes = Arc::new(Mutex::new(Vec::new()));
+            let manager = LedManager::new(
+                Box::new(FakeLed {
+                    writes: writes.clone(),
+                }),
+                clock.clone(),
+                roles,
+            )
+            .unwrap();
+            let (requests, requests_rx) = mpsc::channel(16);
+            let handle = tokio::spawn(manager.run(requests_rx));
+
+            Harness {
+                clock,
+                writes,
+                requests,
+                handle,
+            }
+        }
+
+        async fn send(&self, request: LedRequest) {
+            self.requests.send(request).await.unwrap();
+            settle().await;
+        }
+
+        async fn advance(&self, millis: u64) {
+            self.clock.advance(Duration::from_millis(millis));
+            settle().await;
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.writes.lock().unwrap())
+        }
+    }
+
+    fn claim(role: &str) -> LedRequest {
+        LedRequest::Claim(role.to_owned())
+    }
+
+    fn release(role: &str) -> LedRequest {
+        LedRequest::Release(role.to_owned())
+    }
+
+    #[tokio::test]
+    async fn identify_overrides_and_restores_application() {
+        let led = Harness::start();
+
+        led.send(claim("application")).await;
+        led.advance(1000).await;
+        assert_eq!(
+            led.take(),
+            ["trigger=none", "brightness=255", "brightness=0"]
+        );
+
+        led.send(claim(IDENTIFY_ROLE)).await;
+        for _ in 0..4 {
+            led.advance(100).await;
+        }
+        // two identify blinks, then the application pattern starts over
+        assert_eq!(
+            led.take(),
+            [
+                "brightness=255",
+                "brightness=0",
+                "brightness=255",
+                "brightness=0",
+                "brightness=255",
+            ]
+        );
+
+        led.advance(1000).await;
+        led.send(release("application")).await;
+        assert_eq!(led.take(), ["brightness=0", "trigger=heartbeat"]);
+
+        led.handle.abort();
+    }
+
+    #[tokio::test]
+    async fn lower_priority_claims_are_queued() {
+        let led = Harness::start();
+
+        led.send(claim(IDENTIFY_ROLE)).await;
+        led.send(claim("application")).await;
+        led.send(claim("unknown")).await;
+        assert_eq!(led.take(), ["trigger=none", "brightness=255"]);
+
+        // the identify request is released before completing
+        led.send(release(IDENTIFY_ROLE)).await;
+        assert_eq!(led.take(), ["brightness=255"]);
+
+        led.advance(1000).await;
+        assert_eq!(led.take(), ["brightness=0"]);
+
+        // claiming the playing role again restarts its pattern
+        led.advance(500).await;
+        led.send(claim("application")).await;
+        led.advance(999).await;
+        assert_eq!(led.take(), ["brightness=255"]);
+        led.advance(1).await;
+        assert_eq!(led.take(), ["brightness=0"]);
+
+        // closing the requests hands the LED back to its trigger
+        let Harness {
+            writes,
+            requests,
+            handle,
+            ..
+        } = led;
+        drop(requests);
+        handle.await.unwrap();
+        assert_eq!(*writes.lock().unwrap(), ["trigger=heartbeat"]);
+    }
+}
@@ -0,0 +1,79 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! D-Bus API letting local applications claim the LED roles.
+
+use std::collections::HashSet;
+
+use log::info;
+use tokio::sync::mpsc;
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder};
+
+use crate::error::DeviceManagerError;
+use crate::led::LedRequest;
+
+pub const LED_SERVICE_NAME: &str = "io.edgehog.Led";
+const LED_SERVICE_PATH: &str = "/io/edgehog/Led";
+
+struct LedService {
+    roles: HashSet<String>,
+    requests: mpsc::Sender<LedRequest>,
+}
+
+impl LedService {
+    async fn request(&self, request: LedRequest, role: &str) -> fdo::Result<()> {
+        if !self.roles.contains(role) {
+            return Err(fdo::Error::InvalidArgs(format!("unknown LED role {role}")));
+        }
+
+        self.requests
+            .send(request)
+            .await
+            .map_err(|_| fdo::Error::Failed("LED manager stopped".to_owned()))
+    }
+}
+
+#[dbus_interface(name = "io.edgehog.Led1")]
+impl LedService {
+    /// Claim `role`, the LED plays its pattern unless a higher priority role holds it.
+    async fn claim(&self, role: String) -> fdo::Result<()> {
+        self.request(LedRequest::Claim(role.clone()), &role).await
+    }
+
+    /// Release `role`, handing the LED to the next queued role.
+    async fn release(&self, role: String) -> fdo::Result<()> {
+        self.request(LedRequest::Release(role.clone()), &role).await
+    }
+}
+
+/// Serve the LED API on the system bus, until the returned connection is dropped.
+pub async fn serve(
+    roles: HashSet<String>,
+    requests: mpsc::Sender<LedRequest>,
+) -> Result<Connection, DeviceManagerError> {
+    let connection = ConnectionBuilder::system()?
+        .name(LED_SERVICE_NAME)?
+        .serve_at(LED_SERVICE_PATH, LedService { roles, requests })?
+        .build()
+        .await?;
+    info!("LED API available as {LED_SERVICE_NAME}");
+
+    Ok(connection)
+}
@@ -0,0 +1,113 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! LEDs exposed by the kernel under `/sys/class/leds`.
+
+use std::path::PathBuf;
+
+use crate::error::DeviceManagerError;
+
+pub trait LedDevice: Send + Sync {
+    /// The kernel trigger currently driving the LED.
+    fn trigger(&self) -> Result<String, DeviceManagerError>;
+    fn set_trigger(&self, trigger: &str) -> Result<(), DeviceManagerError>;
+    fn max_brightness(&self) -> Result<u32, DeviceManagerError>;
+    fn set_brightness(&self, brightness: u32) -> Result<(), DeviceManagerError>;
+}
+
+pub struct SysfsLed {
+    path: PathBuf,
+}
+
+impl SysfsLed {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SysfsLed { path: path.into() }
+    }
+
+    fn read(&self, attribute: &str) -> Result<String, DeviceManagerError> {
+        Ok(std::fs::read_to_string(self.path.join(attribute))?)
+    }
+
+    fn write(&self, attribute: &str, value: &str) -> Result<(), DeviceManagerError> {
+        Ok(std::fs::write(self.path.join(attribute), value)?)
+    }
+}
+
+impl LedDevice for SysfsLed {
+    fn trigger(&self) -> Result<String, DeviceManagerError> {
+        // every available trigger is listed, the selected one is in brackets
+        let triggers = self.read("trigger")?;
+        let selected = triggers
+            .split_whitespace()
+            .find_map(|trigger| trigger.strip_prefix('[')?.strip_suffix(']'))
+            .unwrap_or("none");
+
+        Ok(selected.to_owned())
+    }
+
+    fn set_trigger(&self, trigger: &str) -> Result<(), DeviceManagerError> {
+        self.write("trigger", trigger)
+    }
+
+    fn max_brightness(&self) -> Result<u32, DeviceManagerError> {
+        self.read("max_brightness")?
+            .trim()
+            .parse()
+            .map_err(|_| DeviceManagerError::MalformedData {
+                path: self.path.join("max_brightness").display().to_string(),
+                reason: "not a number".to_owned(),
+            })
+    }
+
+    fn set_brightness(&self, brightness: u32) -> Result<(), DeviceManagerError> {
+        self.write("brightness", &brightness.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::led::sysfs::{LedDevice, SysfsLed};
+
+    #[test]
+    fn sysfs_attributes() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(
+            directory.path().join("trigger"),
+            "none timer [heartbeat] mmc0\n",
+        )
+        .unwrap();
+        std::fs::write(directory.path().join("max_brightness"), "255\n").unwrap();
+
+        let led = SysfsLed::new(directory.path());
+        assert_eq!(led.trigger().unwrap(), "heartbeat");
+        assert_eq!(led.max_brightness().unwrap(), 255);
+
+        led.set_trigger("none").unwrap();
+        led.set_brightness(255).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(directory.path().join("trigger")).unwrap(),
+            "none"
+        );
+        assert_eq!(
+            std::fs::read_to_string(directory.path().join("brightness")).unwrap(),
+            "255"
+        );
+    }
+}
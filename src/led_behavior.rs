@@ -0,0 +1,486 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Blinking of any LED of the device on request of `io.edgehog.devicemanager.LedBehavior`, to
+//! find it among identical ones.
+//!
+//! A behavior is played for a bounded time, then the trigger the LED had before is restored. A new
+//! behavior for the same LED replaces the running one, `Off` stops it right away.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::clock::Clock;
+use crate::commands::{CommandRequest, CommandResult};
+use crate::error::DeviceManagerError;
+use crate::led::sysfs::{LedDevice, SysfsLed};
+use crate::led::Step;
+
+pub const LEDS_DIRECTORY: &str = "/sys/class/leds";
+pub const DEFAULT_LED_BEHAVIOR_DURATION: Duration = Duration::from_secs(60);
+/// Name of the behaviors in their results.
+const LED_BEHAVIOR_COMMAND: &str = "LedBehavior";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    Blink,
+    DoubleBlink,
+    SlowBlink,
+    Off,
+}
+
+impl Behavior {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Blink" => Some(Behavior::Blink),
+            "DoubleBlink" => Some(Behavior::DoubleBlink),
+            "SlowBlink" => Some(Behavior::SlowBlink),
+            "Off" => Some(Behavior::Off),
+            _ => None,
+        }
+    }
+
+    /// The steps played in a loop, none for `Off`.
+    fn steps(self) -> &'static [Step] {
+        match self {
+            Behavior::Blink => BLINK,
+            Behavior::DoubleBlink => DOUBLE_BLINK,
+            Behavior::SlowBlink => SLOW_BLINK,
+            Behavior::Off => &[],
+        }
+    }
+}
+
+const fn step(on: bool, millis: u64) -> Step {
+    Step { on, millis }
+}
+
+const BLINK: &[Step] = &[step(true, 500), step(false, 500)];
+const DOUBLE_BLINK: &[Step] = &[
+    step(true, 200),
+    step(false, 200),
+    step(true, 200),
+    step(false, 1400),
+];
+const SLOW_BLINK: &[Step] = &[step(true, 2000), step(false, 2000)];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedBehaviorRequest {
+    pub led: String,
+    pub behavior: Behavior,
+}
+
+impl LedBehaviorRequest {
+    /// The request set on `/<led_id>/behavior`.
+    pub fn from_property(path: &[&str], value: &AstarteType) -> Option<Self> {
+        match (path, value) {
+            ([led, "behavior"], AstarteType::String(behavior)) => Some(LedBehaviorRequest {
+                led: (*led).to_owned(),
+                behavior: Behavior::from_name(behavior)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The LEDs of the device, by id.
+pub trait Leds: Send + Sync {
+    fn led(&self, id: &str) -> Option<Arc<dyn LedDevice>>;
+}
+
+/// LEDs exposed by the kernel, under [`LEDS_DIRECTORY`].
+pub struct SysfsLeds {
+    directory: PathBuf,
+}
+
+impl SysfsLeds {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        SysfsLeds {
+            directory: directory.into(),
+        }
+    }
+}
+
+impl Leds for SysfsLeds {
+    fn led(&self, id: &str) -> Option<Arc<dyn LedDevice>> {
+        // the id is received, it can only name an entry of the directory
+        if id.is_empty() || id.starts_with('.') || id.contains('/') {
+            return None;
+        }
+
+        let path = self.directory.join(id);
+        if !path.join("brightness").exists() {
+            return None;
+        }
+
+        Some(Arc::new(SysfsLed::new(path)))
+    }
+}
+
+/// Behavior playing on a LED.
+struct Running {
+    /// Trigger found before the first behavior, restored by the last one.
+    trigger: String,
+    handle: JoinHandle<()>,
+}
+
+pub struct LedBehaviors {
+    clock: Arc<dyn Clock>,
+    leds: Box<dyn Leds>,
+    duration: Duration,
+    results: Option<mpsc::Sender<CommandResult>>,
+    running: HashMap<String, Running>,
+}
+
+impl LedBehaviors {
+    pub fn new(clock: Arc<dyn Clock>, leds: Box<dyn Leds>, duration: Duration) -> Self {
+        LedBehaviors {
+            clock,
+            leds,
+            duration,
+            results: None,
+            running: HashMap::new(),
+        }
+    }
+
+    /// Acknowledge the result of each behavior on `results`.
+    pub fn with_results(mut self, results: mpsc::Sender<CommandResult>) -> Self {
+        self.results = Some(results);
+        self
+    }
+
+    /// Play the requested behaviors until `requests` is closed.
+    pub async fn run(mut self, mut requests: mpsc::Receiver<LedBehaviorRequest>) {
+        while let Some(request) = requests.recv().await {
+            let result = self.handle(&request).await;
+            if let Err(err) = &result {
+                warn!("LED behavior {:?} failed: {err}", request.behavior);
+            }
+
+            if let Some(results) = &self.results {
+                let command = CommandRequest {
+                    command: LED_BEHAVIOR_COMMAND.to_owned(),
+                    request_id: None,
+                };
+                results
+                    .send(CommandResult::new(&command, &result))
+                    .await
+                    .unwrap_or_else(|_| warn!("The command results stopped"));
+            }
+        }
+
+        for (_, running) in self.running.drain() {
+            running.handle.abort();
+        }
+    }
+
+    async fn handle(&mut self, request: &LedBehaviorRequest) -> Result<(), DeviceManagerError> {
+        let led = self
+            .leds
+            .led(&request.led)
+            .ok_or_else(|| DeviceManagerError::UnknownLed(request.led.clone()))?;
+
+        // the replaced behavior is stopped before restoring its trigger
+        let trigger = match self.running.remove(&request.led) {
+            Some(running) if !running.handle.is_finished() => {
+                running.handle.abort();
+                let _ = running.handle.await;
+                running.trigger
+            }
+            _ => led.trigger()?,
+        };
+
+        if request.behavior == Behavior::Off {
+            info!("LED {} off, restoring trigger {trigger}", request.led);
+            return led.set_trigger(&trigger);
+        }
+
+        info!("LED {} playing {:?}", request.led, request.behavior);
+        let handle = tokio::spawn(play(
+            self.clock.clone(),
+            led,
+            request.behavior,
+            self.duration,
+            trigger.clone(),
+        ));
+        self.running
+            .insert(request.led.clone(), Running { trigger, handle });
+
+        Ok(())
+    }
+}
+
+/// Loop over the steps of `behavior` for `duration`, then restore `trigger`.
+async fn play(
+    clock: Arc<dyn Clock>,
+    led: Arc<dyn LedDevice>,
+    behavior: Behavior,
+    duration: Duration,
+    trigger: String,
+) {
+    let max_brightness = led.max_brightness().unwrap_or(1).max(1);
+    log_error(led.set_trigger("none"));
+
+    let end = clock.now_monotonic() + duration;
+    let mut deadline = clock.now_monotonic();
+    for step in behavior.steps().iter().cycle() {
+        if deadline >= end {
+            break;
+        }
+
+        let brightness = if step.on { max_brightness } else { 0 };
+        log_error(led.set_brightness(brightness));
+        deadline = (deadline + Duration::from_millis(step.millis)).min(end);
+        clock.sleep_until(deadline).await;
+    }
+
+    log_error(led.set_trigger(&trigger));
+}
+
+fn log_error(result: Result<(), DeviceManagerError>) {
+    if let Err(err) = result {
+        warn!("Unable to drive the LED: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+
+    use crate::commands::CommandResult;
+    use crate::error::DeviceManagerError;
+    use crate::led::sysfs::LedDevice;
+    use crate::led_behavior::{
+        Behavior, LedBehaviorRequest, LedBehaviors, Leds, SysfsLeds, DEFAULT_LED_BEHAVIOR_DURATION,
+    };
+    use crate::test_utils::{settle, ManualClock};
+
+    /// LED recording the attributes written.
+    struct FakeLed {
+        writes: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl LedDevice for FakeLed {
+        fn trigger(&self) -> Result<String, DeviceManagerError> {
+            Ok("heartbeat".to_owned())
+        }
+
+        fn set_trigger(&self, trigger: &str) -> Result<(), DeviceManagerError> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push(format!("trigger={trigger}"));
+            Ok(())
+        }
+
+        fn max_brightness(&self) -> Result<u32, DeviceManagerError> {
+            Ok(1)
+        }
+
+        fn set_brightness(&self, brightness: u32) -> Result<(), DeviceManagerError> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push(format!("brightness={brightness}"));
+            Ok(())
+        }
+    }
+
+    struct FakeLeds {
+        status: Arc<FakeLed>,
+    }
+
+    impl Leds for FakeLeds {
+        fn led(&self, id: &str) -> Option<Arc<dyn LedDevice>> {
+            (id == "status").then(|| self.status.clone() as Arc<dyn LedDevice>)
+        }
+    }
+
+    struct Harness {
+        clock: Arc<ManualClock>,
+        writes: Arc<Mutex<Vec<String>>>,
+        requests: mpsc::Sender<LedBehaviorRequest>,
+        results: mpsc::Receiver<CommandResult>,
+    }
+
+    impl Harness {
+        fn start() -> Self {
+            let clock = Arc::new(ManualClock::new());
+            let writes = Arc::new(Mutex::new(Vec::new()));
+            let leds = FakeLeds {
+                status: Arc::new(FakeLed {
+                    writes: writes.clone(),
+                }),
+            };
+            let (results_tx, results) = mpsc::channel(4);
+            let behaviors =
+                LedBehaviors::new(clock.clone(), Box::new(leds), Duration::from_secs(2))
+                    .with_results(results_tx);
+            let (requests, requests_rx) = mpsc::channel(4);
+            tokio::spawn(behaviors.run(requests_rx));
+
+            Harness {
+                clock,
+                writes,
+                requests,
+                results,
+            }
+        }
+
+        async fn send(&mut self, led: &str, behavior: Behavior) -> CommandResult {
+            let request = LedBehaviorRequest {
+                led: led.to_owned(),
+                behavior,
+            };
+            self.requests.send(request).await.unwrap();
+            settle().await;
+            self.results.try_recv().unwrap()
+        }
+
+        async fn advance(&self, millis: u64) {
+            self.clock.advance(Duration::from_millis(millis));
+            settle().await;
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.writes.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn behavior_parsed() {
+        let behavior = |value: &str| {
+            LedBehaviorRequest::from_property(
+                &["status", "behavior"],
+                &AstarteType::String(value.to_owned()),
+            )
+        };
+
+        assert_eq!(
+            behavior("DoubleBlink"),
+            Some(LedBehaviorRequest {
+                led: "status".to_owned(),
+                behavior: Behavior::DoubleBlink,
+            })
+        );
+        assert_eq!(behavior("Blink60Seconds"), None);
+        assert_eq!(
+            LedBehaviorRequest::from_property(&["status"], &AstarteType::String("Off".to_owned())),
+            None
+        );
+        assert_eq!(DEFAULT_LED_BEHAVIOR_DURATION, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn sysfs_leds_found() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::create_dir(directory.path().join("green:status")).unwrap();
+        std::fs::write(directory.path().join("green:status/brightness"), "0\n").unwrap();
+
+        let leds = SysfsLeds::new(directory.path());
+        assert!(leds.led("green:status").is_some());
+        assert!(leds.led("red:status").is_none());
+        assert!(leds.led("..").is_none());
+        assert!(leds.led("green:status/..").is_none());
+    }
+
+    #[tokio::test]
+    async fn blink_bounded_then_trigger_restored() {
+        let mut led = Harness::start();
+
+        assert!(led.send("status", Behavior::Blink).await.success);
+        for _ in 0..4 {
+            led.advance(500).await;
+        }
+
+        assert_eq!(
+            led.take(),
+            [
+                "trigger=none",
+                "brightness=1",
+                "brightness=0",
+                "brightness=1",
+                "brightness=0",
+                "trigger=heartbeat",
+            ]
+        );
+        led.advance(500).await;
+        assert!(led.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_behavior_replaces_running_one() {
+        let mut led = Harness::start();
+
+        led.send("status", Behavior::Blink).await;
+        led.advance(500).await;
+        led.send("status", Behavior::SlowBlink).await;
+        // the trigger of the first behavior is restored
+        led.advance(2000).await;
+        assert_eq!(
+            led.take(),
+            [
+                "trigger=none",
+                "brightness=1",
+                "brightness=0",
+                "trigger=none",
+                "brightness=1",
+                "trigger=heartbeat",
+            ]
+        );
+
+        led.send("status", Behavior::DoubleBlink).await;
+        led.advance(200).await;
+        assert!(led.send("status", Behavior::Off).await.success);
+        led.advance(2000).await;
+        assert_eq!(
+            led.take(),
+            [
+                "trigger=none",
+                "brightness=1",
+                "brightness=0",
+                "trigger=heartbeat",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_led_rejected() {
+        let mut led = Harness::start();
+
+        let result = led.send("wifi", Behavior::Blink).await;
+        assert!(!result.success);
+        assert_eq!(result.command, "LedBehavior");
+        assert_eq!(result.error, "unknown LED wifi");
+        assert!(led.take().is_empty());
+    }
+}
@@ -19,350 +19,3308 @@
  */
 
 use crate::repository::file_state_repository::FileStateRepository;
-use crate::repository::StateRepository;
 use astarte_sdk::builder::AstarteOptions;
-use astarte_sdk::types::AstarteType;
-use astarte_sdk::{registration, Aggregation, AstarteSdk};
-use device::DeviceProxy;
+use astarte_sdk::Clientbound;
+use chrono::Utc;
 use error::DeviceManagerError;
 use log::{debug, info, warn};
 use serde::Deserialize;
 use std::collections::HashMap;
-use tokio::sync::mpsc::Sender;
+#[cfg(feature = "dbus")]
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
 
+use crate::app_config::AppConfigOptions;
 use crate::astarte::Astarte;
+use crate::audit::{AuditReport, StateAudit};
+use crate::benchmark::Benchmark;
+use crate::capabilities::{AuditTarget, CapabilityReport, Feature, SystemProbe};
+use crate::clock::{Clock, SystemClock};
+use crate::commands::CommandRunner;
+use crate::containers::ContainersOptions;
+use crate::crash_reports::{CrashReportOptions, HttpUploader};
+use crate::custom_commands::{CustomCommandOptions, CustomCommands};
 use crate::data::astarte;
+use crate::data::connection::{AstarteConnection, AstarteLibrary};
+use crate::data::deadline::DeadlinePublisher;
+use crate::data::message_hub::AstarteMessageHubNode;
+use crate::data::mute::{InterfaceMutes, MuteEvent, MuteMode};
+use crate::data::reconnect::{self, ReconnectBackoff};
+use crate::data::send_stats::SendStats;
+use crate::data::store_forward::{StoreForward, StoreForwardOptions};
+use crate::data::validation::{InterfaceIndex, PayloadValidator};
+use crate::data::{Publisher, Session, Subscriber};
+use crate::destructive::{DestructiveActions, StoreExecutor};
+use crate::device_id::DBusHardwareId;
+use crate::diagnostics_window::DiagnosticsWindow;
+use crate::disk_guard::{DiskGuard, StatvfsProvider};
+use crate::dispatch::Dispatcher;
+use crate::display_info::{BannerOptions, DisplayInfoStore};
+use crate::event_log::{EventLog, EventLogOptions};
+use crate::file_integrity::FileIntegrityOptions;
+use crate::forwarder::Forwarder;
+use crate::http::{HttpOptions, TlsOptions};
+use crate::http_status::HttpStatusServer;
+use crate::instance_lock::InstanceLock;
+use crate::interface_versions::InterfaceVersions;
+use crate::interfaces::{
+    BASE_IMAGE_INTERFACE, CELLULAR_STATUS_INTERFACE, GEOLOCATION_INTERFACE,
+    HARDWARE_INFO_INTERFACE, NETWORK_INTERFACES_INTERFACE, NETWORK_SOCKETS_INTERFACE,
+    OS_INFO_INTERFACE, RUNTIME_INFO_INTERFACE, RUNTIME_INTERFACES, SENSORS_TEMPERATURE_INTERFACE,
+    STORAGE_USAGE_INTERFACE, SYSTEM_INFO_INTERFACE, SYSTEM_LOAD_INTERFACE, SYSTEM_STATUS_INTERFACE,
+    TAGS_INTERFACE, WIFI_SCAN_RESULTS_INTERFACE,
+};
+use crate::inventory::{Collector, Inventory};
+use crate::kernel_events::KernelEventsOptions;
+use crate::led::LedOptions;
+use crate::led_behavior::{LedBehaviors, SysfsLeds};
+use crate::lifecycle::{Lifecycle, LifecycleEvent};
+use crate::local_access::{LocalAccess, LocalAccessOptions};
+use crate::metrics::Metrics;
+use crate::ota::bandwidth::BandwidthProbeOptions;
+use crate::ota::download::{DownloadAuth, DownloadAuthOptions, DownloadSpaceOptions, Downloader};
+use crate::ota::host_cache::UnreachableHostOptions;
+use crate::ota::image::ImageDeployOptions;
 use crate::ota::ota_handler::OTAHandler;
+use crate::ota::verification::EnforcementOptions;
+use crate::ota::worker::{OtaWorker, OTA_QUEUE_CAPACITY};
+use crate::ota::OtaBackend;
+use crate::power_management::{PlatformPower, RebootScheduler};
+use crate::quiet_hours::{LocalZone, QuietHours, QuietHoursOptions};
+use crate::redaction::{redactor, RedactionOptions};
+use crate::registration::{self, register_device, registration_url, RegistrationRetry};
+use crate::runtime_api::{ConnectionState, SharedState};
+use crate::safe_mode::{SafeMode, SafeModeOptions, StartupMode, Subsystems};
+use crate::secret_store::{
+    CachedSecretStore, FileSecretStore, KeyringSecretStore, SecretStore, SecretStoreKind,
+};
+use crate::simulator::SimulatorOptions;
+use crate::tags::Tags;
+use crate::telemetry::backoff::{TelemetryBackoff, TelemetryBackoffOptions};
+#[cfg(feature = "dbus")]
+use crate::telemetry::cellular_connection::{CellularConnectionTelemetry, ModemManagerSource};
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig, TelemetryConfigWorker};
+use crate::telemetry::flush::FlushRequest;
+use crate::telemetry::geolocation::{
+    GeolocationOptions, GeolocationProvider, GeolocationTelemetry,
+};
+use crate::telemetry::net_interfaces::{NetworkInterfacesOptions, NetworkInterfacesTelemetry};
+use crate::telemetry::net_sockets::NetworkSocketsTelemetry;
+use crate::telemetry::schedule::{CollectorSchedule, ScheduleSource, TelemetrySchedule};
+use crate::telemetry::sent_properties::SentProperties;
+use crate::telemetry::storage_usage::{StorageArea, StorageMountOptions, StorageUsageTelemetry};
+use crate::telemetry::system_info::{HostReader, SystemInfoOptions};
+use crate::telemetry::system_load::SystemLoadTelemetry;
+use crate::telemetry::temperature::TemperatureTelemetry;
+use crate::telemetry::wifi_scan::{NetworkManagerWifi, WifiScanTelemetry};
+use crate::telemetry::{Telemetry, TelemetryTasks};
+use crate::timing::TimingReport;
+use crate::watchdog::{Liveness, MAIN_LOOP};
+use crate::wrapper::platform::{self, platform, PlatformKind};
 
+mod app_config;
+mod audit;
+pub mod benchmark;
+mod boot_latency;
+mod capabilities;
+mod chunked_upload;
+pub mod clock;
 mod commands;
+mod containers;
+mod crash_reports;
+mod custom_commands;
 mod data;
+mod destructive;
+#[cfg(feature = "dbus")]
 mod device;
+mod device_id;
+mod diagnostics_window;
+mod disk_guard;
+mod dispatch;
+mod display_info;
 pub mod error;
+mod event_log;
+mod file_integrity;
+mod forwarder;
+mod http;
+mod http_status;
+mod instance_lock;
+mod interface_versions;
+mod interfaces;
+mod inventory;
+mod kernel_events;
+mod led;
+mod led_behavior;
+mod lifecycle;
+mod local_access;
+pub mod logging;
+mod metrics;
+mod network_manager;
+mod onboarding;
+pub mod options;
 mod ota;
 mod power_management;
+mod quiet_hours;
+mod redaction;
+mod registration;
 mod repository;
+mod runtime_api;
+mod safe_mode;
+mod secret_store;
+mod simulator;
+#[cfg(feature = "dbus")]
+pub mod status;
+mod tags;
 mod telemetry;
+#[cfg(test)]
+mod test_utils;
+mod timing;
+mod watchdog;
 pub mod wrapper;
 
-#[derive(Debug, Deserialize)]
+/// Time granted to each background task to complete on shutdown.
+const SHUTDOWN_TASK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time granted to a running OTA deploy to complete on shutdown.
+const DEFAULT_OTA_SHUTDOWN_GRACE: Duration = Duration::from_secs(120);
+
+/// Datastreams whose timed out publishes are kept in the offline queue.
+const QUEUE_ELIGIBLE_INTERFACES: [&str; 1] = [SYSTEM_STATUS_INTERFACE];
+/// Telemetry kept on disk by the store and forward while its publishes fail.
+const STORED_INTERFACES: [&str; 8] = [
+    SYSTEM_STATUS_INTERFACE,
+    SYSTEM_LOAD_INTERFACE,
+    CELLULAR_STATUS_INTERFACE,
+    GEOLOCATION_INTERFACE,
+    NETWORK_SOCKETS_INTERFACE,
+    STORAGE_USAGE_INTERFACE,
+    WIFI_SCAN_RESULTS_INTERFACE,
+    SENSORS_TEMPERATURE_INTERFACE,
+];
+/// Telemetry sent by the `send_telemetry` command, the collectors that can run once.
+const FLUSHED_INTERFACES: [&str; 2] = [SYSTEM_STATUS_INTERFACE, SYSTEM_LOAD_INTERFACE];
+
+#[derive(Debug, Default, Deserialize)]
 pub struct DeviceManagerOptions {
+    /// Realm of the device, unused when attached to the Astarte Message Hub.
+    #[serde(default)]
     pub realm: String,
     pub device_id: Option<String>,
+    /// Namespace UUID of the device ids derived from the ids that aren't Astarte device ids.
+    pub device_id_namespace: Option<String>,
+    /// Longest wait for the hardware id of the `io.edgehog.Device` D-Bus service.
+    pub hardware_id_timeout_secs: Option<u64>,
     pub credentials_secret: Option<String>,
+    #[serde(default)]
     pub pairing_url: String,
     pub pairing_token: Option<String>,
+    /// Skip the validation of the certificates of Astarte and of its pairing API, only for lab
+    /// and staging clusters with self-signed certificates.
+    pub astarte_ignore_ssl_errors: Option<bool>,
+    /// Connection to Astarte, the one of the SDK or the one shared by the Astarte Message Hub.
+    pub astarte_library: Option<AstarteLibrary>,
+    /// Attempts of the registration while the network or the pairing API fail.
+    pub registration_attempts: Option<u32>,
+    /// Longest time the registration is retried for.
+    pub registration_retry_secs: Option<u64>,
     pub interfaces_directory: String,
+    /// State of the runtime and credentials secret, `/var/lib/edgehog` by default.
+    #[serde(default = "options::default_store_directory")]
     pub store_directory: String,
+    /// Where the credentials secret is kept, a file in the store directory by default.
+    pub secret_store: Option<SecretStoreKind>,
     pub download_directory: String,
+    pub metered_telemetry_period_factor: Option<u32>,
+    pub network_sockets_period_secs: Option<u64>,
+    pub ota_trusted_keys_directory: Option<String>,
+    /// PEM public key the OTA bundles must be signed with, the signatures are not checked when
+    /// neither this nor the trusted keys directory is set.
+    pub ota_public_key_path: Option<String>,
+    pub strict_payload_validation: Option<bool>,
+    pub ota_health_probe_period_secs: Option<u64>,
+    pub ota_download_auth: Option<DownloadAuthOptions>,
+    pub ota_download_resume_attempts: Option<u32>,
+    /// Longest time a download may take, resumes included.
+    pub ota_download_timeout_secs: Option<u64>,
+    /// Attempts of each request of a download, retried on connection and server errors.
+    pub ota_download_attempts: Option<u32>,
+    /// Longest time a request of a download is retried for.
+    pub ota_download_retry_secs: Option<u64>,
+    /// Cap on the speed of the OTA downloads, unlimited when unset or 0.
+    pub ota_max_download_speed_bytes_per_sec: Option<u64>,
+    pub ota_unreachable_hosts: Option<UnreachableHostOptions>,
+    /// Hard-link the bundles staged on the device instead of copying them.
+    pub ota_link_local_bundles: Option<bool>,
+    /// Free space required on the filesystem of the download directory.
+    pub ota_download_space: Option<DownloadSpaceOptions>,
+    /// Leave the artifacts of the earlier OTA requests in the download directory, for debugging.
+    pub ota_keep_stale_downloads: Option<bool>,
+    /// PEM certificates of the CA of the OTA server, trusted on top of the system ones.
+    pub ota_ca_certificate_path: Option<String>,
+    /// PEM certificate and private key presented to the OTA server.
+    pub ota_client_certificate_path: Option<String>,
+    pub ota_client_key_path: Option<String>,
+    /// Skip the validation of the OTA server certificate, as a last resort.
+    pub ota_accept_invalid_certs: Option<bool>,
+    pub ota_bandwidth_probe: Option<BandwidthProbeOptions>,
+    pub ota_enforcement_mode: Option<EnforcementOptions>,
+    pub ota_shutdown_grace_secs: Option<u64>,
+    /// Deploy backend of the OTA updates, RAUC or the image deployer when unset.
+    pub ota_backend: Option<OtaBackend>,
+    pub ota_image_deploy: Option<ImageDeployOptions>,
+    pub telemetry_config_coalesce_millis: Option<u64>,
+    pub send_timeout_secs: Option<u64>,
+    pub mute_mode: Option<MuteMode>,
+    pub benchmark_enabled: Option<bool>,
+    /// Serve the state of the runtime on the `io.edgehog.DeviceRuntime` D-Bus service.
+    pub runtime_api_enabled: Option<bool>,
+    /// Port of the local health and metrics endpoint, disabled unless set.
+    pub http_status_port: Option<u16>,
+    /// Address of the health and metrics endpoint, `127.0.0.1` unless set.
+    pub http_status_address: Option<String>,
+    pub diagnostics_window_max_minutes: Option<u64>,
+    pub destructive_confirm_window_secs: Option<u64>,
+    /// Run the destructive commands without confirmation, for automated factories.
+    pub destructive_single_shot: Option<bool>,
+    /// Commands run by name, the only ones beside the built-in commands.
+    pub custom_commands: Option<HashMap<String, CustomCommandOptions>>,
+    /// Shell sessions opened on request of the backend, disabled unless set.
+    pub enable_remote_terminal: Option<bool>,
+    /// Shell of the remote terminal sessions, `/bin/sh` by default.
+    pub remote_terminal_shell: Option<String>,
+    pub remote_terminal_max_sessions: Option<usize>,
+    /// Docker containers deployed on request of the backend, with the `containers` feature.
+    pub containers: Option<ContainersOptions>,
+    pub tags: Option<Vec<String>>,
+    /// Serial number and part number, taken from the environment or the firmware when unset.
+    pub system_info: Option<SystemInfoOptions>,
+    /// Send all the initial telemetry properties at startup, even the ones unchanged since the
+    /// last send.
+    pub force_resend_properties: Option<bool>,
+    pub disk_free_space_floor_bytes: Option<u64>,
+    pub capability_denial_fatal: Option<bool>,
+    /// Init system, detected when not set.
+    pub platform: Option<PlatformKind>,
+    pub storage_areas: Option<Vec<StorageArea>>,
+    pub storage_usage_period_secs: Option<u64>,
+    /// Usage of the mounted filesystems, reported unless disabled.
+    pub storage_mounts: Option<StorageMountOptions>,
+    /// MAC address, technology and link state of the network interfaces.
+    pub network_interfaces: Option<NetworkInterfacesOptions>,
+    pub cellular_connection_period_secs: Option<u64>,
+    /// Provider of the position published on `io.edgehog.devicemanager.Geolocation`.
+    pub geolocation: Option<GeolocationOptions>,
+    pub geolocation_period_secs: Option<u64>,
+    /// Period of the WiFi scans, at least 30 seconds.
+    pub wifi_scan_period_secs: Option<u64>,
+    pub system_load_period_secs: Option<u64>,
+    pub temperature_period_secs: Option<u64>,
+    /// Stretching of the telemetry periods while its publishes keep failing.
+    pub telemetry_backoff: Option<TelemetryBackoffOptions>,
+    /// Telemetry kept on disk while its publishes fail, sent again once they succeed.
+    pub store_forward: Option<StoreForwardOptions>,
+    pub redaction: Option<RedactionOptions>,
+    pub safe_mode: Option<SafeModeOptions>,
+    pub simulator: Option<SimulatorOptions>,
+    pub led: Option<LedOptions>,
+    /// How long the behaviors of `io.edgehog.devicemanager.LedBehavior` play, 60 seconds by
+    /// default.
+    pub led_behavior_duration_secs: Option<u64>,
+    pub crash_reports: Option<CrashReportOptions>,
+    pub kernel_events: Option<KernelEventsOptions>,
+    /// Critical files and directories whose changes are reported.
+    pub file_integrity: Option<FileIntegrityOptions>,
+    /// Applications whose configuration documents are pushed by the backend.
+    pub app_config: Option<AppConfigOptions>,
+    pub event_log: Option<EventLogOptions>,
+    /// Local clients allowed on the local publish APIs, everyone else is denied.
+    pub local_access: Option<LocalAccessOptions>,
+    /// Identity headers, timeouts, proxy and CA of the outbound HTTP requests.
+    pub http: Option<HttpOptions>,
+    /// Hours when the OTA deploys, the reboots and the commands are held off.
+    pub quiet_hours: Option<QuietHoursOptions>,
+    /// File rendered with the display name and the notes set by the backend.
+    pub display_banner: Option<BannerOptions>,
+    pub onboarding: Option<onboarding::OnboardingOptions>,
 }
 
-pub struct DeviceManager {
-    sdk: AstarteSdk,
-    //we pass the ota event through a channel, to avoid blocking the main loop
-    ota_event_channel: Sender<HashMap<String, AstarteType>>,
+/// Why [`DeviceManager::run`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// SIGTERM, the service was stopped.
+    Terminated,
+    /// SIGINT, the runtime was interrupted from the terminal.
+    Interrupted,
 }
 
-impl DeviceManager {
-    pub async fn new(opts: DeviceManagerOptions) -> Result<DeviceManager, DeviceManagerError> {
-        let device_id: String = get_device_id(opts.device_id.clone()).await?;
-        let credentials_secret: String = get_credentials_secret(
-            &device_id,
-            &opts,
-            FileStateRepository::new(
-                opts.store_directory.clone(),
-                format!("credentials_{}.json", device_id),
+impl ExitReason {
+    /// Exit status of the process once shut down.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ExitReason::Terminated => 0,
+            ExitReason::Interrupted => 128 + nix::sys::signal::Signal::SIGINT as i32,
+        }
+    }
+}
+
+/// The runtime, connected to Astarte through the session `S`.
+pub struct DeviceManager<S: Session = AstarteConnection> {
+    subscriber: S::Subscriber,
+    publisher: DeadlinePublisher<S>,
+    clock: Arc<dyn Clock>,
+    metered: watch::Receiver<bool>,
+    metered_telemetry_period_factor: u32,
+    network_sockets_period: Duration,
+    storage_areas: Vec<StorageArea>,
+    storage_usage_period: Duration,
+    storage_mounts: Option<StorageMountOptions>,
+    network_interfaces: Arc<NetworkInterfacesTelemetry>,
+    system_info: Option<SystemInfoOptions>,
+    sent_properties: SentProperties,
+    #[cfg(feature = "dbus")]
+    cellular_connection_period: Duration,
+    geolocation: Option<Box<dyn GeolocationProvider>>,
+    geolocation_period: Duration,
+    wifi_scan_period: Duration,
+    system_load_period: Duration,
+    temperature_period: Duration,
+    //the received data is handed over through channels, to avoid blocking the main loop
+    dispatcher: Dispatcher,
+    /// Messages injected by the simulator, dispatched like the ones polled from Astarte.
+    injected: tokio::sync::mpsc::Receiver<Clientbound>,
+    ota_worker: Arc<OtaWorker>,
+    ota_shutdown: watch::Sender<bool>,
+    ota_shutdown_grace: Duration,
+    telemetry_config: watch::Receiver<TelemetryConfig>,
+    telemetry_schedule: Arc<TelemetrySchedule>,
+    telemetry_backoff: Option<Arc<TelemetryBackoff>>,
+    /// Progress of the main and telemetry loops, the watchdog heartbeat depends on.
+    liveness: Arc<Liveness>,
+    /// State served by the runtime API.
+    runtime_state: Arc<SharedState>,
+    /// Counters served by the health and metrics endpoint.
+    metrics: Arc<Metrics>,
+    /// Health and metrics endpoint, when `http_status_port` is set.
+    http_status: Option<HttpStatusServer>,
+    pending_ota_response_done: Option<oneshot::Receiver<Duration>>,
+    startup: Arc<TimingReport>,
+    tasks: Vec<JoinHandle<()>>,
+    /// Collectors of the telemetry, stopped between two runs.
+    telemetry_tasks: TelemetryTasks,
+    /// Tasks publishing what the others produce, awaited once the others are stopped.
+    drained_tasks: Vec<JoinHandle<()>>,
+    instance_lock: InstanceLock,
+    startup_history: FileStateRepository,
+    tags: Arc<Tags<'static>>,
+    disk_guard: Arc<DiskGuard>,
+    capabilities: CapabilityReport,
+    lifecycle: Arc<Lifecycle>,
+    /// Registration completed by this run, reported once connected.
+    registration: Option<LifecycleEvent>,
+    send_stats: Arc<SendStats>,
+    safe_mode: Arc<SafeMode>,
+    interface_versions: Arc<InterfaceVersions>,
+    /// Findings of the audit run at startup, published once connected.
+    startup_audit: AuditReport,
+    /// Optional subsystems started in the current mode.
+    subsystems: Subsystems,
+    mutes: Arc<InterfaceMutes>,
+    mute_events: Option<tokio::sync::mpsc::Receiver<MuteEvent>>,
+    telemetry_flush: Option<tokio::sync::mpsc::Receiver<FlushRequest>>,
+    #[cfg(feature = "dbus")]
+    _led_service: Option<zbus::Connection>,
+    #[cfg(feature = "dbus")]
+    _send_stats_service: Option<zbus::Connection>,
+    #[cfg(feature = "dbus")]
+    _status_service: Option<zbus::Connection>,
+    #[cfg(feature = "dbus")]
+    _benchmark_service: Option<zbus::Connection>,
+    #[cfg(feature = "dbus")]
+    _runtime_service: Option<zbus::Connection>,
+}
+
+/// What the runtime needs before connecting to Astarte, whatever the session.
+struct Setup {
+    clock: Arc<dyn Clock>,
+    instance_lock: InstanceLock,
+    disk_guard: Arc<DiskGuard>,
+    safe_mode: Arc<SafeMode>,
+    startup: Arc<TimingReport>,
+    device_id: String,
+    http_client: reqwest::Client,
+    /// Client of the pairing API, validating its certificate like the SDK does.
+    pairing_client: reqwest::Client,
+    ota_client: reqwest::Client,
+    capabilities: CapabilityReport,
+    startup_audit: AuditReport,
+    interface_versions: Arc<InterfaceVersions>,
+    validator: Arc<PayloadValidator>,
+}
+
+impl Setup {
+    async fn prepare(opts: &DeviceManagerOptions) -> Result<Setup, DeviceManagerError> {
+        let problems = opts.validate();
+        if !problems.is_empty() {
+            return Err(DeviceManagerError::Configuration(problems));
+        }
+
+        redaction::init(&opts.redaction.clone().unwrap_or_default());
+        platform::init(opts.platform);
+        let instance_lock = InstanceLock::acquire(&opts.store_directory)?;
+
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let disk_guard = Arc::new(DiskGuard::new(
+            Box::new(StatvfsProvider),
+            &opts.store_directory,
+            opts.disk_free_space_floor_bytes
+                .unwrap_or(disk_guard::DEFAULT_FREE_SPACE_FLOOR),
+            clock.clone(),
+        ));
+        let safe_mode = Arc::new(SafeMode::check(
+            clock.clone(),
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "restart_counters.json".to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
             ),
+            &opts.safe_mode.clone().unwrap_or_default(),
+        ));
+        // the startup is timed from the process start, not from here
+        let startup = Arc::new(TimingReport::since(
+            "startup",
+            clock.clone(),
+            boot_latency::process_age().unwrap_or_default(),
+        ));
+        let device_id: String = startup.time("device_id", get_device_id(opts)).await?;
+        let http_options = opts.http.clone().unwrap_or_default();
+        let http_client = http::client(&http_options, &device_id)?;
+        let ota_tls = TlsOptions {
+            ca_certificate: opts.ota_ca_certificate_path.clone(),
+            client_certificate: opts.ota_client_certificate_path.clone(),
+            client_key: opts.ota_client_key_path.clone(),
+            accept_invalid_certs: opts.ota_accept_invalid_certs.unwrap_or(false),
+        };
+        let ota_client = if ota_tls.is_set() {
+            http::with_tls(http::builder(&http_options, &device_id)?, &ota_tls)?.build()?
+        } else {
+            http_client.clone()
+        };
+        let pairing_tls = astarte_tls(opts);
+        let pairing_client = if pairing_tls.is_set() {
+            warn!(
+                "Certificate validation toward Astarte disabled by astarte_ignore_ssl_errors, \
+                 the credentials can be intercepted: use it only with lab and staging clusters"
+            );
+            http::with_tls(http::builder(&http_options, &device_id)?, &pairing_tls)?.build()?
+        } else {
+            http_client.clone()
+        };
+
+        let capabilities = capabilities::audit(
+            &SystemProbe,
+            &AuditTarget {
+                store_directory: opts.store_directory.clone().into(),
+                download_directory: opts.download_directory.clone().into(),
+                onboarding: opts
+                    .onboarding
+                    .as_ref()
+                    .map_or(false, |onboarding| onboarding.enabled),
+            },
+            opts.capability_denial_fatal.unwrap_or(false),
         )
         .await?;
 
-        let sdk_options = AstarteOptions::new(
-            &opts.realm,
-            &device_id,
-            &credentials_secret,
-            &opts.pairing_url,
+        let interfaces = InterfaceIndex::load(std::path::Path::new(&opts.interfaces_directory))?;
+        // a missing interface would otherwise only show up as failed sends
+        let problems = interfaces.check_required(RUNTIME_INTERFACES);
+        if !problems.is_empty() {
+            return Err(DeviceManagerError::RequiredInterfaces {
+                directory: opts.interfaces_directory.clone(),
+                problems,
+            });
+        }
+        for mismatch in interfaces.check_expected(RUNTIME_INTERFACES) {
+            warn!("Interface not as expected by the runtime: {mismatch}");
+        }
+        let mut unused: Vec<String> = interfaces
+            .interface_names()
+            .into_iter()
+            .filter(|name| !crate::interfaces::is_known(name))
+            .collect();
+        unused.sort();
+        for name in unused {
+            warn!("Interface {name} is not used by the runtime");
+        }
+        // repair the persisted state before the components load it, only at startup: later the
+        // components hold their state in memory
+        let startup_audit = StateAudit::new(
+            &opts.store_directory,
+            &opts.download_directory,
+            interfaces.interface_names(),
+            Some(disk_guard.clone()),
         )
-        .interface_directory(&opts.interfaces_directory)?
-        .build();
-        info!("Starting");
+        .run_once();
+        let interface_versions = Arc::new(InterfaceVersions::new(
+            clock.clone(),
+            opts.interfaces_directory.clone().into(),
+            RUNTIME_INTERFACES,
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "interface_summary.json".to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ),
+        ));
+        let validator = Arc::new(PayloadValidator::new(
+            interfaces,
+            opts.strict_payload_validation
+                .unwrap_or(cfg!(debug_assertions)),
+        ));
+
+        Ok(Setup {
+            clock,
+            instance_lock,
+            disk_guard,
+            safe_mode,
+            startup,
+            device_id,
+            http_client,
+            pairing_client,
+            ota_client,
+            capabilities,
+            startup_audit,
+            interface_versions,
+            validator,
+        })
+    }
+}
+
+impl DeviceManager {
+    pub async fn new(mut opts: DeviceManagerOptions) -> Result<DeviceManager, DeviceManagerError> {
+        let setup = Setup::prepare(&opts).await?;
+        let device_id = setup.device_id.clone();
+
+        // the hub owns the credentials and the connection, the runtime only attaches to it
+        if let Some(AstarteLibrary::AstarteMessageHub(hub_options)) = &opts.astarte_library {
+            info!("Starting");
+
+            platform().notifier().status("Initializing");
+            let node = setup
+                .startup
+                .time(
+                    "message_hub_attach",
+                    AstarteMessageHubNode::attach(
+                        hub_options,
+                        Path::new(&opts.interfaces_directory),
+                        setup.validator.clone(),
+                    ),
+                )
+                .await?;
+
+            return Self::assemble(
+                opts,
+                setup,
+                AstarteConnection::MessageHub(node),
+                String::new(),
+                None,
+            )
+            .await;
+        }
+
+        let credentials_store =
+            open_credentials_store(&opts, &device_id, Some(setup.disk_guard.clone()))?;
+        let credentials_persisted = credentials_store.exists();
+        let token_store = onboarding::token_store(&opts, Some(setup.disk_guard.clone()));
+        let onboarded_token = onboarding::persisted_token(&token_store);
+        if let Some(token) = onboarded_token.clone() {
+            info!("Registering with the pairing token of the previous onboarding");
+            opts.pairing_token.get_or_insert(token);
+        } else if onboarding::should_onboard(&opts, credentials_persisted)
+            && setup.capabilities.is_available(Feature::Onboarding)
+        {
+            platform().notifier().status("Onboarding");
+            if let Some(token) = setup
+                .startup
+                .time("onboarding", onboarding::run(&opts, setup.clock.clone()))
+                .await?
+            {
+                // kept until the registration, a failure or a reboot doesn't onboard again
+                if let Err(err) = token_store.save(&token) {
+                    warn!("Unable to persist the onboarding pairing token: {:?}", err);
+                }
+                opts.pairing_token = Some(token);
+            }
+        }
 
-        wrapper::systemd::systemd_notify_status("Initializing");
-        let astarte_client = Astarte::new(&sdk_options).await?;
+        let registration = (opts.credentials_secret.is_none() && !credentials_persisted)
+            .then(|| LifecycleEvent::first_registration(&device_id));
+        let credentials_secret: String = setup
+            .startup
+            .time(
+                "credentials",
+                get_credentials_secret(
+                    &device_id,
+                    &opts,
+                    &setup.pairing_client,
+                    setup.clock.as_ref(),
+                    credentials_store.as_ref(),
+                ),
+            )
+            .await?;
+        if token_store.exists() {
+            if let Err(err) = token_store.delete() {
+                warn!("Unable to remove the onboarding pairing token: {:?}", err);
+            }
+        }
 
-        let mut ota_handler = OTAHandler::new(&opts).await?;
+        let sdk_options = sdk_options(&opts, &device_id, &credentials_secret)?;
+        info!("Starting");
 
-        ota_handler
-            .ensure_pending_ota_response(&astarte_client)
+        platform().notifier().status("Initializing");
+        let astarte_client = setup
+            .startup
+            .time(
+                "sdk_connect",
+                Astarte::new(sdk_options, setup.validator.clone()),
+            )
             .await?;
 
-        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        Self::assemble(
+            opts,
+            setup,
+            AstarteConnection::Sdk(astarte_client),
+            credentials_secret,
+            registration,
+        )
+        .await
+    }
+}
 
-        let astarte_client_clone = astarte_client.clone();
-        tokio::spawn(async move {
-            while let Some(data) = rx.recv().await {
-                ota_handler
-                    .ota_event(&astarte_client_clone, data)
-                    .await
-                    .ok();
+impl<S: Session> DeviceManager<S> {
+    /// Device manager publishing through `session` and polling its subscriber, instead of
+    /// connecting to Astarte: the configured credentials are used as they are.
+    pub async fn with_publisher(
+        opts: DeviceManagerOptions,
+        session: S,
+    ) -> Result<Self, DeviceManagerError> {
+        let setup = Setup::prepare(&opts).await?;
+        info!("Starting");
+
+        platform().notifier().status("Initializing");
+        let credentials_secret = opts.credentials_secret.clone().unwrap_or_default();
+
+        Self::assemble(opts, setup, session, credentials_secret, None).await
+    }
+
+    /// Start the components of the runtime on the connected `session`.
+    async fn assemble(
+        opts: DeviceManagerOptions,
+        setup: Setup,
+        session: S,
+        credentials_secret: String,
+        registration: Option<LifecycleEvent>,
+    ) -> Result<Self, DeviceManagerError> {
+        let Setup {
+            clock,
+            instance_lock,
+            disk_guard,
+            safe_mode,
+            startup,
+            device_id,
+            http_client,
+            pairing_client: _,
+            ota_client,
+            capabilities,
+            startup_audit,
+            interface_versions,
+            validator: _,
+        } = setup;
+        let subsystems = safe_mode.subsystems();
+        let subscriber = session.subscriber();
+        let send_stats = Arc::new(
+            SendStats::new(clock.clone()).with_repository(Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "send_stats.json".to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            )),
+        );
+        let mutes = Arc::new(InterfaceMutes::new(
+            opts.mute_mode.unwrap_or_default(),
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "muted_interfaces.json".to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ),
+        ));
+        let (mute_tx, mute_events) = tokio::sync::mpsc::channel(8);
+        let (telemetry_flush_tx, telemetry_flush) = tokio::sync::mpsc::channel(1);
+        let telemetry_backoff = opts
+            .telemetry_backoff
+            .as_ref()
+            .filter(|options| options.enabled)
+            .map(|options| Arc::new(TelemetryBackoff::new(clock.clone(), options)));
+        let store_forward = opts
+            .store_forward
+            .as_ref()
+            .filter(|options| options.enabled)
+            .map(|options| {
+                let directory = options
+                    .directory
+                    .as_deref()
+                    .unwrap_or(&opts.store_directory);
+                Arc::new(StoreForward::load(
+                    std::path::Path::new(directory),
+                    options
+                        .max_samples
+                        .unwrap_or(data::store_forward::DEFAULT_MAX_SAMPLES),
+                    STORED_INTERFACES
+                        .iter()
+                        .map(|interface| interface.to_string())
+                        .collect(),
+                ))
+            });
+        let publisher = DeadlinePublisher::new(
+            session,
+            clock.clone(),
+            opts.send_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(data::deadline::DEFAULT_SEND_TIMEOUT),
+            QUEUE_ELIGIBLE_INTERFACES
+                .iter()
+                .map(|interface| interface.to_string())
+                .collect(),
+        )
+        .with_disk_guard(disk_guard.clone())
+        .with_send_stats(send_stats.clone())
+        .with_mutes(mutes.clone())
+        .with_backoff(telemetry_backoff.clone())
+        .with_store_forward(store_forward);
+
+        let local_access = Arc::new(LocalAccess::new(
+            &opts.local_access.clone().unwrap_or_default(),
+        ));
+        let (injected_tx, injected) = tokio::sync::mpsc::channel(32);
+        let simulator = opts
+            .simulator
+            .as_ref()
+            .filter(|simulator| simulator.enabled);
+        #[cfg(feature = "simulator")]
+        let (publisher, simulator_task) = match simulator {
+            Some(options) => {
+                let (task, dump) =
+                    simulator::endpoint::start(options, injected_tx, local_access.clone())?;
+                (publisher.with_outbound_dump(dump), Some(task))
+            }
+            None => (publisher, None),
+        };
+        #[cfg(not(feature = "simulator"))]
+        {
+            if simulator.is_some() {
+                warn!("Built without the simulator feature, ignoring the simulator configuration");
+            }
+            drop(injected_tx);
+        }
+        #[cfg(feature = "dbus")]
+        let send_stats_service =
+            serve_dbus_api("send stats", data::service::serve(send_stats.clone())).await;
+
+        let metered = network_manager::watch_metered().await;
+
+        let downloader = Arc::new(
+            Downloader::new(
+                opts.ota_download_auth.clone().map(|auth| {
+                    DownloadAuth::new(auth, device_id.clone(), credentials_secret.clone())
+                }),
+                clock.clone(),
+            )
+            .with_client(ota_client)
+            .with_disk_guard(disk_guard.clone())
+            .with_resume(
+                opts.ota_download_resume_attempts,
+                opts.ota_download_timeout_secs.map(Duration::from_secs),
+            )
+            .with_retry(
+                opts.ota_download_attempts,
+                opts.ota_download_retry_secs.map(Duration::from_secs),
+            )
+            .with_max_speed(opts.ota_max_download_speed_bytes_per_sec)
+            .with_unreachable_hosts(opts.ota_unreachable_hosts.as_ref())
+            .with_local_links(opts.ota_link_local_bundles)
+            .with_space_check(Box::new(StatvfsProvider), opts.ota_download_space.as_ref()),
+        );
+        let event_log = opts
+            .event_log
+            .as_ref()
+            .filter(|options| options.enabled)
+            .map(|options| {
+                Arc::new(EventLog::new(
+                    clock.clone(),
+                    Box::new(
+                        FileStateRepository::new(
+                            opts.store_directory.clone(),
+                            event_log::SEQUENCE_FILE.to_owned(),
+                        )
+                        .with_disk_guard(Some(disk_guard.clone())),
+                    ),
+                    std::path::Path::new(&opts.store_directory).join(event_log::EVENT_LOG_FILE),
+                    options.max_bytes.unwrap_or(event_log::DEFAULT_MAX_BYTES),
+                ))
+            });
+        let lifecycle = Arc::new(
+            Lifecycle::new(Box::new(
+                FileStateRepository::new(opts.store_directory.clone(), "lifecycle.json".to_owned())
+                    .with_disk_guard(Some(disk_guard.clone())),
+            ))
+            .with_event_log(event_log.clone()),
+        );
+        let quiet_hours = match &opts.quiet_hours {
+            Some(options) => {
+                let quiet_hours = QuietHours::new(
+                    clock.clone(),
+                    Box::new(LocalZone),
+                    options,
+                    Box::new(
+                        FileStateRepository::new(
+                            opts.store_directory.clone(),
+                            quiet_hours::RANGES_FILE.to_owned(),
+                        )
+                        .with_disk_guard(Some(disk_guard.clone())),
+                    ),
+                )
+                .map_err(|err| DeviceManagerError::InvalidOption {
+                    option: "quiet_hours".to_owned(),
+                    reason: err.to_string(),
+                })?
+                .with_event_log(event_log.clone());
+                let quiet_hours = Arc::new(quiet_hours);
+                power_management::hold_during(quiet_hours.clone());
+                Some(quiet_hours)
+            }
+            None => None,
+        };
+        let display_info = Arc::new(DisplayInfoStore::new(
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    display_info::DISPLAY_INFO_FILE.to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ),
+            opts.display_banner.clone(),
+        ));
+        let (ota_shutdown, ota_shutdown_rx) = watch::channel(false);
+        let (ota_cancel_tx, ota_cancel) = watch::channel(None);
+        let runtime_state = Arc::new(SharedState::default());
+        let metrics = Arc::new(Metrics::default());
+        let ota_handler = OTAHandler::new(
+            &opts,
+            clock.clone(),
+            metered.clone(),
+            downloader,
+            lifecycle.clone(),
+            ota_shutdown_rx,
+        )
+        .await?
+        .with_event_log(event_log.clone())
+        .with_quiet_hours(quiet_hours.clone())
+        .with_cancel(ota_cancel)
+        .with_runtime_state(Some(runtime_state.clone()))
+        .with_metrics(Some(metrics.clone()));
+
+        let (pending_tx, pending_rx) = oneshot::channel();
+
+        let telemetry_schedule = Arc::new(telemetry_schedule(
+            &opts,
+            clock.clone(),
+            subsystems,
+            &capabilities,
+        ));
+        let network_interfaces = Arc::new(
+            NetworkInterfacesTelemetry::new(clock.clone(), opts.network_interfaces.as_ref())
+                .with_schedule(telemetry_schedule.clone()),
+        );
+        let ota_status = ota_handler.status();
+        #[cfg(feature = "dbus")]
+        let status_service = serve_dbus_api(
+            "status",
+            status::serve(
+                publisher.health().clone(),
+                ota_status.clone(),
+                send_stats.clone(),
+                telemetry_schedule.clone(),
+                display_info.subscribe(),
+                publisher.clone(),
+                local_access.clone(),
+            ),
+        )
+        .await;
+        #[cfg(feature = "dbus")]
+        let runtime_service = if opts.runtime_api_enabled.unwrap_or(false) {
+            serve_dbus_api(
+                "runtime",
+                runtime_api::serve(device_id.clone(), runtime_state.subscribe()),
+            )
+            .await
+        } else {
+            None
+        };
+        let http_status = match http_status::listen_address(
+            opts.http_status_address.as_deref(),
+            opts.http_status_port,
+        )? {
+            Some(address) => {
+                match HttpStatusServer::spawn(address, runtime_state.subscribe(), metrics.clone()) {
+                    Ok(server) => {
+                        info!("Health and metrics endpoint on {}", server.address());
+                        Some(server)
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Unable to serve the health and metrics endpoint on {address}: {err}"
+                        );
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        // the handler outlives its task, to serve the requests again if the task dies
+        let ota_handler = Arc::new(tokio::sync::Mutex::new(ota_handler));
+        let ota_publisher = publisher.clone();
+        let pending_tx = std::sync::Mutex::new(Some(pending_tx));
+        let ota_worker = Arc::new(
+            OtaWorker::start(clock.clone(), OTA_QUEUE_CAPACITY, move |requests| {
+                let ota_handler = ota_handler.clone();
+                let publisher = ota_publisher.clone();
+                let pending_response_done = pending_tx
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .unwrap_or_else(|| oneshot::channel().0);
+                tokio::spawn(async move {
+                    let mut ota_handler = ota_handler.lock().await;
+                    ota_handler
+                        .serve(publisher, requests, pending_response_done)
+                        .await;
+                })
+            })
+            .with_event_log(event_log.clone()),
+        );
+
+        let (telemetry_config_worker, telemetry_config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "telemetry_config.json".to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ),
+            opts.telemetry_config_coalesce_millis
+                .map(Duration::from_millis)
+                .unwrap_or(telemetry::config::DEFAULT_COALESCE_WINDOW),
+        );
+        let (telemetry_config_tx, telemetry_config_rx) = tokio::sync::mpsc::channel(64);
+        let telemetry_config_handle = tokio::spawn(async move {
+            telemetry_config_worker.run(telemetry_config_rx).await;
+        });
+
+        let tags = Arc::new(Tags::new(
+            clock.clone(),
+            opts.tags.clone().unwrap_or_default(),
+            std::path::Path::new(&opts.store_directory).join("tags"),
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "published_tags.json".to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ),
+        ));
+
+        let mut tasks = vec![telemetry_config_handle];
+        let rejections_worker = ota_worker.clone();
+        let rejections_publisher = publisher.clone();
+        tasks.push(tokio::spawn(async move {
+            rejections_worker.run(&rejections_publisher).await;
+        }));
+        let schedule_publisher = publisher.clone();
+        let published_schedule = telemetry_schedule.clone();
+        tasks.push(tokio::spawn(async move {
+            published_schedule.run(&schedule_publisher).await;
+        }));
+        let inventory = Arc::new(
+            Inventory::new(
+                clock.clone(),
+                vec![
+                    (
+                        OS_INFO_INTERFACE,
+                        Arc::new(telemetry::os_info::get_os_info) as Collector,
+                    ),
+                    (
+                        HARDWARE_INFO_INTERFACE,
+                        Arc::new(telemetry::hardware_info::get_hardware_info) as Collector,
+                    ),
+                    (
+                        RUNTIME_INFO_INTERFACE,
+                        Arc::new(telemetry::runtime_info::get_runtime_info) as Collector,
+                    ),
+                    (
+                        BASE_IMAGE_INTERFACE,
+                        Arc::new(telemetry::base_image::get_base_image) as Collector,
+                    ),
+                ],
+            )
+            .with_interface_versions(interface_versions.clone())
+            .with_event_log(event_log.clone()),
+        );
+        let inventory_publisher = publisher.clone();
+        let refreshed_inventory = inventory.clone();
+        tasks.push(tokio::spawn(async move {
+            refreshed_inventory.run(&inventory_publisher).await;
+        }));
+        let local_access_publisher = publisher.clone();
+        tasks.push(tokio::spawn(async move {
+            local_access.report(&local_access_publisher).await;
+        }));
+        if let Some(quiet_hours) = quiet_hours.clone() {
+            let quiet_hours_publisher = publisher.clone();
+            tasks.push(tokio::spawn(async move {
+                quiet_hours.run(&quiet_hours_publisher).await;
+            }));
+        }
+        #[cfg(feature = "simulator")]
+        tasks.extend(simulator_task);
+        let led = match opts.led.as_ref().filter(|_| subsystems.led) {
+            Some(options) => match led::spawn(options, clock.clone()) {
+                Ok((requests, handle)) => {
+                    tasks.push(handle);
+                    Some(requests)
+                }
+                Err(err) => {
+                    warn!("Unable to drive the LED {}: {:?}", options.path, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        #[cfg(feature = "dbus")]
+        let led_service = match (opts.led.as_ref(), &led) {
+            (Some(options), Some(requests)) => {
+                serve_dbus_api(
+                    "LED",
+                    led::service::serve(options.roles().into_keys().collect(), requests.clone()),
+                )
+                .await
+            }
+            _ => None,
+        };
+        let crash_uploads = match opts
+            .crash_reports
+            .as_ref()
+            .filter(|options| options.enabled && subsystems.crash_reports)
+        {
+            Some(options) => match crash_reports::spawn(
+                options,
+                std::path::Path::new(&opts.store_directory),
+                http_client.clone(),
+                clock.clone(),
+                Box::new(
+                    FileStateRepository::new(
+                        opts.store_directory.clone(),
+                        crash_reports::UPLOAD_SESSION_FILE.to_owned(),
+                    )
+                    .with_disk_guard(Some(disk_guard.clone())),
+                ),
+                publisher.clone(),
+            ) {
+                Ok((uploads, handle)) => {
+                    tasks.push(handle);
+                    Some(uploads)
+                }
+                Err(err) => {
+                    warn!("Unable to start the crash reports: {:?}", err);
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(options) = opts
+            .kernel_events
+            .as_ref()
+            .filter(|options| options.enabled && subsystems.kernel_events)
+        {
+            tasks.push(kernel_events::spawn(
+                options,
+                std::path::Path::new(&opts.store_directory),
+                clock.clone(),
+                publisher.clone(),
+            ));
+        }
+        if let Some(options) = opts
+            .file_integrity
+            .as_ref()
+            .filter(|options| !options.paths.is_empty())
+        {
+            tasks.push(file_integrity::spawn(
+                options,
+                std::path::Path::new(&opts.store_directory),
+                clock.clone(),
+                publisher.clone(),
+            ));
+        }
+        let app_config = match opts
+            .app_config
+            .as_ref()
+            .filter(|options| !options.targets.is_empty())
+        {
+            Some(options) => match app_config::spawn(
+                options,
+                std::path::Path::new(&opts.store_directory),
+                clock.clone(),
+                publisher.clone(),
+            ) {
+                Ok((requests, handle)) => {
+                    tasks.push(handle);
+                    Some(requests)
+                }
+                Err(err) => {
+                    warn!("Unable to start the app config: {:?}", err);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let benchmark_enabled = opts.benchmark_enabled.unwrap_or(false);
+        let benchmark = Arc::new(Benchmark::new(clock.clone(), benchmark_enabled, ota_status));
+        let (benchmark_tx, mut benchmark_rx) = tokio::sync::mpsc::channel(1);
+        let benchmark_publisher = publisher.clone();
+        let command_benchmark = benchmark.clone();
+        tasks.push(tokio::spawn(async move {
+            while let Some(request) = benchmark_rx.recv().await {
+                // the outcome is published on the diagnostics interface
+                let _ =
+                    benchmark::run_and_publish(&command_benchmark, &benchmark_publisher, request)
+                        .await;
+            }
+        }));
+        #[cfg(feature = "dbus")]
+        let benchmark_service = if benchmark_enabled {
+            serve_dbus_api("benchmark", benchmark::serve(benchmark, publisher.clone())).await
+        } else {
+            None
+        };
+
+        let diagnostics_window = DiagnosticsWindow::new(
+            clock.clone(),
+            opts.diagnostics_window_max_minutes
+                .map(|minutes| Duration::from_secs(minutes * 60))
+                .unwrap_or(diagnostics_window::DEFAULT_MAX_WINDOW),
+            publisher.health().clone(),
+        );
+        let (diagnostics_window_tx, diagnostics_window_rx) = tokio::sync::mpsc::channel(4);
+        let diagnostics_window_publisher = publisher.clone();
+        tasks.push(tokio::spawn(async move {
+            diagnostics_window
+                .run(&diagnostics_window_publisher, diagnostics_window_rx)
+                .await;
+        }));
+
+        let destructive = DestructiveActions::new(
+            clock.clone(),
+            opts.destructive_confirm_window_secs
+                .map(Duration::from_secs)
+                .unwrap_or(destructive::DEFAULT_CONFIRM_WINDOW),
+            opts.destructive_single_shot.unwrap_or(false),
+            Box::new(StoreExecutor::new(opts.store_directory.clone().into())),
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    destructive::ARMED_ACTION_FILE.to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ),
+        )
+        .with_event_log(event_log.clone());
+        let (destructive_tx, destructive_rx) = tokio::sync::mpsc::channel(4);
+        let destructive_publisher = publisher.clone();
+        tasks.push(tokio::spawn(async move {
+            destructive
+                .run(&destructive_publisher, destructive_rx)
+                .await;
+        }));
+
+        let (command_results_tx, command_results_rx) = tokio::sync::mpsc::channel(4);
+        let command_results_publisher = publisher.clone();
+        let drained_tasks = vec![tokio::spawn(async move {
+            commands::publish_results(&command_results_publisher, command_results_rx).await;
+        })];
+
+        let command_runner = CommandRunner::new(Box::new(PlatformPower));
+        let (commands_tx, commands_rx) =
+            tokio::sync::mpsc::channel(commands::COMMAND_QUEUE_CAPACITY);
+        let runner_results = command_results_tx.clone();
+        tasks.push(tokio::spawn(async move {
+            command_runner.run(commands_rx, runner_results).await;
+        }));
+
+        let reboot_scheduler = RebootScheduler::new(
+            clock.clone(),
+            Box::new(PlatformPower),
+            Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    power_management::SCHEDULED_REBOOT_FILE.to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ),
+        )
+        .with_results(command_results_tx.clone());
+        let (reboots_tx, reboots_rx) = tokio::sync::mpsc::channel(4);
+        let reboot_publisher = publisher.clone();
+        tasks.push(tokio::spawn(async move {
+            reboot_scheduler.run(&reboot_publisher, reboots_rx).await;
+        }));
+
+        let led_behaviors = LedBehaviors::new(
+            clock.clone(),
+            Box::new(SysfsLeds::new(led_behavior::LEDS_DIRECTORY)),
+            opts.led_behavior_duration_secs
+                .map(Duration::from_secs)
+                .unwrap_or(led_behavior::DEFAULT_LED_BEHAVIOR_DURATION),
+        )
+        .with_results(command_results_tx.clone());
+        let (led_behaviors_tx, led_behaviors_rx) = tokio::sync::mpsc::channel(4);
+        tasks.push(tokio::spawn(led_behaviors.run(led_behaviors_rx)));
+
+        let remote_terminal = opts.enable_remote_terminal.unwrap_or(false).then(|| {
+            let forwarder = Forwarder::new(
+                clock.clone(),
+                opts.remote_terminal_shell
+                    .clone()
+                    .unwrap_or_else(|| forwarder::DEFAULT_REMOTE_TERMINAL_SHELL.to_owned()),
+                opts.remote_terminal_max_sessions
+                    .unwrap_or(forwarder::DEFAULT_REMOTE_TERMINAL_SESSIONS),
+            );
+            let (sessions_tx, sessions_rx) = tokio::sync::mpsc::channel(4);
+            tasks.push(tokio::spawn(async move {
+                forwarder.run(sessions_rx).await;
+            }));
+            sessions_tx
+        });
+
+        let custom_commands = opts.custom_commands.as_ref().map(|options| {
+            let custom_commands = CustomCommands::new(options);
+            let (custom_commands_tx, custom_commands_rx) = tokio::sync::mpsc::channel(4);
+            let custom_command_results = command_results_tx.clone();
+            tasks.push(tokio::spawn(async move {
+                custom_commands
+                    .run(custom_commands_rx, custom_command_results)
+                    .await;
+            }));
+            custom_commands_tx
+        });
+
+        let containers_options = opts
+            .containers
+            .as_ref()
+            .filter(|containers| containers.enabled);
+        #[cfg(feature = "containers")]
+        let containers = match containers_options.map(|options| {
+            containers::docker::DockerEngine::connect().map(|engine| (options, engine))
+        }) {
+            Some(Ok((options, engine))) => {
+                let manager = containers::manager::ContainerManager::new(
+                    clock.clone(),
+                    Box::new(engine),
+                    options.status_period(),
+                );
+                let (containers_tx, containers_rx) = tokio::sync::mpsc::channel(4);
+                let containers_publisher = publisher.clone();
+                tasks.push(tokio::spawn(async move {
+                    manager.run(&containers_publisher, containers_rx).await;
+                }));
+                Some(containers_tx)
+            }
+            Some(Err(err)) => {
+                warn!("Unable to connect to Docker: {}", err);
+                None
             }
+            None => None,
+        };
+        #[cfg(not(feature = "containers"))]
+        let containers = {
+            if containers_options.is_some() {
+                warn!(
+                    "Built without the containers feature, ignoring the containers configuration"
+                );
+            }
+            None
+        };
+
+        let event_log_exports = event_log.map(|event_log| {
+            let (exports_tx, exports_rx) = tokio::sync::mpsc::channel(1);
+            let uploader = HttpUploader::new(http_client.clone());
+            let exports_publisher = publisher.clone();
+            tasks.push(tokio::spawn(async move {
+                event_log
+                    .run_exports(&exports_publisher, &uploader, exports_rx)
+                    .await;
+            }));
+            exports_tx
         });
 
+        startup.record("device_manager_new", startup.elapsed());
+
         Ok(Self {
-            sdk: astarte_client.device_sdk,
-            ota_event_channel: tx,
+            subscriber,
+            publisher,
+            clock,
+            metered,
+            metered_telemetry_period_factor: opts
+                .metered_telemetry_period_factor
+                .unwrap_or(telemetry::DEFAULT_METERED_PERIOD_FACTOR),
+            network_sockets_period: opts
+                .network_sockets_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(telemetry::net_sockets::DEFAULT_NETWORK_SOCKETS_PERIOD),
+            storage_areas: opts.storage_areas.clone().unwrap_or_default(),
+            storage_usage_period: opts
+                .storage_usage_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(telemetry::storage_usage::DEFAULT_STORAGE_USAGE_PERIOD),
+            storage_mounts: opts.storage_mounts.clone(),
+            network_interfaces,
+            system_info: opts.system_info.clone(),
+            sent_properties: SentProperties::new(Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "sent_properties.json".to_owned(),
+                )
+                .with_disk_guard(Some(disk_guard.clone())),
+            ))
+            .with_force_resend(opts.force_resend_properties.unwrap_or(false)),
+            #[cfg(feature = "dbus")]
+            cellular_connection_period: opts
+                .cellular_connection_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(telemetry::cellular_connection::DEFAULT_CELLULAR_CONNECTION_PERIOD),
+            geolocation: opts
+                .geolocation
+                .as_ref()
+                .map(|options| telemetry::geolocation::provider(options, http_client.clone())),
+            geolocation_period: opts
+                .geolocation_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(telemetry::geolocation::DEFAULT_GEOLOCATION_PERIOD),
+            wifi_scan_period: opts
+                .wifi_scan_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(telemetry::wifi_scan::DEFAULT_WIFI_SCAN_PERIOD),
+            system_load_period: opts
+                .system_load_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(telemetry::system_load::DEFAULT_SYSTEM_LOAD_PERIOD),
+            temperature_period: opts
+                .temperature_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(telemetry::temperature::DEFAULT_TEMPERATURE_PERIOD),
+            dispatcher: Dispatcher::new(
+                ota_worker.clone(),
+                telemetry_config_tx,
+                led,
+                capabilities.clone(),
+            )
+            .with_led_behaviors(led_behaviors_tx)
+            .with_crash_uploads(crash_uploads)
+            .with_app_config(app_config)
+            .with_mutes(mute_tx)
+            .with_benchmark(benchmark_tx)
+            .with_diagnostics_window(diagnostics_window_tx)
+            .with_destructive(destructive_tx)
+            .with_reboots(reboots_tx)
+            .with_commands(commands_tx)
+            .with_custom_commands(custom_commands)
+            .with_remote_terminal(remote_terminal)
+            .with_containers(containers)
+            .with_command_results(command_results_tx)
+            .with_event_log_exports(event_log_exports)
+            .with_quiet_hours(quiet_hours)
+            .with_display_info(display_info)
+            .with_telemetry_schedule(telemetry_schedule.clone())
+            .with_telemetry_flush(telemetry_flush_tx)
+            .with_inventory(inventory)
+            .with_ota_cancel(ota_cancel_tx),
+            injected,
+            ota_worker,
+            ota_shutdown,
+            ota_shutdown_grace: opts
+                .ota_shutdown_grace_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_OTA_SHUTDOWN_GRACE),
+            telemetry_config,
+            telemetry_schedule,
+            telemetry_backoff,
+            liveness: Arc::new(Liveness::new(clock.clone())),
+            runtime_state,
+            metrics,
+            http_status,
+            pending_ota_response_done: Some(pending_rx),
+            startup,
+            tasks,
+            telemetry_tasks: TelemetryTasks::new(),
+            drained_tasks,
+            instance_lock,
+            startup_history: FileStateRepository::new(
+                opts.store_directory.clone(),
+                "startup_history.json".to_owned(),
+            )
+            .with_disk_guard(Some(disk_guard.clone())),
+            tags,
+            disk_guard,
+            capabilities,
+            lifecycle,
+            registration,
+            send_stats,
+            safe_mode,
+            interface_versions,
+            startup_audit,
+            subsystems,
+            mutes,
+            mute_events: Some(mute_events),
+            telemetry_flush: Some(telemetry_flush),
+            #[cfg(feature = "dbus")]
+            _led_service: led_service,
+            #[cfg(feature = "dbus")]
+            _send_stats_service: send_stats_service,
+            #[cfg(feature = "dbus")]
+            _status_service: status_service,
+            #[cfg(feature = "dbus")]
+            _benchmark_service: benchmark_service,
+            #[cfg(feature = "dbus")]
+            _runtime_service: runtime_service,
         })
     }
 
-    pub async fn run(&mut self) {
-        wrapper::systemd::systemd_notify_status("Running");
-        let w = self.sdk.clone();
-        tokio::task::spawn(async move {
-            loop {
-                let systatus = telemetry::system_status::get_system_status().unwrap();
+    /// Factor of the telemetry periods, when the backoff is enabled.
+    fn backoff_factor(&self) -> Option<watch::Receiver<u32>> {
+        self.telemetry_backoff
+            .as_ref()
+            .map(|backoff| backoff.factor())
+    }
 
-                w.send_object(
-                    "io.edgehog.devicemanager.SystemStatus",
-                    "/systemStatus",
-                    systatus,
+    /// Status notified while connected.
+    fn running_status(&self) -> &'static str {
+        match self.safe_mode.mode() {
+            StartupMode::Normal => "Running",
+            StartupMode::Safe => "Running in safe mode",
+        }
+    }
+
+    /// Dispatch the messages received from Astarte until a termination signal is received, the
+    /// messages arriving afterwards are left to the next run. The failed polls are retried with
+    /// a backoff, unless retrying can't fix them.
+    pub async fn run(&mut self) -> Result<ExitReason, DeviceManagerError> {
+        platform().notifier().status(self.running_status());
+        let publisher = self.publisher.clone();
+        // the jitter of every collector is drawn from the same generator
+        let random: Arc<dyn Random> = Arc::new(OsRandom);
+        let telemetry = Telemetry::new(
+            self.clock.clone(),
+            telemetry::DEFAULT_SYSTEM_STATUS_PERIOD,
+            self.metered.clone(),
+            self.metered_telemetry_period_factor,
+            self.telemetry_config.clone(),
+        )
+        .with_backoff(self.backoff_factor())
+        .with_schedule(self.telemetry_schedule.clone())
+        .with_random(random.clone())
+        .with_liveness(self.liveness.clone())
+        .with_metrics(self.metrics.clone())
+        .with_shutdown(self.telemetry_tasks.signal());
+        let metered_publisher = publisher.clone();
+        let metered = self.metered.clone();
+        let startup_publisher = publisher.clone();
+        let sockets_publisher = publisher.clone();
+        let tags_publisher = publisher.clone();
+        let disk_guard_publisher = publisher.clone();
+        let send_stats_publisher = publisher.clone();
+        let sockets_telemetry =
+            NetworkSocketsTelemetry::new(self.clock.clone(), self.network_sockets_period)
+                .with_schedule(self.telemetry_schedule.clone())
+                .with_backoff(self.backoff_factor());
+        if self.subsystems.telemetry {
+            let network_interfaces = self.network_interfaces.clone();
+            let interfaces_publisher = publisher.clone();
+            self.tasks.push(tokio::task::spawn(async move {
+                network_interfaces.run(&interfaces_publisher).await;
+            }));
+            #[cfg(feature = "dbus")]
+            {
+                let cellular_clock = self.clock.clone();
+                let cellular_period = self.cellular_connection_period;
+                let cellular_config = self.telemetry_config.clone();
+                let cellular_schedule = self.telemetry_schedule.clone();
+                let cellular_random = random.clone();
+                let cellular_shutdown = self.telemetry_tasks.signal();
+                let cellular_publisher = publisher.clone();
+                self.telemetry_tasks.spawn(async move {
+                    match ModemManagerSource::connect().await {
+                        Ok(source) => {
+                            CellularConnectionTelemetry::new(
+                                cellular_clock,
+                                Box::new(source),
+                                cellular_period,
+                            )
+                            .with_config(cellular_config)
+                            .with_schedule(cellular_schedule)
+                            .with_random(cellular_random)
+                            .with_shutdown(cellular_shutdown)
+                            .run(&cellular_publisher)
+                            .await
+                        }
+                        Err(err) => debug!("No system bus, the modems are not reported: {err:?}"),
+                    }
+                });
+            }
+            if let Some(provider) = self.geolocation.take() {
+                let geolocation = GeolocationTelemetry::new(
+                    self.clock.clone(),
+                    provider,
+                    self.geolocation_period,
                 )
-                .await
-                .unwrap();
+                .with_config(self.telemetry_config.clone())
+                .with_schedule(self.telemetry_schedule.clone())
+                .with_random(random.clone())
+                .with_shutdown(self.telemetry_tasks.signal());
+                let geolocation_publisher = publisher.clone();
+                self.telemetry_tasks.spawn(async move {
+                    geolocation.run(&geolocation_publisher).await;
+                });
+            }
+            let wifi_scan = WifiScanTelemetry::new(
+                self.clock.clone(),
+                Box::new(NetworkManagerWifi::new()),
+                self.wifi_scan_period,
+            )
+            .with_config(self.telemetry_config.clone())
+            .with_schedule(self.telemetry_schedule.clone())
+            .with_random(random.clone())
+            .with_shutdown(self.telemetry_tasks.signal());
+            let wifi_scan_publisher = publisher.clone();
+            self.telemetry_tasks.spawn(async move {
+                wifi_scan.run(&wifi_scan_publisher).await;
+            });
+            let system_load = SystemLoadTelemetry::new(
+                self.clock.clone(),
+                Box::new(telemetry::system_load::get_system_load),
+                self.system_load_period,
+            )
+            .with_config(self.telemetry_config.clone())
+            .with_schedule(self.telemetry_schedule.clone())
+            .with_random(random.clone())
+            .with_shutdown(self.telemetry_tasks.signal());
+            let system_load_publisher = publisher.clone();
+            self.telemetry_tasks.spawn(async move {
+                system_load.run(&system_load_publisher).await;
+            });
+            let temperature = TemperatureTelemetry::new(
+                self.clock.clone(),
+                PathBuf::from(telemetry::temperature::HWMON_DIRECTORY),
+                self.temperature_period,
+            )
+            .with_config(self.telemetry_config.clone())
+            .with_schedule(self.telemetry_schedule.clone())
+            .with_random(random.clone())
+            .with_shutdown(self.telemetry_tasks.signal());
+            let temperature_publisher = publisher.clone();
+            self.telemetry_tasks.spawn(async move {
+                temperature.run(&temperature_publisher).await;
+            });
+            self.telemetry_tasks.spawn(async move {
+                telemetry.run(&publisher).await;
+            });
+            self.tasks.push(tokio::task::spawn(async move {
+                network_manager::publish_metered(&metered_publisher, metered).await;
+            }));
+        }
+        if self.subsystems.network_sockets
+            && self.capabilities.is_available(Feature::NetworkSockets)
+        {
+            self.tasks.push(tokio::task::spawn(async move {
+                sockets_telemetry.run(&sockets_publisher).await;
+            }));
+        }
+        if self.subsystems.storage_usage
+            && storage_usage_reported(&self.storage_areas, self.storage_mounts.as_ref())
+        {
+            let storage_usage = StorageUsageTelemetry::new(
+                self.clock.clone(),
+                Box::new(StatvfsProvider),
+                self.storage_areas.clone(),
+                self.storage_usage_period,
+            )
+            .with_mounts(self.storage_mounts.as_ref())
+            .with_config(self.telemetry_config.clone())
+            .with_schedule(self.telemetry_schedule.clone())
+            .with_backoff(self.backoff_factor())
+            .with_random(random)
+            .with_shutdown(self.telemetry_tasks.signal());
+            let storage_publisher = self.publisher.clone();
+            self.telemetry_tasks.spawn(async move {
+                storage_usage.run(&storage_publisher).await;
+            });
+        }
+        let disk_guard = self.disk_guard.clone();
+        self.tasks.push(tokio::task::spawn(async move {
+            disk_guard
+                .run(&disk_guard_publisher, disk_guard::DISK_CHECK_PERIOD)
+                .await;
+        }));
+        if self.subsystems.tags {
+            let tags = self.tags.clone();
+            self.tasks.push(tokio::task::spawn(async move {
+                tags.run(&tags_publisher, tags::TAGS_FILE_CHECK_PERIOD)
+                    .await;
+            }));
+        }
+        let safe_mode = self.safe_mode.clone();
+        self.tasks.push(tokio::task::spawn(async move {
+            safe_mode.clear_when_stable().await;
+        }));
+        let send_stats = self.send_stats.clone();
+        self.tasks.push(tokio::task::spawn(async move {
+            send_stats
+                .run(&send_stats_publisher, data::send_stats::SEND_STATS_PERIOD)
+                .await;
+        }));
+        if let Some(backoff) = self.telemetry_backoff.clone() {
+            let backoff_publisher = self.publisher.clone();
+            self.tasks.push(tokio::task::spawn(async move {
+                backoff.run(&backoff_publisher).await;
+            }));
+        }
+        let forward_publisher = self.publisher.clone();
+        self.tasks.push(tokio::task::spawn(async move {
+            forward_publisher.forward_stored().await;
+        }));
+        let interface_versions = self.interface_versions.clone();
+        let interface_versions_publisher = self.publisher.clone();
+        self.tasks.push(tokio::task::spawn(async move {
+            interface_versions
+                .run(
+                    &interface_versions_publisher,
+                    interface_versions::INTERFACES_CHECK_PERIOD,
+                )
+                .await;
+        }));
+        if let Some(mute_events) = self.mute_events.take() {
+            let mutes = self.mutes.clone();
+            let mute_publisher = self.publisher.clone();
+            let refresh_publisher = self.publisher.clone();
+            let tags = self.tags.clone();
+            let subsystems = self.subsystems;
+            self.tasks.push(tokio::task::spawn(async move {
+                data::mute::run(mutes, &mute_publisher, mute_events, |interface_name| {
+                    let publisher = refresh_publisher.clone();
+                    let tags = tags.clone();
+                    async move {
+                        if let Err(err) =
+                            refresh_interface(&publisher, &tags, subsystems, &interface_name).await
+                        {
+                            warn!("Unable to refresh {interface_name}: {:?}", err);
+                        }
+                    }
+                })
+                .await;
+            }));
+        }
+        if let Some(telemetry_flush) = self.telemetry_flush.take() {
+            let flush_publisher = self.publisher.clone();
+            let tags = self.tags.clone();
+            let subsystems = self.subsystems;
+            let interfaces: &[&str] = if subsystems.telemetry {
+                &FLUSHED_INTERFACES
+            } else {
+                &[]
+            };
+            self.tasks.push(tokio::task::spawn(async move {
+                telemetry::flush::run(interfaces, telemetry_flush, |interface_name| {
+                    let publisher = flush_publisher.clone();
+                    let tags = tags.clone();
+                    async move {
+                        if let Err(err) =
+                            refresh_interface(&publisher, &tags, subsystems, &interface_name).await
+                        {
+                            warn!("Unable to send {interface_name}: {:?}", err);
+                        }
+                    }
+                })
+                .await;
+            }));
+        }
 
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let startup = self.startup.clone();
+        let pending_ota_response_done = self.pending_ota_response_done.take();
+        self.tasks.push(tokio::task::spawn(async move {
+            if let Some(pending_ota_response_done) = pending_ota_response_done {
+                if let Ok(duration) = pending_ota_response_done.await {
+                    startup.record("pending_ota_response", duration);
+                }
             }
-        });
+            info!("{}", startup.table());
+            if let Err(err) = startup.publish(&startup_publisher).await {
+                warn!("Unable to publish startup timing: {:?}", err);
+            }
+        }));
+
+        if let Some(interval) = wrapper::systemd::systemd_watchdog_interval() {
+            info!("Watchdog heartbeat every {:?}", interval / 2);
+            let clock = self.clock.clone();
+            let liveness = self.liveness.clone();
+            self.tasks.push(tokio::task::spawn(async move {
+                watchdog::heartbeat(clock.as_ref(), interval, &liveness, || {
+                    platform().notifier().watchdog()
+                })
+                .await;
+            }));
+        }
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        let mut backoff = ReconnectBackoff::default();
 
+        platform().notifier().ready(self.running_status());
+        self.runtime_state
+            .set_connection(ConnectionState::Connected);
         loop {
-            match self.sdk.poll().await {
+            let polled = tokio::select! {
+                polled = self.subscriber.poll() => polled,
+                Some(clientbound) = self.injected.recv() => Ok(clientbound),
+                _ = self.publisher.health().reconnect_requested() => {
+                    self.liveness.working(MAIN_LOOP);
+                    self.reconnect().await;
+                    self.liveness.waiting(MAIN_LOOP);
+                    continue;
+                }
+                reason = &mut shutdown => {
+                    info!("Shutting down, {reason:?}");
+                    return Ok(reason);
+                }
+            };
+
+            let err = match polled {
                 Ok(clientbound) => {
-                    debug!("incoming: {:?}", clientbound);
-
-                    match (
-                        clientbound.interface.as_str(),
-                        clientbound
-                            .path
-                            .trim_matches('/')
-                            .split('/')
-                            .collect::<Vec<&str>>()
-                            .as_slice(),
-                        &clientbound.data,
-                    ) {
-                        (
-                            "io.edgehog.devicemanager.OTARequest",
-                            ["request"],
-                            Aggregation::Object(data),
-                        ) => self.ota_event_channel.send(data.clone()).await.unwrap(),
-
-                        (
-                            "io.edgehog.devicemanager.Commands",
-                            ["request"],
-                            Aggregation::Individual(AstarteType::String(command)),
-                        ) => commands::execute_command(command),
-
-                        _ => {
-                            warn!("Receiving data from an unknown path/interface: {clientbound:?}");
-                        }
+                    if backoff.reset() {
+                        info!("Connection to Astarte restored");
+                        platform().notifier().status(self.running_status());
+                        self.runtime_state
+                            .set_connection(ConnectionState::Connected);
+                        // the restored session may come from a new pairing
+                        self.replay_properties().await;
+                        self.republish_tags().await;
                     }
+                    debug!("incoming: {}", redactor().clientbound(&clientbound));
+                    self.metrics.received(&clientbound.interface);
+                    self.liveness.working(MAIN_LOOP);
+                    self.dispatcher.dispatch(&clientbound).await;
+                    self.liveness.waiting(MAIN_LOOP);
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            self.runtime_state
+                .set_last_error(format!("connection to Astarte failed: {err}"));
+            if reconnect::is_fatal(&err) {
+                log::error!("Connection to Astarte failed, not retrying: {:?}", err);
+                self.runtime_state
+                    .set_connection(ConnectionState::Disconnected);
+                return Err(err.into());
+            }
+            self.runtime_state
+                .set_connection(ConnectionState::Reconnecting);
+            let delay = backoff.failed();
+            self.metrics.reconnect_attempted();
+            log::error!(
+                "Connection to Astarte failed, retrying in {:?} (attempt {}): {:?}",
+                delay,
+                backoff.attempt(),
+                err
+            );
+            platform()
+                .notifier()
+                .status(&format!("Reconnecting (attempt {})", backoff.attempt()));
+            tokio::select! {
+                _ = self.clock.sleep(delay) => {}
+                reason = &mut shutdown => {
+                    info!("Shutting down, {reason:?}");
+                    return Ok(reason);
                 }
-                Err(err) => log::error!("{:?}", err),
             }
         }
     }
 
+    /// Replace the connection whose publishes keep timing out, then send what was queued.
+    async fn reconnect(&mut self) {
+        self.metrics.reconnect_attempted();
+        info!(
+            "Reconnecting to Astarte, {} messages queued",
+            self.publisher.queue().len()
+        );
+        match self.publisher.inner().reconnect().await {
+            Ok(subscriber) => self.subscriber = subscriber,
+            Err(err) => {
+                warn!("Unable to reconnect to Astarte: {:?}", err);
+                return;
+            }
+        }
+
+        self.replay_properties().await;
+
+        if let Err(err) = self.publisher.flush_queue().await {
+            warn!("Unable to flush the offline queue: {:?}", err);
+        }
+        self.republish_tags().await;
+        if let Err(err) = self.publisher.health().publish(&self.publisher).await {
+            warn!("Unable to publish the connection stats: {:?}", err);
+        }
+    }
+
+    /// Send the cached device owned properties again, the new session may not know them.
+    async fn replay_properties(&self) {
+        let replay = self.publisher.inner().replay_properties().await;
+        self.publisher.health().record_replay(&replay);
+    }
+
+    /// Publish every tag again, the new session may not know them.
+    async fn republish_tags(&self) {
+        if !self.subsystems.tags {
+            return;
+        }
+
+        if let Err(err) = self.tags.publish_all(&self.publisher).await {
+            warn!("Unable to publish the device tags: {:?}", err);
+        }
+    }
+
     pub async fn init(&self) -> Result<(), DeviceManagerError> {
-        wrapper::systemd::systemd_notify_status("Sending initial telemetry");
-        self.send_initial_telemetry().await?;
+        if let Err(err) = self.safe_mode.publish(&self.publisher).await {
+            warn!("Unable to publish the safe mode status: {:?}", err);
+        }
+
+        if let Err(err) = StateAudit::publish(&self.publisher, &self.startup_audit).await {
+            warn!("Unable to publish the state audit findings: {:?}", err);
+        }
+
+        if self.subsystems.telemetry {
+            platform().notifier().status("Sending initial telemetry");
+            self.startup
+                .time("init", self.send_initial_telemetry())
+                .await?;
+        }
+
+        // the initial telemetry is the first successful publish
+        self.report_boot_latency(self.startup.elapsed()).await;
+
+        if let Err(err) = self.capabilities.publish(&self.publisher).await {
+            warn!("Unable to publish the capabilities: {:?}", err);
+        }
+
+        if let Some(registration) = self.registration.clone() {
+            self.lifecycle.report(&self.publisher, registration).await;
+        }
+        self.lifecycle
+            .report(&self.publisher, LifecycleEvent::first_connection())
+            .await;
 
         Ok(())
     }
 
+    async fn report_boot_latency(&self, latency: Duration) {
+        let boot_latency = match boot_latency::record_startup(
+            &self.startup_history,
+            self.clock.now_wall(),
+            latency,
+        ) {
+            Ok(boot_latency) => boot_latency,
+            Err(err) => {
+                warn!("Unable to record the startup latency: {:?}", err);
+                return;
+            }
+        };
+
+        info!("Boot to connected latency: {:?}", boot_latency);
+
+        let publisher = self.publisher.clone();
+        if let Err(err) = boot_latency::publish(&publisher, &boot_latency).await {
+            warn!("Unable to publish the startup latency: {:?}", err);
+        }
+    }
+
+    /// Stop the background tasks, logging how long each shutdown phase took.
+    pub async fn shutdown(self) {
+        platform().notifier().stopping("Shutting down");
+        self.runtime_state
+            .set_connection(ConnectionState::Disconnected);
+        let report = TimingReport::new("shutdown", self.clock.clone());
+
+        if let Some(http_status) = self.http_status {
+            report
+                .time(
+                    "http_status",
+                    join_task(
+                        self.clock.as_ref(),
+                        http_status.stop(),
+                        SHUTDOWN_TASK_TIMEOUT,
+                    ),
+                )
+                .await;
+        }
+
+        // a running download is paused right away, a running deploy gets the grace budget
+        self.ota_shutdown.send(true).ok();
+        // closing the queue lets the OTA handler complete the request it is serving
+        drop(self.dispatcher);
+        if let Some(ota_task) = self.ota_worker.stop() {
+            report
+                .time(
+                    "ota_handler",
+                    join_task(self.clock.as_ref(), ota_task, self.ota_shutdown_grace),
+                )
+                .await;
+        }
+
+        // the collectors complete the run in progress, the other tasks are stopped right away
+        report
+            .time(
+                "telemetry",
+                self.telemetry_tasks
+                    .shutdown(self.clock.as_ref(), SHUTDOWN_TASK_TIMEOUT),
+            )
+            .await;
+        report
+            .time("tasks", async {
+                for task in self.tasks {
+                    task.abort();
+                    join_task(self.clock.as_ref(), task, SHUTDOWN_TASK_TIMEOUT).await;
+                }
+            })
+            .await;
+        report
+            .time(
+                "drain",
+                drain(
+                    self.clock.as_ref(),
+                    self.drained_tasks,
+                    &self.publisher,
+                    SHUTDOWN_TASK_TIMEOUT,
+                ),
+            )
+            .await;
+
+        self.send_stats.persist();
+        // nothing is left to send, the connection to Astarte is closed with its last handle
+        drop(self.publisher);
+        drop(self.subscriber);
+
+        info!("{}", report.table());
+
+        drop(self.instance_lock);
+    }
+
+    /// Send the properties describing the device, an interface that can't be collected or sent
+    /// doesn't hold back the others. Returns the first error of the sends.
     pub async fn send_initial_telemetry(&self) -> Result<(), DeviceManagerError> {
-        let device = &self.sdk;
+        let device = &self.publisher;
 
         let data = [
+            (OS_INFO_INTERFACE, telemetry::os_info::get_os_info()),
+            (
+                HARDWARE_INFO_INTERFACE,
+                telemetry::hardware_info::get_hardware_info(),
+            ),
             (
-                "io.edgehog.devicemanager.OSInfo",
-                telemetry::os_info::get_os_info()?,
+                RUNTIME_INFO_INTERFACE,
+                telemetry::runtime_info::get_runtime_info(),
             ),
             (
-                "io.edgehog.devicemanager.HardwareInfo",
-                telemetry::hardware_info::get_hardware_info()?,
+                BASE_IMAGE_INTERFACE,
+                telemetry::base_image::get_base_image(),
             ),
             (
-                "io.edgehog.devicemanager.RuntimeInfo",
-                telemetry::runtime_info::get_runtime_info()?,
+                SYSTEM_INFO_INTERFACE,
+                Ok(telemetry::system_info::get_system_info(
+                    self.system_info.as_ref(),
+                    &HostReader,
+                )),
             ),
         ];
 
+        let mut result: Result<(), DeviceManagerError> = Ok(());
         for (ifc, fields) in data {
-            for (path, data) in fields {
-                device.send(ifc, &path, data).await?;
+            let fields = match fields {
+                Ok(fields) => fields,
+                Err(err) => {
+                    warn!("Unable to collect {}, not sent: {}", ifc, err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = self.sent_properties.send_changed(device, ifc, fields).await {
+                warn!("Unable to send {}: {:?}", ifc, err);
+                if result.is_ok() {
+                    result = Err(err.into());
+                }
             }
         }
 
-        Ok(())
+        if let Err(err) = self.network_interfaces.publish(device).await {
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+
+        result
     }
 }
 
-async fn get_device_id(opt_device_id: Option<String>) -> Result<String, DeviceManagerError> {
-    if let Some(device_id) = opt_device_id {
-        Ok(device_id)
-    } else {
-        get_hardware_id_from_dbus().await
+/// Send a fresh value of `interface_name` right away, when it has a collector that can run once.
+async fn refresh_interface(
+    publisher: &impl Publisher,
+    tags: &Tags<'_>,
+    subsystems: Subsystems,
+    interface_name: &str,
+) -> Result<(), DeviceManagerError> {
+    let fields = match interface_name {
+        OS_INFO_INTERFACE => telemetry::os_info::get_os_info()?,
+        HARDWARE_INFO_INTERFACE => telemetry::hardware_info::get_hardware_info()?,
+        RUNTIME_INFO_INTERFACE => telemetry::runtime_info::get_runtime_info()?,
+        BASE_IMAGE_INTERFACE => telemetry::base_image::get_base_image()?,
+        SYSTEM_STATUS_INTERFACE if subsystems.telemetry => {
+            let collected = Utc::now();
+            let system_status = telemetry::system_status::get_system_status()?;
+            publisher
+                .send_object_with_timestamp(
+                    SYSTEM_STATUS_INTERFACE,
+                    "/systemStatus",
+                    system_status,
+                    collected,
+                )
+                .await?;
+            return Ok(());
+        }
+        SYSTEM_LOAD_INTERFACE if subsystems.telemetry => {
+            let collected = Utc::now();
+            let system_load = telemetry::system_load::get_system_load()?;
+            publisher
+                .send_object_with_timestamp(
+                    SYSTEM_LOAD_INTERFACE,
+                    "/systemLoad",
+                    system_load,
+                    collected,
+                )
+                .await?;
+            return Ok(());
+        }
+        TAGS_INTERFACE if subsystems.tags => {
+            tags.publish_all(publisher).await?;
+            return Ok(());
+        }
+        // the periodic collectors send again at the next period
+        _ => return Ok(()),
+    };
+
+    for (path, data) in fields {
+        publisher.send(interface_name, &path, data).await?;
     }
+
+    Ok(())
 }
 
-async fn get_hardware_id_from_dbus() -> Result<String, DeviceManagerError> {
-    let connection = zbus::Connection::system().await?;
-    let proxy = DeviceProxy::new(&connection).await?;
-    let hardware_id: String = proxy.get_hardware_id("").await?;
-    if hardware_id.is_empty() {
-        return Err(DeviceManagerError::FatalError(
-            "No hardware id provided".to_string(),
-        ));
+/// Whether there is any storage area or mounted filesystem to report the usage of.
+fn storage_usage_reported(areas: &[StorageArea], mounts: Option<&StorageMountOptions>) -> bool {
+    !areas.is_empty() || mounts.map_or(true, StorageMountOptions::is_enabled)
+}
+
+/// Schedule of the telemetry collectors as configured, the running ones keep it up to date.
+fn telemetry_schedule(
+    opts: &DeviceManagerOptions,
+    clock: Arc<dyn Clock>,
+    subsystems: Subsystems,
+    capabilities: &CapabilityReport,
+) -> TelemetrySchedule {
+    let configured = |period_secs: Option<u64>, default: Duration| match period_secs {
+        Some(period_secs) => {
+            CollectorSchedule::new(ScheduleSource::ConfigFile, Duration::from_secs(period_secs))
+        }
+        None => CollectorSchedule::new(ScheduleSource::Default, default),
+    };
+
+    let schedule = TelemetrySchedule::new(clock);
+    schedule.register(
+        SYSTEM_STATUS_INTERFACE,
+        CollectorSchedule {
+            enabled: subsystems.telemetry,
+            ..CollectorSchedule::new(
+                ScheduleSource::Default,
+                telemetry::DEFAULT_SYSTEM_STATUS_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        SYSTEM_LOAD_INTERFACE,
+        CollectorSchedule {
+            enabled: subsystems.telemetry,
+            ..configured(
+                opts.system_load_period_secs,
+                telemetry::system_load::DEFAULT_SYSTEM_LOAD_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        SENSORS_TEMPERATURE_INTERFACE,
+        CollectorSchedule {
+            enabled: subsystems.telemetry,
+            ..configured(
+                opts.temperature_period_secs,
+                telemetry::temperature::DEFAULT_TEMPERATURE_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        NETWORK_SOCKETS_INTERFACE,
+        CollectorSchedule {
+            send_on_change: true,
+            enabled: subsystems.network_sockets
+                && capabilities.is_available(Feature::NetworkSockets),
+            ..configured(
+                opts.network_sockets_period_secs,
+                telemetry::net_sockets::DEFAULT_NETWORK_SOCKETS_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        NETWORK_INTERFACES_INTERFACE,
+        CollectorSchedule {
+            send_on_change: true,
+            enabled: subsystems.telemetry,
+            ..configured(
+                opts.network_interfaces
+                    .as_ref()
+                    .and_then(|options| options.period_secs),
+                telemetry::net_interfaces::DEFAULT_NETWORK_INTERFACES_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        CELLULAR_STATUS_INTERFACE,
+        CollectorSchedule {
+            enabled: subsystems.telemetry && cfg!(feature = "dbus"),
+            ..configured(
+                opts.cellular_connection_period_secs,
+                telemetry::cellular_connection::DEFAULT_CELLULAR_CONNECTION_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        GEOLOCATION_INTERFACE,
+        CollectorSchedule {
+            enabled: subsystems.telemetry && opts.geolocation.is_some(),
+            ..configured(
+                opts.geolocation_period_secs,
+                telemetry::geolocation::DEFAULT_GEOLOCATION_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        WIFI_SCAN_RESULTS_INTERFACE,
+        CollectorSchedule {
+            enabled: subsystems.telemetry && cfg!(feature = "dbus"),
+            ..configured(
+                opts.wifi_scan_period_secs.map(|period_secs| {
+                    period_secs.max(telemetry::wifi_scan::MIN_WIFI_SCAN_PERIOD.as_secs())
+                }),
+                telemetry::wifi_scan::DEFAULT_WIFI_SCAN_PERIOD,
+            )
+        },
+    );
+    schedule.register(
+        STORAGE_USAGE_INTERFACE,
+        CollectorSchedule {
+            enabled: subsystems.storage_usage
+                && storage_usage_reported(
+                    opts.storage_areas.as_deref().unwrap_or_default(),
+                    opts.storage_mounts.as_ref(),
+                ),
+            ..configured(
+                opts.storage_usage_period_secs,
+                telemetry::storage_usage::DEFAULT_STORAGE_USAGE_PERIOD,
+            )
+        },
+    );
+
+    schedule
+}
+
+/// Wait for `task` to complete, giving up after `timeout`.
+async fn join_task(clock: &dyn Clock, task: JoinHandle<()>, timeout: Duration) -> bool {
+    tokio::select! {
+        _ = task => true,
+        _ = clock.sleep(timeout) => {
+            warn!("Task still running after {:?}, leaving it behind", timeout);
+            false
+        }
+    }
+}
+
+/// Let the `drained` tasks publish the messages left in their channels, closed now that the
+/// other tasks are stopped, then send the offline queue, each step bounded by `timeout`.
+async fn drain<P: Publisher>(
+    clock: &dyn Clock,
+    drained: Vec<JoinHandle<()>>,
+    publisher: &DeadlinePublisher<P>,
+    timeout: Duration,
+) {
+    for task in drained {
+        join_task(clock, task, timeout).await;
+    }
+
+    if publisher.queue().len() == 0 {
+        return;
+    }
+    tokio::select! {
+        flushed = publisher.flush_queue() => {
+            if let Err(err) = flushed {
+                warn!("Unable to flush the offline queue: {:?}", err);
+            }
+        }
+        _ = clock.sleep(timeout) => {
+            warn!("{} queued messages left unsent", publisher.queue().len());
+        }
+    }
+}
+
+async fn shutdown_signal() -> ExitReason {
+    let mut terminate = match tokio::signal::unix::signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            warn!("Unable to listen for SIGTERM: {err}");
+            tokio::signal::ctrl_c().await.ok();
+            return ExitReason::Interrupted;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => ExitReason::Terminated,
+        _ = tokio::signal::ctrl_c() => ExitReason::Interrupted,
+    }
+}
+
+/// Serve the D-Bus API `name`, unless the platform has no system bus.
+#[cfg(feature = "dbus")]
+async fn serve_dbus_api(
+    name: &str,
+    serve: impl Future<Output = Result<zbus::Connection, DeviceManagerError>>,
+) -> Option<zbus::Connection> {
+    if !platform().has_system_bus() {
+        debug!("No D-Bus system bus, the {name} API is not served");
+        return None;
+    }
+
+    serve
+        .await
+        .map_err(|err| warn!("Unable to serve the {name} API: {:?}", err))
+        .ok()
+}
+
+/// Resolve the device id the way the runtime does, without starting it.
+pub async fn resolve_device_id(opts: &DeviceManagerOptions) -> Result<String, DeviceManagerError> {
+    get_device_id(opts).await
+}
+
+async fn get_device_id(opts: &DeviceManagerOptions) -> Result<String, DeviceManagerError> {
+    let timeout = opts
+        .hardware_id_timeout_secs
+        .map_or(device_id::DEFAULT_HARDWARE_ID_TIMEOUT, Duration::from_secs);
+    device_id::resolve(
+        opts.device_id.as_deref(),
+        &device_id::namespace(opts)?,
+        || device_id::lookup_hardware_id(&DBusHardwareId, timeout),
+        Path::new(device_id::MACHINE_ID_PATH),
+    )
+    .await
+}
+
+/// TLS settings toward Astarte and its pairing API.
+fn astarte_tls(opts: &DeviceManagerOptions) -> TlsOptions {
+    TlsOptions {
+        accept_invalid_certs: opts.astarte_ignore_ssl_errors.unwrap_or(false),
+        ..Default::default()
+    }
+}
+
+/// Options of the SDK connecting `device_id` to Astarte.
+fn sdk_options(
+    opts: &DeviceManagerOptions,
+    device_id: &str,
+    credentials_secret: &str,
+) -> Result<AstarteOptions, DeviceManagerError> {
+    let mut sdk_options = AstarteOptions::new(
+        &opts.realm,
+        device_id,
+        credentials_secret,
+        &opts.pairing_url,
+    );
+    sdk_options.interface_directory(&opts.interfaces_directory)?;
+    if astarte_tls(opts).accept_invalid_certs {
+        sdk_options.ignore_ssl_errors();
+    }
+
+    Ok(sdk_options.build())
+}
+
+/// File of the credentials secret obtained by the registration of `device_id`.
+fn credentials_repository(opts: &DeviceManagerOptions, device_id: &str) -> FileStateRepository {
+    FileStateRepository::new(
+        opts.store_directory.clone(),
+        format!("credentials_{device_id}.json"),
+    )
+}
+
+/// Store of the credentials secret of `device_id`, the file cached in the keyring when chosen by
+/// `secret_store`.
+fn open_credentials_store(
+    opts: &DeviceManagerOptions,
+    device_id: &str,
+    disk_guard: Option<Arc<DiskGuard>>,
+) -> Result<Box<dyn SecretStore>, DeviceManagerError> {
+    let file =
+        FileSecretStore::new(credentials_repository(opts, device_id).with_disk_guard(disk_guard));
+
+    match opts.secret_store.unwrap_or_default() {
+        SecretStoreKind::File => Ok(Box::new(file)),
+        SecretStoreKind::Keyring => Ok(Box::new(CachedSecretStore::new(
+            file,
+            KeyringSecretStore::new(&format!("credentials_{device_id}")),
+        ))),
     }
-    Ok(hardware_id)
 }
 
 async fn get_credentials_secret(
     device_id: &str,
     opts: &DeviceManagerOptions,
-    cred_state_repo: impl StateRepository<String>,
+    client: &reqwest::Client,
+    clock: &dyn Clock,
+    secret_store: &dyn SecretStore,
 ) -> Result<String, DeviceManagerError> {
     if let Some(secret) = opts.credentials_secret.clone() {
         Ok(secret)
-    } else if cred_state_repo.exists() {
-        get_credentials_secret_from_persistence(cred_state_repo)
-    } else if let Some(token) = opts.pairing_token.clone() {
-        get_credentials_secret_from_registration(device_id, &token, opts, cred_state_repo).await
+    } else if secret_store.exists() {
+        let persisted = get_credentials_secret_from_persistence(secret_store);
+        match (persisted, opts.pairing_token.as_deref()) {
+            (Err(DeviceManagerError::CorruptedCredentials { path, source }), Some(token)) => {
+                warn!("Corrupted credentials secret in {path} ({source}), registering again");
+                get_credentials_secret_from_registration(
+                    device_id,
+                    token,
+                    opts,
+                    client,
+                    clock,
+                    secret_store,
+                )
+                .await
+            }
+            (persisted, _) => persisted,
+        }
+    } else if let Some(token) = opts.pairing_token.as_deref() {
+        get_credentials_secret_from_registration(
+            device_id,
+            token,
+            opts,
+            client,
+            clock,
+            secret_store,
+        )
+        .await
     } else {
-        Err(DeviceManagerError::FatalError(
-            "Missing arguments".to_string(),
-        ))
+        Err(DeviceManagerError::MissingCredentials)
     }
 }
 
 fn get_credentials_secret_from_persistence(
-    cred_state_repo: impl StateRepository<String>,
+    secret_store: &dyn SecretStore,
 ) -> Result<String, DeviceManagerError> {
-    Ok(cred_state_repo.read().expect("Unable to read secret"))
+    secret_store.load().map_err(|err| match err {
+        DeviceManagerError::SerdeJsonError(source) => DeviceManagerError::CorruptedCredentials {
+            path: secret_store.location(),
+            source,
+        },
+        err => DeviceManagerError::Persistence {
+            action: "read",
+            path: secret_store.location(),
+            source: Box::new(err),
+        },
+    })
 }
 
 async fn get_credentials_secret_from_registration(
     device_id: &str,
     token: &str,
     opts: &DeviceManagerOptions,
-    cred_state_repo: impl StateRepository<String>,
+    client: &reqwest::Client,
+    clock: &dyn Clock,
+    secret_store: &dyn SecretStore,
 ) -> Result<String, DeviceManagerError> {
-    let registration =
-        registration::register_device(token, &opts.pairing_url, &opts.realm, &device_id).await;
-    if let Ok(credentials_secret) = registration {
-        cred_state_repo
-            .write(&credentials_secret)
-            .expect("Unable to write secret");
-        Ok(credentials_secret)
-    } else {
-        Err(DeviceManagerError::FatalError("Pairing error".to_string()))
-    }
+    let retry = RegistrationRetry {
+        attempts: opts
+            .registration_attempts
+            .unwrap_or(registration::DEFAULT_REGISTRATION_ATTEMPTS)
+            .max(1),
+        max_duration: opts.registration_retry_secs.map(Duration::from_secs),
+    };
+    let credentials_secret = registration::register_with_retries(
+        clock,
+        &retry,
+        device_id,
+        &registration_url(&opts.pairing_url, &opts.realm),
+        || register_device(client, token, &opts.pairing_url, &opts.realm, device_id),
+    )
+    .await?;
+
+    secret_store
+        .save(&credentials_secret)
+        .map_err(|err| DeviceManagerError::Persistence {
+            action: "persist",
+            path: secret_store.location(),
+            source: Box::new(err),
+        })?;
+
+    Ok(credentials_secret)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::repository::MockStateRepository;
-    use crate::{get_credentials_secret, get_device_id, DeviceManagerError, DeviceManagerOptions};
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use crate::capabilities::{CapabilityReport, Feature};
+    use crate::commands::{self, CommandRequest, CommandResult};
+    use crate::data::deadline::DeadlinePublisher;
+    use crate::data::{MockPublisher, Subscriber};
+    use crate::dispatch::Dispatch;
+    use crate::interfaces::{
+        COMMAND_RESULT_INTERFACE, DIAGNOSTICS_INTERFACE, HARDWARE_INFO_INTERFACE,
+        OS_INFO_INTERFACE, RUNTIME_INFO_INTERFACE, SYSTEM_STATUS_INTERFACE,
+    };
+    use crate::options::ConfigOverrides;
+    use crate::ota::messages::OtaRequest;
+    use crate::repository::file_state_repository::FileStateRepository;
+    use crate::test_utils::harness::{self, ScriptedSession};
+    use crate::test_utils::{settle, ManualClock, ScopedEnv};
+    use crate::{
+        astarte_tls, drain, get_credentials_secret, get_device_id, join_task,
+        open_credentials_store, sdk_options, DeviceManager, DeviceManagerError,
+        DeviceManagerOptions, ExitReason,
+    };
 
     #[tokio::test]
     async fn device_id_test() {
+        let mut options = pairing_options(Path::new("/tmp"), "http://localhost", None);
+        options.device_id = Some("iGMT4TuKU3KbkAya7hmeXQ".to_string());
+        assert_eq!(
+            get_device_id(&options).await.unwrap(),
+            "iGMT4TuKU3KbkAya7hmeXQ".to_string()
+        );
+
+        options.device_id = Some("hw-id".to_string());
         assert_eq!(
-            get_device_id(Some("target".to_string())).await.unwrap(),
-            "target".to_string()
+            get_device_id(&options).await.unwrap(),
+            "_rsyBd76WyWXXjLPDfY7aA".to_string()
         );
+
+        options.device_id_namespace = Some("edgehog".to_string());
+        assert!(matches!(
+            get_device_id(&options).await,
+            Err(DeviceManagerError::InvalidOption { .. })
+        ));
     }
 
     #[tokio::test]
     async fn credentials_secret_test() {
-        let state_mock = MockStateRepository::<String>::new();
+        let state_mock = MockSecretStore::new();
         let options = DeviceManagerOptions {
-            realm: "".to_string(),
-            device_id: None,
             credentials_secret: Some("credentials_secret".to_string()),
-            pairing_url: "".to_string(),
-            pairing_token: None,
-            interfaces_directory: "".to_string(),
-            store_directory: "".to_string(),
-            download_directory: "".to_string(),
+            ..Default::default()
         };
         assert_eq!(
-            get_credentials_secret("device_id", &options, state_mock)
-                .await
-                .unwrap(),
+            get_credentials_secret(
+                "device_id",
+                &options,
+                &reqwest::Client::new(),
+                &ManualClock::new(),
+                &state_mock
+            )
+            .await
+            .unwrap(),
             "credentials_secret".to_string()
         );
     }
 
     #[tokio::test]
     async fn not_enough_arguments_credentials_secret_test() {
-        let mut state_mock = MockStateRepository::<String>::new();
+        let mut state_mock = MockSecretStore::new();
         state_mock.expect_exists().returning(|| false);
-        let options = DeviceManagerOptions {
-            realm: "".to_string(),
-            device_id: None,
-            credentials_secret: None,
-            pairing_url: "".to_string(),
-            pairing_token: None,
-            interfaces_directory: "".to_string(),
-            store_directory: "".to_string(),
-            download_directory: "".to_string(),
-        };
-        assert!(get_credentials_secret("device_id", &options, state_mock)
-            .await
-            .is_err());
+        let options = DeviceManagerOptions::default();
+        assert!(matches!(
+            get_credentials_secret(
+                "device_id",
+                &options,
+                &reqwest::Client::new(),
+                &ManualClock::new(),
+                &state_mock
+            )
+            .await,
+            Err(DeviceManagerError::MissingCredentials)
+        ));
     }
 
     #[tokio::test]
-    #[should_panic(expected = "Unable to read secret: FatalError(\"\")")]
     async fn get_credentials_secret_persistence_fail() {
-        let mut state_mock = MockStateRepository::<String>::new();
+        let mut state_mock = MockSecretStore::new();
         state_mock.expect_exists().returning(|| true);
-        state_mock
-            .expect_read()
-            .returning(move || Err(DeviceManagerError::FatalError("".to_owned())));
+        state_mock.expect_load().returning(move || {
+            Err(DeviceManagerError::IOError(
+                std::io::ErrorKind::PermissionDenied.into(),
+            ))
+        });
 
         let options = DeviceManagerOptions {
-            realm: "".to_string(),
             device_id: Some("device_id".to_owned()),
-            credentials_secret: None,
-            pairing_url: "".to_string(),
-            pairing_token: None,
-            interfaces_directory: "".to_string(),
-            store_directory: "".to_string(),
-            download_directory: "".to_string(),
+            ..Default::default()
         };
 
-        assert!(get_credentials_secret("device_id", &options, state_mock)
-            .await
-            .is_err());
+        let err = get_credentials_secret(
+            "device_id",
+            &options,
+            &reqwest::Client::new(),
+            &ManualClock::new(),
+            &state_mock,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                DeviceManagerError::Persistence { action: "read", source, .. }
+                    if matches!(**source, DeviceManagerError::IOError(_))
+            ),
+            "{err:?}"
+        );
+        assert!(!err.is_retryable());
     }
 
     #[tokio::test]
     async fn get_credentials_secret_persistence_success() {
-        let mut state_mock = MockStateRepository::<String>::new();
+        let mut state_mock = MockSecretStore::new();
         state_mock.expect_exists().returning(|| true);
         state_mock
-            .expect_read()
+            .expect_load()
             .returning(move || Ok("cred_secret".to_owned()));
 
         let options = DeviceManagerOptions {
-            realm: "".to_string(),
             device_id: Some("device_id".to_owned()),
-            credentials_secret: None,
-            pairing_url: "".to_string(),
-            pairing_token: None,
-            interfaces_directory: "".to_string(),
-            store_directory: "".to_string(),
-            download_directory: "".to_string(),
+            ..Default::default()
+        };
+        assert!(get_credentials_secret(
+            "device_id",
+            &options,
+            &reqwest::Client::new(),
+            &ManualClock::new(),
+            &state_mock
+        )
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn new_rejects_invalid_options() {
+        let directory = tempfile::tempdir().unwrap();
+        let config_path = directory.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+                realm = "examplerealm"
+                pairing_url = "astarte.example.com"
+                interfaces_directory = "{0}"
+                store_directory = "{0}"
+                download_directory = "{0}"
+                geolocation_period_secs = 0
+                "#,
+                directory.path().display()
+            ),
+        )
+        .unwrap();
+        let options = {
+            let _env = ScopedEnv::set(&[]);
+            DeviceManagerOptions::from_sources(
+                Some(config_path.to_str().unwrap().to_owned()),
+                &ConfigOverrides::default(),
+            )
+            .unwrap()
         };
-        assert!(get_credentials_secret("device_id", &options, state_mock)
+
+        let err = match DeviceManager::new(options).await {
+            Err(err) => err.to_string(),
+            Ok(_) => panic!("invalid options accepted"),
+        };
+
+        assert!(err.starts_with("invalid configuration: "), "{err}");
+        for problem in [
+            "pairing_url astarte.example.com is not an http(s) URL",
+            "contains no .json interface",
+            "geolocation_period_secs is 0",
+            "no credentials",
+        ] {
+            assert!(err.contains(problem), "{problem} missing from {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn credentials_from_environment_never_persisted() {
+        let directory = tempfile::tempdir().unwrap();
+        let store_directory = directory.path().to_str().unwrap().to_owned();
+        let config = format!(
+            r#"
+            realm = "examplerealm"
+            pairing_url = "https://api.astarte.example.com/pairing"
+            interfaces_directory = "{store_directory}"
+            store_directory = "{store_directory}"
+            download_directory = "{store_directory}"
+            "#
+        );
+        let config_path = directory.path().join("config.toml");
+        std::fs::write(&config_path, &config).unwrap();
+
+        let options = {
+            let _env = ScopedEnv::set(&[
+                ("EDGEHOG_CREDENTIALS_SECRET", "env-credentials-secret"),
+                ("EDGEHOG_PAIRING_TOKEN", "env-pairing-token"),
+            ]);
+            DeviceManagerOptions::from_sources(
+                Some(config_path.to_str().unwrap().to_owned()),
+                &ConfigOverrides::default(),
+            )
+            .unwrap()
+        };
+        let store = FileSecretStore::new(FileStateRepository::new(
+            store_directory,
+            "credentials_device_id.json".to_owned(),
+        ));
+
+        assert_eq!(
+            get_credentials_secret(
+                "device_id",
+                &options,
+                &reqwest::Client::new(),
+                &ManualClock::new(),
+                &store
+            )
             .await
-            .is_ok());
+            .unwrap(),
+            "env-credentials-secret"
+        );
+
+        // only the configuration file is in the store directory, untouched
+        let files: Vec<_> = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["config.toml"]);
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), config);
+    }
+
+    /// Pairing API answering every registration with `status` and `body`.
+    fn pairing_api(status: hyper::StatusCode, body: &'static str) -> std::net::SocketAddr {
+        use std::convert::Infallible;
+        use std::net::SocketAddr;
+
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Response, Server};
+
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_| async move {
+                let response = Response::builder().status(status).body(Body::from(body));
+
+                Ok::<_, Infallible>(response.unwrap())
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        address
+    }
+
+    /// Options registering through `pairing_url`, every directory is `directory`.
+    fn pairing_options(
+        directory: &Path,
+        pairing_url: &str,
+        pairing_token: Option<&str>,
+    ) -> DeviceManagerOptions {
+        let directory = directory.to_str().unwrap();
+        let pairing_token = pairing_token
+            .map(|token| format!("pairing_token = \"{token}\""))
+            .unwrap_or_default();
+        let config_path = format!("{directory}/config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+                realm = "examplerealm"
+                pairing_url = "{pairing_url}"
+                {pairing_token}
+                interfaces_directory = "{directory}"
+                store_directory = "{directory}"
+                download_directory = "{directory}"
+                "#
+            ),
+        )
+        .unwrap();
+
+        let _env = ScopedEnv::set(&[]);
+        DeviceManagerOptions::from_sources(Some(config_path), &ConfigOverrides::default()).unwrap()
+    }
+
+    #[test]
+    fn astarte_certificates_validated_by_default() {
+        let directory = tempfile::tempdir().unwrap();
+        let interfaces = tempfile::tempdir().unwrap();
+        let mut options = pairing_options(
+            directory.path(),
+            "https://api.astarte.example.com/pairing",
+            None,
+        );
+        options.interfaces_directory = interfaces.path().to_str().unwrap().to_owned();
+
+        assert_eq!(options.astarte_ignore_ssl_errors, None);
+        assert!(!astarte_tls(&options).is_set());
+        assert!(sdk_options(&options, "device_id", "secret").is_ok());
+
+        options.astarte_ignore_ssl_errors = Some(true);
+        let tls = astarte_tls(&options);
+        assert!(tls.accept_invalid_certs);
+        // only the validation is relaxed, no certificate is added
+        assert!(tls.ca_certificate.is_none() && tls.client_certificate.is_none());
+        assert!(sdk_options(&options, "device_id", "secret").is_ok());
+    }
+
+    #[tokio::test]
+    async fn registration_failures_mapped() {
+        use std::net::TcpListener;
+
+        let rejecting = pairing_api(hyper::StatusCode::UNAUTHORIZED, "");
+        // nothing listens on the port once the listener is dropped
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        for (address, retryable) in [(rejecting, false), (unreachable, true)] {
+            let directory = tempfile::tempdir().unwrap();
+            // the retries are covered with the registration
+            let options = DeviceManagerOptions {
+                registration_attempts: Some(1),
+                ..pairing_options(
+                    directory.path(),
+                    &format!("http://{address}/pairing"),
+                    Some("expired-token"),
+                )
+            };
+            let store = open_credentials_store(&options, "device_id", None).unwrap();
+
+            let err = get_credentials_secret(
+                "device_id",
+                &options,
+                &reqwest::Client::new(),
+                &ManualClock::new(),
+                &*store,
+            )
+            .await
+            .unwrap_err();
+
+            match &err {
+                DeviceManagerError::Registration {
+                    device_id,
+                    url,
+                    attempts,
+                    source,
+                } => {
+                    assert_eq!(device_id, "device_id");
+                    assert_eq!(*attempts, 1);
+                    assert_eq!(
+                        *url,
+                        format!("http://{address}/pairing/v1/examplerealm/agent/devices")
+                    );
+                    assert!(matches!(**source, DeviceManagerError::ReqwestError(_)));
+                }
+                err => panic!("unexpected error {err:?}"),
+            }
+            assert_eq!(err.is_retryable(), retryable, "{address}");
+            // nothing persisted, the registration is tried again at the next start
+            assert!(!directory.path().join("credentials_device_id.json").exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn persisted_credentials_recovered() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let address = pairing_api(
+            hyper::StatusCode::CREATED,
+            r#"{"data":{"credentials_secret":"s3cr3t"}}"#,
+        );
+        let pairing_url = format!("http://{address}/pairing");
+        let client = reqwest::Client::new();
+        let directory = tempfile::tempdir().unwrap();
+        let credentials_path = directory.path().join("credentials_device_id.json");
+        let secret = |options: DeviceManagerOptions| {
+            let client = client.clone();
+            async move {
+                let store = open_credentials_store(&options, "device_id", None)?;
+                get_credentials_secret("device_id", &options, &client, &ManualClock::new(), &*store)
+                    .await
+            }
+        };
+
+        // missing file, nothing to register with
+        let err = secret(pairing_options(directory.path(), &pairing_url, None))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, DeviceManagerError::MissingCredentials),
+            "{err:?}"
+        );
+
+        // garbage JSON, nothing to register with
+        std::fs::write(&credentials_path, b"\"s3cr\xff").unwrap();
+        let err = secret(pairing_options(directory.path(), &pairing_url, None))
+            .await
+            .unwrap_err();
+        match &err {
+            DeviceManagerError::CorruptedCredentials { path, .. } => {
+                assert_eq!(Path::new(path), credentials_path);
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+        assert!(err.to_string().contains("set pairing_token"), "{err}");
+        assert!(!err.is_retryable());
+        // left in place, the runtime doesn't start without it
+        assert_eq!(std::fs::read(&credentials_path).unwrap(), b"\"s3cr\xff");
+        assert!(!directory
+            .path()
+            .join("credentials_device_id.json.corrupt")
+            .exists());
+
+        // garbage JSON replaced by the registration
+        let options = pairing_options(directory.path(), &pairing_url, Some("token"));
+        assert_eq!(secret(options).await.unwrap(), "s3cr3t");
+        assert_eq!(
+            std::fs::read_to_string(&credentials_path).unwrap(),
+            "\"s3cr3t\""
+        );
+        let mode = std::fs::metadata(&credentials_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // read back at the next start, without registering again
+        let restarted = pairing_options(
+            directory.path(),
+            "http://127.0.0.1:1/pairing",
+            Some("token"),
+        );
+        assert_eq!(secret(restarted).await.unwrap(), "s3cr3t");
+
+        // unwritable store directory
+        let options = DeviceManagerOptions {
+            store_directory: "/dev/null/edgehog".to_owned(),
+            ..pairing_options(directory.path(), &pairing_url, Some("token"))
+        };
+        let err = secret(options).await.unwrap_err();
+        match &err {
+            DeviceManagerError::Persistence {
+                action: "persist",
+                path,
+                source,
+            } => {
+                assert_eq!(path, "/dev/null/edgehog/credentials_device_id.json");
+                assert!(matches!(**source, DeviceManagerError::IOError(_)));
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn command_results_drained_on_shutdown() {
+        let clock = Arc::new(ManualClock::new());
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let recorded = published.clone();
+        let mut results_publisher = MockPublisher::new();
+        results_publisher
+            .expect_send_object()
+            .withf(|interface: &str, path: &str, _: &CommandResult| {
+                interface == COMMAND_RESULT_INTERFACE && path == "/result"
+            })
+            .returning(move |_, _, result: CommandResult| {
+                recorded.lock().unwrap().push(result.command);
+                Ok(())
+            });
+
+        let (results_tx, results_rx) = mpsc::channel(4);
+        for command in ["Reboot", "Shutdown"] {
+            let request = CommandRequest::parse(command);
+            results_tx
+                .send(CommandResult::new(&request, &Ok(())))
+                .await
+                .unwrap();
+        }
+        // a background task holding a sender, stopped before the drain
+        let holder = tokio::spawn(async move {
+            let _results_tx = results_tx;
+            std::future::pending::<()>().await
+        });
+        let drained = tokio::spawn(async move {
+            commands::publish_results(&results_publisher, results_rx).await;
+        });
+        holder.abort();
+
+        let publisher = DeadlinePublisher::new(
+            MockPublisher::new(),
+            clock.clone(),
+            Duration::from_secs(10),
+            HashSet::new(),
+        );
+        drain(
+            clock.as_ref(),
+            vec![drained],
+            &publisher,
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert_eq!(*published.lock().unwrap(), ["Reboot", "Shutdown"]);
+    }
+
+    const COMMANDS: &str = r#"{
+        "interface_name": "io.edgehog.devicemanager.Commands",
+        "version_major": 0,
+        "version_minor": 1,
+        "type": "datastream",
+        "ownership": "server",
+        "mappings": [
+            { "endpoint": "/request", "type": "string" }
+        ]
+    }"#;
+
+    /// Runtime publishing through `session`, storing its state in `directory`.
+    async fn injected_manager(
+        directory: &Path,
+        session: ScriptedSession,
+    ) -> DeviceManager<ScriptedSession> {
+        injected_manager_with(directory, session, "").await
+    }
+
+    /// Like [`injected_manager`], with the top level options in `config` added.
+    async fn injected_manager_with(
+        directory: &Path,
+        session: ScriptedSession,
+        config: &str,
+    ) -> DeviceManager<ScriptedSession> {
+        let interfaces = directory.join("interfaces");
+        std::fs::create_dir(&interfaces).unwrap();
+        std::fs::write(interfaces.join("commands.json"), COMMANDS).unwrap();
+        std::fs::create_dir(directory.join("updates")).unwrap();
+        let config_path = directory.join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+                realm = "examplerealm"
+                pairing_url = "https://api.astarte.example.com/pairing"
+                device_id = "device"
+                credentials_secret = "credentials-secret"
+                interfaces_directory = "{interfaces}"
+                store_directory = "{root}"
+                download_directory = "{root}/updates"
+                ota_download_attempts = 1
+                ota_shutdown_grace_secs = 1
+                telemetry_config_coalesce_millis = 1
+                {config}
+
+                [ota_backend]
+                type = "command"
+                script = "/bin/false"
+                "#,
+                interfaces = interfaces.display(),
+                root = directory.display(),
+            ),
+        )
+        .unwrap();
+        let options = {
+            let _env = ScopedEnv::set(&[]);
+            DeviceManagerOptions::from_sources(
+                Some(config_path.to_str().unwrap().to_owned()),
+                &ConfigOverrides::default(),
+            )
+            .unwrap()
+        };
+
+        let mut dm = match DeviceManager::with_publisher(options, session).await {
+            Ok(dm) => dm,
+            Err(err) => panic!("runtime not started: {err}"),
+        };
+        // the host running the tests may have no system bus
+        dm.dispatcher
+            .set_capabilities(CapabilityReport::available(&[Feature::Ota]));
+
+        dm
+    }
+
+    /// Dispatch the whole script like the run loop does, polling the manager subscriber.
+    async fn dispatch_script(
+        dm: &mut DeviceManager<ScriptedSession>,
+        session: &ScriptedSession,
+    ) -> Vec<Dispatch> {
+        let mut dispatched = Vec::new();
+        while !session.is_exhausted() {
+            let clientbound = dm.subscriber.poll().await.unwrap();
+            dispatched.push(dm.dispatcher.dispatch(&clientbound).await);
+        }
+
+        dispatched
+    }
+
+    /// Wait for `published` to return something, the tasks of the manager run on the system
+    /// clock.
+    async fn published<T>(mut published: impl FnMut() -> Vec<T>) -> Vec<T> {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let found = published();
+                if !found.is_empty() {
+                    return found;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nothing published")
+    }
+
+    #[tokio::test]
+    async fn injected_session_serves_ota_requests() {
+        let directory = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        let session = ScriptedSession::builder(Arc::new(ManualClock::new()))
+            .receive(harness::ota_request_message(OtaRequest::new(
+                uuid,
+                "http://127.0.0.1:1/update.bin",
+            )))
+            .build();
+        let mut dm = injected_manager(directory.path(), session.clone()).await;
+
+        assert_eq!(
+            dispatch_script(&mut dm, &session).await,
+            [Dispatch::Handled]
+        );
+
+        let responses = published(|| session.ota_responses()).await;
+        assert_eq!(responses[0].uuid, uuid);
+        assert_eq!(responses[0].status, "InProgress");
+
+        dm.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn initial_telemetry_continues_past_failed_interface() {
+        let directory = tempfile::tempdir().unwrap();
+        let session = ScriptedSession::builder(Arc::new(ManualClock::new()))
+            .fail_sends_to(OS_INFO_INTERFACE)
+            .build();
+        let dm = injected_manager(directory.path(), session.clone()).await;
+
+        assert!(dm.send_initial_telemetry().await.is_err());
+
+        assert!(!session.sent_to(OS_INFO_INTERFACE).is_empty());
+        for interface in [HARDWARE_INFO_INTERFACE, RUNTIME_INFO_INTERFACE] {
+            assert!(
+                session.sent_to(interface).iter().any(|sent| sent.delivered),
+                "{interface} not sent"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn http_status_counts_ota_attempts() {
+        let directory = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        let session = ScriptedSession::builder(Arc::new(ManualClock::new()))
+            .receive(harness::ota_request_message(OtaRequest::new(
+                uuid,
+                "http://127.0.0.1:1/update.bin",
+            )))
+            .build();
+        let mut dm =
+            injected_manager_with(directory.path(), session.clone(), "http_status_port = 0").await;
+        let address = dm.http_status.as_ref().unwrap().address();
+        let scrape = || async move {
+            reqwest::get(format!("http://{address}/metrics"))
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+        };
+
+        // not running, so not connected
+        let health = reqwest::get(format!("http://{address}/health"))
+            .await
+            .unwrap();
+        assert_eq!(health.status(), 503);
+        assert!(scrape().await.contains("edgehog_ota_attempts_total 0\n"));
+
+        dispatch_script(&mut dm, &session).await;
+        published(|| {
+            session
+                .ota_responses()
+                .into_iter()
+                .filter(|response| response.status == "Error")
+                .collect()
+        })
+        .await;
+
+        let scraped = scrape().await;
+        assert!(
+            scraped.contains("edgehog_ota_attempts_total 1\n"),
+            "{scraped}"
+        );
+        assert!(
+            scraped.contains("edgehog_ota_failures_total 1\n"),
+            "{scraped}"
+        );
+        assert!(
+            scraped.contains("edgehog_ota_successes_total 0\n"),
+            "{scraped}"
+        );
+
+        dm.shutdown().await;
+        assert!(reqwest::get(format!("http://{address}/health"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn injected_session_serves_commands() {
+        let directory = tempfile::tempdir().unwrap();
+        let session = ScriptedSession::builder(Arc::new(ManualClock::new()))
+            .receive(harness::command_message("Benchmark"))
+            .build();
+        let mut dm = injected_manager(directory.path(), session.clone()).await;
+
+        assert_eq!(
+            dispatch_script(&mut dm, &session).await,
+            [Dispatch::Handled]
+        );
+
+        // the benchmarks are disabled by default
+        let refused = published(|| {
+            session
+                .sent_to(DIAGNOSTICS_INTERFACE)
+                .into_iter()
+                .filter(|sent| sent.path == "/benchmarkRefused")
+                .collect()
+        })
+        .await;
+        assert_eq!(refused.len(), 1);
+        assert!(refused[0].delivered);
+
+        dm.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn injected_session_applies_telemetry_config() {
+        let directory = tempfile::tempdir().unwrap();
+        let session = ScriptedSession::builder(Arc::new(ManualClock::new()))
+            .receive(harness::telemetry_config_message(
+                SYSTEM_STATUS_INTERFACE,
+                "periodSeconds",
+                AstarteType::LongInteger(60),
+            ))
+            .build();
+        let mut dm = injected_manager(directory.path(), session.clone()).await;
+
+        assert_eq!(
+            dispatch_script(&mut dm, &session).await,
+            [Dispatch::Handled]
+        );
+
+        tokio::time::timeout(Duration::from_secs(10), dm.telemetry_config.changed())
+            .await
+            .expect("telemetry config not applied")
+            .unwrap();
+        let applied = dm
+            .telemetry_config
+            .borrow()
+            .get(SYSTEM_STATUS_INTERFACE)
+            .cloned();
+        assert_eq!(applied.and_then(|config| config.period_secs), Some(60));
+
+        dm.shutdown().await;
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(ExitReason::Terminated.exit_code(), 0);
+        assert_eq!(ExitReason::Interrupted.exit_code(), 130);
+    }
+
+    #[tokio::test]
+    async fn join_task_gives_up_after_timeout() {
+        let clock = Arc::new(ManualClock::new());
+
+        let completed = tokio::spawn(async {});
+        assert!(join_task(clock.as_ref(), completed, Duration::from_secs(5)).await);
+
+        let stuck = tokio::spawn(std::future::pending::<()>());
+        let join = {
+            let clock = clock.clone();
+            tokio::spawn(
+                async move { join_task(clock.as_ref(), stuck, Duration::from_secs(5)).await },
+            )
+        };
+        settle().await;
+        clock.advance(Duration::from_secs(5));
+
+        assert!(!join.await.unwrap());
     }
 }
@@ -0,0 +1,438 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Device lifecycle milestones, published on `io.edgehog.devicemanager.LifecycleEvents` to build
+//! the activity feed of the device.
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::event_log::{AuditedEvent, EventLog};
+use crate::interfaces::LIFECYCLE_INTERFACE;
+use crate::repository::StateRepository;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LifecycleEventType {
+    FirstRegistration,
+    FirstConnection,
+    OtaApplied,
+}
+
+impl LifecycleEventType {
+    /// Events reported once in the lifetime of the device.
+    fn one_time(self) -> bool {
+        matches!(
+            self,
+            LifecycleEventType::FirstRegistration | LifecycleEventType::FirstConnection
+        )
+    }
+}
+
+impl Display for LifecycleEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LifecycleEventType::FirstRegistration => "firstRegistration",
+            LifecycleEventType::FirstConnection => "firstConnection",
+            LifecycleEventType::OtaApplied => "otaApplied",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifecycleEvent {
+    pub event_type: LifecycleEventType,
+    pub message: String,
+    pub from_version: Option<String>,
+    pub to_version: Option<String>,
+    pub request_id: Option<Uuid>,
+}
+
+impl LifecycleEvent {
+    fn new(event_type: LifecycleEventType, message: impl Into<String>) -> Self {
+        LifecycleEvent {
+            event_type,
+            message: message.into(),
+            from_version: None,
+            to_version: None,
+            request_id: None,
+        }
+    }
+
+    pub fn first_registration(device_id: &str) -> Self {
+        Self::new(
+            LifecycleEventType::FirstRegistration,
+            format!("Device {device_id} registered"),
+        )
+    }
+
+    pub fn first_connection() -> Self {
+        Self::new(
+            LifecycleEventType::FirstConnection,
+            "Device connected for the first time",
+        )
+    }
+
+    pub fn ota_applied(
+        request_id: Uuid,
+        from_version: Option<String>,
+        to_version: Option<String>,
+    ) -> Self {
+        let message = format!(
+            "Update applied, from version {} to {}",
+            from_version.as_deref().unwrap_or("unknown"),
+            to_version.as_deref().unwrap_or("unknown")
+        );
+
+        LifecycleEvent {
+            from_version,
+            to_version,
+            request_id: Some(request_id),
+            ..Self::new(LifecycleEventType::OtaApplied, message)
+        }
+    }
+}
+
+/// Object sent on the lifecycle interface, fields not relevant for the event are empty.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LifecycleEventObject {
+    #[serde(rename = "type")]
+    event_type: String,
+    message: String,
+    from_version: String,
+    to_version: String,
+    request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+}
+
+impl From<LifecycleEvent> for LifecycleEventObject {
+    fn from(event: LifecycleEvent) -> Self {
+        LifecycleEventObject {
+            event_type: event.event_type.to_string(),
+            message: event.message,
+            from_version: event.from_version.unwrap_or_default(),
+            to_version: event.to_version.unwrap_or_default(),
+            request_id: event
+                .request_id
+                .map(|request_id| request_id.to_string())
+                .unwrap_or_default(),
+            sequence: None,
+        }
+    }
+}
+
+/// The one-time events already reported, persisted so restarts don't duplicate them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LifecycleState {
+    pub reported: BTreeSet<LifecycleEventType>,
+}
+
+pub struct Lifecycle {
+    repository: Box<dyn StateRepository<LifecycleState>>,
+    state: Mutex<LifecycleState>,
+    event_log: Option<Arc<EventLog>>,
+}
+
+impl Lifecycle {
+    pub fn new(repository: Box<dyn StateRepository<LifecycleState>>) -> Self {
+        let state = if repository.exists() {
+            repository.read().unwrap_or_else(|err| {
+                warn!("Unable to read the lifecycle state: {:?}", err);
+                LifecycleState::default()
+            })
+        } else {
+            LifecycleState::default()
+        };
+
+        Lifecycle {
+            repository,
+            state: Mutex::new(state),
+            event_log: None,
+        }
+    }
+
+    /// Number the events in `event_log`, when enabled.
+    pub fn with_event_log(mut self, event_log: Option<Arc<EventLog>>) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    /// Publish `event`, returns false if it is a one-time event already reported.
+    ///
+    /// One-time events are only marked as reported once published, so a failed publish is
+    /// retried at the next milestone.
+    pub async fn emit(
+        &self,
+        publisher: &impl Publisher,
+        event: LifecycleEvent,
+    ) -> Result<bool, DeviceManagerError> {
+        let event_type = event.event_type;
+        if event_type.one_time() && self.state.lock().unwrap().reported.contains(&event_type) {
+            return Ok(false);
+        }
+
+        info!("Lifecycle event {event_type}: {}", event.message);
+        let correlation_id = event.request_id.map_or_else(
+            || event_type.to_string(),
+            |request_id| request_id.to_string(),
+        );
+        let object = LifecycleEventObject {
+            sequence: self
+                .event_log
+                .as_ref()
+                .and_then(|event_log| event_log.stamp(AuditedEvent::Lifecycle, &correlation_id)),
+            ..LifecycleEventObject::from(event)
+        };
+        publisher
+            .send_object(LIFECYCLE_INTERFACE, "/event", object)
+            .await?;
+
+        if event_type.one_time() {
+            let mut state = self.state.lock().unwrap();
+            state.reported.insert(event_type);
+            self.repository.write(&state)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Log instead of propagating the failure to publish `event`.
+    pub async fn report(&self, publisher: &impl Publisher, event: LifecycleEvent) {
+        let event_type = event.event_type;
+        if let Err(err) = self.emit(publisher, event).await {
+            warn!(
+                "Unable to publish the lifecycle event {event_type}: {:?}",
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use astarte_sdk::AstarteError;
+    use uuid::Uuid;
+
+    use crate::data::MockPublisher;
+    use crate::event_log::{EventLog, EVENT_LOG_FILE};
+    use crate::interfaces::LIFECYCLE_INTERFACE;
+    use crate::lifecycle::{
+        Lifecycle, LifecycleEvent, LifecycleEventObject, LifecycleEventType, LifecycleState,
+    };
+    use crate::test_utils::{recording_publisher, ManualClock, MemoryStateRepository, Recording};
+
+    /// The `(type, message, fromVersion, toVersion, requestId)` of the lifecycle events sent.
+    pub(crate) fn events(recording: &Recording<LifecycleEventObject>) -> Vec<[String; 5]> {
+        let published = recording.on(LIFECYCLE_INTERFACE);
+        assert!(published.iter().all(|(path, _)| path == "/event"));
+
+        recording
+            .objects(LIFECYCLE_INTERFACE)
+            .into_iter()
+            .map(|event| {
+                [
+                    event.event_type,
+                    event.message,
+                    event.from_version,
+                    event.to_version,
+                    event.request_id,
+                ]
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn one_time_events_survive_restarts() {
+        let repository = Arc::new(MemoryStateRepository::<LifecycleState>::new());
+        let (publisher, recording) = recording_publisher();
+
+        let lifecycle = Lifecycle::new(Box::new(repository.clone()));
+        assert!(lifecycle
+            .emit(&publisher, LifecycleEvent::first_registration("device"))
+            .await
+            .unwrap());
+        assert!(lifecycle
+            .emit(&publisher, LifecycleEvent::first_connection())
+            .await
+            .unwrap());
+        assert!(!lifecycle
+            .emit(&publisher, LifecycleEvent::first_connection())
+            .await
+            .unwrap());
+
+        // a restart reads back what was already reported
+        let lifecycle = Lifecycle::new(Box::new(repository.clone()));
+        assert!(!lifecycle
+            .emit(&publisher, LifecycleEvent::first_registration("device"))
+            .await
+            .unwrap());
+        assert!(!lifecycle
+            .emit(&publisher, LifecycleEvent::first_connection())
+            .await
+            .unwrap());
+
+        let types: Vec<String> = events(&recording)
+            .into_iter()
+            .map(|[event_type, ..]| event_type)
+            .collect();
+        assert_eq!(types, ["firstRegistration", "firstConnection"]);
+        assert_eq!(
+            repository
+                .value()
+                .unwrap()
+                .reported
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [
+                LifecycleEventType::FirstRegistration,
+                LifecycleEventType::FirstConnection
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ota_applied_carries_versions_and_request() {
+        let (publisher, recording) = recording_publisher();
+        let lifecycle = Lifecycle::new(Box::new(MemoryStateRepository::new()));
+        let request_id = Uuid::new_v4();
+
+        for _ in 0..2 {
+            assert!(lifecycle
+                .emit(
+                    &publisher,
+                    LifecycleEvent::ota_applied(
+                        request_id,
+                        Some("1.0.0".to_owned()),
+                        Some("1.1.0".to_owned())
+                    ),
+                )
+                .await
+                .unwrap());
+        }
+        lifecycle
+            .report(
+                &publisher,
+                LifecycleEvent::ota_applied(request_id, None, None),
+            )
+            .await;
+
+        let sent = events(&recording);
+        assert_eq!(sent.len(), 3);
+        assert_eq!(
+            sent[0],
+            [
+                "otaApplied".to_owned(),
+                "Update applied, from version 1.0.0 to 1.1.0".to_owned(),
+                "1.0.0".to_owned(),
+                "1.1.0".to_owned(),
+                request_id.to_string(),
+            ]
+        );
+        assert_eq!(sent[2][2], "");
+        assert_eq!(sent[2][3], "");
+    }
+
+    #[tokio::test]
+    async fn events_numbered_in_the_event_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let event_log = Arc::new(EventLog::new(
+            Arc::new(ManualClock::new()),
+            Box::new(MemoryStateRepository::new()),
+            directory.path().join(EVENT_LOG_FILE),
+            1024,
+        ));
+        let lifecycle = Lifecycle::new(Box::new(MemoryStateRepository::new()))
+            .with_event_log(Some(event_log.clone()));
+        let request_id = Uuid::new_v4();
+
+        let sequences = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sequences.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object()
+            .returning(move |_, _, event: LifecycleEventObject| {
+                recorded.lock().unwrap().push(event.sequence);
+                Ok(())
+            });
+
+        lifecycle
+            .report(&publisher, LifecycleEvent::first_connection())
+            .await;
+        // already reported, not numbered
+        lifecycle
+            .report(&publisher, LifecycleEvent::first_connection())
+            .await;
+        lifecycle
+            .report(
+                &publisher,
+                LifecycleEvent::ota_applied(request_id, None, None),
+            )
+            .await;
+
+        assert_eq!(*sequences.lock().unwrap(), [Some(1), Some(2)]);
+        let correlation_ids: Vec<String> = event_log
+            .records()
+            .unwrap()
+            .into_iter()
+            .map(|record| record.correlation_id)
+            .collect();
+        assert_eq!(
+            correlation_ids,
+            ["firstConnection".to_owned(), request_id.to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_publish_is_not_marked_reported() {
+        let repository = Arc::new(MemoryStateRepository::<LifecycleState>::new());
+        let lifecycle = Lifecycle::new(Box::new(repository.clone()));
+
+        let mut failing = MockPublisher::new();
+        failing
+            .expect_send_object()
+            .returning(|_, _, _: LifecycleEventObject| {
+                Err(AstarteError::SendError("test".to_owned()))
+            });
+        assert!(lifecycle
+            .emit(&failing, LifecycleEvent::first_connection())
+            .await
+            .is_err());
+        assert!(repository.value().is_none());
+
+        let (publisher, recording) = recording_publisher();
+        assert!(lifecycle
+            .emit(&publisher, LifecycleEvent::first_connection())
+            .await
+            .unwrap());
+        assert_eq!(events(&recording).len(), 1);
+    }
+}
@@ -0,0 +1,361 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Permissions of the local clients of the APIs publishing through the runtime.
+//!
+//! A client is known by the credentials of its Unix socket peer or by the name it sends its
+//! D-Bus messages from. Only the configured clients are allowed, each on the interfaces matching
+//! its patterns; the denied requests are counted on the diagnostics interface.
+
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use astarte_sdk::types::AstarteType;
+use log::warn;
+use serde::Deserialize;
+use tokio::net::UnixStream;
+use tokio::sync::watch;
+
+use crate::data::Publisher;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LocalAccessOptions {
+    #[serde(default)]
+    pub clients: Vec<ClientPermissions>,
+}
+
+/// What a client may do, the client is matched by all the identity fields set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClientPermissions {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Name the D-Bus messages are sent from, `*` matches any sequence of characters.
+    pub dbus_sender: Option<String>,
+    /// Interfaces the client may publish on, `*` matches any sequence of characters.
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// Whether the client may receive the data sent to the device.
+    #[serde(default)]
+    pub subscribe: bool,
+}
+
+impl ClientPermissions {
+    fn applies_to(&self, client: &LocalClient) -> bool {
+        match client {
+            LocalClient::Unix { uid, gid } => {
+                self.dbus_sender.is_none()
+                    && (self.uid.is_some() || self.gid.is_some())
+                    && self.uid.map_or(true, |allowed| allowed == *uid)
+                    && self.gid.map_or(true, |allowed| allowed == *gid)
+            }
+            LocalClient::DBus { sender } => {
+                self.uid.is_none()
+                    && self.gid.is_none()
+                    && self
+                        .dbus_sender
+                        .as_deref()
+                        .map_or(false, |pattern| matches(pattern, sender))
+            }
+        }
+    }
+}
+
+// the sockets are only served by the simulator builds
+#[cfg_attr(not(any(test, feature = "simulator")), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalClient {
+    Unix { uid: u32, gid: u32 },
+    DBus { sender: String },
+}
+
+#[cfg_attr(not(any(test, feature = "simulator")), allow(dead_code))]
+impl LocalClient {
+    /// The credentials of the process on the other end of `stream`.
+    pub fn peer(stream: &UnixStream) -> std::io::Result<Self> {
+        let credentials = stream.peer_cred()?;
+
+        Ok(LocalClient::Unix {
+            uid: credentials.uid(),
+            gid: credentials.gid(),
+        })
+    }
+}
+
+impl Display for LocalClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalClient::Unix { uid, gid } => write!(f, "uid {uid} gid {gid}"),
+            LocalClient::DBus { sender } => write!(f, "D-Bus sender {sender}"),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AccessError {
+    #[error("{client} may not publish on {interface}")]
+    Publish { client: String, interface: String },
+    #[error("{client} may not subscribe")]
+    Subscribe { client: String },
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any sequence of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some((last, middle)) => (last, middle),
+        // no wildcard
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+pub struct LocalAccess {
+    clients: Vec<ClientPermissions>,
+    denied: AtomicU64,
+    denied_tx: watch::Sender<u64>,
+}
+
+impl LocalAccess {
+    /// Allow the clients in `options`, deny everyone else.
+    pub fn new(options: &LocalAccessOptions) -> Self {
+        LocalAccess {
+            clients: options.clients.clone(),
+            denied: AtomicU64::new(0),
+            denied_tx: watch::channel(0).0,
+        }
+    }
+
+    fn permissions<'a>(
+        &'a self,
+        client: &'a LocalClient,
+    ) -> impl Iterator<Item = &'a ClientPermissions> + 'a {
+        self.clients
+            .iter()
+            .filter(move |permissions| permissions.applies_to(client))
+    }
+
+    pub fn check_publish(&self, client: &LocalClient, interface: &str) -> Result<(), AccessError> {
+        let allowed = self.permissions(client).any(|permissions| {
+            permissions
+                .publish
+                .iter()
+                .any(|pattern| matches(pattern, interface))
+        });
+        if allowed {
+            return Ok(());
+        }
+
+        Err(self.deny(AccessError::Publish {
+            client: client.to_string(),
+            interface: interface.to_owned(),
+        }))
+    }
+
+    // the runtime has no local subscription API yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn check_subscribe(&self, client: &LocalClient) -> Result<(), AccessError> {
+        if self
+            .permissions(client)
+            .any(|permissions| permissions.subscribe)
+        {
+            return Ok(());
+        }
+
+        Err(self.deny(AccessError::Subscribe {
+            client: client.to_string(),
+        }))
+    }
+
+    fn deny(&self, err: AccessError) -> AccessError {
+        warn!("Local request denied: {err}");
+        let denied = self.denied.fetch_add(1, Ordering::SeqCst) + 1;
+        self.denied_tx.send_replace(denied);
+
+        err
+    }
+
+    /// Publish the number of denied requests on `/localAccess/denied` every time it grows.
+    pub async fn report(&self, publisher: &impl Publisher) {
+        let mut denied = self.denied_tx.subscribe();
+        while denied.changed().await.is_ok() {
+            let count = *denied.borrow();
+            if let Err(err) = publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    "/localAccess/denied",
+                    AstarteType::LongInteger(count as i64),
+                )
+                .await
+            {
+                warn!("Unable to publish the denied local requests: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::net::UnixStream;
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::local_access::{
+        matches, AccessError, ClientPermissions, LocalAccess, LocalAccessOptions, LocalClient,
+    };
+    use crate::test_utils::settle;
+
+    /// Client on the other end of a socket pair, with the credentials of the test process.
+    fn socket_client() -> (LocalClient, u32, u32) {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let client = LocalClient::peer(&stream).unwrap();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        assert_eq!(client, LocalClient::Unix { uid, gid });
+
+        (client, uid, gid)
+    }
+
+    fn access(clients: Vec<ClientPermissions>) -> LocalAccess {
+        LocalAccess::new(&LocalAccessOptions { clients })
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(matches("*", "io.edgehog.devicemanager.OTAResponse"));
+        assert!(matches(
+            "io.edgehog.devicemanager.*",
+            "io.edgehog.devicemanager.OTAResponse"
+        ));
+        assert!(matches("com.*.Sensors*", "com.example.SensorsV2"));
+        assert!(matches("com.example.Sensors", "com.example.Sensors"));
+        assert!(!matches("com.example.Sensors", "com.example.SensorsV2"));
+        assert!(!matches("com.*.Sensors", "io.edgehog.Sensors"));
+        assert!(!matches("com.*.Sensors", "com.example.Sensors.Other"));
+    }
+
+    #[tokio::test]
+    async fn socket_peer_allowed_on_its_interfaces() {
+        let (client, uid, gid) = socket_client();
+        let access = access(vec![ClientPermissions {
+            uid: Some(uid),
+            gid: Some(gid),
+            publish: vec!["com.example.*".to_owned()],
+            subscribe: true,
+            ..Default::default()
+        }]);
+
+        assert_eq!(access.check_publish(&client, "com.example.Sensors"), Ok(()));
+        assert_eq!(access.check_subscribe(&client), Ok(()));
+        assert_eq!(
+            access.check_publish(&client, "io.edgehog.devicemanager.OTAResponse"),
+            Err(AccessError::Publish {
+                client: format!("uid {uid} gid {gid}"),
+                interface: "io.edgehog.devicemanager.OTAResponse".to_owned(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_peers_denied_by_default() {
+        let (client, uid, _) = socket_client();
+
+        let nobody = access(Vec::new());
+        assert!(nobody
+            .check_publish(&client, "com.example.Sensors")
+            .is_err());
+        assert!(nobody.check_subscribe(&client).is_err());
+
+        let other_user = access(vec![ClientPermissions {
+            uid: Some(uid + 1),
+            publish: vec!["*".to_owned()],
+            subscribe: true,
+            ..Default::default()
+        }]);
+        assert!(other_user
+            .check_publish(&client, "com.example.Sensors")
+            .is_err());
+        assert!(other_user.check_subscribe(&client).is_err());
+
+        // the D-Bus permissions don't apply to the socket peers
+        let dbus_only = access(vec![ClientPermissions {
+            dbus_sender: Some("*".to_owned()),
+            publish: vec!["*".to_owned()],
+            ..Default::default()
+        }]);
+        assert!(dbus_only
+            .check_publish(&client, "com.example.Sensors")
+            .is_err());
+        assert_eq!(
+            dbus_only.check_publish(
+                &LocalClient::DBus {
+                    sender: ":1.42".to_owned()
+                },
+                "com.example.Sensors"
+            ),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn denials_counted_on_diagnostics() {
+        let (client, _, _) = socket_client();
+        let access = Arc::new(access(Vec::new()));
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, count| {
+                interface == DIAGNOSTICS_INTERFACE
+                    && path == "/localAccess/denied"
+                    && *count == AstarteType::LongInteger(2)
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let reporting = access.clone();
+        let report = tokio::spawn(async move { reporting.report(&publisher).await });
+        settle().await;
+
+        assert!(access
+            .check_publish(&client, "com.example.Sensors")
+            .is_err());
+        assert!(access.check_subscribe(&client).is_err());
+        settle().await;
+
+        report.abort();
+    }
+}
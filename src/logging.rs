@@ -0,0 +1,138 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Logger configured with `RUST_LOG`, whose level can be raised at runtime for the runtime
+//! modules.
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use env_logger::fmt::Formatter;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::wrapper::platform::PlatformKind;
+
+/// Level of the runtime modules while verbose.
+const VERBOSE_LEVEL: LevelFilter = LevelFilter::Debug;
+const RUNTIME_TARGET: &str = "edgehog_device_runtime";
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static JOURNAL: AtomicBool = AtomicBool::new(false);
+
+/// Where the standard error ends up, deciding the format of the records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSink {
+    /// journald, timestamping the records and reading their priority from a `<N>` prefix.
+    Journal,
+    /// A log file or a terminal.
+    Stderr,
+}
+
+struct RuntimeLogger {
+    configured: env_logger::Logger,
+    verbose: env_logger::Logger,
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.configured.enabled(metadata) || (is_verbose() && self.verbose.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.configured.matches(record) {
+            self.configured.log(record);
+        } else if is_verbose() {
+            self.verbose.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.configured.flush();
+    }
+}
+
+/// Syslog priority of `level`.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn format(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    if JOURNAL.load(Ordering::Relaxed) {
+        writeln!(
+            buf,
+            "<{}>{}: {}",
+            priority(record.level()),
+            record.target(),
+            record.args()
+        )
+    } else {
+        writeln!(
+            buf,
+            "[{} {:<5} {}] {}",
+            buf.timestamp(),
+            record.level(),
+            record.target(),
+            record.args()
+        )
+    }
+}
+
+/// Install the logger, in place of `env_logger::init`, for the detected platform.
+pub fn init() {
+    set_sink(PlatformKind::detect(Path::new("/")).log_sink());
+
+    let configured = env_logger::Builder::from_default_env()
+        .format(format)
+        .build();
+    let verbose = env_logger::Builder::new()
+        .filter_module(RUNTIME_TARGET, VERBOSE_LEVEL)
+        .format(format)
+        .build();
+    let max_level = configured.filter().max(VERBOSE_LEVEL);
+
+    if log::set_boxed_logger(Box::new(RuntimeLogger {
+        configured,
+        verbose,
+    }))
+    .is_ok()
+    {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Log the runtime modules at the debug level too, whatever `RUST_LOG` says.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::SeqCst);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::SeqCst)
+}
+
+/// Format the records for `sink`, from now on.
+pub fn set_sink(sink: LogSink) {
+    JOURNAL.store(sink == LogSink::Journal, Ordering::Relaxed);
+}
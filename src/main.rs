@@ -18,86 +18,183 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::any::Any;
 use std::fs;
-#[cfg(feature = "systemd")]
-use std::panic::{self, PanicInfo};
+use std::os::unix::fs::DirBuilderExt;
+use std::panic::{self, Location};
 use std::path::Path;
 
-use config::read_options;
+#[cfg(feature = "dbus")]
+use edgehog_device_runtime::benchmark::{self, BenchmarkRequest};
 use edgehog_device_runtime::error::DeviceManagerError;
-
-mod config;
+use edgehog_device_runtime::options::ConfigOverrides;
+#[cfg(feature = "dbus")]
+use edgehog_device_runtime::status;
+use edgehog_device_runtime::wrapper::platform::platform;
+use edgehog_device_runtime::{logging, resolve_device_id, DeviceManagerOptions};
 
 //Error code state not recoverable
-#[allow(unused)]
 const ENOTRECOVERABLE: i32 = 131;
 
 #[derive(Debug, Parser)]
 struct Cli {
     /// Override configuration file path
-    #[clap(short, long)]
+    #[clap(short, long, alias = "config")]
     configuration_file: Option<String>,
+
+    #[clap(flatten)]
+    overrides: ConfigOverrides,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the connection, OTA and telemetry status of the running instance
+    #[cfg(feature = "dbus")]
+    Status,
+    /// Validate a configuration file, printing every error found
+    CheckConfig { path: String },
+    /// Print the device id, without starting the runtime
+    ShowDeviceId,
+    /// Have the running instance measure its publish throughput, benchmarks must be enabled
+    #[cfg(feature = "dbus")]
+    Benchmark {
+        #[clap(long, default_value_t = 100)]
+        samples: u32,
+        #[clap(long, default_value_t = 64)]
+        payload_bytes: u32,
+        #[clap(long, default_value_t = 1)]
+        concurrency: u32,
+    },
+    /// Have the running instance publish a test event on the diagnostics interface
+    #[cfg(all(debug_assertions, feature = "dbus"))]
+    SendTestEvent {
+        #[clap(default_value = "test event")]
+        message: String,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), edgehog_device_runtime::error::DeviceManagerError> {
-    env_logger::init();
-    #[cfg(feature = "systemd")]
-    {
-        let default_panic_hook = panic::take_hook();
-        panic::set_hook(Box::new(move |panic_info| {
-            default_panic_hook(panic_info);
-            systemd_panic_hook(panic_info);
-        }));
+async fn main() {
+    logging::init();
+    let default_panic_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        default_panic_hook(panic_info);
+        notify_panic(panic_info.payload(), panic_info.location());
+    }));
+
+    // the exit status tells the service manager whether a restart may help
+    if let Err(err) = run(Parser::parse()).await {
+        eprintln!("Error: {err:?}");
+        std::process::exit(err.exit_code());
     }
+}
+
+async fn run(cli: Cli) -> Result<(), DeviceManagerError> {
     let Cli {
         configuration_file: config_file_path,
-    } = Parser::parse();
-
-    let options = read_options(config_file_path)?;
-
-    if !Path::new(&options.download_directory).exists() {
-        fs::create_dir_all(&options.download_directory).map_err(|err| {
-            DeviceManagerError::FatalError(
-                "Unable to create OTA download directory. ".to_owned() + &err.to_string(),
-            )
-        })?;
+        overrides,
+        command,
+    } = cli;
+
+    match command {
+        #[cfg(feature = "dbus")]
+        Some(Command::Status) => {
+            print!("{}", status::query().await?);
+            return Ok(());
+        }
+        Some(Command::CheckConfig { path }) => return check_config(&path),
+        Some(Command::ShowDeviceId) => {
+            let options = DeviceManagerOptions::from_sources(config_file_path, &overrides)?;
+            println!("{}", resolve_device_id(&options).await?);
+            return Ok(());
+        }
+        #[cfg(feature = "dbus")]
+        Some(Command::Benchmark {
+            samples,
+            payload_bytes,
+            concurrency,
+        }) => {
+            let request = BenchmarkRequest {
+                samples,
+                payload_bytes,
+                concurrency,
+            };
+            print!("{}", benchmark::request(request).await?);
+            return Ok(());
+        }
+        #[cfg(all(debug_assertions, feature = "dbus"))]
+        Some(Command::SendTestEvent { message }) => {
+            return status::request_test_event(&message).await;
+        }
+        None => {}
     }
 
-    if !Path::new(&options.store_directory).exists() {
-        fs::create_dir_all(&options.store_directory).map_err(|err| {
-            DeviceManagerError::FatalError(
-                "Unable to create store directory. ".to_owned() + &err.to_string(),
-            )
-        })?;
+    let options = DeviceManagerOptions::from_sources(config_file_path, &overrides)?;
+
+    // the store directory holds the credentials secret
+    for (directory, mode) in [
+        (&options.download_directory, 0o755),
+        (&options.store_directory, 0o700),
+    ] {
+        if !Path::new(directory).exists() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(mode)
+                .create(directory)
+                .map_err(|err| DeviceManagerError::Persistence {
+                    action: "create",
+                    path: directory.clone(),
+                    source: Box::new(err.into()),
+                })?;
+        }
     }
 
     let mut dm = edgehog_device_runtime::DeviceManager::new(options).await?;
 
     dm.init().await?;
 
-    dm.run().await;
+    let exit_reason = dm.run().await;
+
+    dm.shutdown().await;
 
-    Ok(())
+    match exit_reason?.exit_code() {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
 }
 
-#[cfg(feature = "systemd")]
-fn systemd_panic_hook(panic_info: &PanicInfo) {
-    use edgehog_device_runtime::wrapper;
+fn check_config(path: &str) -> Result<(), DeviceManagerError> {
+    let errors = DeviceManagerOptions::from_file(Path::new(path))?.validate();
+    if errors.is_empty() {
+        println!("{path}: ok");
+        return Ok(());
+    }
+
+    for error in &errors {
+        println!("{path}: {error}");
+    }
+    Err(DeviceManagerError::Configuration(errors))
+}
 
-    let message = if let Some(panic_msg) = panic_info.payload().downcast_ref::<&str>() {
+/// Notify the panic with its `payload` and `location`, the panic info type itself is named
+/// differently across the supported toolchains.
+fn notify_panic(payload: &(dyn Any + Send), location: Option<&Location>) {
+    let message = if let Some(panic_msg) = payload.downcast_ref::<&str>() {
         panic_msg
     } else {
         "panic occurred"
     };
 
-    let location = if let Some(location) = panic_info.location() {
+    let location = if let Some(location) = location {
         format!("in file '{}' at line {}", location.file(), location.line(),)
     } else {
         "".to_string()
     };
 
     let status = format!("{} {}", message, location);
-    wrapper::systemd::systemd_notify_errno_status(ENOTRECOVERABLE, &status);
+    platform().notifier().errno(ENOTRECOVERABLE, &status);
 }
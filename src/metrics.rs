@@ -0,0 +1,226 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Counters of what the runtime did since it started, rendered in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::ota::verification::EnforcementOptions;
+
+/// Counters updated by the main loop, the telemetry loop and the OTA handler.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    /// Messages received from Astarte, by interface.
+    received: Mutex<BTreeMap<String, u64>>,
+    telemetry_sends: AtomicU64,
+    ota_attempts: AtomicU64,
+    ota_successes: AtomicU64,
+    ota_failures: AtomicU64,
+    /// Labels of the OTA counters with the enforcement mode of each content check.
+    ota_enforcement: Mutex<String>,
+    reconnect_attempts: AtomicU64,
+}
+
+impl Metrics {
+    pub fn received(&self, interface: &str) {
+        let mut received = self.received.lock().unwrap();
+        match received.get_mut(interface) {
+            Some(count) => *count += 1,
+            None => {
+                received.insert(interface.to_owned(), 1);
+            }
+        }
+    }
+
+    pub fn telemetry_sent(&self) {
+        self.telemetry_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ota_attempted(&self) {
+        self.ota_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ota_succeeded(&self) {
+        self.ota_successes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ota_failed(&self) {
+        self.ota_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Label the OTA counters with the enforcement modes, so the outcomes can be segmented.
+    pub fn ota_enforcement(&self, enforcement: &EnforcementOptions) {
+        *self.ota_enforcement.lock().unwrap() = format!(
+            "{{checksum_mode=\"{}\",signature_mode=\"{}\"}}",
+            enforcement.checksum, enforcement.signature
+        );
+    }
+
+    pub fn reconnect_attempted(&self) {
+        self.reconnect_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        header(
+            &mut text,
+            "edgehog_messages_received_total",
+            "Messages received from Astarte, by interface.",
+        );
+        for (interface, count) in self.received.lock().unwrap().iter() {
+            writeln!(
+                text,
+                "edgehog_messages_received_total{{interface=\"{}\"}} {count}",
+                escape_label(interface)
+            )
+            .ok();
+        }
+
+        let ota_labels = self.ota_enforcement.lock().unwrap().clone();
+        for (name, help, labels, counter) in [
+            (
+                "edgehog_telemetry_sends_total",
+                "System status telemetry sent.",
+                "",
+                &self.telemetry_sends,
+            ),
+            (
+                "edgehog_ota_attempts_total",
+                "OTA updates started.",
+                ota_labels.as_str(),
+                &self.ota_attempts,
+            ),
+            (
+                "edgehog_ota_successes_total",
+                "OTA updates completed.",
+                ota_labels.as_str(),
+                &self.ota_successes,
+            ),
+            (
+                "edgehog_ota_failures_total",
+                "OTA updates failed.",
+                ota_labels.as_str(),
+                &self.ota_failures,
+            ),
+            (
+                "edgehog_reconnect_attempts_total",
+                "Attempts to reconnect to Astarte.",
+                "",
+                &self.reconnect_attempts,
+            ),
+        ] {
+            header(&mut text, name, help);
+            writeln!(text, "{name}{labels} {}", counter.load(Ordering::Relaxed)).ok();
+        }
+
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, help: &str) {
+    writeln!(text, "# HELP {name} {help}").ok();
+    writeln!(text, "# TYPE {name} counter").ok();
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::Metrics;
+    use crate::ota::verification::{EnforcementMode, EnforcementOptions};
+
+    #[test]
+    fn counters_rendered() {
+        let metrics = Metrics::default();
+        metrics.received("io.edgehog.devicemanager.Commands");
+        metrics.received("io.edgehog.devicemanager.OTARequest");
+        metrics.received("io.edgehog.devicemanager.Commands");
+        metrics.telemetry_sent();
+        metrics.ota_attempted();
+        metrics.ota_failed();
+        metrics.reconnect_attempted();
+        metrics.reconnect_attempted();
+
+        assert_eq!(
+            metrics.render(),
+            "\
+# HELP edgehog_messages_received_total Messages received from Astarte, by interface.
+# TYPE edgehog_messages_received_total counter
+edgehog_messages_received_total{interface=\"io.edgehog.devicemanager.Commands\"} 2
+edgehog_messages_received_total{interface=\"io.edgehog.devicemanager.OTARequest\"} 1
+# HELP edgehog_telemetry_sends_total System status telemetry sent.
+# TYPE edgehog_telemetry_sends_total counter
+edgehog_telemetry_sends_total 1
+# HELP edgehog_ota_attempts_total OTA updates started.
+# TYPE edgehog_ota_attempts_total counter
+edgehog_ota_attempts_total 1
+# HELP edgehog_ota_successes_total OTA updates completed.
+# TYPE edgehog_ota_successes_total counter
+edgehog_ota_successes_total 0
+# HELP edgehog_ota_failures_total OTA updates failed.
+# TYPE edgehog_ota_failures_total counter
+edgehog_ota_failures_total 1
+# HELP edgehog_reconnect_attempts_total Attempts to reconnect to Astarte.
+# TYPE edgehog_reconnect_attempts_total counter
+edgehog_reconnect_attempts_total 2
+"
+        );
+    }
+
+    #[test]
+    fn ota_counters_labelled_with_the_enforcement_modes() {
+        let metrics = Metrics::default();
+        metrics.ota_enforcement(&EnforcementOptions {
+            checksum: EnforcementMode::Warn,
+            signature: EnforcementMode::Enforce,
+        });
+        metrics.ota_attempted();
+        metrics.ota_succeeded();
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "edgehog_ota_attempts_total{checksum_mode=\"warn\",signature_mode=\"enforce\"} 1\n"
+        ));
+        assert!(rendered.contains(
+            "edgehog_ota_successes_total{checksum_mode=\"warn\",signature_mode=\"enforce\"} 1\n"
+        ));
+        assert!(rendered.contains("edgehog_telemetry_sends_total 0\n"));
+    }
+
+    #[test]
+    fn label_values_escaped() {
+        let metrics = Metrics::default();
+        metrics.received("a\"b\\c");
+
+        assert!(metrics
+            .render()
+            .contains(r#"edgehog_messages_received_total{interface="a\"b\\c"} 1"#));
+    }
+}
@@ -0,0 +1,221 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use astarte_sdk::types::AstarteType;
+#[cfg(feature = "dbus")]
+use async_trait::async_trait;
+#[cfg(feature = "dbus")]
+use futures_util::StreamExt;
+#[cfg(feature = "dbus")]
+use log::info;
+use log::{debug, warn};
+use tokio::sync::watch;
+#[cfg(feature = "dbus")]
+use zbus::dbus_proxy;
+#[cfg(feature = "dbus")]
+use zbus::PropertyStream;
+
+use crate::data::Publisher;
+#[cfg(feature = "dbus")]
+use crate::error::DeviceManagerError;
+use crate::interfaces::CONNECTION_STATS_INTERFACE;
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::system_bus;
+
+/// Values of the `NMMetered` enum exposed by NetworkManager.
+#[cfg(feature = "dbus")]
+const NM_METERED_YES: u32 = 1;
+#[cfg(feature = "dbus")]
+const NM_METERED_GUESS_YES: u32 = 3;
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    /// Whether the connectivity is currently provided by a metered connection.
+    #[dbus_proxy(property)]
+    fn metered(&self) -> zbus::Result<u32>;
+}
+
+/// Source of the NetworkManager `Metered` property and of its changes.
+#[cfg(feature = "dbus")]
+#[async_trait]
+pub trait MeteredSource: Send {
+    async fn current(&mut self) -> Result<u32, DeviceManagerError>;
+    /// Wait for the next change, `None` when no more changes will be reported.
+    async fn next_change(&mut self) -> Option<u32>;
+}
+
+#[cfg(feature = "dbus")]
+struct NetworkManagerMetered {
+    changes: PropertyStream<'static, u32>,
+    proxy: NetworkManagerProxy<'static>,
+}
+
+#[cfg(feature = "dbus")]
+#[async_trait]
+impl MeteredSource for NetworkManagerMetered {
+    async fn current(&mut self) -> Result<u32, DeviceManagerError> {
+        Ok(self.proxy.metered().await?)
+    }
+
+    async fn next_change(&mut self) -> Option<u32> {
+        let change = self.changes.next().await?;
+        change.get().await.ok()
+    }
+}
+
+#[cfg(feature = "dbus")]
+pub fn is_metered(nm_metered: u32) -> bool {
+    matches!(nm_metered, NM_METERED_YES | NM_METERED_GUESS_YES)
+}
+
+/// Watch the metered state of the active connection. Devices without NetworkManager are
+/// always reported as unmetered.
+pub async fn watch_metered() -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+
+    #[cfg(feature = "dbus")]
+    match network_manager_metered().await {
+        Ok(mut source) => {
+            tokio::spawn(async move { forward_metered(&mut source, tx).await });
+        }
+        Err(err) => {
+            debug!("NetworkManager not available, connection treated as unmetered: {err:?}");
+        }
+    }
+    #[cfg(not(feature = "dbus"))]
+    {
+        debug!("Built without D-Bus, connection treated as unmetered");
+        drop(tx);
+    }
+
+    rx
+}
+
+#[cfg(feature = "dbus")]
+async fn network_manager_metered() -> Result<NetworkManagerMetered, DeviceManagerError> {
+    let connection = system_bus().await?;
+    let proxy = NetworkManagerProxy::new(&connection).await?;
+    let changes = proxy.receive_metered_changed().await;
+
+    Ok(NetworkManagerMetered { changes, proxy })
+}
+
+#[cfg(feature = "dbus")]
+async fn forward_metered(source: &mut impl MeteredSource, tx: watch::Sender<bool>) {
+    match source.current().await {
+        Ok(value) => update_metered(&tx, is_metered(value)),
+        Err(err) => warn!("Unable to read metered state: {err:?}"),
+    }
+
+    while let Some(value) = source.next_change().await {
+        update_metered(&tx, is_metered(value));
+    }
+}
+
+#[cfg(feature = "dbus")]
+fn update_metered(tx: &watch::Sender<bool>, metered: bool) {
+    if *tx.borrow() != metered {
+        info!("Active connection metered: {metered}");
+        tx.send(metered).ok();
+    }
+}
+
+/// Publish the metered flag on the ConnectionStats interface every time it changes.
+pub async fn publish_metered(publisher: &impl Publisher, mut metered: watch::Receiver<bool>) {
+    loop {
+        let value = *metered.borrow();
+        if let Err(err) = publisher
+            .send(
+                CONNECTION_STATS_INTERFACE,
+                "/metered",
+                AstarteType::Boolean(value),
+            )
+            .await
+        {
+            warn!("Unable to publish metered state: {err:?}");
+        }
+
+        if metered.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "dbus"))]
+mod tests {
+    use std::collections::VecDeque;
+
+    use async_trait::async_trait;
+    use tokio::sync::watch;
+
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::CONNECTION_STATS_INTERFACE;
+    use crate::network_manager::{forward_metered, is_metered, MeteredSource};
+
+    struct FakeNetworkManager {
+        current: u32,
+        changes: VecDeque<u32>,
+        seen: watch::Receiver<bool>,
+        history: Vec<bool>,
+    }
+
+    #[async_trait]
+    impl MeteredSource for FakeNetworkManager {
+        async fn current(&mut self) -> Result<u32, DeviceManagerError> {
+            Ok(self.current)
+        }
+
+        async fn next_change(&mut self) -> Option<u32> {
+            self.history.push(*self.seen.borrow());
+            self.changes.pop_front()
+        }
+    }
+
+    #[test]
+    fn nm_metered_values() {
+        assert!(!is_metered(0));
+        assert!(is_metered(1));
+        assert!(!is_metered(2));
+        assert!(is_metered(3));
+        assert!(!is_metered(4));
+    }
+
+    #[tokio::test]
+    async fn metered_flag_follows_network_manager() {
+        let (tx, rx) = watch::channel(false);
+
+        let mut source = FakeNetworkManager {
+            current: 4,
+            changes: VecDeque::from([1, 3, 2]),
+            seen: rx.clone(),
+            history: Vec::new(),
+        };
+
+        forward_metered(&mut source, tx).await;
+
+        assert_eq!(source.history, vec![false, true, true, false]);
+        assert!(!*rx.borrow());
+    }
+}
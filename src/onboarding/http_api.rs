@@ -0,0 +1,294 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{info, warn};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::error::DeviceManagerError;
+use crate::onboarding::{OnboardingState, ProvisioningRequest};
+
+const MAX_BODY_SIZE: usize = 4096;
+
+struct ApiContext {
+    state: watch::Receiver<OnboardingState>,
+    requests: mpsc::Sender<ProvisioningRequest>,
+    pairing_token_required: bool,
+}
+
+/// Outcome of a request to the onboarding API.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ApiResponse {
+    pub status: StatusCode,
+    pub body: String,
+    pub request: Option<ProvisioningRequest>,
+}
+
+impl ApiResponse {
+    fn error(status: StatusCode, message: &str) -> Self {
+        ApiResponse {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+            request: None,
+        }
+    }
+}
+
+/// Handle a request to the onboarding API:
+/// - `GET /status` returns the current onboarding state;
+/// - `POST /provision` accepts the Wi-Fi credentials and the pairing token.
+pub(crate) fn handle_request(
+    method: &Method,
+    path: &str,
+    body: &[u8],
+    state: &OnboardingState,
+    pairing_token_required: bool,
+) -> ApiResponse {
+    match (method, path) {
+        (&Method::GET, "/status") => ApiResponse {
+            status: StatusCode::OK,
+            body: serde_json::to_string(state).unwrap_or_default(),
+            request: None,
+        },
+        (&Method::POST, "/provision") => {
+            if body.len() > MAX_BODY_SIZE {
+                return ApiResponse::error(StatusCode::PAYLOAD_TOO_LARGE, "request too large");
+            }
+
+            if !matches!(state, OnboardingState::WaitingForCredentials { .. }) {
+                return ApiResponse::error(StatusCode::CONFLICT, "provisioning already running");
+            }
+
+            let request: ProvisioningRequest = match serde_json::from_slice(body) {
+                Ok(request) => request,
+                Err(_) => return ApiResponse::error(StatusCode::BAD_REQUEST, "malformed request"),
+            };
+
+            if request.ssid.trim().is_empty() {
+                return ApiResponse::error(StatusCode::BAD_REQUEST, "missing ssid");
+            }
+
+            let has_token = request
+                .pairing_token
+                .as_ref()
+                .map_or(false, |token| !token.trim().is_empty());
+            if pairing_token_required && !has_token {
+                return ApiResponse::error(StatusCode::BAD_REQUEST, "missing pairingToken");
+            }
+
+            ApiResponse {
+                status: StatusCode::ACCEPTED,
+                body: serde_json::json!({ "state": "connecting" }).to_string(),
+                request: Some(request),
+            }
+        }
+        (_, "/status") | (_, "/provision") => {
+            ApiResponse::error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => ApiResponse::error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+async fn serve_request(
+    context: Arc<ApiContext>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Unable to read onboarding request: {err}");
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap_or_default());
+        }
+    };
+
+    let state = context.state.borrow().clone();
+    let response = handle_request(
+        &method,
+        &path,
+        &body,
+        &state,
+        context.pairing_token_required,
+    );
+
+    let status = match response.request {
+        Some(request) => match context.requests.try_send(request) {
+            Ok(()) => response.status,
+            Err(_) => StatusCode::CONFLICT,
+        },
+        None => response.status,
+    };
+
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(response.body))
+        .unwrap_or_default())
+}
+
+/// Serve the onboarding API until `shutdown` fires.
+pub(crate) fn spawn_server(
+    address: SocketAddr,
+    state: watch::Receiver<OnboardingState>,
+    requests: mpsc::Sender<ProvisioningRequest>,
+    pairing_token_required: bool,
+    shutdown: oneshot::Receiver<()>,
+) -> Result<tokio::task::JoinHandle<()>, DeviceManagerError> {
+    let context = Arc::new(ApiContext {
+        state,
+        requests,
+        pairing_token_required,
+    });
+
+    let make_service = make_service_fn(move |_connection| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                serve_request(context.clone(), request)
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&address)
+        .map_err(|err| {
+            DeviceManagerError::Onboarding(format!("unable to bind the API on {address}: {err}"))
+        })?
+        .serve(make_service)
+        .with_graceful_shutdown(async {
+            shutdown.await.ok();
+        });
+
+    info!("Onboarding API listening on {address}");
+
+    Ok(tokio::spawn(async move {
+        if let Err(err) = server.await {
+            warn!("Onboarding API error: {err}");
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Method, StatusCode};
+
+    use crate::onboarding::http_api::handle_request;
+    use crate::onboarding::{OnboardingState, ProvisioningRequest};
+
+    fn waiting() -> OnboardingState {
+        OnboardingState::WaitingForCredentials { last_error: None }
+    }
+
+    #[test]
+    fn provision_accepted() {
+        let body = br#"{"ssid": "factory", "passphrase": "secret123", "pairingToken": "token"}"#;
+
+        let response = handle_request(&Method::POST, "/provision", body, &waiting(), true);
+
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(
+            response.request,
+            Some(ProvisioningRequest {
+                ssid: "factory".to_owned(),
+                passphrase: Some("secret123".to_owned()),
+                pairing_token: Some("token".to_owned()),
+            })
+        );
+    }
+
+    #[test]
+    fn provision_without_token_when_configured() {
+        let body = br#"{"ssid": "factory"}"#;
+
+        let response = handle_request(&Method::POST, "/provision", body, &waiting(), false);
+
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert!(response.request.is_some());
+    }
+
+    #[test]
+    fn provision_rejects_invalid_requests() {
+        let missing_token = br#"{"ssid": "factory", "passphrase": "secret123"}"#;
+        let response = handle_request(&Method::POST, "/provision", missing_token, &waiting(), true);
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert!(response.request.is_none());
+
+        let missing_ssid = br#"{"ssid": " ", "pairingToken": "token"}"#;
+        let response = handle_request(&Method::POST, "/provision", missing_ssid, &waiting(), true);
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+
+        let malformed = b"ssid=factory";
+        let response = handle_request(&Method::POST, "/provision", malformed, &waiting(), true);
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+
+        let too_large = vec![b' '; 8192];
+        let response = handle_request(&Method::POST, "/provision", &too_large, &waiting(), true);
+        assert_eq!(response.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn provision_rejected_while_connecting() {
+        let body = br#"{"ssid": "factory", "pairingToken": "token"}"#;
+
+        let response = handle_request(
+            &Method::POST,
+            "/provision",
+            body,
+            &OnboardingState::Connecting,
+            true,
+        );
+
+        assert_eq!(response.status, StatusCode::CONFLICT);
+        assert!(response.request.is_none());
+    }
+
+    #[test]
+    fn status_reports_state() {
+        let state = OnboardingState::WaitingForCredentials {
+            last_error: Some("pairing url unreachable".to_owned()),
+        };
+
+        let response = handle_request(&Method::GET, "/status", b"", &state, true);
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            response.body,
+            r#"{"state":"waitingForCredentials","lastError":"pairing url unreachable"}"#
+        );
+    }
+
+    #[test]
+    fn unknown_routes() {
+        let response = handle_request(&Method::GET, "/provision", b"", &waiting(), true);
+        assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let response = handle_request(&Method::GET, "/", b"", &waiting(), true);
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,515 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! First boot onboarding through a temporary Wi-Fi access point.
+//!
+//! When the device has no credentials and onboarding is enabled, an access point is brought up
+//! and a local HTTP API accepts the Wi-Fi credentials and the pairing token. Once the device can
+//! reach the pairing URL the access point is torn down and the registration proceeds as usual.
+//!
+//! The pairing token received is kept in the store directory until the registration succeeds, so
+//! that a failed registration or a reboot retries it instead of onboarding again.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::clock::Clock;
+use crate::disk_guard::DiskGuard;
+use crate::error::DeviceManagerError;
+use crate::onboarding::network::{NmcliNetwork, ProvisioningNetwork};
+use crate::redaction::redactor;
+use crate::repository::file_state_repository::FileStateRepository;
+use crate::secret_store::{FileSecretStore, SecretStore};
+use crate::DeviceManagerOptions;
+
+mod http_api;
+pub(crate) mod network;
+
+/// File of the store directory keeping the pairing token received, until the registration.
+const PAIRING_TOKEN_FILE: &str = "onboarding_pairing_token.json";
+
+fn default_ap_ssid() -> String {
+    "edgehog-setup".to_owned()
+}
+
+fn default_listen_address() -> String {
+    "0.0.0.0:8080".to_owned()
+}
+
+fn default_timeout_secs() -> u64 {
+    900
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OnboardingOptions {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_ap_ssid")]
+    pub ap_ssid: String,
+    pub ap_passphrase: Option<String>,
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub(crate) enum OnboardingState {
+    WaitingForCredentials {
+        #[serde(rename = "lastError", skip_serializing_if = "Option::is_none")]
+        last_error: Option<String>,
+    },
+    Connecting,
+    Provisioned,
+    TimedOut,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProvisioningRequest {
+    pub ssid: String,
+    pub passphrase: Option<String>,
+    pub pairing_token: Option<String>,
+}
+
+/// Onboarding runs only on devices without credentials, and only if explicitly enabled.
+pub(crate) fn should_onboard(opts: &DeviceManagerOptions, credentials_persisted: bool) -> bool {
+    let enabled = opts
+        .onboarding
+        .as_ref()
+        .map_or(false, |onboarding| onboarding.enabled);
+
+    enabled && opts.credentials_secret.is_none() && !credentials_persisted
+}
+
+/// Where the pairing token received by the onboarding is kept, readable by its owner only.
+pub(crate) fn token_store(
+    opts: &DeviceManagerOptions,
+    disk_guard: Option<Arc<DiskGuard>>,
+) -> FileSecretStore {
+    FileSecretStore::new(
+        FileStateRepository::new(opts.store_directory.clone(), PAIRING_TOKEN_FILE.to_owned())
+            .with_disk_guard(disk_guard),
+    )
+}
+
+/// Pairing token received by the onboarding of a previous start, a token that can't be read is
+/// discarded.
+pub(crate) fn persisted_token(store: &dyn SecretStore) -> Option<String> {
+    if !store.exists() {
+        return None;
+    }
+
+    match store.load() {
+        Ok(token) => Some(token),
+        Err(err) => {
+            warn!(
+                "Unable to read the onboarding pairing token, discarding it: {:?}",
+                err
+            );
+            store.delete().ok();
+            None
+        }
+    }
+}
+
+/// Run the onboarding, returns the pairing token received from the user, if any.
+pub(crate) async fn run(
+    opts: &DeviceManagerOptions,
+    clock: Arc<dyn Clock>,
+) -> Result<Option<String>, DeviceManagerError> {
+    let onboarding = opts.onboarding.clone().unwrap_or(OnboardingOptions {
+        enabled: true,
+        ap_ssid: default_ap_ssid(),
+        ap_passphrase: None,
+        listen_address: default_listen_address(),
+        timeout_secs: default_timeout_secs(),
+    });
+
+    let address: SocketAddr =
+        onboarding
+            .listen_address
+            .parse()
+            .map_err(|_| DeviceManagerError::InvalidOption {
+                option: "onboarding.listen_address".to_owned(),
+                reason: format!("{} is not an address", onboarding.listen_address),
+            })?;
+
+    let (state_tx, state_rx) =
+        watch::channel(OnboardingState::WaitingForCredentials { last_error: None });
+    let (requests_tx, mut requests_rx) = mpsc::channel(1);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+
+    let server = http_api::spawn_server(
+        address,
+        state_rx,
+        requests_tx,
+        opts.pairing_token.is_none(),
+        shutdown_rx,
+    )?;
+
+    let result = provision(
+        &NmcliNetwork,
+        clock.as_ref(),
+        &onboarding,
+        &opts.pairing_url,
+        &state_tx,
+        &mut requests_rx,
+    )
+    .await;
+
+    shutdown_tx.send(()).ok();
+    server.await.ok();
+
+    result
+}
+
+/// Drive the onboarding state machine until the device is provisioned or the window expires.
+pub(crate) async fn provision(
+    network: &dyn ProvisioningNetwork,
+    clock: &dyn Clock,
+    onboarding: &OnboardingOptions,
+    pairing_url: &str,
+    state: &watch::Sender<OnboardingState>,
+    requests: &mut mpsc::Receiver<ProvisioningRequest>,
+) -> Result<Option<String>, DeviceManagerError> {
+    let deadline = clock.now_monotonic() + Duration::from_secs(onboarding.timeout_secs);
+
+    info!(
+        "Starting onboarding access point {}",
+        redactor().field("ssid", &onboarding.ap_ssid)
+    );
+    network
+        .start_access_point(&onboarding.ap_ssid, onboarding.ap_passphrase.as_deref())
+        .await?;
+
+    loop {
+        let request = tokio::select! {
+            _ = clock.sleep_until(deadline) => None,
+            request = requests.recv() => request,
+        };
+
+        let request = match request {
+            Some(request) => request,
+            None => {
+                warn!("Onboarding timed out");
+                if let Err(err) = network.stop_access_point().await {
+                    warn!("Unable to stop onboarding access point: {err}");
+                }
+                state.send(OnboardingState::TimedOut).ok();
+
+                return Err(DeviceManagerError::OnboardingTimedOut);
+            }
+        };
+
+        state.send(OnboardingState::Connecting).ok();
+
+        if let Err(err) = network.stop_access_point().await {
+            warn!("Unable to stop onboarding access point: {err}");
+        }
+
+        let connected = match network
+            .connect_wifi(&request.ssid, request.passphrase.as_deref())
+            .await
+        {
+            Ok(()) => network
+                .check_reachable(pairing_url)
+                .await
+                .map_err(|err| (err, "pairing url unreachable")),
+            Err(err) => Err((err, "unable to connect to the Wi-Fi network")),
+        };
+
+        match connected {
+            Ok(()) => {
+                info!(
+                    "Onboarding completed, connected to {}",
+                    redactor().field("ssid", &request.ssid)
+                );
+                state.send(OnboardingState::Provisioned).ok();
+
+                return Ok(request.pairing_token);
+            }
+            Err((err, reason)) => {
+                warn!(
+                    "Onboarding attempt failed, {reason}: {}",
+                    redactor().text(&format!("{err:?}"))
+                );
+                state
+                    .send(OnboardingState::WaitingForCredentials {
+                        last_error: Some(reason.to_owned()),
+                    })
+                    .ok();
+
+                network
+                    .start_access_point(&onboarding.ap_ssid, onboarding.ap_passphrase.as_deref())
+                    .await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::sync::{mpsc, watch};
+
+    use crate::error::DeviceManagerError;
+    use crate::onboarding::network::ProvisioningNetwork;
+    use crate::onboarding::{
+        persisted_token, provision, should_onboard, token_store, OnboardingOptions,
+        OnboardingState, ProvisioningRequest, PAIRING_TOKEN_FILE,
+    };
+    use crate::secret_store::SecretStore;
+    use crate::test_utils::{settle, ManualClock};
+    use crate::DeviceManagerOptions;
+
+    #[derive(Default)]
+    struct FakeNetwork {
+        calls: Mutex<Vec<String>>,
+        reachable: Mutex<Vec<bool>>,
+    }
+
+    impl FakeNetwork {
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl ProvisioningNetwork for FakeNetwork {
+        async fn start_access_point(
+            &self,
+            ssid: &str,
+            _passphrase: Option<&str>,
+        ) -> Result<(), DeviceManagerError> {
+            self.calls.lock().unwrap().push(format!("start {ssid}"));
+            Ok(())
+        }
+
+        async fn stop_access_point(&self) -> Result<(), DeviceManagerError> {
+            self.calls.lock().unwrap().push("stop".to_owned());
+            Ok(())
+        }
+
+        async fn connect_wifi(
+            &self,
+            ssid: &str,
+            _passphrase: Option<&str>,
+        ) -> Result<(), DeviceManagerError> {
+            self.calls.lock().unwrap().push(format!("connect {ssid}"));
+            Ok(())
+        }
+
+        async fn check_reachable(&self, url: &str) -> Result<(), DeviceManagerError> {
+            self.calls.lock().unwrap().push(format!("check {url}"));
+            if self.reachable.lock().unwrap().remove(0) {
+                Ok(())
+            } else {
+                Err(DeviceManagerError::IOError(
+                    std::io::ErrorKind::TimedOut.into(),
+                ))
+            }
+        }
+    }
+
+    fn onboarding_options() -> OnboardingOptions {
+        OnboardingOptions {
+            enabled: true,
+            ap_ssid: "edgehog-setup".to_owned(),
+            ap_passphrase: None,
+            listen_address: "127.0.0.1:0".to_owned(),
+            timeout_secs: 60,
+        }
+    }
+
+    fn request(ssid: &str) -> ProvisioningRequest {
+        ProvisioningRequest {
+            ssid: ssid.to_owned(),
+            passphrase: Some("secret123".to_owned()),
+            pairing_token: Some("token".to_owned()),
+        }
+    }
+
+    fn device_options(
+        credentials_secret: Option<String>,
+        onboarding: Option<OnboardingOptions>,
+    ) -> DeviceManagerOptions {
+        DeviceManagerOptions {
+            credentials_secret,
+            onboarding,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn onboarding_activation() {
+        let enabled = Some(onboarding_options());
+        let disabled = Some(OnboardingOptions {
+            enabled: false,
+            ..onboarding_options()
+        });
+
+        assert!(should_onboard(
+            &device_options(None, enabled.clone()),
+            false
+        ));
+        assert!(!should_onboard(
+            &device_options(None, enabled.clone()),
+            true
+        ));
+        assert!(!should_onboard(
+            &device_options(Some("secret".to_owned()), enabled),
+            false
+        ));
+        assert!(!should_onboard(&device_options(None, disabled), false));
+        assert!(!should_onboard(&device_options(None, None), false));
+    }
+
+    #[test]
+    fn pairing_token_persisted() {
+        let store_directory = tempfile::tempdir().unwrap();
+        let opts = DeviceManagerOptions {
+            store_directory: store_directory.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let store = token_store(&opts, None);
+
+        assert_eq!(persisted_token(&store), None);
+        store.save("token").unwrap();
+        // read back at the next start
+        assert_eq!(
+            persisted_token(&token_store(&opts, None)),
+            Some("token".to_owned())
+        );
+
+        std::fs::write(store_directory.path().join(PAIRING_TOKEN_FILE), "not json").unwrap();
+        assert_eq!(persisted_token(&store), None);
+        assert!(!store.exists());
+    }
+
+    #[test]
+    fn onboarding_options_defaults() {
+        let options: OnboardingOptions = toml::from_str("enabled = true").unwrap();
+
+        assert!(options.enabled);
+        assert_eq!(options.ap_ssid, "edgehog-setup");
+        assert_eq!(options.ap_passphrase, None);
+        assert_eq!(options.listen_address, "0.0.0.0:8080");
+        assert_eq!(options.timeout_secs, 900);
+    }
+
+    #[tokio::test]
+    async fn provision_retries_until_reachable() {
+        let network = FakeNetwork {
+            reachable: Mutex::new(vec![false, true]),
+            ..Default::default()
+        };
+        let clock = ManualClock::new();
+        let (state_tx, state_rx) =
+            watch::channel(OnboardingState::WaitingForCredentials { last_error: None });
+        let (requests_tx, mut requests_rx) = mpsc::channel(1);
+
+        requests_tx.send(request("wrong")).await.unwrap();
+        let options = onboarding_options();
+        let result = tokio::join!(
+            provision(
+                &network,
+                &clock,
+                &options,
+                "https://pairing",
+                &state_tx,
+                &mut requests_rx,
+            ),
+            async {
+                settle().await;
+                assert_eq!(
+                    *state_rx.borrow(),
+                    OnboardingState::WaitingForCredentials {
+                        last_error: Some("pairing url unreachable".to_owned())
+                    }
+                );
+                requests_tx.send(request("factory")).await.unwrap();
+            }
+        )
+        .0;
+
+        assert_eq!(result.unwrap(), Some("token".to_owned()));
+        assert_eq!(*state_rx.borrow(), OnboardingState::Provisioned);
+        assert_eq!(
+            network.calls(),
+            vec![
+                "start edgehog-setup",
+                "stop",
+                "connect wrong",
+                "check https://pairing",
+                "start edgehog-setup",
+                "stop",
+                "connect factory",
+                "check https://pairing",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn provision_times_out() {
+        let network = Arc::new(FakeNetwork::default());
+        let clock = Arc::new(ManualClock::new());
+        let (state_tx, state_rx) =
+            watch::channel(OnboardingState::WaitingForCredentials { last_error: None });
+        let (_requests_tx, mut requests_rx) = mpsc::channel(1);
+
+        let task = {
+            let network = network.clone();
+            let clock = clock.clone();
+            tokio::spawn(async move {
+                provision(
+                    network.as_ref(),
+                    clock.as_ref(),
+                    &onboarding_options(),
+                    "https://pairing",
+                    &state_tx,
+                    &mut requests_rx,
+                )
+                .await
+            })
+        };
+
+        settle().await;
+        clock.advance(Duration::from_secs(59));
+        settle().await;
+        assert!(!task.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        let result = task.await.unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(*state_rx.borrow(), OnboardingState::TimedOut);
+        assert_eq!(network.calls(), vec!["start edgehog-setup", "stop"]);
+    }
+}
@@ -0,0 +1,120 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::process::Command;
+
+use crate::error::DeviceManagerError;
+
+const ONBOARDING_CONNECTION: &str = "edgehog-onboarding";
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Network operations needed to onboard a device over a temporary access point.
+#[async_trait]
+pub trait ProvisioningNetwork: Send + Sync {
+    async fn start_access_point(
+        &self,
+        ssid: &str,
+        passphrase: Option<&str>,
+    ) -> Result<(), DeviceManagerError>;
+    async fn stop_access_point(&self) -> Result<(), DeviceManagerError>;
+    /// Connect to the Wi-Fi network, the connection profile is persisted by the network backend.
+    async fn connect_wifi(
+        &self,
+        ssid: &str,
+        passphrase: Option<&str>,
+    ) -> Result<(), DeviceManagerError>;
+    async fn check_reachable(&self, url: &str) -> Result<(), DeviceManagerError>;
+}
+
+/// NetworkManager backend driven through `nmcli`.
+pub struct NmcliNetwork;
+
+impl NmcliNetwork {
+    async fn nmcli(args: &[&str]) -> Result<(), DeviceManagerError> {
+        debug!("Running nmcli {:?}", args.first());
+
+        let output = Command::new("nmcli").args(args).output().await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(DeviceManagerError::CommandFailed(format!(
+                "nmcli failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl ProvisioningNetwork for NmcliNetwork {
+    async fn start_access_point(
+        &self,
+        ssid: &str,
+        passphrase: Option<&str>,
+    ) -> Result<(), DeviceManagerError> {
+        let mut args = vec![
+            "device",
+            "wifi",
+            "hotspot",
+            "con-name",
+            ONBOARDING_CONNECTION,
+            "ssid",
+            ssid,
+        ];
+        if let Some(passphrase) = passphrase {
+            args.extend(["password", passphrase]);
+        }
+
+        NmcliNetwork::nmcli(&args).await
+    }
+
+    async fn stop_access_point(&self) -> Result<(), DeviceManagerError> {
+        NmcliNetwork::nmcli(&["connection", "delete", ONBOARDING_CONNECTION]).await
+    }
+
+    async fn connect_wifi(
+        &self,
+        ssid: &str,
+        passphrase: Option<&str>,
+    ) -> Result<(), DeviceManagerError> {
+        let mut args = vec!["device", "wifi", "connect", ssid];
+        if let Some(passphrase) = passphrase {
+            args.extend(["password", passphrase]);
+        }
+
+        NmcliNetwork::nmcli(&args).await
+    }
+
+    async fn check_reachable(&self, url: &str) -> Result<(), DeviceManagerError> {
+        let client = reqwest::Client::builder()
+            .timeout(REACHABILITY_TIMEOUT)
+            .build()?;
+
+        // any HTTP answer proves the pairing endpoint can be reached
+        client.get(url).send().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Options set by the `EDGEHOG_*` environment variables, replacing the ones of the file.
+//!
+//! The name of a variable is the key of its option in upper case after the prefix, with the keys
+//! of the nested tables separated by a double underscore: `EDGEHOG_CONTAINERS__STATUS_PERIOD_SECS`
+//! sets `status_period_secs` of the `containers` table. The values are trimmed and read as the
+//! type of their option, the lists split on the commas; an empty value sets nothing.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::de::value::{SeqDeserializer, StringDeserializer};
+use serde::de::{self, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, Visitor};
+use toml::value::{Table, Value};
+
+use crate::telemetry::system_info::{PART_NUMBER_ENV, SERIAL_NUMBER_ENV};
+
+type Error = toml::de::Error;
+
+const ENV_PREFIX: &str = "EDGEHOG_";
+const ENV_SEPARATOR: &str = "__";
+/// Variables with the prefix that are read elsewhere and set no option.
+const RESERVED_ENV: [&str; 2] = [SERIAL_NUMBER_ENV, PART_NUMBER_ENV];
+
+/// The variables that set an option, by the path of its key.
+#[derive(Debug, Default)]
+pub(super) struct EnvOptions {
+    vars: HashMap<Vec<String>, String>,
+}
+
+impl EnvOptions {
+    /// Set the options of the `EDGEHOG_*` variables among `vars` in `table`.
+    pub(super) fn apply<I>(table: &mut Table, vars: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut options = HashMap::new();
+        for (var, value) in vars {
+            let key = match var.strip_prefix(ENV_PREFIX) {
+                Some(key) if !RESERVED_ENV.contains(&var.as_str()) => key,
+                _ => continue,
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+
+            let path: Vec<String> = key.split(ENV_SEPARATOR).map(str::to_lowercase).collect();
+            if path.iter().any(String::is_empty) {
+                return Err(invalid(&var, "invalid name"));
+            }
+            let (leaf, tables) = path.split_last().expect("split yields a key");
+            let mut current = &mut *table;
+            for name in tables {
+                current = match current
+                    .entry(name.clone())
+                    .or_insert(Value::Table(Table::new()))
+                {
+                    Value::Table(nested) => nested,
+                    _ => return Err(invalid(&var, format!("{name} is not a table"))),
+                };
+            }
+            current.insert(leaf.clone(), Value::String(value.to_owned()));
+            options.insert(path, var);
+        }
+
+        Ok(EnvOptions { vars: options })
+    }
+
+    /// Forget the variable of the top level `key`, replaced on the command line.
+    pub(super) fn remove(&mut self, key: &str) {
+        self.vars.remove(&vec![key.to_owned()]);
+    }
+
+    /// The variable setting the option at the dotted `path`.
+    pub(super) fn var(&self, path: &str) -> Option<&str> {
+        let path: Vec<String> = path.split('.').map(str::to_owned).collect();
+        self.vars.get(&path).map(String::as_str)
+    }
+
+    pub(super) fn deserializer(&self, table: Table) -> OptionsDeserializer<'_> {
+        OptionsDeserializer {
+            value: Value::Table(table),
+            path: Vec::new(),
+            env: self,
+        }
+    }
+}
+
+fn invalid(var: &str, problem: impl Display) -> Error {
+    de::Error::custom(format!("{var}: {problem}"))
+}
+
+/// Deserializer of the options, reading the values of the variables as the type of their option.
+pub(super) struct OptionsDeserializer<'a> {
+    value: Value,
+    path: Vec<String>,
+    env: &'a EnvOptions,
+}
+
+impl<'a> OptionsDeserializer<'a> {
+    /// The variable and the value it set, unless the value comes from the file.
+    fn env_value(&self) -> Option<(&'a str, &str)> {
+        match &self.value {
+            Value::String(value) => self
+                .env
+                .vars
+                .get(&self.path)
+                .map(|var| (var.as_str(), value.as_str())),
+            _ => None,
+        }
+    }
+
+    fn parse<T: FromStr>(&self, kind: &str) -> Option<Result<T, Error>> {
+        self.env_value().map(|(var, value)| {
+            value
+                .parse()
+                .map_err(|_| invalid(var, format!("{value:?} is not {kind}")))
+        })
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident($ty:ty, $kind:literal),)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self.parse::<$ty>($kind) {
+                    Some(value) => visitor.$visit(value?),
+                    None => self.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for OptionsDeserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Table(table) => visitor.visit_map(TableAccess {
+                entries: table.into_iter().collect::<Vec<_>>().into_iter(),
+                value: None,
+                path: self.path,
+                env: self.env,
+            }),
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool(bool, "true or false"),
+        deserialize_i8 => visit_i8(i8, "an integer"),
+        deserialize_i16 => visit_i16(i16, "an integer"),
+        deserialize_i32 => visit_i32(i32, "an integer"),
+        deserialize_i64 => visit_i64(i64, "an integer"),
+        deserialize_u8 => visit_u8(u8, "an integer between 0 and 255"),
+        deserialize_u16 => visit_u16(u16, "a positive integer"),
+        deserialize_u32 => visit_u32(u32, "a positive integer"),
+        deserialize_u64 => visit_u64(u64, "a positive integer"),
+        deserialize_f32 => visit_f32(f32, "a number"),
+        deserialize_f64 => visit_f64(f64, "a number"),
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let items: Option<Vec<String>> = self.env_value().map(|(_, value)| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect()
+        });
+        match items {
+            Some(items) => {
+                let mut items = SeqDeserializer::<_, Error>::new(items.into_iter());
+                let value = visitor.visit_seq(&mut items)?;
+                items.end()?;
+                Ok(value)
+            }
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if let Some(var) = self.env_value().map(|(var, _)| var) {
+            return Err(invalid(
+                var,
+                format!("sets a table, set its keys with {var}{ENV_SEPARATOR}<KEY>"),
+            ));
+        }
+
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct tuple tuple_struct identifier ignored_any
+    }
+}
+
+/// Entries of a table, each with the path of its key.
+struct TableAccess<'a> {
+    entries: std::vec::IntoIter<(String, Value)>,
+    value: Option<OptionsDeserializer<'a>>,
+    path: Vec<String>,
+    env: &'a EnvOptions,
+}
+
+impl<'de, 'a> MapAccess<'de> for TableAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let (key, value) = match self.entries.next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let mut path = self.path.clone();
+        path.push(key.clone());
+        self.value = Some(OptionsDeserializer {
+            value,
+            path,
+            env: self.env,
+        });
+
+        let key: StringDeserializer<Error> = key.into_deserializer();
+        seed.deserialize(key).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before its key"))?;
+        seed.deserialize(value)
+    }
+}
@@ -0,0 +1,1023 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Loading and validation of the configuration file, shared by the runtime and the operator
+//! subcommands.
+//!
+//! The options are read from the file, then replaced by the `EDGEHOG_*` environment variables and
+//! by the values given on the command line. The keys matching no option are logged rather than
+//! silently dropped.
+
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use nix::unistd::{access, AccessFlags};
+use reqwest::Url;
+use toml::value::{Table, Value};
+
+mod env;
+
+use crate::data::connection::AstarteLibrary;
+use crate::data::message_hub::MessageHubOptions;
+use crate::device_id;
+use crate::error::DeviceManagerError;
+use crate::http_status;
+use crate::options::env::EnvOptions;
+use crate::tags::collect_tags;
+use crate::DeviceManagerOptions;
+
+/// Configuration file looked up first when no path is given.
+const SYSTEM_CONFIG_PATH: &str = "/etc/edgehog/config.toml";
+/// Configuration file looked up next to the binary, then in the working directory.
+const LOCAL_CONFIG_FILE: &str = "edgehog-config.toml";
+/// Store directory when none is configured.
+pub const DEFAULT_STORE_DIRECTORY: &str = "/var/lib/edgehog";
+/// Keys without a default, either in the configuration file or on the command line.
+const REQUIRED_KEYS: [&str; 2] = ["interfaces_directory", "download_directory"];
+/// Keys required on top of [`REQUIRED_KEYS`] unless attached to the Astarte Message Hub.
+const SDK_REQUIRED_KEYS: [&str; 2] = ["realm", "pairing_url"];
+
+/// Options given on the command line, taking precedence over the environment and the
+/// configuration file.
+#[derive(Debug, Default, clap::Args)]
+pub struct ConfigOverrides {
+    #[clap(long)]
+    pub realm: Option<String>,
+    #[clap(long)]
+    pub device_id: Option<String>,
+    #[clap(long)]
+    pub pairing_url: Option<String>,
+    #[clap(long)]
+    pub interfaces_directory: Option<String>,
+    #[clap(long)]
+    pub store_directory: Option<String>,
+    #[clap(long)]
+    pub download_directory: Option<String>,
+    /// Send all the initial telemetry properties, even the ones unchanged since the last send
+    #[clap(long)]
+    pub force_resend_properties: bool,
+}
+
+impl ConfigOverrides {
+    /// Set the options in `table`, returning their keys.
+    fn apply(&self, table: &mut Table) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        let values = [
+            ("realm", &self.realm),
+            ("device_id", &self.device_id),
+            ("pairing_url", &self.pairing_url),
+            ("interfaces_directory", &self.interfaces_directory),
+            ("store_directory", &self.store_directory),
+            ("download_directory", &self.download_directory),
+        ];
+        for (key, value) in values {
+            if let Some(value) = value {
+                table.insert(key.to_owned(), Value::String(value.clone()));
+                keys.push(key);
+            }
+        }
+        if self.force_resend_properties {
+            table.insert("force_resend_properties".to_owned(), Value::Boolean(true));
+            keys.push("force_resend_properties");
+        }
+
+        keys
+    }
+}
+
+pub(crate) fn default_store_directory() -> String {
+    DEFAULT_STORE_DIRECTORY.to_owned()
+}
+
+/// Where the configuration file is looked up when no path is given, in order.
+fn default_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(SYSTEM_CONFIG_PATH)];
+    if let Ok(binary) = std::env::current_exe() {
+        if let Some(directory) = binary.parent() {
+            paths.push(directory.join(LOCAL_CONFIG_FILE));
+        }
+    }
+    paths.push(PathBuf::from(LOCAL_CONFIG_FILE));
+
+    paths
+}
+
+impl DeviceManagerOptions {
+    /// Read the configuration file at `path`, or the first of the default paths found, then
+    /// apply the environment variables and the `overrides`.
+    pub fn from_sources(
+        path: Option<String>,
+        overrides: &ConfigOverrides,
+    ) -> Result<Self, DeviceManagerError> {
+        let path = match path {
+            Some(path) if Path::new(&path).exists() => PathBuf::from(path),
+            Some(path) => return Err(DeviceManagerError::ConfigNotFound(path)),
+            None => {
+                let paths = default_paths();
+                match paths.iter().find(|path| path.exists()) {
+                    Some(path) => path.clone(),
+                    None => {
+                        let paths: Vec<_> = paths
+                            .iter()
+                            .map(|path| path.display().to_string())
+                            .collect();
+                        return Err(DeviceManagerError::ConfigNotFound(paths.join(" or ")));
+                    }
+                }
+            }
+        };
+
+        info!("Found configuration file {}", path.display());
+
+        Self::load(&path, overrides)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, DeviceManagerError> {
+        Self::load(path, &ConfigOverrides::default())
+    }
+
+    fn load(path: &Path, overrides: &ConfigOverrides) -> Result<Self, DeviceManagerError> {
+        let config = std::fs::read_to_string(path)?;
+        // the variables that aren't valid unicode can't name an option
+        let vars = std::env::vars_os()
+            .filter_map(|(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?)));
+        let (options, unknown) = Self::parse(&config, vars, overrides)?;
+        if !unknown.is_empty() {
+            warn!(
+                "Unknown options in {} or in the environment, ignored: {}",
+                path.display(),
+                unknown.join(", ")
+            );
+        }
+
+        Ok(options)
+    }
+
+    /// The options of `config` with the environment `vars` and the `overrides` applied, and the
+    /// keys matching no option. All the missing required keys are reported at once.
+    fn parse(
+        config: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+        overrides: &ConfigOverrides,
+    ) -> Result<(Self, Vec<String>), DeviceManagerError> {
+        let mut table: Table = toml::from_str(config)?;
+        let mut env = EnvOptions::apply(&mut table, vars)?;
+        for key in overrides.apply(&mut table) {
+            env.remove(key);
+        }
+
+        let message_hub = table
+            .get("astarte_library")
+            .and_then(|library| library.get("type"))
+            .and_then(Value::as_str)
+            == Some("astarte-message-hub");
+        let sdk_keys = if message_hub {
+            &[][..]
+        } else {
+            &SDK_REQUIRED_KEYS[..]
+        };
+        let missing: Vec<String> = sdk_keys
+            .iter()
+            .chain(&REQUIRED_KEYS)
+            .filter(|key| !table.contains_key(**key))
+            .map(|key| key.to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(DeviceManagerError::MissingConfigKeys(missing));
+        }
+
+        let mut unknown = Vec::new();
+        let options = serde_ignored::deserialize(env.deserializer(table), |path| {
+            let key = path.to_string();
+            unknown.push(env.var(&key).map_or(key, str::to_owned));
+        })?;
+
+        Ok((options, unknown))
+    }
+
+    /// Every problem of the options found without starting the runtime.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let message_hub = self.message_hub();
+
+        if message_hub.is_none() && self.realm.is_empty() {
+            errors.push("realm is empty".to_owned());
+        }
+        if let Err(DeviceManagerError::InvalidOption { option, reason }) =
+            device_id::namespace(self)
+        {
+            errors.push(format!("{option} {reason}"));
+        }
+        if let Err(DeviceManagerError::InvalidOption { option, reason }) =
+            http_status::listen_address(self.http_status_address.as_deref(), self.http_status_port)
+        {
+            errors.push(format!("{option} {reason}"));
+        }
+        if let Some(hub) = message_hub {
+            for checked in [hub.endpoint().map(drop), hub.node_id().map(drop)] {
+                if let Err(DeviceManagerError::InvalidOption { option, reason }) = checked {
+                    errors.push(format!("{option} {reason}"));
+                }
+            }
+        } else {
+            let http_url = Url::parse(&self.pairing_url).map_or(false, |url| {
+                matches!(url.scheme(), "http" | "https") && url.host_str().is_some()
+            });
+            if !http_url {
+                errors.push(format!(
+                    "pairing_url {} is not an http(s) URL",
+                    self.pairing_url
+                ));
+            }
+        }
+        let interfaces = Path::new(&self.interfaces_directory);
+        if !interfaces.is_dir() {
+            errors.push(format!(
+                "interfaces_directory {} is not a directory",
+                self.interfaces_directory
+            ));
+        } else if !contains_interfaces(interfaces) {
+            errors.push(format!(
+                "interfaces_directory {} contains no .json interface",
+                self.interfaces_directory
+            ));
+        }
+        for (key, directory) in [
+            ("store_directory", &self.store_directory),
+            ("download_directory", &self.download_directory),
+        ] {
+            if let Err(err) = check_writable(Path::new(directory)) {
+                errors.push(format!("{key} {directory} {err}"));
+            }
+        }
+        if let Some(directory) = &self.ota_trusted_keys_directory {
+            if !Path::new(directory).is_dir() {
+                errors.push(format!(
+                    "ota_trusted_keys_directory {directory} is not a directory"
+                ));
+            }
+        }
+        if let Some(path) = &self.ota_public_key_path {
+            if !Path::new(path).is_file() {
+                errors.push(format!("ota_public_key_path {path} is not a file"));
+            }
+        }
+
+        let tags = self.tags.iter().flatten().map(String::as_str);
+        errors.extend(
+            collect_tags(tags)
+                .1
+                .iter()
+                .map(|err| format!("tags: {err}")),
+        );
+
+        for area in self.storage_areas.iter().flatten() {
+            if area.warning_percent.map_or(false, |percent| percent > 100) {
+                errors.push(format!(
+                    "storage area {}: warning_percent is above 100",
+                    area.label
+                ));
+            }
+        }
+        if let Some(store_forward) = self.store_forward.as_ref().filter(|opts| opts.enabled) {
+            if store_forward.max_samples == Some(0) {
+                errors.push("store_forward.max_samples is 0".to_owned());
+            }
+            if let Some(directory) = &store_forward.directory {
+                if let Err(err) = check_writable(Path::new(directory)) {
+                    errors.push(format!("store_forward.directory {directory} {err}"));
+                }
+            }
+        }
+
+        let periods = [
+            (
+                "network_sockets_period_secs",
+                self.network_sockets_period_secs,
+            ),
+            ("storage_usage_period_secs", self.storage_usage_period_secs),
+            (
+                "cellular_connection_period_secs",
+                self.cellular_connection_period_secs,
+            ),
+            ("geolocation_period_secs", self.geolocation_period_secs),
+            ("wifi_scan_period_secs", self.wifi_scan_period_secs),
+            ("system_load_period_secs", self.system_load_period_secs),
+            ("temperature_period_secs", self.temperature_period_secs),
+            (
+                "containers.status_period_secs",
+                self.containers
+                    .as_ref()
+                    .and_then(|containers| containers.status_period_secs),
+            ),
+        ];
+        for (key, _) in periods.iter().filter(|(_, period)| *period == Some(0)) {
+            errors.push(format!("{key} is 0, periods are at least 1 second"));
+        }
+
+        // the hub holds the credentials of the device
+        if message_hub.is_some() {
+            return errors;
+        }
+        if self.credentials_secret.is_some() && self.pairing_token.is_some() {
+            errors.push(
+                "credentials_secret and pairing_token are both set, keep only one".to_owned(),
+            );
+        } else if self.credentials_secret.is_none()
+            && self.pairing_token.is_none()
+            && !self.credentials_persisted()
+            && !self
+                .onboarding
+                .as_ref()
+                .map_or(false, |onboarding| onboarding.enabled)
+        {
+            errors.push(format!(
+                "no credentials: set credentials_secret or pairing_token, or enable the \
+                 onboarding, none are stored in {}",
+                self.store_directory
+            ));
+        }
+
+        errors
+    }
+
+    /// Options of the Astarte Message Hub the runtime attaches to, instead of connecting with
+    /// the SDK.
+    pub(crate) fn message_hub(&self) -> Option<&MessageHubOptions> {
+        match &self.astarte_library {
+            Some(AstarteLibrary::AstarteMessageHub(hub)) => Some(hub),
+            Some(AstarteLibrary::AstarteDeviceSdk) | None => None,
+        }
+    }
+
+    /// Whether the credentials of a previous registration are in the store directory. Without a
+    /// device id, the credentials of any device in the store directory count.
+    fn credentials_persisted(&self) -> bool {
+        if let Some(device_id) = &self.device_id {
+            // the id the runtime registers with
+            let device_id = &device_id::namespace(self)
+                .map(|namespace| device_id::normalize(&namespace, device_id))
+                .unwrap_or_else(|_| device_id.clone());
+            return Path::new(&self.store_directory)
+                .join(format!("credentials_{device_id}.json"))
+                .is_file();
+        }
+
+        std::fs::read_dir(&self.store_directory).map_or(false, |entries| {
+            entries.flatten().any(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("credentials_") && name.ends_with(".json")
+            })
+        })
+    }
+}
+
+/// Whether `directory` holds at least one interface file.
+fn contains_interfaces(directory: &Path) -> bool {
+    std::fs::read_dir(directory).map_or(false, |entries| {
+        entries
+            .flatten()
+            .any(|entry| entry.path().extension().map_or(false, |ext| ext == "json"))
+    })
+}
+
+/// Check that `directory` is writable, or that it can be created if missing.
+fn check_writable(directory: &Path) -> Result<(), String> {
+    if directory.as_os_str().is_empty() {
+        return Err("is empty".to_owned());
+    }
+
+    // a relative path without existing ancestors is created in the working directory
+    let existing = directory
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let problem = if !existing.is_dir() {
+        "is not a directory"
+    } else if access(existing, AccessFlags::W_OK).is_err() {
+        "is not writable"
+    } else {
+        return Ok(());
+    };
+
+    if existing == directory {
+        Err(problem.to_owned())
+    } else {
+        Err(format!(
+            "can't be created, {} {problem}",
+            existing.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::connection::AstarteLibrary;
+    use crate::data::message_hub::MessageHubOptions;
+    use crate::error::DeviceManagerError;
+    use crate::options::{ConfigOverrides, DEFAULT_STORE_DIRECTORY};
+    use crate::test_utils::ScopedEnv;
+    use crate::DeviceManagerOptions;
+
+    const MINIMAL_CONFIG: &str = r#"
+        realm = "examplerealm"
+        pairing_url = "https://api.astarte.example.com/pairing"
+        interfaces_directory = "/usr/share/edgehog/interfaces"
+        store_directory = "/var/lib/edgehog"
+        download_directory = "/var/tmp/edgehog-updates"
+        "#;
+
+    fn no_env() -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn write_config(directory: &tempfile::TempDir, config: &str) -> String {
+        let path = directory.path().join("config.toml");
+        std::fs::write(&path, config).unwrap();
+
+        path.to_str().unwrap().to_owned()
+    }
+
+    /// A directory with an interface, the store and download directories, and the configuration
+    /// using them with `extra` appended.
+    fn valid_setup(extra: &str) -> (tempfile::TempDir, String) {
+        let directory = tempfile::tempdir().unwrap();
+        let interfaces = directory.path().join("interfaces");
+        std::fs::create_dir(&interfaces).unwrap();
+        std::fs::write(
+            interfaces.join("io.edgehog.devicemanager.OTARequest.json"),
+            "{}",
+        )
+        .unwrap();
+        let path = write_config(
+            &directory,
+            &format!(
+                r#"
+                realm = "examplerealm"
+                pairing_url = "https://api.astarte.example.com/pairing"
+                interfaces_directory = "{}"
+                store_directory = "{root}/store"
+                download_directory = "{root}/updates"
+                {extra}
+                "#,
+                interfaces.display(),
+                root = directory.path().display(),
+            ),
+        );
+
+        (directory, path)
+    }
+
+    #[test]
+    fn override_path_read_first() {
+        let _env = ScopedEnv::set(&[]);
+        let (_directory, path) = valid_setup(r#"pairing_token = "pairing-token""#);
+
+        let options =
+            DeviceManagerOptions::from_sources(Some(path), &ConfigOverrides::default()).unwrap();
+
+        assert_eq!(options.realm, "examplerealm");
+        assert!(options.validate().is_empty());
+    }
+
+    #[test]
+    fn validation_errors_aggregated() {
+        let _env = ScopedEnv::set(&[]);
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let path = write_config(
+            &directory,
+            &format!(
+                r#"
+                realm = ""
+                pairing_url = "api.astarte.example.com/pairing"
+                interfaces_directory = "/nonexistent/interfaces"
+                store_directory = "{store}"
+                download_directory = "{file}/updates"
+                credentials_secret = "credentials-secret"
+                pairing_token = "pairing-token"
+                ota_public_key_path = "/nonexistent/ota.pem"
+                tags = ["rev-b", "eu west"]
+                system_load_period_secs = 0
+
+                [containers]
+                status_period_secs = 0
+
+                [[storage_areas]]
+                label = "media"
+                path = "/media"
+                warning_percent = 120
+                "#,
+                store = directory.path().display(),
+                file = file.display(),
+            ),
+        );
+
+        let options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+
+        assert_eq!(
+            options.validate(),
+            vec![
+                "realm is empty".to_owned(),
+                "pairing_url api.astarte.example.com/pairing is not an http(s) URL".to_owned(),
+                "interfaces_directory /nonexistent/interfaces is not a directory".to_owned(),
+                format!(
+                    "download_directory {0}/updates can't be created, {0} is not a directory",
+                    file.display()
+                ),
+                "ota_public_key_path /nonexistent/ota.pem is not a file".to_owned(),
+                "tags: tag eu west contains characters other than letters, digits, '-' and '_'"
+                    .to_owned(),
+                "storage area media: warning_percent is above 100".to_owned(),
+                "system_load_period_secs is 0, periods are at least 1 second".to_owned(),
+                "containers.status_period_secs is 0, periods are at least 1 second".to_owned(),
+                "credentials_secret and pairing_token are both set, keep only one".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn store_forward_validated() {
+        let _env = ScopedEnv::set(&[]);
+        let (_directory, path) = valid_setup(
+            r#"credentials_secret = "credentials-secret"
+
+            [store_forward]
+            enabled = true
+            directory = "/dev/null/edgehog"
+            max_samples = 0"#,
+        );
+        let options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+
+        assert_eq!(
+            options.validate(),
+            [
+                "store_forward.max_samples is 0",
+                "store_forward.directory /dev/null/edgehog can't be created, /dev/null is not a \
+                 directory",
+            ],
+        );
+    }
+
+    #[test]
+    fn pairing_url_must_be_http() {
+        let _env = ScopedEnv::set(&[]);
+        for url in [
+            "ftp://api.astarte.example.com/pairing",
+            "https://",
+            "https//example.com",
+        ] {
+            let (_directory, path) = valid_setup(r#"credentials_secret = "credentials-secret""#);
+            let overrides = ConfigOverrides {
+                pairing_url: Some(url.to_owned()),
+                ..Default::default()
+            };
+            let options = DeviceManagerOptions::from_sources(Some(path), &overrides).unwrap();
+
+            assert_eq!(
+                options.validate(),
+                [format!("pairing_url {url} is not an http(s) URL")],
+            );
+        }
+    }
+
+    #[test]
+    fn device_id_namespace_validated() {
+        let _env = ScopedEnv::set(&[]);
+        let (directory, path) = valid_setup(
+            r#"
+            device_id = "hw-id"
+            device_id_namespace = "edgehog.io"
+            "#,
+        );
+        let store = directory.path().join("store");
+        std::fs::create_dir(&store).unwrap();
+        // stored under the id derived from hw-id in the default namespace
+        std::fs::write(
+            store.join("credentials__rsyBd76WyWXXjLPDfY7aA.json"),
+            "\"secret\"",
+        )
+        .unwrap();
+
+        let mut options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+        let errors = options.validate();
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(
+            errors[0].starts_with("device_id_namespace edgehog.io is not a UUID"),
+            "{errors:?}"
+        );
+
+        options.device_id_namespace = None;
+        assert!(options.validate().is_empty());
+    }
+
+    #[test]
+    fn http_status_address_validated() {
+        let _env = ScopedEnv::set(&[]);
+        let (_directory, path) = valid_setup(
+            r#"
+            credentials_secret = "credentials-secret"
+            http_status_port = 9100
+            http_status_address = "localhost"
+            "#,
+        );
+
+        let mut options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+        assert_eq!(
+            options.validate(),
+            ["http_status_address localhost is not an IP address"]
+        );
+
+        options.http_status_address = Some("0.0.0.0".to_owned());
+        assert!(options.validate().is_empty());
+    }
+
+    #[test]
+    fn message_hub_needs_no_astarte_credentials() {
+        let _env = ScopedEnv::set(&[]);
+        let directory = tempfile::tempdir().unwrap();
+        let interfaces = directory.path().join("interfaces");
+        std::fs::create_dir(&interfaces).unwrap();
+        std::fs::write(
+            interfaces.join("io.edgehog.devicemanager.OTARequest.json"),
+            "{}",
+        )
+        .unwrap();
+        let path = write_config(
+            &directory,
+            &format!(
+                r#"
+                interfaces_directory = "{}"
+                store_directory = "{root}/store"
+                download_directory = "{root}/updates"
+
+                [astarte_library]
+                type = "astarte-message-hub"
+                endpoint = "https://[::1]:50051"
+                "#,
+                interfaces.display(),
+                root = directory.path().display(),
+            ),
+        );
+
+        let mut options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+        assert_eq!(
+            options.validate(),
+            ["astarte_library.endpoint https://[::1]:50051 is not an http URL"]
+        );
+
+        options.astarte_library = Some(AstarteLibrary::AstarteMessageHub(
+            MessageHubOptions::default(),
+        ));
+        assert!(options.validate().is_empty());
+    }
+
+    #[test]
+    fn interfaces_required() {
+        let _env = ScopedEnv::set(&[]);
+        let (directory, path) = valid_setup(r#"credentials_secret = "credentials-secret""#);
+        let interfaces = directory.path().join("interfaces");
+        std::fs::remove_file(interfaces.join("io.edgehog.devicemanager.OTARequest.json")).unwrap();
+        std::fs::write(interfaces.join("README.md"), "").unwrap();
+
+        let options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+
+        assert_eq!(
+            options.validate(),
+            [format!(
+                "interfaces_directory {} contains no .json interface",
+                interfaces.display()
+            )]
+        );
+    }
+
+    #[test]
+    fn credentials_required() {
+        let _env = ScopedEnv::set(&[]);
+        let (directory, path) = valid_setup("");
+        let store = directory.path().join("store");
+
+        let options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+        assert_eq!(
+            options.validate(),
+            [format!(
+                "no credentials: set credentials_secret or pairing_token, or enable the \
+                 onboarding, none are stored in {}",
+                store.display()
+            )]
+        );
+
+        // the credentials of a previous registration are enough
+        std::fs::create_dir(&store).unwrap();
+        std::fs::write(store.join("credentials_device_id.json"), "\"secret\"").unwrap();
+        assert!(options.validate().is_empty());
+
+        // unless they belong to another device
+        let (_directory, path) = valid_setup(r#"device_id = "other_device_id""#);
+        let options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+        assert_eq!(options.validate().len(), 1);
+
+        // the onboarding can provide the pairing token
+        let (_directory, path) = valid_setup(
+            r#"
+            [onboarding]
+            enabled = true
+            "#,
+        );
+        let options = DeviceManagerOptions::from_file(path.as_ref()).unwrap();
+        assert!(options.validate().is_empty());
+    }
+
+    #[test]
+    fn missing_override_path_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("missing.toml");
+
+        let result = DeviceManagerOptions::from_sources(
+            Some(path.to_str().unwrap().to_owned()),
+            &ConfigOverrides::default(),
+        );
+
+        match result {
+            Err(DeviceManagerError::ConfigNotFound(missing)) => {
+                assert_eq!(missing, path.to_str().unwrap())
+            }
+            _ => panic!("missing configuration file accepted"),
+        }
+    }
+
+    #[test]
+    fn defaults_of_the_optional_keys() {
+        let (options, unknown) =
+            DeviceManagerOptions::parse(MINIMAL_CONFIG, no_env(), &ConfigOverrides::default())
+                .unwrap();
+
+        assert_eq!(options.realm, "examplerealm");
+        assert_eq!(options.device_id, None);
+        assert_eq!(options.force_resend_properties, None);
+        assert!(options.containers.is_none());
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let _env = ScopedEnv::set(&[]);
+        let directory = tempfile::tempdir().unwrap();
+        let path = write_config(&directory, MINIMAL_CONFIG);
+        let overrides = ConfigOverrides {
+            realm: Some("otherrealm".to_owned()),
+            device_id: Some("YnRUb3FYTXpTZ3lOcjV3dEtHcUJPZw".to_owned()),
+            force_resend_properties: true,
+            ..Default::default()
+        };
+
+        let options = DeviceManagerOptions::from_sources(Some(path), &overrides).unwrap();
+
+        assert_eq!(options.realm, "otherrealm");
+        assert_eq!(
+            options.device_id.as_deref(),
+            Some("YnRUb3FYTXpTZ3lOcjV3dEtHcUJPZw")
+        );
+        assert_eq!(options.force_resend_properties, Some(true));
+        assert_eq!(
+            options.pairing_url,
+            "https://api.astarte.example.com/pairing"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_reported() {
+        let config = format!(
+            r#"
+            {MINIMAL_CONFIG}
+            pairing_urll = "https://api.astarte.example.com/pairing"
+
+            [containers]
+            enabld = true
+            "#
+        );
+
+        let (options, unknown) =
+            DeviceManagerOptions::parse(&config, no_env(), &ConfigOverrides::default()).unwrap();
+
+        assert!(!options.containers.unwrap().enabled);
+        assert_eq!(unknown, ["containers.enabld", "pairing_urll"]);
+    }
+
+    #[test]
+    fn missing_keys_aggregated() {
+        let config = r#"
+            realm = "examplerealm"
+            store_directory = "/var/lib/edgehog"
+            "#;
+
+        let result = DeviceManagerOptions::parse(config, no_env(), &ConfigOverrides::default());
+        match result {
+            Err(DeviceManagerError::MissingConfigKeys(missing)) => assert_eq!(
+                missing,
+                ["pairing_url", "interfaces_directory", "download_directory"]
+            ),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        // the command line can provide the keys missing from the file
+        let overrides = ConfigOverrides {
+            pairing_url: Some("https://api.astarte.example.com/pairing".to_owned()),
+            interfaces_directory: Some("/usr/share/edgehog/interfaces".to_owned()),
+            download_directory: Some("/var/tmp/edgehog-updates".to_owned()),
+            ..Default::default()
+        };
+        assert!(DeviceManagerOptions::parse(config, no_env(), &overrides).is_ok());
+    }
+
+    #[test]
+    fn store_directory_defaulted() {
+        let config = r#"
+            realm = "examplerealm"
+            pairing_url = "https://api.astarte.example.com/pairing"
+            interfaces_directory = "/usr/share/edgehog/interfaces"
+            download_directory = "/var/tmp/edgehog-updates"
+            "#;
+
+        let (options, _) =
+            DeviceManagerOptions::parse(config, no_env(), &ConfigOverrides::default()).unwrap();
+
+        assert_eq!(options.store_directory, DEFAULT_STORE_DIRECTORY);
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let vars = env(&[
+            ("EDGEHOG_REALM", " envrealm\n"),
+            ("EDGEHOG_PAIRING_TOKEN", "pairing-token"),
+            ("EDGEHOG_SEND_TIMEOUT_SECS", "30"),
+            ("EDGEHOG_TAGS", "rev-b, eu-west"),
+            ("EDGEHOG_CONTAINERS__ENABLED", "true"),
+            ("EDGEHOG_CONTAINERS__STATUS_PERIOD_SECS", "15"),
+            ("EDGEHOG_DEVICE_ID", "  "),
+            ("EDGEHOG_SERIAL_NUMBER", "SN-7"),
+            ("HOME", "/root"),
+        ]);
+        let config = format!(
+            r#"
+            {MINIMAL_CONFIG}
+            send_timeout_secs = 10
+
+            [containers]
+            status_period_secs = 60
+            "#
+        );
+
+        let (options, unknown) =
+            DeviceManagerOptions::parse(&config, vars, &ConfigOverrides::default()).unwrap();
+
+        assert_eq!(options.realm, "envrealm");
+        assert_eq!(options.pairing_token.as_deref(), Some("pairing-token"));
+        assert_eq!(options.send_timeout_secs, Some(30));
+        assert_eq!(
+            options.tags,
+            Some(vec!["rev-b".to_owned(), "eu-west".to_owned()])
+        );
+        let containers = options.containers.unwrap();
+        assert!(containers.enabled);
+        assert_eq!(containers.status_period_secs, Some(15));
+        // the blank value sets nothing
+        assert_eq!(options.device_id, None);
+        assert_eq!(options.store_directory, "/var/lib/edgehog");
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn command_line_overrides_environment() {
+        let overrides = ConfigOverrides {
+            realm: Some("clirealm".to_owned()),
+            ..Default::default()
+        };
+
+        let (options, _) = DeviceManagerOptions::parse(
+            MINIMAL_CONFIG,
+            env(&[("EDGEHOG_REALM", "envrealm")]),
+            &overrides,
+        )
+        .unwrap();
+
+        assert_eq!(options.realm, "clirealm");
+    }
+
+    #[test]
+    fn environment_provides_required_keys() {
+        let vars = env(&[
+            (
+                "EDGEHOG_PAIRING_URL",
+                "https://api.astarte.example.com/pairing",
+            ),
+            (
+                "EDGEHOG_INTERFACES_DIRECTORY",
+                "/usr/share/edgehog/interfaces",
+            ),
+            ("EDGEHOG_DOWNLOAD_DIRECTORY", "/var/tmp/edgehog-updates"),
+        ]);
+        let config = r#"
+            realm = "examplerealm"
+            store_directory = "/var/lib/edgehog"
+            "#;
+
+        let (options, _) =
+            DeviceManagerOptions::parse(config, vars, &ConfigOverrides::default()).unwrap();
+
+        assert_eq!(options.download_directory, "/var/tmp/edgehog-updates");
+    }
+
+    #[test]
+    fn invalid_environment_values_named() {
+        let cases = [
+            (
+                ("EDGEHOG_CONTAINERS__ENABLED", "yes"),
+                r#"EDGEHOG_CONTAINERS__ENABLED: "yes" is not true or false"#,
+            ),
+            (
+                ("EDGEHOG_SEND_TIMEOUT_SECS", "-1"),
+                r#"EDGEHOG_SEND_TIMEOUT_SECS: "-1" is not a positive integer"#,
+            ),
+            (
+                ("EDGEHOG_CONTAINERS", "on"),
+                "EDGEHOG_CONTAINERS: sets a table, set its keys with EDGEHOG_CONTAINERS__<KEY>",
+            ),
+            (
+                ("EDGEHOG_REALM__NAME", "examplerealm"),
+                "EDGEHOG_REALM__NAME: realm is not a table",
+            ),
+            (
+                ("EDGEHOG_CONTAINERS____ENABLED", "true"),
+                "EDGEHOG_CONTAINERS____ENABLED: invalid name",
+            ),
+        ];
+
+        for ((name, value), message) in cases {
+            let result = DeviceManagerOptions::parse(
+                MINIMAL_CONFIG,
+                env(&[(name, value)]),
+                &ConfigOverrides::default(),
+            );
+            match result {
+                Err(DeviceManagerError::ConfigFileError(err)) => {
+                    assert!(err.to_string().contains(message), "{name}: {err}")
+                }
+                other => panic!("{name}: unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_environment_variables_reported() {
+        let (_, unknown) = DeviceManagerOptions::parse(
+            MINIMAL_CONFIG,
+            env(&[("EDGEHOG_REALMM", "examplerealm")]),
+            &ConfigOverrides::default(),
+        )
+        .unwrap();
+
+        assert_eq!(unknown, ["EDGEHOG_REALMM"]);
+    }
+
+    #[test]
+    fn process_environment_read() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = write_config(&directory, MINIMAL_CONFIG);
+        let _env = ScopedEnv::set(&[
+            ("EDGEHOG_DOWNLOAD_DIRECTORY", "/data/edgehog-updates"),
+            ("EDGEHOG_LED_BEHAVIOR_DURATION_SECS", "5"),
+        ]);
+
+        let options =
+            DeviceManagerOptions::from_sources(Some(path), &ConfigOverrides::default()).unwrap();
+
+        assert_eq!(options.download_directory, "/data/edgehog-updates");
+        assert_eq!(options.led_behavior_duration_secs, Some(5));
+    }
+}
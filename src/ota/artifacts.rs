@@ -0,0 +1,220 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! The artifacts of the OTA requests in the download directory.
+//!
+//! Each artifact is named after its request, e.g. `update-<uuid>.bin`, so that the ones left by
+//! failed or interrupted updates can be told apart from the update in progress and from the
+//! unrelated files sharing the directory, which are never touched.
+
+use std::path::Path;
+
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::ota::messages::BundleType;
+
+const UPDATE_PREFIX: &str = "update-";
+const KEY_BUNDLE_PREFIX: &str = "trusted_keys-";
+const EXTENSION: &str = ".bin";
+/// Artifacts of the older releases, shared by all the requests.
+const LEGACY_ARTIFACTS: [&str; 2] = ["update.bin", "trusted_keys.bin"];
+
+/// Name of the artifact of the request `uuid` in the download directory.
+pub(crate) fn artifact_name(bundle_type: BundleType, uuid: Uuid) -> String {
+    let prefix = match bundle_type {
+        BundleType::Update => UPDATE_PREFIX,
+        BundleType::KeyBundle => KEY_BUNDLE_PREFIX,
+    };
+
+    format!("{prefix}{uuid}{EXTENSION}")
+}
+
+/// Request of the artifact named `name`, `None` when it's not one.
+fn artifact_request(name: &str) -> Option<Uuid> {
+    let stem = name.strip_suffix(EXTENSION)?;
+    let uuid = stem
+        .strip_prefix(UPDATE_PREFIX)
+        .or_else(|| stem.strip_prefix(KEY_BUNDLE_PREFIX))?;
+
+    Uuid::parse_str(uuid).ok()
+}
+
+/// What [`remove_stale`] removed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Reclaimed {
+    pub files: Vec<String>,
+    pub bytes: u64,
+}
+
+/// Remove the artifacts of `directory` not belonging to the request `active`, logging what was
+/// removed. The entries that can't be read or removed are skipped.
+pub(crate) fn remove_stale(directory: &Path, active: Option<Uuid>) -> Reclaimed {
+    let mut reclaimed = Reclaimed::default();
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(
+                "Unable to list {} for stale artifacts: {err}",
+                directory.display()
+            );
+            return reclaimed;
+        }
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Unable to read an entry of {}: {err}", directory.display());
+                continue;
+            }
+        };
+        // not named by the runtime
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let stale = match artifact_request(&name) {
+            Some(request) => Some(request) != active,
+            None => LEGACY_ARTIFACTS.contains(&name.as_str()),
+        };
+        if !stale {
+            continue;
+        }
+
+        let path = entry.path();
+        let len = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("Unable to read {}: {err}", path.display());
+                continue;
+            }
+        };
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                info!(
+                    "Removed the stale OTA artifact {} ({len} bytes)",
+                    path.display()
+                );
+                reclaimed.files.push(name);
+                reclaimed.bytes += len;
+            }
+            Err(err) => warn!("Unable to remove {}: {err}", path.display()),
+        }
+    }
+
+    if !reclaimed.files.is_empty() {
+        info!(
+            "Reclaimed {} bytes from {} stale OTA artifacts",
+            reclaimed.bytes,
+            reclaimed.files.len()
+        );
+    }
+
+    reclaimed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use uuid::Uuid;
+
+    use crate::ota::artifacts::{artifact_name, artifact_request, remove_stale, Reclaimed};
+    use crate::ota::messages::BundleType;
+
+    #[test]
+    fn artifacts_named_after_the_request() {
+        let uuid = Uuid::new_v4();
+
+        let update = artifact_name(BundleType::Update, uuid);
+        let key_bundle = artifact_name(BundleType::KeyBundle, uuid);
+
+        assert_eq!(update, format!("update-{uuid}.bin"));
+        assert_ne!(update, key_bundle);
+        assert_eq!(artifact_request(&update), Some(uuid));
+        assert_eq!(artifact_request(&key_bundle), Some(uuid));
+        assert_eq!(artifact_request("update-backup.bin"), None);
+        assert_eq!(artifact_request(&format!("{uuid}.bin")), None);
+    }
+
+    #[test]
+    fn only_stale_artifacts_removed() {
+        let download = tempfile::tempdir().unwrap();
+        let active = Uuid::new_v4();
+        let stale_update = artifact_name(BundleType::Update, Uuid::new_v4());
+        let stale_keys = artifact_name(BundleType::KeyBundle, Uuid::new_v4());
+        let partial = artifact_name(BundleType::Update, active);
+        for (name, content) in [
+            (stale_update.as_str(), &b"stale bundle"[..]),
+            (stale_keys.as_str(), b"keys"),
+            ("update.bin", b"legacy"),
+            (partial.as_str(), b"partial"),
+            ("notes.txt", b"unrelated"),
+            ("update-notes.bin", b"unrelated"),
+        ] {
+            std::fs::write(download.path().join(name), content).unwrap();
+        }
+        // named like an artifact, but not a file
+        let directory = artifact_name(BundleType::Update, Uuid::new_v4());
+        std::fs::create_dir(download.path().join(&directory)).unwrap();
+
+        let mut reclaimed = remove_stale(download.path(), Some(active));
+        reclaimed.files.sort();
+
+        let mut expected = vec![stale_update, stale_keys, "update.bin".to_owned()];
+        expected.sort();
+        assert_eq!(
+            reclaimed,
+            Reclaimed {
+                files: expected,
+                bytes: 22,
+            }
+        );
+        let left: BTreeSet<String> = std::fs::read_dir(download.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(
+            left,
+            BTreeSet::from([
+                partial,
+                "notes.txt".to_owned(),
+                "update-notes.bin".to_owned(),
+                directory,
+            ])
+        );
+    }
+
+    #[test]
+    fn every_artifact_stale_without_an_update() {
+        let download = tempfile::tempdir().unwrap();
+        let artifact = artifact_name(BundleType::Update, Uuid::new_v4());
+        std::fs::write(download.path().join(&artifact), b"bundle").unwrap();
+
+        assert_eq!(remove_stale(download.path(), None).files, vec![artifact]);
+        assert_eq!(
+            remove_stale(&download.path().join("missing"), None),
+            Reclaimed::default()
+        );
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Probe of the link bandwidth before the OTA downloads.
+//!
+//! Over a shared uplink a large download may never complete. When configured, the first bytes
+//! of the artifact, or of a dedicated probe URL, are fetched to measure the throughput, and the
+//! download is deferred while it stays below a floor. The link is probed again on a backoff
+//! schedule until a deadline, past which the update fails so the backend can reschedule it.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+pub const DEFAULT_PROBE_BYTES: u64 = 256 * 1024;
+pub const DEFAULT_PROBE_RETRY: Duration = Duration::from_secs(60);
+pub const DEFAULT_PROBE_DEADLINE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest wait between two probes, unless the configured retry is longer.
+const MAX_PROBE_RETRY: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BandwidthProbeOptions {
+    /// Throughput below which the download is deferred, in bytes per second.
+    pub min_bytes_per_sec: u64,
+    /// Bytes fetched to measure the throughput.
+    pub probe_bytes: Option<u64>,
+    /// Probed instead of the artifact, e.g. a file on the same server.
+    pub probe_url: Option<String>,
+    /// Wait before probing again, doubled after each slow probe.
+    pub retry_secs: Option<u64>,
+    /// Time after which a link still too slow fails the update.
+    pub deadline_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BandwidthProbe {
+    pub min_bytes_per_sec: u64,
+    pub probe_bytes: u64,
+    probe_url: Option<String>,
+    retry: Duration,
+    pub deadline: Duration,
+}
+
+impl BandwidthProbe {
+    pub fn new(options: &BandwidthProbeOptions) -> Self {
+        let secs = |secs: Option<u64>, default| {
+            secs.filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+
+        BandwidthProbe {
+            min_bytes_per_sec: options.min_bytes_per_sec,
+            probe_bytes: options
+                .probe_bytes
+                .filter(|bytes| *bytes > 0)
+                .unwrap_or(DEFAULT_PROBE_BYTES),
+            probe_url: options.probe_url.clone(),
+            retry: secs(options.retry_secs, DEFAULT_PROBE_RETRY),
+            deadline: secs(options.deadline_secs, DEFAULT_PROBE_DEADLINE),
+        }
+    }
+
+    /// URL probed before downloading `artifact_url`.
+    pub fn url<'a>(&'a self, artifact_url: &'a str) -> &'a str {
+        self.probe_url.as_deref().unwrap_or(artifact_url)
+    }
+
+    /// Wait after the slow probe number `attempt`, counting from 0.
+    pub fn retry_after(&self, attempt: u32) -> Duration {
+        self.retry
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(MAX_PROBE_RETRY.max(self.retry))
+    }
+}
+
+/// Bytes per second of `received` bytes taking `elapsed`.
+pub fn throughput(received: u64, elapsed: Duration) -> u64 {
+    // a transfer faster than the clock resolution counts as taking a millisecond
+    (received as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::body::Bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use tokio::task::JoinHandle;
+
+    use crate::clock::Clock;
+    use crate::ota::bandwidth::{throughput, BandwidthProbe, BandwidthProbeOptions};
+    use crate::ota::download::Downloader;
+    use crate::test_utils::ManualClock;
+
+    /// Serve the ranges of a `length` bytes artifact, the first `slow` requests at
+    /// `bytes_per_sec` on `clock` and the others at full speed. Returns the count of the
+    /// requests along with the address.
+    pub(crate) fn throttled_server(
+        clock: Arc<ManualClock>,
+        length: usize,
+        bytes_per_sec: usize,
+        slow: usize,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counted = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let clock = clock.clone();
+            let counted = counted.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let index = counted.fetch_add(1, Ordering::SeqCst);
+                    let end = request
+                        .headers()
+                        .get("Range")
+                        .and_then(|range| range.to_str().ok())
+                        .and_then(|range| range.strip_prefix("bytes=0-"))
+                        .and_then(|end| end.parse::<usize>().ok())
+                        .map_or(length, |end| (end + 1).min(length));
+
+                    let clock = clock.clone();
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        let content = Bytes::from(vec![0x5a; end]);
+                        if index >= slow {
+                            sender.send_data(content).await.ok();
+                            return;
+                        }
+
+                        for start in (0..end).step_by(bytes_per_sec) {
+                            if start > 0 {
+                                clock.sleep(Duration::from_secs(1)).await;
+                            }
+                            let chunk = content.slice(start..(start + bytes_per_sec).min(end));
+                            if sender.send_data(chunk).await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                    let response = Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header("Content-Length", end)
+                        .body(body);
+
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, requests)
+    }
+
+    /// Move `clock` forward by 100ms every millisecond, so the transfers take their time on it.
+    pub(crate) fn drive(clock: Arc<ManualClock>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                clock.advance(Duration::from_millis(100));
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+    }
+
+    #[test]
+    fn retry_doubles_up_to_the_cap() {
+        let probe = BandwidthProbe::new(&BandwidthProbeOptions {
+            min_bytes_per_sec: 1024,
+            probe_bytes: None,
+            probe_url: None,
+            retry_secs: Some(300),
+            deadline_secs: None,
+        });
+
+        let retries: Vec<u64> = (0..5)
+            .map(|attempt| probe.retry_after(attempt).as_secs())
+            .collect();
+        assert_eq!(retries, [300, 600, 1200, 1800, 1800]);
+        assert_eq!(probe.url("http://ota.bin"), "http://ota.bin");
+        assert_eq!(throughput(4096, Duration::from_secs(2)), 2048);
+        assert_eq!(throughput(4096, Duration::ZERO), 4096 * 1000);
+    }
+
+    #[tokio::test]
+    async fn throughput_measured_on_the_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let (address, requests) = throttled_server(clock.clone(), 1024 * 1024, 8 * 1024, 1);
+        let downloader = Downloader::new(None, clock.clone());
+        let url = format!("http://{address}/artifact");
+        let driver = drive(clock);
+
+        let slow = downloader.probe(&url, 64 * 1024).await.unwrap();
+        let fast = downloader.probe(&url, 64 * 1024).await.unwrap();
+        driver.abort();
+
+        // 8 chunks a second apart, the scheduling can only make it slower
+        assert!(slow <= 64 * 1024 / 7, "{slow}");
+        assert!(fast >= 64 * 1024, "{fast}");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
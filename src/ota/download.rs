@@ -0,0 +1,1549 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! HTTP client used to download the OTA artifacts, optionally authenticated with the device
+//! credentials.
+//!
+//! A transfer broken halfway keeps the partial file and goes on from the bytes received with a
+//! range request, as long as the server still has the same version of the artifact. A download
+//! the filesystem of the download directory can't hold is refused before writing anything.
+
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use reqwest::header::{ETAG, IF_RANGE, RANGE};
+use reqwest::{RequestBuilder, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::disk_guard::{self, DiskGuard, SpaceProvider};
+use crate::error::DeviceManagerError;
+use crate::ota::bandwidth;
+use crate::ota::host_cache::{HostCache, UnreachableHostOptions};
+use crate::ota::local;
+use crate::ota::ota_handler::{retry_with_backoff, OTAError};
+use crate::ota::progress::DownloadProgress;
+use crate::ota::rate_limit::RateLimiter;
+use crate::redaction::redactor;
+
+/// Tokens are refreshed this long before their declared expiration.
+const TOKEN_EXPIRATION_MARGIN: Duration = Duration::from_secs(30);
+/// Broken transfers resumed during a download, unless configured otherwise.
+pub const DEFAULT_RESUME_ATTEMPTS: u32 = 5;
+/// Attempts of each request of a download, unless configured otherwise.
+pub const DEFAULT_DOWNLOAD_ATTEMPTS: u32 = 5;
+/// Space left free on top of an artifact, unless configured otherwise.
+pub const DEFAULT_SPACE_MARGIN: u64 = 16 * 1024 * 1024;
+/// Free space required by an artifact of unknown length, unless configured otherwise.
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 256 * 1024 * 1024;
+/// Longest detached signature accepted, the RSA ones are a few hundred bytes.
+const MAX_SIGNATURE_LENGTH: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownloadAuthOptions {
+    /// Artifacts whose URL starts with this prefix are downloaded with a bearer token
+    pub url_prefix: String,
+    /// Endpoint issuing the tokens with the client credentials grant
+    pub token_endpoint: String,
+}
+
+/// Client credentials of the device for the token endpoint.
+#[derive(Clone)]
+pub struct DownloadAuth {
+    options: DownloadAuthOptions,
+    client_id: String,
+    client_secret: String,
+}
+
+impl DownloadAuth {
+    pub fn new(options: DownloadAuthOptions, client_id: String, client_secret: String) -> Self {
+        DownloadAuth {
+            options,
+            client_id,
+            client_secret,
+        }
+    }
+}
+
+impl Debug for DownloadAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DownloadAuth")
+            .field("options", &self.options)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct DownloadSpaceOptions {
+    /// Space left free on top of the length of the artifact reported by the server.
+    pub margin_bytes: Option<u64>,
+    /// Free space required when the server doesn't report the length of the artifact.
+    pub min_free_bytes: Option<u64>,
+}
+
+/// Free space required on the filesystem of the download directory.
+struct SpaceCheck {
+    provider: Box<dyn SpaceProvider>,
+    margin: u64,
+    min_free: u64,
+}
+
+/// Where an interrupted download restarts from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumePoint {
+    /// Bytes of the artifact already written.
+    pub offset: u64,
+    /// Version of the artifact the bytes belong to, the download restarts without it.
+    pub etag: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadOutcome {
+    Completed,
+    /// Stopped by the shutdown, the written bytes are synced to disk.
+    Paused(ResumePoint),
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+struct Token {
+    value: String,
+    expires_at: Option<Instant>,
+}
+
+/// Retries of the failed requests of a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    /// Longest time spent retrying a request, from its first attempt.
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: DEFAULT_DOWNLOAD_ATTEMPTS,
+            max_duration: None,
+        }
+    }
+}
+
+/// Whether a request may succeed when sent again: the connection failed, timed out or the
+/// server failed. The requests the server refused, e.g. with 404 or 403, fail with a network
+/// error and the ones to a host cached as unreachable keep their code.
+pub(crate) fn retryable(err: &DeviceManagerError) -> Result<(), OTAError> {
+    match err {
+        DeviceManagerError::ReqwestError(err)
+            if err.is_connect()
+                || err.is_timeout()
+                || err
+                    .status()
+                    .map_or(false, |status| status.is_server_error()) =>
+        {
+            Ok(())
+        }
+        DeviceManagerError::OTAError(OTAError::HostUnreachableCached) => {
+            Err(OTAError::HostUnreachableCached)
+        }
+        _ => Err(OTAError::Network),
+    }
+}
+
+pub struct Downloader {
+    client: reqwest::Client,
+    auth: Option<DownloadAuth>,
+    clock: Arc<dyn Clock>,
+    token: Mutex<Option<Token>>,
+    disk_guard: Option<Arc<DiskGuard>>,
+    resume_attempts: u32,
+    /// Longest time a download may take, across all its attempts.
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+    /// Cap on the speed of the downloads, unlimited when unset.
+    max_speed: Option<u64>,
+    hosts: HostCache,
+    /// Hard-link the bundles staged on the device instead of copying them.
+    link_local: bool,
+    space: Option<SpaceCheck>,
+}
+
+impl Downloader {
+    pub fn new(auth: Option<DownloadAuth>, clock: Arc<dyn Clock>) -> Self {
+        Downloader {
+            client: reqwest::Client::new(),
+            auth,
+            hosts: HostCache::new(clock.clone(), None),
+            clock,
+            token: Mutex::new(None),
+            disk_guard: None,
+            resume_attempts: DEFAULT_RESUME_ATTEMPTS,
+            timeout: None,
+            retry: RetryPolicy::default(),
+            max_speed: None,
+            link_local: false,
+            space: None,
+        }
+    }
+
+    /// Resume up to `resume_attempts` broken transfers and give up after `timeout`, with no
+    /// time limit when unset.
+    pub fn with_resume(mut self, resume_attempts: Option<u32>, timeout: Option<Duration>) -> Self {
+        self.resume_attempts = resume_attempts.unwrap_or(DEFAULT_RESUME_ATTEMPTS);
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send each request up to `attempts` times, retrying it for at most `max_duration`.
+    pub fn with_retry(mut self, attempts: Option<u32>, max_duration: Option<Duration>) -> Self {
+        self.retry = RetryPolicy {
+            attempts: attempts
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(DEFAULT_DOWNLOAD_ATTEMPTS),
+            max_duration,
+        };
+        self
+    }
+
+    /// Read the downloads at `bytes_per_sec` at most, unlimited when unset or 0.
+    pub fn with_max_speed(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.max_speed = bytes_per_sec.filter(|bytes_per_sec| *bytes_per_sec > 0);
+        self
+    }
+
+    /// Mark the hosts unreachable after the failed connections set in `options`, with the
+    /// defaults when unset.
+    pub fn with_unreachable_hosts(mut self, options: Option<&UnreachableHostOptions>) -> Self {
+        self.hosts = HostCache::new(self.clock.clone(), options);
+        self
+    }
+
+    /// Send the next requests to the host of `url` even if it is marked unreachable.
+    pub fn retry_host(&self, url: &str) {
+        if let Some(host) = host(url) {
+            self.hosts.forget(&host);
+        }
+    }
+
+    /// Hard-link the bundles staged on the device into the download directory, copying them
+    /// when unset or when they are on another filesystem.
+    pub fn with_local_links(mut self, link_local: Option<bool>) -> Self {
+        self.link_local = link_local.unwrap_or(false);
+        self
+    }
+
+    /// Stage the bundle at the local `source` into `path`.
+    pub fn stage(&self, source: &Path, path: &Path) -> Result<(), OTAError> {
+        local::stage(source, path, self.link_local)
+    }
+
+    /// Refuse the downloads the filesystem of their file can't hold, as reported by `provider`.
+    pub fn with_space_check(
+        mut self,
+        provider: Box<dyn SpaceProvider>,
+        options: Option<&DownloadSpaceOptions>,
+    ) -> Self {
+        self.space = Some(SpaceCheck {
+            provider,
+            margin: options
+                .and_then(|options| options.margin_bytes)
+                .unwrap_or(DEFAULT_SPACE_MARGIN),
+            min_free: options
+                .and_then(|options| options.min_free_bytes)
+                .unwrap_or(DEFAULT_MIN_FREE_SPACE),
+        });
+        self
+    }
+
+    /// Send the requests with `client`, the one from the [`http`](crate::http) factory.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Refuse the downloads while `disk_guard` reports the store filesystem as full.
+    pub fn with_disk_guard(mut self, disk_guard: Arc<DiskGuard>) -> Self {
+        self.disk_guard = Some(disk_guard);
+        self
+    }
+
+    pub fn disk_guard(&self) -> Option<Arc<DiskGuard>> {
+        self.disk_guard.clone()
+    }
+
+    /// Request `url`, attaching a bearer token when the URL requires authentication.
+    ///
+    /// The bytes after `resume` are requested when it carries an etag; the server answers with
+    /// the whole artifact if it changed since. A request rejected with 401 is retried once with
+    /// a fresh token.
+    pub async fn get(
+        &self,
+        url: &str,
+        resume: &ResumePoint,
+    ) -> Result<reqwest::Response, DeviceManagerError> {
+        self.send(url, |request| match &resume.etag {
+            Some(etag) if resume.offset > 0 => request
+                .header(RANGE, format!("bytes={}-", resume.offset))
+                .header(IF_RANGE, etag),
+            _ => request,
+        })
+        .await
+    }
+
+    /// Fetch the first `length` bytes of `url`, returning how fast they were received in bytes
+    /// per second.
+    pub async fn probe(&self, url: &str, length: u64) -> Result<u64, DeviceManagerError> {
+        let mut response = self
+            .send(url, |request| {
+                request.header(RANGE, format!("bytes=0-{}", length.saturating_sub(1)))
+            })
+            .await?;
+
+        // the server may ignore the range, only the bytes asked for are read
+        let start = self.clock.now_monotonic();
+        let mut received = 0;
+        while received < length {
+            match response.chunk().await? {
+                Some(chunk) => received += chunk.len() as u64,
+                None => break,
+            }
+        }
+
+        Ok(bandwidth::throughput(
+            received,
+            self.clock.now_monotonic().duration_since(start),
+        ))
+    }
+
+    /// Fetch the detached signature of the artifact at `url`, published next to it with the
+    /// `.sig` extension.
+    pub async fn signature(&self, url: &str) -> Result<Vec<u8>, DeviceManagerError> {
+        if let Some(source) = local::local_path(url)? {
+            let signature = std::fs::read(local::signature_path(&source))?;
+            if signature.len() > MAX_SIGNATURE_LENGTH {
+                return Err(OTAError::InvalidSignature.into());
+            }
+
+            return Ok(signature);
+        }
+
+        let mut response = self.send(&sidecar_url(url)?, |request| request).await?;
+
+        let mut signature = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            signature.extend_from_slice(&chunk);
+            if signature.len() > MAX_SIGNATURE_LENGTH {
+                return Err(OTAError::InvalidSignature.into());
+            }
+        }
+
+        Ok(signature)
+    }
+
+    /// Send the request to `url`, unless its host is marked unreachable. The hosts that failed to
+    /// resolve or to connect are counted, the ones connected to are cleared.
+    async fn send(
+        &self,
+        url: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<reqwest::Response, DeviceManagerError> {
+        let artifact_host = host(url);
+        if let Some(host) = &artifact_host {
+            self.hosts.check(host)?;
+        }
+
+        let result = self.send_request(url, build).await;
+        match &result {
+            Ok(_) => {
+                if let Some(host) = &artifact_host {
+                    self.hosts.forget(host);
+                }
+            }
+            // the failure may come from the token endpoint
+            Err(DeviceManagerError::ReqwestError(err)) => {
+                match err.url().and_then(Url::host_str) {
+                    Some(host) if err.is_connect() => self.hosts.connect_failed(host),
+                    // the server answered
+                    Some(host) if err.status().is_some() => self.hosts.forget(host),
+                    _ => {}
+                }
+            }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    async fn send_request(
+        &self,
+        url: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<reqwest::Response, DeviceManagerError> {
+        let request = || build(self.client.get(url));
+
+        let auth = match &self.auth {
+            Some(auth) if url.starts_with(&auth.options.url_prefix) => auth,
+            _ => return Ok(request().send().await?.error_for_status()?),
+        };
+
+        let token = self.token(auth, false).await?;
+        let response = request().bearer_auth(token).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response.error_for_status()?);
+        }
+
+        info!("Download token rejected, refreshing it");
+        let token = self.token(auth, true).await?;
+        let response = request().bearer_auth(token).send().await?;
+
+        Ok(response.error_for_status()?)
+    }
+
+    /// Download `url` into `path` from `resume`, going on with a range request each time the
+    /// transfer breaks. The failed requests are retried with a backoff, the number of the
+    /// attempt is reported along with the progress. The speed cap holds across the resumes.
+    ///
+    /// The server sends the whole artifact again when it doesn't honor the range or the artifact
+    /// changed, see [`save`]. Once the resume attempts are over or the download took longer than
+    /// the timeout it fails with a network error, the partial file is kept.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        path: &Path,
+        resume: ResumePoint,
+        shutdown: watch::Receiver<bool>,
+        progress: &watch::Sender<DownloadProgress>,
+    ) -> Result<DownloadOutcome, DeviceManagerError> {
+        let mut limiter = self
+            .max_speed
+            .map(|bytes_per_sec| RateLimiter::new(self.clock.clone(), bytes_per_sec));
+        let fetching = async {
+            let mut resume = resume;
+            let mut resumes = 0;
+            loop {
+                let response =
+                    retry_with_backoff(self.clock.as_ref(), &self.retry, retryable, |attempt| {
+                        report_attempt(progress, attempt);
+                        self.get(url, &resume)
+                    })
+                    .await?;
+                self.ensure_space(path, &response)?;
+                let etag = etag(&response);
+
+                let saved = save(
+                    response,
+                    path,
+                    resume,
+                    shutdown.clone(),
+                    progress,
+                    limiter.as_mut(),
+                )
+                .await;
+                match saved {
+                    Err(DeviceManagerError::ReqwestError(err))
+                        if resumes < self.resume_attempts =>
+                    {
+                        resumes += 1;
+                        // the bytes written before the break are all on disk
+                        let offset = std::fs::metadata(path)?.len();
+                        warn!(
+                            "Download broken at byte {offset}, resuming it ({resumes}/{}): {}",
+                            self.resume_attempts,
+                            redactor().text(&err.to_string())
+                        );
+                        resume = ResumePoint { offset, etag };
+                    }
+                    result => return result,
+                }
+            }
+        };
+
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return fetching.await,
+        };
+        tokio::select! {
+            result = fetching => result,
+            _ = self.clock.sleep(timeout) => {
+                warn!("Download not completed in {}s, giving up", timeout.as_secs());
+                Err(OTAError::Network.into())
+            }
+        }
+    }
+
+    /// Fail with [`OTAError::NotEnoughSpace`] when the filesystem of `path` can't hold the body
+    /// of `response` and the margin, or the minimum free space when its length is unknown.
+    fn ensure_space(
+        &self,
+        path: &Path,
+        response: &reqwest::Response,
+    ) -> Result<(), DeviceManagerError> {
+        let space = match &self.space {
+            Some(space) => space,
+            None => return Ok(()),
+        };
+
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+        let usage = match space.provider.usage(directory) {
+            Ok(usage) => usage,
+            Err(err) => {
+                warn!(
+                    "Unable to read the free space of {}: {:?}",
+                    directory.display(),
+                    err
+                );
+                return Ok(());
+            }
+        };
+
+        // a whole artifact replaces the partial file
+        let replaced = match response.status() {
+            StatusCode::PARTIAL_CONTENT => 0,
+            _ => std::fs::metadata(path).map_or(0, |metadata| metadata.len()),
+        };
+        let available = usage.available_bytes + replaced;
+        let required = match response.content_length() {
+            Some(length) => length + space.margin,
+            None => space.min_free,
+        };
+
+        if available < required {
+            warn!(
+                "Not enough space in {}: {required} bytes required, {available} available",
+                directory.display()
+            );
+            return Err(OTAError::NotEnoughSpace.into());
+        }
+
+        Ok(())
+    }
+
+    async fn token(
+        &self,
+        auth: &DownloadAuth,
+        refresh: bool,
+    ) -> Result<String, DeviceManagerError> {
+        let mut token = self.token.lock().await;
+
+        let now = self.clock.now_monotonic();
+        let valid = token.as_ref().filter(|token| {
+            !refresh && token.expires_at.map_or(true, |expires_at| now < expires_at)
+        });
+        if let Some(token) = valid {
+            return Ok(token.value.clone());
+        }
+
+        debug!("Requesting download token");
+        let body = self
+            .client
+            .post(&auth.options.token_endpoint)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", auth.client_id.as_str()),
+                ("client_secret", auth.client_secret.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let response: TokenResponse = serde_json::from_slice(&body)?;
+
+        let value = response.access_token;
+        *token = Some(Token {
+            value: value.clone(),
+            expires_at: response.expires_in.map(|expires_in| {
+                now + Duration::from_secs(expires_in).saturating_sub(TOKEN_EXPIRATION_MARGIN)
+            }),
+        });
+
+        Ok(value)
+    }
+}
+
+/// Write the body of `response` into `path`, appending to the bytes before `resume` when the
+/// server answered with the requested range.
+///
+/// Once `shutdown` is set the download stops at the next chunk, the file is synced and the point
+/// to resume from is returned. The bytes received are reported on `progress` after each chunk,
+/// the chunks are read no faster than `limiter` allows.
+pub async fn save(
+    mut response: reqwest::Response,
+    path: &Path,
+    resume: ResumePoint,
+    mut shutdown: watch::Receiver<bool>,
+    progress: &watch::Sender<DownloadProgress>,
+    mut limiter: Option<&mut RateLimiter>,
+) -> Result<DownloadOutcome, DeviceManagerError> {
+    let etag = etag(&response);
+
+    let (mut file, mut offset) = if response.status() == StatusCode::PARTIAL_CONTENT {
+        info!("Resuming the download at byte {}", resume.offset);
+        (OpenOptions::new().append(true).open(path)?, resume.offset)
+    } else {
+        // replaced rather than truncated, it may be a link to a bundle staged on the device
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        (File::create(path)?, 0)
+    };
+    let total = response.content_length().map(|length| offset + length);
+
+    loop {
+        // a chunk dropped while throttled for the shutdown is not written, nor counted
+        let chunk = tokio::select! {
+            chunk = next_chunk(&mut response, limiter.as_deref_mut()) => chunk?,
+            _ = shutdown_requested(&mut shutdown) => {
+                file.sync_all()?;
+                info!("Download paused at byte {offset}");
+                return Ok(DownloadOutcome::Paused(ResumePoint { offset, etag }));
+            }
+        };
+
+        match chunk {
+            Some(chunk) => {
+                file.write_all(&chunk).map_err(|err| {
+                    if disk_guard::is_storage_full(&err) {
+                        OTAError::NotEnoughSpace.into()
+                    } else {
+                        DeviceManagerError::from(err)
+                    }
+                })?;
+                offset += chunk.len() as u64;
+                let attempt = progress.borrow().attempt;
+                progress.send_replace(DownloadProgress {
+                    received: offset,
+                    total,
+                    attempt,
+                });
+            }
+            None => break,
+        }
+    }
+
+    file.sync_all()?;
+    Ok(DownloadOutcome::Completed)
+}
+
+fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_owned)
+}
+
+/// Read the next chunk of `response`, then wait for `limiter` to let it through.
+async fn next_chunk(
+    response: &mut reqwest::Response,
+    limiter: Option<&mut RateLimiter>,
+) -> reqwest::Result<Option<hyper::body::Bytes>> {
+    let chunk = response.chunk().await?;
+    if let (Some(limiter), Some(chunk)) = (limiter, &chunk) {
+        limiter.throttle(chunk.len() as u64).await;
+    }
+
+    Ok(chunk)
+}
+
+/// Report the attempt of the request of the download, when it changed.
+fn report_attempt(progress: &watch::Sender<DownloadProgress>, attempt: u32) {
+    let mut current = *progress.borrow();
+    if current.attempt != attempt {
+        current.attempt = attempt;
+        progress.send_replace(current);
+    }
+}
+
+/// URL of the signature of the artifact at `url`, keeping its query.
+fn sidecar_url(url: &str) -> Result<String, DeviceManagerError> {
+    let mut url = Url::parse(url)
+        .map_err(|err| DeviceManagerError::UpdateError(format!("invalid OTA URL: {err}")))?;
+    url.set_path(&format!("{}.sig", url.path()));
+
+    Ok(url.into())
+}
+
+fn etag(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Wait until `shutdown` is set, forever if the sender is gone.
+pub(crate) async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use hyper::body::Bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use openssl::sha::sha256;
+    use tokio::sync::watch;
+
+    use crate::clock::SystemClock;
+    use crate::disk_guard::{DiskUsage, MockSpaceProvider};
+    use crate::error::DeviceManagerError;
+    use crate::http::{self, HttpOptions};
+    use crate::ota::bandwidth::tests::drive;
+    use crate::ota::download::{
+        save, sidecar_url, DownloadAuth, DownloadAuthOptions, DownloadOutcome,
+        DownloadSpaceOptions, Downloader, ResumePoint,
+    };
+    use crate::ota::host_cache::UnreachableHostOptions;
+    use crate::ota::ota_handler::OTAError;
+    use crate::ota::progress::DownloadProgress;
+    use crate::test_utils::ManualClock;
+
+    struct TestServer {
+        address: SocketAddr,
+        token_requests: Arc<Mutex<Vec<String>>>,
+        artifact_requests: Arc<AtomicUsize>,
+    }
+
+    /// Serve `/token` issuing `token-N` and `/artifact` accepting only `accepted_token`.
+    fn test_server(accepted_token: Option<&'static str>) -> TestServer {
+        let token_requests = Arc::new(Mutex::new(Vec::new()));
+        let artifact_requests = Arc::new(AtomicUsize::new(0));
+
+        let tokens = token_requests.clone();
+        let artifacts = artifact_requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let tokens = tokens.clone();
+            let artifacts = artifacts.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let tokens = tokens.clone();
+                    let artifacts = artifacts.clone();
+                    async move {
+                        let response = match request.uri().path() {
+                            "/token" => {
+                                let body =
+                                    hyper::body::to_bytes(request.into_body()).await.unwrap();
+                                let mut tokens = tokens.lock().unwrap();
+                                tokens.push(String::from_utf8(body.to_vec()).unwrap());
+                                Response::new(Body::from(format!(
+                                    r#"{{"access_token": "token-{}", "expires_in": 300}}"#,
+                                    tokens.len()
+                                )))
+                            }
+                            _ => {
+                                artifacts.fetch_add(1, Ordering::SeqCst);
+                                let authorization = request
+                                    .headers()
+                                    .get("Authorization")
+                                    .and_then(|value| value.to_str().ok())
+                                    .map(str::to_owned);
+
+                                match (accepted_token, authorization) {
+                                    (None, None) => Response::new(Body::from("bundle")),
+                                    (Some(accepted), Some(authorization))
+                                        if authorization == format!("Bearer {accepted}") =>
+                                    {
+                                        Response::new(Body::from("bundle"))
+                                    }
+                                    _ => Response::builder()
+                                        .status(StatusCode::UNAUTHORIZED)
+                                        .body(Body::empty())
+                                        .unwrap(),
+                                }
+                            }
+                        };
+
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        TestServer {
+            address,
+            token_requests,
+            artifact_requests,
+        }
+    }
+
+    const ARTIFACT_ETAG: &str = "\"v1\"";
+
+    /// Serve `content` honoring the ranges, the full responses stall after `split` bytes.
+    fn resumable_server(content: &'static [u8], split: usize) -> SocketAddr {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+                let start = request
+                    .headers()
+                    .get("Range")
+                    .and_then(|range| range.to_str().ok())
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.strip_suffix('-'))
+                    .and_then(|start| start.parse::<usize>().ok());
+                let unchanged = request
+                    .headers()
+                    .get("If-Range")
+                    .map_or(false, |etag| etag == ARTIFACT_ETAG);
+
+                let response = match start {
+                    Some(start) if unchanged => Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header("ETag", ARTIFACT_ETAG)
+                        .body(Body::from(&content[start..])),
+                    _ => {
+                        let (mut sender, body) = Body::channel();
+                        tokio::spawn(async move {
+                            sender
+                                .send_data(Bytes::from_static(&content[..split]))
+                                .await
+                                .ok();
+                            // the rest never comes, the sender keeps the body open
+                            std::future::pending::<()>().await;
+                        });
+                        Response::builder().header("ETag", ARTIFACT_ETAG).body(body)
+                    }
+                };
+
+                Ok::<_, Infallible>(response.unwrap())
+            }))
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        address
+    }
+
+    /// Serve the `versions` of the artifact, one per request and then the last one, honoring the
+    /// ranges of the current version. The first `breaks` responses kill the connection halfway
+    /// through, the `Range` headers received are returned.
+    fn breaking_server(
+        versions: Vec<(&'static str, Vec<u8>)>,
+        breaks: usize,
+    ) -> (SocketAddr, Arc<Mutex<Vec<Option<String>>>>) {
+        let versions = Arc::new(versions);
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = ranges.clone();
+        let make_service = make_service_fn(move |_| {
+            let versions = versions.clone();
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let range = request
+                        .headers()
+                        .get("Range")
+                        .and_then(|range| range.to_str().ok())
+                        .map(str::to_owned);
+                    let mut ranges = recorded.lock().unwrap();
+                    let index = ranges.len();
+                    ranges.push(range.clone());
+                    let (etag, content) = &versions[index.min(versions.len() - 1)];
+
+                    let start = range
+                        .as_deref()
+                        .and_then(|range| range.strip_prefix("bytes="))
+                        .and_then(|range| range.strip_suffix('-'))
+                        .and_then(|start| start.parse::<usize>().ok())
+                        .filter(|_| {
+                            request
+                                .headers()
+                                .get("If-Range")
+                                .map_or(false, |tag| tag == *etag)
+                        });
+                    let status = match start {
+                        Some(_) => StatusCode::PARTIAL_CONTENT,
+                        None => StatusCode::OK,
+                    };
+                    let rest = Bytes::copy_from_slice(&content[start.unwrap_or(0)..]);
+                    let length = rest.len();
+
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        if index < breaks {
+                            sender.send_data(rest.slice(..length / 2)).await.ok();
+                            // let the bytes sent reach the client before the connection dies
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            sender.abort();
+                        } else {
+                            sender.send_data(rest).await.ok();
+                        }
+                    });
+                    let response = Response::builder()
+                        .status(status)
+                        .header("ETag", *etag)
+                        .header("Content-Length", length)
+                        .body(body);
+
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, ranges)
+    }
+
+    /// Answer the first `failures` requests with `status`, the others with `content`. Returns the
+    /// count of the requests along with the address.
+    fn failing_server(
+        content: &'static [u8],
+        failures: usize,
+        status: StatusCode,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counted = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let counted = counted.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                    let response = if counted.fetch_add(1, Ordering::SeqCst) < failures {
+                        Response::builder().status(status).body(Body::empty())
+                    } else {
+                        Response::builder().body(Body::from(content))
+                    };
+
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, requests)
+    }
+
+    /// Artifact of `length` bytes that doesn't repeat within a few KiB.
+    fn artifact(length: usize, seed: u8) -> Vec<u8> {
+        (0..length)
+            .map(|index| (index % 251) as u8 ^ (index / 4096) as u8 ^ seed)
+            .collect()
+    }
+
+    fn downloader(server: &TestServer) -> Downloader {
+        Downloader::new(
+            Some(DownloadAuth::new(
+                DownloadAuthOptions {
+                    url_prefix: format!("http://{}/", server.address),
+                    token_endpoint: format!("http://{}/token", server.address),
+                },
+                "device".to_owned(),
+                "s3cr3t".to_owned(),
+            )),
+            Arc::new(SystemClock),
+        )
+    }
+
+    #[tokio::test]
+    async fn download_with_bearer_token() {
+        let server = test_server(Some("token-1"));
+        let downloader = downloader(&server);
+        let url = format!("http://{}/artifact", server.address);
+
+        let body = downloader
+            .get(&url, &ResumePoint::default())
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "bundle");
+
+        // the token is cached
+        downloader.get(&url, &ResumePoint::default()).await.unwrap();
+        assert_eq!(
+            *server.token_requests.lock().unwrap(),
+            vec!["grant_type=client_credentials&client_id=device&client_secret=s3cr3t"]
+        );
+    }
+
+    #[tokio::test]
+    async fn token_refreshed_once_on_unauthorized() {
+        let server = test_server(Some("token-2"));
+        let downloader = downloader(&server);
+        let url = format!("http://{}/artifact", server.address);
+
+        assert!(downloader.get(&url, &ResumePoint::default()).await.is_ok());
+        assert_eq!(server.token_requests.lock().unwrap().len(), 2);
+        assert_eq!(server.artifact_requests.load(Ordering::SeqCst), 2);
+
+        let rejecting = test_server(Some("never"));
+        let downloader = self::downloader(&rejecting);
+        let url = format!("http://{}/artifact", rejecting.address);
+
+        assert!(downloader.get(&url, &ResumePoint::default()).await.is_err());
+        assert_eq!(rejecting.token_requests.lock().unwrap().len(), 2);
+        assert_eq!(rejecting.artifact_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn signature_fetched_next_to_the_artifact() {
+        let server = test_server(Some("token-1"));
+        let downloader = downloader(&server);
+
+        let url = format!("http://{}/artifact", server.address);
+        assert_eq!(downloader.signature(&url).await.unwrap(), b"bundle");
+        assert_eq!(
+            sidecar_url("https://ota.example.com/bundles/1.2.0.raucb?expires=60").unwrap(),
+            "https://ota.example.com/bundles/1.2.0.raucb.sig?expires=60"
+        );
+        assert!(sidecar_url("ota.raucb").is_err());
+    }
+
+    #[tokio::test]
+    async fn other_urls_downloaded_without_token() {
+        let server = test_server(None);
+        let other = test_server(None);
+        let downloader = downloader(&other);
+
+        let url = format!("http://{}/artifact", server.address);
+        assert!(downloader.get(&url, &ResumePoint::default()).await.is_ok());
+        assert!(other.token_requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_pauses_and_next_download_resumes() {
+        let address = resumable_server(b"bundle content", 6);
+        let downloader = Downloader::new(None, Arc::new(SystemClock));
+        let url = format!("http://{address}/artifact");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let response = downloader.get(&url, &ResumePoint::default()).await.unwrap();
+        let task_path = path.clone();
+        let saved = tokio::spawn(async move {
+            let (progress, _) = watch::channel(DownloadProgress::default());
+            save(
+                response,
+                &task_path,
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+                None,
+            )
+            .await
+        });
+
+        while std::fs::metadata(&path).map_or(0, |metadata| metadata.len()) < 6 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown_tx.send(true).unwrap();
+
+        let resume = ResumePoint {
+            offset: 6,
+            etag: Some(ARTIFACT_ETAG.to_owned()),
+        };
+        assert_eq!(
+            saved.await.unwrap().unwrap(),
+            DownloadOutcome::Paused(resume.clone())
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"bundle");
+
+        let response = downloader.get(&url, &resume).await.unwrap();
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, progress_rx) = watch::channel(DownloadProgress::default());
+        assert_eq!(
+            save(response, &path, resume, shutdown, &progress, None)
+                .await
+                .unwrap(),
+            DownloadOutcome::Completed
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"bundle content");
+        // the resumed bytes count towards the progress
+        assert_eq!(
+            *progress_rx.borrow(),
+            DownloadProgress {
+                received: 14,
+                total: Some(14),
+                attempt: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn identity_headers_sent_with_the_range() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    recorded.lock().unwrap().extend(
+                        ["User-Agent", "X-Fleet-Id", "Range"]
+                            .map(|name| request.headers()[name].to_str().unwrap().to_owned()),
+                    );
+                    async { Ok::<_, Infallible>(Response::new(Body::from("bundle"))) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let options = HttpOptions {
+            headers: [("X-Fleet-Id".to_owned(), "fleet-1".to_owned())].into(),
+            ..Default::default()
+        };
+        let downloader = Downloader::new(None, Arc::new(SystemClock))
+            .with_client(http::client(&options, "device").unwrap());
+        let resume = ResumePoint {
+            offset: 6,
+            etag: Some(ARTIFACT_ETAG.to_owned()),
+        };
+        downloader
+            .get(&format!("http://{address}/artifact"), &resume)
+            .await
+            .unwrap();
+
+        // the per-request headers layer on top of the configured ones
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                http::user_agent("device"),
+                "fleet-1".to_owned(),
+                "bytes=6-".to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_transfers_resumed_with_ranges() {
+        let content = artifact(64 * 1024, 0);
+        let (address, ranges) = breaking_server(vec![(ARTIFACT_ETAG, content.clone())], 3);
+        let downloader = Downloader::new(None, Arc::new(SystemClock));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, _) = watch::channel(DownloadProgress::default());
+        let outcome = downloader
+            .fetch(
+                &format!("http://{address}/artifact"),
+                &path,
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, DownloadOutcome::Completed);
+        assert_eq!(sha256(&std::fs::read(&path).unwrap()), sha256(&content));
+        let ranges = ranges.lock().unwrap();
+        assert_eq!(ranges.len(), 4);
+        assert_eq!(ranges[0], None);
+        assert!(ranges[1..].iter().all(Option::is_some));
+    }
+
+    #[tokio::test]
+    async fn changed_artifact_downloaded_again() {
+        let previous = artifact(32 * 1024, 0);
+        let current = artifact(48 * 1024, 0x5a);
+        let (address, ranges) = breaking_server(
+            vec![(ARTIFACT_ETAG, previous), ("\"v2\"", current.clone())],
+            1,
+        );
+        let downloader = Downloader::new(None, Arc::new(SystemClock));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, _) = watch::channel(DownloadProgress::default());
+        let outcome = downloader
+            .fetch(
+                &format!("http://{address}/artifact"),
+                &path,
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            )
+            .await
+            .unwrap();
+
+        // the range was asked but the whole new version came, replacing the partial file
+        assert_eq!(outcome, DownloadOutcome::Completed);
+        assert_eq!(ranges.lock().unwrap().len(), 2);
+        assert!(ranges.lock().unwrap()[1].is_some());
+        assert_eq!(sha256(&std::fs::read(&path).unwrap()), sha256(&current));
+    }
+
+    #[tokio::test]
+    async fn resumes_limited() {
+        let (address, ranges) = breaking_server(vec![(ARTIFACT_ETAG, artifact(16 * 1024, 0))], 10);
+        let downloader = Downloader::new(None, Arc::new(SystemClock)).with_resume(Some(2), None);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, _) = watch::channel(DownloadProgress::default());
+        let result = downloader
+            .fetch(
+                &format!("http://{address}/artifact"),
+                &path,
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(ranges.lock().unwrap().len(), 3);
+        // kept for the next request of the same update
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+    }
+
+    #[tokio::test]
+    async fn stalled_download_timed_out() {
+        let address = resumable_server(b"bundle content", 6);
+        let clock = Arc::new(ManualClock::new());
+        let downloader =
+            Downloader::new(None, clock.clone()).with_resume(None, Some(Duration::from_secs(600)));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+
+        let task_path = path.clone();
+        let fetched = tokio::spawn(async move {
+            let (_shutdown_tx, shutdown) = watch::channel(false);
+            let (progress, _) = watch::channel(DownloadProgress::default());
+            downloader
+                .fetch(
+                    &format!("http://{address}/artifact"),
+                    &task_path,
+                    ResumePoint::default(),
+                    shutdown,
+                    &progress,
+                )
+                .await
+        });
+
+        while std::fs::metadata(&path).map_or(0, |metadata| metadata.len()) < 6 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        clock.advance(Duration::from_secs(599));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!fetched.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(fetched.await.unwrap().is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"bundle");
+    }
+
+    #[test]
+    fn secret_not_in_debug() {
+        let auth = DownloadAuth::new(
+            DownloadAuthOptions {
+                url_prefix: "https://artifacts".to_owned(),
+                token_endpoint: "https://auth/token".to_owned(),
+            },
+            "device".to_owned(),
+            "s3cr3t".to_owned(),
+        );
+
+        assert!(!format!("{auth:?}").contains("s3cr3t"));
+    }
+
+    #[tokio::test]
+    async fn server_errors_retried_with_the_attempt_reported() {
+        let (address, requests) =
+            failing_server(b"bundle content", 2, StatusCode::SERVICE_UNAVAILABLE);
+        let clock = Arc::new(ManualClock::new());
+        let downloader = Downloader::new(None, clock.clone()).with_retry(Some(3), None);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+        let driver = drive(clock);
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, progress_rx) = watch::channel(DownloadProgress::default());
+        let outcome = downloader
+            .fetch(
+                &format!("http://{address}/artifact"),
+                &path,
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            )
+            .await;
+        driver.abort();
+
+        assert_eq!(outcome.unwrap(), DownloadOutcome::Completed);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(progress_rx.borrow().attempt, 3);
+        assert_eq!(std::fs::read(&path).unwrap(), b"bundle content");
+    }
+
+    #[tokio::test]
+    async fn not_found_fails_fast() {
+        let (address, requests) = failing_server(b"", usize::MAX, StatusCode::NOT_FOUND);
+        // the clock never moves, a retry would wait forever
+        let downloader = Downloader::new(None, Arc::new(ManualClock::new()));
+        let dir = tempfile::tempdir().unwrap();
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, _) = watch::channel(DownloadProgress::default());
+        let outcome = tokio::time::timeout(
+            Duration::from_secs(5),
+            downloader.fetch(
+                &format!("http://{address}/artifact"),
+                &dir.path().join("update.bin"),
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            outcome,
+            Err(DeviceManagerError::OTAError(OTAError::Network))
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    /// Space provider reporting `available` bytes free.
+    fn free_space(available: u64) -> Box<MockSpaceProvider> {
+        let mut provider = MockSpaceProvider::new();
+        provider.expect_usage().returning(move |_| {
+            Ok(DiskUsage {
+                total_bytes: 1 << 30,
+                available_bytes: available,
+            })
+        });
+
+        Box::new(provider)
+    }
+
+    #[tokio::test]
+    async fn download_refused_without_the_space() {
+        let (address, _) = failing_server(b"bundle content", 0, StatusCode::OK);
+        let streaming = resumable_server(b"bundle content", 6);
+        let options = DownloadSpaceOptions {
+            margin_bytes: Some(10),
+            min_free_bytes: Some(1024),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+
+        let cases = [
+            // 14 bytes and the margin
+            (address, 23, false),
+            (address, 24, true),
+            // the length is not reported, the minimum free space is required
+            (streaming, 1023, false),
+        ];
+        for (address, available, fits) in cases {
+            let downloader = Downloader::new(None, Arc::new(SystemClock))
+                .with_space_check(free_space(available), Some(&options));
+            let (_shutdown_tx, shutdown) = watch::channel(false);
+            let (progress, _) = watch::channel(DownloadProgress::default());
+            let result = downloader
+                .fetch(
+                    &format!("http://{address}/artifact"),
+                    &path,
+                    ResumePoint::default(),
+                    shutdown,
+                    &progress,
+                )
+                .await;
+
+            if fits {
+                assert_eq!(result.unwrap(), DownloadOutcome::Completed);
+                assert_eq!(std::fs::read(&path).unwrap(), b"bundle content");
+                std::fs::remove_file(&path).unwrap();
+            } else {
+                assert!(
+                    matches!(
+                        result,
+                        Err(DeviceManagerError::OTAError(OTAError::NotEnoughSpace))
+                    ),
+                    "{available}"
+                );
+                assert!(!path.exists());
+            }
+        }
+
+        // the partial file is replaced by the whole artifact, its space counts as available
+        std::fs::write(&path, b"bundle").unwrap();
+        let downloader = Downloader::new(None, Arc::new(SystemClock))
+            .with_space_check(free_space(18), Some(&options));
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, _) = watch::channel(DownloadProgress::default());
+        let outcome = downloader
+            .fetch(
+                &format!("http://{address}/artifact"),
+                &path,
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome, DownloadOutcome::Completed);
+    }
+
+    #[tokio::test]
+    async fn download_speed_capped() {
+        let content = artifact(256 * 1024, 0);
+        let (address, _) = breaking_server(vec![(ARTIFACT_ETAG, content.clone())], 0);
+        let downloader =
+            Downloader::new(None, Arc::new(SystemClock)).with_max_speed(Some(128 * 1024));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, _) = watch::channel(DownloadProgress::default());
+        let start = std::time::Instant::now();
+        let outcome = downloader
+            .fetch(
+                &format!("http://{address}/artifact"),
+                &path,
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            )
+            .await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(outcome.unwrap(), DownloadOutcome::Completed);
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        // 2s at the cap, the bucket starts empty
+        assert!(elapsed >= Duration::from_millis(1900), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(4), "{elapsed:?}");
+    }
+
+    /// Address nothing listens on, the connections to it are refused.
+    fn closed_address() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unreachable_host_cached_until_connected() {
+        let clock = Arc::new(ManualClock::new());
+        let downloader = Downloader::new(None, clock.clone()).with_unreachable_hosts(Some(
+            &UnreachableHostOptions {
+                failures: Some(2),
+                cooldown_secs: Some(60),
+            },
+        ));
+        let closed = format!("http://{}/artifact", closed_address());
+        let server = test_server(None);
+        let open = format!("http://{}/artifact", server.address);
+        let get = |url: String| {
+            let downloader = &downloader;
+            async move { downloader.get(&url, &ResumePoint::default()).await }
+        };
+        let refused = |result: Result<reqwest::Response, DeviceManagerError>| match result {
+            Err(DeviceManagerError::ReqwestError(err)) => err.is_connect(),
+            _ => false,
+        };
+        let cached = |result: Result<reqwest::Response, DeviceManagerError>| {
+            matches!(
+                result,
+                Err(DeviceManagerError::OTAError(
+                    OTAError::HostUnreachableCached
+                ))
+            )
+        };
+
+        assert!(refused(get(closed.clone()).await));
+        assert!(refused(get(closed.clone()).await));
+        // any port of the host fails fast for the cooldown
+        assert!(cached(get(closed.clone()).await));
+        assert!(cached(get(open.clone()).await));
+        assert_eq!(server.artifact_requests.load(Ordering::SeqCst), 0);
+
+        clock.advance(Duration::from_secs(60));
+        assert!(get(open.clone()).await.is_ok());
+        // the connection cleared the failures
+        assert!(refused(get(closed.clone()).await));
+        assert!(refused(get(closed.clone()).await));
+        assert!(cached(get(closed.clone()).await));
+
+        downloader.retry_host(&closed);
+        assert!(refused(get(closed.clone()).await));
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_the_unreachable_host() {
+        let clock = Arc::new(ManualClock::new());
+        let downloader = Downloader::new(None, clock.clone())
+            .with_retry(Some(10), None)
+            .with_unreachable_hosts(None);
+        let dir = tempfile::tempdir().unwrap();
+        let driver = drive(clock);
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (progress, progress_rx) = watch::channel(DownloadProgress::default());
+        let outcome = downloader
+            .fetch(
+                &format!("http://{}/artifact", closed_address()),
+                &dir.path().join("update.bin"),
+                ResumePoint::default(),
+                shutdown,
+                &progress,
+            )
+            .await;
+        driver.abort();
+
+        assert!(matches!(
+            outcome,
+            Err(DeviceManagerError::OTAError(
+                OTAError::HostUnreachableCached
+            ))
+        ));
+        // 3 refused connections by default, then the cached failure
+        assert_eq!(progress_rx.borrow().attempt, 4);
+    }
+}
@@ -0,0 +1,422 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Failure details of the OTA responses.
+//!
+//! A failed update is answered with a coarse error code, telling the backend what kind of failure
+//! it was without parsing the status code, and a short message for the operators. Both are
+//! derived from the error here, the matches list every variant so that a new one has to be given
+//! a code.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::DeviceManagerError;
+use crate::ota::ota_handler::OTAError;
+use crate::redaction::redactor;
+
+/// Longest message of a response, in characters.
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+/// Kind of failure of an update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OtaErrorCode {
+    /// The artifact could not be downloaded.
+    NetworkError,
+    /// Reading or writing the artifact failed, e.g. for the lack of space.
+    IOError,
+    /// The artifact is not signed, not trusted or not for this device.
+    InvalidBundle,
+    ChecksumMismatch,
+    /// The deploy backend failed to install the artifact or to confirm the new slot.
+    DeployError,
+    /// The bootloader fell back to the previous slot.
+    Rollback,
+    Canceled,
+    UpdateAlreadyInProgress,
+    InternalError,
+}
+
+impl OtaErrorCode {
+    pub const ALL: [OtaErrorCode; 9] = [
+        OtaErrorCode::NetworkError,
+        OtaErrorCode::IOError,
+        OtaErrorCode::InvalidBundle,
+        OtaErrorCode::ChecksumMismatch,
+        OtaErrorCode::DeployError,
+        OtaErrorCode::Rollback,
+        OtaErrorCode::Canceled,
+        OtaErrorCode::UpdateAlreadyInProgress,
+        OtaErrorCode::InternalError,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OtaErrorCode::NetworkError => "NetworkError",
+            OtaErrorCode::IOError => "IOError",
+            OtaErrorCode::InvalidBundle => "InvalidBundle",
+            OtaErrorCode::ChecksumMismatch => "ChecksumMismatch",
+            OtaErrorCode::DeployError => "DeployError",
+            OtaErrorCode::Rollback => "Rollback",
+            OtaErrorCode::Canceled => "Canceled",
+            OtaErrorCode::UpdateAlreadyInProgress => "UpdateAlreadyInProgress",
+            OtaErrorCode::InternalError => "InternalError",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        OtaErrorCode::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == code)
+    }
+}
+
+impl From<&OTAError> for OtaErrorCode {
+    fn from(error: &OTAError) -> Self {
+        match error {
+            OTAError::Network | OTAError::LinkTooSlow | OTAError::HostUnreachableCached => {
+                OtaErrorCode::NetworkError
+            }
+            OTAError::NotEnoughSpace | OTAError::InvalidLocalFile => OtaErrorCode::IOError,
+            OTAError::InvalidSignature
+            | OTAError::UnknownSigningKey
+            | OTAError::ExpiredSigningKey
+            | OTAError::InvalidKeyBundle => OtaErrorCode::InvalidBundle,
+            OTAError::ChecksumMismatch => OtaErrorCode::ChecksumMismatch,
+            OTAError::Deploy => OtaErrorCode::DeployError,
+            OTAError::Rollback => OtaErrorCode::Rollback,
+            OTAError::Canceled => OtaErrorCode::Canceled,
+            OTAError::UpdateAlreadyInProgress => OtaErrorCode::UpdateAlreadyInProgress,
+            OTAError::Failed => OtaErrorCode::InternalError,
+        }
+    }
+}
+
+impl From<&DeviceManagerError> for OtaErrorCode {
+    fn from(error: &DeviceManagerError) -> Self {
+        match error {
+            DeviceManagerError::OTAError(error) => error.into(),
+            DeviceManagerError::ReqwestError(_) | DeviceManagerError::UploadError(_) => {
+                OtaErrorCode::NetworkError
+            }
+            DeviceManagerError::IOError(_)
+            | DeviceManagerError::OtaDownload { .. }
+            | DeviceManagerError::Persistence { .. }
+            | DeviceManagerError::NewerState { .. }
+            | DeviceManagerError::IncompatibleState { .. } => OtaErrorCode::IOError,
+            // the backend calls and the checks of the bundle they make
+            #[cfg(feature = "dbus")]
+            DeviceManagerError::ZbusError(_) => OtaErrorCode::DeployError,
+            DeviceManagerError::UpdateError(_) => OtaErrorCode::DeployError,
+            DeviceManagerError::AstarteBuilderError(_)
+            | DeviceManagerError::AstarteError(_)
+            | DeviceManagerError::ProcError(_)
+            | DeviceManagerError::ConfigNotFound(_)
+            | DeviceManagerError::InvalidOption { .. }
+            | DeviceManagerError::MissingCredentials
+            | DeviceManagerError::CorruptedCredentials { .. }
+            | DeviceManagerError::Keyring { .. }
+            | DeviceManagerError::Registration { .. }
+            | DeviceManagerError::HardwareIdTimedOut(_)
+            | DeviceManagerError::MissingHardwareId
+            | DeviceManagerError::NoDeviceId(_)
+            | DeviceManagerError::MessageHub { .. }
+            | DeviceManagerError::RequiredInterfaces { .. }
+            | DeviceManagerError::MalformedData { .. }
+            | DeviceManagerError::Onboarding(_)
+            | DeviceManagerError::OnboardingTimedOut
+            | DeviceManagerError::TaskFailed { .. }
+            | DeviceManagerError::SerdeJsonError(_)
+            | DeviceManagerError::ConfigFileError(_)
+            | DeviceManagerError::MissingConfigKeys(_)
+            | DeviceManagerError::Configuration(_)
+            | DeviceManagerError::InstanceLocked(_)
+            | DeviceManagerError::CapabilityDenied(_)
+            | DeviceManagerError::GeolocationError(_)
+            | DeviceManagerError::QuietHours
+            | DeviceManagerError::FlashWriteInProgress
+            | DeviceManagerError::InvalidCommand(_)
+            | DeviceManagerError::CommandFailed(_)
+            | DeviceManagerError::UnknownLed(_)
+            | DeviceManagerError::RemoteTerminalError(_)
+            | DeviceManagerError::ContainerError(_) => OtaErrorCode::InternalError,
+            #[cfg(feature = "dbus")]
+            DeviceManagerError::HardwareIdUnavailable(_) => OtaErrorCode::InternalError,
+        }
+    }
+}
+
+/// Operator readable description of `error`.
+pub fn describe(error: &OTAError) -> &'static str {
+    match error {
+        OTAError::Network => "the artifact download failed",
+        OTAError::Deploy => "the deploy backend failed to install the artifact",
+        OTAError::Failed => "the update failed",
+        OTAError::InvalidSignature => "the artifact signature is missing or doesn't match",
+        OTAError::UnknownSigningKey => "the artifact is signed with a key not trusted",
+        OTAError::ExpiredSigningKey => "the artifact is signed with an expired key",
+        OTAError::InvalidKeyBundle => "the key bundle is malformed or older than the installed one",
+        OTAError::NotEnoughSpace => "not enough free space for the artifact",
+        OTAError::ChecksumMismatch => "the artifact doesn't match the expected checksum",
+        OTAError::Canceled => "the update was canceled",
+        OTAError::LinkTooSlow => "the link stayed too slow for the download",
+        OTAError::HostUnreachableCached => "the artifact host is unreachable",
+        OTAError::InvalidLocalFile => "the bundle on the device is missing or unreadable",
+        OTAError::Rollback => "the bootloader rolled back to the previous slot",
+        OTAError::UpdateAlreadyInProgress => "another update is in progress",
+    }
+}
+
+/// Message of a response failed with `error`, redacted and at most [`MAX_MESSAGE_LEN`]
+/// characters long.
+pub fn message(error: &DeviceManagerError) -> String {
+    let message = match error {
+        DeviceManagerError::OTAError(error) => describe(error).to_owned(),
+        // their display is only the kind of error
+        DeviceManagerError::UpdateError(message) => message.clone(),
+        error => error.to_string(),
+    };
+
+    bounded(&redactor().text(&message))
+}
+
+fn bounded(message: &str) -> String {
+    if message.chars().count() <= MAX_MESSAGE_LEN {
+        return message.to_owned();
+    }
+
+    let kept: String = message.chars().take(MAX_MESSAGE_LEN - 3).collect();
+    format!("{kept}...")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeviceManagerError;
+    use crate::ota::failure::{message, OtaErrorCode, MAX_MESSAGE_LEN};
+    use crate::ota::ota_handler::OTAError;
+
+    #[test]
+    fn error_codes_mapped() {
+        let cases = [
+            (OTAError::Network, OtaErrorCode::NetworkError),
+            (OTAError::LinkTooSlow, OtaErrorCode::NetworkError),
+            (OTAError::HostUnreachableCached, OtaErrorCode::NetworkError),
+            (OTAError::NotEnoughSpace, OtaErrorCode::IOError),
+            (OTAError::InvalidLocalFile, OtaErrorCode::IOError),
+            (OTAError::InvalidSignature, OtaErrorCode::InvalidBundle),
+            (OTAError::UnknownSigningKey, OtaErrorCode::InvalidBundle),
+            (OTAError::ExpiredSigningKey, OtaErrorCode::InvalidBundle),
+            (OTAError::InvalidKeyBundle, OtaErrorCode::InvalidBundle),
+            (OTAError::ChecksumMismatch, OtaErrorCode::ChecksumMismatch),
+            (OTAError::Deploy, OtaErrorCode::DeployError),
+            (OTAError::Rollback, OtaErrorCode::Rollback),
+            (OTAError::Canceled, OtaErrorCode::Canceled),
+            (
+                OTAError::UpdateAlreadyInProgress,
+                OtaErrorCode::UpdateAlreadyInProgress,
+            ),
+            (OTAError::Failed, OtaErrorCode::InternalError),
+        ];
+        for (error, code) in cases {
+            assert_eq!(OtaErrorCode::from(&error), code, "{error:?}");
+            assert_eq!(
+                OtaErrorCode::from(&DeviceManagerError::OTAError(error)),
+                code
+            );
+        }
+
+        let cases = [
+            (
+                DeviceManagerError::IOError(std::io::ErrorKind::NotFound.into()),
+                OtaErrorCode::IOError,
+            ),
+            (
+                DeviceManagerError::UploadError("refused".to_owned()),
+                OtaErrorCode::NetworkError,
+            ),
+            (
+                DeviceManagerError::UpdateError("bundle is not compatible".to_owned()),
+                OtaErrorCode::DeployError,
+            ),
+            (
+                DeviceManagerError::OtaDownload {
+                    path: "/var/tmp/edgehog-updates/update.bin".to_owned(),
+                    reason: "not valid UTF-8".to_owned(),
+                },
+                OtaErrorCode::IOError,
+            ),
+            (
+                DeviceManagerError::Persistence {
+                    action: "create",
+                    path: "/var/tmp/edgehog-updates".to_owned(),
+                    source: Box::new(
+                        std::io::Error::from(std::io::ErrorKind::PermissionDenied).into(),
+                    ),
+                },
+                OtaErrorCode::IOError,
+            ),
+            (
+                DeviceManagerError::NewerState {
+                    path: "/var/lib/edgehog/state.json".to_owned(),
+                    version: 2,
+                },
+                OtaErrorCode::IOError,
+            ),
+            (
+                DeviceManagerError::IncompatibleState {
+                    path: "/var/lib/edgehog/state.json".to_owned(),
+                    version: 1,
+                    reason: "missing field `uuid`".to_owned(),
+                },
+                OtaErrorCode::IOError,
+            ),
+            (
+                DeviceManagerError::CorruptedCredentials {
+                    path: "/var/lib/edgehog/credentials_device_id.json".to_owned(),
+                    source: serde_json::from_str::<String>("{").unwrap_err(),
+                },
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::Keyring {
+                    action: "add",
+                    description: "edgehog:credentials_device_id".to_owned(),
+                    source: nix::errno::Errno::EDQUOT,
+                },
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::RequiredInterfaces {
+                    directory: "/usr/share/edgehog/interfaces".to_owned(),
+                    problems: Vec::new(),
+                },
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::MalformedData {
+                    path: "/proc/loadavg".to_owned(),
+                    reason: "\"\"".to_owned(),
+                },
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::TaskFailed {
+                    task: "download".to_owned(),
+                    reason: "panicked".to_owned(),
+                },
+                OtaErrorCode::InternalError,
+            ),
+            #[cfg(feature = "dbus")]
+            (
+                DeviceManagerError::HardwareIdUnavailable(zbus::Error::Unsupported),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::HardwareIdTimedOut(std::time::Duration::from_secs(10)),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::MissingHardwareId,
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::NoDeviceId(vec!["the configuration: not set".to_owned()]),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::MessageHub {
+                    endpoint: "http://[::1]:50051".to_owned(),
+                    source: tonic::Status::unavailable("connection refused"),
+                },
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::SerdeJsonError(serde_json::from_str::<u32>("").unwrap_err()),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::InstanceLocked("1".to_owned()),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::CapabilityDenied("CAP_SYS_BOOT".to_owned()),
+                OtaErrorCode::InternalError,
+            ),
+            (DeviceManagerError::QuietHours, OtaErrorCode::InternalError),
+            (
+                DeviceManagerError::FlashWriteInProgress,
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::InvalidCommand("PowerCycle".to_owned()),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::CommandFailed("exited with 1".to_owned()),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::UnknownLed("status".to_owned()),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::RemoteTerminalError("connection timed out".to_owned()),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::ContainerError("no such image".to_owned()),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::MissingConfigKeys(vec!["realm".to_owned()]),
+                OtaErrorCode::InternalError,
+            ),
+            (
+                DeviceManagerError::Configuration(vec!["realm is empty".to_owned()]),
+                OtaErrorCode::InternalError,
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(OtaErrorCode::from(&error), code, "{error:?}");
+        }
+
+        for code in OtaErrorCode::ALL {
+            assert_eq!(OtaErrorCode::parse(code.as_str()), Some(code));
+        }
+        assert_eq!(OtaErrorCode::parse("OTAFailed"), None);
+    }
+
+    #[test]
+    fn messages_bounded() {
+        assert_eq!(
+            message(&OTAError::ChecksumMismatch.into()),
+            "the artifact doesn't match the expected checksum"
+        );
+        assert_eq!(
+            message(&DeviceManagerError::UpdateError(
+                "bundle is not compatible".to_owned()
+            )),
+            "bundle is not compatible"
+        );
+
+        let long = message(&DeviceManagerError::UpdateError("è".repeat(1000)));
+        assert_eq!(long.chars().count(), MAX_MESSAGE_LEN);
+        assert!(long.ends_with("..."));
+    }
+}
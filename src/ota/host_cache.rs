@@ -0,0 +1,153 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Negative cache of the unreachable OTA hosts.
+//!
+//! A rollout pointing at a decommissioned host would make every request burn its whole retry
+//! budget. A host whose name didn't resolve or that refused the connection a few times in a row
+//! is marked unreachable for a cooling period, during which the requests to it fail right away.
+//! Any connection to the host clears it, as does a request asking to retry it. The cache is only
+//! kept in memory.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::warn;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::ota::ota_handler::OTAError;
+
+pub const DEFAULT_UNREACHABLE_FAILURES: u32 = 3;
+pub const DEFAULT_UNREACHABLE_COOLDOWN: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnreachableHostOptions {
+    /// Consecutive connection failures marking a host unreachable, 0 disables the cache.
+    pub failures: Option<u32>,
+    /// How long a host stays marked unreachable.
+    pub cooldown_secs: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    failures: u32,
+    unreachable_until: Option<Instant>,
+}
+
+pub struct HostCache {
+    clock: Arc<dyn Clock>,
+    failures: u32,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl HostCache {
+    pub fn new(clock: Arc<dyn Clock>, options: Option<&UnreachableHostOptions>) -> Self {
+        HostCache {
+            clock,
+            failures: options
+                .and_then(|options| options.failures)
+                .unwrap_or(DEFAULT_UNREACHABLE_FAILURES),
+            cooldown: options
+                .and_then(|options| options.cooldown_secs)
+                .map_or(DEFAULT_UNREACHABLE_COOLDOWN, Duration::from_secs),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fail while `host` is marked unreachable.
+    pub fn check(&self, host: &str) -> Result<(), OTAError> {
+        let hosts = self.hosts.lock().unwrap();
+        match hosts.get(host).and_then(|state| state.unreachable_until) {
+            Some(until) if self.clock.now_monotonic() < until => {
+                Err(OTAError::HostUnreachableCached)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a failed resolution or connection to `host`, marking it unreachable once they are
+    /// enough in a row.
+    pub fn connect_failed(&self, host: &str) {
+        if self.failures == 0 {
+            return;
+        }
+
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_owned()).or_default();
+        state.failures += 1;
+        if state.failures >= self.failures {
+            warn!(
+                "{host} unreachable {} times in a row, failing its requests for {}s",
+                state.failures,
+                self.cooldown.as_secs()
+            );
+            state.unreachable_until = Some(self.clock.now_monotonic() + self.cooldown);
+        }
+    }
+
+    /// Clear `host` once connected to it, or when asked to retry it.
+    pub fn forget(&self, host: &str) {
+        self.hosts.lock().unwrap().remove(host);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::ota::host_cache::{HostCache, UnreachableHostOptions};
+    use crate::ota::ota_handler::OTAError;
+    use crate::test_utils::ManualClock;
+
+    #[test]
+    fn host_unreachable_for_the_cooldown() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = HostCache::new(
+            clock.clone(),
+            Some(&UnreachableHostOptions {
+                failures: Some(2),
+                cooldown_secs: Some(60),
+            }),
+        );
+
+        cache.connect_failed("old.example.com");
+        assert!(cache.check("old.example.com").is_ok());
+        cache.connect_failed("old.example.com");
+        assert!(matches!(
+            cache.check("old.example.com"),
+            Err(OTAError::HostUnreachableCached)
+        ));
+        assert!(cache.check("ota.example.com").is_ok());
+
+        clock.advance(Duration::from_secs(60));
+        assert!(cache.check("old.example.com").is_ok());
+        // still failing after the cooldown, marked again right away
+        cache.connect_failed("old.example.com");
+        assert!(cache.check("old.example.com").is_err());
+
+        cache.forget("old.example.com");
+        assert!(cache.check("old.example.com").is_ok());
+    }
+}
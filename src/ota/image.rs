@@ -0,0 +1,512 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Deploy backend of the systems with a single root partition.
+//!
+//! The full image is staged with an applier script, the bootloader is set to boot the applier
+//! once from a ramdisk and the device reboots. The applier writes the image to the partition,
+//! and once the new image boots its version is checked against the one recorded when staging.
+//! Every command run is a template, since the bootloader environment differs on every board.
+
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+#[cfg(test)]
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::error::DeviceManagerError;
+use crate::ota::{BundleInfo, InstallOutcome, OTA};
+use crate::repository::StateRepository;
+
+/// Name of the file keeping the staged image in the store directory.
+pub const STAGED_IMAGE_FILE: &str = "staged_image.json";
+/// Name of the image in the staging directory.
+const IMAGE_NAME: &str = "image.img";
+/// Name of the applier script in the staging directory.
+const APPLIER_NAME: &str = "apply.sh";
+
+const DEFAULT_APPLIER: &str = "\
+#!/bin/sh
+# Generated by edgehog-device-runtime, run once from the ramdisk.
+set -e
+dd if={image} of={target} bs=4M conv=fsync
+sync
+";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageDeployOptions {
+    /// Where the image and the applier are staged, on a tmpfs or on a dedicated partition.
+    pub staging_directory: PathBuf,
+    /// Partition the applier writes the image to.
+    pub target_device: String,
+    /// Compatible string of the board, checked against the one of the images.
+    pub compatible: String,
+    /// Command printing the `compatible=` and `version=` lines of `{image}`.
+    pub inspect_command: String,
+    /// Commands setting the bootloader to boot the applier once, e.g.
+    /// `fw_setenv applier_script {applier}`.
+    pub boot_applier_commands: Vec<String>,
+    /// Commands run once the new image is confirmed, e.g. `fw_setenv upgrade_available 0`.
+    #[serde(default)]
+    pub confirm_commands: Vec<String>,
+    /// Template of the applier script, the built-in `dd` one when unset.
+    pub applier_template: Option<PathBuf>,
+}
+
+/// Image staged for the applier, recorded to confirm it after the reboot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StagedImage {
+    pub expected_version: String,
+    pub previous_version: Option<String>,
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait CommandRunner: Send + Sync {
+    /// Run `command` through the shell, returning its standard output.
+    async fn run(&self, command: &str) -> Result<String, DeviceManagerError>;
+}
+
+pub struct ShellRunner;
+
+#[async_trait]
+impl CommandRunner for ShellRunner {
+    async fn run(&self, command: &str) -> Result<String, DeviceManagerError> {
+        debug!("Running {command}");
+
+        let output = Command::new("sh").arg("-c").arg(command).output().await?;
+
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(DeviceManagerError::UpdateError(format!(
+                "{command} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+pub struct ImageDeployer {
+    options: ImageDeployOptions,
+    runner: Box<dyn CommandRunner>,
+    repository: Box<dyn StateRepository<StagedImage>>,
+    /// Version of the running image.
+    running_version: fn() -> Option<String>,
+    /// Outcome of the last staging, taken by `receive_completed`.
+    outcome: InstallOutcome,
+}
+
+impl ImageDeployer {
+    pub fn new(
+        options: ImageDeployOptions,
+        runner: Box<dyn CommandRunner>,
+        repository: Box<dyn StateRepository<StagedImage>>,
+        running_version: fn() -> Option<String>,
+    ) -> Self {
+        ImageDeployer {
+            options,
+            runner,
+            repository,
+            running_version,
+            outcome: InstallOutcome::default(),
+        }
+    }
+
+    fn image_path(&self) -> PathBuf {
+        self.options.staging_directory.join(IMAGE_NAME)
+    }
+
+    fn applier_path(&self) -> PathBuf {
+        self.options.staging_directory.join(APPLIER_NAME)
+    }
+
+    /// Fill the `{image}`, `{applier}`, `{staging}` and `{target}` placeholders of `template`.
+    fn render(&self, template: &str) -> String {
+        template
+            .replace("{image}", &self.image_path().to_string_lossy())
+            .replace("{applier}", &self.applier_path().to_string_lossy())
+            .replace(
+                "{staging}",
+                &self.options.staging_directory.to_string_lossy(),
+            )
+            .replace("{target}", &self.options.target_device)
+    }
+
+    fn running_version(&self) -> Result<String, DeviceManagerError> {
+        (self.running_version)().ok_or_else(|| {
+            DeviceManagerError::UpdateError("unable to read the running version".to_owned())
+        })
+    }
+
+    fn write_applier(&self) -> Result<(), DeviceManagerError> {
+        let template = match &self.options.applier_template {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => DEFAULT_APPLIER.to_owned(),
+        };
+
+        let path = self.applier_path();
+        std::fs::write(&path, self.render(&template))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+
+        Ok(())
+    }
+
+    /// Stage the image at `source` and set the bootloader to apply it at the next boot.
+    async fn stage(&self, source: &Path) -> Result<(), DeviceManagerError> {
+        std::fs::create_dir_all(&self.options.staging_directory)?;
+        std::fs::copy(source, self.image_path())?;
+        let image = self.info(&self.image_path().to_string_lossy()).await?;
+        self.write_applier()?;
+
+        self.repository.write(&StagedImage {
+            expected_version: image.version,
+            previous_version: (self.running_version)(),
+        })?;
+
+        for command in &self.options.boot_applier_commands {
+            self.runner.run(&self.render(command)).await?;
+        }
+        info!("Image staged, the applier runs at the next boot");
+
+        Ok(())
+    }
+}
+
+/// Quote `arg` for the shell the commands are run with.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Parse the `key=value` lines printed by the inspect command.
+pub(crate) fn parse_image_info(output: &str) -> Result<BundleInfo, DeviceManagerError> {
+    let values: HashMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+        .collect();
+
+    match (values.get("compatible"), values.get("version")) {
+        (Some(compatible), Some(version)) => Ok(BundleInfo {
+            compatible: compatible.to_string(),
+            version: version.to_string(),
+        }),
+        _ => Err(DeviceManagerError::UpdateError(
+            "the image has no compatible or version".to_owned(),
+        )),
+    }
+}
+
+#[async_trait]
+impl OTA for ImageDeployer {
+    async fn install_bundle(&self, source: &str) -> Result<(), DeviceManagerError> {
+        let result = self.stage(Path::new(source)).await;
+        self.outcome.record(&result);
+
+        result
+    }
+
+    async fn last_error(&self) -> Result<String, DeviceManagerError> {
+        Ok(self.outcome.last_error())
+    }
+
+    async fn info(&self, bundle: &str) -> Result<BundleInfo, DeviceManagerError> {
+        let command = self.options.inspect_command.replace("{image}", bundle);
+
+        parse_image_info(&self.runner.run(&command).await?)
+    }
+
+    async fn operation(&self) -> Result<String, DeviceManagerError> {
+        Ok("idle".to_owned())
+    }
+
+    /// The image is staged by `install_bundle` itself.
+    async fn progress(&self) -> Result<i32, DeviceManagerError> {
+        Ok(self.outcome.progress())
+    }
+
+    async fn compatible(&self) -> Result<String, DeviceManagerError> {
+        Ok(self.options.compatible.clone())
+    }
+
+    /// The single partition is told apart by the version of its image.
+    async fn boot_slot(&self) -> Result<String, DeviceManagerError> {
+        self.running_version()
+    }
+
+    async fn receive_completed(&self) -> Result<i32, DeviceManagerError> {
+        self.outcome.take()
+    }
+
+    async fn get_primary(&self) -> Result<String, DeviceManagerError> {
+        self.running_version()
+    }
+
+    /// Confirm the image running as `slot_identifier` if it is the staged one, or discard the
+    /// staged image when marked bad after a rollback.
+    async fn mark(
+        &self,
+        state: &str,
+        slot_identifier: &str,
+    ) -> Result<(String, String), DeviceManagerError> {
+        let staged = self.repository.read()?;
+        if state == "bad" {
+            if let Err(err) = std::fs::remove_file(self.image_path()) {
+                warn!("Unable to remove the staged image: {:?}", err);
+            }
+            self.repository.clear()?;
+
+            return Ok((
+                staged.expected_version.clone(),
+                format!("discarded image {}", staged.expected_version),
+            ));
+        }
+        if staged.expected_version != slot_identifier {
+            return Err(DeviceManagerError::UpdateError(format!(
+                "running {slot_identifier}, expected {}",
+                staged.expected_version
+            )));
+        }
+
+        for command in &self.options.confirm_commands {
+            self.runner.run(&self.render(command)).await?;
+        }
+        if let Err(err) = std::fs::remove_file(self.image_path()) {
+            warn!("Unable to remove the staged image: {:?}", err);
+        }
+        self.repository.clear()?;
+
+        Ok((
+            slot_identifier.to_owned(),
+            format!("marked image {slot_identifier} as {state}"),
+        ))
+    }
+
+    async fn health_check(&self) -> Result<(), DeviceManagerError> {
+        std::fs::create_dir_all(&self.options.staging_directory)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    use mockall::predicate::eq;
+    use mockall::Sequence;
+
+    use crate::ota::image::{
+        parse_image_info, ImageDeployOptions, ImageDeployer, MockCommandRunner, StagedImage,
+    };
+    use crate::ota::OTA;
+    use crate::repository::StateRepository;
+    use crate::test_utils::MemoryStateRepository;
+
+    const INSPECT: &str = "image-info {image}";
+
+    fn options(staging: &std::path::Path) -> ImageDeployOptions {
+        ImageDeployOptions {
+            staging_directory: staging.to_owned(),
+            target_device: "/dev/mmcblk0p2".to_owned(),
+            compatible: "board-x".to_owned(),
+            inspect_command: INSPECT.to_owned(),
+            boot_applier_commands: vec![
+                "fw_setenv applier_script {applier}".to_owned(),
+                "fw_setenv bootcmd_once 'run boot_ramdisk'".to_owned(),
+            ],
+            confirm_commands: vec!["fw_setenv upgrade_available 0".to_owned()],
+            applier_template: None,
+        }
+    }
+
+    fn old_version() -> Option<String> {
+        Some("1.0.0".to_owned())
+    }
+
+    fn new_version() -> Option<String> {
+        Some("2.0.0".to_owned())
+    }
+
+    #[test]
+    fn image_info_parsed() {
+        let info = parse_image_info("compatible=\"board-x\"\nversion=2.0.0\nsize=1024\n").unwrap();
+        assert_eq!(info.compatible, "board-x");
+        assert_eq!(info.version, "2.0.0");
+        assert!(parse_image_info("compatible=board-x\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn image_staged_and_applier_booted_once() {
+        let directory = tempfile::tempdir().unwrap();
+        let staging = directory.path().join("staging");
+        let source = directory.path().join("download.img");
+        std::fs::write(&source, b"rootfs").unwrap();
+
+        let image = staging.join("image.img");
+        let mut runner = MockCommandRunner::new();
+        let mut sequence = Sequence::new();
+        runner
+            .expect_run()
+            .with(eq(format!("image-info {}", image.display())))
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Ok("compatible=board-x\nversion=2.0.0\n".to_owned()));
+        for command in [
+            format!(
+                "fw_setenv applier_script {}",
+                staging.join("apply.sh").display()
+            ),
+            "fw_setenv bootcmd_once 'run boot_ramdisk'".to_owned(),
+        ] {
+            runner
+                .expect_run()
+                .with(eq(command))
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_| Ok(String::new()));
+        }
+
+        let repository = Arc::new(MemoryStateRepository::new());
+        let deployer = ImageDeployer::new(
+            options(&staging),
+            Box::new(runner),
+            Box::new(repository.clone()),
+            old_version,
+        );
+
+        deployer
+            .install_bundle(&source.to_string_lossy())
+            .await
+            .unwrap();
+        assert_eq!(deployer.receive_completed().await.unwrap(), 0);
+        assert!(deployer.receive_completed().await.is_err());
+
+        assert_eq!(std::fs::read(&image).unwrap(), b"rootfs");
+        let applier = staging.join("apply.sh");
+        let script = std::fs::read_to_string(&applier).unwrap();
+        assert!(script.contains(&format!("dd if={} of=/dev/mmcblk0p2", image.display())));
+        let mode = std::fs::metadata(&applier).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        assert_eq!(
+            repository.value(),
+            Some(StagedImage {
+                expected_version: "2.0.0".to_owned(),
+                previous_version: Some("1.0.0".to_owned()),
+            })
+        );
+        assert_eq!(deployer.boot_slot().await.unwrap(), "1.0.0");
+    }
+
+    #[tokio::test]
+    async fn failed_bootloader_command_reported() {
+        let directory = tempfile::tempdir().unwrap();
+        let staging = directory.path().join("staging");
+        let source = directory.path().join("download.img");
+        std::fs::write(&source, b"rootfs").unwrap();
+
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|command: &str| command.starts_with("image-info"))
+            .returning(|_| Ok("compatible=board-x\nversion=2.0.0\n".to_owned()));
+        runner
+            .expect_run()
+            .withf(|command: &str| command.starts_with("fw_setenv"))
+            .times(1)
+            .returning(|_| {
+                Err(crate::error::DeviceManagerError::UpdateError(
+                    "no environment".to_owned(),
+                ))
+            });
+
+        let deployer = ImageDeployer::new(
+            options(&staging),
+            Box::new(runner),
+            Box::new(MemoryStateRepository::new()),
+            old_version,
+        );
+
+        assert!(deployer
+            .install_bundle(&source.to_string_lossy())
+            .await
+            .is_err());
+        assert_eq!(deployer.receive_completed().await.unwrap(), 1);
+        assert!(!deployer.last_error().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_image_confirmed_after_boot() {
+        let directory = tempfile::tempdir().unwrap();
+        let repository = Arc::new(MemoryStateRepository::new());
+        let staged = StagedImage {
+            expected_version: "2.0.0".to_owned(),
+            previous_version: Some("1.0.0".to_owned()),
+        };
+
+        // the old image booted again, nothing is confirmed
+        repository.write(&staged).unwrap();
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run().never();
+        let deployer = ImageDeployer::new(
+            options(directory.path()),
+            Box::new(runner),
+            Box::new(repository.clone()),
+            old_version,
+        );
+        let primary = deployer.get_primary().await.unwrap();
+        assert!(deployer.mark("good", &primary).await.is_err());
+        assert_eq!(repository.value(), Some(staged.clone()));
+        // marked bad after the rollback, the staged image is discarded
+        assert_eq!(
+            deployer.mark("bad", "other").await.unwrap().0,
+            "2.0.0".to_owned()
+        );
+        assert_eq!(repository.value(), None);
+        repository.write(&staged).unwrap();
+
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .with(eq("fw_setenv upgrade_available 0".to_owned()))
+            .times(1)
+            .returning(|_| Ok(String::new()));
+        let deployer = ImageDeployer::new(
+            options(directory.path()),
+            Box::new(runner),
+            Box::new(repository.clone()),
+            new_version,
+        );
+        let primary = deployer.get_primary().await.unwrap();
+        assert_eq!(
+            deployer.mark("good", &primary).await.unwrap().0,
+            "2.0.0".to_owned()
+        );
+        assert_eq!(repository.value(), None);
+    }
+}
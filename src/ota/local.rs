@@ -0,0 +1,157 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Bundles already on the device, e.g. on a USB stick during the factory provisioning.
+//!
+//! An OTA request may point at a local file with a `file://` URL or an absolute path instead of
+//! an HTTP URL. The file is staged into the download directory, copied or hard-linked, and then
+//! verified and deployed like a downloaded one. The relative paths are rejected, they would
+//! depend on the working directory of the runtime.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+use reqwest::Url;
+
+use crate::ota::ota_handler::OTAError;
+
+/// Path of the local file `url` points at, `None` for the remote URLs.
+pub fn local_path(url: &str) -> Result<Option<PathBuf>, OTAError> {
+    if url.starts_with('/') {
+        return Ok(Some(PathBuf::from(url)));
+    }
+
+    match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "file" => match parsed.to_file_path() {
+            Ok(path) => Ok(Some(path)),
+            Err(()) => {
+                error!("Invalid local bundle URL {url}");
+                Err(OTAError::InvalidLocalFile)
+            }
+        },
+        Ok(_) => Ok(None),
+        Err(_) if !url.contains(':') => {
+            error!("Relative bundle path {url} rejected, only absolute paths are accepted");
+            Err(OTAError::InvalidLocalFile)
+        }
+        // left to the downloader to report
+        Err(_) => Ok(None),
+    }
+}
+
+/// Stage the bundle at `source` into `target`, hard-linking it when `link` is set and both are
+/// on the same filesystem, copying it otherwise.
+pub fn stage(source: &Path, target: &Path, link: bool) -> Result<(), OTAError> {
+    let invalid = |reason: std::io::Error| {
+        error!(
+            "Unable to stage the bundle {}: {}",
+            source.display(),
+            reason
+        );
+        OTAError::InvalidLocalFile
+    };
+
+    // opened to check it can be read, the directories are rejected too
+    let metadata = File::open(source)
+        .and_then(|file| file.metadata())
+        .map_err(invalid)?;
+    if !metadata.is_file() {
+        error!("The bundle {} is not a regular file", source.display());
+        return Err(OTAError::InvalidLocalFile);
+    }
+
+    match std::fs::remove_file(target) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(invalid(err)),
+        _ => {}
+    }
+    if link && std::fs::hard_link(source, target).is_ok() {
+        info!("Bundle {} linked", source.display());
+        return Ok(());
+    }
+
+    std::fs::copy(source, target).map_err(invalid)?;
+    info!("Bundle {} copied", source.display());
+
+    Ok(())
+}
+
+/// Path of the detached signature of the bundle at `source`, next to it with the `.sig`
+/// extension.
+pub fn signature_path(source: &Path) -> PathBuf {
+    let mut path = source.as_os_str().to_owned();
+    path.push(".sig");
+
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+
+    use crate::ota::local::{local_path, signature_path, stage};
+    use crate::ota::ota_handler::OTAError;
+
+    #[test]
+    fn local_urls_recognized() {
+        assert_eq!(
+            local_path("file:///media/usb/update.raucb").unwrap(),
+            Some(PathBuf::from("/media/usb/update.raucb"))
+        );
+        assert_eq!(
+            local_path("/data/update.raucb").unwrap(),
+            Some(PathBuf::from("/data/update.raucb"))
+        );
+        assert_eq!(local_path("https://ota.example.com/1.raucb").unwrap(), None);
+        assert!(matches!(
+            local_path("bundles/update.raucb"),
+            Err(OTAError::InvalidLocalFile)
+        ));
+        assert!(local_path("file://bundles/update.raucb").is_err());
+        assert_eq!(
+            signature_path(Path::new("/media/usb/update.raucb")),
+            PathBuf::from("/media/usb/update.raucb.sig")
+        );
+    }
+
+    #[test]
+    fn bundle_copied_or_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("update.raucb");
+        std::fs::write(&source, b"bundle").unwrap();
+        let target = dir.path().join("update.bin");
+        std::fs::write(&target, b"previous download").unwrap();
+
+        stage(&source, &target, false).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"bundle");
+        assert_eq!(std::fs::metadata(&source).unwrap().nlink(), 1);
+
+        stage(&source, &target, true).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"bundle");
+        assert_eq!(std::fs::metadata(&source).unwrap().nlink(), 2);
+
+        assert!(matches!(
+            stage(&dir.path().join("missing.raucb"), &target, false),
+            Err(OTAError::InvalidLocalFile)
+        ));
+        assert!(stage(dir.path(), &target, false).is_err());
+    }
+}
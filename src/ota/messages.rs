@@ -0,0 +1,580 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Payloads of the `io.edgehog.devicemanager.OTARequest` and
+//! `io.edgehog.devicemanager.OTAResponse` interfaces.
+//!
+//! A request with the `Cancel` operation cancels the update with the same uuid instead of
+//! starting one.
+//!
+//! The conversions from the Astarte objects check every known field, a field with the wrong type
+//! rejects the whole request; fields unknown to this version are ignored.
+
+use std::collections::HashMap;
+
+use astarte_sdk::types::AstarteType;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::error::DeviceManagerError;
+use crate::ota::failure::OtaErrorCode;
+use crate::ota::ota_handler::BundleSignature;
+use crate::ota::signature::PUBLIC_KEY_ID;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("Unable to find key {0} in OTA request")]
+    MissingField(&'static str),
+    #[error("Got bad data in OTARequest field {0}")]
+    WrongType(&'static str),
+    #[error("Unable to parse request_uuid")]
+    InvalidUuid,
+    #[error("Unable to parse checksum")]
+    InvalidChecksum,
+    #[error("Unknown bundle type {0}")]
+    UnknownBundleType(String),
+}
+
+impl From<RequestError> for DeviceManagerError {
+    fn from(err: RequestError) -> Self {
+        DeviceManagerError::UpdateError(err.to_string())
+    }
+}
+
+/// What the artifact of the request is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BundleType {
+    /// RAUC bundle of the system update.
+    Update,
+    /// New set of trusted keys.
+    KeyBundle,
+}
+
+impl Default for BundleType {
+    fn default() -> Self {
+        BundleType::Update
+    }
+}
+
+impl BundleType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BundleType::Update => "update",
+            BundleType::KeyBundle => "keyBundle",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OtaRequest {
+    pub uuid: Uuid,
+    pub url: String,
+    pub bundle_type: Option<BundleType>,
+    pub allow_metered: Option<bool>,
+    pub key_id: Option<String>,
+    pub signature: Option<Vec<u8>>,
+    /// SHA-256 digest of the artifact, hex encoded in the payload.
+    pub checksum: Option<[u8; 32]>,
+    /// Connect to the artifact host even if it is cached as unreachable.
+    pub retry_unreachable_host: Option<bool>,
+}
+
+impl OtaRequest {
+    pub fn new(uuid: Uuid, url: &str) -> Self {
+        OtaRequest {
+            uuid,
+            url: url.to_owned(),
+            bundle_type: None,
+            allow_metered: None,
+            key_id: None,
+            signature: None,
+            checksum: None,
+            retry_unreachable_host: None,
+        }
+    }
+
+    pub fn bundle_type(&self) -> BundleType {
+        self.bundle_type.unwrap_or_default()
+    }
+
+    pub fn allow_metered(&self) -> bool {
+        self.allow_metered.unwrap_or(false)
+    }
+
+    pub fn retry_unreachable_host(&self) -> bool {
+        self.retry_unreachable_host.unwrap_or(false)
+    }
+
+    /// The signature of the artifact, made with the `ota_public_key_path` key when the key id
+    /// is not set.
+    pub fn bundle_signature(&self) -> Option<BundleSignature> {
+        self.signature.as_ref().map(|signature| BundleSignature {
+            key_id: self
+                .key_id
+                .clone()
+                .unwrap_or_else(|| PUBLIC_KEY_ID.to_owned()),
+            signature: signature.clone(),
+        })
+    }
+}
+
+fn field<'a, T>(
+    data: &'a HashMap<String, AstarteType>,
+    name: &'static str,
+    convert: impl FnOnce(&'a AstarteType) -> Option<T>,
+) -> Result<Option<T>, RequestError> {
+    data.get(name)
+        .map(|value| convert(value).ok_or(RequestError::WrongType(name)))
+        .transpose()
+}
+
+fn string(value: &AstarteType) -> Option<&String> {
+    match value {
+        AstarteType::String(value) => Some(value),
+        _ => None,
+    }
+}
+
+impl TryFrom<&HashMap<String, AstarteType>> for OtaRequest {
+    type Error = RequestError;
+
+    fn try_from(data: &HashMap<String, AstarteType>) -> Result<Self, Self::Error> {
+        let required =
+            |name: &'static str| field(data, name, string)?.ok_or(RequestError::MissingField(name));
+
+        let url = required("url")?.clone();
+        let uuid = Uuid::parse_str(required("uuid")?).map_err(|_| RequestError::InvalidUuid)?;
+
+        let bundle_type = field(data, "bundleType", string)?
+            .map(|bundle_type| match bundle_type.as_str() {
+                "update" => Ok(BundleType::Update),
+                "keyBundle" => Ok(BundleType::KeyBundle),
+                _ => Err(RequestError::UnknownBundleType(bundle_type.clone())),
+            })
+            .transpose()?;
+
+        let boolean = |value: &AstarteType| match value {
+            AstarteType::Boolean(value) => Some(*value),
+            _ => None,
+        };
+        let allow_metered = field(data, "allowMetered", boolean)?;
+        let retry_unreachable_host = field(data, "retryUnreachableHost", boolean)?;
+
+        let key_id = field(data, "keyId", string)?.cloned();
+        let signature = field(data, "signature", |value| match value {
+            AstarteType::BinaryBlob(value) => Some(value.clone()),
+            _ => None,
+        })?;
+
+        let checksum = field(data, "checksum", string)?
+            .map(|checksum| parse_sha256(checksum).ok_or(RequestError::InvalidChecksum))
+            .transpose()?;
+
+        Ok(OtaRequest {
+            uuid,
+            url,
+            bundle_type,
+            allow_metered,
+            key_id,
+            signature,
+            checksum,
+            retry_unreachable_host,
+        })
+    }
+}
+
+impl From<OtaRequest> for HashMap<String, AstarteType> {
+    fn from(request: OtaRequest) -> Self {
+        let mut data = HashMap::from([
+            (
+                "uuid".to_owned(),
+                AstarteType::String(request.uuid.to_string()),
+            ),
+            ("url".to_owned(), AstarteType::String(request.url)),
+        ]);
+
+        if let Some(bundle_type) = request.bundle_type {
+            data.insert(
+                "bundleType".to_owned(),
+                AstarteType::String(bundle_type.as_str().to_owned()),
+            );
+        }
+        if let Some(allow_metered) = request.allow_metered {
+            data.insert(
+                "allowMetered".to_owned(),
+                AstarteType::Boolean(allow_metered),
+            );
+        }
+        if let Some(retry_unreachable_host) = request.retry_unreachable_host {
+            data.insert(
+                "retryUnreachableHost".to_owned(),
+                AstarteType::Boolean(retry_unreachable_host),
+            );
+        }
+        if let Some(key_id) = request.key_id {
+            data.insert("keyId".to_owned(), AstarteType::String(key_id));
+        }
+        if let Some(signature) = request.signature {
+            data.insert("signature".to_owned(), AstarteType::BinaryBlob(signature));
+        }
+        if let Some(checksum) = request.checksum {
+            data.insert(
+                "checksum".to_owned(),
+                AstarteType::String(checksum.iter().map(|byte| format!("{byte:02x}")).collect()),
+            );
+        }
+
+        data
+    }
+}
+
+/// Request to cancel the update `uuid`, sent on the request interface with the `Cancel`
+/// operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtaCancel {
+    pub uuid: Uuid,
+}
+
+impl OtaCancel {
+    /// Whether `data` asks to cancel an update rather than to start one.
+    pub fn is_cancel(data: &HashMap<String, AstarteType>) -> bool {
+        matches!(
+            data.get("operation"),
+            Some(AstarteType::String(operation)) if operation == "Cancel"
+        )
+    }
+}
+
+impl TryFrom<&HashMap<String, AstarteType>> for OtaCancel {
+    type Error = RequestError;
+
+    fn try_from(data: &HashMap<String, AstarteType>) -> Result<Self, Self::Error> {
+        let uuid = field(data, "uuid", string)?.ok_or(RequestError::MissingField("uuid"))?;
+
+        Ok(OtaCancel {
+            uuid: Uuid::parse_str(uuid).map_err(|_| RequestError::InvalidUuid)?,
+        })
+    }
+}
+
+impl From<OtaCancel> for HashMap<String, AstarteType> {
+    fn from(cancel: OtaCancel) -> Self {
+        HashMap::from([
+            (
+                "uuid".to_owned(),
+                AstarteType::String(cancel.uuid.to_string()),
+            ),
+            (
+                "operation".to_owned(),
+                AstarteType::String("Cancel".to_owned()),
+            ),
+        ])
+    }
+}
+
+/// Progress of a request, `status_code` tells the error when the status is `Error`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OtaResponse {
+    pub uuid: Uuid,
+    pub status: String,
+    pub status_code: String,
+    /// Kind of failure and its description, when the status is `Error`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<OtaErrorCode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl TryFrom<&HashMap<String, AstarteType>> for OtaResponse {
+    type Error = RequestError;
+
+    fn try_from(data: &HashMap<String, AstarteType>) -> Result<Self, Self::Error> {
+        let required = |name: &'static str| {
+            field(data, name, string)?
+                .cloned()
+                .ok_or(RequestError::MissingField(name))
+        };
+
+        Ok(OtaResponse {
+            uuid: Uuid::parse_str(&required("uuid")?).map_err(|_| RequestError::InvalidUuid)?,
+            status: required("status")?,
+            status_code: required("statusCode")?,
+            error_code: field(data, "errorCode", |value| match value {
+                AstarteType::String(code) => OtaErrorCode::parse(code),
+                _ => None,
+            })?,
+            message: field(data, "message", string)?.cloned(),
+        })
+    }
+}
+
+impl From<OtaResponse> for HashMap<String, AstarteType> {
+    fn from(response: OtaResponse) -> Self {
+        let mut data = HashMap::from([
+            (
+                "uuid".to_owned(),
+                AstarteType::String(response.uuid.to_string()),
+            ),
+            ("status".to_owned(), AstarteType::String(response.status)),
+            (
+                "statusCode".to_owned(),
+                AstarteType::String(response.status_code),
+            ),
+        ]);
+
+        if let Some(error_code) = response.error_code {
+            data.insert(
+                "errorCode".to_owned(),
+                AstarteType::String(error_code.as_str().to_owned()),
+            );
+        }
+        if let Some(message) = response.message {
+            data.insert("message".to_owned(), AstarteType::String(message));
+        }
+
+        data
+    }
+}
+
+/// Parse a hex encoded SHA-256 digest.
+pub(crate) fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use astarte_sdk::types::AstarteType;
+    use uuid::Uuid;
+
+    use crate::ota::failure::OtaErrorCode;
+    use crate::ota::messages::{
+        parse_sha256, BundleType, OtaCancel, OtaRequest, OtaResponse, RequestError,
+    };
+
+    fn full_request() -> OtaRequest {
+        OtaRequest {
+            bundle_type: Some(BundleType::KeyBundle),
+            allow_metered: Some(true),
+            key_id: Some("release-2022".to_owned()),
+            signature: Some(vec![1, 2, 3]),
+            checksum: parse_sha256(&"ab".repeat(32)),
+            retry_unreachable_host: Some(true),
+            ..OtaRequest::new(Uuid::new_v4(), "http://ota.bin")
+        }
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let full = full_request();
+        let minimal = OtaRequest::new(Uuid::new_v4(), "http://ota.bin");
+
+        for request in [full.clone(), minimal] {
+            let data = HashMap::from(request.clone());
+            assert_eq!(OtaRequest::try_from(&data), Ok(request));
+        }
+
+        // every optional field by itself
+        let optional: [fn(&mut OtaRequest); 6] = [
+            |request| request.bundle_type = Some(BundleType::Update),
+            |request| request.allow_metered = Some(false),
+            |request| request.key_id = Some("release-2022".to_owned()),
+            |request| request.signature = Some(Vec::new()),
+            |request| request.checksum = Some([0; 32]),
+            |request| request.retry_unreachable_host = Some(false),
+        ];
+        for set in optional {
+            let mut request = OtaRequest::new(full.uuid, &full.url);
+            set(&mut request);
+
+            let data = HashMap::from(request.clone());
+            assert_eq!(data.len(), 3);
+            assert_eq!(OtaRequest::try_from(&data), Ok(request));
+        }
+    }
+
+    #[test]
+    fn request_fields_parsed() {
+        let uuid = Uuid::new_v4();
+        let data = HashMap::from([
+            ("uuid".to_owned(), AstarteType::String(uuid.to_string())),
+            (
+                "url".to_owned(),
+                AstarteType::String("http://ota.bin".to_owned()),
+            ),
+            ("checksum".to_owned(), AstarteType::String("AB".repeat(32))),
+            ("unknownField".to_owned(), AstarteType::Integer(1)),
+        ]);
+
+        let request = OtaRequest::try_from(&data).unwrap();
+        assert_eq!(request.uuid, uuid);
+        assert_eq!(request.checksum, Some([0xab; 32]));
+        assert_eq!(request.bundle_type(), BundleType::Update);
+        assert!(!request.allow_metered());
+        assert!(!request.retry_unreachable_host());
+        assert!(request.bundle_signature().is_none());
+    }
+
+    #[test]
+    fn request_with_wrong_types_rejected() {
+        let valid = HashMap::from(full_request());
+
+        for (name, value, error) in [
+            (
+                "uuid",
+                AstarteType::Integer(0),
+                RequestError::WrongType("uuid"),
+            ),
+            (
+                "url",
+                AstarteType::Boolean(true),
+                RequestError::WrongType("url"),
+            ),
+            (
+                "uuid",
+                AstarteType::String("bad_uuid".to_owned()),
+                RequestError::InvalidUuid,
+            ),
+            (
+                "bundleType",
+                AstarteType::Integer(1),
+                RequestError::WrongType("bundleType"),
+            ),
+            (
+                "bundleType",
+                AstarteType::String("firmware".to_owned()),
+                RequestError::UnknownBundleType("firmware".to_owned()),
+            ),
+            (
+                "allowMetered",
+                AstarteType::String("true".to_owned()),
+                RequestError::WrongType("allowMetered"),
+            ),
+            (
+                "retryUnreachableHost",
+                AstarteType::Integer(1),
+                RequestError::WrongType("retryUnreachableHost"),
+            ),
+            (
+                "keyId",
+                AstarteType::BinaryBlob(vec![1]),
+                RequestError::WrongType("keyId"),
+            ),
+            (
+                "signature",
+                AstarteType::String("c2lnbmF0dXJl".to_owned()),
+                RequestError::WrongType("signature"),
+            ),
+            (
+                "checksum",
+                AstarteType::BinaryBlob(vec![0xab; 32]),
+                RequestError::WrongType("checksum"),
+            ),
+            (
+                "checksum",
+                AstarteType::String("ab".repeat(16)),
+                RequestError::InvalidChecksum,
+            ),
+        ] {
+            let mut data = valid.clone();
+            data.insert(name.to_owned(), value);
+            assert_eq!(OtaRequest::try_from(&data), Err(error), "{name}");
+        }
+
+        for required in ["uuid", "url"] {
+            let mut data = valid.clone();
+            data.remove(required);
+            assert_eq!(
+                OtaRequest::try_from(&data),
+                Err(RequestError::MissingField(required))
+            );
+        }
+    }
+
+    #[test]
+    fn cancel_told_apart_from_the_requests() {
+        let cancel = OtaCancel {
+            uuid: Uuid::new_v4(),
+        };
+        let data = HashMap::from(cancel);
+        assert!(OtaCancel::is_cancel(&data));
+        assert_eq!(OtaCancel::try_from(&data), Ok(cancel));
+        assert!(!OtaCancel::is_cancel(&HashMap::from(full_request())));
+
+        let mut data = data;
+        data.insert(
+            "uuid".to_owned(),
+            AstarteType::String("bad_uuid".to_owned()),
+        );
+        assert_eq!(OtaCancel::try_from(&data), Err(RequestError::InvalidUuid));
+        data.remove("uuid");
+        assert_eq!(
+            OtaCancel::try_from(&data),
+            Err(RequestError::MissingField("uuid"))
+        );
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let response = OtaResponse {
+            uuid: Uuid::new_v4(),
+            status: "Error".to_owned(),
+            status_code: "OTAErrorNetwork".to_owned(),
+            error_code: Some(OtaErrorCode::NetworkError),
+            message: Some("the artifact download failed".to_owned()),
+        };
+
+        let data = HashMap::from(response.clone());
+        assert_eq!(OtaResponse::try_from(&data), Ok(response.clone()));
+
+        let mut data = HashMap::from(response);
+        data.insert("statusCode".to_owned(), AstarteType::Integer(1));
+        assert_eq!(
+            OtaResponse::try_from(&data),
+            Err(RequestError::WrongType("statusCode"))
+        );
+        data.remove("statusCode");
+        assert_eq!(
+            OtaResponse::try_from(&data),
+            Err(RequestError::MissingField("statusCode"))
+        );
+    }
+
+    #[test]
+    fn sha256_parsed() {
+        assert_eq!(parse_sha256(&"0f".repeat(32)), Some([0x0f; 32]));
+        assert_eq!(parse_sha256("0f"), None);
+        assert_eq!(parse_sha256(&"zz".repeat(32)), None);
+    }
+}
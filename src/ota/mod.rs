@@ -18,23 +18,69 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+use std::sync::Mutex;
+
 use async_trait::async_trait;
 #[cfg(test)]
 use mockall::automock;
+use serde::{Deserialize, Serialize};
 
 use crate::error::DeviceManagerError;
-use crate::ota::rauc::BundleInfo;
+use crate::ota::script::ScriptDeployOptions;
+use crate::ota::swupdate::SwUpdateOptions;
 
+pub(crate) mod artifacts;
+pub(crate) mod bandwidth;
+pub(crate) mod download;
+pub(crate) mod failure;
+pub(crate) mod host_cache;
+pub(crate) mod image;
+pub(crate) mod local;
+pub(crate) mod messages;
 pub(crate) mod ota_handler;
+pub(crate) mod progress;
+pub(crate) mod rate_limit;
+#[cfg(feature = "dbus")]
 pub(crate) mod rauc;
+pub(crate) mod script;
+pub(crate) mod signature;
+pub(crate) mod swupdate;
+pub(crate) mod verification;
+pub(crate) mod worker;
+
+/// Deploy backend selected with `ota_backend`, the image deployer or RAUC when unset.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OtaBackend {
+    Rauc,
+    Swupdate(SwUpdateOptions),
+    Command(ScriptDeployOptions),
+}
+
+/// Compatible and version of a bundle, as the RAUC `Info` call returns them.
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(
+    feature = "dbus",
+    derive(zbus::zvariant::Type),
+    zvariant(signature = "(ss)")
+)]
+pub struct BundleInfo {
+    pub compatible: String,
+    pub version: String,
+}
 
+/// Deploy backend the [`OTAHandler`](ota_handler::OTAHandler) installs the bundles with, modelled
+/// on the RAUC D-Bus interface.
 #[cfg_attr(test, automock)]
 #[async_trait]
+#[allow(clippy::upper_case_acronyms)]
 pub trait OTA: Send + Sync {
     async fn install_bundle(&self, source: &str) -> Result<(), DeviceManagerError>;
     async fn last_error(&self) -> Result<String, DeviceManagerError>;
     async fn info(&self, bundle: &str) -> Result<BundleInfo, DeviceManagerError>;
     async fn operation(&self) -> Result<String, DeviceManagerError>;
+    /// Percentage of the running installation.
+    async fn progress(&self) -> Result<i32, DeviceManagerError>;
     async fn compatible(&self) -> Result<String, DeviceManagerError>;
     async fn boot_slot(&self) -> Result<String, DeviceManagerError>;
     async fn receive_completed(&self) -> Result<i32, DeviceManagerError>;
@@ -44,4 +90,108 @@ pub trait OTA: Send + Sync {
         state: &str,
         slot_identifier: &str,
     ) -> Result<(String, String), DeviceManagerError>;
+    /// Cheap call to check that the deploy backend is available.
+    async fn health_check(&self) -> Result<(), DeviceManagerError>;
+}
+
+/// Outcome of the backends installing within `install_bundle`, reported through the calls RAUC
+/// answers asynchronously.
+#[derive(Debug, Default)]
+pub struct InstallOutcome {
+    completed: Mutex<Option<i32>>,
+    last_error: Mutex<String>,
+}
+
+impl InstallOutcome {
+    pub fn record(&self, result: &Result<(), DeviceManagerError>) {
+        *self.last_error.lock().unwrap() = match result {
+            Ok(()) => String::new(),
+            Err(err) => format!("{:?}", err),
+        };
+        *self.completed.lock().unwrap() = Some(if result.is_ok() { 0 } else { 1 });
+    }
+
+    pub fn last_error(&self) -> String {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Percentage of the installation, complete once recorded.
+    pub fn progress(&self) -> i32 {
+        let installed = self.completed.lock().unwrap().is_some();
+
+        if installed {
+            100
+        } else {
+            0
+        }
+    }
+
+    /// Take the result of the last installation, like the RAUC `Completed` signal.
+    pub fn take(&self) -> Result<i32, DeviceManagerError> {
+        self.completed.lock().unwrap().take().ok_or_else(|| {
+            DeviceManagerError::UpdateError("no bundle is being installed".to_owned())
+        })
+    }
+}
+
+/// Deploy backend of the platforms without a system bus, every call fails.
+pub struct UnavailableOTA;
+
+impl UnavailableOTA {
+    fn error<T>(&self) -> Result<T, DeviceManagerError> {
+        Err(DeviceManagerError::UpdateError(
+            "RAUC is not reachable without a D-Bus system bus".to_owned(),
+        ))
+    }
+}
+
+#[async_trait]
+impl OTA for UnavailableOTA {
+    async fn install_bundle(&self, _source: &str) -> Result<(), DeviceManagerError> {
+        self.error()
+    }
+
+    async fn last_error(&self) -> Result<String, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn info(&self, _bundle: &str) -> Result<BundleInfo, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn operation(&self) -> Result<String, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn progress(&self) -> Result<i32, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn compatible(&self) -> Result<String, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn boot_slot(&self) -> Result<String, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn receive_completed(&self) -> Result<i32, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn get_primary(&self) -> Result<String, DeviceManagerError> {
+        self.error()
+    }
+
+    async fn mark(
+        &self,
+        _state: &str,
+        _slot_identifier: &str,
+    ) -> Result<(String, String), DeviceManagerError> {
+        self.error()
+    }
+
+    async fn health_check(&self) -> Result<(), DeviceManagerError> {
+        self.error()
+    }
 }
@@ -19,27 +19,126 @@
  */
 
 use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use astarte_sdk::types::AstarteType;
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{oneshot, watch};
 use uuid::Uuid;
 
+use crate::clock::Clock;
 use crate::data::Publisher;
 use crate::error::DeviceManagerError;
+use crate::event_log::{AuditedEvent, EventLog};
+use crate::interfaces::{BASE_IMAGE_INTERFACE, DIAGNOSTICS_INTERFACE, OTA_RESPONSE_INTERFACE};
+use crate::inventory::Collector;
+use crate::lifecycle::{Lifecycle, LifecycleEvent};
+use crate::metrics::Metrics;
+use crate::ota::artifacts::{self, artifact_name};
+use crate::ota::bandwidth::BandwidthProbe;
+#[cfg(not(test))]
+use crate::ota::download;
+use crate::ota::download::{
+    shutdown_requested, DownloadOutcome, Downloader, ResumePoint, RetryPolicy,
+};
+use crate::ota::failure::{self, OtaErrorCode};
+use crate::ota::image::{ImageDeployer, ShellRunner, STAGED_IMAGE_FILE};
+use crate::ota::local;
+use crate::ota::messages::{BundleType, OtaRequest, OtaResponse};
+use crate::ota::progress::{DownloadProgress, Phase, ProgressReporter, ProgressThrottle};
+#[cfg(feature = "dbus")]
 use crate::ota::rauc::OTARauc;
-use crate::ota::OTA;
-use crate::power_management;
+use crate::ota::script::ScriptDeployer;
+use crate::ota::signature::{TrustedKeySet, TrustedKeys, PUBLIC_KEY_ID};
+use crate::ota::swupdate::SwUpdateDeployer;
+use crate::ota::verification::{
+    self, EnforcementMode, EnforcementOptions, SignatureCheck, Verdict, VerificationSpec,
+};
+use crate::ota::{OtaBackend, UnavailableOTA, OTA};
+use crate::power_management::{self, PlatformPower, PowerActions};
+use crate::quiet_hours::QuietHours;
+use crate::redaction::redactor;
 use crate::repository::file_state_repository::FileStateRepository;
 use crate::repository::StateRepository;
+use crate::runtime_api::SharedState;
+use crate::telemetry::{base_image, os_info};
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::platform;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct PersistentState {
     uuid: Uuid,
     slot: String,
+    #[serde(default)]
+    from_version: Option<String>,
+    #[serde(default)]
+    to_version: Option<String>,
+    /// Verdicts of the content checks run on the installed artifact.
+    #[serde(default)]
+    verdicts: Vec<Verdict>,
+    /// Throughput measured by the bandwidth probe before the download, in bytes per second.
+    #[serde(default)]
+    link_throughput: Option<u64>,
+    /// Outcome of the reboot into the update, recorded once the slots are marked so that the
+    /// next start only publishes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    outcome: Option<BootOutcome>,
 }
 
-#[derive(thiserror::Error, Debug)]
+/// How the reboot into an update went.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum BootOutcome {
+    /// Booted into the new slot, marked good.
+    Applied,
+    /// The bootloader fell back to the previous slot, the new one was marked bad.
+    RolledBack,
+    /// The new slot could not be confirmed.
+    Failed,
+}
+
+impl BootOutcome {
+    fn status(self) -> OTAStatus {
+        match self {
+            BootOutcome::Applied => OTAStatus::Done,
+            BootOutcome::RolledBack => OTAStatus::Error(OTAError::Rollback),
+            BootOutcome::Failed => OTAStatus::Error(OTAError::Failed),
+        }
+    }
+}
+
+/// Step of an update in progress, recorded at each transition.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum UpdatePhase {
+    /// Downloading and verifying the artifact.
+    Downloading,
+    /// The artifact is complete and handed over to the deploy backend, until the reboot.
+    Deploying,
+}
+
+/// A download stopped by the shutdown or an update in progress, resumed at the next start.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PausedDownload {
+    pub(crate) request: OtaRequest,
+    pub(crate) resume: ResumePoint,
+    /// Step of the update in progress, unset for a download paused by the shutdown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) in_flight: Option<UpdatePhase>,
+    /// Starts that found the update interrupted, e.g. by a crash.
+    #[serde(default)]
+    pub(crate) restarts: u32,
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
 pub enum OTAError {
     /// Bundle download failed
     #[error("OTAErrorNetwork")]
@@ -50,55 +149,417 @@ pub enum OTAError {
     /// OTA failed
     #[error("OTAFailed")]
     Failed,
+    /// The bundle signature is missing or does not match
+    #[error("OTAErrorInvalidSignature")]
+    InvalidSignature,
+    /// The bundle is signed with a key that is not trusted
+    #[error("OTAErrorUnknownSigningKey")]
+    UnknownSigningKey,
+    /// The bundle is signed with a trusted key past its expiration date
+    #[error("OTAErrorExpiredSigningKey")]
+    ExpiredSigningKey,
+    /// The key bundle is malformed or older than the installed trusted keys
+    #[error("OTAErrorInvalidKeyBundle")]
+    InvalidKeyBundle,
+    /// The store filesystem is almost full
+    #[error("OTAErrorNotEnoughSpace")]
+    NotEnoughSpace,
+    /// The artifact content does not match the expected checksum
+    #[error("OTAErrorChecksumMismatch")]
+    ChecksumMismatch,
+    /// The update was canceled before being deployed
+    #[error("OTAErrorCanceled")]
+    Canceled,
+    /// The link stayed below the configured throughput until the probe deadline
+    #[error("OTAErrorLinkTooSlow")]
+    LinkTooSlow,
+    /// The artifact host failed to resolve or to connect too many times in a row
+    #[error("OTAErrorHostUnreachableCached")]
+    HostUnreachableCached,
+    /// The bundle staged on the device is missing, unreadable or not at an absolute path
+    #[error("OTAErrorInvalidLocalFile")]
+    InvalidLocalFile,
+    /// The bootloader rolled back to the previous slot after the reboot
+    #[error("OTAErrorRollback")]
+    Rollback,
+    /// Another update is in progress, the request is refused
+    #[error("OTAErrorUpdateAlreadyInProgress")]
+    UpdateAlreadyInProgress,
+}
+
+/// Signature of the bundle, along with the id of the key used to sign it.
+#[derive(Debug, Clone)]
+pub struct BundleSignature {
+    pub key_id: String,
+    pub signature: Vec<u8>,
 }
 
-#[derive(Debug)]
-enum OTAStatus {
+#[derive(Debug, Clone)]
+pub(crate) enum OTAStatus {
     InProgress,
+    /// The download stopped for the shutdown, it continues after the restart.
+    Paused,
+    /// The download is deferred until the link is fast enough.
+    LinkTooSlow,
     Done,
     Error(OTAError),
-}
-
-#[derive(serde::Serialize)]
-#[serde(rename_all = "camelCase")]
-struct OTAResponse {
-    uuid: Uuid,
-    status: String,
-    status_code: String,
+    /// The cancel came after the bundle was handed over to the installer, the update goes on.
+    CancelRejected,
 }
 
 impl OTAStatus {
     fn to_status_code(&self) -> (String, String) {
         match self {
             OTAStatus::InProgress => ("InProgress".to_string(), String::new()),
+            OTAStatus::Paused => ("Paused".to_string(), String::new()),
+            OTAStatus::LinkTooSlow => ("LinkTooSlow".to_string(), String::new()),
             OTAStatus::Done => ("Done".to_string(), String::new()),
             OTAStatus::Error(error) => ("Error".to_string(), error.to_string()),
+            OTAStatus::CancelRejected => (
+                "InProgress".to_string(),
+                "OTAErrorCancelNotPossible".to_string(),
+            ),
+        }
+    }
+
+    pub(crate) fn to_response(&self, uuid: Uuid) -> OtaResponse {
+        let (status, status_code) = self.to_status_code();
+        // the other statuses keep the shape they always had
+        let (error_code, message) = match self {
+            OTAStatus::Error(error) => (
+                Some(error.into()),
+                Some(failure::describe(error).to_owned()),
+            ),
+            _ => (None, None),
+        };
+
+        OtaResponse {
+            uuid,
+            status,
+            status_code,
+            error_code,
+            message,
+        }
+    }
+}
+
+/// Update downloaded and verified, ready to be deployed.
+enum Prepared {
+    Verified {
+        to_version: Option<String>,
+        verdicts: Vec<Verdict>,
+        link_throughput: Option<u64>,
+    },
+    /// The download stopped for the shutdown.
+    Paused(DownloadOutcome),
+}
+
+/// Wait for a cancel of the update `uuid` sent after `cancel` was last seen.
+async fn cancel_requested(cancel: &mut watch::Receiver<Option<Uuid>>, uuid: Uuid) {
+    loop {
+        if cancel.changed().await.is_err() {
+            // nothing can cancel the update anymore
+            return std::future::pending().await;
+        }
+        if *cancel.borrow() == Some(uuid) {
+            return;
+        }
+    }
+}
+
+/// Response of `uuid` with `status`, numbered in `event_log` when enabled. The number is
+/// published on the diagnostics interface, as the OTAResponse interface has no room for it.
+pub(crate) async fn stamped_response(
+    event_log: Option<&EventLog>,
+    sdk: &impl Publisher,
+    uuid: Uuid,
+    status: &OTAStatus,
+) -> OtaResponse {
+    if let Some(event_log) = event_log {
+        event_log
+            .publish_stamp(sdk, AuditedEvent::OtaStatus, &uuid.to_string())
+            .await;
+    }
+
+    status.to_response(uuid)
+}
+
+/// Run `update`, the handling of the request `active`, refusing the requests received meanwhile
+/// with [`OTAError::UpdateAlreadyInProgress`]. The request `active` sent again is dropped.
+async fn exclusively<F: Future>(
+    update: F,
+    active: Option<Uuid>,
+    sdk: &impl Publisher,
+    clock: &dyn Clock,
+    event_log: Option<&EventLog>,
+    requests: &mut Receiver<HashMap<String, AstarteType>>,
+) -> F::Output {
+    tokio::pin!(update);
+    let mut open = true;
+    loop {
+        // the update goes as far as it can before the requests are taken
+        let data = tokio::select! {
+            biased;
+            output = &mut update => return output,
+            data = requests.recv(), if open => data,
+        };
+        let data = match data {
+            Some(data) => data,
+            None => {
+                open = false;
+                continue;
+            }
+        };
+
+        let request = match OtaRequest::try_from(&data) {
+            Ok(request) => request,
+            Err(err) => {
+                error!("Invalid OTARequest ({}): {}", redactor().object(&data), err);
+                continue;
+            }
+        };
+        if Some(request.uuid) == active {
+            info!(
+                "Update {} already in progress, request ignored",
+                request.uuid
+            );
+            continue;
+        }
+
+        warn!(
+            "Update {} rejected, the update {:?} is in progress",
+            request.uuid, active
+        );
+        let status = OTAStatus::Error(OTAError::UpdateAlreadyInProgress);
+        let response = stamped_response(event_log, sdk, request.uuid, &status).await;
+        let timestamp = DateTime::<Utc>::from(clock.now_wall());
+        if let Err(err) = sdk
+            .send_object_with_timestamp(OTA_RESPONSE_INTERFACE, "/response", response, timestamp)
+            .await
+        {
+            warn!("Unable to publish OTA response -> {:?}", err);
         }
     }
 }
 
+/// Starts finding an update interrupted before it is given up, a crash loop may be its fault.
+const MAX_UPDATE_RESTARTS: u32 = 3;
+
+const PENDING_RESPONSE_ATTEMPTS: u32 = 5;
+/// Longest wait between two attempts of a download request, before the jitter.
+const MAX_DOWNLOAD_RETRY_WAIT: Duration = Duration::from_secs(5 * 60);
+pub const DEFAULT_HEALTH_PROBE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub struct OTAHandler<'a> {
     ota: Box<dyn OTA + 'a>,
     state_repository: Box<dyn StateRepository<PersistentState> + 'a>,
     download_file_path: String,
+    /// Leave the artifacts of the other requests in the download directory, for debugging.
+    keep_stale_downloads: bool,
+    clock: Arc<dyn Clock>,
+    /// Reboots into the deployed updates.
+    power: Box<dyn PowerActions + 'a>,
+    metered: watch::Receiver<bool>,
+    trusted_keys: Option<TrustedKeys<'a>>,
+    health_probe_period: Duration,
+    /// Deploy readiness last published on the diagnostics interface.
+    deploy_ready: Option<bool>,
+    enforcement: EnforcementOptions,
+    downloader: Arc<Downloader>,
+    /// The link is probed before the downloads, deferring them while it is too slow.
+    bandwidth_probe: Option<BandwidthProbe>,
+    download_repository: Box<dyn StateRepository<PausedDownload> + 'a>,
+    /// Response of an update given up publishing, published again before the next request.
+    unsent_response: Box<dyn StateRepository<OtaResponse> + 'a>,
+    /// Set when the runtime is shutting down, pausing the running download.
+    shutdown: watch::Receiver<bool>,
+    /// Uuid of the last update whose cancel was requested.
+    cancel: watch::Receiver<Option<Uuid>>,
+    lifecycle: Option<Arc<Lifecycle>>,
+    /// The image the device runs, published again once an update is applied.
+    base_image: Option<Collector>,
+    event_log: Option<Arc<EventLog>>,
+    /// Deploys and reboots are deferred to after the quiet hours.
+    quiet_hours: Option<Arc<QuietHours>>,
+    /// How often the progress of the updates is published, not published when unset.
+    progress: Option<ProgressThrottle>,
+    /// Last status sent for an OTA request, for the local status API.
+    status: watch::Sender<Option<OtaResponse>>,
+    /// Phase of the running update and last failure, for the local runtime API.
+    runtime_state: Option<Arc<SharedState>>,
+    /// Counters of the updates attempted, completed and failed.
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl<'a> OTAHandler<'a> {
     pub async fn new(
         opts: &crate::DeviceManagerOptions,
+        clock: Arc<dyn Clock>,
+        metered: watch::Receiver<bool>,
+        downloader: Arc<Downloader>,
+        lifecycle: Arc<Lifecycle>,
+        shutdown: watch::Receiver<bool>,
     ) -> Result<OTAHandler<'a>, DeviceManagerError> {
-        let ota = OTARauc::new().await?;
+        let ota: Box<dyn OTA + 'a> = match &opts.ota_backend {
+            #[cfg(feature = "dbus")]
+            Some(OtaBackend::Rauc) => Box::new(OTARauc::new().await?),
+            #[cfg(not(feature = "dbus"))]
+            Some(OtaBackend::Rauc) => {
+                warn!("Built without D-Bus, the RAUC backend is not available");
+                Box::new(UnavailableOTA)
+            }
+            Some(OtaBackend::Swupdate(options)) => Box::new(SwUpdateDeployer::new(
+                options.clone(),
+                Box::new(ShellRunner),
+            )),
+            Some(OtaBackend::Command(options)) => {
+                Box::new(ScriptDeployer::new(options.clone(), Box::new(ShellRunner)))
+            }
+            None => Self::default_backend(opts, &downloader).await?,
+        };
 
-        Ok(OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(FileStateRepository::new(
-                opts.store_directory.clone(),
-                "state.json".to_owned(),
+        let provisioned = match (&opts.ota_trusted_keys_directory, &opts.ota_public_key_path) {
+            (None, None) => None,
+            (directory, public_key) => {
+                let mut keys = match directory {
+                    Some(directory) => TrustedKeySet::load_directory(Path::new(directory))?,
+                    None => TrustedKeySet::default(),
+                };
+                if let Some(public_key) = public_key {
+                    keys = keys.with_public_key_file(Path::new(public_key))?;
+                }
+                Some(keys)
+            }
+        };
+        let trusted_keys = match provisioned {
+            Some(provisioned) => Some(TrustedKeys::new(
+                provisioned,
+                Box::new(
+                    FileStateRepository::new(
+                        opts.store_directory.clone(),
+                        "trusted_keys.json".to_owned(),
+                    )
+                    .with_disk_guard(downloader.disk_guard()),
+                ),
             )),
+            None => None,
+        };
+
+        Ok(OTAHandler {
+            ota,
+            state_repository: Box::new(
+                FileStateRepository::new(opts.store_directory.clone(), "state.json".to_owned())
+                    .with_disk_guard(downloader.disk_guard()),
+            ),
             download_file_path: opts.download_directory.clone(),
+            keep_stale_downloads: opts.ota_keep_stale_downloads.unwrap_or(false),
+            clock,
+            power: Box::new(PlatformPower),
+            metered,
+            trusted_keys,
+            health_probe_period: opts
+                .ota_health_probe_period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_HEALTH_PROBE_PERIOD),
+            deploy_ready: None,
+            enforcement: opts.ota_enforcement_mode.unwrap_or_default(),
+            download_repository: Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "ota_download.json".to_owned(),
+                )
+                .with_disk_guard(downloader.disk_guard()),
+            ),
+            unsent_response: Box::new(
+                FileStateRepository::new(
+                    opts.store_directory.clone(),
+                    "ota_unsent_response.json".to_owned(),
+                )
+                .with_disk_guard(downloader.disk_guard()),
+            ),
+            downloader,
+            bandwidth_probe: opts.ota_bandwidth_probe.as_ref().map(BandwidthProbe::new),
+            shutdown,
+            cancel: watch::channel(None).1,
+            status: watch::channel(None).0,
+            runtime_state: None,
+            metrics: None,
+            event_log: None,
+            quiet_hours: None,
+            progress: Some(ProgressThrottle::default()),
+            lifecycle: Some(lifecycle),
+            base_image: Some(Arc::new(base_image::get_base_image)),
         })
     }
 
+    /// The backend used without `ota_backend`: the image deployer when configured, else RAUC if
+    /// the system bus is available.
+    async fn default_backend(
+        opts: &crate::DeviceManagerOptions,
+        downloader: &Downloader,
+    ) -> Result<Box<dyn OTA + 'a>, DeviceManagerError> {
+        if let Some(image_deploy) = &opts.ota_image_deploy {
+            return Ok(Box::new(ImageDeployer::new(
+                image_deploy.clone(),
+                Box::new(ShellRunner),
+                Box::new(
+                    FileStateRepository::new(
+                        opts.store_directory.clone(),
+                        STAGED_IMAGE_FILE.to_owned(),
+                    )
+                    .with_disk_guard(downloader.disk_guard()),
+                ),
+                os_info::os_version,
+            )));
+        }
+
+        #[cfg(feature = "dbus")]
+        if platform().has_system_bus() {
+            return Ok(Box::new(OTARauc::new().await?));
+        }
+
+        warn!("No D-Bus system bus, the OTA updates are not available");
+        Ok(Box::new(UnavailableOTA))
+    }
+
+    /// Number the responses in `event_log`, when enabled.
+    pub fn with_event_log(mut self, event_log: Option<Arc<EventLog>>) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    /// Defer the deploys and the reboots to after `quiet_hours`, when configured.
+    pub fn with_quiet_hours(mut self, quiet_hours: Option<Arc<QuietHours>>) -> Self {
+        self.quiet_hours = quiet_hours;
+        self
+    }
+
+    /// Report the phases of the updates and their failures to `runtime_state`.
+    pub fn with_runtime_state(mut self, runtime_state: Option<Arc<SharedState>>) -> Self {
+        self.runtime_state = runtime_state;
+        self
+    }
+
+    /// Count the updates attempted and their outcomes in `metrics`, by enforcement mode.
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        if let Some(metrics) = &metrics {
+            metrics.ota_enforcement(&self.enforcement);
+        }
+        self.metrics = metrics;
+        self
+    }
+
+    /// Cancel the updates whose uuid is sent on `cancel`, until they are deployed.
+    pub fn with_cancel(mut self, cancel: watch::Receiver<Option<Uuid>>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Follow the last status sent for an OTA request.
+    pub fn status(&self) -> watch::Receiver<Option<OtaResponse>> {
+        self.status.subscribe()
+    }
+
     pub async fn last_error(&self) -> Result<String, DeviceManagerError> {
         self.ota.last_error().await
     }
@@ -108,92 +569,357 @@ impl<'a> OTAHandler<'a> {
         sdk: &impl Publisher,
         data: HashMap<String, AstarteType>,
     ) -> Result<(), DeviceManagerError> {
-        if !data.contains_key("url") || !data.contains_key("uuid") {
-            return Err(DeviceManagerError::UpdateError(
-                "Unable to find keys in OTA request".to_owned(),
-            ));
+        let request = OtaRequest::try_from(&data).map_err(|err| {
+            error!("Invalid OTARequest ({}): {}", redactor().object(&data), err);
+            err
+        })?;
+
+        self.handle_request(sdk, request).await
+    }
+
+    async fn handle_request(
+        &mut self,
+        sdk: &impl Publisher,
+        request: OtaRequest,
+    ) -> Result<(), DeviceManagerError> {
+        if request.retry_unreachable_host() {
+            self.downloader.retry_host(&request.url);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.ota_attempted();
         }
 
-        if let (AstarteType::String(request_url), AstarteType::String(request_uuid_str)) =
-            (&data["url"], &data["uuid"])
-        {
-            let request_uuid = Uuid::parse_str(request_uuid_str).map_err(|_| {
-                DeviceManagerError::UpdateError("Unable to parse request_uuid".to_owned())
-            })?;
+        let result = match request.bundle_type() {
+            BundleType::KeyBundle => {
+                self.handle_key_bundle_event(
+                    sdk,
+                    &request.url,
+                    request.uuid,
+                    request.bundle_signature(),
+                )
+                .await
+            }
+            BundleType::Update => {
+                self.record_in_flight(&request);
+                self.handle_ota_event(
+                    sdk,
+                    &request.url,
+                    request.uuid,
+                    request.allow_metered(),
+                    request.bundle_signature().as_ref(),
+                    request.checksum,
+                )
+                .await
+            }
+        };
 
-            match self.handle_ota_event(sdk, request_url, request_uuid).await {
-                Err(err) => {
-                    error!("Update failed!");
-                    error!("{:?}", err);
-                    error!("{:?}", self.last_error().await);
-
-                    match err {
-                        DeviceManagerError::OTAError(err) => self
-                            .send_ota_response(sdk, &request_uuid, OTAStatus::Error(err))
-                            .await
-                            .ok(),
-                        _ => self
-                            .send_ota_response(
-                                sdk,
-                                &request_uuid,
-                                OTAStatus::Error(OTAError::Failed),
-                            )
-                            .await
-                            .ok(),
-                    };
-                    Err(DeviceManagerError::UpdateError(
-                        "Unable to handle OTA event".to_owned(),
-                    ))
+        match result {
+            Ok(DownloadOutcome::Paused(resume)) => {
+                self.pause(sdk, request, resume).await;
+                Ok(())
+            }
+            Ok(DownloadOutcome::Completed) => {
+                self.clear_paused_download();
+                Ok(())
+            }
+            Err(DeviceManagerError::OTAError(OTAError::Canceled)) => {
+                info!("Update {} canceled", request.uuid);
+                self.discard_update(request.uuid);
+                self.remove_stale_downloads();
+                self.send_ota_response(sdk, &request.uuid, OTAStatus::Error(OTAError::Canceled))
+                    .await
+            }
+            Err(err) => {
+                self.clear_paused_download();
+                // nothing resumes from the partial file, it would only take up the space
+                self.remove_download(&artifact_name(request.bundle_type(), request.uuid));
+                self.remove_stale_downloads();
+
+                error!("Update failed!");
+                error!("{}", redactor().text(&format!("{:?}", err)));
+                error!("{:?}", self.last_error().await);
+
+                #[cfg(feature = "dbus")]
+                if let DeviceManagerError::ZbusError(backend_err) = &err {
+                    self.publish_deploy_readiness(sdk, Err(backend_err.to_string()))
+                        .await;
                 }
-                _ => Ok(()),
+
+                self.send_ota_failure(sdk, &request.uuid, &err).await.ok();
+                Err(DeviceManagerError::UpdateError(
+                    "Unable to handle OTA event".to_owned(),
+                ))
             }
-        } else {
-            error!("Got bad data in OTARequest ({data:?})");
-            Err(DeviceManagerError::UpdateError(
-                "Got bad data in OTARequest".to_owned(),
-            ))
         }
     }
 
+    /// Persist where the download of `request` stopped and report it as paused.
+    async fn pause(&self, sdk: &impl Publisher, request: OtaRequest, resume: ResumePoint) {
+        let uuid = request.uuid;
+        let restarts = self.update_record(uuid).map_or(0, |record| record.restarts);
+        if let Err(err) = self.download_repository.write(&PausedDownload {
+            request,
+            resume,
+            in_flight: None,
+            restarts,
+        }) {
+            error!("Unable to persist the paused download: {:?}", err);
+        }
+
+        // the response is published again when the download resumes
+        if let Err(err) = self.send_ota_response(sdk, &uuid, OTAStatus::Paused).await {
+            warn!("Unable to publish the paused OTA status: {:?}", err);
+        }
+    }
+
+    /// Record `request` in progress, so that a restart of the runtime resumes it. The resume
+    /// point, the phase and the restarts of a previous record of the request are kept.
+    fn record_in_flight(&self, request: &OtaRequest) {
+        let previous = self.update_record(request.uuid);
+        let record = PausedDownload {
+            request: request.clone(),
+            resume: previous
+                .as_ref()
+                .map(|previous| previous.resume.clone())
+                .unwrap_or_default(),
+            in_flight: Some(
+                previous
+                    .as_ref()
+                    .and_then(|previous| previous.in_flight)
+                    .unwrap_or(UpdatePhase::Downloading),
+            ),
+            restarts: previous.map_or(0, |previous| previous.restarts),
+        };
+
+        if let Err(err) = self.download_repository.write(&record) {
+            error!("Unable to persist the OTA state: {:?}", err);
+        }
+    }
+
+    /// Record the update `uuid` as deploying once its artifact is complete. The paused download
+    /// of a key bundle is cleared instead.
+    fn download_completed(&self, uuid: Uuid) {
+        match self.update_record(uuid) {
+            Some(mut record) if record.in_flight.is_some() => {
+                record.in_flight = Some(UpdatePhase::Deploying);
+                if let Err(err) = self.download_repository.write(&record) {
+                    error!("Unable to persist the OTA state: {:?}", err);
+                }
+            }
+            _ => self.clear_paused_download(),
+        }
+    }
+
+    /// The record of the request `uuid`, paused or in progress.
+    fn update_record(&self, uuid: Uuid) -> Option<PausedDownload> {
+        if !self.download_repository.exists() {
+            return None;
+        }
+
+        match self.download_repository.read() {
+            Ok(record) if record.request.uuid == uuid => Some(record),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Unable to read the paused download: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Request of the paused download or of the update in progress, if any.
+    fn paused_request(&self) -> Option<Uuid> {
+        if !self.download_repository.exists() {
+            return None;
+        }
+
+        self.download_repository
+            .read()
+            .ok()
+            .map(|record| record.request.uuid)
+    }
+
+    fn clear_paused_download(&self) {
+        if self.download_repository.exists() {
+            if let Err(err) = self.download_repository.clear() {
+                warn!("Unable to clear the paused download: {:?}", err);
+            }
+        }
+    }
+
+    /// Remove what is left of a canceled or refused update, so that neither the download resumes
+    /// nor the next start reports it as pending.
+    fn discard_update(&self, uuid: Uuid) {
+        self.clear_paused_download();
+        self.remove_download(&artifact_name(BundleType::Update, uuid));
+
+        if self.state_repository.exists() {
+            if let Err(err) = self.state_repository.clear() {
+                warn!("Unable to clear the OTA state: {:?}", err);
+            }
+        }
+    }
+
+    /// Remove the file `name` of the download directory, if any.
+    fn remove_download(&self, name: &str) {
+        let path = Path::new(&self.download_file_path).join(name);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Unable to remove {}: {:?}", path.display(), err),
+        }
+    }
+
+    /// Remove the artifacts left in the download directory by the requests other than the one
+    /// paused or in progress, unless they are kept for debugging. A deployed update is rebooted
+    /// into, its artifact is removed at the next start.
+    fn remove_stale_downloads(&self) {
+        if self.keep_stale_downloads {
+            return;
+        }
+
+        artifacts::remove_stale(Path::new(&self.download_file_path), self.paused_request());
+    }
+
+    /// Where the download of the request `uuid` into `path` restarts from. A download
+    /// interrupted by a crash goes on from the bytes written since it was recorded.
+    fn resume_point(&self, uuid: Uuid, path: &Path) -> ResumePoint {
+        match self.update_record(uuid) {
+            Some(PausedDownload {
+                resume,
+                in_flight: Some(_),
+                ..
+            }) if resume.etag.is_some() => ResumePoint {
+                offset: std::fs::metadata(path).map_or(0, |metadata| metadata.len()),
+                etag: resume.etag,
+            },
+            Some(record) => record.resume,
+            None => ResumePoint::default(),
+        }
+    }
+
+    /// Download `url` into `path`, pausing when the runtime is shutting down. The bundles already
+    /// on the device are staged from their local path instead.
+    async fn download(
+        &self,
+        request_uuid: Uuid,
+        #[cfg_attr(test, allow(unused_variables))] url: &str,
+        #[cfg_attr(test, allow(unused_variables))] path: &Path,
+        #[cfg_attr(test, allow(unused_variables))] progress: watch::Sender<DownloadProgress>,
+    ) -> Result<DownloadOutcome, DeviceManagerError> {
+        let phase = self
+            .update_record(request_uuid)
+            .and_then(|record| record.in_flight);
+        if phase == Some(UpdatePhase::Deploying) && path.exists() {
+            info!("Artifact downloaded before the restart, verifying it again");
+            return Ok(DownloadOutcome::Completed);
+        }
+
+        if let Some(source) = local::local_path(url)? {
+            info!("Staging the local bundle {}", source.display());
+            self.downloader.stage(&source, path)?;
+            self.download_completed(request_uuid);
+            return Ok(DownloadOutcome::Completed);
+        }
+
+        let resume = self.resume_point(request_uuid, path);
+        if *self.shutdown.borrow() {
+            info!("Shutting down, not starting the download");
+            return Ok(DownloadOutcome::Paused(resume));
+        }
+
+        #[cfg(not(test))]
+        let outcome = wget(
+            &self.downloader,
+            url,
+            path,
+            resume,
+            self.shutdown.clone(),
+            &progress,
+        )
+        .await?;
+        #[cfg(test)]
+        let outcome = DownloadOutcome::Completed;
+
+        if outcome == DownloadOutcome::Completed {
+            self.download_completed(request_uuid);
+        }
+
+        Ok(outcome)
+    }
+
     async fn handle_ota_event(
         &mut self,
         sdk: &impl Publisher,
         request_url: &str,
         request_uuid: Uuid,
-    ) -> Result<(), DeviceManagerError> {
+        allow_metered: bool,
+        signature: Option<&BundleSignature>,
+        checksum: Option<[u8; 32]>,
+    ) -> Result<DownloadOutcome, DeviceManagerError> {
         info!("Got update event");
 
+        // a cancel may have come while the request was queued
+        let mut cancel = self.cancel.clone();
+        if *cancel.borrow() == Some(request_uuid) {
+            return Err(OTAError::Canceled.into());
+        }
+
         self.send_ota_response(sdk, &request_uuid, OTAStatus::InProgress)
             .await?;
 
-        let path = std::path::Path::new(&self.download_file_path).join("update.bin");
-        let path = path.to_str().ok_or_else(|| {
-            DeviceManagerError::FatalError("wrong download file path".to_string())
-        })?;
-
-        #[cfg(not(test))]
-        wget(request_url, path).await?;
-
-        let bundle_info = self.ota.info(path).await?;
-        debug!("bundle info: {:?}", bundle_info);
-
-        let compatible = self.ota.compatible().await?;
+        let path = Path::new(&self.download_file_path)
+            .join(artifact_name(BundleType::Update, request_uuid));
+        let path = path
+            .to_str()
+            .ok_or_else(|| DeviceManagerError::OtaDownload {
+                path: path.display().to_string(),
+                reason: "not valid UTF-8".to_owned(),
+            })?;
 
-        if bundle_info.compatible != compatible {
-            error!(
-                "bundle '{}' is not compatible with system '{}'",
-                bundle_info.compatible, compatible
-            );
-            return Err(DeviceManagerError::UpdateError(
-                "bundle is not compatible".to_owned(),
-            ));
-        }
+        let mut progress = self.progress.map(|throttle| {
+            ProgressReporter::new(sdk, self.clock.clone(), throttle, request_uuid)
+                .with_runtime_state(self.runtime_state.clone())
+        });
+        // the update can be canceled until the bundle is handed over to the installer
+        let prepared = tokio::select! {
+            prepared = self.prepare_update(
+                sdk,
+                request_uuid,
+                request_url,
+                path,
+                allow_metered,
+                signature,
+                checksum,
+                &mut progress,
+            ) => prepared?,
+            _ = cancel_requested(&mut cancel, request_uuid) => {
+                return Err(OTAError::Canceled.into());
+            }
+        };
+        let (to_version, verdicts, link_throughput) = match prepared {
+            Prepared::Verified {
+                to_version,
+                verdicts,
+                link_throughput,
+            } => (to_version, verdicts, link_throughput),
+            Prepared::Paused(outcome) => return Ok(outcome),
+        };
 
         self.state_repository.write(&PersistentState {
-            uuid: request_uuid.clone(),
+            uuid: request_uuid,
             slot: self.ota.boot_slot().await?,
+            from_version: os_info::os_version(),
+            to_version,
+            verdicts,
+            link_throughput,
+            outcome: None,
         })?;
 
+        if let Some(progress) = &mut progress {
+            progress.phase(Phase::Deploying).await;
+        }
+        // the power off waits for the slot to be written
+        let flash_write = power_management::writing_flash();
         self.ota.install_bundle(path).await?;
 
         debug!(
@@ -204,18 +930,31 @@ impl<'a> OTAHandler<'a> {
         debug!("rauc operation = {}", self.ota.operation().await?);
 
         info!("Waiting for signal...");
-        if let Ok(signal) = self.ota.receive_completed().await {
+        let completed = self
+            .wait_completed(sdk, request_uuid, &mut cancel, progress.as_mut())
+            .await;
+        drop(flash_write);
+        if let Ok(signal) = completed {
             info!("Completed signal! {:?}", signal);
 
             match signal {
                 0 => {
                     info!("Update successful");
-                    info!("Rebooting in 5 seconds");
 
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    self.wait_quiet_hours("reboot").await;
+                    if let Some(progress) = &mut progress {
+                        progress.phase(Phase::Rebooting).await;
+                    }
+
+                    // the reboot must not resume it
+                    self.clear_paused_download();
 
-                    #[cfg(not(test))]
-                    power_management::reboot()?;
+                    power_management::reboot_after(
+                        self.clock.as_ref(),
+                        self.power.as_ref(),
+                        power_management::UPDATE_REBOOT_DELAY,
+                    )
+                    .await?;
                 }
                 _ => {
                     error!("Update failed with signal {signal}");
@@ -224,131 +963,1033 @@ impl<'a> OTAHandler<'a> {
             }
         }
 
-        Ok(())
+        Ok(DownloadOutcome::Completed)
     }
 
-    pub async fn ensure_pending_ota_response(
+    /// Download and verify the update, waiting for an unmetered connection fast enough and the
+    /// end of the quiet hours.
+    #[allow(clippy::too_many_arguments)]
+    async fn prepare_update<P: Publisher>(
         &self,
-        sdk: &impl Publisher,
-    ) -> Result<(), DeviceManagerError> {
-        if self.state_repository.exists() {
-            info!("Found pending update");
-            let state = self.state_repository.read()?;
-            let result_response = match self.do_pending_ota(&state).await {
-                Ok(()) => {
-                    info!("OTA successful");
-                    self.send_ota_response(sdk, &state.uuid, OTAStatus::Done)
-                        .await
-                }
-                Err(error) => {
-                    warn!("OTA failed, error -> {:?}", error);
-                    self.send_ota_response(sdk, &state.uuid, OTAStatus::Error(OTAError::Failed))
-                        .await
-                }
-            };
+        sdk: &P,
+        request_uuid: Uuid,
+        request_url: &str,
+        path: &str,
+        allow_metered: bool,
+        signature: Option<&BundleSignature>,
+        checksum: Option<[u8; 32]>,
+        progress: &mut Option<ProgressReporter<'_, P>>,
+    ) -> Result<Prepared, DeviceManagerError> {
+        // a bundle staged on the device needs no link
+        let link_throughput = if local::local_path(request_url)?.is_some() {
+            None
+        } else {
+            if !allow_metered {
+                self.wait_unmetered().await;
+            }
+            self.wait_link(sdk, request_uuid, request_url, allow_metered)
+                .await?
+        };
 
-            if result_response.is_err() {
-                warn!(
-                    "Unable to publish OTA response -> {:?}",
-                    result_response.err()
-                );
-                //TODO Retry the publish response, maybe it should be done on the SDK side.
+        let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
+        let downloading = self.download(request_uuid, request_url, Path::new(path), progress_tx);
+        let outcome = match progress {
+            Some(progress) => {
+                progress.phase(Phase::Downloading).await;
+                let (outcome, ()) = tokio::join!(downloading, progress.follow(progress_rx));
+                outcome?
             }
+            None => downloading.await?,
+        };
+        if let DownloadOutcome::Paused(_) = outcome {
+            return Ok(Prepared::Paused(outcome));
         }
 
-        Ok(())
-    }
-
-    async fn do_pending_ota(&self, state: &PersistentState) -> Result<(), DeviceManagerError> {
-        const GOOD_STATE: &str = "good";
-
-        let pending_ota_result = if state.slot != self.ota.boot_slot().await? {
-            let primary_slot = self.ota.get_primary().await?;
-            let (marked_slot, _) = self.ota.mark(GOOD_STATE, &primary_slot).await?;
-            if primary_slot == marked_slot {
-                Ok(())
-            } else {
-                Err(DeviceManagerError::UpdateError(
-                    "Unable to mark slot".to_owned(),
-                ))
+        let sidecar = match signature {
+            None => self.sidecar_signature(request_url).await?,
+            Some(_) => None,
+        };
+        let signature = signature.or(sidecar.as_ref());
+
+        // the checksums off only let the requests without one through, a checksum in the
+        // request is always enforced
+        let checksum_enabled = self.enforcement.checksum != EnforcementMode::Off;
+        let mut enforcement = self.enforcement;
+        match checksum {
+            None if !checksum_enabled => {
+                warn!("No checksum in the OTA request, the bundle content is not verified");
             }
-        } else {
-            Err(DeviceManagerError::UpdateError(
-                "Unable to switch slot".to_owned(),
-            ))
+            Some(_) if !checksum_enabled => enforcement.checksum = EnforcementMode::Enforce,
+            _ => {}
+        }
+        let spec = VerificationSpec {
+            sha256: checksum,
+            checksum_required: checksum_enabled,
+            signature: self.signature_check(signature)?,
+            signature_required: self.trusted_keys.is_some()
+                && self.enforcement.signature != EnforcementMode::Off,
+            compatibility: true,
+            enforcement,
         };
+        let report = verification::verify(Path::new(path), &spec, self.ota.as_ref()).await;
+        let to_version = report
+            .artifact
+            .as_ref()
+            .map(|artifact| artifact.version.clone());
+        let (verdicts, result) = report.enforce();
+        self.publish_verdicts(sdk, &verdicts).await;
+        if let Err(failure) = result {
+            self.discard_update(request_uuid);
+            return Err(failure.into());
+        }
 
-        self.state_repository.clear()?;
+        self.wait_quiet_hours("deploy").await;
 
-        pending_ota_result
+        Ok(Prepared::Verified {
+            to_version,
+            verdicts,
+            link_throughput,
+        })
     }
 
-    async fn send_ota_response(
+    /// Wait for the deploy to complete, publishing its progress and rejecting the cancels.
+    async fn wait_completed<P: Publisher>(
         &self,
-        sdk: &impl Publisher,
-        request_uuid: &Uuid,
-        status: OTAStatus,
-    ) -> Result<(), DeviceManagerError> {
-        info!("Sending ota response {:?}", status);
+        sdk: &P,
+        request_uuid: Uuid,
+        cancel: &mut watch::Receiver<Option<Uuid>>,
+        mut progress: Option<&mut ProgressReporter<'_, P>>,
+    ) -> Result<i32, DeviceManagerError> {
+        let completed = self.ota.receive_completed();
+        tokio::pin!(completed);
+        let period = progress.as_ref().map(|progress| progress.period());
+
+        loop {
+            // a cancel sent before the end of the deploy is answered before its outcome
+            tokio::select! {
+                biased;
+                _ = cancel_requested(cancel, request_uuid) => {
+                    warn!("Update {request_uuid} handed to the installer, not canceling it");
+                    if let Err(err) = self
+                        .send_ota_response(sdk, &request_uuid, OTAStatus::CancelRejected)
+                        .await
+                    {
+                        warn!("Unable to reject the OTA cancel: {:?}", err);
+                    }
+                }
+                signal = &mut completed => return signal,
+                _ = self.clock.sleep(period.unwrap_or_default()), if period.is_some() => {
+                    if let Some(progress) = &mut progress {
+                        match self.ota.progress().await {
+                            Ok(percentage) => progress.update(percentage.clamp(0, 100) as u8).await,
+                            Err(err) => debug!("Unable to read the deploy progress: {:?}", err),
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-        let (status, status_code) = status.to_status_code();
+    /// Wait for the end of the quiet hours, deferring `action`.
+    async fn wait_quiet_hours(&self, action: &str) {
+        let quiet_hours = match &self.quiet_hours {
+            Some(quiet_hours) => quiet_hours,
+            None => return,
+        };
 
-        sdk.send_object(
-            "io.edgehog.devicemanager.OTAResponse",
-            "/response",
-            OTAResponse {
-                uuid: request_uuid.clone(),
-                status,
-                status_code,
-            },
+        // the ranges can change while waiting
+        while let Some(remaining) = quiet_hours.remaining() {
+            info!(
+                "Quiet hours, deferring the {action} by {}s",
+                remaining.as_secs()
+            );
+            self.clock.sleep(remaining).await;
+        }
+    }
+
+    /// Replace the trusted keys with the key set downloaded from `request_url`.
+    async fn handle_key_bundle_event(
+        &mut self,
+        sdk: &impl Publisher,
+        request_url: &str,
+        request_uuid: Uuid,
+        signature: Option<BundleSignature>,
+    ) -> Result<DownloadOutcome, DeviceManagerError> {
+        info!("Got key bundle event");
+
+        self.send_ota_response(sdk, &request_uuid, OTAStatus::InProgress)
+            .await?;
+
+        let trusted_keys = self.trusted_keys.as_ref().ok_or_else(|| {
+            error!("No trusted keys configured, refusing key bundle");
+            OTAError::InvalidKeyBundle
+        })?;
+        let signature = signature.ok_or(OTAError::InvalidSignature)?;
+
+        let path = Path::new(&self.download_file_path)
+            .join(artifact_name(BundleType::KeyBundle, request_uuid));
+
+        // the key bundles are small, their progress is not published
+        let (progress, _) = watch::channel(DownloadProgress::default());
+        let outcome = self
+            .download(request_uuid, request_url, &path, progress)
+            .await?;
+        if let DownloadOutcome::Paused(_) = outcome {
+            return Ok(outcome);
+        }
+
+        trusted_keys.install_key_bundle(
+            &signature.key_id,
+            &path,
+            &signature.signature,
+            self.clock.now_wall().into(),
+        )?;
+
+        self.send_ota_response(sdk, &request_uuid, OTAStatus::Done)
+            .await?;
+
+        Ok(outcome)
+    }
+
+    /// The signature published next to the bundle at `request_url`, when the request has none
+    /// and the `ota_public_key_path` key is trusted. A missing sidecar fails the signature check.
+    async fn sidecar_signature(
+        &self,
+        request_url: &str,
+    ) -> Result<Option<BundleSignature>, DeviceManagerError> {
+        let trusted_keys = match &self.trusted_keys {
+            Some(trusted_keys) if self.enforcement.signature != EnforcementMode::Off => {
+                trusted_keys
+            }
+            _ => return Ok(None),
+        };
+        if !trusted_keys.current()?.contains(PUBLIC_KEY_ID) {
+            return Ok(None);
+        }
+
+        match self.downloader.signature(request_url).await {
+            Ok(signature) => Ok(Some(BundleSignature {
+                key_id: PUBLIC_KEY_ID.to_owned(),
+                signature,
+            })),
+            Err(err) => {
+                warn!("Unable to fetch the signature of the bundle: {:?}", err);
+                Ok(None)
+            }
+        }
+    }
+
+    /// The signature check of the bundle, when trusted keys are configured.
+    fn signature_check(
+        &self,
+        signature: Option<&BundleSignature>,
+    ) -> Result<Option<SignatureCheck>, DeviceManagerError> {
+        let (trusted_keys, signature) = match (&self.trusted_keys, signature) {
+            (Some(trusted_keys), Some(signature))
+                if self.enforcement.signature != EnforcementMode::Off =>
+            {
+                (trusted_keys, signature)
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(SignatureCheck {
+            keys: trusted_keys.current()?,
+            key_id: signature.key_id.clone(),
+            signature: signature.signature.clone(),
+            now: self.clock.now_wall().into(),
+        }))
+    }
+
+    /// Publish the enforcement mode and the verdict of each content check that ran.
+    async fn publish_verdicts(&self, sdk: &impl Publisher, verdicts: &[Verdict]) {
+        for verdict in verdicts {
+            let published = async {
+                sdk.send(
+                    DIAGNOSTICS_INTERFACE,
+                    &format!("/otaVerification/{}/mode", verdict.check),
+                    AstarteType::String(verdict.mode.to_string()),
+                )
+                .await?;
+                sdk.send(
+                    DIAGNOSTICS_INTERFACE,
+                    &format!("/otaVerification/{}/verdict", verdict.check),
+                    AstarteType::String(verdict.outcome.clone()),
+                )
+                .await
+            };
+
+            if let Err(err) = published.await {
+                warn!(
+                    "Unable to publish the OTA {} verdict: {:?}",
+                    verdict.check, err
+                );
+            }
+        }
+    }
+
+    /// Defer the download until the active connection is no longer metered or the runtime is
+    /// shutting down.
+    async fn wait_unmetered(&self) {
+        let mut metered = self.metered.clone();
+        let mut shutdown = self.shutdown.clone();
+
+        if *metered.borrow() {
+            info!("Metered connection, deferring OTA download");
+        }
+
+        while *metered.borrow() {
+            tokio::select! {
+                changed = metered.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = shutdown_requested(&mut shutdown) => break,
+            }
+        }
+    }
+
+    /// Defer the download while the probed throughput of the link is below the floor, failing
+    /// once the probe deadline is past. Returns the throughput of the last probe.
+    ///
+    /// Each probe waits for an unmetered connection like the download does, the shutdown ends
+    /// the wait so that the download is paused right away.
+    async fn wait_link(
+        &self,
+        sdk: &impl Publisher,
+        request_uuid: Uuid,
+        request_url: &str,
+        allow_metered: bool,
+    ) -> Result<Option<u64>, DeviceManagerError> {
+        let probe = match &self.bandwidth_probe {
+            Some(probe) => probe,
+            None => return Ok(None),
+        };
+        let mut shutdown = self.shutdown.clone();
+        let deadline = self.clock.now_monotonic() + probe.deadline;
+
+        let mut attempt = 0;
+        loop {
+            if *shutdown.borrow() {
+                return Ok(None);
+            }
+            if !allow_metered {
+                self.wait_unmetered().await;
+            }
+
+            let throughput = match self
+                .downloader
+                .probe(probe.url(request_url), probe.probe_bytes)
+                .await
+            {
+                Ok(throughput) => throughput,
+                Err(err) => {
+                    warn!(
+                        "Bandwidth probe failed: {}",
+                        redactor().text(&err.to_string())
+                    );
+                    0
+                }
+            };
+            if throughput >= probe.min_bytes_per_sec {
+                info!("Link measured at {throughput} B/s, downloading the update");
+                return Ok(Some(throughput));
+            }
+
+            let now = self.clock.now_monotonic();
+            if now >= deadline {
+                warn!("Link still at {throughput} B/s past the probe deadline");
+                return Err(OTAError::LinkTooSlow.into());
+            }
+            info!(
+                "Link measured at {throughput} B/s, below {} B/s, deferring OTA download",
+                probe.min_bytes_per_sec
+            );
+            if attempt == 0 {
+                if let Err(err) = self
+                    .send_ota_response(sdk, &request_uuid, OTAStatus::LinkTooSlow)
+                    .await
+                {
+                    warn!("Unable to publish the deferred OTA status: {:?}", err);
+                }
+            }
+
+            let retry_at = deadline.min(now + probe.retry_after(attempt));
+            attempt += 1;
+            tokio::select! {
+                _ = self.clock.sleep_until(retry_at) => {}
+                _ = shutdown_requested(&mut shutdown) => return Ok(None),
+            }
+        }
+    }
+
+    /// Handle the OTA requests received on `requests` in order.
+    ///
+    /// The response for an update still pending from before the reboot is published first, new
+    /// requests are held in the channel until that publish has completed or definitively failed.
+    /// A response given up is recorded and published again before each new request, and at the
+    /// next start. How long that took is reported on `pending_response_done`. A download paused
+    /// by the previous shutdown is then resumed, before the new requests.
+    pub async fn run(
+        mut self,
+        sdk: impl Publisher,
+        requests: Receiver<HashMap<String, AstarteType>>,
+        pending_response_done: oneshot::Sender<Duration>,
+    ) {
+        self.serve(sdk, requests, pending_response_done).await
+    }
+
+    /// Like [`OTAHandler::run`], keeping the handler to serve a new channel if the task dies.
+    pub async fn serve(
+        &mut self,
+        sdk: impl Publisher,
+        mut requests: Receiver<HashMap<String, AstarteType>>,
+        pending_response_done: oneshot::Sender<Duration>,
+    ) {
+        let start = self.clock.now_monotonic();
+        if let Err(err) = self.ensure_pending_ota_response(&sdk).await {
+            error!("Unable to handle pending OTA: {:?}", err);
+        }
+        pending_response_done
+            .send(self.clock.now_monotonic() - start)
+            .ok();
+        // the artifacts left behind by a crash or an update given up
+        self.remove_stale_downloads();
+
+        let resumed = self.paused_request();
+        let clock = self.clock.clone();
+        let event_log = self.event_log.clone();
+        exclusively(
+            self.resume_update(&sdk),
+            resumed,
+            &sdk,
+            clock.as_ref(),
+            event_log.as_deref(),
+            &mut requests,
         )
-        .await?;
+        .await;
+
+        // the probe shares the loop with the requests, so it never runs during an update
+        let mut next_probe = self.clock.now_monotonic();
+        loop {
+            let request = tokio::select! {
+                request = requests.recv() => request,
+                _ = self.clock.sleep_until(next_probe) => {
+                    self.probe_deploy_backend(&sdk).await;
+                    next_probe = self.clock.now_monotonic() + self.health_probe_period;
+                    continue;
+                }
+            };
+
+            match request {
+                Some(data) => {
+                    self.send_unsent_response(&sdk).await;
+                    let active = OtaRequest::try_from(&data).ok().map(|request| request.uuid);
+                    let clock = self.clock.clone();
+                    let event_log = self.event_log.clone();
+                    exclusively(
+                        self.ota_event(&sdk, data),
+                        active,
+                        &sdk,
+                        clock.as_ref(),
+                        event_log.as_deref(),
+                        &mut requests,
+                    )
+                    .await
+                    .ok();
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Check that the deploy backend answers, publishing its readiness when it changes.
+    async fn probe_deploy_backend(&mut self, sdk: &impl Publisher) {
+        let result = self.ota.health_check().await;
+        if let Err(err) = &result {
+            warn!("OTA deploy backend unavailable: {:?}", err);
+        }
+
+        self.publish_deploy_readiness(sdk, result.map_err(|err| err.to_string()))
+            .await;
+    }
+
+    async fn publish_deploy_readiness(&mut self, sdk: &impl Publisher, result: Result<(), String>) {
+        let ready = result.is_ok();
+        if self.deploy_ready == Some(ready) {
+            return;
+        }
+
+        let published = async {
+            sdk.send(
+                DIAGNOSTICS_INTERFACE,
+                "/otaDeployReady",
+                AstarteType::Boolean(ready),
+            )
+            .await?;
+            sdk.send(
+                DIAGNOSTICS_INTERFACE,
+                "/otaDeployError",
+                AstarteType::String(
+                    result
+                        .err()
+                        .map(|err| redactor().text(&err))
+                        .unwrap_or_default(),
+                ),
+            )
+            .await
+        };
+
+        match published.await {
+            Ok(()) => self.deploy_ready = Some(ready),
+            Err(err) => warn!("Unable to publish OTA deploy readiness: {:?}", err),
+        }
+    }
+
+    /// Resume the update recorded by the previous run: a download paused by the shutdown, or an
+    /// update interrupted by a crash and not given up by
+    /// [`ensure_pending_ota_response`](Self::ensure_pending_ota_response).
+    pub async fn resume_update(&mut self, sdk: &impl Publisher) {
+        if !self.download_repository.exists() {
+            return;
+        }
+
+        match self.download_repository.read() {
+            Ok(record) => {
+                match record.in_flight {
+                    None => info!(
+                        "Resuming the paused download at byte {}",
+                        record.resume.offset
+                    ),
+                    Some(phase) => info!(
+                        "Resuming the update {} interrupted while {:?}",
+                        record.request.uuid, phase
+                    ),
+                }
+                self.handle_request(sdk, record.request).await.ok();
+            }
+            Err(err) => {
+                error!("Unable to read the paused download: {:?}", err);
+                self.clear_paused_download();
+            }
+        }
+    }
+
+    /// Publish the response of the update deployed before the reboot. An update interrupted
+    /// before, e.g. by a crash, is left to [`resume_update`](Self::resume_update) or given up
+    /// after [`MAX_UPDATE_RESTARTS`] starts, with a failure response.
+    pub async fn ensure_pending_ota_response(
+        &self,
+        sdk: &impl Publisher,
+    ) -> Result<(), DeviceManagerError> {
+        self.send_unsent_response(sdk).await;
+
+        let interrupted = if self.download_repository.exists() {
+            self.download_repository
+                .read()
+                .ok()
+                .filter(|record| record.in_flight.is_some())
+        } else {
+            None
+        };
+        if let Some(record) = interrupted {
+            if !self.deployed().await? {
+                return self.restart_update(sdk, record).await;
+            }
+            // rebooted into the new slot before the record was cleared
+            self.clear_paused_download();
+        }
+
+        if self.state_repository.exists() {
+            info!("Found pending update");
+            let mut state = self.state_repository.read()?;
+            let outcome = match state.outcome {
+                Some(outcome) => {
+                    info!("Update {} already {:?}, publishing it", state.uuid, outcome);
+                    outcome
+                }
+                None => {
+                    let outcome = match self.do_pending_ota(&state).await {
+                        Ok(()) => {
+                            info!("OTA successful");
+                            BootOutcome::Applied
+                        }
+                        Err(DeviceManagerError::OTAError(OTAError::Rollback)) => {
+                            BootOutcome::RolledBack
+                        }
+                        Err(error) => {
+                            warn!("OTA failed, error -> {:?}", error);
+                            BootOutcome::Failed
+                        }
+                    };
+                    // the slots are not marked again if the response is not published
+                    state.outcome = Some(outcome);
+                    if let Err(err) = self.state_repository.write(&state) {
+                        warn!("Unable to record the outcome of the update: {:?}", err);
+                    }
+                    outcome
+                }
+            };
+
+            self.send_pending_ota_response(sdk, &state.uuid, outcome.status())
+                .await;
+            if outcome == BootOutcome::Applied {
+                self.send_base_image(sdk).await;
+            }
+
+            if let (BootOutcome::Applied, Some(lifecycle)) = (outcome, &self.lifecycle) {
+                let mut event =
+                    LifecycleEvent::ota_applied(state.uuid, state.from_version, state.to_version);
+                // the checks in warn mode did not stop the update, report their outcome with it
+                for verdict in state
+                    .verdicts
+                    .iter()
+                    .filter(|verdict| verdict.mode == EnforcementMode::Warn)
+                {
+                    event.message.push_str(&format!(
+                        ", {} check in warn mode: {}",
+                        verdict.check, verdict.outcome
+                    ));
+                }
+                if let Some(throughput) = state.link_throughput {
+                    event
+                        .message
+                        .push_str(&format!(", link probed at {} KiB/s", throughput / 1024));
+                }
+
+                lifecycle.report(sdk, event).await;
+            }
+
+            self.state_repository.clear()?;
+        }
 
         Ok(())
     }
-}
 
-async fn wget(url: &str, file_path: &str) -> Result<(), DeviceManagerError> {
-    info!("Downloading {:?}", url);
-    for i in 0..5 {
-        let response = reqwest::get(url).await;
+    /// Whether the device rebooted into the slot of the update pending.
+    async fn deployed(&self) -> Result<bool, DeviceManagerError> {
+        if !self.state_repository.exists() {
+            return Ok(false);
+        }
+
+        Ok(self.state_repository.read()?.slot != self.ota.boot_slot().await?)
+    }
+
+    /// Count the start finding the update of `record` interrupted, giving it up after
+    /// [`MAX_UPDATE_RESTARTS`] of them.
+    async fn restart_update(
+        &self,
+        sdk: &impl Publisher,
+        mut record: PausedDownload,
+    ) -> Result<(), DeviceManagerError> {
+        let uuid = record.request.uuid;
+        if record.restarts >= MAX_UPDATE_RESTARTS {
+            error!(
+                "Update {uuid} interrupted {} times, giving it up",
+                record.restarts + 1
+            );
+            self.discard_update(uuid);
+            self.send_pending_ota_response(sdk, &uuid, OTAStatus::Error(OTAError::Failed))
+                .await;
+
+            return Ok(());
+        }
+
+        // the deploy did not reach the reboot, it is done again
+        if self.state_repository.exists() {
+            self.state_repository.clear()?;
+        }
+        record.restarts += 1;
+        warn!(
+            "Update {uuid} interrupted while {:?}, resuming it ({}/{MAX_UPDATE_RESTARTS})",
+            record.in_flight.unwrap_or(UpdatePhase::Downloading),
+            record.restarts
+        );
+        self.download_repository.write(&record)?;
+
+        Ok(())
+    }
+
+    /// Publish the image the device rebooted into, without waiting for the next restart.
+    async fn send_base_image(&self, sdk: &impl Publisher) {
+        let base_image = match self.base_image.as_ref().map(|collect| collect()) {
+            Some(Ok(base_image)) => base_image,
+            Some(Err(err)) => {
+                warn!("Unable to read the base image: {:?}", err);
+                return;
+            }
+            None => return,
+        };
+
+        for (path, value) in base_image {
+            if let Err(err) = sdk.send(BASE_IMAGE_INTERFACE, &path, value).await {
+                warn!("Unable to send the base image {path}: {:?}", err);
+            }
+        }
+    }
 
-        match response {
-            Ok(response) => {
-                debug!("Writing {file_path}");
-                let mut os_file = std::fs::File::create(&file_path)?;
-                let mut content = std::io::Cursor::new(response.bytes().await?);
-                std::io::copy(&mut content, &mut os_file)?;
-                return Ok(());
+    /// Publish the response of an update found at startup, recording it to be published again
+    /// when all the attempts fail.
+    async fn send_pending_ota_response(
+        &self,
+        sdk: &impl Publisher,
+        request_uuid: &Uuid,
+        status: OTAStatus,
+    ) {
+        info!("Sending ota response {:?}", status);
+        let response =
+            stamped_response(self.event_log.as_deref(), sdk, *request_uuid, &status).await;
+        for attempt in 1..=PENDING_RESPONSE_ATTEMPTS {
+            match self.publish_response(sdk, response.clone()).await {
+                Ok(()) => return,
+                Err(err) => {
+                    warn!(
+                        "Unable to publish OTA response (attempt {attempt}) -> {:?}",
+                        err
+                    );
+                    if attempt < PENDING_RESPONSE_ATTEMPTS {
+                        self.clock
+                            .sleep(Duration::from_secs(u64::pow(2, attempt)))
+                            .await;
+                    }
+                }
             }
+        }
+
+        error!("Giving up publishing the response for OTA {request_uuid}, recording it");
+        if let Err(err) = self.unsent_response.write(&response) {
+            error!(
+                "Unable to record the response for OTA {request_uuid}: {:?}",
+                err
+            );
+        }
+    }
+
+    /// Publish again the response recorded by
+    /// [`send_pending_ota_response`](Self::send_pending_ota_response), keeping it when that
+    /// fails too.
+    async fn send_unsent_response(&self, sdk: &impl Publisher) {
+        if !self.unsent_response.exists() {
+            return;
+        }
+
+        let response = match self.unsent_response.read() {
+            Ok(response) => response,
             Err(err) => {
-                let wait = u64::pow(2, i);
-                error!("Error downloading update: {err:?}");
-                error!("Next attempt in {}s", wait);
-                tokio::time::sleep(std::time::Duration::from_secs(wait)).await;
+                error!("Unable to read the unsent OTA response: {:?}", err);
+                self.unsent_response.clear().ok();
+                return;
+            }
+        };
+        match self.publish_response(sdk, response.clone()).await {
+            Ok(()) => {
+                info!(
+                    "Published the response for OTA {} recorded before",
+                    response.uuid
+                );
+                if let Err(err) = self.unsent_response.clear() {
+                    warn!("Unable to clear the unsent OTA response: {:?}", err);
+                }
+            }
+            Err(err) => warn!(
+                "Unable to publish the response for OTA {} again -> {:?}",
+                response.uuid, err
+            ),
+        }
+    }
+
+    /// Confirm the slot booted after the update of `state`, marking it good, or mark the slot of
+    /// the update bad when the bootloader rolled back to the previous one.
+    async fn do_pending_ota(&self, state: &PersistentState) -> Result<(), DeviceManagerError> {
+        const GOOD_STATE: &str = "good";
+        const BAD_STATE: &str = "bad";
+        // the slot not booted, as RAUC names it
+        const OTHER_SLOT: &str = "other";
+
+        let booted = self.ota.boot_slot().await?;
+        if state.slot == booted {
+            error!(
+                "Update {} rolled back, booted again from the slot {booted}",
+                state.uuid
+            );
+            match self.ota.mark(BAD_STATE, OTHER_SLOT).await {
+                Ok((marked_slot, _)) => info!("Slot {marked_slot} of the update marked bad"),
+                Err(err) => warn!("Unable to mark the slot of the update bad: {:?}", err),
+            }
+
+            return Err(OTAError::Rollback.into());
+        }
+
+        let primary_slot = self.ota.get_primary().await?;
+        let (marked_slot, _) = self.ota.mark(GOOD_STATE, &primary_slot).await?;
+        if primary_slot != marked_slot {
+            return Err(DeviceManagerError::UpdateError(
+                "Unable to mark slot".to_owned(),
+            ));
+        }
+        info!("Booted into the slot {booted} of the update, marked good");
+
+        Ok(())
+    }
+
+    async fn send_ota_response(
+        &self,
+        sdk: &impl Publisher,
+        request_uuid: &Uuid,
+        status: OTAStatus,
+    ) -> Result<(), DeviceManagerError> {
+        info!("Sending ota response {:?}", status);
+
+        let response =
+            stamped_response(self.event_log.as_deref(), sdk, *request_uuid, &status).await;
+        self.publish_response(sdk, response).await
+    }
+
+    /// Report the request `request_uuid` failed with `error`, described in the response.
+    async fn send_ota_failure(
+        &self,
+        sdk: &impl Publisher,
+        request_uuid: &Uuid,
+        error: &DeviceManagerError,
+    ) -> Result<(), DeviceManagerError> {
+        let status = match error {
+            DeviceManagerError::OTAError(error) => OTAStatus::Error(*error),
+            _ => OTAStatus::Error(OTAError::Failed),
+        };
+        info!("Sending ota response {:?}", status);
+
+        let mut response =
+            stamped_response(self.event_log.as_deref(), sdk, *request_uuid, &status).await;
+        response.error_code = Some(OtaErrorCode::from(error));
+        response.message = Some(failure::message(error));
+        self.publish_response(sdk, response).await
+    }
+
+    async fn publish_response(
+        &self,
+        sdk: &impl Publisher,
+        response: OtaResponse,
+    ) -> Result<(), DeviceManagerError> {
+        // the outcome is counted even when it can't be published
+        if let Some(metrics) = &self.metrics {
+            match response.status.as_str() {
+                "Done" => metrics.ota_succeeded(),
+                "Error" => metrics.ota_failed(),
+                _ => {}
+            }
+        }
+        let timestamp = DateTime::<Utc>::from(self.clock.now_wall());
+        sdk.send_object_with_timestamp(
+            OTA_RESPONSE_INTERFACE,
+            "/response",
+            response.clone(),
+            timestamp,
+        )
+        .await?;
+        if let Some(runtime_state) = &self.runtime_state {
+            if response.status != "InProgress" {
+                runtime_state.set_ota(None, 0);
+            }
+            if let Some(message) = &response.message {
+                runtime_state.set_last_error(format!("OTA update {}: {message}", response.uuid));
+            }
+        }
+        self.status.send_replace(Some(response));
+
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
+async fn wget(
+    downloader: &Downloader,
+    url: &str,
+    file_path: &Path,
+    resume: ResumePoint,
+    shutdown: watch::Receiver<bool>,
+    progress: &watch::Sender<DownloadProgress>,
+) -> Result<DownloadOutcome, DeviceManagerError> {
+    if let Some(guard) = downloader.disk_guard() {
+        guard.ensure_space()?;
+    }
+
+    // the partial file may be gone, e.g. with the download directory on a tmpfs
+    let resume = match std::fs::metadata(file_path) {
+        Ok(metadata) if metadata.len() == resume.offset => resume,
+        _ => ResumePoint::default(),
+    };
+
+    info!("Downloading {}", redactor().url(url));
+    debug!("Writing {}", file_path.display());
+    downloader
+        .fetch(url, file_path, resume, shutdown, progress)
+        .await
+}
+
+/// Run `attempt` until it succeeds, waiting an exponentially growing delay with some jitter
+/// between failures. `attempt` gets the number of the attempt, counting from 1.
+///
+/// The failures for which `retryable` returns an error end the retries with it, the others are
+/// retried within the attempts and the total duration of `policy`.
+pub(crate) async fn retry_with_backoff<T, E, F, Fut>(
+    clock: &dyn Clock,
+    policy: &RetryPolicy,
+    retryable: impl Fn(&E) -> Result<(), OTAError>,
+    mut attempt: F,
+) -> Result<T, OTAError>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Debug,
+{
+    let start = clock.now_monotonic();
+    for i in 0..policy.attempts {
+        let err = match attempt(i + 1).await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let err_text = redactor().text(&format!("{err:?}"));
+        if let Err(code) = retryable(&err) {
+            error!("Error downloading update, not retrying: {}", err_text);
+            return Err(code);
+        }
+        if i + 1 == policy.attempts {
+            error!("Error downloading update, no attempts left: {}", err_text);
+            break;
+        }
+
+        let wait =
+            jittered(Duration::from_secs(2_u64.saturating_pow(i)).min(MAX_DOWNLOAD_RETRY_WAIT));
+        let elapsed = clock.now_monotonic().duration_since(start);
+        if let Some(max_duration) = policy.max_duration {
+            if elapsed + wait > max_duration {
+                error!(
+                    "Error downloading update, retrying for {}s already: {}",
+                    elapsed.as_secs(),
+                    err_text
+                );
+                break;
             }
         }
+
+        warn!(
+            "Download attempt {}/{} failed: {}, next attempt in {:.1}s",
+            i + 1,
+            policy.attempts,
+            err_text,
+            wait.as_secs_f64()
+        );
+        clock.sleep(wait).await;
     }
 
-    Err(OTAError::Network.into())
+    Err(OTAError::Network)
+}
+
+/// Random wait between half of `wait` and `wait`, so the devices failing together don't retry
+/// all at once.
+fn jittered(wait: Duration) -> Duration {
+    let mut random = [0; 4];
+    let fraction = match openssl::rand::rand_bytes(&mut random) {
+        Ok(()) => f64::from(u32::from_ne_bytes(random)) / f64::from(u32::MAX),
+        Err(_) => 1.0,
+    };
+
+    wait.mul_f64(0.5 + fraction / 2.0)
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     use astarte_sdk::types::AstarteType;
     use astarte_sdk::AstarteError;
+    use async_trait::async_trait;
+    use chrono::{DateTime, TimeZone, Utc};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use mockall::predicate::eq;
+    use openssl::pkey::PKey;
+    use openssl::sha::sha256;
+    use tokio::sync::{mpsc, oneshot, watch, Notify};
     use uuid::Uuid;
 
-    use crate::data::MockPublisher;
+    use crate::capabilities::{CapabilityReport, Feature};
+    use crate::clock::SystemClock;
+    use crate::data::{MockPublisher, Publisher};
+    use crate::dispatch::{Dispatch, Dispatcher};
     use crate::error::DeviceManagerError;
-    use crate::ota::ota_handler::{OTAError, OTAHandler, OTAResponse, OTAStatus, PersistentState};
-    use crate::ota::rauc::BundleInfo;
-    use crate::ota::MockOTA;
-    use crate::repository::MockStateRepository;
-
-    #[test]
+    use crate::event_log::{EventLog, EVENT_LOG_FILE};
+    use crate::interfaces::{BASE_IMAGE_INTERFACE, DIAGNOSTICS_INTERFACE, OTA_RESPONSE_INTERFACE};
+    use crate::lifecycle::tests as lifecycle_tests;
+    use crate::lifecycle::Lifecycle;
+    use crate::ota::bandwidth::tests as bandwidth_tests;
+    use crate::ota::bandwidth::{BandwidthProbe, BandwidthProbeOptions};
+    use crate::ota::download::{Downloader, ResumePoint, RetryPolicy};
+    use crate::ota::failure::OtaErrorCode;
+    use crate::ota::messages::{parse_sha256, BundleType, OtaRequest, OtaResponse};
+    use crate::ota::ota_handler::{
+        retry_with_backoff, stamped_response, BootOutcome, BundleSignature, OTAError, OTAHandler,
+        OTAStatus, PausedDownload, PersistentState, UpdatePhase, DEFAULT_HEALTH_PROBE_PERIOD,
+        MAX_UPDATE_RESTARTS,
+    };
+    use crate::ota::progress::tests as progress_tests;
+    use crate::ota::progress::ProgressThrottle;
+    use crate::ota::signature::tests as signature_tests;
+    use crate::ota::signature::{TrustedKeySet, TrustedKeys};
+    use crate::ota::verification::{EnforcementMode, EnforcementOptions, Verdict};
+    use crate::ota::{BundleInfo, MockOTA, OTA};
+    use crate::power_management::MockPowerActions;
+    use crate::repository::file_state_repository::FileStateRepository;
+    use crate::repository::{MockStateRepository, StateRepository};
+    use crate::test_utils::harness::{self, Outbound, ScriptedSession, Sent};
+    use crate::test_utils::{
+        recording_publisher, settle, ManualClock, MemoryStateRepository, Recording,
+    };
+
+    /// Handler deploying through `ota`, with none of the optional features.
+    fn ota_handler_with<'a>(
+        ota: impl OTA + 'a,
+        state_repository: impl StateRepository<PersistentState> + 'a,
+    ) -> OTAHandler<'a> {
+        let mut power = MockPowerActions::new();
+        power.expect_reboot().returning(|| Ok(()));
+
+        OTAHandler {
+            ota: Box::new(ota),
+            state_repository: Box::new(state_repository),
+            download_file_path: "".to_owned(),
+            keep_stale_downloads: false,
+            clock: Arc::new(SystemClock),
+            power: Box::new(power),
+            metered: watch::channel(false).1,
+            trusted_keys: None,
+            health_probe_period: DEFAULT_HEALTH_PROBE_PERIOD,
+            deploy_ready: None,
+            enforcement: EnforcementOptions::default(),
+            downloader: Arc::new(Downloader::new(None, Arc::new(SystemClock))),
+            bandwidth_probe: None,
+            download_repository: Box::new(MemoryStateRepository::new()),
+            unsent_response: Box::new(MemoryStateRepository::new()),
+            shutdown: watch::channel(false).1,
+            cancel: watch::channel(None).1,
+            lifecycle: None,
+            base_image: None,
+            event_log: None,
+            quiet_hours: None,
+            progress: None,
+            status: watch::channel(None).0,
+            runtime_state: None,
+            metrics: None,
+        }
+    }
+
+    #[test]
     fn ota_status() {
         assert_eq!(
             ("Error".to_owned(), "OTAFailed".to_owned()),
@@ -367,18 +2008,1979 @@ mod tests {
             OTAStatus::Done.to_status_code()
         );
         assert_eq!(
-            ("InProgress".to_owned(), "".to_owned()),
-            OTAStatus::InProgress.to_status_code()
+            ("InProgress".to_owned(), "".to_owned()),
+            OTAStatus::InProgress.to_status_code()
+        );
+        assert_eq!(
+            ("Paused".to_owned(), "".to_owned()),
+            OTAStatus::Paused.to_status_code()
+        );
+        assert_eq!(
+            ("LinkTooSlow".to_owned(), "".to_owned()),
+            OTAStatus::LinkTooSlow.to_status_code()
+        );
+        assert_eq!(
+            ("Error".to_owned(), "OTAErrorLinkTooSlow".to_owned()),
+            OTAStatus::Error(OTAError::LinkTooSlow).to_status_code()
+        );
+        assert_eq!(
+            (
+                "Error".to_owned(),
+                "OTAErrorHostUnreachableCached".to_owned()
+            ),
+            OTAStatus::Error(OTAError::HostUnreachableCached).to_status_code()
+        );
+        assert_eq!(
+            ("Error".to_owned(), "OTAErrorInvalidLocalFile".to_owned()),
+            OTAStatus::Error(OTAError::InvalidLocalFile).to_status_code()
+        );
+        assert_eq!(
+            ("Error".to_owned(), "OTAErrorRollback".to_owned()),
+            OTAStatus::Error(OTAError::Rollback).to_status_code()
+        );
+        assert_eq!(
+            (
+                "Error".to_owned(),
+                "OTAErrorUpdateAlreadyInProgress".to_owned()
+            ),
+            OTAStatus::Error(OTAError::UpdateAlreadyInProgress).to_status_code()
+        );
+        assert_eq!(
+            (
+                "InProgress".to_owned(),
+                "OTAErrorCancelNotPossible".to_owned()
+            ),
+            OTAStatus::CancelRejected.to_status_code()
+        );
+    }
+
+    #[tokio::test]
+    async fn response_sequence_published_on_diagnostics() {
+        let directory = tempfile::tempdir().unwrap();
+        let event_log = EventLog::new(
+            Arc::new(ManualClock::new()),
+            Box::new(MemoryStateRepository::new()),
+            directory.path().join(EVENT_LOG_FILE),
+            1024,
+        );
+        let uuid = Uuid::new_v4();
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .with(
+                eq(DIAGNOSTICS_INTERFACE),
+                eq("/eventLog/sequence"),
+                eq(AstarteType::LongInteger(1)),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let response = stamped_response(Some(&event_log), &publisher, uuid, &OTAStatus::Done).await;
+
+        assert_eq!(response, OTAStatus::Done.to_response(uuid));
+        assert_eq!(
+            event_log.records().unwrap()[0].correlation_id,
+            uuid.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_ota_event_bundle_not_compatible() {
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(|_, _: &str, _: OtaResponse, _| Ok(()));
+
+        let mut ota = MockOTA::new();
+        ota.expect_info().returning(|_: &str| {
+            Ok(BundleInfo {
+                compatible: "rauc-demo-x86".to_string(),
+                version: "1".to_string(),
+            })
+        });
+
+        ota.expect_compatible()
+            .returning(|| Ok("rauc-demo-arm".to_string()));
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_write().returning(|_| Ok(()));
+
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let result = ota_handler
+            .handle_ota_event(&publisher, "", Uuid::new_v4(), false, None, None)
+            .await;
+        assert!(result.is_err());
+
+        match result.err().unwrap() {
+            DeviceManagerError::UpdateError(val) => {
+                assert_eq!(val, "bundle is not compatible".to_owned())
+            }
+            _ => {
+                panic!("Wrong DeviceManagerError type");
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn handle_ota_event_bundle_install_bundle_fail() {
+        let mut ota = MockOTA::new();
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(|_, _: &str, _: OtaResponse, _| Ok(()));
+
+        ota.expect_info().returning(|_: &str| {
+            Ok(BundleInfo {
+                compatible: "rauc-demo-x86".to_string(),
+                version: "1".to_string(),
+            })
+        });
+
+        ota.expect_compatible()
+            .returning(|| Ok("rauc-demo-x86".to_string()));
+        ota.expect_operation().returning(|| Ok("".to_string()));
+        ota.expect_receive_completed().returning(|| Ok(-1));
+        ota.expect_install_bundle().returning(|_| Ok(()));
+        ota.expect_boot_slot().returning(|| Ok("".to_owned()));
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_write().returning(|_| Ok(()));
+
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let result = ota_handler
+            .handle_ota_event(&publisher, "", Uuid::new_v4(), false, None, None)
+            .await;
+        assert!(result.is_err());
+        assert!(matches!(
+            result.err().unwrap(),
+            DeviceManagerError::OTAError(OTAError::Deploy)
+        ));
+    }
+
+    #[tokio::test]
+    async fn ensure_pending_ota_response_rolled_back() {
+        let mut ota = MockOTA::new();
+        let uuid = Uuid::new_v4();
+        let slot = "A";
+
+        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+        ota.expect_mark()
+            .with(eq("bad".to_owned()), eq("other".to_owned()))
+            .times(1)
+            .returning(|_: &str, _: &str| {
+                Ok((
+                    "rootfs.1".to_owned(),
+                    "marked slot rootfs.1 as bad".to_owned(),
+                ))
+            });
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid,
+                slot: slot.to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+
+        state_mock
+            .expect_write()
+            .withf(|state| state.outcome == Some(BootOutcome::RolledBack))
+            .returning(|_| Ok(()));
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let ota_handler = ota_handler_with(ota, state_mock);
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .withf(move |_: &str, _: &str, response: &OtaResponse, _| {
+                let status = OTAStatus::Error(OTAError::Rollback).to_status_code();
+                response.status.eq(&status.0)
+                    && response.status_code.eq(&status.1)
+                    && response.uuid == uuid
+            })
+            .returning(|_: &str, _: &str, _: OtaResponse, _| Ok(()));
+
+        let result = ota_handler.ensure_pending_ota_response(&publisher).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ensure_pending_ota_response_ota_success() {
+        let mut ota = MockOTA::new();
+        let uuid = Uuid::new_v4();
+        let slot = "A";
+
+        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        ota.expect_get_primary()
+            .returning(|| Ok("rootfs.0".to_owned()));
+        ota.expect_mark().returning(|_: &str, _: &str| {
+            Ok((
+                "rootfs.0".to_owned(),
+                "marked slot rootfs.0 as good".to_owned(),
+            ))
+        });
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid,
+                slot: slot.to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+
+        state_mock.expect_write().returning(|_| Ok(()));
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let ota_handler = OTAHandler {
+            clock: Arc::new(ManualClock::new()),
+            ..ota_handler_with(ota, state_mock)
+        };
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .withf(move |_: &str, _: &str, response: &OtaResponse, timestamp| {
+                let status = OTAStatus::Done.to_status_code();
+                response.status.eq(&status.0)
+                    && response.status_code.eq(&status.1)
+                    && response.uuid == uuid
+                    // dated by the handler clock
+                    && *timestamp == Utc.timestamp_opt(1_650_000_000, 0).unwrap()
+            })
+            .returning(|_: &str, _: &str, _: OtaResponse, _| Ok(()));
+
+        let result = ota_handler.ensure_pending_ota_response(&publisher).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pending_ota_reports_lifecycle_event() {
+        let uuid = Uuid::new_v4();
+
+        for (boot_slot, applied) in [("B", true), ("A", false)] {
+            let mut ota = MockOTA::new();
+            ota.expect_boot_slot()
+                .returning(move || Ok(boot_slot.to_owned()));
+            ota.expect_get_primary()
+                .returning(|| Ok("rootfs.0".to_owned()));
+            ota.expect_mark()
+                .returning(|_: &str, _: &str| Ok(("rootfs.0".to_owned(), "marked".to_owned())));
+
+            let mut state_mock = MockStateRepository::<PersistentState>::new();
+            state_mock.expect_exists().returning(|| true);
+            state_mock.expect_read().returning(move || {
+                Ok(PersistentState {
+                    uuid,
+                    slot: "A".to_owned(),
+                    from_version: Some("1.0.0".to_owned()),
+                    to_version: Some("1.1.0".to_owned()),
+                    verdicts: vec![
+                        Verdict {
+                            check: "checksum".to_owned(),
+                            mode: EnforcementMode::Warn,
+                            passed: false,
+                            outcome: "checksum mismatch".to_owned(),
+                        },
+                        Verdict {
+                            check: "signature".to_owned(),
+                            mode: EnforcementMode::Enforce,
+                            passed: true,
+                            outcome: "passed".to_owned(),
+                        },
+                    ],
+                    link_throughput: Some(512 * 1024),
+                    outcome: None,
+                })
+            });
+            state_mock.expect_write().returning(|_| Ok(()));
+            state_mock.expect_clear().returning(|| Ok(()));
+
+            let ota_handler = OTAHandler {
+                lifecycle: Some(Arc::new(Lifecycle::new(Box::new(
+                    MemoryStateRepository::new(),
+                )))),
+                ..ota_handler_with(ota, state_mock)
+            };
+
+            let (mut publisher, recording) = recording_publisher();
+            publisher
+                .expect_send_object_with_timestamp()
+                .returning(|_: &str, _: &str, _: OtaResponse, _| Ok(()));
+
+            ota_handler
+                .ensure_pending_ota_response(&publisher)
+                .await
+                .unwrap();
+
+            let events = lifecycle_tests::events(&recording);
+            if applied {
+                assert_eq!(
+                    events,
+                    [[
+                        "otaApplied".to_owned(),
+                        "Update applied, from version 1.0.0 to 1.1.0, checksum check in warn \
+                         mode: checksum mismatch, link probed at 512 KiB/s"
+                            .to_owned(),
+                        "1.0.0".to_owned(),
+                        "1.1.0".to_owned(),
+                        uuid.to_string(),
+                    ]]
+                );
+            } else {
+                assert!(events.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn base_image_sent_once_applied() {
+        let uuid = Uuid::new_v4();
+
+        for (boot_slot, applied) in [("B", true), ("A", false)] {
+            let mut ota = MockOTA::new();
+            ota.expect_boot_slot()
+                .returning(move || Ok(boot_slot.to_owned()));
+            ota.expect_get_primary()
+                .returning(|| Ok("rootfs.0".to_owned()));
+            ota.expect_mark()
+                .returning(|_: &str, _: &str| Ok(("rootfs.0".to_owned(), "marked".to_owned())));
+
+            let mut state_mock = MockStateRepository::<PersistentState>::new();
+            state_mock.expect_exists().returning(|| true);
+            state_mock.expect_read().returning(move || {
+                Ok(PersistentState {
+                    uuid,
+                    slot: "A".to_owned(),
+                    from_version: None,
+                    to_version: None,
+                    verdicts: Vec::new(),
+                    link_throughput: None,
+                    outcome: None,
+                })
+            });
+            state_mock.expect_write().returning(|_| Ok(()));
+            state_mock.expect_clear().returning(|| Ok(()));
+
+            let ota_handler = OTAHandler {
+                base_image: Some(Arc::new(|| {
+                    Ok(HashMap::from([(
+                        "/version".to_owned(),
+                        AstarteType::String("1.1.0".to_owned()),
+                    )]))
+                })),
+                ..ota_handler_with(ota, state_mock)
+            };
+
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            let recorded = sent.clone();
+            let mut publisher = MockPublisher::new();
+            publisher
+                .expect_send_object_with_timestamp()
+                .returning(|_: &str, _: &str, _: OtaResponse, _| Ok(()));
+            publisher
+                .expect_send()
+                .withf(|interface, _, _| interface == BASE_IMAGE_INTERFACE)
+                .returning(move |_, path, value| {
+                    recorded.lock().unwrap().push((path.to_owned(), value));
+                    Ok(())
+                });
+
+            ota_handler
+                .ensure_pending_ota_response(&publisher)
+                .await
+                .unwrap();
+
+            let sent = sent.lock().unwrap();
+            if applied {
+                assert_eq!(
+                    *sent,
+                    [(
+                        "/version".to_owned(),
+                        AstarteType::String("1.1.0".to_owned())
+                    )]
+                );
+            } else {
+                assert!(sent.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn do_pending_ota_fail_marked_wrong_slot() {
+        let mut ota = MockOTA::new();
+        let uuid = Uuid::new_v4();
+        let slot = "A";
+
+        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
+        ota.expect_get_primary()
+            .returning(|| Ok("rootfs.0".to_owned()));
+        ota.expect_mark().returning(|_: &str, _: &str| {
+            Ok((
+                "rootfs.1".to_owned(),
+                "marked slot rootfs.1 as good".to_owned(),
+            ))
+        });
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid,
+                slot: slot.to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let ota_handler = ota_handler_with(ota, state_mock);
+
+        let state = ota_handler.state_repository.read().unwrap();
+        let result = ota_handler.do_pending_ota(&state).await;
+        assert!(result.is_err());
+        match result.err().unwrap() {
+            DeviceManagerError::UpdateError(val) => {
+                assert_eq!(val, "Unable to mark slot".to_owned())
+            }
+            _ => {
+                panic!("Wrong DeviceManagerError type");
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn do_pending_ota_rolled_back_unknown_slot() {
+        let mut ota = MockOTA::new();
+        let uuid = Uuid::new_v4();
+        let slot = "A";
+
+        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+        ota.expect_get_primary()
+            .returning(|| Ok("rootfs.2".to_owned()));
+        ota.expect_mark().returning(|_: &str, _: &str| {
+            Err(DeviceManagerError::UpdateError(
+                "No slot with class rootfs and name rootfs.2 found".to_owned(),
+            ))
+        });
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid,
+                slot: slot.to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let ota_handler = ota_handler_with(ota, state_mock);
+
+        let state = ota_handler.state_repository.read().unwrap();
+        let result = ota_handler.do_pending_ota(&state).await;
+        // rolled back, failing to mark the slot of the update doesn't change it
+        assert!(matches!(
+            result,
+            Err(DeviceManagerError::OTAError(OTAError::Rollback))
+        ));
+    }
+
+    #[tokio::test]
+    async fn do_pending_ota_success() {
+        let mut ota = MockOTA::new();
+        let uuid = Uuid::new_v4();
+        let slot = "A";
+
+        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
+        ota.expect_get_primary()
+            .returning(|| Ok("rootfs.0".to_owned()));
+        ota.expect_mark().returning(|_: &str, _: &str| {
+            Ok((
+                "rootfs.0".to_owned(),
+                "marked slot rootfs.0 as good".to_owned(),
+            ))
+        });
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid,
+                slot: slot.to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let ota_handler = ota_handler_with(ota, state_mock);
+
+        let state = ota_handler.state_repository.read().unwrap();
+        let result = ota_handler.do_pending_ota(&state).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ota_event_fail_empty_keys() {
+        let ota = MockOTA::new();
+        let state_mock = MockStateRepository::<PersistentState>::new();
+
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let result = ota_handler
+            .ota_event(&MockPublisher::new(), HashMap::new())
+            .await;
+
+        assert!(result.is_err());
+        match result.err().unwrap() {
+            DeviceManagerError::UpdateError(val) => {
+                assert_eq!(val, "Unable to find key url in OTA request".to_owned())
+            }
+            _ => {
+                panic!("Wrong DeviceManagerError type");
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn ota_event_fail_with_one_key() {
+        let ota = MockOTA::new();
+        let state_mock = MockStateRepository::<PersistentState>::new();
+
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let mut ota_req_map = HashMap::new();
+        ota_req_map.insert(
+            "url".to_owned(),
+            AstarteType::String("http://ota.bin".to_owned()),
+        );
+
+        let result = ota_handler
+            .ota_event(&MockPublisher::new(), ota_req_map)
+            .await;
+
+        assert!(result.is_err());
+        match result.err().unwrap() {
+            DeviceManagerError::UpdateError(val) => {
+                assert_eq!(val, "Unable to find key uuid in OTA request".to_owned())
+            }
+            _ => {
+                panic!("Wrong DeviceManagerError type");
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn ota_event_fail_with_wrong_astarte_type() {
+        let ota = MockOTA::new();
+        let state_mock = MockStateRepository::<PersistentState>::new();
+
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let mut ota_req_map = HashMap::new();
+        ota_req_map.insert(
+            "url".to_owned(),
+            AstarteType::String("http://ota.bin".to_owned()),
+        );
+        ota_req_map.insert("uuid".to_owned(), AstarteType::Integer(0));
+
+        let result = ota_handler
+            .ota_event(&MockPublisher::new(), ota_req_map)
+            .await;
+
+        assert!(result.is_err());
+        match result.err().unwrap() {
+            DeviceManagerError::UpdateError(val) => {
+                assert_eq!(val, "Got bad data in OTARequest field uuid".to_owned())
+            }
+            _ => {
+                panic!("Wrong DeviceManagerError type");
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn ota_event_fail_with_wrong_uuid() {
+        let ota = MockOTA::new();
+        let state_mock = MockStateRepository::<PersistentState>::new();
+
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let mut ota_req_map = HashMap::new();
+        ota_req_map.insert(
+            "url".to_owned(),
+            AstarteType::String("http://ota.bin".to_owned()),
+        );
+        ota_req_map.insert(
+            "uuid".to_owned(),
+            AstarteType::String("bad_uuid".to_owned()),
+        );
+
+        let result = ota_handler
+            .ota_event(&MockPublisher::new(), ota_req_map)
+            .await;
+
+        assert!(result.is_err());
+        match result.err().unwrap() {
+            DeviceManagerError::UpdateError(val) => {
+                assert_eq!(val, "Unable to parse request_uuid".to_owned())
+            }
+            _ => {
+                panic!("Wrong DeviceManagerError type");
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn ota_event_fail() {
+        let ota = MockOTA::new();
+        let uuid = Uuid::new_v4();
+        let mut publisher = MockPublisher::new();
+        publisher.expect_send_object_with_timestamp().returning(
+            |_: &str, _: &str, _: OtaResponse, _| Err(AstarteError::SendError("test".to_owned())),
+        );
+
+        let ota_req_map = OtaRequest::new(uuid, "http://ota.bin").into();
+
+        let state_mock = MockStateRepository::<PersistentState>::new();
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let result = ota_handler.ota_event(&publisher, ota_req_map).await;
+
+        assert!(result.is_err());
+        match result.err().unwrap() {
+            DeviceManagerError::UpdateError(val) => {
+                assert_eq!(val, "Unable to handle OTA event".to_owned())
+            }
+            _ => {
+                panic!("Wrong DeviceManagerError type");
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn ota_event_success() {
+        let mut ota = MockOTA::new();
+        ota.expect_info().returning(|_: &str| {
+            Ok(BundleInfo {
+                compatible: "rauc-demo-x86".to_string(),
+                version: "1".to_string(),
+            })
+        });
+
+        ota.expect_compatible()
+            .returning(|| Ok("rauc-demo-x86".to_string()));
+
+        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
+        ota.expect_install_bundle().returning(|_: &str| Ok(()));
+        ota.expect_receive_completed().returning(|| Ok(0));
+
+        let uuid = Uuid::new_v4();
+        let slot = "A";
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid,
+                slot: slot.to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+        state_mock.expect_write().returning(|_| Ok(()));
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let mut ota_handler = ota_handler_with(ota, state_mock);
+
+        let mut publisher = MockPublisher::new();
+
+        publisher
+            .expect_send_object_with_timestamp()
+            .withf(move |_: &str, _: &str, response: &OtaResponse, _| {
+                let status = OTAStatus::Done.to_status_code();
+                response.status.eq(&status.0)
+                    && response.status_code.eq(&status.1)
+                    && response.uuid == uuid
+            })
+            .returning(|_: &str, _: &str, _: OtaResponse, _| Ok(()));
+
+        publisher
+            .expect_send_object_with_timestamp()
+            .withf(move |_: &str, _: &str, response: &OtaResponse, _| {
+                let status = OTAStatus::InProgress.to_status_code();
+                response.status.eq(&status.0)
+                    && response.status_code.eq(&status.1)
+                    && response.uuid == uuid
+            })
+            .returning(|_: &str, _: &str, _: OtaResponse, _| Ok(()));
+
+        let ota_req_map = OtaRequest::new(uuid, "http://ota.bin").into();
+        let result = ota_handler.ota_event(&publisher, ota_req_map).await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ota_event_reports_each_phase() {
+        let mut ota = MockOTA::new();
+        ota.expect_info().returning(|_: &str| {
+            Ok(BundleInfo {
+                compatible: "rauc-demo-x86".to_string(),
+                version: "1".to_string(),
+            })
+        });
+        ota.expect_compatible()
+            .returning(|| Ok("rauc-demo-x86".to_string()));
+        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+        ota.expect_operation().returning(|| Ok("idle".to_owned()));
+        ota.expect_last_error().returning(|| Ok(String::new()));
+        ota.expect_install_bundle().returning(|_: &str| Ok(()));
+        ota.expect_progress().returning(|| Ok(50));
+        ota.expect_receive_completed().returning(|| Ok(0));
+
+        let clock = Arc::new(ManualClock::new());
+        let mut ota_handler = OTAHandler {
+            clock: clock.clone(),
+            progress: Some(ProgressThrottle::default()),
+            ..ota_handler_with(ota, MemoryStateRepository::new())
+        };
+
+        let (mut publisher, recording) = recording_publisher();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(|_, _: &str, _: OtaResponse, _| Ok(()));
+
+        let uuid = Uuid::new_v4();
+        let request: HashMap<String, AstarteType> = OtaRequest::new(uuid, "http://ota.bin").into();
+        // the reboot is 5 seconds after the deploy
+        let advancing = async {
+            loop {
+                settle().await;
+                clock.advance(Duration::from_secs(1));
+            }
+        };
+        let result = tokio::select! {
+            result = ota_handler.ota_event(&publisher, request) => result,
+            _ = advancing => unreachable!(),
+        };
+
+        assert!(result.is_ok());
+        let phases: Vec<(String, String)> = progress_tests::events(&recording)
+            .into_iter()
+            .map(|event| (event.request_uuid, event.status))
+            .collect();
+        assert_eq!(
+            phases,
+            ["Downloading", "Deploying", "Rebooting"]
+                .map(|phase| (uuid.to_string(), phase.to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_with_backoff_waits_exponentially() {
+        let clock = Arc::new(ManualClock::new());
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+
+        let task_clock = clock.clone();
+        let task_attempts = attempts.clone();
+        let handle = tokio::spawn(async move {
+            retry_with_backoff(
+                task_clock.as_ref(),
+                &RetryPolicy::default(),
+                |_| Ok(()),
+                |attempt| {
+                    task_attempts.lock().unwrap().push(attempt);
+                    async move {
+                        if attempt < 3 {
+                            Err("connection refused")
+                        } else {
+                            Ok(attempt)
+                        }
+                    }
+                },
+            )
+            .await
+        });
+        let attempts = || attempts.lock().unwrap().clone();
+
+        settle().await;
+        assert_eq!(attempts(), [1]);
+
+        // the waits are jittered between half and the whole of 1s, 2s...
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(attempts(), [1, 2]);
+
+        clock.advance(Duration::from_millis(999));
+        settle().await;
+        assert_eq!(attempts(), [1, 2]);
+
+        clock.advance(Duration::from_millis(1001));
+        settle().await;
+        assert_eq!(attempts(), [1, 2, 3]);
+
+        assert_eq!(handle.await.unwrap().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn retry_with_backoff_gives_up() {
+        let clock = Arc::new(ManualClock::new());
+
+        let task_clock = clock.clone();
+        let handle = tokio::spawn(async move {
+            retry_with_backoff(
+                task_clock.as_ref(),
+                &RetryPolicy::default(),
+                |_| Ok(()),
+                |_| async { Err::<(), _>("timeout") },
+            )
+            .await
+        });
+
+        for _ in 0..5 {
+            settle().await;
+            clock.advance(Duration::from_secs(16));
+        }
+
+        assert!(matches!(handle.await.unwrap(), Err(OTAError::Network)));
+    }
+
+    #[tokio::test]
+    async fn retry_with_backoff_within_the_max_duration() {
+        let clock = Arc::new(ManualClock::new());
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let task_clock = clock.clone();
+        let task_attempts = attempts.clone();
+        let handle = tokio::spawn(async move {
+            let policy = RetryPolicy {
+                attempts: 10,
+                max_duration: Some(Duration::from_millis(2500)),
+            };
+            retry_with_backoff(
+                task_clock.as_ref(),
+                &policy,
+                |_| Ok(()),
+                |_| {
+                    task_attempts.fetch_add(1, Ordering::SeqCst);
+                    async { Err::<(), _>("timeout") }
+                },
+            )
+            .await
+        });
+
+        // the second failure comes 2s in, the wait of at least 1s would end past the 2.5s
+        for _ in 0..2 {
+            settle().await;
+            clock.advance(Duration::from_secs(2));
+        }
+
+        assert!(matches!(handle.await.unwrap(), Err(OTAError::Network)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_with_backoff_not_retryable() {
+        let clock = Arc::new(ManualClock::new());
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let result = retry_with_backoff(
+            clock.as_ref(),
+            &RetryPolicy::default(),
+            |err: &&str| match *err {
+                "404 Not Found" => Err(OTAError::Network),
+                _ => Ok(()),
+            },
+            |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err::<(), _>("404 Not Found") }
+            },
+        )
+        .await;
+
+        assert!(matches!(result, Err(OTAError::Network)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    fn incompatible_bundle_ota(info_calls: Arc<AtomicUsize>) -> MockOTA {
+        let mut ota = MockOTA::new();
+        ota.expect_info().returning(move |_: &str| {
+            info_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(BundleInfo {
+                compatible: "rauc-demo-x86".to_string(),
+                version: "1".to_string(),
+            })
+        });
+        ota.expect_compatible()
+            .returning(|| Ok("rauc-demo-arm".to_string()));
+        ota
+    }
+
+    #[tokio::test]
+    async fn handle_ota_event_deferred_while_metered() {
+        let info_calls = Arc::new(AtomicUsize::new(0));
+        let (metered_tx, metered) = watch::channel(true);
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(|_, _: &str, _: OtaResponse, _| Ok(()));
+
+        let mut ota_handler = OTAHandler {
+            metered,
+            ..ota_handler_with(
+                incompatible_bundle_ota(info_calls.clone()),
+                MockStateRepository::<PersistentState>::new(),
+            )
+        };
+
+        let handle = tokio::spawn(async move {
+            ota_handler
+                .handle_ota_event(&publisher, "", Uuid::new_v4(), false, None, None)
+                .await
+        });
+
+        settle().await;
+        assert_eq!(info_calls.load(Ordering::SeqCst), 0);
+
+        metered_tx.send(false).unwrap();
+        assert!(handle.await.unwrap().is_err());
+        assert_eq!(info_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn handle_ota_event_allowed_on_metered() {
+        let info_calls = Arc::new(AtomicUsize::new(0));
+        let (_metered_tx, metered) = watch::channel(true);
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(|_, _: &str, _: OtaResponse, _| Ok(()));
+
+        let mut ota_handler = OTAHandler {
+            metered,
+            ..ota_handler_with(
+                incompatible_bundle_ota(info_calls.clone()),
+                MockStateRepository::<PersistentState>::new(),
+            )
+        };
+
+        let result = ota_handler
+            .handle_ota_event(&publisher, "", Uuid::new_v4(), true, None, None)
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(info_calls.load(Ordering::SeqCst), 1);
+    }
+
+    /// Handler probing the link for 32 KiB/s, again after 10s and 20s and so on until
+    /// `deadline_secs`.
+    fn probing_handler(
+        clock: Arc<ManualClock>,
+        info_calls: Arc<AtomicUsize>,
+        deadline_secs: u64,
+    ) -> OTAHandler<'static> {
+        OTAHandler {
+            clock: clock.clone(),
+            downloader: Arc::new(Downloader::new(None, clock)),
+            bandwidth_probe: Some(BandwidthProbe::new(&BandwidthProbeOptions {
+                min_bytes_per_sec: 32 * 1024,
+                probe_bytes: Some(64 * 1024),
+                probe_url: None,
+                retry_secs: Some(10),
+                deadline_secs: Some(deadline_secs),
+            })),
+            ..ota_handler_with(
+                incompatible_bundle_ota(info_calls),
+                MockStateRepository::<PersistentState>::new(),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_link_defers_the_download() {
+        let clock = Arc::new(ManualClock::new());
+        let info_calls = Arc::new(AtomicUsize::new(0));
+        // the first two probes get 8 KiB/s
+        let (address, probes) =
+            bandwidth_tests::throttled_server(clock.clone(), 1024 * 1024, 8 * 1024, 2);
+        let mut ota_handler = probing_handler(clock.clone(), info_calls.clone(), 3600);
+        let (publisher, sent) = recording_publisher();
+
+        let driver = bandwidth_tests::drive(clock);
+        let result = ota_handler
+            .handle_ota_event(
+                &publisher,
+                &format!("http://{address}/update.bin"),
+                Uuid::new_v4(),
+                false,
+                None,
+                None,
+            )
+            .await;
+        driver.abort();
+
+        // downloaded once the link recovered, the bundle is then found incompatible
+        assert!(result.is_err());
+        assert_eq!(info_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(probes.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            statuses(&sent),
+            vec![
+                ("InProgress".to_owned(), "".to_owned()),
+                ("LinkTooSlow".to_owned(), "".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_link_past_the_deadline_fails() {
+        let clock = Arc::new(ManualClock::new());
+        let info_calls = Arc::new(AtomicUsize::new(0));
+        let (address, probes) =
+            bandwidth_tests::throttled_server(clock.clone(), 1024 * 1024, 8 * 1024, usize::MAX);
+        let mut ota_handler = probing_handler(clock.clone(), info_calls.clone(), 30);
+        let (publisher, sent) = recording_publisher();
+
+        let driver = bandwidth_tests::drive(clock);
+        let result = ota_handler
+            .handle_ota_event(
+                &publisher,
+                &format!("http://{address}/update.bin"),
+                Uuid::new_v4(),
+                false,
+                None,
+                None,
+            )
+            .await;
+        driver.abort();
+
+        assert!(matches!(
+            result,
+            Err(DeviceManagerError::OTAError(OTAError::LinkTooSlow))
+        ));
+        assert_eq!(info_calls.load(Ordering::SeqCst), 0);
+        // the last probe is at the deadline
+        assert!(probes.load(Ordering::SeqCst) >= 2);
+        assert_eq!(
+            statuses(&sent),
+            vec![
+                ("InProgress".to_owned(), "".to_owned()),
+                ("LinkTooSlow".to_owned(), "".to_owned()),
+            ]
+        );
+    }
+
+    /// Publisher holding back the completion of the first send until released.
+    struct GatedPublisher {
+        gate: Arc<Notify>,
+        sent: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    #[async_trait]
+    impl Publisher for GatedPublisher {
+        async fn send_object<T>(
+            &self,
+            _interface_name: &str,
+            _interface_path: &str,
+            data: T,
+        ) -> Result<(), AstarteError>
+        where
+            T: serde::Serialize + Send + 'static,
+        {
+            let response = serde_json::to_value(data).unwrap();
+            let first = {
+                let mut sent = self.sent.lock().unwrap();
+                sent.push((
+                    response["uuid"].as_str().unwrap().to_owned(),
+                    response["status"].as_str().unwrap().to_owned(),
+                ));
+                sent.len() == 1
+            };
+
+            if first {
+                self.gate.notified().await;
+            }
+
+            Ok(())
+        }
+
+        async fn send(
+            &self,
+            _interface_name: &str,
+            _interface_path: &str,
+            _data: AstarteType,
+        ) -> Result<(), AstarteError> {
+            Err(AstarteError::SendError("unexpected send".to_owned()))
+        }
+
+        async fn unset(
+            &self,
+            _interface_name: &str,
+            _interface_path: &str,
+        ) -> Result<(), AstarteError> {
+            Err(AstarteError::SendError("unexpected unset".to_owned()))
+        }
+
+        async fn send_object_with_timestamp<T>(
+            &self,
+            interface_name: &str,
+            interface_path: &str,
+            data: T,
+            _timestamp: DateTime<Utc>,
+        ) -> Result<(), AstarteError>
+        where
+            T: serde::Serialize + Send + 'static,
+        {
+            self.send_object(interface_name, interface_path, data).await
+        }
+
+        async fn send_with_timestamp(
+            &self,
+            interface_name: &str,
+            interface_path: &str,
+            data: AstarteType,
+            _timestamp: DateTime<Utc>,
+        ) -> Result<(), AstarteError> {
+            self.send(interface_name, interface_path, data).await
+        }
+    }
+
+    #[tokio::test]
+    async fn run_publishes_pending_response_before_new_requests() {
+        let pending_uuid = Uuid::new_v4();
+        let new_uuid = Uuid::new_v4();
+
+        let mut ota = incompatible_bundle_ota(Arc::new(AtomicUsize::new(0)));
+        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
+        ota.expect_health_check().returning(|| Ok(()));
+        ota.expect_get_primary()
+            .returning(|| Ok("rootfs.0".to_owned()));
+        ota.expect_mark().returning(|_: &str, _: &str| {
+            Ok((
+                "rootfs.0".to_owned(),
+                "marked slot rootfs.0 as good".to_owned(),
+            ))
+        });
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid: pending_uuid,
+                slot: "A".to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+        state_mock.expect_write().returning(|_| Ok(()));
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let ota_handler = ota_handler_with(ota, state_mock);
+
+        let gate = Arc::new(Notify::new());
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let publisher = GatedPublisher {
+            gate: gate.clone(),
+            sent: sent.clone(),
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        let (done_tx, mut done_rx) = tokio::sync::oneshot::channel();
+        let worker = tokio::spawn(ota_handler.run(publisher, rx, done_tx));
+
+        tx.send(OtaRequest::new(new_uuid, "http://ota.bin").into())
+            .await
+            .unwrap();
+
+        settle().await;
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![(pending_uuid.to_string(), "Done".to_owned())]
+        );
+        assert!(done_rx.try_recv().is_err());
+
+        gate.notify_one();
+        drop(tx);
+        worker.await.unwrap();
+        assert!(done_rx.try_recv().is_ok());
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                (pending_uuid.to_string(), "Done".to_owned()),
+                (new_uuid.to_string(), "InProgress".to_owned()),
+                (new_uuid.to_string(), "Error".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unsent_pending_response_published_before_the_next_request() {
+        let pending_uuid = Uuid::new_v4();
+        let new_uuid = Uuid::new_v4();
+
+        let mut ota = incompatible_bundle_ota(Arc::new(AtomicUsize::new(0)));
+        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
+        ota.expect_health_check().returning(|| Ok(()));
+        ota.expect_get_primary()
+            .returning(|| Ok("rootfs.0".to_owned()));
+        ota.expect_mark().returning(|_: &str, _: &str| {
+            Ok((
+                "rootfs.0".to_owned(),
+                "marked slot rootfs.0 as good".to_owned(),
+            ))
+        });
+
+        let state = Arc::new(MemoryStateRepository::new());
+        state
+            .write(&PersistentState {
+                uuid: pending_uuid,
+                slot: "A".to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+            .unwrap();
+        let unsent = Arc::new(MemoryStateRepository::new());
+        let clock = Arc::new(ManualClock::new());
+        let ota_handler = OTAHandler {
+            clock: clock.clone(),
+            unsent_response: Box::new(unsent.clone()),
+            ..ota_handler_with(ota, state.clone())
+        };
+
+        // every attempt at startup fails
+        let session = (0..5)
+            .fold(ScriptedSession::builder(clock.clone()), |builder, send| {
+                builder.fail_send(send, AstarteError::SendError("test".to_owned()))
+            })
+            .build();
+        let (tx, rx) = mpsc::channel(8);
+        let (done_tx, done_rx) = oneshot::channel();
+        let worker = tokio::spawn(ota_handler.run(session.clone(), rx, done_tx));
+        for _ in 0..4 {
+            settle().await;
+            clock.advance(Duration::from_secs(16));
+        }
+        done_rx.await.unwrap();
+
+        assert!(!state.exists());
+        assert_eq!(
+            unsent
+                .value()
+                .map(|response| (response.uuid, response.status)),
+            Some((pending_uuid, "Done".to_owned()))
+        );
+
+        tx.send(OtaRequest::new(new_uuid, "http://ota.bin").into())
+            .await
+            .unwrap();
+        drop(tx);
+        worker.await.unwrap();
+
+        assert!(!unsent.exists());
+        let published: Vec<(Uuid, String)> = session
+            .sent_to(OTA_RESPONSE_INTERFACE)
+            .into_iter()
+            .filter(|sent| sent.delivered)
+            .filter_map(|sent| match sent.data {
+                Outbound::Object(data) => serde_json::from_value::<OtaResponse>(data).ok(),
+                _ => None,
+            })
+            .map(|response| (response.uuid, response.status))
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                (pending_uuid, "Done".to_owned()),
+                (new_uuid, "InProgress".to_owned()),
+                (new_uuid, "Error".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_response_retried_until_published() {
+        let uuid = Uuid::new_v4();
+
+        let mut ota = MockOTA::new();
+        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+        ota.expect_mark()
+            .returning(|_: &str, _: &str| Ok(("rootfs.1".to_owned(), "marked".to_owned())));
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| true);
+        state_mock.expect_read().returning(move || {
+            Ok(PersistentState {
+                uuid,
+                slot: "A".to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: None,
+            })
+        });
+        state_mock.expect_write().returning(|_| Ok(()));
+        state_mock.expect_clear().returning(|| Ok(()));
+
+        let clock = Arc::new(ManualClock::new());
+        let ota_handler = OTAHandler {
+            clock: clock.clone(),
+            ..ota_handler_with(ota, state_mock)
+        };
+
+        let session = ScriptedSession::builder(clock.clone())
+            .fail_send(0, AstarteError::SendError("test".to_owned()))
+            .fail_send(1, AstarteError::SendError("test".to_owned()))
+            .build();
+        let publisher = session.clone();
+
+        let handle =
+            tokio::spawn(async move { ota_handler.ensure_pending_ota_response(&publisher).await });
+
+        for _ in 0..3 {
+            settle().await;
+            clock.advance(Duration::from_secs(8));
+        }
+
+        assert!(handle.await.unwrap().is_ok());
+        let sent = session.sent();
+        assert_eq!(
+            sent.iter()
+                .map(|sent| (sent.at, sent.delivered))
+                .collect::<Vec<_>>(),
+            vec![
+                (Duration::ZERO, false),
+                (Duration::from_secs(8), false),
+                (Duration::from_secs(16), true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ota_request_dispatched_and_reported_in_progress() {
+        let uuid = Uuid::new_v4();
+
+        let mut ota = MockOTA::new();
+        ota.expect_health_check().returning(|| Ok(()));
+        ota.expect_info().returning(|_: &str| {
+            Ok(BundleInfo {
+                compatible: "rauc-demo-x86".to_string(),
+                version: "1".to_string(),
+            })
+        });
+        ota.expect_compatible()
+            .returning(|| Ok("rauc-demo-x86".to_string()));
+        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
+        ota.expect_install_bundle()
+            .times(1)
+            .returning(|_: &str| Ok(()));
+        ota.expect_receive_completed().returning(|| Ok(0));
+
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| false);
+        state_mock
+            .expect_write()
+            .times(1)
+            .withf(move |state: &PersistentState| state.uuid == uuid && state.slot == "B")
+            .returning(|_| Ok(()));
+
+        let clock = Arc::new(ManualClock::new());
+        let reboots = Arc::new(AtomicUsize::new(0));
+        let mut power = MockPowerActions::new();
+        let counted = reboots.clone();
+        power.expect_reboot().returning(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let ota_handler = OTAHandler {
+            clock: clock.clone(),
+            power: Box::new(power),
+            ..ota_handler_with(ota, state_mock)
+        };
+
+        let (ota_tx, ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let dispatcher = Dispatcher::new(
+            ota_tx,
+            config_tx,
+            None,
+            CapabilityReport::available(&[Feature::Ota]),
+        );
+        let mut session = ScriptedSession::builder(clock.clone())
+            .receive_after(
+                Duration::from_secs(1),
+                harness::ota_request_message(OtaRequest::new(uuid, "http://ota.bin")),
+            )
+            .build();
+
+        let (pending_tx, _pending_rx) = oneshot::channel();
+        let handler = tokio::spawn(ota_handler.run(session.clone(), ota_rx, pending_tx));
+        let publisher = session.clone();
+        // the dispatcher is dropped with the task, letting the handler stop
+        let dispatched = tokio::spawn(async move { session.dispatch_all(&dispatcher).await });
+
+        settle().await;
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 0);
+        // reboot delay after the update completed
+        clock.advance(Duration::from_secs(5));
+
+        assert_eq!(dispatched.await.unwrap(), vec![Ok(Dispatch::Handled)]);
+        handler.await.unwrap();
+        assert_eq!(reboots.load(Ordering::SeqCst), 1);
+
+        assert_eq!(
+            publisher.sent_to(DIAGNOSTICS_INTERFACE),
+            readiness(true, "")
+                .into_iter()
+                .map(|(path, data)| Sent {
+                    at: Duration::ZERO,
+                    interface: DIAGNOSTICS_INTERFACE.to_owned(),
+                    path,
+                    data: Outbound::Individual(data),
+                    delivered: true,
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            publisher.ota_responses(),
+            vec![OtaResponse {
+                uuid,
+                status: "InProgress".to_owned(),
+                status_code: "".to_owned(),
+                error_code: None,
+                message: None,
+            }]
+        );
+        assert_eq!(
+            publisher.sent_to(OTA_RESPONSE_INTERFACE)[0].at,
+            Duration::from_secs(1)
+        );
+    }
+
+    /// Put the update artifact of the request `uuid` in `download`, the tests don't download.
+    fn stage_update(download: &std::path::Path, uuid: Uuid, content: &[u8]) -> std::path::PathBuf {
+        let artifact = download.join(artifact_name(BundleType::Update, uuid));
+        std::fs::write(&artifact, content).unwrap();
+
+        artifact
+    }
+
+    /// `(status, statusCode)` of the OTA responses sent.
+    fn statuses(recording: &Recording<OtaResponse>) -> Vec<(String, String)> {
+        recording
+            .objects(OTA_RESPONSE_INTERFACE)
+            .into_iter()
+            .map(|response| (response.status, response.status_code))
+            .collect()
+    }
+
+    fn trusted_keys_with(
+        store: &std::path::Path,
+        keys: Vec<crate::ota::signature::TrustedKey>,
+    ) -> TrustedKeys<'static> {
+        TrustedKeys::new(
+            TrustedKeySet { version: 0, keys },
+            Box::new(FileStateRepository::new(
+                store.to_str().unwrap().to_owned(),
+                "trusted_keys.json".to_owned(),
+            )),
+        )
+    }
+
+    #[tokio::test]
+    async fn handle_ota_event_rejects_unknown_signing_key() {
+        let download = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        stage_update(download.path(), uuid, b"bundle");
+
+        let trusted = signature_tests::generate_key();
+        let untrusted = signature_tests::generate_key();
+
+        let info_calls = Arc::new(AtomicUsize::new(0));
+        let mut ota_handler = OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            trusted_keys: Some(trusted_keys_with(
+                download.path(),
+                vec![signature_tests::trusted("fleet", &trusted, None)],
+            )),
+            ..ota_handler_with(
+                incompatible_bundle_ota(info_calls.clone()),
+                MockStateRepository::<PersistentState>::new(),
+            )
+        };
+
+        let (publisher, sent) = recording_publisher();
+
+        let signature = BundleSignature {
+            key_id: "other".to_owned(),
+            signature: signature_tests::sign(&untrusted, b"bundle"),
+        };
+        let result = ota_handler
+            .handle_ota_event(&publisher, "", uuid, true, Some(&signature), None)
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(DeviceManagerError::OTAError(OTAError::UnknownSigningKey))
+        ));
+        assert_eq!(info_calls.load(Ordering::SeqCst), 0);
+
+        let missing = ota_handler
+            .handle_ota_event(&publisher, "", uuid, true, None, None)
+            .await;
+        assert!(matches!(
+            missing,
+            Err(DeviceManagerError::OTAError(OTAError::InvalidSignature))
+        ));
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_under_each_enforcement_mode() {
+        let download = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        stage_update(download.path(), uuid, b"bundle");
+        let checksum = parse_sha256(&"ab".repeat(32)).unwrap();
+
+        let verdict = |mode: &str| {
+            vec![
+                (
+                    "/otaVerification/checksum/mode".to_owned(),
+                    AstarteType::String(mode.to_owned()),
+                ),
+                (
+                    "/otaVerification/checksum/verdict".to_owned(),
+                    AstarteType::String("checksum mismatch".to_owned()),
+                ),
+            ]
+        };
+        let cases = [
+            // the checksum in the request is enforced also with the checksums off
+            (EnforcementMode::Off, false, verdict("enforce")),
+            (EnforcementMode::Warn, true, verdict("warn")),
+            (EnforcementMode::Enforce, false, verdict("enforce")),
+        ];
+
+        for (mode, proceeds, expected) in cases {
+            let installs = Arc::new(AtomicUsize::new(0));
+            let counter = installs.clone();
+            let mut ota = MockOTA::new();
+            ota.expect_info().returning(|_: &str| {
+                Ok(BundleInfo {
+                    compatible: "rauc-demo-x86".to_string(),
+                    version: "1".to_string(),
+                })
+            });
+            ota.expect_compatible()
+                .returning(|| Ok("rauc-demo-x86".to_string()));
+            ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+            ota.expect_install_bundle().returning(move |_: &str| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+            ota.expect_receive_completed()
+                .returning(|| Err(DeviceManagerError::UpdateError("no signal".to_owned())));
+
+            let state = Arc::new(MemoryStateRepository::<PersistentState>::new());
+            let mut ota_handler = OTAHandler {
+                download_file_path: download.path().to_str().unwrap().to_owned(),
+                enforcement: EnforcementOptions {
+                    checksum: mode,
+                    ..Default::default()
+                },
+                ..ota_handler_with(ota, state.clone())
+            };
+
+            let (publisher, diagnostics) = recording_publisher::<OtaResponse>();
+
+            let result = ota_handler
+                .handle_ota_event(&publisher, "", uuid, true, None, Some(checksum))
+                .await;
+
+            assert_eq!(result.is_ok(), proceeds, "{mode}");
+            if !proceeds {
+                assert!(matches!(
+                    result,
+                    Err(DeviceManagerError::OTAError(OTAError::ChecksumMismatch))
+                ));
+            }
+            assert_eq!(installs.load(Ordering::SeqCst), usize::from(proceeds));
+            assert_eq!(
+                diagnostics.values(DIAGNOSTICS_INTERFACE),
+                expected,
+                "{mode}"
+            );
+            assert_eq!(
+                state.value().map(|state| state.verdicts.len()),
+                proceeds.then(|| expected.len() / 2),
+                "{mode}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn bundle_checksum_verified_before_deploying() {
+        let enforce = EnforcementOptions {
+            checksum: EnforcementMode::Enforce,
+            ..Default::default()
+        };
+        let cases = [
+            (enforce, Some(sha256(b"bundle")), true),
+            (enforce, Some(sha256(b"other")), false),
+            // without a checksum the update goes on unless the checksums are enforced
+            (EnforcementOptions::default(), None, true),
+            (EnforcementOptions::default(), Some(sha256(b"other")), false),
+        ];
+
+        for (enforcement, checksum, proceeds) in cases {
+            let download = tempfile::tempdir().unwrap();
+            let uuid = Uuid::new_v4();
+            stage_update(download.path(), uuid, b"bundle");
+            let installs = Arc::new(AtomicUsize::new(0));
+            let mut ota_handler = OTAHandler {
+                download_file_path: download.path().to_str().unwrap().to_owned(),
+                enforcement,
+                ..ota_handler_with(
+                    installing_ota(installs.clone()),
+                    MemoryStateRepository::<PersistentState>::new(),
+                )
+            };
+
+            let (publisher, sent) = recording_publisher();
+            let mut request = OtaRequest::new(uuid, "http://ota.bin");
+            request.checksum = checksum;
+            let result = ota_handler.ota_event(&publisher, request.into()).await;
+
+            assert_eq!(result.is_ok(), proceeds, "{checksum:?}");
+            assert_eq!(installs.load(Ordering::SeqCst), usize::from(proceeds));
+            let mut expected = vec![("InProgress".to_owned(), "".to_owned())];
+            if !proceeds {
+                expected.push(("Error".to_owned(), "OTAErrorChecksumMismatch".to_owned()));
+            }
+            assert_eq!(statuses(&sent), expected, "{checksum:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn local_bundle_staged_and_verified() {
+        let staging = tempfile::tempdir().unwrap();
+        let bundle = staging.path().join("update.raucb");
+        std::fs::write(&bundle, b"bundle").unwrap();
+        let missing = staging.path().join("missing.raucb");
+
+        let cases = [
+            (format!("file://{}", bundle.display()), true),
+            (bundle.display().to_string(), true),
+            (format!("file://{}", missing.display()), false),
+            ("update.raucb".to_owned(), false),
+        ];
+
+        for (url, proceeds) in cases {
+            let download = tempfile::tempdir().unwrap();
+            let installs = Arc::new(AtomicUsize::new(0));
+            let mut ota_handler = OTAHandler {
+                download_file_path: download.path().to_str().unwrap().to_owned(),
+                ..ota_handler_with(
+                    installing_ota(installs.clone()),
+                    MemoryStateRepository::<PersistentState>::new(),
+                )
+            };
+
+            let (publisher, sent) = recording_publisher();
+            let uuid = Uuid::new_v4();
+            let mut request = OtaRequest::new(uuid, &url);
+            request.checksum = Some(sha256(b"bundle"));
+            let result = ota_handler.ota_event(&publisher, request.into()).await;
+
+            assert_eq!(result.is_ok(), proceeds, "{url}");
+            assert_eq!(installs.load(Ordering::SeqCst), usize::from(proceeds));
+            let mut expected = vec![("InProgress".to_owned(), "".to_owned())];
+            if proceeds {
+                let staged = download
+                    .path()
+                    .join(artifact_name(BundleType::Update, uuid));
+                let staged = std::fs::read(staged).unwrap();
+                assert_eq!(staged, b"bundle");
+            } else {
+                expected.push(("Error".to_owned(), "OTAErrorInvalidLocalFile".to_owned()));
+            }
+            assert_eq!(statuses(&sent), expected, "{url}");
+        }
+        // copied, the staged bundle is left in place
+        assert!(bundle.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_update_download_removed() {
+        let staging = tempfile::tempdir().unwrap();
+        let bundle = staging.path().join("update.raucb");
+        std::fs::write(&bundle, b"bundle").unwrap();
+        let download = tempfile::tempdir().unwrap();
+        let installs = Arc::new(AtomicUsize::new(0));
+        let mut ota_handler = OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            ..ota_handler_with(
+                installing_ota(installs.clone()),
+                MemoryStateRepository::<PersistentState>::new(),
+            )
+        };
+
+        let (publisher, _) = recording_publisher::<OtaResponse>();
+        let uuid = Uuid::new_v4();
+        let mut request = OtaRequest::new(uuid, &bundle.display().to_string());
+        request.checksum = Some(sha256(b"another bundle"));
+        let result = ota_handler.ota_event(&publisher, request.into()).await;
+
+        assert!(result.is_err());
+        assert_eq!(installs.load(Ordering::SeqCst), 0);
+        assert!(!download
+            .path()
+            .join(artifact_name(BundleType::Update, uuid))
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn failure_described_in_the_response() {
+        let staging = tempfile::tempdir().unwrap();
+        let bundle = staging.path().join("update.raucb");
+        std::fs::write(&bundle, b"bundle").unwrap();
+        let download = tempfile::tempdir().unwrap();
+        let mut ota_handler = OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            ..ota_handler_with(
+                installing_ota(Arc::new(AtomicUsize::new(0))),
+                MemoryStateRepository::<PersistentState>::new(),
+            )
+        };
+
+        let (publisher, _) = recording_publisher::<OtaResponse>();
+        let mut request = OtaRequest::new(Uuid::new_v4(), &bundle.display().to_string());
+        request.checksum = Some(sha256(b"another bundle"));
+        assert!(ota_handler
+            .ota_event(&publisher, request.into())
+            .await
+            .is_err());
+
+        let response = ota_handler.status.borrow().clone().unwrap();
+        assert_eq!(response.status, "Error");
+        assert_eq!(response.error_code, Some(OtaErrorCode::ChecksumMismatch));
+        assert_eq!(
+            response.message.as_deref(),
+            Some("the artifact doesn't match the expected checksum")
+        );
+
+        let response = OTAStatus::InProgress.to_response(Uuid::new_v4());
+        assert_eq!((response.error_code, response.message), (None, None));
+    }
+
+    /// Serve `signature` on every path, returning the paths requested.
+    fn sidecar_server(signature: Vec<u8>) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let paths = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = paths.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            let signature = signature.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push(request.uri().path().to_owned());
+                    let response = Response::new(Body::from(signature.clone()));
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, paths)
+    }
+
+    #[tokio::test]
+    async fn bundle_signed_with_the_public_key() {
+        let store = tempfile::tempdir().unwrap();
+        let key = PKey::generate_ed25519().unwrap();
+        let public_key = store.path().join("ota.pem");
+        std::fs::write(&public_key, key.public_key_to_pem().unwrap()).unwrap();
+        let keys = TrustedKeySet::default()
+            .with_public_key_file(&public_key)
+            .unwrap();
+        let (address, paths) = sidecar_server(signature_tests::sign(&key, b"bundle"));
+
+        let cases = [
+            (Some(signature_tests::sign(&key, b"bundle")), true),
+            (Some(signature_tests::sign(&key, b"tampered")), false),
+            // fetched from the sidecar
+            (None, true),
+        ];
+
+        for (signature, proceeds) in cases {
+            let download = tempfile::tempdir().unwrap();
+            let uuid = Uuid::new_v4();
+            let update = download
+                .path()
+                .join(artifact_name(BundleType::Update, uuid));
+            std::fs::write(&update, b"bundle").unwrap();
+            let installs = Arc::new(AtomicUsize::new(0));
+            let mut ota_handler = OTAHandler {
+                download_file_path: download.path().to_str().unwrap().to_owned(),
+                trusted_keys: Some(TrustedKeys::new(
+                    keys.clone(),
+                    Box::new(MemoryStateRepository::new()),
+                )),
+                ..ota_handler_with(
+                    installing_ota(installs.clone()),
+                    MemoryStateRepository::<PersistentState>::new(),
+                )
+            };
+
+            let (publisher, sent) = recording_publisher();
+            let mut request = OtaRequest::new(uuid, &format!("http://{address}/ota.bin"));
+            request.signature = signature;
+            let result = ota_handler.ota_event(&publisher, request.into()).await;
+
+            assert_eq!(result.is_ok(), proceeds);
+            assert_eq!(installs.load(Ordering::SeqCst), usize::from(proceeds));
+            // a refused artifact is deleted
+            assert_eq!(update.exists(), proceeds);
+            let mut expected = vec![("InProgress".to_owned(), "".to_owned())];
+            if !proceeds {
+                expected.push(("Error".to_owned(), "OTAErrorInvalidSignature".to_owned()));
+            }
+            assert_eq!(statuses(&sent), expected);
+        }
+        assert_eq!(*paths.lock().unwrap(), ["/ota.bin.sig"]);
+    }
+
+    #[tokio::test]
+    async fn ota_event_installs_key_bundle() {
+        let download = tempfile::tempdir().unwrap();
+        let old = signature_tests::generate_key();
+        let new = signature_tests::generate_key();
+
+        let key_set = TrustedKeySet {
+            version: 3,
+            keys: vec![signature_tests::trusted("new", &new, None)],
+        };
+        let bundle = serde_json::to_vec(&key_set).unwrap();
+
+        let mut ota_handler = OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            trusted_keys: Some(trusted_keys_with(
+                download.path(),
+                vec![signature_tests::trusted("old", &old, None)],
+            )),
+            ..ota_handler_with(
+                MockOTA::new(),
+                MockStateRepository::<PersistentState>::new(),
+            )
+        };
+
+        let (publisher, sent) = recording_publisher();
+
+        // the downloads are skipped by the tests, the bundle is put in place of each
+        let key_bundle_request = |key_id: &str, signature: Vec<u8>| {
+            let uuid = Uuid::new_v4();
+            std::fs::write(
+                download
+                    .path()
+                    .join(artifact_name(BundleType::KeyBundle, uuid)),
+                &bundle,
+            )
+            .unwrap();
+            HashMap::from(OtaRequest {
+                bundle_type: Some(BundleType::KeyBundle),
+                key_id: Some(key_id.to_owned()),
+                signature: Some(signature),
+                ..OtaRequest::new(uuid, "http://keys.bin")
+            })
+        };
+
+        let result = ota_handler
+            .ota_event(
+                &publisher,
+                key_bundle_request("old", signature_tests::sign(&old, &bundle)),
+            )
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(
+            ota_handler
+                .trusted_keys
+                .as_ref()
+                .unwrap()
+                .current()
+                .unwrap(),
+            key_set
+        );
+
+        // the rotated out key can no longer sign a key bundle
+        let result = ota_handler
+            .ota_event(
+                &publisher,
+                key_bundle_request("old", signature_tests::sign(&old, &bundle)),
+            )
+            .await;
+        assert!(result.is_err());
+
+        assert_eq!(
+            statuses(&sent),
+            vec![
+                ("InProgress".to_owned(), "".to_owned()),
+                ("Done".to_owned(), "".to_owned()),
+                ("InProgress".to_owned(), "".to_owned()),
+                ("Error".to_owned(), "OTAErrorUnknownSigningKey".to_owned()),
+            ]
         );
     }
 
-    #[tokio::test]
-    async fn handle_ota_event_bundle_not_compatible() {
-        let mut publisher = MockPublisher::new();
-        publisher
-            .expect_send_object()
-            .returning(|_, _: &str, _: OTAResponse| Ok(()));
-
+    fn installing_ota(installs: Arc<AtomicUsize>) -> MockOTA {
         let mut ota = MockOTA::new();
         ota.expect_info().returning(|_: &str| {
             Ok(BundleInfo {
@@ -386,530 +3988,899 @@ mod tests {
                 version: "1".to_string(),
             })
         });
-
         ota.expect_compatible()
-            .returning(|| Ok("rauc-demo-arm".to_string()));
-
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_write().returning(|_| Ok(()));
+            .returning(|| Ok("rauc-demo-x86".to_string()));
+        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+        ota.expect_operation().returning(|| Ok("idle".to_owned()));
+        ota.expect_last_error().returning(|| Ok(String::new()));
+        ota.expect_health_check().returning(|| Ok(()));
+        ota.expect_install_bundle().returning(move |_: &str| {
+            installs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        ota.expect_receive_completed()
+            .returning(|| Err(DeviceManagerError::UpdateError("no signal".to_owned())));
+        ota
+    }
 
+    #[tokio::test]
+    async fn shutdown_during_download_pauses_and_next_run_resumes() {
+        let download = tempfile::tempdir().unwrap();
+        let request = OtaRequest::new(Uuid::new_v4(), "http://ota.bin");
+        stage_update(download.path(), request.uuid, b"bundle");
+        let paused = Arc::new(MemoryStateRepository::<PausedDownload>::new());
+        let installs = Arc::new(AtomicUsize::new(0));
+
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        shutdown_tx.send(true).unwrap();
         let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            download_repository: Box::new(paused.clone()),
+            shutdown,
+            ..ota_handler_with(
+                installing_ota(installs.clone()),
+                MemoryStateRepository::new(),
+            )
         };
 
+        let (publisher, sent) = recording_publisher();
         let result = ota_handler
-            .handle_ota_event(&publisher, "", Uuid::new_v4())
+            .ota_event(&publisher, request.clone().into())
             .await;
-        assert!(result.is_err());
 
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "bundle is not compatible".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
-            }
+        assert!(result.is_ok());
+        assert_eq!(
+            statuses(&sent),
+            vec![
+                ("InProgress".to_owned(), "".to_owned()),
+                ("Paused".to_owned(), "".to_owned()),
+            ]
+        );
+        assert_eq!(
+            paused.value(),
+            Some(PausedDownload {
+                request: request.clone(),
+                resume: ResumePoint::default(),
+                in_flight: None,
+                restarts: 0,
+            })
+        );
+        assert_eq!(installs.load(Ordering::SeqCst), 0);
+
+        // the next start resumes the download before the new requests, the artifacts of the
+        // other requests are removed
+        let stale = stage_update(download.path(), Uuid::new_v4(), b"stale bundle");
+        let ota_handler = OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            download_repository: Box::new(paused.clone()),
+            ..ota_handler_with(
+                installing_ota(installs.clone()),
+                MemoryStateRepository::new(),
+            )
         };
+
+        let (publisher, sent) = recording_publisher();
+        let (requests_tx, requests) = mpsc::channel(1);
+        drop(requests_tx);
+        ota_handler
+            .run(publisher, requests, oneshot::channel().0)
+            .await;
+
+        assert_eq!(
+            statuses(&sent),
+            vec![("InProgress".to_owned(), "".to_owned())]
+        );
+        assert!(paused.value().is_none());
+        assert_eq!(installs.load(Ordering::SeqCst), 1);
+        assert!(!stale.exists());
     }
 
     #[tokio::test]
-    async fn handle_ota_event_bundle_install_bundle_fail() {
-        let mut ota = MockOTA::new();
-
-        let mut publisher = MockPublisher::new();
-        publisher
-            .expect_send_object()
-            .returning(|_, _: &str, _: OTAResponse| Ok(()));
+    async fn shutdown_during_deploy_lets_it_complete() {
+        let download = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        stage_update(download.path(), uuid, b"bundle");
+        let (shutdown_tx, shutdown) = watch::channel(false);
 
+        let mut ota = MockOTA::new();
         ota.expect_info().returning(|_: &str| {
             Ok(BundleInfo {
                 compatible: "rauc-demo-x86".to_string(),
                 version: "1".to_string(),
             })
         });
-
         ota.expect_compatible()
             .returning(|| Ok("rauc-demo-x86".to_string()));
-        ota.expect_operation().returning(|| Ok("".to_string()));
-        ota.expect_receive_completed().returning(|| Ok(-1));
-        ota.expect_install_bundle().returning(|_| Ok(()));
-        ota.expect_boot_slot().returning(|| Ok("".to_owned()));
-
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_write().returning(|_| Ok(()));
+        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+        ota.expect_operation().returning(|| Ok("idle".to_owned()));
+        ota.expect_last_error().returning(|| Ok(String::new()));
+        ota.expect_install_bundle()
+            .times(1)
+            .returning(move |_: &str| {
+                // SIGTERM while RAUC is installing
+                shutdown_tx.send(true).unwrap();
+                Ok(())
+            });
+        ota.expect_receive_completed().returning(|| Ok(0));
 
+        let state = Arc::new(MemoryStateRepository::<PersistentState>::new());
+        let paused = Arc::new(MemoryStateRepository::<PausedDownload>::new());
         let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            download_repository: Box::new(paused.clone()),
+            shutdown,
+            ..ota_handler_with(ota, state.clone())
         };
 
-        let result = ota_handler
-            .handle_ota_event(&publisher, "", Uuid::new_v4())
-            .await;
-        assert!(result.is_err());
-        assert!(matches!(
-            result.err().unwrap(),
-            DeviceManagerError::OTAError(OTAError::Deploy)
-        ));
+        let (publisher, sent) = recording_publisher();
+        let request = OtaRequest::new(uuid, "http://ota.bin");
+        let result = ota_handler.ota_event(&publisher, request.into()).await;
+
+        assert!(result.is_ok());
+        assert_eq!(
+            statuses(&sent),
+            vec![("InProgress".to_owned(), "".to_owned())]
+        );
+        assert!(paused.value().is_none());
+        assert_eq!(state.value().unwrap().slot, "A");
     }
 
-    #[tokio::test]
-    async fn ensure_pending_ota_response_ota_fail() {
-        let mut ota = MockOTA::new();
-        let uuid = Uuid::new_v4();
-        let slot = "A";
+    fn readiness(ready: bool, error: &str) -> Vec<(String, AstarteType)> {
+        vec![
+            ("/otaDeployReady".to_owned(), AstarteType::Boolean(ready)),
+            (
+                "/otaDeployError".to_owned(),
+                AstarteType::String(error.to_owned()),
+            ),
+        ]
+    }
 
-        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+    #[tokio::test]
+    async fn deploy_readiness_published_on_transitions() {
+        let available = Arc::new(AtomicBool::new(true));
 
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_exists().returning(|| true);
-        state_mock.expect_read().returning(move || {
-            Ok(PersistentState {
-                uuid: uuid.clone(),
-                slot: slot.to_owned(),
-            })
+        let mut ota = MockOTA::new();
+        let available_cloned = available.clone();
+        ota.expect_health_check().returning(move || {
+            if available_cloned.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err(DeviceManagerError::UpdateError("unreachable".to_owned()))
+            }
         });
 
-        state_mock.expect_clear().returning(|| Ok(()));
+        let mut state_mock = MockStateRepository::<PersistentState>::new();
+        state_mock.expect_exists().returning(|| false);
 
+        let clock = Arc::new(ManualClock::new());
+        let period = Duration::from_secs(60);
         let ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
+            clock: clock.clone(),
+            health_probe_period: period,
+            ..ota_handler_with(ota, state_mock)
         };
 
-        let mut publisher = MockPublisher::new();
-        publisher
-            .expect_send_object()
-            .withf(move |_: &str, _: &str, response: &OTAResponse| {
-                let status = OTAStatus::Error(OTAError::Failed).to_status_code();
-                response.status.eq(&status.0)
-                    && response.status_code.eq(&status.1)
-                    && response.uuid == uuid.clone()
-            })
-            .returning(|_: &str, _: &str, _: OTAResponse| Ok(()));
+        let (publisher, diagnostics) = recording_publisher::<OtaResponse>();
 
-        let result = ota_handler.ensure_pending_ota_response(&publisher).await;
-        assert!(result.is_ok());
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        let worker =
+            tokio::spawn(ota_handler.run(publisher, rx, tokio::sync::oneshot::channel().0));
+
+        settle().await;
+        assert_eq!(
+            diagnostics.values(DIAGNOSTICS_INTERFACE),
+            readiness(true, "")
+        );
+
+        available.store(false, Ordering::SeqCst);
+        clock.advance(period);
+        settle().await;
+        clock.advance(period);
+        settle().await;
+
+        let error = DeviceManagerError::UpdateError("unreachable".to_owned()).to_string();
+        let mut expected = readiness(true, "");
+        expected.extend(readiness(false, &error));
+        assert_eq!(diagnostics.values(DIAGNOSTICS_INTERFACE), expected);
+
+        available.store(true, Ordering::SeqCst);
+        clock.advance(period);
+        settle().await;
+
+        expected.extend(readiness(true, ""));
+        assert_eq!(diagnostics.values(DIAGNOSTICS_INTERFACE), expected);
+
+        drop(tx);
+        worker.await.unwrap();
     }
 
+    #[cfg(feature = "dbus")]
     #[tokio::test]
-    async fn ensure_pending_ota_response_ota_success() {
+    async fn unavailable_backend_during_ota_flips_deploy_readiness() {
         let mut ota = MockOTA::new();
-        let uuid = Uuid::new_v4();
-        let slot = "A";
-
-        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
-
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_exists().returning(|| true);
-        ota.expect_get_primary()
-            .returning(|| Ok("rootfs.0".to_owned()));
-        ota.expect_mark().returning(|_: &str, _: &str| {
-            Ok((
-                "rootfs.0".to_owned(),
-                "marked slot rootfs.0 as good".to_owned(),
+        ota.expect_info().returning(|_: &str| {
+            Err(DeviceManagerError::ZbusError(
+                zbus::Error::InterfaceNotFound,
             ))
         });
-        state_mock.expect_read().returning(move || {
-            Ok(PersistentState {
-                uuid: uuid.to_owned(),
-                slot: slot.to_owned(),
-            })
-        });
-
-        state_mock.expect_clear().returning(|| Ok(()));
+        ota.expect_last_error().returning(|| Ok("".to_owned()));
 
-        let ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
+        let mut ota_handler = OTAHandler {
+            deploy_ready: Some(true),
+            ..ota_handler_with(ota, MockStateRepository::<PersistentState>::new())
         };
 
-        let mut publisher = MockPublisher::new();
-        publisher
-            .expect_send_object()
-            .withf(move |_: &str, _: &str, response: &OTAResponse| {
-                let status = OTAStatus::Done.to_status_code();
-                response.status.eq(&status.0)
-                    && response.status_code.eq(&status.1)
-                    && response.uuid == uuid.to_owned()
+        let (publisher, diagnostics) = recording_publisher::<OtaResponse>();
+
+        let request = OtaRequest::new(Uuid::new_v4(), "http://ota.bin").into();
+        assert!(ota_handler.ota_event(&publisher, request).await.is_err());
+
+        let error = zbus::Error::InterfaceNotFound.to_string();
+        assert_eq!(
+            diagnostics.values(DIAGNOSTICS_INTERFACE),
+            readiness(false, &error)
+        );
+        assert_eq!(ota_handler.deploy_ready, Some(false));
+    }
+
+    #[tokio::test]
+    async fn cancel_before_install_discards_the_update() {
+        let download = tempfile::tempdir().unwrap();
+        let request = OtaRequest::new(Uuid::new_v4(), "http://ota.bin");
+        let artifact = stage_update(download.path(), request.uuid, b"partial");
+        let state = Arc::new(MemoryStateRepository::<PersistentState>::new());
+        let paused = Arc::new(MemoryStateRepository::<PausedDownload>::new());
+        let installs = Arc::new(AtomicUsize::new(0));
+        paused
+            .write(&PausedDownload {
+                request: request.clone(),
+                resume: ResumePoint::default(),
+                in_flight: None,
+                restarts: 0,
             })
-            .returning(|_: &str, _: &str, _: OTAResponse| Ok(()));
+            .unwrap();
+
+        let (cancel_tx, cancel) = watch::channel(None);
+        let mut ota_handler = OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            clock: Arc::new(SystemClock),
+            // the update waits for an unmetered connection
+            metered: watch::channel(true).1,
+            download_repository: Box::new(paused.clone()),
+            cancel,
+            ..ota_handler_with(installing_ota(installs.clone()), state.clone())
+        };
+
+        let (publisher, sent) = recording_publisher();
+        let canceling = async {
+            settle().await;
+            cancel_tx.send_replace(Some(request.uuid));
+            std::future::pending::<()>().await
+        };
+        let result = tokio::select! {
+            result = ota_handler.ota_event(&publisher, request.clone().into()) => result,
+            _ = canceling => unreachable!(),
+        };
 
-        let result = ota_handler.ensure_pending_ota_response(&publisher).await;
         assert!(result.is_ok());
+        assert_eq!(
+            statuses(&sent),
+            vec![
+                ("InProgress".to_owned(), "".to_owned()),
+                ("Error".to_owned(), "OTAErrorCanceled".to_owned()),
+            ]
+        );
+        assert!(!artifact.exists());
+        assert!(state.value().is_none());
+        assert!(paused.value().is_none());
+        assert_eq!(installs.load(Ordering::SeqCst), 0);
     }
 
     #[tokio::test]
-    async fn do_pending_ota_fail_marked_wrong_slot() {
-        let mut ota = MockOTA::new();
+    async fn cancel_after_install_rejected() {
+        let download = tempfile::tempdir().unwrap();
         let uuid = Uuid::new_v4();
-        let slot = "A";
-
-        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
-        ota.expect_get_primary()
-            .returning(|| Ok("rootfs.0".to_owned()));
-        ota.expect_mark().returning(|_: &str, _: &str| {
-            Ok((
-                "rootfs.1".to_owned(),
-                "marked slot rootfs.1 as good".to_owned(),
-            ))
-        });
+        let artifact = stage_update(download.path(), uuid, b"bundle");
+        let state = Arc::new(MemoryStateRepository::<PersistentState>::new());
 
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_exists().returning(|| true);
-        state_mock.expect_read().returning(move || {
-            Ok(PersistentState {
-                uuid: uuid.clone(),
-                slot: slot.to_owned(),
+        let (cancel_tx, cancel) = watch::channel(None);
+        let mut ota = MockOTA::new();
+        ota.expect_info().returning(|_: &str| {
+            Ok(BundleInfo {
+                compatible: "rauc-demo-x86".to_string(),
+                version: "1".to_string(),
             })
         });
+        ota.expect_compatible()
+            .returning(|| Ok("rauc-demo-x86".to_string()));
+        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
+        ota.expect_operation()
+            .returning(|| Ok("installing".to_owned()));
+        ota.expect_last_error().returning(|| Ok(String::new()));
+        // the cancel comes while the bundle is being installed
+        ota.expect_install_bundle().returning(move |_: &str| {
+            cancel_tx.send_replace(Some(uuid));
+            Ok(())
+        });
+        ota.expect_receive_completed().returning(|| Ok(0));
 
-        state_mock.expect_clear().returning(|| Ok(()));
-
-        let ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
+        let clock = Arc::new(ManualClock::new());
+        let mut ota_handler = OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            clock: clock.clone(),
+            cancel,
+            ..ota_handler_with(ota, state.clone())
         };
 
-        let state = ota_handler.state_repository.read().unwrap();
-        let result = ota_handler.do_pending_ota(&state).await;
-        assert!(result.is_err());
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "Unable to mark slot".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
+        let (publisher, sent) = recording_publisher();
+        let request: HashMap<String, AstarteType> = OtaRequest::new(uuid, "http://ota.bin").into();
+        // the reboot is 5 seconds after the deploy
+        let advancing = async {
+            loop {
+                settle().await;
+                clock.advance(Duration::from_secs(1));
             }
         };
+        let result = tokio::select! {
+            result = ota_handler.ota_event(&publisher, request) => result,
+            _ = advancing => unreachable!(),
+        };
+
+        assert!(result.is_ok());
+        assert_eq!(
+            statuses(&sent),
+            vec![
+                ("InProgress".to_owned(), "".to_owned()),
+                (
+                    "InProgress".to_owned(),
+                    "OTAErrorCancelNotPossible".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(state.value().map(|state| state.uuid), Some(uuid));
+        assert!(artifact.exists());
     }
 
-    #[tokio::test]
-    async fn do_pending_ota_fail_unknown_slot() {
-        let mut ota = MockOTA::new();
-        let uuid = Uuid::new_v4();
-        let slot = "A";
+    /// Deploy backend booting `booted`, recording the calls changing the system. The installs
+    /// never complete when `hangs` is set.
+    struct FakeDeployer {
+        booted: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+        hangs: bool,
+    }
 
-        ota.expect_boot_slot().returning(|| Ok("A".to_owned()));
-        ota.expect_get_primary()
-            .returning(|| Ok("rootfs.2".to_owned()));
-        ota.expect_mark().returning(|_: &str, _: &str| {
-            Err(DeviceManagerError::UpdateError(
-                "No slot with class rootfs and name rootfs.2 found".to_owned(),
-            ))
-        });
+    impl FakeDeployer {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
 
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_exists().returning(|| true);
-        state_mock.expect_read().returning(move || {
-            Ok(PersistentState {
-                uuid: uuid.clone(),
-                slot: slot.to_owned(),
+    #[async_trait]
+    impl OTA for FakeDeployer {
+        async fn install_bundle(&self, source: &str) -> Result<(), DeviceManagerError> {
+            self.record(format!("install {source}"));
+            Ok(())
+        }
+
+        async fn last_error(&self) -> Result<String, DeviceManagerError> {
+            Ok(String::new())
+        }
+
+        async fn info(&self, bundle: &str) -> Result<BundleInfo, DeviceManagerError> {
+            self.record(format!("info {bundle}"));
+            Ok(BundleInfo {
+                compatible: "board-x".to_owned(),
+                version: "2.0.0".to_owned(),
             })
-        });
+        }
 
-        state_mock.expect_clear().returning(|| Ok(()));
+        async fn operation(&self) -> Result<String, DeviceManagerError> {
+            Ok("idle".to_owned())
+        }
 
-        let ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
-        };
+        async fn progress(&self) -> Result<i32, DeviceManagerError> {
+            Ok(100)
+        }
 
-        let state = ota_handler.state_repository.read().unwrap();
-        let result = ota_handler.do_pending_ota(&state).await;
-        assert!(result.is_err());
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "Unable to switch slot".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
-            }
-        };
-    }
+        async fn compatible(&self) -> Result<String, DeviceManagerError> {
+            Ok("board-x".to_owned())
+        }
 
-    #[tokio::test]
-    async fn do_pending_ota_success() {
-        let mut ota = MockOTA::new();
-        let uuid = Uuid::new_v4();
-        let slot = "A";
+        async fn boot_slot(&self) -> Result<String, DeviceManagerError> {
+            Ok(self.booted.to_owned())
+        }
 
-        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
-        ota.expect_get_primary()
-            .returning(|| Ok("rootfs.0".to_owned()));
-        ota.expect_mark().returning(|_: &str, _: &str| {
-            Ok((
-                "rootfs.0".to_owned(),
-                "marked slot rootfs.0 as good".to_owned(),
-            ))
-        });
+        async fn receive_completed(&self) -> Result<i32, DeviceManagerError> {
+            if self.hangs {
+                std::future::pending::<()>().await;
+            }
 
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_exists().returning(|| true);
-        state_mock.expect_read().returning(move || {
-            Ok(PersistentState {
-                uuid: uuid.clone(),
-                slot: slot.to_owned(),
-            })
-        });
+            Ok(0)
+        }
 
-        state_mock.expect_clear().returning(|| Ok(()));
+        async fn get_primary(&self) -> Result<String, DeviceManagerError> {
+            Ok(self.booted.to_owned())
+        }
 
-        let ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
-        };
+        async fn mark(
+            &self,
+            state: &str,
+            slot_identifier: &str,
+        ) -> Result<(String, String), DeviceManagerError> {
+            self.record(format!("mark {state} {slot_identifier}"));
+            Ok((slot_identifier.to_owned(), String::new()))
+        }
 
-        let state = ota_handler.state_repository.read().unwrap();
-        let result = ota_handler.do_pending_ota(&state).await;
-        assert!(result.is_ok());
+        async fn health_check(&self) -> Result<(), DeviceManagerError> {
+            Ok(())
+        }
     }
 
     #[tokio::test]
-    async fn ota_event_fail_empty_keys() {
-        let ota = MockOTA::new();
-        let state_mock = MockStateRepository::<PersistentState>::new();
-
-        let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
+    async fn update_deployed_and_confirmed_through_the_backend() {
+        let download = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        let bundle = stage_update(download.path(), uuid, b"bundle");
+        let state = Arc::new(MemoryStateRepository::<PersistentState>::new());
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let handler = |booted| OTAHandler {
+            download_file_path: download.path().to_str().unwrap().to_owned(),
+            ..ota_handler_with(
+                FakeDeployer {
+                    booted,
+                    calls: calls.clone(),
+                    hangs: false,
+                },
+                state.clone(),
+            )
         };
 
+        let (publisher, sent) = recording_publisher();
+        let mut ota_handler = handler("a");
         let result = ota_handler
-            .ota_event(&MockPublisher::new(), HashMap::new())
+            .ota_event(&publisher, OtaRequest::new(uuid, "http://ota.bin").into())
             .await;
+        assert!(result.is_ok());
+        assert_eq!(state.value().map(|state| state.slot), Some("a".to_owned()));
 
-        assert!(result.is_err());
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "Unable to find keys in OTA request".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
-            }
-        };
+        // rebooted into the other slot
+        handler("b")
+            .ensure_pending_ota_response(&publisher)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                format!("info {}", bundle.display()),
+                format!("install {}", bundle.display()),
+                "mark good b".to_owned(),
+            ]
+        );
+        assert_eq!(
+            statuses(&sent),
+            [
+                ("InProgress".to_owned(), "".to_owned()),
+                ("Done".to_owned(), "".to_owned()),
+            ]
+        );
+        assert!(state.value().is_none());
     }
 
     #[tokio::test]
-    async fn ota_event_fail_with_one_key() {
-        let ota = MockOTA::new();
-        let state_mock = MockStateRepository::<PersistentState>::new();
-
-        let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
-        };
-
-        let mut ota_req_map = HashMap::new();
-        ota_req_map.insert(
-            "url".to_owned(),
-            AstarteType::String("http://ota.bin".to_owned()),
+    async fn second_request_rejected_while_updating() {
+        let download = tempfile::tempdir().unwrap();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        stage_update(download.path(), first, b"bundle");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        // the deploy of the first update never completes
+        let ota_handler = restarted_handler(
+            download.path(),
+            FakeDeployer {
+                booted: "a",
+                calls: calls.clone(),
+                hangs: true,
+            },
+            watch::channel(false).1,
         );
 
-        let result = ota_handler
-            .ota_event(&MockPublisher::new(), ota_req_map)
-            .await;
+        let (publisher, sent) = recording_publisher::<OtaResponse>();
 
-        assert!(result.is_err());
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "Unable to find keys in OTA request".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
-            }
-        };
+        let (tx, rx) = mpsc::channel(32);
+        let (done_tx, _done_rx) = oneshot::channel();
+        let worker = tokio::spawn(ota_handler.run(publisher, rx, done_tx));
+
+        for uuid in [first, second, first] {
+            tx.send(OtaRequest::new(uuid, "http://ota.bin").into())
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let installs = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.starts_with("install"))
+            .count();
+        assert_eq!(installs, 1);
+        // the first one sent again is dropped
+        assert_eq!(
+            sent.objects(OTA_RESPONSE_INTERFACE)
+                .into_iter()
+                .map(|response| (response.uuid, response.status, response.status_code))
+                .collect::<Vec<_>>(),
+            [
+                (first, "InProgress".to_owned(), "".to_owned()),
+                (
+                    second,
+                    "Error".to_owned(),
+                    "OTAErrorUpdateAlreadyInProgress".to_owned()
+                ),
+            ]
+        );
+        worker.abort();
     }
 
     #[tokio::test]
-    async fn ota_event_fail_with_wrong_astarte_type() {
-        let ota = MockOTA::new();
-        let state_mock = MockStateRepository::<PersistentState>::new();
-
-        let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
-        };
+    async fn update_rolled_back_by_the_bootloader() {
+        let download = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        stage_update(download.path(), uuid, b"bundle");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (publisher, sent) = recording_publisher();
+
+        let mut ota_handler = restarted_handler(
+            download.path(),
+            FakeDeployer {
+                booted: "a",
+                calls: calls.clone(),
+                hangs: false,
+            },
+            watch::channel(false).1,
+        );
+        ota_handler
+            .ota_event(&publisher, OtaRequest::new(uuid, "http://ota.bin").into())
+            .await
+            .unwrap();
+        calls.lock().unwrap().clear();
+
+        // the new slot failed to boot, the bootloader fell back to the previous one
+        ota_handler
+            .ensure_pending_ota_response(&publisher)
+            .await
+            .unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), ["mark bad other".to_owned()]);
+        assert_eq!(
+            statuses(&sent).last().unwrap(),
+            &("Error".to_owned(), "OTAErrorRollback".to_owned())
+        );
+        assert!(!download.path().join("state.json").exists());
+    }
 
-        let mut ota_req_map = HashMap::new();
-        ota_req_map.insert(
-            "url".to_owned(),
-            AstarteType::String("http://ota.bin".to_owned()),
+    #[tokio::test]
+    async fn boot_outcome_recorded_not_decided_again() {
+        let store = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        let repository: Box<dyn StateRepository<PersistentState>> =
+            Box::new(FileStateRepository::new(
+                store.path().to_str().unwrap().to_owned(),
+                "state.json".to_owned(),
+            ));
+        // the slots were marked, the runtime stopped before publishing the response
+        repository
+            .write(&PersistentState {
+                uuid,
+                slot: "a".to_owned(),
+                from_version: None,
+                to_version: None,
+                verdicts: Vec::new(),
+                link_throughput: None,
+                outcome: Some(BootOutcome::Applied),
+            })
+            .unwrap();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let ota_handler = restarted_handler(
+            store.path(),
+            FakeDeployer {
+                booted: "a",
+                calls: calls.clone(),
+                hangs: false,
+            },
+            watch::channel(false).1,
         );
-        ota_req_map.insert("uuid".to_owned(), AstarteType::Integer(0));
+        let (publisher, sent) = recording_publisher();
+        ota_handler
+            .ensure_pending_ota_response(&publisher)
+            .await
+            .unwrap();
+
+        assert!(calls.lock().unwrap().is_empty());
+        assert_eq!(statuses(&sent), [("Done".to_owned(), "".to_owned())]);
+        assert!(!repository.exists());
+    }
 
-        let result = ota_handler
-            .ota_event(&MockPublisher::new(), ota_req_map)
-            .await;
+    /// Copy the state file `fixture`, as written by another release, into `store`.
+    fn historical_state(store: &std::path::Path, fixture: &str) -> std::path::PathBuf {
+        let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/state")
+            .join(fixture);
+        let path = store.join("state.json");
+        std::fs::copy(fixture, &path).unwrap();
 
-        assert!(result.is_err());
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "Got bad data in OTARequest".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
-            }
-        };
+        path
     }
 
-    #[tokio::test]
-    async fn ota_event_fail_with_wrong_uuid() {
-        let ota = MockOTA::new();
-        let state_mock = MockStateRepository::<PersistentState>::new();
-
-        let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
-        };
+    #[test]
+    fn released_state_format_round_trip() {
+        let store = tempfile::tempdir().unwrap();
+        let path = historical_state(store.path(), "ota_state_initial.json");
+        let repository: Box<dyn StateRepository<PersistentState>> =
+            Box::new(FileStateRepository::new(
+                store.path().to_str().unwrap().to_owned(),
+                "state.json".to_owned(),
+            ));
 
-        let mut ota_req_map = HashMap::new();
-        ota_req_map.insert(
-            "url".to_owned(),
-            AstarteType::String("http://ota.bin".to_owned()),
+        let state = repository.read().unwrap();
+        assert_eq!(
+            state.uuid.to_string(),
+            "5d4c3b2a-1f0e-4d9c-8b7a-6f5e4d3c2b1a"
         );
-        ota_req_map.insert(
-            "uuid".to_owned(),
-            AstarteType::String("bad_uuid".to_owned()),
+        assert_eq!(state.slot, "A");
+        assert!(state.to_version.is_none());
+        assert!(state.link_throughput.is_none());
+        assert!(state.outcome.is_none());
+
+        repository.write(&state).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["state_version"], 1, "{written}");
+        assert_eq!(
+            serde_json::to_value(repository.read().unwrap()).unwrap(),
+            serde_json::to_value(&state).unwrap()
         );
+    }
 
-        let result = ota_handler
-            .ota_event(&MockPublisher::new(), ota_req_map)
-            .await;
+    #[tokio::test]
+    async fn update_of_released_state_confirmed() {
+        let store = tempfile::tempdir().unwrap();
+        let path = historical_state(store.path(), "ota_state_initial.json");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let ota_handler = restarted_handler(
+            store.path(),
+            FakeDeployer {
+                booted: "B",
+                calls: calls.clone(),
+                hangs: false,
+            },
+            watch::channel(false).1,
+        );
+        let (publisher, sent) = recording_publisher();
 
-        assert!(result.is_err());
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "Unable to parse request_uuid".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
-            }
-        };
+        ota_handler
+            .ensure_pending_ota_response(&publisher)
+            .await
+            .unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), ["mark good B".to_owned()]);
+        assert_eq!(statuses(&sent), [("Done".to_owned(), "".to_owned())]);
+        assert!(!path.exists());
     }
 
     #[tokio::test]
-    async fn ota_event_fail() {
-        let ota = MockOTA::new();
-        let uuid = Uuid::new_v4();
-        let mut publisher = MockPublisher::new();
-        publisher
-            .expect_send_object()
-            .returning(|_: &str, _: &str, _: OTAResponse| {
-                Err(AstarteError::SendError("test".to_owned()))
-            });
-
-        let mut ota_req_map = HashMap::new();
-        ota_req_map.insert(
-            "url".to_owned(),
-            AstarteType::String("http://ota.bin".to_owned()),
+    async fn state_of_newer_release_not_clobbered() {
+        let store = tempfile::tempdir().unwrap();
+        let path = historical_state(store.path(), "ota_state_v2.json");
+        let written = std::fs::read(&path).unwrap();
+        let ota_handler = restarted_handler(
+            store.path(),
+            FakeDeployer {
+                booted: "B",
+                calls: Arc::new(Mutex::new(Vec::new())),
+                hangs: false,
+            },
+            watch::channel(false).1,
         );
-        ota_req_map.insert(
-            "uuid".to_owned(),
-            AstarteType::String(uuid.clone().to_string()),
+
+        let err = ota_handler
+            .ensure_pending_ota_response(&recording_publisher::<OtaResponse>().0)
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(err, DeviceManagerError::NewerState { version: 2, .. }),
+            "{err:?}"
         );
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+    }
 
-        let state_mock = MockStateRepository::<PersistentState>::new();
-        let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
-        };
+    /// Handler over the state files in `store`, as after a restart of the runtime.
+    fn restarted_handler<'a>(
+        store: &std::path::Path,
+        ota: impl OTA + 'a,
+        metered: watch::Receiver<bool>,
+    ) -> OTAHandler<'a> {
+        let store_directory = store.to_str().unwrap().to_owned();
+
+        OTAHandler {
+            download_file_path: store_directory.clone(),
+            metered,
+            download_repository: Box::new(FileStateRepository::new(
+                store_directory,
+                "ota_download.json".to_owned(),
+            )),
+            ..ota_handler_with(
+                ota,
+                FileStateRepository::new(store_directory.clone(), "state.json".to_owned()),
+            )
+        }
+    }
 
-        let result = ota_handler.ota_event(&publisher, ota_req_map).await;
+    fn update_record(store: &std::path::Path) -> Option<PausedDownload> {
+        let repository: Box<dyn StateRepository<PausedDownload>> =
+            Box::new(FileStateRepository::new(
+                store.to_str().unwrap().to_owned(),
+                "ota_download.json".to_owned(),
+            ));
 
-        assert!(result.is_err());
-        match result.err().unwrap() {
-            DeviceManagerError::UpdateError(val) => {
-                assert_eq!(val, "Unable to handle OTA event".to_owned())
-            }
-            _ => {
-                panic!("Wrong DeviceManagerError type");
-            }
-        };
+        repository.exists().then(|| repository.read().unwrap())
     }
 
     #[tokio::test]
-    async fn ota_event_success() {
-        let mut ota = MockOTA::new();
-        ota.expect_info().returning(|_: &str| {
-            Ok(BundleInfo {
-                compatible: "rauc-demo-x86".to_string(),
-                version: "1".to_string(),
-            })
-        });
+    async fn update_interrupted_while_downloading_resumed_after_restart() {
+        let store = tempfile::tempdir().unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let deployer = || FakeDeployer {
+            booted: "a",
+            calls: calls.clone(),
+            hangs: false,
+        };
+        let (publisher, sent) = recording_publisher();
+        let request = OtaRequest::new(Uuid::new_v4(), "http://ota.bin");
+        let bundle = stage_update(store.path(), request.uuid, b"bundle");
+
+        // the runtime crashes while the download waits for an unmetered connection
+        let (_metered_tx, metered) = watch::channel(true);
+        let mut ota_handler = restarted_handler(store.path(), deployer(), metered);
+        let crashed = tokio::time::timeout(
+            Duration::from_millis(100),
+            ota_handler.ota_event(&publisher, request.clone().into()),
+        )
+        .await;
+        assert!(crashed.is_err());
+        drop(ota_handler);
+        let record = update_record(store.path()).unwrap();
+        assert_eq!(record.request, request);
+        assert_eq!(record.in_flight, Some(UpdatePhase::Downloading));
+
+        let mut ota_handler = restarted_handler(store.path(), deployer(), watch::channel(false).1);
+        ota_handler
+            .ensure_pending_ota_response(&publisher)
+            .await
+            .unwrap();
+        assert_eq!(update_record(store.path()).unwrap().restarts, 1);
+        ota_handler.resume_update(&publisher).await;
 
-        ota.expect_compatible()
-            .returning(|| Ok("rauc-demo-x86".to_string()));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                format!("info {}", bundle.display()),
+                format!("install {}", bundle.display()),
+            ]
+        );
+        // the backend only sees the update in progress again
+        assert_eq!(
+            statuses(&sent),
+            [
+                ("InProgress".to_owned(), "".to_owned()),
+                ("InProgress".to_owned(), "".to_owned()),
+            ]
+        );
+        assert!(update_record(store.path()).is_none());
+    }
 
-        ota.expect_boot_slot().returning(|| Ok("B".to_owned()));
-        ota.expect_install_bundle().returning(|_: &str| Ok(()));
-        ota.expect_receive_completed().returning(|| Ok(0));
+    #[tokio::test]
+    async fn update_interrupted_while_deploying_deployed_again_after_restart() {
+        let store = tempfile::tempdir().unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let deployer = |hangs| FakeDeployer {
+            booted: "a",
+            calls: calls.clone(),
+            hangs,
+        };
+        let (publisher, sent) = recording_publisher();
+        let request = OtaRequest::new(Uuid::new_v4(), "http://ota.bin");
+        let bundle = stage_update(store.path(), request.uuid, b"bundle");
+
+        let mut ota_handler =
+            restarted_handler(store.path(), deployer(true), watch::channel(false).1);
+        let crashed = tokio::time::timeout(
+            Duration::from_millis(100),
+            ota_handler.ota_event(&publisher, request.clone().into()),
+        )
+        .await;
+        assert!(crashed.is_err());
+        drop(ota_handler);
+        assert_eq!(
+            update_record(store.path()).unwrap().in_flight,
+            Some(UpdatePhase::Deploying)
+        );
+        assert!(store.path().join("state.json").exists());
+
+        // restarted before the reboot, the slot did not change
+        let mut ota_handler =
+            restarted_handler(store.path(), deployer(false), watch::channel(false).1);
+        ota_handler
+            .ensure_pending_ota_response(&publisher)
+            .await
+            .unwrap();
+        assert!(!store.path().join("state.json").exists());
+        ota_handler.resume_update(&publisher).await;
+
+        let install = format!("install {}", bundle.display());
+        let installs = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| **call == install)
+            .count();
+        assert_eq!(installs, 2);
+        assert!(statuses(&sent)
+            .iter()
+            .all(|(status, _)| status == "InProgress"));
+        assert!(update_record(store.path()).is_none());
+        // recorded again by the second deploy
+        assert!(store.path().join("state.json").exists());
+    }
 
-        let uuid = Uuid::new_v4();
-        let slot = "A";
-        let mut state_mock = MockStateRepository::<PersistentState>::new();
-        state_mock.expect_exists().returning(|| true);
-        state_mock.expect_read().returning(move || {
-            Ok(PersistentState {
-                uuid: uuid.to_owned(),
-                slot: slot.to_owned(),
+    #[tokio::test]
+    async fn update_interrupted_too_many_times_given_up() {
+        let store = tempfile::tempdir().unwrap();
+        let request = OtaRequest::new(Uuid::new_v4(), "http://ota.bin");
+        let bundle = stage_update(store.path(), request.uuid, b"bundle");
+        let repository: Box<dyn StateRepository<PausedDownload>> =
+            Box::new(FileStateRepository::new(
+                store.path().to_str().unwrap().to_owned(),
+                "ota_download.json".to_owned(),
+            ));
+        repository
+            .write(&PausedDownload {
+                request: request.clone(),
+                resume: ResumePoint::default(),
+                in_flight: Some(UpdatePhase::Downloading),
+                restarts: MAX_UPDATE_RESTARTS,
             })
-        });
-        state_mock.expect_write().returning(|_| Ok(()));
-        state_mock.expect_clear().returning(|| Ok(()));
+            .unwrap();
 
-        let mut ota_handler = OTAHandler {
-            ota: Box::new(ota),
-            state_repository: Box::new(state_mock),
-            download_file_path: "".to_owned(),
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let deployer = FakeDeployer {
+            booted: "a",
+            calls: calls.clone(),
+            hangs: false,
         };
-
+        let mut ota_handler = restarted_handler(store.path(), deployer, watch::channel(false).1);
         let mut publisher = MockPublisher::new();
-
-        publisher
-            .expect_send_object()
-            .withf(move |_: &str, _: &str, response: &OTAResponse| {
-                let status = OTAStatus::Done.to_status_code();
-                response.status.eq(&status.0)
-                    && response.status_code.eq(&status.1)
-                    && response.uuid == uuid.to_owned()
-            })
-            .returning(|_: &str, _: &str, _: OTAResponse| Ok(()));
-
         publisher
-            .expect_send_object()
-            .withf(move |_: &str, _: &str, response: &OTAResponse| {
-                let status = OTAStatus::InProgress.to_status_code();
-                response.status.eq(&status.0)
-                    && response.status_code.eq(&status.1)
-                    && response.uuid == uuid.to_owned()
+            .expect_send_object_with_timestamp()
+            .withf(move |_: &str, _: &str, response: &OtaResponse, _| {
+                response.uuid == request.uuid
+                    && response.status == "Error"
+                    && response.status_code == "OTAFailed"
             })
-            .returning(|_: &str, _: &str, _: OTAResponse| Ok(()));
-
-        let mut ota_req_map = HashMap::new();
-        ota_req_map.insert(
-            "url".to_owned(),
-            AstarteType::String("http://ota.bin".to_owned()),
-        );
-        ota_req_map.insert(
-            "uuid".to_owned(),
-            AstarteType::String(uuid.clone().to_string()),
-        );
-        let result = ota_handler.ota_event(&publisher, ota_req_map).await;
-
-        assert!(result.is_ok());
+            .times(1)
+            .returning(|_: &str, _: &str, _: OtaResponse, _| Ok(()));
+
+        ota_handler
+            .ensure_pending_ota_response(&publisher)
+            .await
+            .unwrap();
+        ota_handler.resume_update(&publisher).await;
+
+        assert!(update_record(store.path()).is_none());
+        assert!(!bundle.exists());
+        assert!(calls.lock().unwrap().is_empty());
     }
 }
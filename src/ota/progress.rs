@@ -0,0 +1,358 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Progress of a running update, published on the `OTAEvent` interface.
+//!
+//! Each phase starts at 0%, then the percentage is published only when it grew by a step or a
+//! period went by, so a large download doesn't flood Astarte. The outcome of the update is still
+//! sent on the `OTAResponse` interface.
+
+use std::fmt::{self, Display};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::OTA_EVENT_INTERFACE;
+use crate::runtime_api::SharedState;
+
+pub const DEFAULT_PROGRESS_PERIOD: Duration = Duration::from_secs(5);
+pub const DEFAULT_PROGRESS_STEP: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Downloading,
+    Deploying,
+    Rebooting,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Downloading => write!(f, "Downloading"),
+            Phase::Deploying => write!(f, "Deploying"),
+            Phase::Rebooting => write!(f, "Rebooting"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct OtaEvent {
+    #[serde(rename = "requestUUID")]
+    pub request_uuid: String,
+    pub status: String,
+    pub status_progress: i32,
+    /// Attempt of the request of the download, 1 unless it was retried.
+    pub status_attempt: i32,
+}
+
+/// When the progress of a phase is published.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressThrottle {
+    /// Longest time without publishing a grown percentage.
+    pub period: Duration,
+    /// Growth of the percentage published right away.
+    pub step: u8,
+}
+
+impl ProgressThrottle {
+    /// Whether `percentage` is published at `now`, given when and which percentage was last
+    /// published.
+    pub fn due(&self, published: Option<(Instant, u8)>, percentage: u8, now: Instant) -> bool {
+        match published {
+            Some((at, last)) => {
+                percentage > last
+                    && (percentage == 100
+                        || percentage >= last.saturating_add(self.step)
+                        || now.duration_since(at) >= self.period)
+            }
+            None => true,
+        }
+    }
+}
+
+impl Default for ProgressThrottle {
+    fn default() -> Self {
+        ProgressThrottle {
+            period: DEFAULT_PROGRESS_PERIOD,
+            step: DEFAULT_PROGRESS_STEP,
+        }
+    }
+}
+
+/// Bytes received by a download, out of `total` when the server sent the length.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub received: u64,
+    pub total: Option<u64>,
+    /// Attempt of the request of the download, counting from 1, 0 before the first.
+    pub attempt: u32,
+}
+
+impl DownloadProgress {
+    pub fn percentage(&self) -> Option<u8> {
+        match self.total {
+            Some(total) if total > 0 => Some((self.received.min(total) * 100 / total) as u8),
+            _ => None,
+        }
+    }
+}
+
+/// Publishes the progress of the update `uuid`.
+pub struct ProgressReporter<'a, P> {
+    publisher: &'a P,
+    clock: Arc<dyn Clock>,
+    throttle: ProgressThrottle,
+    uuid: Uuid,
+    phase: Phase,
+    /// When the last percentage of the phase was published.
+    published: Option<(Instant, u8)>,
+    attempt: u32,
+    runtime_state: Option<Arc<SharedState>>,
+}
+
+impl<'a, P: Publisher> ProgressReporter<'a, P> {
+    pub fn new(
+        publisher: &'a P,
+        clock: Arc<dyn Clock>,
+        throttle: ProgressThrottle,
+        uuid: Uuid,
+    ) -> Self {
+        ProgressReporter {
+            publisher,
+            clock,
+            throttle,
+            uuid,
+            phase: Phase::Downloading,
+            published: None,
+            attempt: 1,
+            runtime_state: None,
+        }
+    }
+
+    /// Report the published progress to `runtime_state` too.
+    pub fn with_runtime_state(mut self, runtime_state: Option<Arc<SharedState>>) -> Self {
+        self.runtime_state = runtime_state;
+        self
+    }
+
+    pub fn period(&self) -> Duration {
+        self.throttle.period
+    }
+
+    /// Start `phase`, published at 0%.
+    pub async fn phase(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.published = None;
+        self.attempt = 1;
+        self.publish(0).await;
+    }
+
+    /// Publish `percentage` of the current phase, unless it is too close to the last one.
+    pub async fn update(&mut self, percentage: u8) {
+        let percentage = percentage.min(100);
+        if self
+            .throttle
+            .due(self.published, percentage, self.clock.now_monotonic())
+        {
+            self.publish(percentage).await;
+        }
+    }
+
+    /// Publish the progress of a download until it completes, right away when a request of the
+    /// download is retried.
+    pub async fn follow(&mut self, mut progress: watch::Receiver<DownloadProgress>) {
+        while progress.changed().await.is_ok() {
+            let current = *progress.borrow();
+            if current.attempt > 0 && current.attempt != self.attempt {
+                self.attempt = current.attempt;
+                let last = self.published.map_or(0, |(_, last)| last);
+                self.publish(current.percentage().unwrap_or(last).max(last))
+                    .await;
+            } else if let Some(percentage) = current.percentage() {
+                self.update(percentage).await;
+            }
+        }
+    }
+
+    async fn publish(&mut self, percentage: u8) {
+        self.published = Some((self.clock.now_monotonic(), percentage));
+        if let Some(runtime_state) = &self.runtime_state {
+            runtime_state.set_ota(Some(self.phase), percentage);
+        }
+
+        let event = OtaEvent {
+            request_uuid: self.uuid.to_string(),
+            status: self.phase.to_string(),
+            status_progress: percentage.into(),
+            status_attempt: self.attempt as i32,
+        };
+        let timestamp = DateTime::<Utc>::from(self.clock.now_wall());
+        if let Err(err) = self
+            .publisher
+            .send_object_with_timestamp(OTA_EVENT_INTERFACE, "/event", event, timestamp)
+            .await
+        {
+            warn!("Unable to publish the OTA progress: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+    use uuid::Uuid;
+
+    use crate::interfaces::OTA_EVENT_INTERFACE;
+    use crate::ota::progress::{
+        DownloadProgress, OtaEvent, Phase, ProgressReporter, ProgressThrottle,
+    };
+    use crate::runtime_api::SharedState;
+    use crate::test_utils::{recording_publisher, settle, ManualClock, Recording};
+
+    /// OTA events published on `/event`.
+    pub(crate) fn events(recording: &Recording<OtaEvent>) -> Vec<OtaEvent> {
+        let published = recording.on(OTA_EVENT_INTERFACE);
+        assert!(published.iter().all(|(path, _)| path == "/event"));
+
+        recording.objects(OTA_EVENT_INTERFACE)
+    }
+
+    #[test]
+    fn download_percentage() {
+        let progress = |received, total| DownloadProgress {
+            received,
+            total,
+            attempt: 1,
+        };
+
+        assert_eq!(progress(512, Some(1024)).percentage(), Some(50));
+        assert_eq!(progress(2048, Some(1024)).percentage(), Some(100));
+        assert_eq!(progress(512, None).percentage(), None);
+        assert_eq!(progress(0, Some(0)).percentage(), None);
+    }
+
+    #[tokio::test]
+    async fn download_progress_throttled_and_increasing() {
+        let clock = Arc::new(ManualClock::new());
+        let (publisher, recording) = recording_publisher();
+        let uuid = Uuid::new_v4();
+        let mut reporter = ProgressReporter::new(
+            &publisher,
+            clock.clone(),
+            ProgressThrottle {
+                period: Duration::from_secs(5),
+                step: 10,
+            },
+            uuid,
+        );
+        reporter.phase(Phase::Downloading).await;
+
+        // 1 MiB received in 256 chunks of 4 KiB, one every 100ms
+        let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
+        let download = async move {
+            for chunk in 1..=256 {
+                clock.advance(Duration::from_millis(100));
+                progress_tx.send_replace(DownloadProgress {
+                    received: chunk * 4096,
+                    total: Some(1024 * 1024),
+                    attempt: 1,
+                });
+                settle().await;
+            }
+        };
+        tokio::join!(reporter.follow(progress_rx), download);
+
+        let events = events(&recording);
+        assert!(events.iter().all(|event| {
+            event.request_uuid == uuid.to_string() && event.status == "Downloading"
+        }));
+        let percentages: Vec<i32> = events.iter().map(|event| event.status_progress).collect();
+        assert!(percentages.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(percentages.first(), Some(&0));
+        assert_eq!(percentages.last(), Some(&100));
+        // every 10% and the final 100%, the whole download takes less than a period per step
+        assert_eq!(percentages.len(), 11);
+    }
+
+    #[tokio::test]
+    async fn progress_reported_to_runtime_state() {
+        let clock = Arc::new(ManualClock::new());
+        let (publisher, _) = recording_publisher::<OtaEvent>();
+        let runtime_state = Arc::new(SharedState::default());
+        let state = runtime_state.subscribe();
+        let mut reporter = ProgressReporter::new(
+            &publisher,
+            clock.clone(),
+            ProgressThrottle::default(),
+            Uuid::new_v4(),
+        )
+        .with_runtime_state(Some(runtime_state));
+
+        reporter.phase(Phase::Downloading).await;
+        reporter.update(43).await;
+        assert_eq!(state.borrow().ota_phase, Some(Phase::Downloading));
+        assert_eq!(state.borrow().ota_progress, 43);
+
+        reporter.phase(Phase::Deploying).await;
+        assert_eq!(state.borrow().ota_phase, Some(Phase::Deploying));
+        assert_eq!(state.borrow().ota_progress, 0);
+    }
+
+    #[tokio::test]
+    async fn slow_download_published_every_period() {
+        let clock = Arc::new(ManualClock::new());
+        let (publisher, recording) = recording_publisher();
+        let mut reporter = ProgressReporter::new(
+            &publisher,
+            clock.clone(),
+            ProgressThrottle::default(),
+            Uuid::new_v4(),
+        );
+        reporter.phase(Phase::Deploying).await;
+
+        for percentage in 1..=4 {
+            clock.advance(Duration::from_secs(1));
+            reporter.update(percentage).await;
+        }
+        clock.advance(Duration::from_secs(1));
+        reporter.update(5).await;
+        // a percentage going back is never published
+        clock.advance(Duration::from_secs(10));
+        reporter.update(3).await;
+
+        let percentages: Vec<i32> = events(&recording)
+            .iter()
+            .map(|event| event.status_progress)
+            .collect();
+        assert_eq!(percentages, [0, 5]);
+    }
+}
@@ -0,0 +1,104 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Cap on the speed of the OTA downloads.
+//!
+//! The devices may share a narrow uplink with the product traffic. The chunks read from the
+//! server are taken from a token bucket filled at the configured rate, holding at most a second
+//! of it, and the next read waits while the bucket is in debt.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+
+pub struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    bytes_per_sec: u64,
+    /// Bytes that can be read right away, negative after a chunk larger than them.
+    tokens: f64,
+    filled: Instant,
+}
+
+impl RateLimiter {
+    /// Limit to `bytes_per_sec`, starting with an empty bucket so a download doesn't begin with
+    /// a burst.
+    pub fn new(clock: Arc<dyn Clock>, bytes_per_sec: u64) -> Self {
+        let filled = clock.now_monotonic();
+
+        RateLimiter {
+            clock,
+            bytes_per_sec: bytes_per_sec.max(1),
+            tokens: 0.0,
+            filled,
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait for it to be out of debt.
+    pub fn take(&mut self, bytes: u64) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let now = self.clock.now_monotonic();
+        let refill = now.duration_since(self.filled).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate) - bytes as f64;
+        self.filled = now;
+
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Take `bytes` from the bucket, waiting until they were paid for.
+    pub async fn throttle(&mut self, bytes: u64) {
+        let wait = self.take(bytes);
+        if !wait.is_zero() {
+            self.clock.sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::ota::rate_limit::RateLimiter;
+    use crate::test_utils::ManualClock;
+
+    #[test]
+    fn chunks_paid_at_the_rate() {
+        let clock = Arc::new(ManualClock::new());
+        let mut limiter = RateLimiter::new(clock.clone(), 1000);
+
+        assert_eq!(limiter.take(500), Duration::from_millis(500));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(limiter.take(2000), Duration::from_secs(2));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(limiter.take(0), Duration::ZERO);
+
+        // an idle link fills the bucket with a second at most
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.take(1000), Duration::ZERO);
+        assert_eq!(limiter.take(250), Duration::from_millis(250));
+    }
+}
@@ -19,17 +19,19 @@
  */
 
 use async_trait::async_trait;
+use futures_util::StreamExt;
 use log::info;
 use serde::{Deserialize, Serialize};
 use zbus::dbus_proxy;
-use zbus::export::futures_util::StreamExt;
 use zbus::zvariant::{DeserializeDict, SerializeDict, Type};
 
-use crate::ota::OTA;
+use crate::ota::{BundleInfo, OTA};
+use crate::wrapper::platform::system_bus;
 use crate::DeviceManagerError;
 
 #[derive(DeserializeDict, SerializeDict, Type, Debug)]
 #[zvariant(signature = "dict")]
+#[allow(dead_code)]
 pub struct SlotStatus {
     #[zvariant(rename = "boot-status")]
     boot_status: Option<String>,
@@ -43,18 +45,12 @@ pub struct SlotStatus {
 
 #[derive(Debug, Deserialize, Serialize, Type)]
 #[zvariant(signature = "(sa{sv})")]
+#[allow(dead_code)]
 pub struct Slot {
     name: String,
     data: SlotStatus,
 }
 
-#[derive(Debug, Deserialize, Serialize, Type)]
-#[zvariant(signature = "(ss)")]
-pub struct BundleInfo {
-    pub compatible: String,
-    pub version: String,
-}
-
 #[dbus_proxy(
     interface = "de.pengutronix.rauc.Installer",
     default_service = "de.pengutronix.rauc",
@@ -137,35 +133,45 @@ impl<'a> OTA for OTARauc<'a> {
         self.rauc
             .last_error()
             .await
-            .map_err(|err| DeviceManagerError::ZbusError(err))
+            .map_err(DeviceManagerError::ZbusError)
     }
 
     async fn info(&self, bundle: &str) -> Result<BundleInfo, DeviceManagerError> {
         self.rauc
             .info(bundle)
             .await
-            .map_err(|err| DeviceManagerError::ZbusError(err))
+            .map_err(DeviceManagerError::ZbusError)
     }
 
     async fn operation(&self) -> Result<String, DeviceManagerError> {
         self.rauc
             .operation()
             .await
-            .map_err(|err| DeviceManagerError::ZbusError(err))
+            .map_err(DeviceManagerError::ZbusError)
+    }
+
+    async fn progress(&self) -> Result<i32, DeviceManagerError> {
+        let (percentage, _message, _depth) = self
+            .rauc
+            .progress()
+            .await
+            .map_err(DeviceManagerError::ZbusError)?;
+
+        Ok(percentage)
     }
 
     async fn compatible(&self) -> Result<String, DeviceManagerError> {
         self.rauc
             .compatible()
             .await
-            .map_err(|err| DeviceManagerError::ZbusError(err))
+            .map_err(DeviceManagerError::ZbusError)
     }
 
     async fn boot_slot(&self) -> Result<String, DeviceManagerError> {
         self.rauc
             .boot_slot()
             .await
-            .map_err(|err| DeviceManagerError::ZbusError(err))
+            .map_err(DeviceManagerError::ZbusError)
     }
 
     async fn receive_completed(&self) -> Result<i32, DeviceManagerError> {
@@ -187,7 +193,7 @@ impl<'a> OTA for OTARauc<'a> {
         self.rauc
             .get_primary()
             .await
-            .map_err(|err| DeviceManagerError::ZbusError(err))
+            .map_err(DeviceManagerError::ZbusError)
     }
 
     async fn mark(
@@ -198,13 +204,18 @@ impl<'a> OTA for OTARauc<'a> {
         self.rauc
             .mark(state, slot_identifier)
             .await
-            .map_err(|err| DeviceManagerError::ZbusError(err))
+            .map_err(DeviceManagerError::ZbusError)
+    }
+
+    async fn health_check(&self) -> Result<(), DeviceManagerError> {
+        self.rauc.get_slot_status().await?;
+        Ok(())
     }
 }
 
 impl<'a> OTARauc<'a> {
     pub async fn new() -> Result<OTARauc<'a>, DeviceManagerError> {
-        let connection = zbus::Connection::system().await?;
+        let connection = system_bus().await?;
 
         let proxy = RaucProxy::new(&connection).await?;
 
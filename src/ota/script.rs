@@ -0,0 +1,190 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Deploy backend delegating to a script of the integrator.
+//!
+//! Every call runs the script with a subcommand, e.g. `deploy.sh install /data/update.bin`:
+//!
+//! - `install <bundle>` installs the bundle, failing with a non-zero exit status;
+//! - `info <bundle>` prints the `compatible=` and `version=` lines of the bundle;
+//! - `compatible` prints the compatible string of the system;
+//! - `boot-slot` prints the booted slot;
+//! - `primary` prints the slot booted next;
+//! - `mark <state> <slot>` marks the slot `good`, `bad` or `active`;
+//! - `health` checks the backend is available.
+
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::error::DeviceManagerError;
+use crate::ota::image::{parse_image_info, shell_quote, CommandRunner};
+use crate::ota::{BundleInfo, InstallOutcome, OTA};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScriptDeployOptions {
+    /// Script run with the subcommands of the backend.
+    pub script: PathBuf,
+}
+
+pub struct ScriptDeployer {
+    options: ScriptDeployOptions,
+    runner: Box<dyn CommandRunner>,
+    /// Outcome of the last installation, taken by `receive_completed`.
+    outcome: InstallOutcome,
+}
+
+impl ScriptDeployer {
+    pub fn new(options: ScriptDeployOptions, runner: Box<dyn CommandRunner>) -> Self {
+        ScriptDeployer {
+            options,
+            runner,
+            outcome: InstallOutcome::default(),
+        }
+    }
+
+    /// Run the script with `args`, returning its trimmed output.
+    async fn run(&self, args: &[&str]) -> Result<String, DeviceManagerError> {
+        let mut command = shell_quote(&self.options.script.to_string_lossy());
+        for arg in args {
+            command.push(' ');
+            command += &shell_quote(arg);
+        }
+
+        Ok(self.runner.run(&command).await?.trim().to_owned())
+    }
+}
+
+#[async_trait]
+impl OTA for ScriptDeployer {
+    async fn install_bundle(&self, source: &str) -> Result<(), DeviceManagerError> {
+        let result = self.run(&["install", source]).await.map(drop);
+        self.outcome.record(&result);
+
+        result
+    }
+
+    async fn last_error(&self) -> Result<String, DeviceManagerError> {
+        Ok(self.outcome.last_error())
+    }
+
+    async fn info(&self, bundle: &str) -> Result<BundleInfo, DeviceManagerError> {
+        parse_image_info(&self.run(&["info", bundle]).await?)
+    }
+
+    async fn operation(&self) -> Result<String, DeviceManagerError> {
+        Ok("idle".to_owned())
+    }
+
+    /// The bundle is installed by `install_bundle` itself.
+    async fn progress(&self) -> Result<i32, DeviceManagerError> {
+        Ok(self.outcome.progress())
+    }
+
+    async fn compatible(&self) -> Result<String, DeviceManagerError> {
+        self.run(&["compatible"]).await
+    }
+
+    async fn boot_slot(&self) -> Result<String, DeviceManagerError> {
+        self.run(&["boot-slot"]).await
+    }
+
+    async fn receive_completed(&self) -> Result<i32, DeviceManagerError> {
+        self.outcome.take()
+    }
+
+    async fn get_primary(&self) -> Result<String, DeviceManagerError> {
+        self.run(&["primary"]).await
+    }
+
+    async fn mark(
+        &self,
+        state: &str,
+        slot_identifier: &str,
+    ) -> Result<(String, String), DeviceManagerError> {
+        let message = self.run(&["mark", state, slot_identifier]).await?;
+
+        Ok((slot_identifier.to_owned(), message))
+    }
+
+    async fn health_check(&self) -> Result<(), DeviceManagerError> {
+        self.run(&["health"]).await.map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::ota::image::ShellRunner;
+    use crate::ota::script::{ScriptDeployOptions, ScriptDeployer};
+    use crate::ota::OTA;
+
+    const SCRIPT: &str = r#"#!/bin/sh
+echo "$@" >> "$(dirname "$0")/calls"
+case "$1" in
+    install) test -f "$2" ;;
+    info) printf 'compatible=board-x\nversion=2.0.0\n' ;;
+    boot-slot) echo a ;;
+    primary) echo b ;;
+    mark) echo "marked $3 as $2" ;;
+    *) exit 1 ;;
+esac
+"#;
+
+    #[tokio::test]
+    async fn script_run_with_the_subcommands() {
+        let directory = tempfile::tempdir().unwrap();
+        let script = directory.path().join("deploy.sh");
+        std::fs::write(&script, SCRIPT).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let bundle = directory.path().join("update it's.bin");
+        std::fs::write(&bundle, b"bundle").unwrap();
+
+        let deployer = ScriptDeployer::new(ScriptDeployOptions { script }, Box::new(ShellRunner));
+        let bundle = bundle.to_string_lossy();
+        assert_eq!(deployer.info(&bundle).await.unwrap().version, "2.0.0");
+        deployer.install_bundle(&bundle).await.unwrap();
+        assert_eq!(deployer.receive_completed().await.unwrap(), 0);
+        assert_eq!(deployer.boot_slot().await.unwrap(), "a");
+        assert_eq!(
+            deployer.mark("good", "b").await.unwrap(),
+            ("b".to_owned(), "marked b as good".to_owned())
+        );
+
+        assert!(deployer.install_bundle("/missing.bin").await.is_err());
+        assert_eq!(deployer.receive_completed().await.unwrap(), 1);
+        assert!(deployer.health_check().await.is_err());
+
+        let calls = std::fs::read_to_string(directory.path().join("calls")).unwrap();
+        assert_eq!(
+            calls.lines().collect::<Vec<_>>(),
+            [
+                format!("info {bundle}"),
+                format!("install {bundle}"),
+                "boot-slot".to_owned(),
+                "mark good b".to_owned(),
+                "install /missing.bin".to_owned(),
+                "health".to_owned(),
+            ]
+        );
+    }
+}
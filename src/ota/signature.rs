@@ -0,0 +1,532 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Verification of the OTA bundle signatures against a rotating set of trusted keys.
+//!
+//! The RSA (PKCS#1 v1.5) and EC keys sign the SHA-256 digest of the content. The Ed25519 keys
+//! sign the content itself (PureEdDSA), which is held in memory until the signature is checked.
+
+use std::io::Read;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, PKeyRef, Public};
+use openssl::rsa::Padding;
+use openssl::sign::Verifier;
+use serde::{Deserialize, Serialize};
+
+use crate::error::DeviceManagerError;
+use crate::ota::ota_handler::OTAError;
+use crate::repository::StateRepository;
+
+/// Id of the key read from `ota_public_key_path`, checking the signatures without a key id.
+pub const PUBLIC_KEY_ID: &str = "default";
+
+/// Check of a detached signature, fed with the content as it is read.
+pub trait SignatureVerifier {
+    fn update(&mut self, data: &[u8]) -> Result<(), ErrorStack>;
+    fn verify(self: Box<Self>, signature: &[u8]) -> Result<bool, ErrorStack>;
+}
+
+/// SHA-256 signatures of the RSA, with the PKCS#1 v1.5 padding, and EC keys.
+struct DigestVerifier<'a>(Verifier<'a>);
+
+impl SignatureVerifier for DigestVerifier<'_> {
+    fn update(&mut self, data: &[u8]) -> Result<(), ErrorStack> {
+        self.0.update(data)
+    }
+
+    fn verify(self: Box<Self>, signature: &[u8]) -> Result<bool, ErrorStack> {
+        self.0.verify(signature)
+    }
+}
+
+/// Ed25519 signatures of the content, which can only be checked in one shot.
+struct Ed25519Verifier<'a> {
+    public_key: &'a PKeyRef<Public>,
+    content: Vec<u8>,
+}
+
+impl SignatureVerifier for Ed25519Verifier<'_> {
+    fn update(&mut self, data: &[u8]) -> Result<(), ErrorStack> {
+        self.content.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn verify(self: Box<Self>, signature: &[u8]) -> Result<bool, ErrorStack> {
+        Verifier::new_without_digest(self.public_key)?.verify_oneshot(signature, &self.content)
+    }
+}
+
+/// The verifier of the signatures made with the private key of `public_key`.
+pub fn verifier(
+    public_key: &PKeyRef<Public>,
+) -> Result<Box<dyn SignatureVerifier + '_>, ErrorStack> {
+    match public_key.id() {
+        Id::ED25519 => Ok(Box::new(Ed25519Verifier {
+            public_key,
+            content: Vec::new(),
+        })),
+        Id::RSA => {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), public_key)?;
+            verifier.set_rsa_padding(Padding::PKCS1)?;
+            Ok(Box::new(DigestVerifier(verifier)))
+        }
+        _ => Ok(Box::new(DigestVerifier(Verifier::new(
+            MessageDigest::sha256(),
+            public_key,
+        )?))),
+    }
+}
+
+/// A public key trusted to sign OTA bundles, in PEM format.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrustedKey {
+    pub id: String,
+    #[serde(default)]
+    pub not_after: Option<DateTime<Utc>>,
+    pub public_key: String,
+}
+
+/// Versioned set of trusted keys, replaced as a whole by a key bundle.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrustedKeySet {
+    pub version: u64,
+    pub keys: Vec<TrustedKey>,
+}
+
+impl TrustedKeySet {
+    /// Load every `*.json` key file in `directory`, the provisioned set has version 0.
+    pub fn load_directory(directory: &Path) -> Result<Self, DeviceManagerError> {
+        let mut keys = Vec::new();
+
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+
+            let key: TrustedKey = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            keys.push(key);
+        }
+
+        keys.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(TrustedKeySet { version: 0, keys })
+    }
+
+    /// Trust the PEM public key at `path` as [`PUBLIC_KEY_ID`].
+    pub fn with_public_key_file(mut self, path: &Path) -> Result<Self, DeviceManagerError> {
+        let public_key = std::fs::read_to_string(path)?;
+        PKey::public_key_from_pem(public_key.as_bytes()).map_err(|err| {
+            DeviceManagerError::InvalidOption {
+                option: format!("ota_public_key_path {}", path.display()),
+                reason: err.to_string(),
+            }
+        })?;
+
+        self.keys.retain(|key| key.id != PUBLIC_KEY_ID);
+        self.keys.push(TrustedKey {
+            id: PUBLIC_KEY_ID.to_owned(),
+            not_after: None,
+            public_key,
+        });
+
+        Ok(self)
+    }
+
+    pub fn contains(&self, key_id: &str) -> bool {
+        self.keys.iter().any(|key| key.id == key_id)
+    }
+
+    /// The public key `key_id`, if it is trusted and not expired at `now`.
+    pub fn public_key(&self, key_id: &str, now: DateTime<Utc>) -> Result<PKey<Public>, OTAError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == key_id)
+            .ok_or_else(|| {
+                warn!("Unknown signing key {key_id}");
+                OTAError::UnknownSigningKey
+            })?;
+
+        if key.not_after.map_or(false, |not_after| now > not_after) {
+            warn!("Signing key {key_id} expired");
+            return Err(OTAError::ExpiredSigningKey);
+        }
+
+        PKey::public_key_from_pem(key.public_key.as_bytes()).map_err(|err| {
+            warn!("Invalid public key {key_id}: {:?}", err);
+            OTAError::InvalidSignature
+        })
+    }
+
+    /// Verify the SHA-256 signature of `content` with the key `key_id`.
+    pub fn verify(
+        &self,
+        key_id: &str,
+        mut content: impl Read,
+        signature: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<(), OTAError> {
+        let public_key = self.public_key(key_id, now)?;
+
+        let invalid = |err| {
+            warn!("Signature verification error: {:?}", err);
+            OTAError::InvalidSignature
+        };
+
+        let mut verifier = verifier(&public_key).map_err(invalid)?;
+
+        let mut buffer = [0; 8192];
+        loop {
+            let read = content.read(&mut buffer).map_err(|err| {
+                warn!("Unable to read signed content: {err}");
+                OTAError::InvalidSignature
+            })?;
+            if read == 0 {
+                break;
+            }
+            verifier.update(&buffer[..read]).map_err(invalid)?;
+        }
+
+        if verifier.verify(signature).map_err(invalid)? {
+            Ok(())
+        } else {
+            Err(OTAError::InvalidSignature)
+        }
+    }
+}
+
+/// The trusted keys provisioned on the device, superseded by the last installed key bundle.
+pub struct TrustedKeys<'a> {
+    provisioned: TrustedKeySet,
+    repository: Box<dyn StateRepository<TrustedKeySet> + 'a>,
+}
+
+impl<'a> TrustedKeys<'a> {
+    pub fn new(
+        provisioned: TrustedKeySet,
+        repository: Box<dyn StateRepository<TrustedKeySet> + 'a>,
+    ) -> Self {
+        TrustedKeys {
+            provisioned,
+            repository,
+        }
+    }
+
+    pub fn current(&self) -> Result<TrustedKeySet, DeviceManagerError> {
+        if self.repository.exists() {
+            let installed = self.repository.read()?;
+            if installed.version > self.provisioned.version {
+                return Ok(installed);
+            }
+        }
+
+        Ok(self.provisioned.clone())
+    }
+
+    /// Replace the trusted keys with the set in the key bundle at `path`, which must be signed by
+    /// a currently trusted key and be newer than the current set.
+    pub fn install_key_bundle(
+        &self,
+        key_id: &str,
+        path: &Path,
+        signature: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<u64, DeviceManagerError> {
+        let bundle = std::fs::read(path)?;
+
+        let current = self.current()?;
+        current.verify(key_id, bundle.as_slice(), signature, now)?;
+
+        let key_set: TrustedKeySet =
+            serde_json::from_slice(&bundle).map_err(|_| OTAError::InvalidKeyBundle)?;
+        if key_set.version <= current.version || key_set.keys.is_empty() {
+            warn!(
+                "Refusing key bundle version {}, current version is {}",
+                key_set.version, current.version
+            );
+            return Err(OTAError::InvalidKeyBundle.into());
+        }
+
+        self.repository.write(&key_set)?;
+        info!("Installed trusted keys version {}", key_set.version);
+
+        Ok(key_set.version)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{Id, PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::sha::sha256;
+    use openssl::sign::Signer;
+
+    use crate::error::DeviceManagerError;
+    use crate::ota::ota_handler::OTAError;
+    use crate::ota::signature::{TrustedKey, TrustedKeySet, TrustedKeys, PUBLIC_KEY_ID};
+    use crate::repository::file_state_repository::FileStateRepository;
+
+    pub(crate) fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    pub(crate) fn trusted(
+        id: &str,
+        key: &PKey<Private>,
+        not_after: Option<DateTime<Utc>>,
+    ) -> TrustedKey {
+        TrustedKey {
+            id: id.to_owned(),
+            not_after,
+            public_key: String::from_utf8(key.public_key_to_pem().unwrap()).unwrap(),
+        }
+    }
+
+    pub(crate) fn sign(key: &PKey<Private>, content: &[u8]) -> Vec<u8> {
+        if key.id() == Id::ED25519 {
+            return Signer::new_without_digest(key)
+                .unwrap()
+                .sign_oneshot_to_vec(content)
+                .unwrap();
+        }
+
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(content).unwrap();
+        signer.sign_to_vec().unwrap()
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2022, 6, 1).and_hms(12, 0, 0)
+    }
+
+    fn trusted_keys(directory: &Path, provisioned: TrustedKeySet) -> TrustedKeys<'static> {
+        TrustedKeys::new(
+            provisioned,
+            Box::new(FileStateRepository::new(
+                directory.to_str().unwrap().to_owned(),
+                "trusted_keys.json".to_owned(),
+            )),
+        )
+    }
+
+    fn assert_ota_error(result: Result<(), OTAError>, expected: OTAError) {
+        assert_eq!(result.unwrap_err().to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn verify_selects_key_by_id() {
+        let old = generate_key();
+        let new = generate_key();
+        let keys = TrustedKeySet {
+            version: 0,
+            keys: vec![trusted("old", &old, None), trusted("new", &new, None)],
+        };
+        let content = b"bundle content";
+
+        assert!(keys
+            .verify("old", &content[..], &sign(&old, content), now())
+            .is_ok());
+        assert!(keys
+            .verify("new", &content[..], &sign(&new, content), now())
+            .is_ok());
+        assert_ota_error(
+            keys.verify("old", &content[..], &sign(&new, content), now()),
+            OTAError::InvalidSignature,
+        );
+        assert_ota_error(
+            keys.verify("old", &b"tampered"[..], &sign(&old, content), now()),
+            OTAError::InvalidSignature,
+        );
+    }
+
+    #[test]
+    fn rsa_and_ed25519_signatures() {
+        let rsa = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let keys = TrustedKeySet {
+            version: 0,
+            keys: vec![
+                trusted("rsa", &rsa, None),
+                trusted("ed25519", &ed25519, None),
+            ],
+        };
+        let content = vec![0x5a; 20_000];
+
+        for (key_id, key) in [("rsa", &rsa), ("ed25519", &ed25519)] {
+            let signature = sign(key, &content);
+            assert!(keys
+                .verify(key_id, content.as_slice(), &signature, now())
+                .is_ok());
+            assert_ota_error(
+                keys.verify(key_id, &content[1..], &signature, now()),
+                OTAError::InvalidSignature,
+            );
+        }
+        // the Ed25519 keys sign the content, not its digest
+        let signature = Signer::new_without_digest(&ed25519)
+            .unwrap()
+            .sign_oneshot_to_vec(&sha256(&content))
+            .unwrap();
+        assert_ota_error(
+            keys.verify("ed25519", content.as_slice(), &signature, now()),
+            OTAError::InvalidSignature,
+        );
+    }
+
+    #[test]
+    fn public_key_file_trusted() {
+        let directory = tempfile::tempdir().unwrap();
+        let key = PKey::generate_ed25519().unwrap();
+        let path = directory.path().join("ota.pem");
+        std::fs::write(&path, key.public_key_to_pem().unwrap()).unwrap();
+
+        let keys = TrustedKeySet::default()
+            .with_public_key_file(&path)
+            .unwrap();
+        let content = b"bundle content";
+        assert!(keys
+            .verify(PUBLIC_KEY_ID, &content[..], &sign(&key, content), now())
+            .is_ok());
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(TrustedKeySet::default()
+            .with_public_key_file(&path)
+            .is_err());
+    }
+
+    #[test]
+    fn verify_unknown_and_expired_keys() {
+        let key = generate_key();
+        let keys = TrustedKeySet {
+            version: 0,
+            keys: vec![trusted("expiring", &key, Some(now() + Duration::days(1)))],
+        };
+        let content = b"bundle content";
+        let signature = sign(&key, content);
+
+        assert!(keys
+            .verify("expiring", &content[..], &signature, now())
+            .is_ok());
+        assert_ota_error(
+            keys.verify(
+                "expiring",
+                &content[..],
+                &signature,
+                now() + Duration::days(2),
+            ),
+            OTAError::ExpiredSigningKey,
+        );
+        assert_ota_error(
+            keys.verify("missing", &content[..], &signature, now()),
+            OTAError::UnknownSigningKey,
+        );
+    }
+
+    #[test]
+    fn load_key_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        let key = generate_key();
+        let trusted_key = trusted("fleet-2022", &key, Some(now()));
+        std::fs::write(
+            directory.path().join("fleet-2022.json"),
+            serde_json::to_string(&trusted_key).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(directory.path().join("README"), "not a key").unwrap();
+
+        let keys = TrustedKeySet::load_directory(directory.path()).unwrap();
+
+        assert_eq!(
+            keys,
+            TrustedKeySet {
+                version: 0,
+                keys: vec![trusted_key]
+            }
+        );
+    }
+
+    #[test]
+    fn key_bundle_rotation() {
+        let store = tempfile::tempdir().unwrap();
+        let old = generate_key();
+        let new = generate_key();
+        let keys = trusted_keys(
+            store.path(),
+            TrustedKeySet {
+                version: 0,
+                keys: vec![trusted("old", &old, None)],
+            },
+        );
+
+        let key_set = TrustedKeySet {
+            version: 1,
+            keys: vec![trusted("new", &new, None)],
+        };
+        let bundle = serde_json::to_vec(&key_set).unwrap();
+        let bundle_path = store.path().join("keys.bin");
+        std::fs::write(&bundle_path, &bundle).unwrap();
+
+        // a bundle must be signed by a trusted key
+        assert!(keys
+            .install_key_bundle("new", &bundle_path, &sign(&new, &bundle), now())
+            .is_err());
+
+        assert_eq!(
+            keys.install_key_bundle("old", &bundle_path, &sign(&old, &bundle), now())
+                .unwrap(),
+            1
+        );
+        assert_eq!(keys.current().unwrap(), key_set);
+
+        // the rotated key is no longer trusted
+        let content = b"bundle content";
+        let current = keys.current().unwrap();
+        assert_ota_error(
+            current.verify("old", &content[..], &sign(&old, content), now()),
+            OTAError::UnknownSigningKey,
+        );
+        assert!(current
+            .verify("new", &content[..], &sign(&new, content), now())
+            .is_ok());
+
+        // replaying the same version is refused
+        assert!(matches!(
+            keys.install_key_bundle("new", &bundle_path, &sign(&new, &bundle), now()),
+            Err(DeviceManagerError::OTAError(OTAError::InvalidKeyBundle))
+        ));
+    }
+}
@@ -0,0 +1,299 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Deploy backend of the images built with SWUpdate.
+//!
+//! The `.swu` image is installed with the `swupdate` binary, selecting the software collection
+//! of the slot not booted, and its `sw-description` switches the bootloader to it. The booted
+//! slot is read with a configured command, since it is kept in the bootloader environment.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use log::info;
+use serde::Deserialize;
+
+use crate::error::DeviceManagerError;
+use crate::ota::image::{shell_quote, CommandRunner};
+use crate::ota::{BundleInfo, InstallOutcome, OTA};
+
+const DEFAULT_INSPECT_COMMAND: &str = "cpio --quiet -i --to-stdout sw-description < {image}";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SwUpdateOptions {
+    /// Hardware compatibility of the board, one of the `hardware-compatibility` of the images.
+    pub compatible: String,
+    /// Command printing the booted slot, e.g. `fw_printenv -n rootfs_slot`.
+    pub boot_slot_command: String,
+    /// Software collection installed from each booted slot, e.g. `a = "stable,copy2"`.
+    pub selections: HashMap<String, String>,
+    /// Public key checking the signed images, passed to SWUpdate with `-k`.
+    pub public_key: Option<PathBuf>,
+    /// Command printing the `sw-description` of `{image}`, extracted with `cpio` when unset.
+    pub inspect_command: Option<String>,
+    /// Commands run once the new slot is confirmed, e.g. `fw_setenv upgrade_available 0`.
+    #[serde(default)]
+    pub confirm_commands: Vec<String>,
+}
+
+pub struct SwUpdateDeployer {
+    options: SwUpdateOptions,
+    runner: Box<dyn CommandRunner>,
+    /// Outcome of the last installation, taken by `receive_completed`.
+    outcome: InstallOutcome,
+}
+
+impl SwUpdateDeployer {
+    pub fn new(options: SwUpdateOptions, runner: Box<dyn CommandRunner>) -> Self {
+        SwUpdateDeployer {
+            options,
+            runner,
+            outcome: InstallOutcome::default(),
+        }
+    }
+
+    /// Install the image at `source` into the slot not booted.
+    async fn install(&self, source: &str) -> Result<(), DeviceManagerError> {
+        let booted = self.boot_slot().await?;
+        let selection = self.options.selections.get(&booted).ok_or_else(|| {
+            DeviceManagerError::UpdateError(format!("no software selection for the slot {booted}"))
+        })?;
+
+        let mut command = format!(
+            "swupdate -v -i {} -e {}",
+            shell_quote(source),
+            shell_quote(selection)
+        );
+        if let Some(public_key) = &self.options.public_key {
+            command += &format!(" -k {}", shell_quote(&public_key.to_string_lossy()));
+        }
+        self.runner.run(&command).await?;
+        info!("Image installed with the {selection} selection");
+
+        Ok(())
+    }
+}
+
+/// The version and the hardware compatibility in the `sw-description` of an image, the latter
+/// being `compatible` when it is listed.
+fn parse_sw_description(
+    description: &str,
+    compatible: &str,
+) -> Result<BundleInfo, DeviceManagerError> {
+    let value = |line: &str, key: &str| -> Option<String> {
+        let rest = line.trim().strip_prefix(key)?.trim_start();
+        let rest = rest.strip_prefix('=').or_else(|| rest.strip_prefix(':'))?;
+
+        Some(rest.trim().trim_end_matches(';').trim().to_owned())
+    };
+
+    let version = description
+        .lines()
+        .find_map(|line| value(line, "version"))
+        .map(|version| version.trim_matches('"').to_owned());
+    let hardware: Option<Vec<String>> = description
+        .lines()
+        .find_map(|line| value(line, "hardware-compatibility"))
+        .map(|list| {
+            list.trim_matches(|c| c == '[' || c == ']')
+                .split(',')
+                .map(|entry| entry.trim().trim_matches('"').to_owned())
+                .filter(|entry| !entry.is_empty())
+                .collect()
+        });
+
+    match (version, hardware) {
+        (Some(version), Some(hardware)) if !hardware.is_empty() => Ok(BundleInfo {
+            compatible: hardware
+                .iter()
+                .find(|entry| *entry == compatible)
+                .unwrap_or(&hardware[0])
+                .clone(),
+            version,
+        }),
+        _ => Err(DeviceManagerError::UpdateError(
+            "the image has no version or hardware compatibility".to_owned(),
+        )),
+    }
+}
+
+#[async_trait]
+impl OTA for SwUpdateDeployer {
+    async fn install_bundle(&self, source: &str) -> Result<(), DeviceManagerError> {
+        let result = self.install(source).await;
+        self.outcome.record(&result);
+
+        result
+    }
+
+    async fn last_error(&self) -> Result<String, DeviceManagerError> {
+        Ok(self.outcome.last_error())
+    }
+
+    async fn info(&self, bundle: &str) -> Result<BundleInfo, DeviceManagerError> {
+        let command = self
+            .options
+            .inspect_command
+            .as_deref()
+            .unwrap_or(DEFAULT_INSPECT_COMMAND)
+            .replace("{image}", &shell_quote(bundle));
+
+        parse_sw_description(&self.runner.run(&command).await?, &self.options.compatible)
+    }
+
+    async fn operation(&self) -> Result<String, DeviceManagerError> {
+        Ok("idle".to_owned())
+    }
+
+    /// The image is installed by `install_bundle` itself.
+    async fn progress(&self) -> Result<i32, DeviceManagerError> {
+        Ok(self.outcome.progress())
+    }
+
+    async fn compatible(&self) -> Result<String, DeviceManagerError> {
+        Ok(self.options.compatible.clone())
+    }
+
+    async fn boot_slot(&self) -> Result<String, DeviceManagerError> {
+        let slot = self.runner.run(&self.options.boot_slot_command).await?;
+
+        Ok(slot.trim().to_owned())
+    }
+
+    async fn receive_completed(&self) -> Result<i32, DeviceManagerError> {
+        self.outcome.take()
+    }
+
+    /// The bootloader boots the new slot once installed, the primary is the booted one.
+    async fn get_primary(&self) -> Result<String, DeviceManagerError> {
+        self.boot_slot().await
+    }
+
+    /// The bootloader already gave up on a slot marked bad, only the good one is confirmed.
+    async fn mark(
+        &self,
+        state: &str,
+        slot_identifier: &str,
+    ) -> Result<(String, String), DeviceManagerError> {
+        if state == "good" {
+            for command in &self.options.confirm_commands {
+                self.runner.run(command).await?;
+            }
+        }
+
+        Ok((
+            slot_identifier.to_owned(),
+            format!("marked slot {slot_identifier} as {state}"),
+        ))
+    }
+
+    async fn health_check(&self) -> Result<(), DeviceManagerError> {
+        self.boot_slot().await.map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mockall::predicate::eq;
+
+    use crate::error::DeviceManagerError;
+    use crate::ota::image::MockCommandRunner;
+    use crate::ota::swupdate::{parse_sw_description, SwUpdateDeployer, SwUpdateOptions};
+    use crate::ota::OTA;
+
+    const SW_DESCRIPTION: &str = r#"software =
+{
+    version = "2.0.0";
+    hardware-compatibility: [ "1.0", "board-x" ];
+    stable = {
+        copy2: {
+            images: ( { filename = "rootfs.ext4.gz"; device = "/dev/mmcblk0p3"; } );
+        };
+    };
+}
+"#;
+
+    fn options() -> SwUpdateOptions {
+        SwUpdateOptions {
+            compatible: "board-x".to_owned(),
+            boot_slot_command: "fw_printenv -n rootfs_slot".to_owned(),
+            selections: HashMap::from([
+                ("a".to_owned(), "stable,copy2".to_owned()),
+                ("b".to_owned(), "stable,copy1".to_owned()),
+            ]),
+            public_key: Some("/etc/swupdate/public.pem".into()),
+            inspect_command: None,
+            confirm_commands: vec!["fw_setenv upgrade_available 0".to_owned()],
+        }
+    }
+
+    #[test]
+    fn sw_description_parsed() {
+        let info = parse_sw_description(SW_DESCRIPTION, "board-x").unwrap();
+        assert_eq!(info.version, "2.0.0");
+        assert_eq!(info.compatible, "board-x");
+
+        let info = parse_sw_description(SW_DESCRIPTION, "board-y").unwrap();
+        assert_eq!(info.compatible, "1.0");
+        assert!(parse_sw_description("software = { version = \"2.0.0\"; }", "board-x").is_err());
+    }
+
+    #[tokio::test]
+    async fn image_installed_in_the_slot_not_booted() {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .with(eq("fw_printenv -n rootfs_slot".to_owned()))
+            .returning(|_| Ok("a\n".to_owned()));
+        runner
+            .expect_run()
+            .with(eq(
+                "swupdate -v -i '/data/update.bin' -e 'stable,copy2' -k '/etc/swupdate/public.pem'"
+                    .to_owned(),
+            ))
+            .times(1)
+            .returning(|_| Ok(String::new()));
+
+        let deployer = SwUpdateDeployer::new(options(), Box::new(runner));
+        deployer.install_bundle("/data/update.bin").await.unwrap();
+        assert_eq!(deployer.receive_completed().await.unwrap(), 0);
+        assert_eq!(deployer.boot_slot().await.unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn failed_install_reported() {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .with(eq("fw_printenv -n rootfs_slot".to_owned()))
+            .returning(|_| Ok("c\n".to_owned()));
+
+        let deployer = SwUpdateDeployer::new(options(), Box::new(runner));
+        assert!(matches!(
+            deployer.install_bundle("/data/update.bin").await,
+            Err(DeviceManagerError::UpdateError(_))
+        ));
+        assert_eq!(deployer.receive_completed().await.unwrap(), 1);
+        assert!(deployer.last_error().await.unwrap().contains("slot c"));
+    }
+}
@@ -0,0 +1,793 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Verification pipeline for downloaded artifacts.
+//!
+//! The content checks (checksum and signature) are computed while streaming the file once, the
+//! metadata constraints are only inspected when the content checks passed, so that untrusted
+//! artifacts never reach the metadata reader.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use openssl::error::ErrorStack;
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::error::DeviceManagerError;
+use crate::ota::ota_handler::OTAError;
+use crate::ota::signature::{self, SignatureVerifier, TrustedKeySet};
+use crate::ota::OTA;
+
+/// Signature of an artifact, checked against a set of trusted keys.
+#[derive(Debug, Clone)]
+pub struct SignatureCheck {
+    pub keys: TrustedKeySet,
+    pub key_id: String,
+    pub signature: Vec<u8>,
+    pub now: DateTime<Utc>,
+}
+
+/// The checks to run on an artifact, every check left unset is skipped.
+#[derive(Debug, Clone, Default)]
+pub struct VerificationSpec {
+    /// Expected SHA-256 digest of the content.
+    pub sha256: Option<[u8; 32]>,
+    /// Fail when no checksum is provided.
+    pub checksum_required: bool,
+    pub signature: Option<SignatureCheck>,
+    /// Fail when no signature is provided.
+    pub signature_required: bool,
+    /// Check that the artifact metadata is compatible with the system.
+    pub compatibility: bool,
+    /// How the content check failures affect the update, checks in warn mode never stop the
+    /// pipeline.
+    pub enforcement: EnforcementOptions,
+}
+
+#[derive(Error, Debug)]
+pub enum VerificationFailure {
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    #[error("missing checksum")]
+    MissingChecksum,
+    #[error("missing signature")]
+    MissingSignature,
+    #[error("unknown signing key")]
+    UnknownSigningKey,
+    #[error("expired signing key")]
+    ExpiredSigningKey,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("artifact '{artifact}' is not compatible with '{system}'")]
+    Incompatible { system: String, artifact: String },
+    #[error("unable to read the artifact")]
+    Unreadable(std::io::ErrorKind),
+    #[error("unable to read the artifact metadata")]
+    Metadata(#[source] DeviceManagerError),
+}
+
+impl From<VerificationFailure> for DeviceManagerError {
+    fn from(failure: VerificationFailure) -> Self {
+        match failure {
+            VerificationFailure::ChecksumMismatch | VerificationFailure::MissingChecksum => {
+                OTAError::ChecksumMismatch.into()
+            }
+            VerificationFailure::MissingSignature | VerificationFailure::InvalidSignature => {
+                OTAError::InvalidSignature.into()
+            }
+            VerificationFailure::UnknownSigningKey => OTAError::UnknownSigningKey.into(),
+            VerificationFailure::ExpiredSigningKey => OTAError::ExpiredSigningKey.into(),
+            VerificationFailure::Incompatible { .. } => {
+                DeviceManagerError::UpdateError("bundle is not compatible".to_owned())
+            }
+            VerificationFailure::Unreadable(kind) => DeviceManagerError::IOError(kind.into()),
+            VerificationFailure::Metadata(err) => err,
+        }
+    }
+}
+
+/// How a failed content check affects the update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnforcementMode {
+    /// The check is not run.
+    Off,
+    /// The check runs and its verdict is reported, but the update proceeds.
+    Warn,
+    /// A failed check denies the update.
+    Enforce,
+}
+
+impl Display for EnforcementMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EnforcementMode::Off => "off",
+            EnforcementMode::Warn => "warn",
+            EnforcementMode::Enforce => "enforce",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Enforcement mode of each content check.
+///
+/// Checksums are off unless configured, so that the fleet readiness can be measured in warn
+/// mode before enforcing them. Off, they are only left unchecked when the request has none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct EnforcementOptions {
+    pub checksum: EnforcementMode,
+    pub signature: EnforcementMode,
+}
+
+impl Default for EnforcementOptions {
+    fn default() -> Self {
+        EnforcementOptions {
+            checksum: EnforcementMode::Off,
+            signature: EnforcementMode::Enforce,
+        }
+    }
+}
+
+/// Verdict of a content check that ran.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Verdict {
+    pub check: String,
+    pub mode: EnforcementMode,
+    pub passed: bool,
+    pub outcome: String,
+}
+
+#[derive(Debug)]
+pub enum CheckOutcome {
+    Skipped,
+    Passed,
+    Failed(VerificationFailure),
+}
+
+impl CheckOutcome {
+    fn failed(&self) -> bool {
+        matches!(self, CheckOutcome::Failed(_))
+    }
+}
+
+/// Outcome of every check of a [`VerificationSpec`].
+#[derive(Debug)]
+pub struct VerificationReport {
+    pub checksum: CheckOutcome,
+    pub signature: CheckOutcome,
+    pub compatibility: CheckOutcome,
+    /// Metadata of the artifact, once inspected and found compatible.
+    pub artifact: Option<ArtifactInfo>,
+    pub enforcement: EnforcementOptions,
+}
+
+impl VerificationReport {
+    /// Apply the enforcement modes to the content checks.
+    ///
+    /// Returns the verdict of every content check that ran and the first failure denying the
+    /// update in pipeline order, failures of the checks in warn mode are only logged. The compatibility is always
+    /// enforced.
+    pub fn enforce(self) -> (Vec<Verdict>, Result<(), VerificationFailure>) {
+        let mut verdicts = Vec::new();
+        let mut denied = None;
+
+        for (check, mode, outcome) in [
+            ("checksum", self.enforcement.checksum, self.checksum),
+            ("signature", self.enforcement.signature, self.signature),
+        ] {
+            let failure = match outcome {
+                CheckOutcome::Skipped => continue,
+                CheckOutcome::Passed => None,
+                CheckOutcome::Failed(failure) => Some(failure),
+            };
+
+            verdicts.push(Verdict {
+                check: check.to_owned(),
+                mode,
+                passed: failure.is_none(),
+                outcome: failure
+                    .as_ref()
+                    .map_or_else(|| "passed".to_owned(), ToString::to_string),
+            });
+
+            match (mode, failure) {
+                (EnforcementMode::Warn, Some(failure)) => {
+                    warn!("Artifact {check} check failed in warn mode, proceeding: {failure}");
+                }
+                (_, Some(failure)) if denied.is_none() => denied = Some(failure),
+                _ => {}
+            }
+        }
+
+        let result = match (denied, self.compatibility) {
+            (Some(failure), _) | (None, CheckOutcome::Failed(failure)) => Err(failure),
+            (None, _) => Ok(()),
+        };
+
+        (verdicts, result)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArtifactInfo {
+    pub compatible: String,
+    pub version: String,
+}
+
+/// Reads the metadata of an artifact and the system it is verified for.
+#[async_trait]
+pub trait ArtifactMetadata: Send + Sync {
+    async fn inspect(&self, path: &Path) -> Result<ArtifactInfo, DeviceManagerError>;
+    async fn system_compatible(&self) -> Result<String, DeviceManagerError>;
+}
+
+#[async_trait]
+impl<'a> ArtifactMetadata for dyn OTA + 'a {
+    async fn inspect(&self, path: &Path) -> Result<ArtifactInfo, DeviceManagerError> {
+        let bundle_info = self.info(&path.to_string_lossy()).await?;
+        debug!("bundle info: {:?}", bundle_info);
+
+        Ok(ArtifactInfo {
+            compatible: bundle_info.compatible,
+            version: bundle_info.version,
+        })
+    }
+
+    async fn system_compatible(&self) -> Result<String, DeviceManagerError> {
+        OTA::compatible(self).await
+    }
+}
+
+/// Run the checks of `spec` on the artifact at `path`.
+pub async fn verify<M>(path: &Path, spec: &VerificationSpec, metadata: &M) -> VerificationReport
+where
+    M: ArtifactMetadata + ?Sized,
+{
+    let (checksum, signature) = verify_content(path, spec);
+
+    let blocking = |outcome: &CheckOutcome, mode| outcome.failed() && mode != EnforcementMode::Warn;
+
+    let mut artifact = None;
+    let compatibility = if !spec.compatibility {
+        CheckOutcome::Skipped
+    } else if blocking(&checksum, spec.enforcement.checksum)
+        || blocking(&signature, spec.enforcement.signature)
+    {
+        // never hand an untrusted artifact to the metadata reader
+        CheckOutcome::Skipped
+    } else {
+        match verify_compatibility(path, metadata).await {
+            Ok(info) => {
+                artifact = Some(info);
+                CheckOutcome::Passed
+            }
+            Err(failure) => CheckOutcome::Failed(failure),
+        }
+    };
+
+    VerificationReport {
+        checksum,
+        signature,
+        compatibility,
+        artifact,
+        enforcement: spec.enforcement,
+    }
+}
+
+async fn verify_compatibility<M>(
+    path: &Path,
+    metadata: &M,
+) -> Result<ArtifactInfo, VerificationFailure>
+where
+    M: ArtifactMetadata + ?Sized,
+{
+    let info = metadata
+        .inspect(path)
+        .await
+        .map_err(VerificationFailure::Metadata)?;
+    let system = metadata
+        .system_compatible()
+        .await
+        .map_err(VerificationFailure::Metadata)?;
+
+    if info.compatible != system {
+        error!(
+            "artifact '{}' is not compatible with system '{system}'",
+            info.compatible
+        );
+        return Err(VerificationFailure::Incompatible {
+            system,
+            artifact: info.compatible,
+        });
+    }
+
+    Ok(info)
+}
+
+/// Compute the checksum and signature outcomes, reading the file at most once.
+fn verify_content(path: &Path, spec: &VerificationSpec) -> (CheckOutcome, CheckOutcome) {
+    // signature failure known before or while streaming the content
+    let mut failure = None;
+
+    let public_key = match &spec.signature {
+        Some(check) => match check.keys.public_key(&check.key_id, check.now) {
+            Ok(public_key) => Some(public_key),
+            Err(err) => {
+                failure = Some(match err {
+                    OTAError::UnknownSigningKey => VerificationFailure::UnknownSigningKey,
+                    OTAError::ExpiredSigningKey => VerificationFailure::ExpiredSigningKey,
+                    _ => VerificationFailure::InvalidSignature,
+                });
+                None
+            }
+        },
+        None => {
+            if spec.signature_required {
+                error!("Missing signature for the artifact");
+                failure = Some(VerificationFailure::MissingSignature);
+            }
+            None
+        }
+    };
+
+    let mut verifier = public_key.as_ref().and_then(|public_key| {
+        signature::verifier(public_key)
+            .map_err(|err| failure = Some(invalid_signature(err)))
+            .ok()
+    });
+    let mut hasher = spec.sha256.map(|_| Sha256::new());
+
+    if hasher.is_some() || verifier.is_some() {
+        if let Err(kind) = stream(path, hasher.as_mut(), &mut verifier, &mut failure) {
+            let unreadable = || CheckOutcome::Failed(VerificationFailure::Unreadable(kind));
+            let checksum = match spec.sha256 {
+                Some(_) => unreadable(),
+                None if spec.checksum_required => {
+                    CheckOutcome::Failed(VerificationFailure::MissingChecksum)
+                }
+                None => CheckOutcome::Skipped,
+            };
+            let signature = match (failure, verifier) {
+                (Some(failure), _) => CheckOutcome::Failed(failure),
+                (None, Some(_)) => unreadable(),
+                (None, None) => CheckOutcome::Skipped,
+            };
+
+            return (checksum, signature);
+        }
+    }
+
+    let checksum = match (spec.sha256, hasher.map(Sha256::finish)) {
+        (Some(expected), Some(digest)) if digest == expected => CheckOutcome::Passed,
+        (Some(_), _) => {
+            error!("Checksum mismatch for the artifact");
+            CheckOutcome::Failed(VerificationFailure::ChecksumMismatch)
+        }
+        (None, _) if spec.checksum_required => {
+            error!("Missing checksum for the artifact");
+            CheckOutcome::Failed(VerificationFailure::MissingChecksum)
+        }
+        (None, _) => CheckOutcome::Skipped,
+    };
+
+    let signature = match (failure, verifier, &spec.signature) {
+        (Some(failure), _, _) => CheckOutcome::Failed(failure),
+        (None, Some(verifier), Some(check)) => match verifier.verify(&check.signature) {
+            Ok(true) => CheckOutcome::Passed,
+            Ok(false) => {
+                error!("Invalid signature for the artifact");
+                CheckOutcome::Failed(VerificationFailure::InvalidSignature)
+            }
+            Err(err) => CheckOutcome::Failed(invalid_signature(err)),
+        },
+        (None, _, _) => CheckOutcome::Skipped,
+    };
+
+    (checksum, signature)
+}
+
+fn invalid_signature(err: ErrorStack) -> VerificationFailure {
+    warn!("Signature verification error: {:?}", err);
+    VerificationFailure::InvalidSignature
+}
+
+/// Feed the content at `path` to the hasher and the verifier, dropping the verifier on error.
+fn stream(
+    path: &Path,
+    mut hasher: Option<&mut Sha256>,
+    verifier: &mut Option<Box<dyn SignatureVerifier + '_>>,
+    failure: &mut Option<VerificationFailure>,
+) -> Result<(), std::io::ErrorKind> {
+    let mut file = File::open(path).map_err(|err| {
+        error!("Unable to open {}: {err}", path.display());
+        err.kind()
+    })?;
+
+    let mut buffer = [0; 8192];
+    loop {
+        let read = file.read(&mut buffer).map_err(|err| {
+            error!("Unable to read {}: {err}", path.display());
+            err.kind()
+        })?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[..read]);
+        }
+        if let Some(err) = verifier
+            .as_mut()
+            .and_then(|verifier| verifier.update(&buffer[..read]).err())
+        {
+            *verifier = None;
+            *failure = Some(invalid_signature(err));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use openssl::sha::sha256;
+
+    use crate::error::DeviceManagerError;
+    use crate::ota::signature::tests::{generate_key, sign, trusted};
+    use crate::ota::signature::TrustedKeySet;
+    use crate::ota::verification::{
+        verify, ArtifactInfo, ArtifactMetadata, CheckOutcome, EnforcementMode, EnforcementOptions,
+        SignatureCheck, Verdict, VerificationFailure, VerificationSpec,
+    };
+
+    const CONTENT: &[u8] = b"artifact content";
+
+    struct Metadata {
+        compatible: Option<&'static str>,
+        calls: AtomicUsize,
+    }
+
+    impl Metadata {
+        fn new(compatible: Option<&'static str>) -> Self {
+            Metadata {
+                compatible,
+                calls: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ArtifactMetadata for Metadata {
+        async fn inspect(&self, _path: &Path) -> Result<ArtifactInfo, DeviceManagerError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.compatible
+                .map(|compatible| ArtifactInfo {
+                    compatible: compatible.to_owned(),
+                    version: "1.2.0".to_owned(),
+                })
+                .ok_or_else(|| DeviceManagerError::UpdateError("no metadata".to_owned()))
+        }
+
+        async fn system_compatible(&self) -> Result<String, DeviceManagerError> {
+            Ok("rauc-demo-x86".to_owned())
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2022, 6, 1).and_hms(12, 0, 0)
+    }
+
+    fn artifact() -> (tempfile::TempDir, std::path::PathBuf) {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("artifact.bin");
+        std::fs::write(&path, CONTENT).unwrap();
+        (directory, path)
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Expected {
+        Skipped,
+        Passed,
+        Failed(&'static str),
+    }
+
+    fn outcome(outcome: &CheckOutcome) -> Expected {
+        match outcome {
+            CheckOutcome::Skipped => Expected::Skipped,
+            CheckOutcome::Passed => Expected::Passed,
+            CheckOutcome::Failed(failure) => failure_name(failure),
+        }
+    }
+
+    fn failure_name(failure: &VerificationFailure) -> Expected {
+        Expected::Failed(match failure {
+            VerificationFailure::ChecksumMismatch => "checksum",
+            VerificationFailure::MissingChecksum => "missing checksum",
+            VerificationFailure::MissingSignature => "missing",
+            VerificationFailure::UnknownSigningKey => "unknown",
+            VerificationFailure::ExpiredSigningKey => "expired",
+            VerificationFailure::InvalidSignature => "invalid",
+            VerificationFailure::Incompatible { .. } => "incompatible",
+            VerificationFailure::Unreadable(_) => "unreadable",
+            VerificationFailure::Metadata(_) => "metadata",
+        })
+    }
+
+    #[tokio::test]
+    async fn content_check_combinations() {
+        let (_directory, path) = artifact();
+        let key = generate_key();
+        let other = generate_key();
+        let keys = TrustedKeySet {
+            version: 0,
+            keys: vec![
+                trusted("fleet", &key, None),
+                trusted("expired", &key, Some(now() - Duration::days(1))),
+            ],
+        };
+        let check = |key_id: &str, signature: Vec<u8>| SignatureCheck {
+            keys: keys.clone(),
+            key_id: key_id.to_owned(),
+            signature,
+            now: now(),
+        };
+
+        let checksums = [
+            (None, Expected::Skipped),
+            (Some(sha256(CONTENT)), Expected::Passed),
+            (Some(sha256(b"other")), Expected::Failed("checksum")),
+        ];
+        let signatures = [
+            (None, false, Expected::Skipped),
+            (None, true, Expected::Failed("missing")),
+            (
+                Some(check("fleet", sign(&key, CONTENT))),
+                true,
+                Expected::Passed,
+            ),
+            (
+                Some(check("fleet", sign(&key, CONTENT))),
+                false,
+                Expected::Passed,
+            ),
+            (
+                Some(check("fleet", sign(&other, CONTENT))),
+                true,
+                Expected::Failed("invalid"),
+            ),
+            (
+                Some(check("fleet", sign(&key, b"tampered"))),
+                true,
+                Expected::Failed("invalid"),
+            ),
+            (
+                Some(check("other", sign(&other, CONTENT))),
+                true,
+                Expected::Failed("unknown"),
+            ),
+            (
+                Some(check("expired", sign(&key, CONTENT))),
+                true,
+                Expected::Failed("expired"),
+            ),
+        ];
+
+        for (sha256, expected_checksum) in &checksums {
+            for (signature, signature_required, expected_signature) in &signatures {
+                let spec = VerificationSpec {
+                    sha256: *sha256,
+                    signature: signature.clone(),
+                    signature_required: *signature_required,
+                    compatibility: false,
+                    ..Default::default()
+                };
+                let metadata = Metadata::new(None);
+
+                let report = verify(&path, &spec, &metadata).await;
+
+                assert_eq!(outcome(&report.checksum), *expected_checksum, "{spec:?}");
+                assert_eq!(outcome(&report.signature), *expected_signature, "{spec:?}");
+                assert_eq!(outcome(&report.compatibility), Expected::Skipped);
+                assert_eq!(metadata.calls.load(Ordering::SeqCst), 0);
+
+                // the first failure in pipeline order is reported
+                let first = [*expected_checksum, *expected_signature]
+                    .into_iter()
+                    .find(|expected| matches!(expected, Expected::Failed(_)));
+                let failure = report.enforce().1.err();
+                assert_eq!(failure.as_ref().map(failure_name), first);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compatibility_checked_after_content() {
+        let (_directory, path) = artifact();
+
+        let spec = |sha256| VerificationSpec {
+            sha256,
+            compatibility: true,
+            ..Default::default()
+        };
+        let cases = [
+            (
+                Some(sha256(CONTENT)),
+                Some("rauc-demo-x86"),
+                Expected::Passed,
+                1,
+            ),
+            (
+                None,
+                Some("rauc-demo-arm"),
+                Expected::Failed("incompatible"),
+                1,
+            ),
+            (None, None, Expected::Failed("metadata"), 1),
+            // untrusted content never reaches the metadata reader
+            (
+                Some(sha256(b"other")),
+                Some("rauc-demo-x86"),
+                Expected::Skipped,
+                0,
+            ),
+        ];
+
+        for (sha256, compatible, expected, calls) in cases {
+            let metadata = Metadata::new(compatible);
+            let report = verify(&path, &spec(sha256), &metadata).await;
+
+            assert_eq!(outcome(&report.compatibility), expected);
+            assert_eq!(metadata.calls.load(Ordering::SeqCst), calls);
+            assert_eq!(
+                report.artifact.map(|artifact| artifact.version),
+                (expected == Expected::Passed).then(|| "1.2.0".to_owned())
+            );
+        }
+
+        let failure = verify(&path, &spec(None), &Metadata::new(Some("rauc-demo-arm")))
+            .await
+            .enforce()
+            .1
+            .unwrap_err();
+        assert!(matches!(
+            DeviceManagerError::from(failure),
+            DeviceManagerError::UpdateError(message) if message == "bundle is not compatible"
+        ));
+    }
+
+    #[tokio::test]
+    async fn warn_mode_failures_do_not_stop_the_pipeline() {
+        let (_directory, path) = artifact();
+
+        for (mode, denied, calls) in [
+            (EnforcementMode::Warn, false, 1),
+            (EnforcementMode::Enforce, true, 0),
+        ] {
+            let spec = VerificationSpec {
+                sha256: Some(sha256(b"other")),
+                checksum_required: true,
+                signature_required: true,
+                compatibility: true,
+                enforcement: EnforcementOptions {
+                    checksum: mode,
+                    signature: EnforcementMode::Warn,
+                },
+                ..Default::default()
+            };
+            let metadata = Metadata::new(Some("rauc-demo-x86"));
+
+            let (verdicts, result) = verify(&path, &spec, &metadata).await.enforce();
+
+            assert_eq!(
+                verdicts,
+                vec![
+                    Verdict {
+                        check: "checksum".to_owned(),
+                        mode,
+                        passed: false,
+                        outcome: "checksum mismatch".to_owned(),
+                    },
+                    Verdict {
+                        check: "signature".to_owned(),
+                        mode: EnforcementMode::Warn,
+                        passed: false,
+                        outcome: "missing signature".to_owned(),
+                    }
+                ]
+            );
+            assert_eq!(
+                result.as_ref().err().map(failure_name),
+                denied.then(|| Expected::Failed("checksum")),
+                "{mode}"
+            );
+            assert_eq!(metadata.calls.load(Ordering::SeqCst), calls, "{mode}");
+        }
+
+        // without a checksum, the check fails only when required
+        let spec = |checksum_required| VerificationSpec {
+            checksum_required,
+            enforcement: EnforcementOptions {
+                checksum: EnforcementMode::Enforce,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (verdicts, result) = verify(&path, &spec(false), &Metadata::new(None))
+            .await
+            .enforce();
+        assert!(verdicts.is_empty() && result.is_ok());
+        let (_, result) = verify(&path, &spec(true), &Metadata::new(None))
+            .await
+            .enforce();
+        assert_eq!(
+            result.as_ref().err().map(failure_name),
+            Some(Expected::Failed("missing checksum"))
+        );
+    }
+
+    #[tokio::test]
+    async fn unreadable_artifact() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("missing.bin");
+        let key = generate_key();
+
+        // nothing to check, the artifact is never opened
+        let report = verify(&path, &VerificationSpec::default(), &Metadata::new(None)).await;
+        assert!(report.enforce().1.is_ok());
+
+        let spec = VerificationSpec {
+            sha256: Some(sha256(CONTENT)),
+            signature: Some(SignatureCheck {
+                keys: TrustedKeySet {
+                    version: 0,
+                    keys: vec![trusted("fleet", &key, None)],
+                },
+                key_id: "fleet".to_owned(),
+                signature: sign(&key, CONTENT),
+                now: now(),
+            }),
+            signature_required: true,
+            compatibility: true,
+            ..Default::default()
+        };
+        let metadata = Metadata::new(Some("rauc-demo-x86"));
+        let report = verify(&path, &spec, &metadata).await;
+
+        assert_eq!(outcome(&report.checksum), Expected::Failed("unreadable"));
+        assert_eq!(outcome(&report.signature), Expected::Failed("unreadable"));
+        assert_eq!(outcome(&report.compatibility), Expected::Skipped);
+        assert_eq!(metadata.calls.load(Ordering::SeqCst), 0);
+        assert!(matches!(
+            report.enforce().1.map_err(DeviceManagerError::from),
+            Err(DeviceManagerError::IOError(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Supervision of the task handling the OTA requests.
+//!
+//! The requests are handed over to the task without waiting, so that a stuck task can't block
+//! the dispatch of the other messages: a request finding the queue full is refused as another
+//! update in progress, and one finding the task dead fails while the task is started again.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use astarte_sdk::types::AstarteType;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::event_log::EventLog;
+use crate::interfaces::OTA_RESPONSE_INTERFACE;
+use crate::ota::messages::OtaRequest;
+use crate::ota::ota_handler::{stamped_response, OTAError, OTAStatus};
+use crate::redaction::redactor;
+
+/// Requests waiting for the task, the ones beyond are refused.
+pub(crate) const OTA_QUEUE_CAPACITY: usize = 32;
+
+type SpawnTask = dyn Fn(Receiver<HashMap<String, AstarteType>>) -> JoinHandle<()> + Send + Sync;
+
+struct Running {
+    requests: Sender<HashMap<String, AstarteType>>,
+    task: JoinHandle<()>,
+}
+
+/// Task handling the OTA requests, started again when it dies.
+pub struct OtaWorker {
+    clock: Arc<dyn Clock>,
+    capacity: usize,
+    spawn: Box<SpawnTask>,
+    /// Unset once stopped.
+    running: Mutex<Option<Running>>,
+    event_log: Option<Arc<EventLog>>,
+    /// Requests refused without reaching the task, answered by [`OtaWorker::run`].
+    rejected: Mutex<VecDeque<(Uuid, OTAError)>>,
+    changed: Notify,
+}
+
+impl OtaWorker {
+    /// Start the task with `spawn`, which gets the queue of the requests, `capacity` long.
+    pub fn start<F>(clock: Arc<dyn Clock>, capacity: usize, spawn: F) -> Self
+    where
+        F: Fn(Receiver<HashMap<String, AstarteType>>) -> JoinHandle<()> + Send + Sync + 'static,
+    {
+        let worker = OtaWorker {
+            clock,
+            capacity,
+            spawn: Box::new(spawn),
+            running: Mutex::new(None),
+            event_log: None,
+            rejected: Mutex::new(VecDeque::new()),
+            changed: Notify::new(),
+        };
+        *worker.running.lock().unwrap() = Some(worker.spawn_task());
+
+        worker
+    }
+
+    /// Number the responses to the refused requests in `event_log`, when enabled.
+    pub fn with_event_log(mut self, event_log: Option<Arc<EventLog>>) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    fn spawn_task(&self) -> Running {
+        let (requests, receiver) = mpsc::channel(self.capacity);
+
+        Running {
+            requests,
+            task: (self.spawn)(receiver),
+        }
+    }
+
+    /// Hand the request `data` over to the task, refusing it when the queue is full. When the
+    /// task died the request fails and the task is started again.
+    pub fn submit(&self, data: HashMap<String, AstarteType>) {
+        let mut running = self.running.lock().unwrap();
+        let current = match running.as_mut() {
+            Some(current) => current,
+            None => {
+                warn!("OTA stopped, ignoring the request");
+                return;
+            }
+        };
+
+        let (data, err) = match current.requests.try_send(data) {
+            Ok(()) => return,
+            Err(TrySendError::Full(data)) => {
+                warn!("The OTA requests queue is full, refusing the request");
+                (data, OTAError::UpdateAlreadyInProgress)
+            }
+            Err(TrySendError::Closed(data)) => {
+                error!("The OTA task stopped, starting it again");
+                *current = self.spawn_task();
+                (data, OTAError::Failed)
+            }
+        };
+        drop(running);
+
+        match OtaRequest::try_from(&data) {
+            Ok(request) => {
+                self.rejected.lock().unwrap().push_back((request.uuid, err));
+                self.changed.notify_one();
+            }
+            Err(err) => error!("Invalid OTARequest ({}): {}", redactor().object(&data), err),
+        }
+    }
+
+    /// Close the queue, returning the task to wait for: it completes the request it is serving.
+    pub fn stop(&self) -> Option<JoinHandle<()>> {
+        self.running
+            .lock()
+            .unwrap()
+            .take()
+            .map(|running| running.task)
+    }
+
+    /// Publish the responses to the refused requests.
+    pub async fn run(&self, publisher: &impl Publisher) {
+        loop {
+            let rejected = self.rejected.lock().unwrap().pop_front();
+            let (uuid, err) = match rejected {
+                Some(rejected) => rejected,
+                None => {
+                    self.changed.notified().await;
+                    continue;
+                }
+            };
+
+            let response = stamped_response(
+                self.event_log.as_deref(),
+                publisher,
+                uuid,
+                &OTAStatus::Error(err),
+            )
+            .await;
+            let timestamp = DateTime::<Utc>::from(self.clock.now_wall());
+            if let Err(err) = publisher
+                .send_object_with_timestamp(
+                    OTA_RESPONSE_INTERFACE,
+                    "/response",
+                    response,
+                    timestamp,
+                )
+                .await
+            {
+                warn!("Unable to publish OTA response -> {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use crate::capabilities::{CapabilityReport, Feature};
+    use crate::dispatch::{Dispatch, Dispatcher};
+    use crate::ota::messages::OtaRequest;
+    use crate::ota::worker::OtaWorker;
+    use crate::test_utils::harness::{self, ScriptedSession};
+    use crate::test_utils::{settle, ManualClock};
+
+    fn request(uuid: Uuid) -> OtaRequest {
+        OtaRequest::new(uuid, "http://ota.bin")
+    }
+
+    #[tokio::test]
+    async fn dead_task_started_again() {
+        let clock = Arc::new(ManualClock::new());
+        let spawns = Arc::new(AtomicUsize::new(0));
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let worker = {
+            let spawns = spawns.clone();
+            let served = served.clone();
+            Arc::new(OtaWorker::start(clock.clone(), 4, move |mut requests| {
+                let first = spawns.fetch_add(1, Ordering::SeqCst) == 0;
+                let served = served.clone();
+                tokio::spawn(async move {
+                    while let Some(data) = requests.recv().await {
+                        if first {
+                            panic!("simulated crash of the OTA task");
+                        }
+                        let request = OtaRequest::try_from(&data).unwrap();
+                        served.lock().unwrap().push(request.uuid);
+                    }
+                })
+            }))
+        };
+        let session = ScriptedSession::builder(clock.clone()).build();
+        let rejections = {
+            let worker = worker.clone();
+            let session = session.clone();
+            tokio::spawn(async move { worker.run(&session).await })
+        };
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let dispatcher = Dispatcher::new(
+            worker.clone(),
+            config_tx,
+            None,
+            CapabilityReport::available(&[Feature::Ota]),
+        );
+
+        let (crashing, failed, next) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let message = |uuid| harness::ota_request_message(request(uuid));
+        assert_eq!(
+            dispatcher.dispatch(&message(crashing)).await,
+            Dispatch::Handled
+        );
+        settle().await;
+        // the request finding the task dead fails, the next one is served by the new task
+        assert_eq!(
+            dispatcher.dispatch(&message(failed)).await,
+            Dispatch::Handled
+        );
+        assert_eq!(dispatcher.dispatch(&message(next)).await, Dispatch::Handled);
+        settle().await;
+
+        assert_eq!(spawns.load(Ordering::SeqCst), 2);
+        assert_eq!(*served.lock().unwrap(), [next]);
+        let responses = session.ota_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].uuid, failed);
+        assert_eq!(responses[0].status, "Error");
+        assert_eq!(responses[0].status_code, "OTAFailed");
+
+        rejections.abort();
+    }
+
+    #[tokio::test]
+    async fn full_queue_refused_without_waiting() {
+        let clock = Arc::new(ManualClock::new());
+        let queue = Arc::new(Mutex::new(None));
+        let worker = {
+            let queue = queue.clone();
+            Arc::new(OtaWorker::start(clock.clone(), 1, move |requests| {
+                // a stuck task, never taking the requests
+                *queue.lock().unwrap() = Some(requests);
+                tokio::spawn(std::future::pending())
+            }))
+        };
+        let session = ScriptedSession::builder(clock.clone()).build();
+        let rejections = {
+            let worker = worker.clone();
+            let session = session.clone();
+            tokio::spawn(async move { worker.run(&session).await })
+        };
+
+        let (queued, refused) = (Uuid::new_v4(), Uuid::new_v4());
+        worker.submit(request(queued).into());
+        worker.submit(request(refused).into());
+        settle().await;
+
+        let responses = session.ota_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].uuid, refused);
+        assert_eq!(responses[0].status_code, "OTAErrorUpdateAlreadyInProgress");
+        let mut requests = queue.lock().unwrap().take().unwrap();
+        let request = OtaRequest::try_from(&requests.try_recv().unwrap()).unwrap();
+        assert_eq!(request.uuid, queued);
+
+        rejections.abort();
+    }
+
+    #[tokio::test]
+    async fn stop_closes_the_queue() {
+        let clock = Arc::new(ManualClock::new());
+        let worker = OtaWorker::start(clock, 1, |mut requests| {
+            tokio::spawn(async move { while requests.recv().await.is_some() {} })
+        });
+
+        let task = worker.stop().unwrap();
+
+        task.await.unwrap();
+        assert!(worker.stop().is_none());
+        worker.submit(request(Uuid::new_v4()).into());
+    }
+}
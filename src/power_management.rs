@@ -18,29 +18,635 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use log::{error, info};
+//! Reboot and power off of the device, requested by the backend commands and by the OTA updates.
+//!
+//! `Reboot <delay_secs>` schedules the reboot instead, until `CancelReboot` or an immediate
+//! `Reboot`. The scheduled time is persisted, so that a restart of the runtime keeps it.
+//!
+//! The power off is refused while an OTA update writes to flash, it would leave a half written
+//! slot. Through logind or systemctl the services are stopped in order, so the runtime persists
+//! the state of the update in progress before the device goes down.
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+#[cfg(test)]
+use mockall::automock;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::clock::Clock;
+use crate::commands::{CommandRequest, CommandResult};
+use crate::data::Publisher;
 use crate::error::DeviceManagerError;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::quiet_hours::QuietHours;
+use crate::repository::StateRepository;
+use crate::wrapper::platform::{platform, PowerBackend, ServiceNotifier};
+
+static QUIET_HOURS: OnceCell<Arc<QuietHours>> = OnceCell::new();
+static FLASH_WRITES: FlashWrites = FlashWrites::new();
+
+/// Refuse the reboots during `quiet_hours`, only the first call has effect.
+pub fn hold_during(quiet_hours: Arc<QuietHours>) {
+    QUIET_HOURS.set(quiet_hours).ok();
+}
+
+/// Count of the writes to flash in progress.
+pub struct FlashWrites {
+    count: AtomicUsize,
+}
+
+/// A write to flash in progress, until dropped.
+pub struct FlashWrite<'a> {
+    writes: &'a FlashWrites,
+}
+
+impl FlashWrites {
+    pub const fn new() -> Self {
+        FlashWrites {
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn begin(&self) -> FlashWrite<'_> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+
+        FlashWrite { writes: self }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.count.load(Ordering::SeqCst) > 0
+    }
+}
+
+impl Drop for FlashWrite<'_> {
+    fn drop(&mut self) {
+        self.writes.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Hold off the power off until the returned guard is dropped, while an update writes to flash.
+pub fn writing_flash() -> FlashWrite<'static> {
+    FLASH_WRITES.begin()
+}
+
+/// The power actions of the commands, the platform ones outside of the tests.
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait PowerActions: Send + Sync {
+    async fn reboot(&self) -> Result<(), DeviceManagerError>;
+    async fn shutdown(&self) -> Result<(), DeviceManagerError>;
+}
+
+pub struct PlatformPower;
+
+#[async_trait]
+impl PowerActions for PlatformPower {
+    async fn reboot(&self) -> Result<(), DeviceManagerError> {
+        reboot().await
+    }
+
+    async fn shutdown(&self) -> Result<(), DeviceManagerError> {
+        shutdown().await
+    }
+}
+
+/// Pause before the reboot into an update, letting its last messages go out.
+pub const UPDATE_REBOOT_DELAY: Duration = Duration::from_secs(5);
+
+/// Reboot through `power` once `delay` has passed on `clock`.
+pub async fn reboot_after(
+    clock: &dyn Clock,
+    power: &dyn PowerActions,
+    delay: Duration,
+) -> Result<(), DeviceManagerError> {
+    info!("Rebooting in {} seconds", delay.as_secs());
+    clock.sleep(delay).await;
+
+    power.reboot().await
+}
+
+/// Reboot through the power backend of the platform, unless held off by the quiet hours.
+pub async fn reboot() -> Result<(), DeviceManagerError> {
+    if QUIET_HOURS
+        .get()
+        .map_or(false, |quiet_hours| quiet_hours.is_active())
+    {
+        warn!("Quiet hours, not rebooting");
+
+        return Err(DeviceManagerError::QuietHours);
+    }
 
-pub fn reboot() -> Result<(), DeviceManagerError> {
     if std::env::var("DM_NO_REBOOT").is_ok() {
         info!("Dry run, exiting");
 
         std::process::exit(0);
     }
 
-    // TODO: use systemd api
-    let mut cmd = std::process::Command::new("shutdown");
-    cmd.arg("-r");
-    cmd.arg("now");
+    platform().power().reboot().await.map_err(|err| {
+        error!("Reboot failed {:?}", err);
+        err
+    })
+}
+
+/// Power off through the power backend of the platform, unless an update is writing to flash.
+pub async fn shutdown() -> Result<(), DeviceManagerError> {
+    if std::env::var("DM_NO_REBOOT").is_ok() && !FLASH_WRITES.in_progress() {
+        info!("Dry run, exiting");
+
+        std::process::exit(0);
+    }
+
+    power_off(platform().power(), platform().notifier(), &FLASH_WRITES).await
+}
+
+async fn power_off(
+    power: &dyn PowerBackend,
+    notifier: &dyn ServiceNotifier,
+    flash_writes: &FlashWrites,
+) -> Result<(), DeviceManagerError> {
+    if flash_writes.in_progress() {
+        warn!("An OTA update is writing to flash, not powering off");
+
+        return Err(DeviceManagerError::FlashWriteInProgress);
+    }
+
+    notifier.status("Powering off");
+    power.power_off().await.map_err(|err| {
+        error!("Power off failed {:?}", err);
+        err
+    })
+}
+
+/// Command rebooting the device, followed by the delay in seconds.
+pub const REBOOT_COMMAND: &str = "Reboot";
+/// Command canceling the scheduled reboot.
+pub const CANCEL_REBOOT_COMMAND: &str = "CancelReboot";
+/// Name of the file keeping the scheduled reboot in the store directory.
+pub const SCHEDULED_REBOOT_FILE: &str = "scheduled_reboot.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootCommand {
+    /// Reboot once the delay has passed, right away when zero.
+    Reboot(Duration),
+    Cancel,
+}
+
+impl RebootCommand {
+    /// Parse `Reboot [delay_secs]` or `CancelReboot`.
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [REBOOT_COMMAND] => Some(RebootCommand::Reboot(Duration::ZERO)),
+            [REBOOT_COMMAND, delay] => delay
+                .parse()
+                .ok()
+                .map(|delay| RebootCommand::Reboot(Duration::from_secs(delay))),
+            [CANCEL_REBOOT_COMMAND] => Some(RebootCommand::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// Persisted until the reboot or its cancellation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledReboot {
+    pub reboot_at: DateTime<Utc>,
+}
+
+/// Holds the reboot scheduled by the commands, published on `/reboot/scheduled` of the
+/// diagnostics interface until it happens or is canceled. A reboot due while the runtime was
+/// stopped happens at the next start.
+pub struct RebootScheduler {
+    clock: Arc<dyn Clock>,
+    power: Box<dyn PowerActions>,
+    repository: Box<dyn StateRepository<ScheduledReboot>>,
+    scheduled: Mutex<Option<ScheduledReboot>>,
+    results: Option<mpsc::Sender<CommandResult>>,
+}
+
+impl RebootScheduler {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        power: Box<dyn PowerActions>,
+        repository: Box<dyn StateRepository<ScheduledReboot>>,
+    ) -> Self {
+        let scheduled = repository
+            .exists()
+            .then(|| repository.read())
+            .and_then(|read| {
+                read.map_err(|err| warn!("Unable to read the scheduled reboot: {:?}", err))
+                    .ok()
+            });
+
+        RebootScheduler {
+            clock,
+            power,
+            repository,
+            scheduled: Mutex::new(scheduled),
+            results: None,
+        }
+    }
 
-    let output = cmd.output()?;
+    /// Acknowledge the result of each command on `results`.
+    pub fn with_results(mut self, results: mpsc::Sender<CommandResult>) -> Self {
+        self.results = Some(results);
+        self
+    }
 
-    if output.status.success() && output.stderr.is_empty() {
-        panic!("Reboot command was successful, bye");
-    } else {
-        error!("Reboot failed {:?}", output.stderr);
+    fn now(&self) -> DateTime<Utc> {
+        self.clock.now_wall().into()
     }
 
-    Ok(())
+    /// Time left before the scheduled reboot, if any.
+    fn remaining(&self) -> Option<Duration> {
+        self.scheduled.lock().unwrap().as_ref().map(|scheduled| {
+            (scheduled.reboot_at - self.now())
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+        })
+    }
+
+    fn unschedule(&self) -> Option<ScheduledReboot> {
+        if let Err(err) = self.repository.clear() {
+            warn!("Unable to clear the scheduled reboot: {:?}", err);
+        }
+
+        self.scheduled.lock().unwrap().take()
+    }
+
+    pub async fn handle(
+        &self,
+        publisher: &impl Publisher,
+        command: RebootCommand,
+    ) -> Result<(), DeviceManagerError> {
+        match command {
+            RebootCommand::Reboot(Duration::ZERO) => {
+                if let Some(overridden) = self.unschedule() {
+                    info!("Reboot scheduled at {} overridden", overridden.reboot_at);
+                }
+                self.power.reboot().await
+            }
+            RebootCommand::Reboot(delay) => self.schedule(publisher, delay).await,
+            RebootCommand::Cancel => match self.unschedule() {
+                Some(canceled) => {
+                    info!("Reboot scheduled at {} canceled", canceled.reboot_at);
+                    self.send(
+                        publisher,
+                        "/reboot/canceled",
+                        AstarteType::String(canceled.reboot_at.to_rfc3339()),
+                    )
+                    .await;
+                    Ok(())
+                }
+                None => {
+                    warn!("No reboot scheduled, nothing to cancel");
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    async fn schedule(
+        &self,
+        publisher: &impl Publisher,
+        delay: Duration,
+    ) -> Result<(), DeviceManagerError> {
+        let reboot_at = chrono::Duration::from_std(delay)
+            .ok()
+            .and_then(|delay| self.now().checked_add_signed(delay));
+        let scheduled = match reboot_at {
+            Some(reboot_at) => ScheduledReboot { reboot_at },
+            None => {
+                return Err(DeviceManagerError::InvalidCommand(format!(
+                    "reboot delay of {delay:?} out of range"
+                )));
+            }
+        };
+        if let Some(previous) = self.scheduled.lock().unwrap().replace(scheduled) {
+            info!("Reboot scheduled at {} moved", previous.reboot_at);
+        }
+        if let Err(err) = self.repository.write(&scheduled) {
+            warn!("Unable to persist the scheduled reboot: {:?}", err);
+        }
+
+        info!("Reboot scheduled at {}", scheduled.reboot_at);
+        self.publish_scheduled(publisher, scheduled).await;
+
+        Ok(())
+    }
+
+    async fn publish_scheduled(&self, publisher: &impl Publisher, scheduled: ScheduledReboot) {
+        self.send(
+            publisher,
+            "/reboot/scheduled",
+            AstarteType::String(scheduled.reboot_at.to_rfc3339()),
+        )
+        .await;
+    }
+
+    /// Reboot when the scheduled time has come, returns whether it had.
+    pub async fn reboot_if_due(&self) -> bool {
+        if self.remaining() != Some(Duration::ZERO) {
+            return false;
+        }
+
+        if let Some(due) = self.unschedule() {
+            info!("Reboot scheduled at {} due", due.reboot_at);
+            // the failure is logged, the device keeps running
+            self.power.reboot().await.ok();
+        }
+
+        true
+    }
+
+    pub async fn run(
+        &self,
+        publisher: &impl Publisher,
+        mut commands: mpsc::Receiver<CommandRequest>,
+    ) {
+        let scheduled = *self.scheduled.lock().unwrap();
+        if let Some(scheduled) = scheduled {
+            self.publish_scheduled(publisher, scheduled).await;
+        }
+
+        loop {
+            let remaining = self.remaining();
+            let due = async {
+                match remaining {
+                    Some(remaining) => self.clock.sleep(remaining).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                request = commands.recv() => match request {
+                    Some(request) => self.acknowledge(publisher, request).await,
+                    None => break,
+                },
+                _ = due => {
+                    self.reboot_if_due().await;
+                }
+            }
+        }
+    }
+
+    async fn acknowledge(&self, publisher: &impl Publisher, request: CommandRequest) {
+        let result = match RebootCommand::from_command(&request.command) {
+            Some(command) => self.handle(publisher, command).await,
+            None => Err(DeviceManagerError::InvalidCommand(request.command.clone())),
+        };
+
+        if let Some(results) = &self.results {
+            if results
+                .send(CommandResult::new(&request, &result))
+                .await
+                .is_err()
+            {
+                warn!("The command results stopped");
+            }
+        }
+    }
+
+    async fn send(&self, publisher: &impl Publisher, path: &str, data: AstarteType) {
+        if let Err(err) = publisher.send(DIAGNOSTICS_INTERFACE, path, data).await {
+            warn!("Unable to publish {path}: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+
+    use crate::commands::{CommandRequest, CommandResult};
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::power_management::{
+        power_off, FlashWrites, MockPowerActions, RebootCommand, RebootScheduler, ScheduledReboot,
+    };
+    use crate::test_utils::{
+        recording_publisher, settle, ManualClock, MemoryStateRepository, Recording,
+    };
+    use crate::wrapper::platform::{MockPowerBackend, NoopNotifier};
+
+    type Sent = Recording<()>;
+
+    /// Paths of the strings sent on the diagnostics interface.
+    fn paths(sent: &Sent) -> Vec<String> {
+        sent.values(DIAGNOSTICS_INTERFACE)
+            .into_iter()
+            .map(|(path, data)| {
+                assert!(matches!(data, AstarteType::String(_)));
+                path
+            })
+            .collect()
+    }
+
+    /// Scheduler counting its reboots, running until the returned sender is dropped.
+    fn start(
+        clock: &Arc<ManualClock>,
+        repository: &Arc<MemoryStateRepository<ScheduledReboot>>,
+    ) -> (mpsc::Sender<CommandRequest>, Arc<AtomicUsize>, Sent) {
+        let reboots = Arc::new(AtomicUsize::new(0));
+        let counter = reboots.clone();
+        let mut power = MockPowerActions::new();
+        power.expect_reboot().returning(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        let scheduler =
+            RebootScheduler::new(clock.clone(), Box::new(power), Box::new(repository.clone()));
+        let (publisher, sent) = recording_publisher();
+        let (commands_tx, commands) = mpsc::channel(4);
+        tokio::spawn(async move { scheduler.run(&publisher, commands).await });
+
+        (commands_tx, reboots, sent)
+    }
+
+    #[test]
+    fn command_parsed() {
+        assert_eq!(
+            RebootCommand::from_command("Reboot"),
+            Some(RebootCommand::Reboot(Duration::ZERO))
+        );
+        assert_eq!(
+            RebootCommand::from_command("Reboot 600"),
+            Some(RebootCommand::Reboot(Duration::from_secs(600)))
+        );
+        assert_eq!(
+            RebootCommand::from_command("CancelReboot"),
+            Some(RebootCommand::Cancel)
+        );
+        assert_eq!(RebootCommand::from_command("Reboot -1"), None);
+        assert_eq!(RebootCommand::from_command("Reboot 1 2"), None);
+        assert_eq!(RebootCommand::from_command("Shutdown"), None);
+    }
+
+    #[tokio::test]
+    async fn scheduled_reboot_waits_its_delay() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let (commands, reboots, sent) = start(&clock, &repository);
+
+        commands
+            .send(CommandRequest::parse("Reboot 600"))
+            .await
+            .unwrap();
+        settle().await;
+        assert_eq!(paths(&sent), ["/reboot/scheduled"]);
+        assert!(repository.value().is_some());
+
+        clock.advance(Duration::from_secs(599));
+        settle().await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 0);
+
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 1);
+        assert_eq!(repository.value(), None);
+    }
+
+    #[tokio::test]
+    async fn immediate_reboot_overrides_schedule() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let (commands, reboots, _) = start(&clock, &repository);
+
+        commands
+            .send(CommandRequest::parse("Reboot 600"))
+            .await
+            .unwrap();
+        commands
+            .send(CommandRequest::parse("Reboot"))
+            .await
+            .unwrap();
+        settle().await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 1);
+        assert_eq!(repository.value(), None);
+
+        // the schedule is gone
+        clock.advance(Duration::from_secs(600));
+        settle().await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn results_acknowledged() {
+        let clock = Arc::new(ManualClock::new());
+        let mut power = MockPowerActions::new();
+        power
+            .expect_reboot()
+            .returning(|| Err(DeviceManagerError::QuietHours));
+        let (results_tx, mut results) = mpsc::channel(4);
+        let scheduler = RebootScheduler::new(
+            clock.clone(),
+            Box::new(power),
+            Box::new(MemoryStateRepository::new()),
+        )
+        .with_results(results_tx);
+        let (publisher, _) = recording_publisher::<()>();
+        let (commands, commands_rx) = mpsc::channel(4);
+        tokio::spawn(async move { scheduler.run(&publisher, commands_rx).await });
+
+        for command in ["Reboot 600 requestId=1", "Reboot requestId=2"] {
+            commands.send(CommandRequest::parse(command)).await.unwrap();
+        }
+        settle().await;
+
+        let result = |request_id: &str, error: &str| CommandResult {
+            command: "Reboot".to_owned(),
+            success: error.is_empty(),
+            error: error.to_owned(),
+            request_id: request_id.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(results.try_recv().unwrap(), result("1", ""));
+        assert_eq!(
+            results.try_recv().unwrap(),
+            result("2", "held off by the quiet hours")
+        );
+    }
+
+    #[tokio::test]
+    async fn canceled_reboot_not_run() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let (commands, reboots, sent) = start(&clock, &repository);
+
+        // nothing to cancel
+        commands
+            .send(CommandRequest::parse("CancelReboot"))
+            .await
+            .unwrap();
+        commands
+            .send(CommandRequest::parse("Reboot 600"))
+            .await
+            .unwrap();
+        commands
+            .send(CommandRequest::parse("CancelReboot"))
+            .await
+            .unwrap();
+        settle().await;
+        assert_eq!(paths(&sent), ["/reboot/scheduled", "/reboot/canceled"]);
+        assert_eq!(repository.value(), None);
+
+        clock.advance(Duration::from_secs(600));
+        settle().await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn scheduled_reboot_survives_restart() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        let (commands, _, _) = start(&clock, &repository);
+        commands
+            .send(CommandRequest::parse("Reboot 600"))
+            .await
+            .unwrap();
+        settle().await;
+        drop(commands);
+        settle().await;
+
+        let (_commands, reboots, sent) = start(&clock, &repository);
+        settle().await;
+        // published again at the start
+        assert_eq!(paths(&sent), ["/reboot/scheduled"]);
+
+        clock.advance(Duration::from_secs(600));
+        settle().await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn power_off_refused_while_writing_flash() {
+        let flash_writes = FlashWrites::new();
+        let mut power = MockPowerBackend::new();
+        power.expect_power_off().times(1).returning(|| Ok(()));
+
+        let write = flash_writes.begin();
+        assert!(matches!(
+            power_off(&power, &NoopNotifier, &flash_writes).await,
+            Err(DeviceManagerError::FlashWriteInProgress)
+        ));
+
+        drop(write);
+        power_off(&power, &NoopNotifier, &flash_writes)
+            .await
+            .unwrap();
+    }
 }
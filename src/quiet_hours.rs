@@ -0,0 +1,586 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Quiet hours, during which the remote actions are held off.
+//!
+//! The ranges are evaluated on the local wall clock, so they keep their local times across the
+//! DST changes: the night of a change they get shorter or longer by the shift. A range ending
+//! before its start crosses midnight, its weekdays are the days it starts on. The backend can
+//! replace the configured ranges through a property, unsetting it restores them.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use astarte_sdk::types::AstarteType;
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::diagnostics_window::WINDOW_COMMAND;
+use crate::event_log::EventLog;
+use crate::interfaces::{DIAGNOSTICS_INTERFACE, QUIET_HOURS_INTERFACE};
+use crate::power_management::CANCEL_REBOOT_COMMAND;
+use crate::repository::StateRepository;
+use crate::telemetry::schedule::SCHEDULE_COMMAND;
+
+/// Acknowledgement of the commands rejected during the quiet hours.
+pub const QUIET_HOURS_CODE: &str = "QuietHours";
+/// Commands still run during the quiet hours.
+pub const EXEMPT_COMMANDS: [&str; 4] = [
+    "Ping",
+    WINDOW_COMMAND,
+    SCHEDULE_COMMAND,
+    CANCEL_REBOOT_COMMAND,
+];
+/// Name of the file keeping the ranges set by the backend in the store directory.
+pub const RANGES_FILE: &str = "quiet_hours.json";
+/// Resolution of the changes of the quiet hours.
+const STEP: Duration = Duration::from_secs(60);
+/// Longest look ahead for a change, past a whole week the ranges repeat.
+const HORIZON: Duration = Duration::from_secs(8 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QuietHoursOptions {
+    #[serde(default)]
+    pub ranges: Vec<RangeConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeConfig {
+    /// Local time the range starts at, `HH:MM`.
+    pub start: String,
+    /// Local time the range ends at, before `start` when the range crosses midnight.
+    pub end: String,
+    /// Days the range starts on, e.g. `mon`, every day when empty.
+    #[serde(default)]
+    pub weekdays: Vec<String>,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RangeError {
+    #[error("invalid time {0}, expected HH:MM")]
+    Time(String),
+    #[error("invalid weekday {0}")]
+    Weekday(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    start: NaiveTime,
+    end: NaiveTime,
+    /// Bit `n` set when the range starts on the day `n` days from Monday.
+    weekdays: u8,
+}
+
+impl Range {
+    fn parse(config: &RangeConfig) -> Result<Self, RangeError> {
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| RangeError::Time(time.to_owned()))
+        };
+
+        let weekdays = if config.weekdays.is_empty() {
+            0b111_1111
+        } else {
+            config.weekdays.iter().try_fold(0, |mask, day| {
+                Weekday::from_str(day)
+                    .map(|day| mask | (1 << day.num_days_from_monday()))
+                    .map_err(|_| RangeError::Weekday(day.clone()))
+            })?
+        };
+
+        Ok(Range {
+            start: time(&config.start)?,
+            end: time(&config.end)?,
+            weekdays,
+        })
+    }
+
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.weekdays & (1 << day.num_days_from_monday()) != 0
+    }
+
+    fn contains(&self, local: NaiveDateTime) -> bool {
+        let (day, time) = (local.weekday(), local.time());
+        if self.start < self.end {
+            return self.starts_on(day) && self.start <= time && time < self.end;
+        }
+
+        // the early hours belong to the range started the day before
+        (self.starts_on(day) && time >= self.start)
+            || (self.starts_on(day.pred()) && time < self.end)
+    }
+}
+
+fn parse_ranges(configs: &[RangeConfig]) -> Result<Vec<Range>, RangeError> {
+    configs.iter().map(Range::parse).collect()
+}
+
+/// Conversion of the instants to the local wall clock.
+pub trait Zone: Send + Sync {
+    fn local(&self, at: SystemTime) -> NaiveDateTime;
+}
+
+/// The timezone of the system.
+pub struct LocalZone;
+
+impl Zone for LocalZone {
+    fn local(&self, at: SystemTime) -> NaiveDateTime {
+        DateTime::<Local>::from(at).naive_local()
+    }
+}
+
+/// Change of the `/request/ranges` property, holding the ranges as a JSON array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuietHoursEvent {
+    Set(Vec<RangeConfig>),
+    Unset,
+}
+
+impl QuietHoursEvent {
+    pub fn from_property(path: &[&str], value: &AstarteType) -> Option<Self> {
+        match (path, value) {
+            (["request", "ranges"], AstarteType::String(ranges)) => {
+                serde_json::from_str(ranges).ok().map(QuietHoursEvent::Set)
+            }
+            (["request", "ranges"], AstarteType::Unset) => Some(QuietHoursEvent::Unset),
+            _ => None,
+        }
+    }
+}
+
+pub struct QuietHours {
+    clock: Arc<dyn Clock>,
+    zone: Box<dyn Zone>,
+    configured: Vec<Range>,
+    /// Ranges set by the backend, in place of the configured ones.
+    remote: Mutex<Option<Vec<Range>>>,
+    repository: Box<dyn StateRepository<Vec<RangeConfig>>>,
+    event_log: Option<Arc<EventLog>>,
+    /// Names of the rejected commands, still to acknowledge.
+    rejected: Mutex<VecDeque<String>>,
+    changed: Notify,
+}
+
+impl QuietHours {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        zone: Box<dyn Zone>,
+        options: &QuietHoursOptions,
+        repository: Box<dyn StateRepository<Vec<RangeConfig>>>,
+    ) -> Result<Self, RangeError> {
+        let configured = parse_ranges(&options.ranges)?;
+
+        let mut remote = None;
+        if repository.exists() {
+            match repository.read().map(|configs| parse_ranges(&configs)) {
+                Ok(Ok(ranges)) => remote = Some(ranges),
+                Ok(Err(err)) => warn!("Ignoring the quiet hours set by the backend: {err}"),
+                Err(err) => warn!("Unable to read the quiet hours: {:?}", err),
+            }
+        }
+
+        Ok(QuietHours {
+            clock,
+            zone,
+            configured,
+            remote: Mutex::new(remote),
+            repository,
+            event_log: None,
+            rejected: Mutex::new(VecDeque::new()),
+            changed: Notify::new(),
+        })
+    }
+
+    /// Number the acknowledgements of the rejected commands in `event_log`, when enabled.
+    pub fn with_event_log(mut self, event_log: Option<Arc<EventLog>>) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    fn ranges(&self) -> Vec<Range> {
+        self.remote
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.configured.clone())
+    }
+
+    fn is_quiet(&self, ranges: &[Range], at: SystemTime) -> bool {
+        let local = self.zone.local(at);
+        ranges.iter().any(|range| range.contains(local))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_quiet(&self.ranges(), self.clock.now_wall())
+    }
+
+    /// Whether the quiet hours are active and how long until that changes, up to [`HORIZON`].
+    fn until_change(&self) -> (bool, Duration) {
+        let ranges = self.ranges();
+        let now = self.clock.now_wall();
+        let active = self.is_quiet(&ranges, now);
+
+        // the ranges change on whole minutes
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut until =
+            STEP - Duration::from_nanos((since_epoch.as_nanos() % STEP.as_nanos()) as u64);
+        while until < HORIZON && self.is_quiet(&ranges, now + until) == active {
+            until += STEP;
+        }
+
+        (active, until)
+    }
+
+    /// Time left until the end of the quiet hours, when they are active.
+    pub fn remaining(&self) -> Option<Duration> {
+        match self.until_change() {
+            (true, remaining) => Some(remaining),
+            (false, _) => None,
+        }
+    }
+
+    /// Whether `command` may run now.
+    pub fn allows(&self, command: &str) -> bool {
+        let name = command.split_whitespace().next().unwrap_or_default();
+
+        EXEMPT_COMMANDS.contains(&name) || !self.is_active()
+    }
+
+    /// Reject `command`, acknowledged with [`QUIET_HOURS_CODE`] by [`QuietHours::run`].
+    pub fn reject(&self, command: &str) {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        info!("Quiet hours, rejecting the command {name}");
+
+        self.rejected.lock().unwrap().push_back(name.to_owned());
+        self.changed.notify_one();
+    }
+
+    /// Replace the ranges with the ones set by the backend, or restore the configured ones.
+    pub fn apply(&self, event: QuietHoursEvent) -> Result<(), RangeError> {
+        let remote = match event {
+            QuietHoursEvent::Set(configs) => {
+                let ranges = parse_ranges(&configs)?;
+                if let Err(err) = self.repository.write(&configs) {
+                    warn!("Unable to persist the quiet hours: {:?}", err);
+                }
+                Some(ranges)
+            }
+            QuietHoursEvent::Unset => {
+                if self.repository.exists() {
+                    if let Err(err) = self.repository.clear() {
+                        warn!("Unable to clear the quiet hours: {:?}", err);
+                    }
+                }
+                None
+            }
+        };
+
+        *self.remote.lock().unwrap() = remote;
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    /// Publish whether the quiet hours are active on `/active` every time that changes, and
+    /// acknowledge the rejected commands.
+    pub async fn run(&self, publisher: &impl Publisher) {
+        let mut published = None;
+        loop {
+            let (active, until) = self.until_change();
+            if published != Some(active) {
+                match publisher
+                    .send(
+                        QUIET_HOURS_INTERFACE,
+                        "/active",
+                        AstarteType::Boolean(active),
+                    )
+                    .await
+                {
+                    Ok(()) => published = Some(active),
+                    Err(err) => warn!("Unable to publish the quiet hours: {:?}", err),
+                }
+            }
+
+            let rejected: Vec<String> = self.rejected.lock().unwrap().drain(..).collect();
+            for name in rejected {
+                self.acknowledge(publisher, &name).await;
+            }
+
+            tokio::select! {
+                _ = self.clock.sleep(until) => {}
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+
+    async fn acknowledge(&self, publisher: &impl Publisher, name: &str) {
+        let path = format!("/commandAck/{name}");
+        let data = AstarteType::String(QUIET_HOURS_CODE.to_owned());
+        if let Some(event_log) = &self.event_log {
+            return event_log.acknowledge(publisher, &path, data).await;
+        }
+
+        if let Err(err) = publisher.send(DIAGNOSTICS_INTERFACE, &path, data).await {
+            warn!("Unable to publish {path}: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    use astarte_sdk::types::AstarteType;
+    use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+    use crate::clock::Clock;
+    use crate::data::MockPublisher;
+    use crate::interfaces::{DIAGNOSTICS_INTERFACE, QUIET_HOURS_INTERFACE};
+    use crate::quiet_hours::{
+        QuietHours, QuietHoursEvent, QuietHoursOptions, RangeConfig, RangeError, Zone,
+    };
+    use crate::test_utils::{settle, ManualClock, MemoryStateRepository};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    /// Central European time, switching to summer time on 2023-03-26 and back on 2022-10-30.
+    struct CentralEurope;
+
+    impl Zone for CentralEurope {
+        fn local(&self, at: SystemTime) -> NaiveDateTime {
+            let at = chrono::DateTime::<Utc>::from(at);
+            let summer = at < Utc.ymd(2022, 10, 30).and_hms(1, 0, 0)
+                || at >= Utc.ymd(2023, 3, 26).and_hms(1, 0, 0);
+            let offset = if summer { 2 } else { 1 };
+
+            at.naive_utc() + chrono::Duration::hours(offset)
+        }
+    }
+
+    fn range(start: &str, end: &str, weekdays: &[&str]) -> RangeConfig {
+        RangeConfig {
+            start: start.to_owned(),
+            end: end.to_owned(),
+            weekdays: weekdays.iter().map(|day| day.to_string()).collect(),
+        }
+    }
+
+    /// Clock moved forward to the UTC time `at`.
+    fn clock_at(at: NaiveDateTime) -> Arc<ManualClock> {
+        let clock = Arc::new(ManualClock::new());
+        let at = SystemTime::from(Utc.from_utc_datetime(&at));
+        clock.advance(at.duration_since(clock.now_wall()).unwrap());
+
+        clock
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    fn quiet_hours(clock: Arc<ManualClock>, ranges: Vec<RangeConfig>) -> QuietHours {
+        QuietHours::new(
+            clock,
+            Box::new(CentralEurope),
+            &QuietHoursOptions { ranges },
+            Box::new(MemoryStateRepository::new()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn range_crossing_midnight_on_its_weekdays() {
+        // Friday 2022-11-04, 21:30 local
+        let clock = clock_at(utc(2022, 11, 4, 20, 30));
+        let quiet = quiet_hours(clock.clone(), vec![range("22:00", "06:00", &["fri"])]);
+
+        assert!(!quiet.is_active());
+        clock.advance(HOUR / 2);
+        assert!(quiet.is_active());
+        // until 06:00 local on Saturday
+        assert_eq!(quiet.remaining(), Some(8 * HOUR));
+
+        clock.advance(5 * HOUR);
+        assert!(quiet.is_active());
+        assert_eq!(quiet.remaining(), Some(3 * HOUR));
+
+        // Saturday night is not quiet
+        clock.advance(19 * HOUR);
+        assert!(!quiet.is_active());
+        assert_eq!(quiet.remaining(), None);
+    }
+
+    #[test]
+    fn ranges_keep_their_local_times_across_dst() {
+        // Saturday 2022-10-29, 22:00 local, summer time
+        let clock = clock_at(utc(2022, 10, 29, 20, 0));
+        let quiet = quiet_hours(clock.clone(), vec![range("22:00", "06:00", &[])]);
+
+        // the clocks go back an hour at 03:00, the night lasts an hour more
+        assert!(quiet.is_active());
+        assert_eq!(quiet.remaining(), Some(9 * HOUR));
+
+        // Saturday 2023-03-25, 22:00 local, winter time
+        let clock = clock_at(utc(2023, 3, 25, 21, 0));
+        let quiet = quiet_hours(clock.clone(), vec![range("22:00", "06:00", &[])]);
+
+        // the clocks go forward an hour at 02:00, the night lasts an hour less
+        assert!(quiet.is_active());
+        assert_eq!(quiet.remaining(), Some(7 * HOUR));
+
+        // the skipped hour doesn't end the range early
+        clock.advance(4 * HOUR);
+        assert!(quiet.is_active());
+        assert_eq!(quiet.remaining(), Some(3 * HOUR));
+    }
+
+    #[test]
+    fn invalid_ranges_rejected() {
+        let clock = Arc::new(ManualClock::new());
+        let options = |ranges| QuietHoursOptions { ranges };
+
+        assert_eq!(
+            QuietHours::new(
+                clock.clone(),
+                Box::new(CentralEurope),
+                &options(vec![range("22", "06:00", &[])]),
+                Box::new(MemoryStateRepository::new()),
+            )
+            .err(),
+            Some(RangeError::Time("22".to_owned()))
+        );
+        assert_eq!(
+            QuietHours::new(
+                clock,
+                Box::new(CentralEurope),
+                &options(vec![range("22:00", "06:00", &["someday"])]),
+                Box::new(MemoryStateRepository::new()),
+            )
+            .err(),
+            Some(RangeError::Weekday("someday".to_owned()))
+        );
+    }
+
+    #[test]
+    fn backend_ranges_replace_the_configured_ones() {
+        // Monday 2022-11-07, 12:00 local
+        let clock = clock_at(utc(2022, 11, 7, 11, 0));
+        let repository = Arc::new(MemoryStateRepository::new());
+        let quiet = QuietHours::new(
+            clock.clone(),
+            Box::new(CentralEurope),
+            &QuietHoursOptions {
+                ranges: vec![range("22:00", "06:00", &[])],
+            },
+            Box::new(repository.clone()),
+        )
+        .unwrap();
+        assert!(!quiet.is_active());
+        assert!(quiet.allows("Reboot"));
+
+        let event = QuietHoursEvent::from_property(
+            &["request", "ranges"],
+            &AstarteType::String(r#"[{"start": "09:00", "end": "18:00"}]"#.to_owned()),
+        )
+        .unwrap();
+        quiet.apply(event).unwrap();
+        assert!(quiet.is_active());
+        assert!(!quiet.allows("Reboot"));
+        assert!(quiet.allows("CancelReboot"));
+        assert!(quiet.allows("Ping"));
+        assert!(quiet.allows("DiagnosticsWindow 10"));
+        assert_eq!(repository.value(), Some(vec![range("09:00", "18:00", &[])]));
+
+        // persisted across the restarts
+        let restarted = QuietHours::new(
+            clock.clone(),
+            Box::new(CentralEurope),
+            &QuietHoursOptions::default(),
+            Box::new(repository.clone()),
+        )
+        .unwrap();
+        assert!(restarted.is_active());
+
+        quiet.apply(QuietHoursEvent::Unset).unwrap();
+        assert!(!quiet.is_active());
+        assert_eq!(repository.value(), None);
+    }
+
+    #[tokio::test]
+    async fn active_state_and_rejections_published() {
+        // Friday 2022-11-04, 21:59 local
+        let clock = clock_at(utc(2022, 11, 4, 20, 59));
+        let quiet = Arc::new(quiet_hours(
+            clock.clone(),
+            vec![range("22:00", "06:00", &[])],
+        ));
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut publisher = MockPublisher::new();
+        let record = sent.clone();
+        publisher
+            .expect_send()
+            .returning(move |interface, path, data| {
+                record
+                    .lock()
+                    .unwrap()
+                    .push((interface.to_owned(), path.to_owned(), data));
+                Ok(())
+            });
+        let running = quiet.clone();
+        let run = tokio::spawn(async move { running.run(&publisher).await });
+        settle().await;
+
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert!(!quiet.allows("Reboot"));
+        quiet.reject("Reboot");
+        settle().await;
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                (
+                    QUIET_HOURS_INTERFACE.to_owned(),
+                    "/active".to_owned(),
+                    AstarteType::Boolean(false)
+                ),
+                (
+                    QUIET_HOURS_INTERFACE.to_owned(),
+                    "/active".to_owned(),
+                    AstarteType::Boolean(true)
+                ),
+                (
+                    DIAGNOSTICS_INTERFACE.to_owned(),
+                    "/commandAck/Reboot".to_owned(),
+                    AstarteType::String("QuietHours".to_owned())
+                ),
+            ]
+        );
+
+        run.abort();
+    }
+}
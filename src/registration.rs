@@ -0,0 +1,373 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Registration of the device on the Astarte pairing API, giving its credentials secret.
+//!
+//! The registration is tried again, waiting longer each time, while the network or the pairing
+//! API fail, e.g. at the first boot when the network is still coming up. The pairing API refusing
+//! it, like with an invalid token or an unknown realm, ends it at once.
+
+use std::future::Future;
+use std::time::Duration;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::clock::Clock;
+use crate::error::DeviceManagerError;
+use crate::wrapper::platform::platform;
+
+/// Attempts of the registration, unless configured otherwise.
+pub const DEFAULT_REGISTRATION_ATTEMPTS: u32 = 8;
+/// Wait after the first failed attempt, doubled after each of the next ones.
+const FIRST_RETRY_WAIT: Duration = Duration::from_secs(1);
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Retries of a failed registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RegistrationRetry {
+    pub attempts: u32,
+    /// Longest time spent retrying, from the first attempt.
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for RegistrationRetry {
+    fn default() -> Self {
+        RegistrationRetry {
+            attempts: DEFAULT_REGISTRATION_ATTEMPTS,
+            max_duration: None,
+        }
+    }
+}
+
+/// Wait after the `attempt`th failed attempt, counting from 1.
+fn retry_wait(attempt: u32) -> Duration {
+    FIRST_RETRY_WAIT
+        .saturating_mul(2_u32.saturating_pow(attempt - 1))
+        .min(MAX_RETRY_WAIT)
+}
+
+/// Run `register` until it succeeds or fails for good, the error tells the attempts made.
+///
+/// The service status tells the attempt running while retrying.
+pub(crate) async fn register_with_retries<F, Fut>(
+    clock: &dyn Clock,
+    retry: &RegistrationRetry,
+    device_id: &str,
+    url: &str,
+    mut register: F,
+) -> Result<String, DeviceManagerError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<String, DeviceManagerError>>,
+{
+    let start = clock.now_monotonic();
+    let mut attempt = 1;
+    loop {
+        let err = match register().await {
+            Ok(secret) => return Ok(secret),
+            Err(err) => err,
+        };
+
+        let wait = retry_wait(attempt);
+        let elapsed = clock.now_monotonic().duration_since(start);
+        let give_up = !err.is_retryable()
+            || attempt >= retry.attempts
+            || retry
+                .max_duration
+                .map_or(false, |max_duration| elapsed + wait > max_duration);
+        if give_up {
+            return Err(DeviceManagerError::Registration {
+                device_id: device_id.to_owned(),
+                url: url.to_owned(),
+                attempts: attempt,
+                source: Box::new(err),
+            });
+        }
+
+        warn!(
+            "Registration attempt {attempt}/{} failed: {err}, next attempt in {}s",
+            retry.attempts,
+            wait.as_secs()
+        );
+        clock.sleep(wait).await;
+        attempt += 1;
+        platform()
+            .notifier()
+            .status(&format!("Registering (attempt {attempt})"));
+    }
+}
+
+#[derive(Deserialize)]
+struct RegistrationResponse {
+    data: RegistrationData,
+}
+
+#[derive(Deserialize)]
+struct RegistrationData {
+    credentials_secret: String,
+}
+
+pub(crate) fn registration_url(pairing_url: &str, realm: &str) -> String {
+    format!(
+        "{}/v1/{realm}/agent/devices",
+        pairing_url.trim_end_matches('/')
+    )
+}
+
+/// Register `device_id` with the pairing API, returning its credentials secret.
+pub(crate) async fn register_device(
+    client: &reqwest::Client,
+    token: &str,
+    pairing_url: &str,
+    realm: &str,
+    device_id: &str,
+) -> Result<String, DeviceManagerError> {
+    let url = registration_url(pairing_url, realm);
+    let body = serde_json::json!({ "data": { "hw_id": device_id } });
+
+    let response = client
+        .post(url)
+        .bearer_auth(token)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let response: RegistrationResponse = serde_json::from_slice(&response)?;
+
+    Ok(response.data.credentials_secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::error::DeviceManagerError;
+    use crate::http::{self, HttpOptions};
+    use crate::registration::{register_device, register_with_retries, RegistrationRetry};
+    use crate::test_utils::{settle, ManualClock};
+
+    const URL: &str = "https://api.astarte.example.com/pairing/v1/realm/agent/devices";
+
+    fn refused() -> DeviceManagerError {
+        std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()
+    }
+
+    /// Error of a request the pairing API answered with `status`.
+    fn rejected(status: u16) -> DeviceManagerError {
+        let response = hyper::Response::builder().status(status).body("").unwrap();
+
+        reqwest::Response::from(response)
+            .error_for_status()
+            .unwrap_err()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn transient_failures_retried_with_growing_waits() {
+        let clock = Arc::new(ManualClock::new());
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+
+        let task_clock = clock.clone();
+        let task_attempts = attempts.clone();
+        let handle = tokio::spawn(async move {
+            let attempted = task_clock.clone();
+            register_with_retries(
+                task_clock.as_ref(),
+                &RegistrationRetry::default(),
+                "device_id",
+                URL,
+                || {
+                    let mut attempts = task_attempts.lock().unwrap();
+                    attempts.push(attempted.now_monotonic());
+                    let result = match attempts.len() {
+                        1 => Err(refused()),
+                        2 | 3 => Err(rejected(503)),
+                        _ => Ok("s3cr3t".to_owned()),
+                    };
+                    async move { result }
+                },
+            )
+            .await
+        });
+
+        for _ in 0..7 {
+            settle().await;
+            clock.advance(Duration::from_secs(1));
+        }
+        settle().await;
+
+        assert_eq!(handle.await.unwrap().unwrap(), "s3cr3t");
+        let attempts = attempts.lock().unwrap();
+        let waits: Vec<_> = attempts
+            .windows(2)
+            .map(|pair| pair[1].duration_since(pair[0]))
+            .collect();
+        assert_eq!(waits, [1, 2, 4].map(Duration::from_secs));
+    }
+
+    #[test]
+    fn waits_double_up_to_a_minute() {
+        let waits: Vec<_> = (1..=8).map(super::retry_wait).collect();
+
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60].map(Duration::from_secs));
+    }
+
+    #[tokio::test]
+    async fn refused_registration_not_retried() {
+        let clock = ManualClock::new();
+
+        for status in [401, 403, 404] {
+            let attempts = Mutex::new(0);
+            let err = register_with_retries(
+                &clock,
+                &RegistrationRetry::default(),
+                "device_id",
+                URL,
+                || {
+                    *attempts.lock().unwrap() += 1;
+                    async move { Err(rejected(status)) }
+                },
+            )
+            .await
+            .unwrap_err();
+
+            match &err {
+                DeviceManagerError::Registration {
+                    attempts, source, ..
+                } => {
+                    assert_eq!(*attempts, 1);
+                    assert!(source.to_string().contains(&status.to_string()), "{source}");
+                }
+                err => panic!("unexpected error {err:?}"),
+            }
+            assert_eq!(*attempts.lock().unwrap(), 1, "{status}");
+            assert!(!err.is_retryable());
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_bounded() {
+        let clock = Arc::new(ManualClock::new());
+        for (retry, made) in [
+            (
+                RegistrationRetry {
+                    attempts: 3,
+                    max_duration: None,
+                },
+                3,
+            ),
+            // the third failure comes 3s in, the 4s wait would end past the 5s
+            (
+                RegistrationRetry {
+                    attempts: 10,
+                    max_duration: Some(Duration::from_secs(5)),
+                },
+                3,
+            ),
+        ] {
+            let task_clock = clock.clone();
+            let handle = tokio::spawn(async move {
+                register_with_retries(task_clock.as_ref(), &retry, "device_id", URL, || async {
+                    Err(refused())
+                })
+                .await
+            });
+            for _ in 0..10 {
+                settle().await;
+                clock.advance(Duration::from_secs(1));
+            }
+
+            match handle.await.unwrap() {
+                Err(DeviceManagerError::Registration { attempts, .. }) => {
+                    assert_eq!(attempts, made)
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn registration_sends_identity_headers() {
+        use std::convert::Infallible;
+        use std::net::SocketAddr;
+
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server, StatusCode};
+
+        let received = Arc::new(Mutex::new(None));
+        let recorded = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let recorded = recorded.clone();
+                    async move {
+                        let header = |name| request.headers()[name].to_str().unwrap().to_owned();
+                        *recorded.lock().unwrap() = Some((
+                            request.uri().path().to_owned(),
+                            header("User-Agent"),
+                            header("X-Fleet-Id"),
+                            header("Authorization"),
+                        ));
+                        let response = Response::builder()
+                            .status(StatusCode::CREATED)
+                            .body(Body::from(r#"{"data":{"credentials_secret":"s3cr3t"}}"#));
+
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let options = HttpOptions {
+            headers: [("X-Fleet-Id".to_owned(), "fleet-1".to_owned())].into(),
+            ..Default::default()
+        };
+        let client = http::client(&options, "device_id").unwrap();
+        let secret = register_device(
+            &client,
+            "token",
+            &format!("http://{address}/pairing/"),
+            "realm",
+            "device_id",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(secret, "s3cr3t");
+        assert_eq!(
+            received.lock().unwrap().clone().unwrap(),
+            (
+                "/pairing/v1/realm/agent/devices".to_owned(),
+                http::user_agent("device_id"),
+                "fleet-1".to_owned(),
+                "Bearer token".to_owned(),
+            )
+        );
+    }
+}
@@ -18,12 +18,45 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use crate::error::DeviceManagerError;
-use crate::repository::StateRepository;
+//! State persisted in JSON files, replaced atomically and checked on load.
+//!
+//! Each file holds a plain JSON document, so the previous releases can still read it. The JSON
+//! objects record the version of their structure in the `state_version` field; the other
+//! documents, like the credentials secret, and the files written before the version was recorded
+//! are version 0. A file that is not valid JSON, e.g. cut short by the storage, is moved aside,
+//! with the `.corrupt` suffix, and the state is reported as missing. The documents of the older
+//! versions are upgraded with [`migrate`], along the chain of their file, and a document not
+//! having the structure of its version is left in place and reported as incompatible; the ones
+//! of a newer release are read as they are and never replaced.
+
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::error;
+use once_cell::sync::OnceCell;
 use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::disk_guard::{self, DiskGuard};
+use crate::error::DeviceManagerError;
+use crate::repository::{migrate, migrations, state_version, StateRepository};
+
+/// Field of the JSON objects with the version of their structure.
+const VERSION_FIELD: &str = "state_version";
+/// Suffix of a corrupt file moved aside, kept for diagnostics.
+const CORRUPT_SUFFIX: &str = ".corrupt";
 
 pub struct FileStateRepository {
     pub path: String,
+    pub disk_guard: Option<Arc<DiskGuard>>,
+    pub private: bool,
+    pub keep_corrupt: bool,
+    /// Version of the file found by the first access, `None` without a readable one.
+    stored_version: OnceCell<Option<u32>>,
 }
 
 impl FileStateRepository {
@@ -33,47 +66,269 @@ impl FileStateRepository {
         } else {
             path + "/" + &name
         };
-        FileStateRepository { path }
+        FileStateRepository {
+            path,
+            disk_guard: None,
+            private: false,
+            keep_corrupt: false,
+            stored_version: OnceCell::new(),
+        }
+    }
+
+    /// Use the emergency reserve of `disk_guard` when a write finds the filesystem full.
+    pub fn with_disk_guard(mut self, disk_guard: Option<Arc<DiskGuard>>) -> Self {
+        self.disk_guard = disk_guard;
+        self
+    }
+
+    /// Keep the file readable by its owner only: for the secrets.
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Leave a corrupt file in place instead of moving it aside: for the secrets the runtime
+    /// doesn't start without.
+    pub fn with_keep_corrupt(mut self, keep_corrupt: bool) -> Self {
+        self.keep_corrupt = keep_corrupt;
+        self
+    }
+
+    /// Name of the file, keying the migration chain of its state.
+    fn name(&self) -> &str {
+        Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+    }
+
+    /// Path the file is moved to when it is found corrupt.
+    pub fn corrupt_path(&self) -> String {
+        format!("{}{CORRUPT_SUFFIX}", self.path)
+    }
+
+    fn write_file(&self, data_json: &[u8]) -> std::io::Result<()> {
+        let path = Path::new(&self.path);
+        // a device or a pipe can't be replaced, it is written in place
+        if fs::metadata(path).map_or(false, |metadata| !metadata.is_file()) {
+            let mut file = fs::File::create(path)?;
+            return file.write_all(data_json);
+        }
+
+        write_atomically(path, data_json, self.private.then(|| 0o600))
+    }
+
+    /// Move the corrupt file aside, the next read finds no state.
+    fn set_aside(&self, reason: &serde_json::Error) {
+        let corrupt = self.corrupt_path();
+        match fs::rename(&self.path, &corrupt) {
+            Ok(()) => error!(
+                "Corrupt state in {} ({reason}), moved to {corrupt}, continuing without it",
+                self.path
+            ),
+            Err(err) => error!(
+                "Corrupt state in {} ({reason}), unable to move it to {corrupt}: {err}",
+                self.path
+            ),
+        }
+    }
+
+    /// Version of the file on the storage, `None` without a readable one.
+    fn read_stored_version(&self) -> Option<u32> {
+        // a device would be read without end
+        let stored = fs::metadata(&self.path)
+            .map_or(false, |metadata| metadata.is_file())
+            .then(|| fs::read(&self.path).ok())
+            .flatten()?;
+
+        parse_document(&stored).ok().map(|(version, _)| version)
+    }
+
+    /// Refuse to replace or remove the state of a newer release, that release would lose it
+    /// once back.
+    ///
+    /// The version is checked once: only this repository writes the file afterwards.
+    fn check_writable(&self) -> Result<(), DeviceManagerError> {
+        let version = *self
+            .stored_version
+            .get_or_init(|| self.read_stored_version());
+
+        let current = state_version(self.name());
+        match version {
+            Some(version) if version > current => {
+                error!(
+                    "State in {} is version {version}, newer than {current}: left as it is",
+                    self.path
+                );
+                Err(DeviceManagerError::NewerState {
+                    path: self.path.clone(),
+                    version,
+                })
+            }
+            _ => Ok(()),
+        }
     }
 }
 
+/// Replace `path` with `data` at once: a crash leaves either the previous content or the new
+/// one, never a mix of them.
+///
+/// The data is written and synced to a sibling temporary file, renamed over `path`, and the
+/// directory is synced to persist the rename. With `mode` the file is created with those
+/// permissions, also when a crash left the temporary file behind.
+pub(crate) fn write_atomically(path: &Path, data: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    let mut temporary = OsString::from(path.as_os_str());
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let written = write_synced(&temporary, data, mode).and_then(|()| fs::rename(&temporary, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written?;
+
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    fs::File::open(directory)?.sync_all()
+}
+
+fn write_synced(path: &Path, data: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if let Some(mode) = mode {
+        options.mode(mode);
+    }
+
+    let mut file = options.open(path)?;
+    if let Some(mode) = mode {
+        // the mode only applies to the new files, not to one left by a crash
+        file.set_permissions(Permissions::from_mode(mode))?;
+    }
+    file.write_all(data)?;
+
+    file.sync_all()
+}
+
+/// The JSON of `value`, recording `version` when it is an object.
+fn versioned_document<T: Serialize>(value: &T, version: u32) -> serde_json::Result<Vec<u8>> {
+    let mut document = serde_json::to_value(value)?;
+    if let Value::Object(fields) = &mut document {
+        fields.insert(VERSION_FIELD.to_owned(), version.into());
+    }
+
+    serde_json::to_vec(&document)
+}
+
+/// The version and the document of a file, without the version field.
+fn parse_document(content: &[u8]) -> Result<(u32, Value), serde_json::Error> {
+    let mut document: Value = serde_json::from_slice(content)?;
+    let version = match document.as_object_mut() {
+        Some(fields) => fields.remove(VERSION_FIELD),
+        None => None,
+    };
+    let version = match version {
+        Some(version) => serde_json::from_value(version)?,
+        None => 0,
+    };
+
+    Ok((version, document))
+}
+
 impl<T> StateRepository<T> for FileStateRepository
 where
     T: Serialize + DeserializeOwned + Send + Sync,
 {
     fn write(&self, value: &T) -> Result<(), DeviceManagerError> {
-        let mut file = std::fs::File::create(&self.path)?;
-        let data_json = serde_json::to_string(value)?;
-        std::io::Write::write_all(&mut file, data_json.as_bytes())?;
+        self.check_writable()?;
+        let data_json = versioned_document(value, state_version(self.name()))?;
+
+        match self.write_file(&data_json) {
+            Err(err) if disk_guard::is_storage_full(&err) => {
+                let released = self
+                    .disk_guard
+                    .as_ref()
+                    .map_or(false, |disk_guard| disk_guard.release_reserve());
+                if !released {
+                    return Err(err.into());
+                }
+
+                self.write_file(&data_json)?;
+            }
+            written => written?,
+        }
+
         Ok(())
     }
 
+    /// The persisted value, upgraded to the current structure. A corrupt file is moved aside,
+    /// unless kept, and reported as a JSON error, a whole one not having the structure of its
+    /// version is left in place.
     fn read(&self) -> Result<T, DeviceManagerError> {
-        let value_str = std::fs::read_to_string(&self.path)?;
-        let value = serde_json::from_str(&value_str)?;
-        Ok(value)
+        let content = fs::read(&self.path)?;
+        let (version, raw) = match parse_document(&content) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                if !self.keep_corrupt {
+                    self.set_aside(&err);
+                }
+                return Err(err.into());
+            }
+        };
+        self.stored_version.get_or_init(|| Some(version));
+
+        let current = state_version(self.name());
+        if version > current {
+            error!(
+                "State in {} is version {version}, written by a newer release than this one \
+                 (version {current}): reading it as it is, it won't be replaced",
+                self.path
+            );
+            return serde_json::from_value(raw).map_err(|_| DeviceManagerError::NewerState {
+                path: self.path.clone(),
+                version,
+            });
+        }
+
+        migrate(migrations(self.name()), version, raw)
+            .and_then(|raw| serde_json::from_value(raw).map_err(|err| err.to_string()))
+            .map_err(|reason| DeviceManagerError::IncompatibleState {
+                path: self.path.clone(),
+                version,
+                reason,
+            })
     }
 
     fn exists(&self) -> bool {
-        std::path::Path::new(&self.path).exists()
+        Path::new(&self.path).exists()
     }
 
     fn clear(&self) -> Result<(), DeviceManagerError> {
-        std::fs::remove_file(&self.path)?;
+        self.check_writable()?;
+        fs::remove_file(&self.path)?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::clock::SystemClock;
+    use crate::disk_guard::tests::FakeSpace;
+    use crate::disk_guard::{self, DiskGuard};
+    use crate::error::DeviceManagerError;
     use crate::repository::file_state_repository::FileStateRepository;
-    use crate::repository::StateRepository;
+    use crate::repository::{migrate, state_version, Migration, StateRepository};
 
     #[test]
     fn file_state_test() {
-        let repository: Box<dyn StateRepository<i32>> = Box::new(FileStateRepository {
-            path: "test.json".to_string(),
-        });
+        let repository: Box<dyn StateRepository<i32>> = Box::new(FileStateRepository::new(
+            ".".to_owned(),
+            "test.json".to_string(),
+        ));
         let value: i32 = 0;
         repository.write(&value).unwrap();
         assert!(repository.exists());
@@ -94,4 +349,238 @@ mod tests {
 
         assert_eq!(file.path, "/tmp/path/state.json".to_owned())
     }
+
+    #[test]
+    fn full_filesystem_releases_reserve() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = Arc::new(DiskGuard::new(
+            Box::new(FakeSpace(Arc::new(Mutex::new(500)))),
+            dir.path().to_str().unwrap(),
+            100,
+            Arc::new(SystemClock),
+        ));
+
+        // writes to /dev/full always fail with ENOSPC
+        let repository = FileStateRepository::new("/dev".to_owned(), "full".to_owned())
+            .with_disk_guard(Some(guard.clone()));
+
+        let err = StateRepository::<i32>::write(&repository, &0).unwrap_err();
+        assert!(
+            matches!(err, DeviceManagerError::IOError(err) if disk_guard::is_storage_full(&err))
+        );
+        assert!(!guard.release_reserve());
+    }
+
+    #[test]
+    fn private_file_replaced_at_once() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let repository = FileStateRepository::new(
+            dir.path().to_str().unwrap().to_owned(),
+            "secret.json".to_owned(),
+        )
+        .with_private(true);
+        // left by a crash in the middle of a write
+        std::fs::write(format!("{}.tmp", repository.path), "\"tor").unwrap();
+
+        StateRepository::<String>::write(&repository, &"s3cr3t".to_owned()).unwrap();
+
+        let metadata = std::fs::metadata(&repository.path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(
+            StateRepository::<String>::read(&repository).unwrap(),
+            "s3cr3t"
+        );
+        let files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["secret.json"]);
+    }
+
+    fn state_repository(dir: &tempfile::TempDir) -> FileStateRepository {
+        FileStateRepository::new(
+            dir.path().to_str().unwrap().to_owned(),
+            "state.json".to_owned(),
+        )
+    }
+
+    /// Object state, recording its version.
+    fn fields(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// `document` as written by the release with the structure `version`.
+    fn versioned(version: u32, mut document: serde_json::Value) -> String {
+        document["state_version"] = version.into();
+
+        document.to_string()
+    }
+
+    #[test]
+    fn truncated_state_set_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = state_repository(&dir);
+        let state = fields(&[("first", "second")]);
+        StateRepository::write(&repository, &state).unwrap();
+        let written = std::fs::read(&repository.path).unwrap();
+
+        for offset in 0..written.len() {
+            StateRepository::write(&repository, &state).unwrap();
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&repository.path)
+                .unwrap()
+                .set_len(offset as u64)
+                .unwrap();
+
+            let err = StateRepository::<BTreeMap<String, String>>::read(&repository).unwrap_err();
+
+            assert!(
+                matches!(err, DeviceManagerError::SerdeJsonError(_)),
+                "{err:?} cut at {offset}"
+            );
+            assert!(!StateRepository::<BTreeMap<String, String>>::exists(
+                &repository
+            ));
+            assert_eq!(
+                std::fs::read(repository.corrupt_path()).unwrap(),
+                &written[..offset]
+            );
+        }
+    }
+
+    #[test]
+    fn version_recorded_in_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = state_repository(&dir);
+        let state = fields(&[("first", "second")]);
+
+        StateRepository::write(&repository, &state).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&repository.path).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({ "first": "second", "state_version": state_version("state.json") })
+        );
+        assert_eq!(StateRepository::read(&repository).unwrap(), state);
+
+        // the other documents are written as they are
+        StateRepository::write(&repository, &vec!["first".to_owned()]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&repository.path).unwrap(),
+            r#"["first"]"#
+        );
+    }
+
+    #[test]
+    fn unversioned_state_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = state_repository(&dir);
+        // written by a release without the version
+        std::fs::write(&repository.path, r#"["first","second"]"#).unwrap();
+
+        assert_eq!(
+            StateRepository::<Vec<String>>::read(&repository).unwrap(),
+            ["first", "second"]
+        );
+        assert!(!std::path::Path::new(&repository.corrupt_path()).exists());
+
+        std::fs::write(&repository.path, r#"["first","sec"#).unwrap();
+        StateRepository::<Vec<String>>::read(&repository).unwrap_err();
+        assert!(std::path::Path::new(&repository.corrupt_path()).exists());
+    }
+
+    #[test]
+    fn newer_state_not_clobbered() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = state_repository(&dir);
+        let newer = versioned(
+            state_version("state.json") + 1,
+            serde_json::json!({ "first": "second" }),
+        );
+        std::fs::write(&repository.path, &newer).unwrap();
+
+        // still readable, as long as the structure is compatible
+        assert_eq!(
+            StateRepository::<BTreeMap<String, String>>::read(&repository).unwrap(),
+            fields(&[("first", "second")])
+        );
+        let err = StateRepository::write(&repository, &fields(&[("third", "")])).unwrap_err();
+        match err {
+            DeviceManagerError::NewerState { version, .. } => {
+                assert_eq!(version, state_version("state.json") + 1)
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+        let err = StateRepository::<BTreeMap<String, String>>::clear(&repository).unwrap_err();
+        assert!(matches!(err, DeviceManagerError::NewerState { .. }));
+        let err = StateRepository::<u32>::read(&repository).unwrap_err();
+        assert!(matches!(err, DeviceManagerError::NewerState { .. }));
+
+        assert_eq!(std::fs::read_to_string(&repository.path).unwrap(), newer);
+        assert!(!std::path::Path::new(&repository.corrupt_path()).exists());
+    }
+
+    #[test]
+    fn older_state_upgraded_on_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = state_repository(&dir);
+        std::fs::write(&repository.path, r#"{"first":"second"}"#).unwrap();
+
+        let state: BTreeMap<String, String> = StateRepository::read(&repository).unwrap();
+        StateRepository::write(&repository, &state).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&repository.path).unwrap(),
+            versioned(
+                state_version("state.json"),
+                serde_json::json!({ "first": "second" })
+            )
+        );
+    }
+
+    #[test]
+    fn mismatched_state_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = state_repository(&dir);
+        let written = versioned(
+            state_version("state.json"),
+            serde_json::json!({ "uuid": "1" }),
+        );
+        std::fs::write(&repository.path, &written).unwrap();
+
+        let err = StateRepository::<Vec<String>>::read(&repository).unwrap_err();
+
+        match err {
+            DeviceManagerError::IncompatibleState { version, .. } => {
+                assert_eq!(version, state_version("state.json"))
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+        assert_eq!(std::fs::read_to_string(&repository.path).unwrap(), written);
+        assert!(!std::path::Path::new(&repository.corrupt_path()).exists());
+    }
+
+    #[test]
+    fn migrations_applied_from_version() {
+        fn unversioned(raw: serde_json::Value) -> Result<serde_json::Value, String> {
+            Ok(raw)
+        }
+        fn wrapped(raw: serde_json::Value) -> Result<serde_json::Value, String> {
+            Ok(serde_json::json!({ "tags": raw }))
+        }
+        let chain: &[Migration] = &[unversioned, wrapped];
+
+        let raw = serde_json::json!(["first"]);
+        let upgraded = serde_json::json!({ "tags": ["first"] });
+        assert_eq!(migrate(chain, 0, raw.clone()), Ok(upgraded.clone()));
+        assert_eq!(migrate(chain, 1, raw), Ok(upgraded.clone()));
+        assert_eq!(migrate(chain, 2, upgraded.clone()), Ok(upgraded));
+    }
 }
@@ -20,11 +20,53 @@
 
 #[cfg(test)]
 use mockall::automock;
+use serde_json::Value;
 
 use crate::error::DeviceManagerError;
 
 pub(crate) mod file_state_repository;
 
+/// Upgrade of a raw payload from a version of its structure to the next one.
+pub type Migration = fn(Value) -> Result<Value, String>;
+
+/// Migration chains of the states whose structure changed, by the name of their file. The `n`th
+/// step of a chain upgrades the payloads of version `n`, starting from [`from_unversioned`].
+/// Only the JSON objects record their version, a chain is only for the states persisted as one.
+const MIGRATIONS: &[(&str, &[Migration])] = &[];
+
+/// Chain of the states whose structure never changed.
+const UNCHANGED: &[Migration] = &[from_unversioned];
+
+/// Version 0 is the files written before the version was recorded: their structure is the one
+/// of version 1.
+fn from_unversioned(raw: Value) -> Result<Value, String> {
+    Ok(raw)
+}
+
+/// Migration chain of the state persisted in the file `name`.
+pub fn migrations(name: &str) -> &'static [Migration] {
+    MIGRATIONS
+        .iter()
+        .find(|(file, _)| *file == name)
+        .map_or(UNCHANGED, |(_, chain)| chain)
+}
+
+/// Version of the structure of the state persisted in the file `name`, recorded along it.
+pub fn state_version(name: &str) -> u32 {
+    migrations(name).len() as u32
+}
+
+/// Upgrade `raw`, persisted with the structure `from_version`, along `chain` to the current
+/// structure.
+///
+/// The payloads of a version newer than the end of `chain` are returned as they are.
+pub fn migrate(chain: &[Migration], from_version: u32, raw: Value) -> Result<Value, String> {
+    chain
+        .iter()
+        .skip(from_version as usize)
+        .try_fold(raw, |raw, migration| migration(raw))
+}
+
 #[cfg_attr(test, automock)]
 pub trait StateRepository<T: Send + Sync>: Send + Sync {
     fn write(&self, value: &T) -> Result<(), DeviceManagerError>;
@@ -0,0 +1,359 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! D-Bus API exposing the state of the runtime as properties, for the local integrations like an
+//! on-device UI.
+//!
+//! The main loop and the OTA handler push their state into [`SharedState`], the properties read
+//! it and their changes are notified with `PropertiesChanged`. The transitions between the phases
+//! of an update are also emitted as the `OtaPhaseTransition` signal.
+
+use std::fmt::{self, Display};
+
+#[cfg(feature = "dbus")]
+use log::{info, warn};
+use tokio::sync::watch;
+#[cfg(feature = "dbus")]
+use zbus::{dbus_interface, Connection, ConnectionBuilder, SignalContext};
+
+#[cfg(feature = "dbus")]
+use crate::error::DeviceManagerError;
+use crate::ota::progress::Phase;
+
+#[cfg(feature = "dbus")]
+pub const RUNTIME_SERVICE_NAME: &str = "io.edgehog.DeviceRuntime";
+#[cfg(feature = "dbus")]
+const RUNTIME_SERVICE_PATH: &str = "/io/edgehog/DeviceRuntime";
+/// Phase of the updates property when none is running.
+#[cfg(feature = "dbus")]
+const IDLE_PHASE: &str = "Idle";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Disconnected,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState::Connecting
+    }
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Reconnecting => write!(f, "reconnecting"),
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RuntimeState {
+    pub connection: ConnectionState,
+    /// Phase of the running update, `None` when idle.
+    pub ota_phase: Option<Phase>,
+    /// Percentage of the phase, as last published.
+    pub ota_progress: u8,
+    /// Last error, empty until one occurs.
+    pub last_error: String,
+}
+
+#[cfg(feature = "dbus")]
+impl RuntimeState {
+    fn ota_phase_name(&self) -> String {
+        self.ota_phase
+            .map_or_else(|| IDLE_PHASE.to_owned(), |phase| phase.to_string())
+    }
+}
+
+/// State of the runtime, shared by the parts updating it and the API reading it.
+#[derive(Debug)]
+pub(crate) struct SharedState(watch::Sender<RuntimeState>);
+
+impl Default for SharedState {
+    fn default() -> Self {
+        SharedState(watch::channel(RuntimeState::default()).0)
+    }
+}
+
+impl SharedState {
+    pub fn subscribe(&self) -> watch::Receiver<RuntimeState> {
+        self.0.subscribe()
+    }
+
+    pub fn set_connection(&self, connection: ConnectionState) {
+        self.update(|state| state.connection = connection);
+    }
+
+    /// Set the phase of the running update and its percentage, `None` once it ended.
+    pub fn set_ota(&self, phase: Option<Phase>, progress: u8) {
+        self.update(|state| {
+            state.ota_phase = phase;
+            state.ota_progress = progress;
+        });
+    }
+
+    pub fn set_last_error(&self, error: impl Display) {
+        let error = error.to_string();
+        self.update(|state| state.last_error = error);
+    }
+
+    /// Apply `change`, notifying the receivers only when the state changed.
+    fn update(&self, change: impl FnOnce(&mut RuntimeState)) {
+        let mut state = self.0.borrow().clone();
+        change(&mut state);
+        let changed = state != *self.0.borrow();
+        if changed {
+            self.0.send_replace(state);
+        }
+    }
+}
+
+/// Properties whose value differs between two states.
+#[cfg(feature = "dbus")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Connection,
+    OtaPhase,
+    OtaProgress,
+    LastError,
+}
+
+#[cfg(feature = "dbus")]
+fn changes(previous: &RuntimeState, current: &RuntimeState) -> Vec<Change> {
+    [
+        (
+            previous.connection != current.connection,
+            Change::Connection,
+        ),
+        (previous.ota_phase != current.ota_phase, Change::OtaPhase),
+        (
+            previous.ota_progress != current.ota_progress,
+            Change::OtaProgress,
+        ),
+        (previous.last_error != current.last_error, Change::LastError),
+    ]
+    .into_iter()
+    .filter_map(|(changed, change)| changed.then(|| change))
+    .collect()
+}
+
+#[cfg(feature = "dbus")]
+struct RuntimeService {
+    device_id: String,
+    state: watch::Receiver<RuntimeState>,
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_interface(name = "io.edgehog.DeviceRuntime1")]
+impl RuntimeService {
+    /// State of the connection to Astarte: connecting, connected, reconnecting or disconnected.
+    #[dbus_interface(property)]
+    fn connection_state(&self) -> String {
+        self.state.borrow().connection.to_string()
+    }
+
+    /// Phase of the running update: Downloading, Deploying or Rebooting, Idle when none runs.
+    #[dbus_interface(property)]
+    fn ota_phase(&self) -> String {
+        self.state.borrow().ota_phase_name()
+    }
+
+    /// Percentage of the phase of the running update.
+    #[dbus_interface(property)]
+    fn ota_progress(&self) -> u8 {
+        self.state.borrow().ota_progress
+    }
+
+    #[dbus_interface(property)]
+    fn device_id(&self) -> String {
+        self.device_id.clone()
+    }
+
+    /// Last error of the connection or of an update, empty until one occurs.
+    #[dbus_interface(property)]
+    fn last_error(&self) -> String {
+        self.state.borrow().last_error.clone()
+    }
+
+    /// The running update moved from the phase `from` to `to`.
+    #[dbus_interface(signal)]
+    async fn ota_phase_transition(
+        ctxt: &SignalContext<'_>,
+        from: &str,
+        to: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Serve the runtime API on the system bus, until the returned connection is dropped.
+#[cfg(feature = "dbus")]
+pub async fn serve(
+    device_id: String,
+    state: watch::Receiver<RuntimeState>,
+) -> Result<Connection, DeviceManagerError> {
+    let connection = ConnectionBuilder::system()?
+        .name(RUNTIME_SERVICE_NAME)?
+        .serve_at(
+            RUNTIME_SERVICE_PATH,
+            RuntimeService {
+                device_id,
+                state: state.clone(),
+            },
+        )?
+        .build()
+        .await?;
+    info!("Runtime API available as {RUNTIME_SERVICE_NAME}");
+
+    let notified = connection.clone();
+    tokio::spawn(async move {
+        if let Err(err) = notify_changes(&notified, state).await {
+            warn!("Unable to notify the changes of the runtime state: {err}");
+        }
+    });
+
+    Ok(connection)
+}
+
+/// Notify the changes of the properties until the state is dropped.
+#[cfg(feature = "dbus")]
+async fn notify_changes(
+    connection: &Connection,
+    mut state: watch::Receiver<RuntimeState>,
+) -> zbus::Result<()> {
+    let service = connection
+        .object_server()
+        .interface::<_, RuntimeService>(RUNTIME_SERVICE_PATH)
+        .await?;
+    let ctxt = service.signal_context();
+
+    let mut previous = state.borrow().clone();
+    while state.changed().await.is_ok() {
+        let current = state.borrow().clone();
+        let service = service.get().await;
+        for change in changes(&previous, &current) {
+            match change {
+                Change::Connection => service.connection_state_changed(ctxt).await?,
+                Change::OtaPhase => {
+                    service.ota_phase_changed(ctxt).await?;
+                    RuntimeService::ota_phase_transition(
+                        ctxt,
+                        &previous.ota_phase_name(),
+                        &current.ota_phase_name(),
+                    )
+                    .await?;
+                }
+                Change::OtaProgress => service.ota_progress_changed(ctxt).await?,
+                Change::LastError => service.last_error_changed(ctxt).await?,
+            }
+        }
+        previous = current;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "dbus")]
+    use crate::ota::progress::Phase;
+    #[cfg(feature = "dbus")]
+    use crate::runtime_api::{changes, Change, RuntimeService, RuntimeState};
+    use crate::runtime_api::{ConnectionState, SharedState};
+
+    #[cfg(feature = "dbus")]
+    #[test]
+    fn state_read_by_the_properties() {
+        let shared = SharedState::default();
+        let service = RuntimeService {
+            device_id: "iGMT4TuKU3KbkAya7hmeXQ".to_owned(),
+            state: shared.subscribe(),
+        };
+
+        assert_eq!(service.connection_state(), "connecting");
+        assert_eq!(service.ota_phase(), "Idle");
+        assert_eq!(service.ota_progress(), 0);
+        assert_eq!(service.device_id(), "iGMT4TuKU3KbkAya7hmeXQ");
+        assert_eq!(service.last_error(), "");
+
+        shared.set_connection(ConnectionState::Connected);
+        shared.set_ota(Some(Phase::Downloading), 43);
+        shared.set_last_error("connection refused");
+        assert_eq!(service.connection_state(), "connected");
+        assert_eq!(service.ota_phase(), "Downloading");
+        assert_eq!(service.ota_progress(), 43);
+        assert_eq!(service.last_error(), "connection refused");
+
+        shared.set_ota(None, 0);
+        assert_eq!(service.ota_phase(), "Idle");
+    }
+
+    #[test]
+    fn unchanged_state_not_notified() {
+        let shared = SharedState::default();
+        let mut state = shared.subscribe();
+
+        shared.set_connection(ConnectionState::Connecting);
+        assert!(!state.has_changed().unwrap());
+
+        shared.set_connection(ConnectionState::Reconnecting);
+        assert!(state.has_changed().unwrap());
+        state.borrow_and_update();
+        shared.set_connection(ConnectionState::Reconnecting);
+        assert!(!state.has_changed().unwrap());
+    }
+
+    #[cfg(feature = "dbus")]
+    #[test]
+    fn changed_properties_found() {
+        let idle = RuntimeState::default();
+        let downloading = RuntimeState {
+            ota_phase: Some(Phase::Downloading),
+            ..Default::default()
+        };
+        let progressed = RuntimeState {
+            ota_progress: 43,
+            ..downloading.clone()
+        };
+        let failed = RuntimeState {
+            connection: ConnectionState::Reconnecting,
+            last_error: "connection refused".to_owned(),
+            ..idle.clone()
+        };
+
+        assert_eq!(changes(&idle, &idle), []);
+        assert_eq!(changes(&idle, &downloading), [Change::OtaPhase]);
+        assert_eq!(changes(&downloading, &progressed), [Change::OtaProgress]);
+        assert_eq!(
+            changes(&progressed, &failed),
+            [
+                Change::Connection,
+                Change::OtaPhase,
+                Change::OtaProgress,
+                Change::LastError
+            ]
+        );
+    }
+}
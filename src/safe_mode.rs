@@ -0,0 +1,345 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Reduced startup after a crash loop.
+//!
+//! Every start close to the previous one is counted as a rapid restart. Past the threshold the
+//! runtime starts in safe mode, keeping only what the backend needs to push a fix: the Astarte
+//! connection, the commands, the OTA updates and the diagnostics. Once the runtime stayed up
+//! long enough the counters are cleared and the next start is a normal one.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::repository::StateRepository;
+
+/// Rapid restarts starting the safe mode.
+pub const DEFAULT_RESTART_THRESHOLD: u32 = 5;
+/// A start within this time from the previous one is a rapid restart.
+pub const DEFAULT_RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Uptime after which the counters are cleared.
+pub const DEFAULT_STABLE_UPTIME: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SafeModeOptions {
+    pub restart_threshold: Option<u32>,
+    pub restart_window_secs: Option<u64>,
+    pub stable_uptime_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestartCounters {
+    /// Consecutive starts, each within the window from the previous one.
+    pub rapid_restarts: u32,
+    pub last_start: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupMode {
+    Normal,
+    Safe,
+}
+
+/// The optional subsystems started in a mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subsystems {
+    /// The initial and periodic telemetry, including the metered connection status.
+    pub telemetry: bool,
+    pub network_sockets: bool,
+    pub storage_usage: bool,
+    pub tags: bool,
+    pub led: bool,
+    pub crash_reports: bool,
+    pub kernel_events: bool,
+}
+
+impl Subsystems {
+    pub fn for_mode(mode: StartupMode) -> Self {
+        let enabled = mode == StartupMode::Normal;
+
+        Subsystems {
+            telemetry: enabled,
+            network_sockets: enabled,
+            storage_usage: enabled,
+            tags: enabled,
+            led: enabled,
+            crash_reports: enabled,
+            kernel_events: enabled,
+        }
+    }
+}
+
+pub struct SafeMode {
+    clock: Arc<dyn Clock>,
+    repository: Box<dyn StateRepository<RestartCounters>>,
+    stable_uptime: Duration,
+    rapid_restarts: u32,
+    mode: StartupMode,
+}
+
+impl SafeMode {
+    /// Count the current start, choosing the mode from the persisted counters.
+    pub fn check(
+        clock: Arc<dyn Clock>,
+        repository: Box<dyn StateRepository<RestartCounters>>,
+        options: &SafeModeOptions,
+    ) -> Self {
+        let window = options
+            .restart_window_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RESTART_WINDOW);
+        let threshold = options
+            .restart_threshold
+            .unwrap_or(DEFAULT_RESTART_THRESHOLD);
+
+        let previous = if repository.exists() {
+            repository.read().unwrap_or_else(|err| {
+                warn!("Unable to read the restart counters: {:?}", err);
+                RestartCounters::default()
+            })
+        } else {
+            RestartCounters::default()
+        };
+
+        let now = clock.now_wall();
+        let rapid = previous.last_start.map_or(false, |last_start| {
+            now.duration_since(last_start)
+                .map_or(true, |elapsed| elapsed <= window)
+        });
+        let counters = RestartCounters {
+            rapid_restarts: if rapid {
+                previous.rapid_restarts + 1
+            } else {
+                0
+            },
+            last_start: Some(now),
+        };
+        if let Err(err) = repository.write(&counters) {
+            warn!("Unable to persist the restart counters: {:?}", err);
+        }
+
+        let mode = if counters.rapid_restarts >= threshold {
+            warn!(
+                "{} rapid restarts, starting in safe mode",
+                counters.rapid_restarts
+            );
+            StartupMode::Safe
+        } else {
+            StartupMode::Normal
+        };
+
+        SafeMode {
+            clock,
+            repository,
+            stable_uptime: options
+                .stable_uptime_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_STABLE_UPTIME),
+            rapid_restarts: counters.rapid_restarts,
+            mode,
+        }
+    }
+
+    pub fn mode(&self) -> StartupMode {
+        self.mode
+    }
+
+    pub fn subsystems(&self) -> Subsystems {
+        Subsystems::for_mode(self.mode)
+    }
+
+    /// Publish whether the safe mode is active on `/safeMode/*` of the diagnostics interface.
+    pub async fn publish(&self, publisher: &impl Publisher) -> Result<(), AstarteError> {
+        publisher
+            .send(
+                DIAGNOSTICS_INTERFACE,
+                "/safeMode/active",
+                AstarteType::Boolean(self.mode == StartupMode::Safe),
+            )
+            .await?;
+        publisher
+            .send(
+                DIAGNOSTICS_INTERFACE,
+                "/safeMode/rapidRestarts",
+                AstarteType::LongInteger(self.rapid_restarts.into()),
+            )
+            .await
+    }
+
+    /// Clear the counters once the runtime has been up for the stable uptime.
+    pub async fn clear_when_stable(&self) {
+        self.clock.sleep(self.stable_uptime).await;
+
+        info!("Stable uptime reached, clearing the restart counters");
+        if let Err(err) = self.repository.clear() {
+            warn!("Unable to clear the restart counters: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::safe_mode::{
+        RestartCounters, SafeMode, SafeModeOptions, StartupMode, Subsystems,
+        DEFAULT_RESTART_THRESHOLD, DEFAULT_RESTART_WINDOW, DEFAULT_STABLE_UPTIME,
+    };
+    use crate::test_utils::{settle, ManualClock, MemoryStateRepository};
+
+    fn start(
+        clock: &Arc<ManualClock>,
+        repository: &Arc<MemoryStateRepository<RestartCounters>>,
+    ) -> SafeMode {
+        SafeMode::check(
+            clock.clone(),
+            Box::new(repository.clone()),
+            &SafeModeOptions::default(),
+        )
+    }
+
+    #[test]
+    fn crash_loop_starts_safe_mode() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+
+        let first = start(&clock, &repository);
+        assert_eq!(first.mode(), StartupMode::Normal);
+        assert_eq!(
+            first.subsystems(),
+            Subsystems {
+                telemetry: true,
+                network_sockets: true,
+                storage_usage: true,
+                tags: true,
+                led: true,
+                crash_reports: true,
+                kernel_events: true,
+            }
+        );
+
+        for _ in 1..DEFAULT_RESTART_THRESHOLD {
+            clock.advance(Duration::from_secs(30));
+            assert_eq!(start(&clock, &repository).mode(), StartupMode::Normal);
+        }
+
+        clock.advance(Duration::from_secs(30));
+        let safe = start(&clock, &repository);
+        assert_eq!(safe.mode(), StartupMode::Safe);
+        assert_eq!(
+            safe.subsystems(),
+            Subsystems {
+                telemetry: false,
+                network_sockets: false,
+                storage_usage: false,
+                tags: false,
+                led: false,
+                crash_reports: false,
+                kernel_events: false,
+            }
+        );
+        assert_eq!(
+            repository.value().unwrap().rapid_restarts,
+            DEFAULT_RESTART_THRESHOLD
+        );
+    }
+
+    #[test]
+    fn slow_restarts_not_counted() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+
+        for _ in 0..2 * DEFAULT_RESTART_THRESHOLD {
+            assert_eq!(start(&clock, &repository).mode(), StartupMode::Normal);
+            clock.advance(DEFAULT_RESTART_WINDOW + Duration::from_secs(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn stable_uptime_clears_counters() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+
+        for _ in 0..DEFAULT_RESTART_THRESHOLD {
+            start(&clock, &repository);
+        }
+        let safe_mode = Arc::new(start(&clock, &repository));
+        assert_eq!(safe_mode.mode(), StartupMode::Safe);
+
+        let task_safe_mode = safe_mode.clone();
+        let cleared = tokio::spawn(async move { task_safe_mode.clear_when_stable().await });
+        settle().await;
+        clock.advance(DEFAULT_STABLE_UPTIME - Duration::from_secs(1));
+        settle().await;
+        assert!(!cleared.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        cleared.await.unwrap();
+        assert!(repository.value().is_none());
+
+        // a crash right after does not go back to safe mode
+        assert_eq!(start(&clock, &repository).mode(), StartupMode::Normal);
+    }
+
+    #[tokio::test]
+    async fn safe_mode_flag_published() {
+        let clock = Arc::new(ManualClock::new());
+        let repository = Arc::new(MemoryStateRepository::new());
+        for _ in 0..DEFAULT_RESTART_THRESHOLD {
+            start(&clock, &repository);
+        }
+        let safe_mode = start(&clock, &repository);
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, data| {
+                interface == DIAGNOSTICS_INTERFACE
+                    && path == "/safeMode/active"
+                    && *data == AstarteType::Boolean(true)
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        publisher
+            .expect_send()
+            .withf(|_, path, data| {
+                path == "/safeMode/rapidRestarts"
+                    && *data == AstarteType::LongInteger(DEFAULT_RESTART_THRESHOLD.into())
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        safe_mode.publish(&publisher).await.unwrap();
+    }
+}
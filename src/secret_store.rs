@@ -0,0 +1,477 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Where the credentials secret is kept between the starts: a JSON file in the store directory,
+//! optionally cached in the persistent kernel keyring of the user running the runtime.
+//!
+//! The keyring is held in memory by the kernel, out of reach of the processes of other users, but
+//! it doesn't survive a reboot and it expires after a few days unused: it is only a cache of the
+//! file, never the one copy of the secret. A failure of the keyring is reported.
+
+use std::ffi::CString;
+
+use log::info;
+#[cfg(test)]
+use mockall::automock;
+use nix::errno::Errno;
+use nix::libc::{self, c_long};
+use serde::Deserialize;
+
+use crate::error::DeviceManagerError;
+use crate::repository::file_state_repository::FileStateRepository;
+use crate::repository::StateRepository;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretStoreKind {
+    /// JSON file in the store directory, readable by its owner only.
+    File,
+    /// Persistent kernel keyring of the user.
+    Keyring,
+}
+
+impl Default for SecretStoreKind {
+    fn default() -> Self {
+        SecretStoreKind::File
+    }
+}
+
+/// A secret kept between the starts.
+#[cfg_attr(test, automock)]
+pub trait SecretStore: Send + Sync {
+    /// Where the secret is kept, for the logs and the errors.
+    fn location(&self) -> String;
+    fn exists(&self) -> bool;
+    fn load(&self) -> Result<String, DeviceManagerError>;
+    fn save(&self, secret: &str) -> Result<(), DeviceManagerError>;
+    fn delete(&self) -> Result<(), DeviceManagerError>;
+}
+
+/// Secret in a JSON file, written atomically and readable by its owner only. A corrupt file is
+/// left in place.
+pub struct FileSecretStore {
+    repository: FileStateRepository,
+}
+
+impl FileSecretStore {
+    pub fn new(repository: FileStateRepository) -> Self {
+        FileSecretStore {
+            repository: repository.with_private(true).with_keep_corrupt(true),
+        }
+    }
+}
+
+impl SecretStore for FileSecretStore {
+    fn location(&self) -> String {
+        self.repository.path.clone()
+    }
+
+    fn exists(&self) -> bool {
+        StateRepository::<String>::exists(&self.repository)
+    }
+
+    fn load(&self) -> Result<String, DeviceManagerError> {
+        self.repository.read()
+    }
+
+    fn save(&self, secret: &str) -> Result<(), DeviceManagerError> {
+        self.repository.write(&secret.to_owned())
+    }
+
+    fn delete(&self) -> Result<(), DeviceManagerError> {
+        StateRepository::<String>::clear(&self.repository)
+    }
+}
+
+type KeySerial = i32;
+
+const KEY_SPEC_PROCESS_KEYRING: KeySerial = -2;
+const KEYCTL_UNLINK: c_long = 9;
+const KEYCTL_SEARCH: c_long = 10;
+const KEYCTL_READ: c_long = 11;
+const KEYCTL_GET_PERSISTENT: c_long = 22;
+const KEY_TYPE: &[u8] = b"user\0";
+
+/// Secret in a `user` key of the persistent kernel keyring, through the keyutils syscalls.
+pub struct KeyringSecretStore {
+    description: String,
+}
+
+impl KeyringSecretStore {
+    /// Store the secret in the key described as `edgehog:{name}`.
+    pub fn new(name: &str) -> Self {
+        KeyringSecretStore {
+            description: format!("edgehog:{name}"),
+        }
+    }
+
+    fn error(&self, action: &'static str, source: Errno) -> DeviceManagerError {
+        DeviceManagerError::Keyring {
+            action,
+            description: self.description.clone(),
+            source,
+        }
+    }
+
+    fn c_description(&self) -> Result<CString, Errno> {
+        CString::new(self.description.as_str()).map_err(|_| Errno::EINVAL)
+    }
+
+    /// The persistent keyring of the user, linked to the keyring of the process.
+    fn persistent_keyring() -> Result<KeySerial, Errno> {
+        // SAFETY: the arguments are plain integers, -1 is the user running the process
+        let keyring = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_GET_PERSISTENT,
+                -1 as c_long,
+                KEY_SPEC_PROCESS_KEYRING as c_long,
+            )
+        };
+
+        Errno::result(keyring).map(|keyring| keyring as KeySerial)
+    }
+
+    /// The key and the keyring holding it, no key when there's none.
+    fn search(&self) -> Result<(KeySerial, Option<KeySerial>), Errno> {
+        let keyring = Self::persistent_keyring()?;
+        let description = self.c_description()?;
+        // SAFETY: the type and the description are NUL terminated and outlive the call
+        let key = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_SEARCH,
+                keyring as c_long,
+                KEY_TYPE.as_ptr(),
+                description.as_ptr(),
+                0 as c_long,
+            )
+        };
+
+        match Errno::result(key) {
+            Ok(key) => Ok((keyring, Some(key as KeySerial))),
+            Err(Errno::ENOKEY) | Err(Errno::EKEYEXPIRED) | Err(Errno::EKEYREVOKED) => {
+                Ok((keyring, None))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read(key: KeySerial) -> Result<Vec<u8>, Errno> {
+        let mut payload = Vec::new();
+        loop {
+            // SAFETY: the kernel writes at most the capacity of the buffer
+            let length = unsafe {
+                libc::syscall(
+                    libc::SYS_keyctl,
+                    KEYCTL_READ,
+                    key as c_long,
+                    payload.as_mut_ptr(),
+                    payload.capacity(),
+                )
+            };
+            let length = Errno::result(length)? as usize;
+            if length <= payload.capacity() {
+                // SAFETY: the kernel wrote `length` bytes
+                unsafe { payload.set_len(length) };
+                return Ok(payload);
+            }
+            // the key is longer than the buffer, or was updated since the last read
+            payload.reserve_exact(length);
+        }
+    }
+}
+
+impl SecretStore for KeyringSecretStore {
+    fn location(&self) -> String {
+        format!("the {} key of the persistent keyring", self.description)
+    }
+
+    fn exists(&self) -> bool {
+        matches!(self.search(), Ok((_, Some(_))))
+    }
+
+    fn load(&self) -> Result<String, DeviceManagerError> {
+        let key = match self.search() {
+            Ok((_, Some(key))) => key,
+            Ok((_, None)) => return Err(self.error("find", Errno::ENOKEY)),
+            Err(err) => return Err(self.error("find", err)),
+        };
+        let payload = Self::read(key).map_err(|err| self.error("read", err))?;
+
+        String::from_utf8(payload).map_err(|_| self.error("read", Errno::EBADMSG))
+    }
+
+    fn save(&self, secret: &str) -> Result<(), DeviceManagerError> {
+        let keyring = Self::persistent_keyring().map_err(|err| self.error("open", err))?;
+        let description = self.c_description().map_err(|err| self.error("add", err))?;
+        // SAFETY: the type and the description are NUL terminated, the payload is `len` bytes,
+        // all of them outlive the call
+        let key = unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                KEY_TYPE.as_ptr(),
+                description.as_ptr(),
+                secret.as_ptr(),
+                secret.len(),
+                keyring as c_long,
+            )
+        };
+
+        Errno::result(key)
+            .map(drop)
+            .map_err(|err| self.error("add", err))
+    }
+
+    fn delete(&self) -> Result<(), DeviceManagerError> {
+        let (keyring, key) = self.search().map_err(|err| self.error("find", err))?;
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        // SAFETY: the arguments are plain integers
+        let unlinked = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_UNLINK,
+                key as c_long,
+                keyring as c_long,
+            )
+        };
+
+        Errno::result(unlinked)
+            .map(drop)
+            .map_err(|err| self.error("remove", err))
+    }
+}
+
+/// Secret kept in `source` and read through `cache`. The source stays the reference: the cache,
+/// written along with it, is filled again from it when found empty, e.g. after a reboot emptied
+/// the keyring.
+pub struct CachedSecretStore<S, C> {
+    source: S,
+    cache: C,
+}
+
+impl<S: SecretStore, C: SecretStore> CachedSecretStore<S, C> {
+    pub fn new(source: S, cache: C) -> Self {
+        CachedSecretStore { source, cache }
+    }
+}
+
+impl<S: SecretStore, C: SecretStore> SecretStore for CachedSecretStore<S, C> {
+    fn location(&self) -> String {
+        self.source.location()
+    }
+
+    fn exists(&self) -> bool {
+        self.source.exists()
+    }
+
+    fn load(&self) -> Result<String, DeviceManagerError> {
+        if self.cache.exists() {
+            return self.cache.load();
+        }
+
+        let secret = self.source.load()?;
+        self.cache.save(&secret)?;
+        info!(
+            "Cached the secret of {} in {}",
+            self.source.location(),
+            self.cache.location()
+        );
+
+        Ok(secret)
+    }
+
+    fn save(&self, secret: &str) -> Result<(), DeviceManagerError> {
+        self.source.save(secret)?;
+        self.cache.save(secret)
+    }
+
+    fn delete(&self) -> Result<(), DeviceManagerError> {
+        self.cache.delete()?;
+        self.source.delete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use nix::errno::Errno;
+
+    use crate::error::DeviceManagerError;
+    use crate::repository::file_state_repository::FileStateRepository;
+    use crate::secret_store::{
+        CachedSecretStore, FileSecretStore, MockSecretStore, SecretStore, SecretStoreKind,
+    };
+
+    /// Secret kept in memory.
+    #[derive(Default)]
+    struct MemorySecretStore(Mutex<Option<String>>);
+
+    impl MemorySecretStore {
+        fn with(secret: &str) -> Self {
+            MemorySecretStore(Mutex::new(Some(secret.to_owned())))
+        }
+
+        fn secret(&self) -> Option<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl SecretStore for MemorySecretStore {
+        fn location(&self) -> String {
+            "memory".to_owned()
+        }
+
+        fn exists(&self) -> bool {
+            self.secret().is_some()
+        }
+
+        fn load(&self) -> Result<String, DeviceManagerError> {
+            self.secret()
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound).into())
+        }
+
+        fn save(&self, secret: &str) -> Result<(), DeviceManagerError> {
+            *self.0.lock().unwrap() = Some(secret.to_owned());
+            Ok(())
+        }
+
+        fn delete(&self) -> Result<(), DeviceManagerError> {
+            *self.0.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+
+    fn file_store(directory: &tempfile::TempDir) -> FileSecretStore {
+        FileSecretStore::new(FileStateRepository::new(
+            directory.path().to_str().unwrap().to_owned(),
+            "credentials_device_id.json".to_owned(),
+        ))
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = file_store(&directory);
+        assert!(!store.exists());
+
+        store.save("s3cr3t").unwrap();
+
+        assert!(store.exists());
+        assert_eq!(store.load().unwrap(), "s3cr3t");
+        assert_eq!(
+            std::fs::read_to_string(directory.path().join("credentials_device_id.json")).unwrap(),
+            "\"s3cr3t\""
+        );
+        store.delete().unwrap();
+        assert!(!store.exists());
+    }
+
+    #[test]
+    fn cache_filled_from_source() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = file_store(&directory);
+        source.save("s3cr3t").unwrap();
+        let store = CachedSecretStore::new(source, MemorySecretStore::default());
+
+        assert_eq!(store.load().unwrap(), "s3cr3t");
+
+        assert_eq!(store.cache.secret().as_deref(), Some("s3cr3t"));
+        // the file is kept, the cache doesn't survive a reboot
+        assert_eq!(store.source.load().unwrap(), "s3cr3t");
+    }
+
+    #[test]
+    fn cached_secret_read_first() {
+        let mut source = MockSecretStore::new();
+        source.expect_load().never();
+        let store = CachedSecretStore::new(source, MemorySecretStore::with("s3cr3t"));
+
+        assert_eq!(store.load().unwrap(), "s3cr3t");
+    }
+
+    #[test]
+    fn secret_saved_and_deleted_in_both() {
+        let store = CachedSecretStore::new(
+            MemorySecretStore::default(),
+            MemorySecretStore::with("stale"),
+        );
+
+        store.save("s3cr3t").unwrap();
+
+        assert_eq!(store.source.secret().as_deref(), Some("s3cr3t"));
+        assert_eq!(store.cache.secret().as_deref(), Some("s3cr3t"));
+
+        store.delete().unwrap();
+
+        assert!(!store.exists());
+        assert_eq!(store.cache.secret(), None);
+    }
+
+    #[test]
+    fn cache_failure_reported() {
+        let mut cache = MockSecretStore::new();
+        cache.expect_exists().returning(|| false);
+        cache.expect_save().returning(|_| {
+            Err(DeviceManagerError::Keyring {
+                action: "add",
+                description: "edgehog:credentials_device_id".to_owned(),
+                source: Errno::EDQUOT,
+            })
+        });
+        let store = CachedSecretStore::new(MemorySecretStore::with("s3cr3t"), cache);
+
+        let err = store.load().unwrap_err();
+
+        assert!(
+            matches!(err, DeviceManagerError::Keyring { action: "add", .. }),
+            "{err:?}"
+        );
+        assert_eq!(store.source.secret().as_deref(), Some("s3cr3t"));
+    }
+
+    #[test]
+    fn corrupted_source_not_cached() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("credentials_device_id.json");
+        std::fs::write(&path, "\"s3cr").unwrap();
+        let store = CachedSecretStore::new(file_store(&directory), MemorySecretStore::default());
+
+        let err = store.load().unwrap_err();
+
+        assert!(
+            matches!(err, DeviceManagerError::SerdeJsonError(_)),
+            "{err:?}"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "\"s3cr");
+        assert_eq!(store.cache.secret(), None);
+    }
+
+    #[test]
+    fn store_kind_deserialized() {
+        let kind: SecretStoreKind = serde_json::from_str(r#""keyring""#).unwrap();
+        assert_eq!(kind, SecretStoreKind::Keyring);
+        assert_eq!(SecretStoreKind::default(), SecretStoreKind::File);
+    }
+}
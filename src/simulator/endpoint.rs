@@ -0,0 +1,563 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Unix socket accepting the injected messages, and dump of the outbound ones in the same form.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::{Aggregation, Clientbound};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+use crate::error::DeviceManagerError;
+use crate::local_access::{AccessError, LocalAccess, LocalClient};
+use crate::redaction::redactor;
+use crate::simulator::SimulatorOptions;
+
+/// A message injected on the socket or appended to the dump.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub interface: String,
+    pub path: String,
+    /// A JSON object for the object aggregated interfaces, `null` unsets a property.
+    pub payload: Value,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum InjectError {
+    #[error("invalid message: {0}")]
+    InvalidMessage(String),
+    #[error("unsupported value: {0}")]
+    UnsupportedValue(Value),
+    #[error("the runtime stopped handling the messages")]
+    Stopped,
+    #[error(transparent)]
+    Denied(#[from] AccessError),
+}
+
+impl Message {
+    /// The message as polled from the SDK.
+    pub fn into_clientbound(self) -> Result<Clientbound, InjectError> {
+        let data = match self.payload {
+            Value::Object(fields) => Aggregation::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| Ok((name, astarte_value(&value)?)))
+                    .collect::<Result<_, InjectError>>()?,
+            ),
+            value => Aggregation::Individual(astarte_value(&value)?),
+        };
+
+        Ok(Clientbound {
+            interface: self.interface,
+            path: self.path,
+            data,
+        })
+    }
+}
+
+/// Integers are read as long integers, the other numbers as doubles.
+fn astarte_value(value: &Value) -> Result<AstarteType, InjectError> {
+    match value {
+        Value::Null => Ok(AstarteType::Unset),
+        Value::Bool(value) => Ok(AstarteType::Boolean(*value)),
+        Value::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(value), _) => Ok(AstarteType::LongInteger(value)),
+            (None, Some(value)) => Ok(AstarteType::Double(value)),
+            (None, None) => Err(InjectError::UnsupportedValue(value.clone())),
+        },
+        Value::String(value) => Ok(AstarteType::String(value.clone())),
+        Value::Array(items) => {
+            astarte_array(items).ok_or_else(|| InjectError::UnsupportedValue(value.clone()))
+        }
+        Value::Object(_) => Err(InjectError::UnsupportedValue(value.clone())),
+    }
+}
+
+fn astarte_array(items: &[Value]) -> Option<AstarteType> {
+    fn all<T>(items: &[Value], item: impl Fn(&Value) -> Option<T>) -> Option<Vec<T>> {
+        items.iter().map(item).collect()
+    }
+
+    all(items, |item| item.as_str().map(str::to_owned))
+        .map(AstarteType::StringArray)
+        .or_else(|| all(items, Value::as_bool).map(AstarteType::BooleanArray))
+        .or_else(|| all(items, Value::as_i64).map(AstarteType::LongIntegerArray))
+        .or_else(|| all(items, Value::as_f64).map(AstarteType::DoubleArray))
+}
+
+/// JSON form of `value`, binary blobs are arrays of bytes.
+fn json_value(value: &AstarteType) -> Value {
+    match value {
+        AstarteType::Double(value) => json!(value),
+        AstarteType::Integer(value) => json!(value),
+        AstarteType::Boolean(value) => json!(value),
+        AstarteType::LongInteger(value) => json!(value),
+        AstarteType::String(value) => json!(value),
+        AstarteType::BinaryBlob(value) => json!(value),
+        AstarteType::DateTime(value) => json!(value),
+        AstarteType::DoubleArray(value) => json!(value),
+        AstarteType::IntegerArray(value) => json!(value),
+        AstarteType::BooleanArray(value) => json!(value),
+        AstarteType::LongIntegerArray(value) => json!(value),
+        AstarteType::StringArray(value) => json!(value),
+        AstarteType::BinaryBlobArray(value) => json!(value),
+        AstarteType::DateTimeArray(value) => json!(value),
+        AstarteType::Unset => Value::Null,
+    }
+}
+
+/// The outbound messages, appended to a file one JSON line each.
+pub struct OutboundDump {
+    file: Mutex<File>,
+}
+
+impl OutboundDump {
+    pub fn create(path: &Path) -> Result<Self, DeviceManagerError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(OutboundDump {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, interface: &str, path: &str, payload: Value) {
+        let message = Message {
+            interface: interface.to_owned(),
+            path: path.to_owned(),
+            payload,
+        };
+        let mut line = json!(message).to_string();
+        line.push('\n');
+
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Unable to dump the outbound message: {err}");
+        }
+    }
+
+    pub fn record_individual(&self, interface: &str, path: &str, value: &AstarteType) {
+        self.record(interface, path, json_value(value));
+    }
+}
+
+/// Serve the socket configured in `options`, handing the injected messages over to `injected`.
+///
+/// Each message is only accepted when `access` allows the peer to publish on its interface.
+pub fn start(
+    options: &SimulatorOptions,
+    injected: Sender<Clientbound>,
+    access: Arc<LocalAccess>,
+) -> Result<(JoinHandle<()>, Option<Arc<OutboundDump>>), DeviceManagerError> {
+    let dump = options
+        .dump_path
+        .as_ref()
+        .map(|path| OutboundDump::create(Path::new(path)).map(Arc::new))
+        .transpose()?;
+
+    let socket_path = Path::new(&options.socket_path);
+    // left behind by the previous run
+    if socket_path.exists() {
+        std::fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    warn!(
+        "Simulator listening on {}, injected messages are handled as received from Astarte",
+        socket_path.display()
+    );
+
+    Ok((tokio::spawn(accept(listener, injected, access)), dump))
+}
+
+async fn accept(listener: UnixListener, injected: Sender<Clientbound>, access: Arc<LocalAccess>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(stream, injected.clone(), access.clone()));
+            }
+            Err(err) => warn!("Unable to accept a simulator connection: {err}"),
+        }
+    }
+}
+
+/// Inject each line received on `stream`, replying with whether it was accepted.
+async fn handle_connection(
+    stream: UnixStream,
+    injected: Sender<Clientbound>,
+    access: Arc<LocalAccess>,
+) {
+    let client = match LocalClient::peer(&stream) {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Unable to identify the simulator client: {err}");
+            return;
+        }
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = match inject(&line, &injected, &access, &client).await {
+            Ok(()) => json!({ "accepted": true }),
+            Err(err) => {
+                warn!("Rejected injected message: {err}");
+                json!({ "accepted": false, "error": err.to_string() })
+            }
+        };
+
+        if writer
+            .write_all(format!("{reply}\n").as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn inject(
+    line: &str,
+    injected: &Sender<Clientbound>,
+    access: &LocalAccess,
+    client: &LocalClient,
+) -> Result<(), InjectError> {
+    let message: Message =
+        serde_json::from_str(line).map_err(|err| InjectError::InvalidMessage(err.to_string()))?;
+    access.check_publish(client, &message.interface)?;
+    let clientbound = message.into_clientbound()?;
+
+    info!("Injecting {}", redactor().clientbound(&clientbound));
+    injected
+        .send(clientbound)
+        .await
+        .map_err(|_| InjectError::Stopped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use crate::capabilities::{CapabilityReport, Feature};
+    use crate::data::deadline::DeadlinePublisher;
+    use crate::data::{MockPublisher, Publisher};
+    use crate::dispatch::{Dispatch, Dispatcher};
+    use crate::interfaces::{DIAGNOSTICS_INTERFACE, OTA_REQUEST_INTERFACE, OTA_RESPONSE_INTERFACE};
+    use crate::led::{self, LedRequest};
+    use crate::local_access::{ClientPermissions, LocalAccess, LocalAccessOptions};
+    use crate::ota::messages::{OtaRequest, OtaResponse};
+    use crate::simulator::endpoint::{start, Message, OutboundDump};
+    use crate::simulator::SimulatorOptions;
+    use crate::test_utils::harness;
+    use crate::test_utils::ManualClock;
+
+    fn options(dir: &Path) -> SimulatorOptions {
+        SimulatorOptions {
+            enabled: true,
+            socket_path: dir.join("simulator.sock").to_str().unwrap().to_owned(),
+            dump_path: Some(dir.join("outbound.jsonl").to_str().unwrap().to_owned()),
+        }
+    }
+
+    /// Access allowing the test process to inject on the `allowed` interfaces.
+    fn access(allowed: &str) -> Arc<LocalAccess> {
+        Arc::new(LocalAccess::new(&LocalAccessOptions {
+            clients: vec![ClientPermissions {
+                uid: Some(nix::unistd::getuid().as_raw()),
+                publish: vec![allowed.to_owned()],
+                ..Default::default()
+            }],
+        }))
+    }
+
+    /// Send `line` on the socket, returning the reply.
+    async fn inject(options: &SimulatorOptions, line: &str) -> Value {
+        let stream = UnixStream::connect(&options.socket_path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(format!("{line}\n").as_bytes())
+            .await
+            .unwrap();
+
+        let reply = BufReader::new(reader).lines().next_line().await.unwrap();
+        serde_json::from_str(&reply.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn injected_ota_request_dispatched_like_sdk_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        let (injected_tx, mut injected) = mpsc::channel(1);
+        let (_task, _dump) = start(&options, injected_tx, access("*")).unwrap();
+
+        let uuid = Uuid::new_v4();
+        let message = json!({
+            "interface": OTA_REQUEST_INTERFACE,
+            "path": "/request",
+            "payload": { "uuid": uuid.to_string(), "url": "http://ota.bin", "allowMetered": true },
+        });
+        assert_eq!(
+            inject(&options, &message.to_string()).await,
+            json!({ "accepted": true })
+        );
+
+        let (ota_tx, mut ota_rx) = harness::ota_worker(2);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let dispatcher = Dispatcher::new(
+            ota_tx,
+            config_tx,
+            None,
+            CapabilityReport::available(&[Feature::Ota]),
+        );
+
+        let clientbound = injected.recv().await.unwrap();
+        assert_eq!(dispatcher.dispatch(&clientbound).await, Dispatch::Handled);
+        let from_simulator = ota_rx.recv().await.unwrap();
+
+        let sdk_message = harness::ota_request_message(OtaRequest {
+            allow_metered: Some(true),
+            ..OtaRequest::new(uuid, "http://ota.bin")
+        });
+        assert_eq!(dispatcher.dispatch(&sdk_message).await, Dispatch::Handled);
+        let from_sdk = ota_rx.recv().await.unwrap();
+
+        assert_eq!(from_simulator, from_sdk);
+    }
+
+    #[tokio::test]
+    async fn injected_command_dispatched_like_sdk_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        let (injected_tx, mut injected) = mpsc::channel(1);
+        let (_task, _dump) = start(&options, injected_tx, access("*")).unwrap();
+
+        let message = json!({
+            "interface": "io.edgehog.devicemanager.Commands",
+            "path": "/request",
+            "payload": "Identify",
+        });
+        assert_eq!(
+            inject(&options, &message.to_string()).await,
+            json!({ "accepted": true })
+        );
+
+        let (ota_tx, _ota_rx) = harness::ota_worker(1);
+        let (config_tx, _config_rx) = mpsc::channel(1);
+        let (led_tx, mut led_rx) = mpsc::channel(2);
+        let dispatcher =
+            Dispatcher::new(ota_tx, config_tx, Some(led_tx), CapabilityReport::default());
+
+        let clientbound = injected.recv().await.unwrap();
+        assert_eq!(dispatcher.dispatch(&clientbound).await, Dispatch::Handled);
+        assert_eq!(
+            dispatcher
+                .dispatch(&harness::command_message("Identify"))
+                .await,
+            Dispatch::Handled
+        );
+
+        let claim = LedRequest::Claim(led::IDENTIFY_ROLE.to_owned());
+        assert_eq!(led_rx.recv().await, Some(claim.clone()));
+        assert_eq!(led_rx.recv().await, Some(claim));
+    }
+
+    #[tokio::test]
+    async fn invalid_message_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        let (injected_tx, mut injected) = mpsc::channel(1);
+        let (_task, _dump) = start(&options, injected_tx, access("*")).unwrap();
+
+        let reply = inject(&options, "not json").await;
+        assert_eq!(reply["accepted"], json!(false));
+
+        let nested = json!({
+            "interface": OTA_REQUEST_INTERFACE,
+            "path": "/request",
+            "payload": { "url": { "nested": true } },
+        });
+        assert_eq!(
+            inject(&options, &nested.to_string()).await,
+            json!({
+                "accepted": false,
+                "error": "unsupported value: {\"nested\":true}",
+            })
+        );
+
+        assert!(injected.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn message_on_forbidden_interface_denied() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        let (injected_tx, mut injected) = mpsc::channel(1);
+        let (_task, _dump) = start(
+            &options,
+            injected_tx,
+            access("io.edgehog.devicemanager.Commands"),
+        )
+        .unwrap();
+
+        let message = json!({
+            "interface": OTA_REQUEST_INTERFACE,
+            "path": "/request",
+            "payload": { "uuid": Uuid::new_v4().to_string(), "url": "http://ota.bin" },
+        });
+        let reply = inject(&options, &message.to_string()).await;
+        assert_eq!(reply["accepted"], json!(false));
+        assert!(reply["error"]
+            .as_str()
+            .unwrap()
+            .ends_with("may not publish on io.edgehog.devicemanager.OTARequest"));
+
+        assert!(injected.try_recv().is_err());
+    }
+
+    #[test]
+    fn payload_values_converted() {
+        let message = Message {
+            interface: "io.edgehog.devicemanager.config.telemetry.ServerConfig".to_owned(),
+            path: "/request/io.edgehog.devicemanager.SystemStatus/periodSeconds".to_owned(),
+            payload: json!(60),
+        };
+        assert!(matches!(
+            message.into_clientbound().unwrap().data,
+            astarte_sdk::Aggregation::Individual(AstarteType::LongInteger(60))
+        ));
+
+        let values = [
+            (json!(null), AstarteType::Unset),
+            (json!(21.5), AstarteType::Double(21.5)),
+            (
+                json!(["indoor", "eu"]),
+                AstarteType::StringArray(vec!["indoor".to_owned(), "eu".to_owned()]),
+            ),
+            (json!([1, 2]), AstarteType::LongIntegerArray(vec![1, 2])),
+            (json!([1, 2.5]), AstarteType::DoubleArray(vec![1.0, 2.5])),
+        ];
+        for (payload, expected) in values {
+            let message = Message {
+                interface: "io.edgehog.devicemanager.Test".to_owned(),
+                path: "/value".to_owned(),
+                payload,
+            };
+            match message.into_clientbound().unwrap().data {
+                astarte_sdk::Aggregation::Individual(value) => assert_eq!(value, expected),
+                astarte_sdk::Aggregation::Object(_) => panic!("not an individual value"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn outbound_messages_dumped() {
+        let dir = tempfile::tempdir().unwrap();
+        let dump_path = dir.path().join("outbound.jsonl");
+
+        let mut inner = MockPublisher::new();
+        inner
+            .expect_send_object()
+            .returning(|_, _: &str, _: OtaResponse| Ok(()));
+        inner.expect_send().returning(|_, _, _| Ok(()));
+        inner.expect_unset().returning(|_, _| Ok(()));
+        let publisher = DeadlinePublisher::new(
+            inner,
+            Arc::new(ManualClock::new()),
+            Duration::from_secs(30),
+            HashSet::new(),
+        )
+        .with_outbound_dump(Some(Arc::new(OutboundDump::create(&dump_path).unwrap())));
+
+        let uuid = Uuid::new_v4();
+        publisher
+            .send_object(
+                OTA_RESPONSE_INTERFACE,
+                "/response",
+                OtaResponse {
+                    uuid,
+                    status: "InProgress".to_owned(),
+                    status_code: "".to_owned(),
+                    error_code: None,
+                    message: None,
+                },
+            )
+            .await
+            .unwrap();
+        publisher
+            .send(
+                DIAGNOSTICS_INTERFACE,
+                "/safeMode/active",
+                AstarteType::Boolean(false),
+            )
+            .await
+            .unwrap();
+        publisher
+            .unset(DIAGNOSTICS_INTERFACE, "/sendFailureStreak")
+            .await
+            .unwrap();
+
+        let dumped: Vec<Message> = std::fs::read_to_string(&dump_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            dumped,
+            vec![
+                Message {
+                    interface: OTA_RESPONSE_INTERFACE.to_owned(),
+                    path: "/response".to_owned(),
+                    payload: json!({
+                        "uuid": uuid.to_string(),
+                        "status": "InProgress",
+                        "statusCode": "",
+                    }),
+                },
+                Message {
+                    interface: DIAGNOSTICS_INTERFACE.to_owned(),
+                    path: "/safeMode/active".to_owned(),
+                    payload: json!(false),
+                },
+                Message {
+                    interface: DIAGNOSTICS_INTERFACE.to_owned(),
+                    path: "/sendFailureStreak".to_owned(),
+                    payload: Value::Null,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,40 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Device-local injection of the messages received from Astarte, for development.
+//!
+//! The endpoint is only built with the `simulator` feature and only served when enabled in the
+//! configuration; the injected messages take the same dispatch path as the ones polled from the
+//! SDK.
+
+use serde::Deserialize;
+
+#[cfg(any(test, feature = "simulator"))]
+pub(crate) mod endpoint;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimulatorOptions {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Unix socket accepting the messages to inject, one JSON object per line.
+    pub socket_path: String,
+    /// File the outbound messages are appended to, one JSON object per line.
+    pub dump_path: Option<String>,
+}
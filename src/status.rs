@@ -0,0 +1,449 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! D-Bus API answering the `status` subcommand with the state of the running instance, and the
+//! `send-test-event` one in the debug builds.
+
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+#[cfg(debug_assertions)]
+use astarte_sdk::types::AstarteType;
+#[cfg(debug_assertions)]
+use astarte_sdk::AstarteError;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use zbus::{dbus_interface, dbus_proxy, fdo, Connection, ConnectionBuilder};
+
+use crate::data::deadline::{ConnectionHealth, ConnectionStats, DeadlinePublisher};
+use crate::data::send_stats::{SendStats, SendStatsMap};
+use crate::data::Publisher;
+use crate::display_info::DisplayInfo;
+use crate::error::DeviceManagerError;
+#[cfg(debug_assertions)]
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+use crate::local_access::LocalAccess;
+#[cfg(debug_assertions)]
+use crate::local_access::LocalClient;
+use crate::ota::messages::OtaResponse;
+use crate::telemetry::schedule::{self, Schedule, TelemetrySchedule};
+use crate::wrapper::platform::system_bus;
+
+pub const STATUS_SERVICE_NAME: &str = "io.edgehog.Status";
+const STATUS_SERVICE_PATH: &str = "/io/edgehog/Status";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeStatus {
+    /// No publish timed out since the last successful one.
+    pub connection_healthy: bool,
+    pub connection: ConnectionStats,
+    /// Last status sent for an OTA request since the start.
+    pub ota: Option<OtaResponse>,
+    pub send_stats: SendStatsMap,
+    /// Effective schedule of the telemetry collectors.
+    #[serde(default)]
+    pub telemetry: Schedule,
+    /// Name given to the device by the operators.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+impl Display for RuntimeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(display_name) = &self.display_name {
+            writeln!(f, "Device: {display_name}")?;
+        }
+        if let Some(notes) = &self.notes {
+            writeln!(f, "Notes: {notes}")?;
+        }
+
+        writeln!(
+            f,
+            "Connection: {} ({} send timeouts, {} reconnects)",
+            if self.connection_healthy {
+                "healthy"
+            } else {
+                "timing out"
+            },
+            self.connection.send_timeouts,
+            self.connection.reconnects
+        )?;
+
+        match &self.ota {
+            Some(ota) if ota.status_code.is_empty() => {
+                writeln!(f, "OTA: {} {}", ota.uuid, ota.status)?
+            }
+            Some(ota) => writeln!(f, "OTA: {} {} ({})", ota.uuid, ota.status, ota.status_code)?,
+            None => writeln!(f, "OTA: idle")?,
+        }
+
+        writeln!(f, "Last sent:")?;
+        for (interface_name, stats) in &self.send_stats {
+            let last_success = stats
+                .last_success
+                .map_or_else(|| "never".to_owned(), |at| at.to_rfc3339());
+            writeln!(
+                f,
+                "  {interface_name}: {last_success} ({} failures in a row)",
+                stats.failure_streak
+            )?;
+        }
+
+        if !self.telemetry.is_empty() {
+            writeln!(f, "Telemetry schedule:")?;
+            schedule::write_schedule(f, &self.telemetry)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct StatusService {
+    health: Arc<ConnectionHealth>,
+    ota: watch::Receiver<Option<OtaResponse>>,
+    send_stats: Arc<SendStats>,
+    schedule: Arc<TelemetrySchedule>,
+    display_info: watch::Receiver<DisplayInfo>,
+}
+
+impl StatusService {
+    fn snapshot(&self) -> RuntimeStatus {
+        let display_info = self.display_info.borrow().clone();
+
+        RuntimeStatus {
+            connection_healthy: self.health.is_healthy(),
+            connection: self.health.stats(),
+            ota: self.ota.borrow().clone(),
+            send_stats: self.send_stats.snapshot(),
+            telemetry: self.schedule.snapshot(),
+            display_name: display_info.display_name,
+            notes: display_info.notes,
+        }
+    }
+}
+
+#[dbus_interface(name = "io.edgehog.Status1")]
+impl StatusService {
+    /// The status of the runtime, as a JSON object.
+    fn status(&self) -> fdo::Result<String> {
+        serde_json::to_string(&self.snapshot()).map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// The effective schedule of the telemetry collectors, as a JSON object.
+    fn telemetry_schedule(&self) -> fdo::Result<String> {
+        serde_json::to_string(&self.schedule.snapshot())
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+}
+
+#[dbus_proxy(
+    interface = "io.edgehog.Status1",
+    default_service = "io.edgehog.Status",
+    default_path = "/io/edgehog/Status"
+)]
+trait Status {
+    fn status(&self) -> zbus::Result<String>;
+}
+
+#[cfg(debug_assertions)]
+struct TestEventService<P> {
+    publisher: DeadlinePublisher<P>,
+    access: Arc<LocalAccess>,
+}
+
+#[cfg(debug_assertions)]
+#[dbus_interface(name = "io.edgehog.TestEvent1")]
+impl<P> TestEventService<P>
+where
+    P: Publisher + Clone + 'static,
+{
+    /// Publish `message` on `/testEvent` of the diagnostics interface.
+    async fn send(
+        &self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        message: String,
+    ) -> fdo::Result<()> {
+        let client = LocalClient::DBus {
+            sender: header
+                .sender()?
+                .map(|sender| sender.to_string())
+                .unwrap_or_default(),
+        };
+        self.access
+            .check_publish(&client, DIAGNOSTICS_INTERFACE)
+            .map_err(|err| fdo::Error::AccessDenied(err.to_string()))?;
+
+        send_test_event(&self.publisher, message)
+            .await
+            .map_err(|err| fdo::Error::Failed(format!("{err:?}")))
+    }
+}
+
+#[cfg(debug_assertions)]
+async fn send_test_event(publisher: &impl Publisher, message: String) -> Result<(), AstarteError> {
+    info!("Sending test event: {message}");
+
+    publisher
+        .send(
+            DIAGNOSTICS_INTERFACE,
+            "/testEvent",
+            AstarteType::String(message),
+        )
+        .await
+}
+
+#[cfg(debug_assertions)]
+#[dbus_proxy(
+    interface = "io.edgehog.TestEvent1",
+    default_service = "io.edgehog.Status",
+    default_path = "/io/edgehog/Status"
+)]
+trait TestEvent {
+    fn send(&self, message: &str) -> zbus::Result<()>;
+}
+
+/// Serve the status API on the system bus, until the returned connection is dropped.
+pub async fn serve<P>(
+    health: Arc<ConnectionHealth>,
+    ota: watch::Receiver<Option<OtaResponse>>,
+    send_stats: Arc<SendStats>,
+    schedule: Arc<TelemetrySchedule>,
+    display_info: watch::Receiver<DisplayInfo>,
+    publisher: DeadlinePublisher<P>,
+    access: Arc<LocalAccess>,
+) -> Result<Connection, DeviceManagerError>
+where
+    P: Publisher + Clone + 'static,
+{
+    let builder = ConnectionBuilder::system()?
+        .name(STATUS_SERVICE_NAME)?
+        .serve_at(
+            STATUS_SERVICE_PATH,
+            StatusService {
+                health,
+                ota,
+                send_stats,
+                schedule,
+                display_info,
+            },
+        )?;
+    #[cfg(debug_assertions)]
+    let builder = builder.serve_at(STATUS_SERVICE_PATH, TestEventService { publisher, access })?;
+    #[cfg(not(debug_assertions))]
+    drop((publisher, access));
+    let connection = builder.build().await?;
+    info!("Status API available as {STATUS_SERVICE_NAME}");
+
+    Ok(connection)
+}
+
+/// Ask the running instance for its status.
+pub async fn query() -> Result<RuntimeStatus, DeviceManagerError> {
+    let connection = system_bus().await?;
+    let status = StatusProxy::new(&connection).await?.status().await?;
+
+    Ok(serde_json::from_str(&status)?)
+}
+
+/// Ask the running instance to publish a test event.
+#[cfg(debug_assertions)]
+pub async fn request_test_event(message: &str) -> Result<(), DeviceManagerError> {
+    let connection = system_bus().await?;
+    TestEventProxy::new(&connection)
+        .await?
+        .send(message)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use tokio::sync::watch;
+    use uuid::Uuid;
+
+    use crate::data::deadline::ConnectionHealth;
+    use crate::data::send_stats::{InterfaceSendStats, SendStats};
+    use crate::display_info::DisplayInfo;
+    use crate::interfaces::{
+        NETWORK_SOCKETS_INTERFACE, OS_INFO_INTERFACE, SYSTEM_STATUS_INTERFACE,
+    };
+    use crate::ota::messages::OtaResponse;
+    use crate::status::{RuntimeStatus, StatusService};
+    use crate::telemetry::schedule::{CollectorSchedule, ScheduleSource, TelemetrySchedule};
+    use crate::test_utils::ManualClock;
+
+    const EXPECTED: &str = "\
+Device: Press 3
+Notes: Ask Maria at the line desk
+Connection: healthy (0 send timeouts, 0 reconnects)
+OTA: 6a2ab9a4-61c8-4a5f-b4a4-6d3f4d0d3c1e Error (OTAErrorNetwork)
+Last sent:
+  io.edgehog.devicemanager.OSInfo: never (2 failures in a row)
+  io.edgehog.devicemanager.SystemStatus: 2022-06-01T10:00:00+00:00 (0 failures in a row)
+Telemetry schedule:
+  io.edgehog.devicemanager.NetworkSockets: disabled (default)
+  io.edgehog.devicemanager.SystemStatus: every 60s (runtime), next 2022-06-01T10:01:00+00:00
+";
+
+    #[test]
+    fn status_printed() {
+        let status = RuntimeStatus {
+            connection_healthy: true,
+            connection: Default::default(),
+            ota: Some(OtaResponse {
+                uuid: Uuid::parse_str("6a2ab9a4-61c8-4a5f-b4a4-6d3f4d0d3c1e").unwrap(),
+                status: "Error".to_owned(),
+                status_code: "OTAErrorNetwork".to_owned(),
+                sequence: None,
+                error_code: None,
+                message: None,
+            }),
+            send_stats: [
+                (
+                    SYSTEM_STATUS_INTERFACE.to_owned(),
+                    InterfaceSendStats {
+                        last_success: Some(Utc.ymd(2022, 6, 1).and_hms(10, 0, 0)),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    OS_INFO_INTERFACE.to_owned(),
+                    InterfaceSendStats {
+                        failure_streak: 2,
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into(),
+            telemetry: [
+                (
+                    SYSTEM_STATUS_INTERFACE.to_owned(),
+                    CollectorSchedule {
+                        next_run: Some(Utc.ymd(2022, 6, 1).and_hms(10, 1, 0)),
+                        ..CollectorSchedule::new(ScheduleSource::Runtime, Duration::from_secs(60))
+                    },
+                ),
+                (
+                    NETWORK_SOCKETS_INTERFACE.to_owned(),
+                    CollectorSchedule {
+                        enabled: false,
+                        send_on_change: true,
+                        ..CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(600))
+                    },
+                ),
+            ]
+            .into(),
+            display_name: Some("Press 3".to_owned()),
+            notes: Some("Ask Maria at the line desk".to_owned()),
+        };
+
+        assert_eq!(status.to_string(), EXPECTED);
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn test_event_published() {
+        use astarte_sdk::types::AstarteType;
+
+        use crate::data::MockPublisher;
+        use crate::interfaces::DIAGNOSTICS_INTERFACE;
+        use crate::status::send_test_event;
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, data| {
+                interface == DIAGNOSTICS_INTERFACE
+                    && path == "/testEvent"
+                    && *data == AstarteType::String("hello".to_owned())
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        send_test_event(&publisher, "hello".to_owned())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn idle_status_served() {
+        let (ota_tx, ota) = watch::channel(None);
+        let (display_info_tx, display_info) = watch::channel(DisplayInfo::default());
+        let clock = Arc::new(ManualClock::new());
+        let service = StatusService {
+            health: Arc::new(ConnectionHealth::default()),
+            ota,
+            send_stats: Arc::new(SendStats::new(clock.clone())),
+            schedule: Arc::new(TelemetrySchedule::new(clock)),
+            display_info,
+        };
+
+        let status: RuntimeStatus = serde_json::from_str(&service.status().unwrap()).unwrap();
+        assert_eq!(
+            status,
+            RuntimeStatus {
+                connection_healthy: true,
+                ..Default::default()
+            }
+        );
+        assert!(status.to_string().contains("OTA: idle"));
+
+        let response = OtaResponse {
+            uuid: Uuid::new_v4(),
+            status: "InProgress".to_owned(),
+            status_code: String::new(),
+            sequence: None,
+            error_code: None,
+            message: None,
+        };
+        ota_tx.send_replace(Some(response.clone()));
+        let status: RuntimeStatus = serde_json::from_str(&service.status().unwrap()).unwrap();
+        assert_eq!(status.ota, Some(response));
+
+        service.schedule.register(
+            SYSTEM_STATUS_INTERFACE,
+            CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(1)),
+        );
+        let status: RuntimeStatus = serde_json::from_str(&service.status().unwrap()).unwrap();
+        assert_eq!(status.telemetry, service.schedule.snapshot());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&service.telemetry_schedule().unwrap())
+                .unwrap(),
+            serde_json::to_value(&status.telemetry).unwrap()
+        );
+
+        display_info_tx.send_replace(DisplayInfo {
+            display_name: Some("Press 3".to_owned()),
+            notes: None,
+        });
+        let status: RuntimeStatus = serde_json::from_str(&service.status().unwrap()).unwrap();
+        assert_eq!(status.display_name.as_deref(), Some("Press 3"));
+        assert!(status.to_string().starts_with("Device: Press 3\n"));
+    }
+}
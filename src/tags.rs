@@ -0,0 +1,395 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Fleet grouping tags declared by the device, published on `io.edgehog.devicemanager.Tags`.
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use log::{info, warn};
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::repository::StateRepository;
+
+/// How often the tags file is read again looking for changes.
+pub const TAGS_FILE_CHECK_PERIOD: Duration = Duration::from_secs(60);
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidTag {
+    Empty,
+    TooLong(String),
+    InvalidCharacter(String),
+    TooMany(String),
+}
+
+impl Display for InvalidTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidTag::Empty => write!(f, "empty tag"),
+            InvalidTag::TooLong(tag) => {
+                write!(f, "tag {tag} is longer than {MAX_TAG_LENGTH} characters")
+            }
+            InvalidTag::InvalidCharacter(tag) => write!(
+                f,
+                "tag {tag} contains characters other than letters, digits, '-' and '_'"
+            ),
+            InvalidTag::TooMany(tag) => write!(f, "tag {tag} exceeds the {MAX_TAGS} tags limit"),
+        }
+    }
+}
+
+fn validate(tag: &str) -> Result<(), InvalidTag> {
+    if tag.is_empty() {
+        Err(InvalidTag::Empty)
+    } else if tag.len() > MAX_TAG_LENGTH {
+        Err(InvalidTag::TooLong(tag.to_owned()))
+    } else if !tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Err(InvalidTag::InvalidCharacter(tag.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Collect the valid tags, reporting the rejected ones.
+pub(crate) fn collect_tags<'t>(
+    tags: impl IntoIterator<Item = &'t str>,
+) -> (BTreeSet<String>, Vec<InvalidTag>) {
+    let mut valid = BTreeSet::new();
+    let mut invalid = Vec::new();
+
+    for tag in tags {
+        match validate(tag) {
+            Ok(()) if valid.len() == MAX_TAGS && !valid.contains(tag) => {
+                invalid.push(InvalidTag::TooMany(tag.to_owned()))
+            }
+            Ok(()) => {
+                valid.insert(tag.to_owned());
+            }
+            Err(err) => invalid.push(err),
+        }
+    }
+
+    (valid, invalid)
+}
+
+/// Publishes the tags from the configuration and the optional tags file, one per line.
+///
+/// The published set is persisted, so that the tags removed while the runtime was stopped are
+/// unset too.
+pub struct Tags<'a> {
+    clock: Arc<dyn Clock>,
+    configured: Vec<String>,
+    file: PathBuf,
+    published: Box<dyn StateRepository<BTreeSet<String>> + 'a>,
+}
+
+impl<'a> Tags<'a> {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        configured: Vec<String>,
+        file: PathBuf,
+        published: Box<dyn StateRepository<BTreeSet<String>> + 'a>,
+    ) -> Self {
+        Tags {
+            clock,
+            configured,
+            file,
+            published,
+        }
+    }
+
+    /// The declared tags, an error when the tags file exists but can't be read.
+    fn load(&self) -> std::io::Result<BTreeSet<String>> {
+        let content = match std::fs::read_to_string(&self.file) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let from_file = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        let (tags, invalid) =
+            collect_tags(self.configured.iter().map(String::as_str).chain(from_file));
+        for err in invalid {
+            warn!("Ignoring tag: {err}");
+        }
+
+        Ok(tags)
+    }
+
+    fn last_published(&self) -> BTreeSet<String> {
+        if !self.published.exists() {
+            return BTreeSet::new();
+        }
+
+        self.published.read().unwrap_or_else(|err| {
+            warn!("Unable to read the published tags: {:?}", err);
+            BTreeSet::new()
+        })
+    }
+
+    /// Publish every tag, unsetting the ones no longer declared.
+    ///
+    /// Nothing is published when the tags file can't be read.
+    pub async fn publish_all(&self, publisher: &impl Publisher) -> Result<(), DeviceManagerError> {
+        let tags = self.load()?;
+        let removed: BTreeSet<String> = self.last_published().difference(&tags).cloned().collect();
+
+        self.publish(publisher, &tags, tags.iter(), removed.iter())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Publish the tags added and unset the tags removed since the last publish.
+    pub async fn sync(&self, publisher: &impl Publisher) -> Result<(), DeviceManagerError> {
+        let tags = self.load()?;
+        let published = self.last_published();
+        if tags == published {
+            return Ok(());
+        }
+
+        self.publish(
+            publisher,
+            &tags,
+            tags.difference(&published),
+            published.difference(&tags),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        publisher: &impl Publisher,
+        tags: &BTreeSet<String>,
+        added: impl Iterator<Item = &String>,
+        removed: impl Iterator<Item = &String>,
+    ) -> Result<(), AstarteError> {
+        for tag in added {
+            publisher
+                .send(
+                    TAGS_INTERFACE,
+                    &format!("/tags/{tag}"),
+                    AstarteType::Boolean(true),
+                )
+                .await?;
+        }
+
+        for tag in removed {
+            publisher
+                .unset(TAGS_INTERFACE, &format!("/tags/{tag}"))
+                .await?;
+        }
+
+        info!("Published device tags: {:?}", tags);
+        if let Err(err) = self.published.write(tags) {
+            warn!("Unable to persist the published tags: {:?}", err);
+        }
+
+        Ok(())
+    }
+
+    /// Publish every tag, then follow the changes of the tags file, never returns.
+    pub async fn run(&self, publisher: &impl Publisher, period: Duration) {
+        if let Err(err) = self.publish_all(publisher).await {
+            warn!("Unable to publish the device tags: {:?}", err);
+        }
+
+        loop {
+            self.clock.sleep(period).await;
+
+            if let Err(err) = self.sync(publisher).await {
+                warn!("Unable to publish the device tags: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::clock::SystemClock;
+    use crate::interfaces::TAGS_INTERFACE;
+    use crate::repository::StateRepository;
+    use crate::tags::{collect_tags, InvalidTag, Tags, MAX_TAGS};
+    use crate::test_utils::{recording_publisher, MemoryStateRepository, Recorded, Recording};
+
+    #[derive(Debug, PartialEq)]
+    enum Published {
+        Set(String),
+        Unset(String),
+    }
+
+    fn published(recording: &Recording<()>) -> Vec<Published> {
+        recording
+            .on(TAGS_INTERFACE)
+            .into_iter()
+            .map(|(path, data)| match data {
+                Recorded::Individual(AstarteType::Boolean(true)) => Published::Set(path),
+                Recorded::Unset => Published::Unset(path),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn invalid_tags_rejected() {
+        let too_long = "a".repeat(65);
+        let (tags, invalid) = collect_tags(["rev-b", "", "eu west", &too_long, "rev-b"]);
+
+        assert_eq!(tags, BTreeSet::from(["rev-b".to_owned()]));
+        assert_eq!(
+            invalid,
+            vec![
+                InvalidTag::Empty,
+                InvalidTag::InvalidCharacter("eu west".to_owned()),
+                InvalidTag::TooLong(too_long),
+            ]
+        );
+
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag{i}")).collect();
+        let (tags, invalid) = collect_tags(many.iter().map(String::as_str));
+        assert_eq!(tags.len(), MAX_TAGS);
+        assert_eq!(invalid, vec![InvalidTag::TooMany(format!("tag{MAX_TAGS}"))]);
+    }
+
+    #[tokio::test]
+    async fn tags_added_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("tags");
+        std::fs::write(&file, "# hardware\nrev-b\n\npilot\n").unwrap();
+
+        let repository = Arc::new(MemoryStateRepository::new());
+        let tags = Tags::new(
+            Arc::new(SystemClock),
+            vec!["eu-west".to_owned(), "not valid".to_owned()],
+            file.clone(),
+            Box::new(repository.clone()),
+        );
+
+        let (publisher, recording) = recording_publisher();
+        tags.publish_all(&publisher).await.unwrap();
+        assert_eq!(
+            published(&recording),
+            vec![
+                Published::Set("/tags/eu-west".to_owned()),
+                Published::Set("/tags/pilot".to_owned()),
+                Published::Set("/tags/rev-b".to_owned()),
+            ]
+        );
+
+        // nothing changed
+        recording.clear();
+        tags.sync(&publisher).await.unwrap();
+        assert!(published(&recording).is_empty());
+
+        std::fs::write(&file, "rev-c\npilot\n").unwrap();
+        tags.sync(&publisher).await.unwrap();
+        assert_eq!(
+            published(&recording),
+            vec![
+                Published::Set("/tags/rev-c".to_owned()),
+                Published::Unset("/tags/rev-b".to_owned()),
+            ]
+        );
+        assert_eq!(
+            repository.value(),
+            Some(BTreeSet::from([
+                "eu-west".to_owned(),
+                "pilot".to_owned(),
+                "rev-c".to_owned()
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn tags_removed_while_stopped_unset() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let repository = Arc::new(MemoryStateRepository::new());
+        repository
+            .write(&BTreeSet::from(["eu-west".to_owned(), "pilot".to_owned()]))
+            .unwrap();
+        let tags = Tags::new(
+            Arc::new(SystemClock),
+            vec!["eu-west".to_owned()],
+            dir.path().join("tags"),
+            Box::new(repository.clone()),
+        );
+
+        let (publisher, recording) = recording_publisher();
+        tags.publish_all(&publisher).await.unwrap();
+        assert_eq!(
+            published(&recording),
+            vec![
+                Published::Set("/tags/eu-west".to_owned()),
+                Published::Unset("/tags/pilot".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unreadable_tags_file_not_synced() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let repository = Arc::new(MemoryStateRepository::new());
+        repository
+            .write(&BTreeSet::from(["eu-west".to_owned(), "pilot".to_owned()]))
+            .unwrap();
+        // reading a directory fails with an error other than not found
+        let tags = Tags::new(
+            Arc::new(SystemClock),
+            vec!["eu-west".to_owned()],
+            dir.path().to_path_buf(),
+            Box::new(repository.clone()),
+        );
+
+        let (publisher, recording) = recording_publisher();
+        assert!(tags.sync(&publisher).await.is_err());
+        assert!(tags.publish_all(&publisher).await.is_err());
+
+        assert!(published(&recording).is_empty());
+        assert_eq!(
+            repository.value(),
+            Some(BTreeSet::from(["eu-west".to_owned(), "pilot".to_owned()]))
+        );
+    }
+}
@@ -0,0 +1,331 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Backoff of the Bulk telemetry while its publishes keep failing.
+//!
+//! A broker that is reachable but slow or over quota rejects the telemetry at the rate it is
+//! produced, filling the offline queue. When too many Bulk publishes fail over a window the
+//! periods of the collectors are doubled, up to a cap, and halved again after each window with
+//! the publishes going through. Every other interface is Critical traffic and never slowed down.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::{
+    DIAGNOSTICS_INTERFACE, NETWORK_SOCKETS_INTERFACE, STORAGE_USAGE_INTERFACE,
+    SYSTEM_STATUS_INTERFACE,
+};
+
+/// Interfaces of the telemetry collectors, slowed down by the backoff.
+pub const BULK_INTERFACES: [&str; 3] = [
+    SYSTEM_STATUS_INTERFACE,
+    NETWORK_SOCKETS_INTERFACE,
+    STORAGE_USAGE_INTERFACE,
+];
+pub const DEFAULT_BACKOFF_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_FAILURE_RATE: f64 = 0.5;
+pub const DEFAULT_MAX_FACTOR: u32 = 8;
+/// Publishes of a window needed to back off, a few failures are not a trend.
+const MIN_SAMPLES: u32 = 5;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TelemetryBackoffOptions {
+    pub enabled: bool,
+    pub window_secs: Option<u64>,
+    /// Share of failed publishes over a window, between 0 and 1, doubling the periods.
+    pub failure_rate: Option<f64>,
+    pub max_factor: Option<u32>,
+}
+
+/// Change of the factor, published on `/telemetry/backoff` of the diagnostics interface.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackoffStep {
+    pub factor: u32,
+    /// Failed publishes out of the ones of the last window.
+    pub failure_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Outcomes {
+    attempts: u32,
+    failures: u32,
+}
+
+pub struct TelemetryBackoff {
+    clock: Arc<dyn Clock>,
+    window: Duration,
+    failure_rate: f64,
+    max_factor: u32,
+    outcomes: Mutex<Outcomes>,
+    factor_tx: watch::Sender<u32>,
+    factor_rx: watch::Receiver<u32>,
+}
+
+impl TelemetryBackoff {
+    pub fn new(clock: Arc<dyn Clock>, options: &TelemetryBackoffOptions) -> Self {
+        let (factor_tx, factor_rx) = watch::channel(1);
+
+        TelemetryBackoff {
+            clock,
+            window: options
+                .window_secs
+                .filter(|window| *window > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_BACKOFF_WINDOW),
+            failure_rate: options
+                .failure_rate
+                .filter(|rate| (0.0..1.0).contains(rate))
+                .unwrap_or(DEFAULT_FAILURE_RATE),
+            max_factor: options.max_factor.unwrap_or(DEFAULT_MAX_FACTOR).max(1),
+            outcomes: Mutex::new(Outcomes::default()),
+            factor_tx,
+            factor_rx,
+        }
+    }
+
+    /// Factor of the periods of the Bulk collectors, 1 when not backing off.
+    pub fn factor(&self) -> watch::Receiver<u32> {
+        self.factor_rx.clone()
+    }
+
+    /// Record the outcome of a publish on `interface_name`, only the Bulk ones count.
+    pub fn record(&self, interface_name: &str, success: bool) {
+        if !BULK_INTERFACES.contains(&interface_name) {
+            return;
+        }
+
+        let mut outcomes = self.outcomes.lock().unwrap();
+        outcomes.attempts += 1;
+        if !success {
+            outcomes.failures += 1;
+        }
+    }
+
+    /// Close the current window, returning the change of the factor it caused.
+    pub fn step(&self) -> Option<BackoffStep> {
+        let outcomes = std::mem::take(&mut *self.outcomes.lock().unwrap());
+        if outcomes.attempts == 0 {
+            return None;
+        }
+
+        let failure_rate = f64::from(outcomes.failures) / f64::from(outcomes.attempts);
+        let factor = *self.factor_rx.borrow();
+        let next = if failure_rate > self.failure_rate {
+            if outcomes.attempts < MIN_SAMPLES {
+                return None;
+            }
+            factor.saturating_mul(2).min(self.max_factor)
+        } else {
+            (factor / 2).max(1)
+        };
+        if next == factor {
+            return None;
+        }
+
+        info!(
+            "{:.0}% of the telemetry publishes failed, period factor set to {next}",
+            failure_rate * 100.0
+        );
+        self.factor_tx.send_replace(next);
+
+        Some(BackoffStep {
+            factor: next,
+            failure_rate,
+        })
+    }
+
+    /// Adapt the factor at the end of every window, publishing each change.
+    pub async fn run(&self, publisher: &impl Publisher) {
+        loop {
+            self.clock.sleep(self.window).await;
+
+            let step = match self.step() {
+                Some(step) => step,
+                None => continue,
+            };
+            let step = match serde_json::to_string(&step) {
+                Ok(step) => step,
+                Err(err) => {
+                    warn!("Unable to serialize the telemetry backoff: {:?}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    "/telemetry/backoff",
+                    AstarteType::String(step),
+                )
+                .await
+            {
+                warn!("Unable to publish the telemetry backoff: {:?}", err);
+            }
+        }
+    }
+}
+
+/// `period` stretched by the factor in `backoff`, along with the factor.
+pub fn backed_off(period: Duration, backoff: Option<&watch::Receiver<u32>>) -> (Duration, u32) {
+    let factor = backoff.map_or(1, |backoff| *backoff.borrow()).max(1);
+
+    (period * factor, factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use astarte_sdk::AstarteError;
+
+    use crate::data::deadline::DeadlinePublisher;
+    use crate::data::{MockPublisher, Publisher};
+    use crate::interfaces::{DIAGNOSTICS_INTERFACE, OS_INFO_INTERFACE, SYSTEM_STATUS_INTERFACE};
+    use crate::telemetry::backoff::{BackoffStep, TelemetryBackoff, TelemetryBackoffOptions};
+    use crate::test_utils::{settle, ManualClock};
+
+    /// SDK failing every publish while `failing` is set.
+    fn flaky_sdk(failing: Arc<Mutex<bool>>) -> MockPublisher {
+        let mut sdk = MockPublisher::new();
+        sdk.expect_send().returning(move |_, _, _| {
+            if *failing.lock().unwrap() {
+                Err(AstarteError::SendError("quota exceeded".to_owned()))
+            } else {
+                Ok(())
+            }
+        });
+        sdk
+    }
+
+    async fn send(publisher: &impl Publisher, interface_name: &str, times: usize) {
+        for _ in 0..times {
+            let _ = publisher
+                .send(interface_name, "/value", AstarteType::Boolean(true))
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn factor_follows_the_failures() {
+        let clock = Arc::new(ManualClock::new());
+        let window = Duration::from_secs(60);
+        let backoff = Arc::new(TelemetryBackoff::new(
+            clock.clone(),
+            &TelemetryBackoffOptions {
+                enabled: true,
+                window_secs: Some(60),
+                failure_rate: Some(0.5),
+                max_factor: Some(8),
+            },
+        ));
+        let failing = Arc::new(Mutex::new(true));
+        let publisher = DeadlinePublisher::new(
+            flaky_sdk(failing.clone()),
+            clock.clone(),
+            Duration::from_secs(10),
+            HashSet::new(),
+        )
+        .with_backoff(Some(backoff.clone()));
+
+        let steps = Arc::new(Mutex::new(Vec::new()));
+        let recorded = steps.clone();
+        let mut events = MockPublisher::new();
+        events
+            .expect_send()
+            .withf(|interface, path, _| {
+                interface == DIAGNOSTICS_INTERFACE && path == "/telemetry/backoff"
+            })
+            .returning(move |_, _, data| {
+                if let AstarteType::String(step) = data {
+                    let step: BackoffStep = serde_json::from_str(&step).unwrap();
+                    recorded.lock().unwrap().push(step.factor);
+                }
+                Ok(())
+            });
+        let running = backoff.clone();
+        let run = tokio::spawn(async move { running.run(&events).await });
+        settle().await;
+
+        let factor = backoff.factor();
+        let mut trajectory = Vec::new();
+        // failing, recovering, a window without Bulk publishes, then failing again
+        for (failures, successes) in [
+            (10, 0),
+            (8, 2),
+            (6, 4),
+            (10, 0),
+            (0, 10),
+            (2, 8),
+            (0, 0),
+            (0, 10),
+            (0, 10),
+            (3, 0),
+            (6, 0),
+        ] {
+            *failing.lock().unwrap() = true;
+            send(&publisher, SYSTEM_STATUS_INTERFACE, failures).await;
+            *failing.lock().unwrap() = false;
+            send(&publisher, SYSTEM_STATUS_INTERFACE, successes).await;
+
+            clock.advance(window);
+            settle().await;
+            trajectory.push(*factor.borrow());
+        }
+        run.abort();
+
+        assert_eq!(trajectory, [2, 4, 8, 8, 4, 2, 2, 1, 1, 1, 2]);
+        assert_eq!(*steps.lock().unwrap(), [2, 4, 8, 4, 2, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn critical_failures_ignored() {
+        let clock = Arc::new(ManualClock::new());
+        let backoff = Arc::new(TelemetryBackoff::new(
+            clock.clone(),
+            &TelemetryBackoffOptions {
+                enabled: true,
+                ..Default::default()
+            },
+        ));
+        let publisher = DeadlinePublisher::new(
+            flaky_sdk(Arc::new(Mutex::new(true))),
+            clock.clone(),
+            Duration::from_secs(10),
+            HashSet::new(),
+        )
+        .with_backoff(Some(backoff.clone()));
+
+        send(&publisher, OS_INFO_INTERFACE, 20).await;
+        send(&publisher, DIAGNOSTICS_INTERFACE, 20).await;
+
+        assert_eq!(backoff.step(), None);
+        assert_eq!(*backoff.factor().borrow(), 1);
+    }
+}
@@ -0,0 +1,133 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! The OS image the device runs, for `io.edgehog.devicemanager.BaseImage`.
+//!
+//! The values come from the image fields of the os-release file: `IMAGE_ID` is the name,
+//! `IMAGE_VERSION` the version and `BUILD_ID` the build, else the build metadata of the version
+//! after a `+`. The fingerprint is the `EDGEHOG_IMAGE_FINGERPRINT` extension field, set by the
+//! image build. The fields missing are not sent.
+
+use std::collections::HashMap;
+
+use astarte_sdk::types::AstarteType;
+
+use crate::error::DeviceManagerError;
+use crate::telemetry::os_info::{parse_os_release, read_os_release, OS_RELEASE_PATHS};
+
+/// Data for the `io.edgehog.devicemanager.BaseImage` interface.
+pub fn get_base_image() -> Result<HashMap<String, AstarteType>, DeviceManagerError> {
+    let os_release =
+        read_os_release(&OS_RELEASE_PATHS.map(std::path::Path::new)).ok_or_else(|| {
+            DeviceManagerError::IOError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no os-release file found",
+            ))
+        })?;
+
+    Ok(parse_base_image(&os_release))
+}
+
+fn parse_base_image(os_release: &str) -> HashMap<String, AstarteType> {
+    let fields = parse_os_release(os_release);
+    let field = |key: &str| fields.get(key).map(String::as_str);
+
+    let (version, build_metadata) = match field("IMAGE_VERSION") {
+        Some(version) => match version.split_once('+') {
+            Some((version, build)) => (Some(version), Some(build)),
+            None => (Some(version), None),
+        },
+        None => (None, None),
+    };
+    let build_id = field("BUILD_ID").or(build_metadata);
+
+    [
+        ("/name", field("IMAGE_ID")),
+        ("/version", version),
+        ("/buildId", build_id),
+        ("/fingerprint", field("EDGEHOG_IMAGE_FINGERPRINT")),
+    ]
+    .into_iter()
+    .filter_map(|(path, value)| Some((path.to_owned(), AstarteType::String(value?.to_owned()))))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use astarte_sdk::types::AstarteType;
+
+    use crate::telemetry::base_image::parse_base_image;
+
+    fn string(value: &str) -> AstarteType {
+        AstarteType::String(value.to_owned())
+    }
+
+    #[test]
+    fn image_fields_parsed() {
+        let file = r#"NAME="Edgehog Linux"
+ID=edgehog
+VERSION_ID=2022.08
+IMAGE_ID="edgehog-gateway"
+IMAGE_VERSION="1.4.0"
+BUILD_ID=20220815.3
+EDGEHOG_IMAGE_FINGERPRINT="f1e2d3c4b5a6"
+"#;
+
+        let data = parse_base_image(file);
+        assert_eq!(data.len(), 4);
+        assert_eq!(data["/name"], string("edgehog-gateway"));
+        assert_eq!(data["/version"], string("1.4.0"));
+        assert_eq!(data["/buildId"], string("20220815.3"));
+        assert_eq!(data["/fingerprint"], string("f1e2d3c4b5a6"));
+    }
+
+    #[test]
+    fn build_taken_from_the_version() {
+        let file = r#"IMAGE_ID=edgehog-gateway
+IMAGE_VERSION='1.4.0+git.a1b2c3'
+"#;
+
+        let data = parse_base_image(file);
+        assert_eq!(data["/version"], string("1.4.0"));
+        assert_eq!(data["/buildId"], string("git.a1b2c3"));
+        assert!(!data.contains_key("/fingerprint"));
+    }
+
+    #[test]
+    fn missing_fields_skipped() {
+        // a distribution without image fields, only the build is known
+        let file = r#"NAME="Arch Linux"
+ID=arch
+BUILD_ID=rolling
+"#;
+        let data = parse_base_image(file);
+        assert_eq!(data.len(), 1);
+        assert_eq!(data["/buildId"], string("rolling"));
+
+        let file = r#"PRETTY_NAME="Debian GNU/Linux 11 (bullseye)"
+NAME="Debian GNU/Linux"
+VERSION_ID="11"
+IMAGE_ID=""
+
+HOME_URL="https://www.debian.org/""#;
+        assert!(parse_base_image(file).is_empty());
+        assert!(parse_base_image("IMAGE@@").is_empty());
+    }
+}
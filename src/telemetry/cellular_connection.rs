@@ -0,0 +1,689 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Cellular modems managed by ModemManager.
+//!
+//! The IMEI, the model and the supported technologies of each modem are published once on
+//! `io.edgehog.devicemanager.CellularConnectionProperties`, the registration, the operator, the
+//! signal and the technology in use periodically on
+//! `io.edgehog.devicemanager.CellularConnectionStatus`. The modems are identified by their
+//! ModemManager index, e.g. `0` for `/org/freedesktop/ModemManager1/Modem/0`. Devices without a
+//! modem or without ModemManager publish nothing.
+
+#[cfg(feature = "dbus")]
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use async_trait::async_trait;
+use log::{debug, error};
+#[cfg(test)]
+use mockall::automock;
+use serde::Serialize;
+use tokio::sync::watch;
+#[cfg(feature = "dbus")]
+use zbus::dbus_proxy;
+#[cfg(feature = "dbus")]
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::{CELLULAR_PROPERTIES_INTERFACE, CELLULAR_STATUS_INTERFACE};
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig};
+use crate::telemetry::schedule::TelemetrySchedule;
+use crate::telemetry::CollectorRuns;
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::system_bus;
+
+pub const DEFAULT_CELLULAR_CONNECTION_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+#[cfg(feature = "dbus")]
+const MODEM_INTERFACE: &str = "org.freedesktop.ModemManager1.Modem";
+/// Seconds between the signal measurements of the modems.
+#[cfg(feature = "dbus")]
+const SIGNAL_REFRESH_RATE: u32 = 30;
+
+/// `MMModemCapability` flags and their technologies.
+const CAPABILITIES: &[(u32, &str)] = &[
+    (1 << 1, "CDMA/EVDO"),
+    (1 << 2, "GSM/UMTS"),
+    (1 << 3, "LTE"),
+    (1 << 5, "Iridium"),
+    (1 << 6, "5GNR"),
+    (1 << 7, "TDS"),
+];
+
+/// `MMModemAccessTechnology` flags, from the oldest to the newest.
+const ACCESS_TECHNOLOGIES: &[(u32, &str)] = &[
+    (1 << 1, "GSM"),
+    (1 << 2, "GSMCompact"),
+    (1 << 3, "GPRS"),
+    (1 << 4, "EDGE"),
+    (1 << 5, "UMTS"),
+    (1 << 6, "HSDPA"),
+    (1 << 7, "HSUPA"),
+    (1 << 8, "HSPA"),
+    (1 << 9, "HSPA+"),
+    (1 << 10, "1xRTT"),
+    (1 << 11, "EVDO0"),
+    (1 << 12, "EVDOA"),
+    (1 << 13, "EVDOB"),
+    (1 << 14, "LTE"),
+    (1 << 15, "5GNR"),
+    (1 << 16, "LTECatM"),
+    (1 << 17, "LTENBIoT"),
+];
+
+/// Interfaces and their properties by object path.
+#[cfg(feature = "dbus")]
+type ManagedObjects = HashMap<OwnedObjectPath, HashMap<String, HashMap<String, OwnedValue>>>;
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.DBus.ObjectManager",
+    default_service = "org.freedesktop.ModemManager1",
+    default_path = "/org/freedesktop/ModemManager1"
+)]
+trait ModemManager {
+    /// The objects exported by ModemManager with their interfaces.
+    fn get_managed_objects(&self) -> zbus::Result<ManagedObjects>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.ModemManager1.Modem",
+    default_service = "org.freedesktop.ModemManager1"
+)]
+trait Modem {
+    /// The IMEI of the 3GPP modems.
+    #[dbus_proxy(property)]
+    fn equipment_identifier(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property)]
+    fn manufacturer(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property)]
+    fn model(&self) -> zbus::Result<String>;
+
+    /// Combinations of `MMModemCapability` flags the modem supports.
+    #[dbus_proxy(property)]
+    fn supported_capabilities(&self) -> zbus::Result<Vec<u32>>;
+
+    /// `MMModemAccessTechnology` flags of the technologies in use.
+    #[dbus_proxy(property)]
+    fn access_technologies(&self) -> zbus::Result<u32>;
+
+    /// The signal quality percentage and whether it was measured recently.
+    #[dbus_proxy(property)]
+    fn signal_quality(&self) -> zbus::Result<(u32, bool)>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.ModemManager1.Modem.Modem3gpp",
+    default_service = "org.freedesktop.ModemManager1"
+)]
+trait Modem3gpp {
+    /// `MMModem3gppRegistrationState` of the modem.
+    #[dbus_proxy(property)]
+    fn registration_state(&self) -> zbus::Result<u32>;
+
+    /// The MCC and the MNC of the operator.
+    #[dbus_proxy(property)]
+    fn operator_code(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property)]
+    fn operator_name(&self) -> zbus::Result<String>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.ModemManager1.Modem.Signal",
+    default_service = "org.freedesktop.ModemManager1"
+)]
+trait Signal {
+    /// Measure the signal every `rate` seconds, 0 to stop.
+    fn setup(&self, rate: u32) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn lte(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+
+    #[dbus_proxy(property)]
+    fn nr5g(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+
+    #[dbus_proxy(property)]
+    fn umts(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+
+    #[dbus_proxy(property)]
+    fn gsm(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+}
+
+/// Static properties of a modem, as reported by ModemManager.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModemInfo {
+    pub imei: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub supported_capabilities: Vec<u32>,
+}
+
+/// Connection of a modem, as reported by ModemManager.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModemState {
+    pub registration_state: u32,
+    pub operator_code: String,
+    pub operator_name: String,
+    pub access_technologies: u32,
+    pub signal_quality: u32,
+    /// RSSI in dBm, when the modem measures it.
+    pub rssi: Option<f64>,
+}
+
+/// Access to the modems, ModemManager outside of the tests.
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait ModemSource: Send + Sync {
+    /// Identifiers of the modems, empty when there is none.
+    async fn modems(&self) -> Result<Vec<String>, DeviceManagerError>;
+    async fn info(&self, modem: &str) -> Result<ModemInfo, DeviceManagerError>;
+    async fn state(&self, modem: &str) -> Result<ModemState, DeviceManagerError>;
+}
+
+#[cfg(feature = "dbus")]
+pub struct ModemManagerSource {
+    connection: zbus::Connection,
+}
+
+#[cfg(feature = "dbus")]
+impl ModemManagerSource {
+    /// Connect to the system bus, ModemManager itself may start later.
+    pub async fn connect() -> Result<Self, DeviceManagerError> {
+        Ok(ModemManagerSource {
+            connection: system_bus().await?,
+        })
+    }
+
+    fn path(modem: &str) -> String {
+        format!("/org/freedesktop/ModemManager1/Modem/{modem}")
+    }
+}
+
+#[cfg(feature = "dbus")]
+#[async_trait]
+impl ModemSource for ModemManagerSource {
+    async fn modems(&self) -> Result<Vec<String>, DeviceManagerError> {
+        // fails when ModemManager is not running
+        let objects = ModemManagerProxy::new(&self.connection)
+            .await?
+            .get_managed_objects()
+            .await?;
+
+        let mut modems: Vec<String> = objects
+            .into_iter()
+            .filter(|(_, interfaces)| interfaces.contains_key(MODEM_INTERFACE))
+            .filter_map(|(path, _)| path.as_str().rsplit('/').next().map(str::to_owned))
+            .collect();
+        modems.sort();
+
+        Ok(modems)
+    }
+
+    async fn info(&self, modem: &str) -> Result<ModemInfo, DeviceManagerError> {
+        let path = Self::path(modem);
+        let proxy = ModemProxy::builder(&self.connection)
+            .path(path.as_str())?
+            .build()
+            .await?;
+
+        // the extended signal measurements are off until set up
+        let signal = SignalProxy::builder(&self.connection)
+            .path(path.as_str())?
+            .build()
+            .await?;
+        if let Err(err) = signal.setup(SIGNAL_REFRESH_RATE).await {
+            debug!("Unable to set up the signal measurements of the modem {modem}: {err}");
+        }
+
+        Ok(ModemInfo {
+            imei: proxy.equipment_identifier().await?,
+            manufacturer: proxy.manufacturer().await?,
+            model: proxy.model().await?,
+            supported_capabilities: proxy.supported_capabilities().await?,
+        })
+    }
+
+    async fn state(&self, modem: &str) -> Result<ModemState, DeviceManagerError> {
+        let path = Self::path(modem);
+        let proxy = ModemProxy::builder(&self.connection)
+            .path(path.as_str())?
+            .build()
+            .await?;
+        let modem_3gpp = Modem3gppProxy::builder(&self.connection)
+            .path(path.as_str())?
+            .build()
+            .await?;
+        let signal = SignalProxy::builder(&self.connection)
+            .path(path.as_str())?
+            .build()
+            .await?;
+
+        // the newest technology measured first
+        let mut rssi = None;
+        for measured in [
+            signal.nr5g().await,
+            signal.lte().await,
+            signal.umts().await,
+            signal.gsm().await,
+        ] {
+            rssi = measured.ok().and_then(|measured| {
+                measured
+                    .get("rssi")
+                    .and_then(|rssi| rssi.downcast_ref::<f64>())
+                    .copied()
+            });
+            if rssi.is_some() {
+                break;
+            }
+        }
+
+        Ok(ModemState {
+            registration_state: modem_3gpp.registration_state().await?,
+            operator_code: modem_3gpp.operator_code().await?,
+            operator_name: modem_3gpp.operator_name().await?,
+            access_technologies: proxy.access_technologies().await?,
+            signal_quality: proxy.signal_quality().await?.0,
+            rssi,
+        })
+    }
+}
+
+/// Object published on `io.edgehog.devicemanager.CellularConnectionStatus`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CellularConnectionStatus {
+    pub carrier: String,
+    /// MCC and MNC of the operator.
+    pub operator_code: String,
+    pub registration_status: String,
+    pub signal_quality: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<f64>,
+    pub technology: String,
+}
+
+impl From<&ModemState> for CellularConnectionStatus {
+    fn from(state: &ModemState) -> Self {
+        CellularConnectionStatus {
+            carrier: state.operator_name.clone(),
+            operator_code: state.operator_code.clone(),
+            registration_status: registration_status(state.registration_state).to_owned(),
+            signal_quality: state.signal_quality.min(100) as i32,
+            rssi: state.rssi,
+            technology: technology(state.access_technologies).to_owned(),
+        }
+    }
+}
+
+/// Name of a `MMModem3gppRegistrationState`.
+fn registration_status(state: u32) -> &'static str {
+    match state {
+        0 => "NotRegistered",
+        // home and roaming, the SMS only ones included
+        1 | 6 => "Registered",
+        5 | 7 => "RegisteredRoaming",
+        2 => "SearchingOperator",
+        3 => "RegistrationDenied",
+        8 => "EmergencyOnly",
+        _ => "Unknown",
+    }
+}
+
+/// Newest technology of the `MMModemAccessTechnology` flags.
+fn technology(access_technologies: u32) -> &'static str {
+    ACCESS_TECHNOLOGIES
+        .iter()
+        .rev()
+        .find(|(flag, _)| access_technologies & flag != 0)
+        .map_or("Unknown", |(_, name)| name)
+}
+
+/// Properties of the `modem` published on `io.edgehog.devicemanager.CellularConnectionProperties`.
+fn modem_properties(modem: &str, info: &ModemInfo) -> Vec<(String, AstarteType)> {
+    let supported = info
+        .supported_capabilities
+        .iter()
+        .fold(0, |supported, capabilities| supported | capabilities);
+    let technologies = CAPABILITIES
+        .iter()
+        .filter(|(flag, _)| supported & flag != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+
+    vec![
+        (
+            format!("/{modem}/imei"),
+            AstarteType::String(info.imei.clone()),
+        ),
+        (
+            format!("/{modem}/manufacturer"),
+            AstarteType::String(info.manufacturer.clone()),
+        ),
+        (
+            format!("/{modem}/model"),
+            AstarteType::String(info.model.clone()),
+        ),
+        (
+            format!("/{modem}/supportedTechnologies"),
+            AstarteType::StringArray(technologies),
+        ),
+    ]
+}
+
+pub struct CellularConnectionTelemetry {
+    clock: Arc<dyn Clock>,
+    source: Box<dyn ModemSource>,
+    period: Duration,
+    /// Modems whose properties were published.
+    described: HashSet<String>,
+    config: watch::Receiver<TelemetryConfig>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    random: Arc<dyn Random>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl CellularConnectionTelemetry {
+    pub fn new(clock: Arc<dyn Clock>, source: Box<dyn ModemSource>, period: Duration) -> Self {
+        let (_, config) = watch::channel(TelemetryConfig::new());
+        let (_, shutdown) = watch::channel(false);
+
+        CellularConnectionTelemetry {
+            clock,
+            source,
+            period,
+            described: HashSet::new(),
+            config,
+            schedule: None,
+            random: Arc::new(OsRandom),
+            shutdown,
+        }
+    }
+
+    /// Follow the period and the enabling set by the backend in `config`.
+    pub fn with_config(mut self, config: watch::Receiver<TelemetryConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(self, publisher: &impl Publisher) {
+        let runs = CollectorRuns::new(
+            CELLULAR_STATUS_INTERFACE,
+            "Cellular connection",
+            self.clock.clone(),
+            self.config.clone(),
+            self.period,
+        )
+        .with_schedule(self.schedule.clone())
+        .with_random(self.random.clone())
+        .with_shutdown(self.shutdown.clone());
+
+        runs.run(self, |mut telemetry, enabled| async move {
+            telemetry.collect(publisher, enabled).await;
+            telemetry
+        })
+        .await;
+    }
+
+    /// Publish the properties of the modems not described yet and, when `status` is set, the
+    /// connection of every modem.
+    async fn collect(&mut self, publisher: &impl Publisher, status: bool) {
+        let modems = match self.source.modems().await {
+            Ok(modems) => modems,
+            Err(err) => {
+                debug!("Unable to list the modems: {:?}", err);
+                return;
+            }
+        };
+
+        for modem in modems {
+            if !self.described.contains(&modem) {
+                match self.describe(publisher, &modem).await {
+                    Ok(()) => {
+                        self.described.insert(modem.clone());
+                    }
+                    Err(err) => error!("Unable to publish the modem {modem}: {:?}", err),
+                }
+            }
+
+            if !status {
+                continue;
+            }
+            let state = match self.source.state(&modem).await {
+                Ok(state) => state,
+                Err(err) => {
+                    debug!(
+                        "Unable to read the connection of the modem {modem}: {:?}",
+                        err
+                    );
+                    continue;
+                }
+            };
+            if let Err(err) = publisher
+                .send_object(
+                    CELLULAR_STATUS_INTERFACE,
+                    &format!("/{modem}"),
+                    CellularConnectionStatus::from(&state),
+                )
+                .await
+            {
+                error!(
+                    "Unable to send the connection of the modem {modem}: {:?}",
+                    err
+                );
+            }
+        }
+    }
+
+    async fn describe(
+        &self,
+        publisher: &impl Publisher,
+        modem: &str,
+    ) -> Result<(), DeviceManagerError> {
+        let info = self.source.info(modem).await?;
+        for (path, value) in modem_properties(modem, &info) {
+            publisher
+                .send(CELLULAR_PROPERTIES_INTERFACE, &path, value)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::clock::SystemClock;
+    use crate::data::MockPublisher;
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::{CELLULAR_PROPERTIES_INTERFACE, CELLULAR_STATUS_INTERFACE};
+    use crate::telemetry::cellular_connection::{
+        modem_properties, CellularConnectionStatus, CellularConnectionTelemetry, MockModemSource,
+        ModemInfo, ModemState,
+    };
+
+    fn info() -> ModemInfo {
+        ModemInfo {
+            imei: "356938035643809".to_owned(),
+            manufacturer: "Quectel".to_owned(),
+            model: "EG25".to_owned(),
+            // GSM/UMTS and GSM/UMTS with LTE
+            supported_capabilities: vec![4, 12],
+        }
+    }
+
+    fn state() -> ModemState {
+        ModemState {
+            registration_state: 5,
+            operator_code: "22210".to_owned(),
+            operator_name: "vodafone IT".to_owned(),
+            // UMTS and LTE
+            access_technologies: (1 << 5) | (1 << 14),
+            signal_quality: 72,
+            rssi: Some(-65.0),
+        }
+    }
+
+    #[test]
+    fn modem_values_mapped() {
+        assert_eq!(
+            modem_properties("0", &info()),
+            vec![
+                (
+                    "/0/imei".to_owned(),
+                    AstarteType::String("356938035643809".to_owned())
+                ),
+                (
+                    "/0/manufacturer".to_owned(),
+                    AstarteType::String("Quectel".to_owned())
+                ),
+                (
+                    "/0/model".to_owned(),
+                    AstarteType::String("EG25".to_owned())
+                ),
+                (
+                    "/0/supportedTechnologies".to_owned(),
+                    AstarteType::StringArray(vec!["GSM/UMTS".to_owned(), "LTE".to_owned()])
+                ),
+            ]
+        );
+
+        assert_eq!(
+            CellularConnectionStatus::from(&state()),
+            CellularConnectionStatus {
+                carrier: "vodafone IT".to_owned(),
+                operator_code: "22210".to_owned(),
+                registration_status: "RegisteredRoaming".to_owned(),
+                signal_quality: 72,
+                rssi: Some(-65.0),
+                technology: "LTE".to_owned(),
+            }
+        );
+
+        let status = CellularConnectionStatus::from(&ModemState {
+            registration_state: 2,
+            access_technologies: 0,
+            rssi: None,
+            ..state()
+        });
+        assert_eq!(status.registration_status, "SearchingOperator");
+        assert_eq!(status.technology, "Unknown");
+        assert!(!serde_json::to_string(&status).unwrap().contains("rssi"));
+    }
+
+    #[tokio::test]
+    async fn modems_described_once() {
+        let mut source = MockModemSource::new();
+        // ModemManager starts after the first run
+        let started = AtomicBool::new(false);
+        source.expect_modems().returning(move || {
+            if started.swap(true, Ordering::SeqCst) {
+                Ok(vec!["0".to_owned()])
+            } else {
+                Err(DeviceManagerError::UpdateError(
+                    "ModemManager not running".to_owned(),
+                ))
+            }
+        });
+        source.expect_info().times(1).returning(|_| Ok(info()));
+        source.expect_state().returning(|_| Ok(state()));
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut publisher = MockPublisher::new();
+        let recorded = sent.clone();
+        publisher
+            .expect_send()
+            .withf(|interface, _, _| interface == CELLULAR_PROPERTIES_INTERFACE)
+            .returning(move |_, path, _| {
+                recorded.lock().unwrap().push(path.to_owned());
+                Ok(())
+            });
+        let recorded = sent.clone();
+        publisher
+            .expect_send_object()
+            .withf(|interface, _, _: &CellularConnectionStatus| {
+                interface == CELLULAR_STATUS_INTERFACE
+            })
+            .returning(move |_, path, _: CellularConnectionStatus| {
+                recorded.lock().unwrap().push(path.to_owned());
+                Ok(())
+            });
+
+        let mut telemetry = CellularConnectionTelemetry::new(
+            Arc::new(SystemClock),
+            Box::new(source),
+            Duration::from_secs(60),
+        );
+
+        // without ModemManager nothing is published
+        telemetry.collect(&publisher, true).await;
+        assert!(sent.lock().unwrap().is_empty());
+
+        telemetry.collect(&publisher, true).await;
+        telemetry.collect(&publisher, false).await;
+        telemetry.collect(&publisher, true).await;
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                "/0/imei",
+                "/0/manufacturer",
+                "/0/model",
+                "/0/supportedTechnologies",
+                "/0",
+                "/0"
+            ]
+        );
+    }
+}
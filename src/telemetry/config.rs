@@ -0,0 +1,696 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Server owned telemetry configuration, received on `io.edgehog.devicemanager.config.Telemetry`.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{error, info, warn};
+#[cfg(test)]
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::repository::StateRepository;
+use crate::telemetry::schedule::TelemetrySchedule;
+
+/// Changes received within this window are applied as a single batch, unless configured
+/// otherwise.
+pub const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(500);
+/// Largest jitter, so that the runs are never closer than half of the period.
+pub const MAX_JITTER_PERCENT: u8 = 50;
+
+/// Configuration of a telemetry interface, unset values fall back to the runtime defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TelemetryInterfaceConfig {
+    pub enabled: Option<bool>,
+    pub period_secs: Option<u64>,
+    /// Wait before the first run after the start.
+    pub initial_delay_secs: Option<u64>,
+    /// Random shift of the runs, as a percentage of the period: the first run is delayed by up
+    /// to it and the following ones are moved earlier or later by up to it.
+    pub jitter_percent: Option<u8>,
+}
+
+/// Telemetry configuration by interface name.
+pub type TelemetryConfig = BTreeMap<String, TelemetryInterfaceConfig>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelemetryConfigChange {
+    Enabled(Option<bool>),
+    Period(Option<u64>),
+    InitialDelay(Option<u64>),
+    Jitter(Option<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryConfigEvent {
+    pub interface_name: String,
+    pub change: TelemetryConfigChange,
+}
+
+impl TelemetryConfigEvent {
+    /// Parse a property set on
+    /// `/request/{interface_name}/enable|periodSeconds|initialDelaySeconds|jitterPercent`.
+    pub fn from_property(path: &[&str], value: &AstarteType) -> Option<Self> {
+        let (interface_name, endpoint) = match path {
+            ["request", interface_name, endpoint] => (*interface_name, *endpoint),
+            _ => return None,
+        };
+
+        let change = match (endpoint, value) {
+            ("enable", AstarteType::Boolean(enabled)) => {
+                TelemetryConfigChange::Enabled(Some(*enabled))
+            }
+            ("enable", AstarteType::Unset) => TelemetryConfigChange::Enabled(None),
+            ("periodSeconds", AstarteType::LongInteger(period)) => {
+                TelemetryConfigChange::Period(Some(u64::try_from(*period).ok()?))
+            }
+            ("periodSeconds", AstarteType::Unset) => TelemetryConfigChange::Period(None),
+            ("initialDelaySeconds", AstarteType::LongInteger(delay)) => {
+                TelemetryConfigChange::InitialDelay(Some(u64::try_from(*delay).ok()?))
+            }
+            ("initialDelaySeconds", AstarteType::Unset) => {
+                TelemetryConfigChange::InitialDelay(None)
+            }
+            ("jitterPercent", AstarteType::Integer(percent)) => {
+                TelemetryConfigChange::Jitter(Some(
+                    u8::try_from(*percent)
+                        .ok()
+                        .filter(|percent| *percent <= MAX_JITTER_PERCENT)?,
+                ))
+            }
+            ("jitterPercent", AstarteType::Unset) => TelemetryConfigChange::Jitter(None),
+            _ => return None,
+        };
+
+        Some(TelemetryConfigEvent {
+            interface_name: interface_name.to_owned(),
+            change,
+        })
+    }
+}
+
+/// Whether the collector of `interface_name` is enabled in `config`, the default.
+pub fn is_enabled(config: &TelemetryConfig, interface_name: &str) -> bool {
+    config
+        .get(interface_name)
+        .and_then(|config| config.enabled)
+        .unwrap_or(true)
+}
+
+/// Period of `interface_name` set in `config`, when valid.
+pub fn period(config: &TelemetryConfig, interface_name: &str) -> Option<Duration> {
+    config
+        .get(interface_name)
+        .and_then(|config| config.period_secs)
+        .filter(|period| *period > 0)
+        .map(Duration::from_secs)
+}
+
+/// Source of the randomness of the jitter.
+#[cfg_attr(test, automock)]
+pub trait Random: Send + Sync {
+    /// Random fraction between 0 and 1.
+    fn fraction(&self) -> f64;
+}
+
+/// Randomness of the OpenSSL generator.
+pub struct OsRandom;
+
+impl Random for OsRandom {
+    fn fraction(&self) -> f64 {
+        let mut random = [0; 4];
+        match openssl::rand::rand_bytes(&mut random) {
+            Ok(()) => f64::from(u32::from_ne_bytes(random)) / f64::from(u32::MAX),
+            // the runs stay on the period
+            Err(_) => 0.5,
+        }
+    }
+}
+
+/// Largest shift of the runs of `interface_name` every `period`, none unless set in `config`.
+pub fn jitter(config: &TelemetryConfig, interface_name: &str, period: Duration) -> Duration {
+    let percent = config
+        .get(interface_name)
+        .and_then(|config| config.jitter_percent)
+        .unwrap_or(0)
+        .min(MAX_JITTER_PERCENT);
+
+    period * u32::from(percent) / 100
+}
+
+/// Wait before the first run of `interface_name`: the initial delay set in `config`, plus up to
+/// the jitter.
+pub fn first_run_delay(
+    config: &TelemetryConfig,
+    interface_name: &str,
+    period: Duration,
+    random: &dyn Random,
+) -> Duration {
+    let initial_delay = config
+        .get(interface_name)
+        .and_then(|config| config.initial_delay_secs)
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    let jitter = jitter(config, interface_name, period);
+    if jitter.is_zero() {
+        return initial_delay;
+    }
+
+    initial_delay + jitter.mul_f64(random.fraction().clamp(0.0, 1.0))
+}
+
+/// Wait `delay` before the first run of `interface_name`, returning when the run starts.
+pub async fn wait_first_run(
+    clock: &dyn Clock,
+    schedule: Option<&TelemetrySchedule>,
+    interface_name: &str,
+    delay: Duration,
+) -> Instant {
+    let start = clock.now_monotonic() + delay;
+    if delay.is_zero() {
+        return start;
+    }
+
+    info!("First run of {interface_name} delayed by {:?}", delay);
+    if let Some(schedule) = schedule {
+        schedule.next_run(interface_name, Some(delay));
+    }
+    clock.sleep_until(start).await;
+
+    start
+}
+
+/// `period` moved earlier or later by up to the jitter of `interface_name`, drawn again at every
+/// call.
+pub fn jittered_period(
+    config: &TelemetryConfig,
+    interface_name: &str,
+    period: Duration,
+    random: &dyn Random,
+) -> Duration {
+    let jitter = jitter(config, interface_name, period);
+    if jitter.is_zero() {
+        return period;
+    }
+
+    (period + jitter.mul_f64(2.0 * random.fraction().clamp(0.0, 1.0))).saturating_sub(jitter)
+}
+
+/// Apply `event` to `config`, dropping the interfaces left without any setting.
+fn apply(config: &mut TelemetryConfig, event: TelemetryConfigEvent) {
+    let entry = config.entry(event.interface_name.clone()).or_default();
+
+    match event.change {
+        TelemetryConfigChange::Enabled(enabled) => entry.enabled = enabled,
+        TelemetryConfigChange::Period(period) => entry.period_secs = period,
+        TelemetryConfigChange::InitialDelay(delay) => entry.initial_delay_secs = delay,
+        TelemetryConfigChange::Jitter(percent) => entry.jitter_percent = percent,
+    }
+
+    if *entry == TelemetryInterfaceConfig::default() {
+        config.remove(&event.interface_name);
+    }
+}
+
+/// Receives the telemetry configuration changes, persisting and publishing them to the
+/// telemetry scheduler in batches.
+pub struct TelemetryConfigWorker<'a> {
+    clock: Arc<dyn Clock>,
+    repository: Box<dyn StateRepository<TelemetryConfig> + 'a>,
+    window: Duration,
+    config: watch::Sender<TelemetryConfig>,
+}
+
+impl<'a> TelemetryConfigWorker<'a> {
+    /// Create the worker and the receiver of the effective configuration, initialized with the
+    /// persisted one.
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        repository: Box<dyn StateRepository<TelemetryConfig> + 'a>,
+        window: Duration,
+    ) -> (Self, watch::Receiver<TelemetryConfig>) {
+        let initial = if repository.exists() {
+            repository.read().unwrap_or_else(|err| {
+                warn!(
+                    "Unable to read the telemetry config, using defaults: {:?}",
+                    err
+                );
+                TelemetryConfig::new()
+            })
+        } else {
+            TelemetryConfig::new()
+        };
+
+        let (config, receiver) = watch::channel(initial);
+
+        (
+            TelemetryConfigWorker {
+                clock,
+                repository,
+                window,
+                config,
+            },
+            receiver,
+        )
+    }
+
+    /// Apply the received changes until the channel is closed.
+    pub async fn run(&self, mut events: mpsc::Receiver<TelemetryConfigEvent>) {
+        while let Some(first) = events.recv().await {
+            let mut batch = vec![first];
+
+            let deadline = self.clock.now_monotonic() + self.window;
+            loop {
+                tokio::select! {
+                    _ = self.clock.sleep_until(deadline) => break,
+                    event = events.recv() => match event {
+                        Some(event) => batch.push(event),
+                        None => break,
+                    },
+                }
+            }
+
+            self.apply_batch(batch);
+        }
+    }
+
+    fn apply_batch(&self, batch: Vec<TelemetryConfigEvent>) {
+        let received = batch.len();
+        let mut config = self.config.borrow().clone();
+        for event in batch {
+            apply(&mut config, event);
+        }
+
+        if config == *self.config.borrow() {
+            info!("Received {received} telemetry config changes, none effective");
+            return;
+        }
+
+        if let Err(err) = self.repository.write(&config) {
+            error!("Unable to persist the telemetry config: {:?}", err);
+        }
+
+        info!(
+            "Applied {received} telemetry config changes, {} interfaces configured",
+            config.len()
+        );
+        // the scheduler may be gone during shutdown
+        let _ = self.config.send(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::mpsc;
+
+    use crate::repository::MockStateRepository;
+    use crate::telemetry::config::{
+        first_run_delay, jittered_period, MockRandom, TelemetryConfig, TelemetryConfigChange,
+        TelemetryConfigEvent, TelemetryConfigWorker, TelemetryInterfaceConfig,
+    };
+    use crate::test_utils::{settle, ManualClock};
+
+    const SYSTEM_STATUS: &str = "io.edgehog.devicemanager.SystemStatus";
+    const STORAGE_USAGE: &str = "io.edgehog.devicemanager.StorageUsage";
+
+    fn period(interface_name: &str, period: u64) -> TelemetryConfigEvent {
+        TelemetryConfigEvent {
+            interface_name: interface_name.to_owned(),
+            change: TelemetryConfigChange::Period(Some(period)),
+        }
+    }
+
+    fn enabled(interface_name: &str, enabled: bool) -> TelemetryConfigEvent {
+        TelemetryConfigEvent {
+            interface_name: interface_name.to_owned(),
+            change: TelemetryConfigChange::Enabled(Some(enabled)),
+        }
+    }
+
+    #[test]
+    fn parse_property() {
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "periodSeconds"],
+                &AstarteType::LongInteger(60)
+            ),
+            Some(period(SYSTEM_STATUS, 60))
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "enable"],
+                &AstarteType::Boolean(false)
+            ),
+            Some(enabled(SYSTEM_STATUS, false))
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "enable"],
+                &AstarteType::Unset
+            ),
+            Some(TelemetryConfigEvent {
+                interface_name: SYSTEM_STATUS.to_owned(),
+                change: TelemetryConfigChange::Enabled(None),
+            })
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "periodSeconds"],
+                &AstarteType::Unset
+            ),
+            Some(TelemetryConfigEvent {
+                interface_name: SYSTEM_STATUS.to_owned(),
+                change: TelemetryConfigChange::Period(None),
+            })
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "periodSeconds"],
+                &AstarteType::LongInteger(-1)
+            ),
+            None
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "enable"],
+                &AstarteType::LongInteger(1)
+            ),
+            None
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "jitterPercent"],
+                &AstarteType::Integer(20)
+            ),
+            Some(TelemetryConfigEvent {
+                interface_name: SYSTEM_STATUS.to_owned(),
+                change: TelemetryConfigChange::Jitter(Some(20)),
+            })
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "jitterPercent"],
+                &AstarteType::Integer(51)
+            ),
+            None
+        );
+        assert_eq!(
+            TelemetryConfigEvent::from_property(
+                &["request", SYSTEM_STATUS, "initialDelaySeconds"],
+                &AstarteType::Unset
+            ),
+            Some(TelemetryConfigEvent {
+                interface_name: SYSTEM_STATUS.to_owned(),
+                change: TelemetryConfigChange::InitialDelay(None),
+            })
+        );
+    }
+
+    #[test]
+    fn jitter_within_bounds() {
+        let period = Duration::from_secs(100);
+        let configured = |initial_delay_secs, jitter_percent| {
+            [(
+                SYSTEM_STATUS.to_owned(),
+                TelemetryInterfaceConfig {
+                    initial_delay_secs,
+                    jitter_percent,
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect::<TelemetryConfig>()
+        };
+        let random = |fraction: f64| {
+            let mut random = MockRandom::new();
+            random.expect_fraction().return_const(fraction);
+            random
+        };
+
+        // nothing is drawn without jitter
+        let mut unused = MockRandom::new();
+        unused.expect_fraction().never();
+        assert_eq!(
+            first_run_delay(&TelemetryConfig::new(), SYSTEM_STATUS, period, &unused),
+            Duration::ZERO
+        );
+        assert_eq!(
+            jittered_period(&configured(Some(30), None), SYSTEM_STATUS, period, &unused),
+            period
+        );
+        assert_eq!(
+            first_run_delay(&configured(Some(30), None), SYSTEM_STATUS, period, &unused),
+            Duration::from_secs(30)
+        );
+
+        let config = configured(Some(30), Some(20));
+        assert_eq!(
+            first_run_delay(&config, SYSTEM_STATUS, period, &random(0.5)),
+            Duration::from_secs(40)
+        );
+        assert_eq!(
+            jittered_period(&config, SYSTEM_STATUS, period, &random(0.0)),
+            Duration::from_secs(80)
+        );
+        assert_eq!(
+            jittered_period(&config, SYSTEM_STATUS, period, &random(1.0)),
+            Duration::from_secs(120)
+        );
+        // the other interfaces keep their period
+        assert_eq!(
+            jittered_period(&config, STORAGE_USAGE, period, &random(1.0)),
+            period
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_burst_persisted_once() {
+        let clock = Arc::new(ManualClock::new());
+
+        let mut repository = MockStateRepository::<TelemetryConfig>::new();
+        repository.expect_exists().returning(|| false);
+        repository
+            .expect_write()
+            .times(1)
+            .withf(|config: &TelemetryConfig| {
+                config
+                    .get(SYSTEM_STATUS)
+                    .and_then(|config| config.period_secs)
+                    == Some(30)
+            })
+            .returning(|_| Ok(()));
+
+        let (worker, mut config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(repository),
+            Duration::from_millis(500),
+        );
+        let (tx, rx) = mpsc::channel(32);
+        let handle = tokio::spawn(async move { worker.run(rx).await });
+
+        // contradictory values for the same key, the last one wins
+        tx.send(period(SYSTEM_STATUS, 10)).await.unwrap();
+        tx.send(period(STORAGE_USAGE, 3600)).await.unwrap();
+        tx.send(enabled(SYSTEM_STATUS, true)).await.unwrap();
+        tx.send(period(SYSTEM_STATUS, 30)).await.unwrap();
+        tx.send(enabled(SYSTEM_STATUS, false)).await.unwrap();
+        settle().await;
+        assert!(config.borrow().is_empty());
+
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+
+        assert!(config.has_changed().unwrap());
+        let expected: TelemetryConfig = [
+            (
+                SYSTEM_STATUS.to_owned(),
+                TelemetryInterfaceConfig {
+                    enabled: Some(false),
+                    period_secs: Some(30),
+                    ..Default::default()
+                },
+            ),
+            (
+                STORAGE_USAGE.to_owned(),
+                TelemetryInterfaceConfig {
+                    enabled: None,
+                    period_secs: Some(3600),
+                    ..Default::default()
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(*config.borrow_and_update(), expected);
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unchanged_batch_not_persisted() {
+        let clock = Arc::new(ManualClock::new());
+
+        let mut repository = MockStateRepository::<TelemetryConfig>::new();
+        repository.expect_exists().returning(|| false);
+        repository.expect_write().times(1).returning(|_| Ok(()));
+
+        let (worker, mut config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(repository),
+            Duration::from_millis(500),
+        );
+
+        let (tx, rx) = mpsc::channel(32);
+        let handle = tokio::spawn(async move { worker.run(rx).await });
+
+        tx.send(period(SYSTEM_STATUS, 60)).await.unwrap();
+        tx.send(enabled(SYSTEM_STATUS, true)).await.unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+        assert!(config.has_changed().unwrap());
+        assert_eq!(
+            config.borrow_and_update().get(SYSTEM_STATUS),
+            Some(&TelemetryInterfaceConfig {
+                enabled: Some(true),
+                period_secs: Some(60),
+                ..Default::default()
+            })
+        );
+
+        // replayed after a reconnection, ending with the same values
+        tx.send(period(SYSTEM_STATUS, 60)).await.unwrap();
+        tx.send(enabled(SYSTEM_STATUS, false)).await.unwrap();
+        tx.send(enabled(SYSTEM_STATUS, true)).await.unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+
+        assert!(!config.has_changed().unwrap());
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    fn persisted(config: TelemetryInterfaceConfig) -> MockStateRepository<TelemetryConfig> {
+        let persisted: TelemetryConfig = [(SYSTEM_STATUS.to_owned(), config)].into_iter().collect();
+
+        let mut repository = MockStateRepository::<TelemetryConfig>::new();
+        repository.expect_exists().returning(|| true);
+        repository
+            .expect_read()
+            .returning(move || Ok(persisted.clone()));
+        repository
+    }
+
+    #[tokio::test]
+    async fn persisted_config_restored() {
+        let clock = Arc::new(ManualClock::new());
+        let overridden = TelemetryInterfaceConfig {
+            enabled: Some(true),
+            period_secs: Some(60),
+            ..Default::default()
+        };
+
+        let mut repository = persisted(overridden.clone());
+        repository.expect_write().never();
+
+        let (worker, config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(repository),
+            Duration::from_millis(500),
+        );
+        assert_eq!(config.borrow().get(SYSTEM_STATUS), Some(&overridden));
+
+        // the same values replayed after the restart
+        let (tx, rx) = mpsc::channel(32);
+        let handle = tokio::spawn(async move { worker.run(rx).await });
+
+        tx.send(period(SYSTEM_STATUS, 60)).await.unwrap();
+        tx.send(enabled(SYSTEM_STATUS, true)).await.unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+
+        assert!(!config.has_changed().unwrap());
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unset_removes_the_override() {
+        let clock = Arc::new(ManualClock::new());
+
+        let mut repository = persisted(TelemetryInterfaceConfig {
+            enabled: Some(false),
+            period_secs: Some(60),
+            ..Default::default()
+        });
+        repository
+            .expect_write()
+            .times(1)
+            .withf(|config: &TelemetryConfig| config.is_empty())
+            .returning(|_| Ok(()));
+
+        let (worker, mut config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(repository),
+            Duration::from_millis(500),
+        );
+        let (tx, rx) = mpsc::channel(32);
+        let handle = tokio::spawn(async move { worker.run(rx).await });
+
+        for change in [
+            TelemetryConfigChange::Period(None),
+            TelemetryConfigChange::Enabled(None),
+        ] {
+            tx.send(TelemetryConfigEvent {
+                interface_name: SYSTEM_STATUS.to_owned(),
+                change,
+            })
+            .await
+            .unwrap();
+        }
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+
+        assert!(config.has_changed().unwrap());
+        assert!(config.borrow_and_update().is_empty());
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! On-demand send of the telemetry, requested with the `send_telemetry` command.
+//!
+//! The command sends a fresh value of every telemetry interface that can be collected once, or
+//! `send_telemetry {interface_name}` of just that one. The periodic collectors keep their
+//! schedule, the requests are served by their own task so a slow collection doesn't hold the
+//! received messages.
+
+use std::future::Future;
+
+use log::{info, warn};
+use tokio::sync::mpsc;
+
+use crate::redaction::redactor;
+
+pub const FLUSH_COMMAND: &str = "send_telemetry";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushRequest {
+    /// The interface to send, all of them when unset.
+    pub interface_name: Option<String>,
+}
+
+impl FlushRequest {
+    /// Parse `send_telemetry [interface_name]`.
+    pub fn from_command(command: &str) -> Option<Self> {
+        let mut words = command.split_whitespace();
+        if words.next() != Some(FLUSH_COMMAND) {
+            return None;
+        }
+
+        match (words.next(), words.next()) {
+            (interface_name, None) => Some(FlushRequest {
+                interface_name: interface_name.map(str::to_owned),
+            }),
+            (_, Some(_)) => None,
+        }
+    }
+}
+
+/// Serve the flush requests, calling `refresh` with each requested interface among `interfaces`.
+pub async fn run<F, Fut>(
+    interfaces: &[&str],
+    mut requests: mpsc::Receiver<FlushRequest>,
+    refresh: F,
+) where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    while let Some(request) = requests.recv().await {
+        let flushed: Vec<&str> = match &request.interface_name {
+            Some(interface_name) if interfaces.contains(&interface_name.as_str()) => {
+                vec![interface_name.as_str()]
+            }
+            Some(interface_name) => {
+                warn!(
+                    "No telemetry to send for {}, ignoring the request",
+                    redactor().text(interface_name)
+                );
+                continue;
+            }
+            None if interfaces.is_empty() => {
+                warn!("No telemetry to send, ignoring the request");
+                continue;
+            }
+            None => interfaces.to_vec(),
+        };
+
+        info!("Sending the telemetry of {} on request", flushed.join(", "));
+        for interface_name in flushed {
+            refresh(interface_name.to_owned()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::sync::mpsc;
+
+    use crate::data::{MockPublisher, Publisher};
+    use crate::interfaces::{SYSTEM_LOAD_INTERFACE, SYSTEM_STATUS_INTERFACE};
+    use crate::telemetry::flush::{self, FlushRequest};
+    use crate::telemetry::system_status::{self, SystemStatus};
+
+    #[test]
+    fn command_parsed() {
+        assert_eq!(
+            FlushRequest::from_command("send_telemetry"),
+            Some(FlushRequest {
+                interface_name: None
+            })
+        );
+        assert_eq!(
+            FlushRequest::from_command(" send_telemetry  io.edgehog.devicemanager.SystemStatus "),
+            Some(FlushRequest {
+                interface_name: Some(SYSTEM_STATUS_INTERFACE.to_owned())
+            })
+        );
+        assert_eq!(FlushRequest::from_command("send_telemetry a b"), None);
+        assert_eq!(FlushRequest::from_command("send_telemetryx"), None);
+    }
+
+    #[tokio::test]
+    async fn system_status_sent_on_request() {
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object()
+            .withf(|interface: &str, path: &str, _: &SystemStatus| {
+                interface == SYSTEM_STATUS_INTERFACE && path == "/systemStatus"
+            })
+            .times(1)
+            .returning(|_, _, _: SystemStatus| Ok(()));
+        let publisher = Arc::new(publisher);
+
+        let (requests_tx, requests) = mpsc::channel(1);
+        requests_tx
+            .send(FlushRequest {
+                interface_name: Some(SYSTEM_STATUS_INTERFACE.to_owned()),
+            })
+            .await
+            .unwrap();
+        drop(requests_tx);
+
+        flush::run(&[SYSTEM_STATUS_INTERFACE], requests, |interface_name| {
+            let publisher = publisher.clone();
+            async move {
+                assert_eq!(interface_name, SYSTEM_STATUS_INTERFACE);
+                let system_status = system_status::get_system_status().unwrap();
+                publisher
+                    .send_object(SYSTEM_STATUS_INTERFACE, "/systemStatus", system_status)
+                    .await
+                    .unwrap();
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unknown_interface_ignored() {
+        let (requests_tx, requests) = mpsc::channel(4);
+        for interface_name in [Some("io.edgehog.devicemanager.Unknown"), None] {
+            requests_tx
+                .send(FlushRequest {
+                    interface_name: interface_name.map(str::to_owned),
+                })
+                .await
+                .unwrap();
+        }
+        drop(requests_tx);
+
+        let refreshed = Arc::new(Mutex::new(Vec::new()));
+        let interfaces = [SYSTEM_STATUS_INTERFACE, SYSTEM_LOAD_INTERFACE];
+        flush::run(&interfaces, requests, |interface_name| {
+            let refreshed = refreshed.clone();
+            async move { refreshed.lock().unwrap().push(interface_name) }
+        })
+        .await;
+
+        // only the request of every interface is served
+        assert_eq!(*refreshed.lock().unwrap(), interfaces);
+    }
+}
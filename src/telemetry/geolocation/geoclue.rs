@@ -0,0 +1,203 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Position from GeoClue, the location service of the system bus.
+//!
+//! A GeoClue client is created and started on the first run, then kept running so that GeoClue
+//! refreshes its location between the runs. GeoClue only serves the desktop ids with a section in
+//! its configuration, `[edgehog-device-runtime]` unless another one is configured.
+
+use async_trait::async_trait;
+#[cfg(feature = "dbus")]
+use chrono::{TimeZone, Utc};
+#[cfg(feature = "dbus")]
+use log::info;
+use serde::Deserialize;
+#[cfg(feature = "dbus")]
+use tokio::sync::Mutex;
+#[cfg(feature = "dbus")]
+use zbus::dbus_proxy;
+#[cfg(feature = "dbus")]
+use zbus::zvariant::OwnedObjectPath;
+
+use crate::error::DeviceManagerError;
+use crate::telemetry::geolocation::{GeolocationProvider, Position};
+#[cfg(not(feature = "dbus"))]
+use crate::wrapper::platform::no_system_bus;
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::system_bus;
+
+#[cfg(feature = "dbus")]
+const DEFAULT_DESKTOP_ID: &str = "edgehog-device-runtime";
+/// `GCLUE_ACCURACY_LEVEL_EXACT`.
+#[cfg(feature = "dbus")]
+const DEFAULT_ACCURACY_LEVEL: u32 = 8;
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Manager",
+    default_service = "org.freedesktop.GeoClue2",
+    default_path = "/org/freedesktop/GeoClue2/Manager"
+)]
+trait GeoClueManager {
+    /// The client of the caller, created on the first call.
+    fn get_client(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Client",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoClueClient {
+    fn start(&self) -> zbus::Result<()>;
+
+    /// Path of the current location, `/` until the first one is found.
+    #[dbus_proxy(property)]
+    fn location(&self) -> zbus::Result<OwnedObjectPath>;
+
+    #[dbus_proxy(property)]
+    fn set_desktop_id(&self, id: &str) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn set_requested_accuracy_level(&self, level: u32) -> zbus::Result<()>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Location",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoClueLocation {
+    #[dbus_proxy(property)]
+    fn latitude(&self) -> zbus::Result<f64>;
+
+    #[dbus_proxy(property)]
+    fn longitude(&self) -> zbus::Result<f64>;
+
+    /// Radius of the uncertainty, in meters.
+    #[dbus_proxy(property)]
+    fn accuracy(&self) -> zbus::Result<f64>;
+
+    /// Seconds and microseconds since the epoch.
+    #[dbus_proxy(property)]
+    fn timestamp(&self) -> zbus::Result<(u64, u64)>;
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GeoClueOptions {
+    /// Desktop id the client is authorized with, `edgehog-device-runtime` by default.
+    pub desktop_id: Option<String>,
+    /// `GClueAccuracyLevel` requested, 8 for the exact position by default.
+    pub accuracy_level: Option<u32>,
+}
+
+pub struct GeoClueProvider {
+    options: GeoClueOptions,
+    /// The started client, kept for the following runs.
+    #[cfg(feature = "dbus")]
+    client: Mutex<Option<GeoClueClientProxy<'static>>>,
+}
+
+impl GeoClueProvider {
+    pub fn new(options: GeoClueOptions) -> Self {
+        GeoClueProvider {
+            options,
+            #[cfg(feature = "dbus")]
+            client: Mutex::new(None),
+        }
+    }
+
+    #[cfg(feature = "dbus")]
+    async fn start(&self) -> Result<GeoClueClientProxy<'static>, DeviceManagerError> {
+        let connection = system_bus().await?;
+        let path = GeoClueManagerProxy::new(&connection)
+            .await?
+            .get_client()
+            .await?;
+        let client = GeoClueClientProxy::builder(&connection)
+            .path(path.as_str().to_owned())?
+            .build()
+            .await?;
+
+        client
+            .set_desktop_id(
+                self.options
+                    .desktop_id
+                    .as_deref()
+                    .unwrap_or(DEFAULT_DESKTOP_ID),
+            )
+            .await?;
+        client
+            .set_requested_accuracy_level(
+                self.options
+                    .accuracy_level
+                    .unwrap_or(DEFAULT_ACCURACY_LEVEL),
+            )
+            .await?;
+        client.start().await?;
+        info!("GeoClue client started");
+
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl GeolocationProvider for GeoClueProvider {
+    fn source(&self) -> &'static str {
+        "geoclue"
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    async fn locate(&self) -> Result<Position, DeviceManagerError> {
+        Err(no_system_bus())
+    }
+
+    #[cfg(feature = "dbus")]
+    async fn locate(&self) -> Result<Position, DeviceManagerError> {
+        let mut started = self.client.lock().await;
+        let client = match started.take() {
+            Some(client) => client,
+            None => self.start().await?,
+        };
+        let client = started.insert(client);
+
+        let path = client.location().await?;
+        if path.as_str() == "/" {
+            return Err(DeviceManagerError::GeolocationError(
+                "GeoClue has no location yet".to_owned(),
+            ));
+        }
+        let location = GeoClueLocationProxy::builder(client.inner().connection())
+            .path(path.as_str())?
+            .build()
+            .await?;
+        let (secs, micros) = location.timestamp().await?;
+
+        Ok(Position {
+            latitude: location.latitude().await?,
+            longitude: location.longitude().await?,
+            accuracy: location.accuracy().await?,
+            timestamp: i64::try_from(secs)
+                .ok()
+                .map(|secs| Utc.timestamp(secs, (micros % 1_000_000) as u32 * 1000)),
+        })
+    }
+}
@@ -0,0 +1,370 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Position of the device, published on `io.edgehog.devicemanager.Geolocation`.
+//!
+//! The position comes from the provider selected with `geolocation`: GeoClue, or a geolocation
+//! API queried with the WiFi access points in range. A provider failing is logged and retried at
+//! the next run, without affecting the rest of the telemetry.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+#[cfg(test)]
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::GEOLOCATION_INTERFACE;
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig};
+use crate::telemetry::geolocation::geoclue::{GeoClueOptions, GeoClueProvider};
+use crate::telemetry::geolocation::wifi::{
+    NetworkManagerScanner, WifiScanOptions, WifiScanProvider,
+};
+use crate::telemetry::schedule::TelemetrySchedule;
+use crate::telemetry::CollectorRuns;
+
+// the options are only read by the GeoClue client of the system bus
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub(crate) mod geoclue;
+pub(crate) mod wifi;
+
+pub const DEFAULT_GEOLOCATION_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// Provider of the position selected with `geolocation`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum GeolocationOptions {
+    Geoclue(GeoClueOptions),
+    Wifi(WifiScanOptions),
+}
+
+/// A position fix of a provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Radius of the uncertainty, in meters.
+    pub accuracy: f64,
+    /// When the fix was taken, the time of the run when the provider doesn't tell.
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait GeolocationProvider: Send + Sync {
+    /// Name of the provider, published as the source of the positions.
+    fn source(&self) -> &'static str;
+    async fn locate(&self) -> Result<Position, DeviceManagerError>;
+}
+
+/// The provider configured by `options`, connecting to the system bus on its first run.
+pub fn provider(
+    options: &GeolocationOptions,
+    http_client: reqwest::Client,
+) -> Box<dyn GeolocationProvider> {
+    match options {
+        GeolocationOptions::Geoclue(options) => Box::new(GeoClueProvider::new(options.clone())),
+        GeolocationOptions::Wifi(options) => Box::new(WifiScanProvider::new(
+            options.clone(),
+            Box::new(NetworkManagerScanner::new()),
+            http_client,
+        )),
+    }
+}
+
+/// Object published on `io.edgehog.devicemanager.Geolocation`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Geolocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: f64,
+    pub timestamp: DateTime<Utc>,
+    pub source: String,
+}
+
+impl Geolocation {
+    /// The `position` of `source` fixed at `now` unless it has its own timestamp, refusing the
+    /// coordinates out of range.
+    pub fn new(
+        position: Position,
+        source: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Self, DeviceManagerError> {
+        let valid = (-90.0..=90.0).contains(&position.latitude)
+            && (-180.0..=180.0).contains(&position.longitude)
+            && position.accuracy.is_finite()
+            && position.accuracy >= 0.0;
+        if !valid {
+            return Err(DeviceManagerError::GeolocationError(format!(
+                "invalid position {}, {} ± {}",
+                position.latitude, position.longitude, position.accuracy
+            )));
+        }
+
+        Ok(Geolocation {
+            latitude: position.latitude,
+            longitude: position.longitude,
+            accuracy: position.accuracy,
+            timestamp: position.timestamp.unwrap_or(now),
+            source: source.to_owned(),
+        })
+    }
+}
+
+pub struct GeolocationTelemetry {
+    clock: Arc<dyn Clock>,
+    provider: Box<dyn GeolocationProvider>,
+    period: Duration,
+    config: watch::Receiver<TelemetryConfig>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    random: Arc<dyn Random>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl GeolocationTelemetry {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        provider: Box<dyn GeolocationProvider>,
+        period: Duration,
+    ) -> Self {
+        let (_, config) = watch::channel(TelemetryConfig::new());
+        let (_, shutdown) = watch::channel(false);
+
+        GeolocationTelemetry {
+            clock,
+            provider,
+            period,
+            config,
+            schedule: None,
+            random: Arc::new(OsRandom),
+            shutdown,
+        }
+    }
+
+    /// Follow the period and the enabling set by the backend in `config`.
+    pub fn with_config(mut self, config: watch::Receiver<TelemetryConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(self, publisher: &impl Publisher) {
+        let runs = CollectorRuns::new(
+            GEOLOCATION_INTERFACE,
+            "Geolocation",
+            self.clock.clone(),
+            self.config.clone(),
+            self.period,
+        )
+        .with_schedule(self.schedule.clone())
+        .with_random(self.random.clone())
+        .with_shutdown(self.shutdown.clone());
+
+        let telemetry = &self;
+        runs.run((), |(), enabled| async move {
+            if enabled {
+                telemetry.publish(publisher).await;
+            }
+        })
+        .await;
+    }
+
+    /// Publish the position of the provider, if it has one.
+    async fn publish(&self, publisher: &impl Publisher) {
+        let source = self.provider.source();
+        let now = DateTime::<Utc>::from(self.clock.now_wall());
+        let geolocation = match self
+            .provider
+            .locate()
+            .await
+            .and_then(|position| Geolocation::new(position, source, now))
+        {
+            Ok(geolocation) => geolocation,
+            Err(err) => {
+                warn!("Unable to locate the device with {source}: {:?}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = publisher
+            .send_object(GEOLOCATION_INTERFACE, "/location", geolocation)
+            .await
+        {
+            error!("Unable to send the geolocation: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::clock::SystemClock;
+    use crate::data::MockPublisher;
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::GEOLOCATION_INTERFACE;
+    use crate::telemetry::geolocation::{
+        Geolocation, GeolocationOptions, GeolocationTelemetry, MockGeolocationProvider, Position,
+    };
+
+    fn position() -> Position {
+        Position {
+            latitude: 45.4642,
+            longitude: 9.19,
+            accuracy: 25.0,
+            timestamp: Some(Utc.timestamp(1_660_000_000, 0)),
+        }
+    }
+
+    #[test]
+    fn payload_mapped() {
+        let now = Utc.timestamp(1_660_000_600, 0);
+
+        let geolocation = Geolocation::new(position(), "geoclue", now).unwrap();
+        assert_eq!(
+            serde_json::to_value(&geolocation).unwrap(),
+            serde_json::json!({
+                "latitude": 45.4642,
+                "longitude": 9.19,
+                "accuracy": 25.0,
+                "timestamp": "2022-08-08T23:06:40Z",
+                "source": "geoclue",
+            })
+        );
+
+        let untimed = Position {
+            timestamp: None,
+            ..position()
+        };
+        assert_eq!(
+            Geolocation::new(untimed, "wifi", now).unwrap().timestamp,
+            now
+        );
+
+        for invalid in [
+            Position {
+                latitude: 91.0,
+                ..position()
+            },
+            Position {
+                longitude: f64::NAN,
+                ..position()
+            },
+            Position {
+                accuracy: -1.0,
+                ..position()
+            },
+        ] {
+            assert!(matches!(
+                Geolocation::new(invalid, "wifi", now),
+                Err(DeviceManagerError::GeolocationError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn options_parsed() {
+        let options: GeolocationOptions = toml::from_str(
+            r#"
+            type = "wifi"
+            url = "https://location.example.com/v1/geolocate"
+            api_key = "secret"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(options, GeolocationOptions::Wifi(options)
+            if options.api_key.as_deref() == Some("secret")));
+
+        let options: GeolocationOptions = toml::from_str(r#"type = "geoclue""#).unwrap();
+        assert!(matches!(options, GeolocationOptions::Geoclue(_)));
+    }
+
+    #[tokio::test]
+    async fn provider_failure_skips_the_run() {
+        let mut provider = MockGeolocationProvider::new();
+        provider.expect_source().return_const("wifi");
+        let mut failed = false;
+        provider.expect_locate().returning(move || {
+            failed = !failed;
+            if failed {
+                Err(DeviceManagerError::GeolocationError(
+                    "no access points in range".to_owned(),
+                ))
+            } else {
+                Ok(position())
+            }
+        });
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object()
+            .withf(|interface, path, _: &Geolocation| {
+                interface == GEOLOCATION_INTERFACE && path == "/location"
+            })
+            .returning(move |_, _, geolocation: Geolocation| {
+                recorded.lock().unwrap().push(geolocation);
+                Ok(())
+            });
+
+        let telemetry = GeolocationTelemetry::new(
+            Arc::new(SystemClock),
+            Box::new(provider),
+            Duration::from_secs(60),
+        );
+        telemetry.publish(&publisher).await;
+        assert!(sent.lock().unwrap().is_empty());
+
+        telemetry.publish(&publisher).await;
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].source, "wifi");
+        assert_eq!(sent[0].timestamp, position().timestamp.unwrap());
+    }
+}
@@ -0,0 +1,296 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Position from a geolocation API, queried with the WiFi access points in range.
+//!
+//! The access points last scanned by NetworkManager are posted to the configured URL in the
+//! request format of the Google Geolocation API, shared by the Mozilla Location Service and its
+//! successors, and the API answers with the position.
+
+use async_trait::async_trait;
+#[cfg(test)]
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+
+use crate::error::DeviceManagerError;
+use crate::telemetry::geolocation::{GeolocationProvider, Position};
+use crate::telemetry::wifi_scan::{NetworkManagerWifi, WifiSource};
+
+/// The geolocation APIs refuse to locate a single access point.
+const MIN_ACCESS_POINTS: usize = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WifiScanOptions {
+    /// Endpoint of the geolocation API, e.g. `https://www.googleapis.com/geolocation/v1/geolocate`.
+    pub url: String,
+    /// Key of the API, sent as the `key` query parameter.
+    pub api_key: Option<String>,
+}
+
+/// An access point in range.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WifiAccessPoint {
+    pub mac_address: String,
+    /// Signal strength in dBm.
+    pub signal_strength: i32,
+}
+
+/// Access to the access points in range, NetworkManager outside of the tests.
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait AccessPointScanner: Send + Sync {
+    async fn access_points(&self) -> Result<Vec<WifiAccessPoint>, DeviceManagerError>;
+}
+
+/// The access points last scanned by NetworkManager, without requesting a new scan.
+#[derive(Default)]
+pub struct NetworkManagerScanner {
+    wifi: NetworkManagerWifi,
+}
+
+impl NetworkManagerScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl AccessPointScanner for NetworkManagerScanner {
+    async fn access_points(&self) -> Result<Vec<WifiAccessPoint>, DeviceManagerError> {
+        let access_points =
+            self.wifi.access_points(false).await?.ok_or_else(|| {
+                DeviceManagerError::GeolocationError("no wireless device".to_owned())
+            })?;
+
+        Ok(access_points
+            .into_iter()
+            .map(|access_point| WifiAccessPoint {
+                mac_address: access_point.bssid,
+                signal_strength: access_point.rssi,
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LocateRequest<'a> {
+    /// Only the access points are trusted, the address is the one of the VPN or the carrier NAT.
+    consider_ip: bool,
+    wifi_access_points: &'a [WifiAccessPoint],
+}
+
+#[derive(Debug, Deserialize)]
+struct LocateResponse {
+    location: LocateLocation,
+    accuracy: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct LocateLocation {
+    lat: f64,
+    lng: f64,
+}
+
+pub struct WifiScanProvider {
+    options: WifiScanOptions,
+    scanner: Box<dyn AccessPointScanner>,
+    client: reqwest::Client,
+}
+
+impl WifiScanProvider {
+    pub fn new(
+        options: WifiScanOptions,
+        scanner: Box<dyn AccessPointScanner>,
+        client: reqwest::Client,
+    ) -> Self {
+        WifiScanProvider {
+            options,
+            scanner,
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl GeolocationProvider for WifiScanProvider {
+    fn source(&self) -> &'static str {
+        "wifi"
+    }
+
+    async fn locate(&self) -> Result<Position, DeviceManagerError> {
+        let access_points = self.scanner.access_points().await?;
+        if access_points.len() < MIN_ACCESS_POINTS {
+            return Err(DeviceManagerError::GeolocationError(format!(
+                "{} access points in range, at least {MIN_ACCESS_POINTS} needed",
+                access_points.len()
+            )));
+        }
+
+        let body = serde_json::to_string(&LocateRequest {
+            consider_ip: false,
+            wifi_access_points: &access_points,
+        })?;
+        let mut request = self.client.post(&self.options.url);
+        if let Some(api_key) = &self.options.api_key {
+            request = request.query(&[("key", api_key)]);
+        }
+        let response = request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let response: LocateResponse = serde_json::from_slice(&response)?;
+
+        Ok(Position {
+            latitude: response.location.lat,
+            longitude: response.location.lng,
+            accuracy: response.accuracy,
+            timestamp: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+
+    use crate::error::DeviceManagerError;
+    use crate::telemetry::geolocation::wifi::{
+        MockAccessPointScanner, WifiAccessPoint, WifiScanOptions, WifiScanProvider,
+    };
+    use crate::telemetry::geolocation::{GeolocationProvider, Position};
+
+    /// A geolocation API answering `response`, recording the queries and the bodies received.
+    fn locate_server(response: &'static str) -> (SocketAddr, Arc<Mutex<Vec<(String, String)>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let recorded = recorded.clone();
+                    async move {
+                        let query = request.uri().query().unwrap_or_default().to_owned();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push((query, String::from_utf8(body.to_vec()).unwrap()));
+                        Ok::<_, Infallible>(Response::new(Body::from(response)))
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, requests)
+    }
+
+    fn scanner(access_points: Vec<WifiAccessPoint>) -> MockAccessPointScanner {
+        let mut scanner = MockAccessPointScanner::new();
+        scanner
+            .expect_access_points()
+            .returning(move || Ok(access_points.clone()));
+        scanner
+    }
+
+    #[tokio::test]
+    async fn access_points_posted() {
+        let (address, requests) =
+            locate_server(r#"{"location": {"lat": 45.4642, "lng": 9.19}, "accuracy": 40.5}"#);
+        let provider = WifiScanProvider::new(
+            WifiScanOptions {
+                url: format!("http://{address}/v1/geolocate"),
+                api_key: Some("secret".to_owned()),
+            },
+            Box::new(scanner(vec![
+                WifiAccessPoint {
+                    mac_address: "01:23:45:67:89:ab".to_owned(),
+                    signal_strength: -65,
+                },
+                WifiAccessPoint {
+                    mac_address: "01:23:45:67:89:ac".to_owned(),
+                    signal_strength: -80,
+                },
+            ])),
+            reqwest::Client::new(),
+        );
+
+        assert_eq!(
+            provider.locate().await.unwrap(),
+            Position {
+                latitude: 45.4642,
+                longitude: 9.19,
+                accuracy: 40.5,
+                timestamp: None,
+            }
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "key=secret");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&requests[0].1).unwrap(),
+            serde_json::json!({
+                "considerIp": false,
+                "wifiAccessPoints": [
+                    {"macAddress": "01:23:45:67:89:ab", "signalStrength": -65},
+                    {"macAddress": "01:23:45:67:89:ac", "signalStrength": -80},
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn single_access_point_refused() {
+        let (address, requests) = locate_server("{}");
+        let provider = WifiScanProvider::new(
+            WifiScanOptions {
+                url: format!("http://{address}/v1/geolocate"),
+                api_key: None,
+            },
+            Box::new(scanner(vec![WifiAccessPoint {
+                mac_address: "01:23:45:67:89:ab".to_owned(),
+                signal_strength: -65,
+            }])),
+            reqwest::Client::new(),
+        );
+
+        assert!(matches!(
+            provider.locate().await,
+            Err(DeviceManagerError::GeolocationError(_))
+        ));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
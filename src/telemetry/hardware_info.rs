@@ -20,168 +20,361 @@
 
 use crate::error::DeviceManagerError;
 use astarte_sdk::types::AstarteType;
-use procfs::{CpuInfo, Meminfo, ProcResult};
 use std::collections::HashMap;
+use std::path::Path;
 
-/// get structured data for `io.edgehog.devicemanager.HardwareInfo` interface
+const CPUINFO: &str = "proc/cpuinfo";
+const MEMINFO: &str = "proc/meminfo";
+const CPU_DIRECTORY: &str = "sys/devices/system/cpu";
+const DEVICE_TREE: &str = "proc/device-tree";
+
+/// get structured data for `io.edgehog.devicemanager.HardwareInfo` interface, without the fields
+/// that can't be determined on this machine
 pub fn get_hardware_info() -> Result<HashMap<String, AstarteType>, DeviceManagerError> {
-    let mut ret: HashMap<String, AstarteType> = HashMap::new();
+    read_hardware_info(Path::new("/"), nix::sys::utsname::uname().machine())
+}
 
-    let architecture = get_machine_architecture();
-    ret.insert("/cpu/architecture".to_owned(), architecture.into());
+/// The hardware info of the machine whose `/proc` and `/sys` are under `root`.
+fn read_hardware_info(
+    root: &Path,
+    architecture: &str,
+) -> Result<HashMap<String, AstarteType>, DeviceManagerError> {
+    let cpuinfo = parse_cpuinfo(&std::fs::read_to_string(root.join(CPUINFO))?);
+    let mem_total = parse_mem_total(&std::fs::read_to_string(root.join(MEMINFO))?);
+    let cpu_directory = root.join(CPU_DIRECTORY);
+    let device_tree = |name: &str| {
+        std::fs::read(root.join(DEVICE_TREE).join(name))
+            .map(|value| parse_device_tree_strings(&value))
+            .unwrap_or_default()
+    };
 
-    let cpuinfo = get_cpu_info()?;
-    if let Some(f) = cpuinfo.fields.get("model") {
-        ret.insert("/cpu/model".to_owned(), f.clone().into());
-    }
+    // the ARM boards have no model name in cpuinfo, their device tree names them
+    let model_name = cpuinfo
+        .model_name
+        .or_else(|| device_tree("model").into_iter().next());
+    let vendor = cpuinfo
+        .vendor
+        .or_else(|| compatible_vendor(&device_tree("compatible")));
+    let core_count = std::fs::read_to_string(cpu_directory.join("present"))
+        .ok()
+        .and_then(|present| parse_cpu_count(&present))
+        .or_else(|| {
+            i32::try_from(cpuinfo.processors)
+                .ok()
+                .filter(|count| *count > 0)
+        });
 
-    if let Some(f) = cpuinfo.fields.get("model name") {
-        ret.insert("/cpu/modelName".to_owned(), f.clone().into());
+    let mut ret: HashMap<String, AstarteType> = HashMap::new();
+    let strings = [
+        (
+            "/cpu/architecture",
+            Some(architecture.to_owned()).filter(|architecture| !architecture.is_empty()),
+        ),
+        ("/cpu/model", cpuinfo.model),
+        ("/cpu/modelName", model_name),
+        ("/cpu/vendor", vendor),
+    ];
+    for (path, value) in strings {
+        if let Some(value) = value {
+            ret.insert(path.to_owned(), value.into());
+        }
     }
 
-    if let Some(f) = cpuinfo.fields.get("vendor_id") {
-        ret.insert("/cpu/vendor".to_owned(), f.clone().into());
+    if let Some(core_count) = core_count {
+        ret.insert(
+            "/cpu/coreCount".to_owned(),
+            AstarteType::Integer(core_count),
+        );
+    }
+    if let Some(frequency) = max_frequency_hz(&cpu_directory) {
+        ret.insert(
+            "/cpu/maxFrequencyHz".to_owned(),
+            AstarteType::LongInteger(frequency),
+        );
+    }
+    if let Some(mem_total) = mem_total {
+        ret.insert(
+            "/mem/totalBytes".to_owned(),
+            AstarteType::LongInteger(mem_total),
+        );
     }
-
-    let meminfo = get_meminfo()?;
-    ret.insert(
-        "/mem/totalBytes".to_owned(),
-        (meminfo.mem_total as i64).into(),
-    );
 
     Ok(ret)
 }
 
-#[cfg(not(test))]
-fn get_cpu_info() -> ProcResult<CpuInfo> {
-    procfs::CpuInfo::new()
+/// Fields of `/proc/cpuinfo`, the ones of the first processor.
+#[derive(Debug, Default, PartialEq)]
+struct CpuInfo {
+    model: Option<String>,
+    model_name: Option<String>,
+    vendor: Option<String>,
+    /// Processors listed.
+    processors: usize,
 }
 
-#[cfg(not(test))]
-fn get_machine_architecture() -> String {
-    nix::sys::utsname::uname().machine().to_owned()
+fn parse_cpuinfo(cpuinfo: &str) -> CpuInfo {
+    let mut info = CpuInfo::default();
+
+    for line in cpuinfo.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        let field = match key {
+            "processor" => {
+                info.processors += 1;
+                continue;
+            }
+            "model" => &mut info.model,
+            "model name" => &mut info.model_name,
+            "vendor_id" => &mut info.vendor,
+            _ => continue,
+        };
+        if field.is_none() && !value.is_empty() {
+            *field = Some(value.to_owned());
+        }
+    }
+
+    info
 }
 
-#[cfg(not(test))]
-fn get_meminfo() -> ProcResult<Meminfo> {
-    procfs::Meminfo::new()
+/// `MemTotal` of `/proc/meminfo`, in bytes.
+fn parse_mem_total(meminfo: &str) -> Option<i64> {
+    meminfo.lines().find_map(|line| {
+        let kib = line.strip_prefix("MemTotal:")?.trim().strip_suffix("kB")?;
+        kib.trim().parse::<i64>().ok()?.checked_mul(1024)
+    })
 }
 
-#[cfg(test)]
-fn get_cpu_info() -> ProcResult<CpuInfo> {
-    let data = r#"processor       : 0
-vendor_id       : GenuineIntel
-model           : 158
-model name      : ARMv7 Processor rev 10 (v7l)
-BogoMIPS        : 6.00
-Features        : half thumb fastmult vfp edsp neon vfpv3 tls vfpd32
-CPU implementer : 0x41
-CPU architecture: 7
-CPU variant     : 0x2
-CPU part        : 0xc09
-CPU revision    : 10
-
-Hardware        : Freescale i.MX6 SoloX (Device Tree)
-Revision        : 0000
-Serial          : 0000000000000000
-"#;
-
-    let r = std::io::Cursor::new(data.as_bytes());
-
-    Ok(CpuInfo::from_reader(r).unwrap())
+/// CPUs in a list like `0-3,6`, the format of `/sys/devices/system/cpu/present`.
+fn parse_cpu_count(list: &str) -> Option<i32> {
+    let mut count = 0;
+
+    for range in list.trim().split(',') {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first.parse::<i32>().ok()?, last.parse::<i32>().ok()?),
+            None => {
+                let cpu = range.parse::<i32>().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if last < first {
+            return None;
+        }
+        count += last - first + 1;
+    }
+
+    Some(count)
 }
 
-#[cfg(test)]
-fn get_machine_architecture() -> String {
-    "test_architecture".to_owned()
+/// `cpuinfo_max_freq` of a CPU, given in kHz.
+fn parse_frequency_hz(khz: &str) -> Option<i64> {
+    khz.trim().parse::<i64>().ok()?.checked_mul(1000)
 }
 
-#[cfg(test)]
-fn get_meminfo() -> ProcResult<Meminfo> {
-    let data = r#"MemTotal:        1019356 kB
-MemFree:          739592 kB
-MemAvailable:     802296 kB
-Buffers:            7372 kB
-Cached:            88364 kB
-SwapCached:            0 kB
-Active:            41328 kB
-Inactive:          64908 kB
-Active(anon):       1224 kB
-Inactive(anon):    35160 kB
-Active(file):      40104 kB
-Inactive(file):    29748 kB
-Unevictable:           0 kB
-Mlocked:               0 kB
-HighTotal:             0 kB
-HighFree:              0 kB
-LowTotal:        1019356 kB
-LowFree:          739592 kB
-SwapTotal:             0 kB
-SwapFree:              0 kB
-Dirty:                 4 kB
-Writeback:             0 kB
-AnonPages:         10500 kB
-Mapped:            21688 kB
-Shmem:             25884 kB
-KReclaimable:       8452 kB
-Slab:              21180 kB
-SReclaimable:       8452 kB
-SUnreclaim:        12728 kB
-KernelStack:         752 kB
-PageTables:          656 kB
-NFS_Unstable:          0 kB
-Bounce:                0 kB
-WritebackTmp:          0 kB
-CommitLimit:      509676 kB
-Committed_AS:     139696 kB
-VmallocTotal:    1032192 kB
-VmallocUsed:        6648 kB
-VmallocChunk:          0 kB
-Percpu:              376 kB
-CmaTotal:         327680 kB
-CmaFree:          194196 kB
-"#;
-
-    let r = std::io::Cursor::new(data.as_bytes());
-    Ok(Meminfo::from_reader(r).unwrap())
+/// Highest maximum frequency of the CPUs with a frequency scaling driver.
+fn max_frequency_hz(cpu_directory: &Path) -> Option<i64> {
+    std::fs::read_dir(cpu_directory)
+        .ok()?
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("cpu"))
+                .map_or(false, |id| {
+                    !id.is_empty() && id.bytes().all(|c| c.is_ascii_digit())
+                })
+        })
+        .filter_map(|entry| {
+            std::fs::read_to_string(entry.path().join("cpufreq/cpuinfo_max_freq")).ok()
+        })
+        .filter_map(|khz| parse_frequency_hz(&khz))
+        .max()
+}
+
+/// The NUL separated strings of a device tree property.
+fn parse_device_tree_strings(value: &[u8]) -> Vec<String> {
+    value
+        .split(|byte| *byte == 0)
+        .map(|string| String::from_utf8_lossy(string).trim().to_owned())
+        .filter(|string| !string.is_empty())
+        .collect()
+}
+
+/// Vendor of the SoC, the last and most generic of the `compatible` strings, like `fsl` for
+/// `fsl,imx8mm`.
+fn compatible_vendor(compatible: &[String]) -> Option<String> {
+    let (vendor, _) = compatible.last()?.split_once(',')?;
+
+    Some(vendor.to_owned()).filter(|vendor| !vendor.is_empty())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::telemetry::hardware_info::get_hardware_info;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
     use astarte_sdk::types::AstarteType;
 
+    use crate::telemetry::hardware_info::{
+        compatible_vendor, parse_cpu_count, parse_cpuinfo, parse_device_tree_strings,
+        parse_mem_total, read_hardware_info, CpuInfo,
+    };
+
+    fn fixture(machine: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/hardware_info")
+            .join(machine)
+    }
+
+    fn read_fixture(machine: &str, file: &str) -> String {
+        std::fs::read_to_string(fixture(machine).join(file)).unwrap()
+    }
+
+    fn string(value: &str) -> AstarteType {
+        AstarteType::String(value.to_owned())
+    }
+
     #[test]
-    fn hardware_info_test() {
-        let astarte_hardware_info = get_hardware_info().unwrap();
+    fn cpuinfo_parsed() {
+        assert_eq!(
+            parse_cpuinfo(&read_fixture("x86_64", "proc/cpuinfo")),
+            CpuInfo {
+                model: Some("142".to_owned()),
+                model_name: Some("Intel(R) Core(TM) i5-8250U CPU @ 1.60GHz".to_owned()),
+                vendor: Some("GenuineIntel".to_owned()),
+                processors: 8,
+            }
+        );
+        assert_eq!(
+            parse_cpuinfo(&read_fixture("aarch64", "proc/cpuinfo")),
+            CpuInfo {
+                processors: 4,
+                ..CpuInfo::default()
+            }
+        );
         assert_eq!(
-            astarte_hardware_info
-                .get("/cpu/architecture")
-                .unwrap()
-                .to_owned(),
-            AstarteType::String("test_architecture".to_string())
+            parse_cpuinfo(&read_fixture("armv7", "proc/cpuinfo")),
+            CpuInfo {
+                model_name: Some("ARMv7 Processor rev 10 (v7l)".to_owned()),
+                processors: 1,
+                ..CpuInfo::default()
+            }
         );
+    }
+
+    #[test]
+    fn mem_total_in_bytes() {
         assert_eq!(
-            astarte_hardware_info.get("/cpu/model").unwrap().to_owned(),
-            AstarteType::String("158".to_string())
+            parse_mem_total(&read_fixture("armv7", "proc/meminfo")),
+            Some(1043820544)
         );
+        assert_eq!(parse_mem_total("MemFree:          739592 kB\n"), None);
+    }
+
+    #[test]
+    fn cpu_lists_counted() {
+        assert_eq!(parse_cpu_count("0\n"), Some(1));
+        assert_eq!(parse_cpu_count("0-7\n"), Some(8));
+        assert_eq!(parse_cpu_count("0-1,4-5,7"), Some(5));
+        assert_eq!(parse_cpu_count("3-1"), None);
+        assert_eq!(parse_cpu_count(""), None);
+    }
+
+    #[test]
+    fn device_tree_strings_split() {
+        let compatible = parse_device_tree_strings(b"fsl,imx8mm-evk\0fsl,imx8mm\0");
+        assert_eq!(compatible, ["fsl,imx8mm-evk", "fsl,imx8mm"]);
+        assert_eq!(compatible_vendor(&compatible), Some("fsl".to_owned()));
+
+        assert_eq!(compatible_vendor(&["simple-bus".to_owned()]), None);
+        assert_eq!(compatible_vendor(&[]), None);
+    }
+
+    #[test]
+    fn x86_64_hardware_info() {
+        let hardware_info = read_hardware_info(&fixture("x86_64"), "x86_64").unwrap();
+
         assert_eq!(
-            astarte_hardware_info
-                .get("/cpu/modelName")
-                .unwrap()
-                .to_owned(),
-            AstarteType::String("ARMv7 Processor rev 10 (v7l)".to_string())
+            hardware_info,
+            HashMap::from([
+                ("/cpu/architecture".to_owned(), string("x86_64")),
+                ("/cpu/model".to_owned(), string("142")),
+                (
+                    "/cpu/modelName".to_owned(),
+                    string("Intel(R) Core(TM) i5-8250U CPU @ 1.60GHz")
+                ),
+                ("/cpu/vendor".to_owned(), string("GenuineIntel")),
+                ("/cpu/coreCount".to_owned(), AstarteType::Integer(8)),
+                (
+                    "/cpu/maxFrequencyHz".to_owned(),
+                    AstarteType::LongInteger(3_400_000_000)
+                ),
+                (
+                    "/mem/totalBytes".to_owned(),
+                    AstarteType::LongInteger(16710123520)
+                ),
+            ])
         );
+    }
+
+    #[test]
+    fn aarch64_named_by_device_tree() {
+        let hardware_info = read_hardware_info(&fixture("aarch64"), "aarch64").unwrap();
+
         assert_eq!(
-            astarte_hardware_info.get("/cpu/vendor").unwrap().to_owned(),
-            AstarteType::String("GenuineIntel".to_string())
+            hardware_info,
+            HashMap::from([
+                ("/cpu/architecture".to_owned(), string("aarch64")),
+                ("/cpu/modelName".to_owned(), string("FSL i.MX8MM EVK board")),
+                ("/cpu/vendor".to_owned(), string("fsl")),
+                ("/cpu/coreCount".to_owned(), AstarteType::Integer(4)),
+                (
+                    "/cpu/maxFrequencyHz".to_owned(),
+                    AstarteType::LongInteger(1_800_000_000)
+                ),
+                (
+                    "/mem/totalBytes".to_owned(),
+                    AstarteType::LongInteger(2060738560)
+                ),
+            ])
         );
+    }
+
+    #[test]
+    fn armv7_without_frequency_scaling() {
+        let hardware_info = read_hardware_info(&fixture("armv7"), "armv7l").unwrap();
+
         assert_eq!(
-            astarte_hardware_info
-                .get("/mem/totalBytes")
-                .unwrap()
-                .to_owned(),
-            AstarteType::LongInteger(1043820544)
+            hardware_info,
+            HashMap::from([
+                ("/cpu/architecture".to_owned(), string("armv7l")),
+                (
+                    "/cpu/modelName".to_owned(),
+                    string("ARMv7 Processor rev 10 (v7l)")
+                ),
+                ("/cpu/vendor".to_owned(), string("fsl")),
+                ("/cpu/coreCount".to_owned(), AstarteType::Integer(1)),
+                (
+                    "/mem/totalBytes".to_owned(),
+                    AstarteType::LongInteger(1043820544)
+                ),
+            ])
         );
     }
+
+    #[test]
+    fn undetermined_fields_omitted() {
+        let root = tempfile::tempdir().unwrap();
+        let proc = root.path().join("proc");
+        std::fs::create_dir(&proc).unwrap();
+        std::fs::write(proc.join("cpuinfo"), "").unwrap();
+        std::fs::write(proc.join("meminfo"), "").unwrap();
+
+        assert!(read_hardware_info(root.path(), "").unwrap().is_empty());
+
+        std::fs::remove_file(proc.join("meminfo")).unwrap();
+        assert!(read_hardware_info(root.path(), "x86_64").is_err());
+    }
 }
@@ -18,7 +18,1218 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::SYSTEM_STATUS_INTERFACE;
+use crate::metrics::Metrics;
+use crate::ota::download::shutdown_requested;
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig};
+use crate::telemetry::schedule::{ScheduleSource, TelemetrySchedule};
+use crate::watchdog::{Liveness, TELEMETRY_LOOP};
+
+pub(crate) mod backoff;
+pub(crate) mod base_image;
+// only collected from ModemManager on the system bus
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub(crate) mod cellular_connection;
+pub(crate) mod config;
+pub(crate) mod flush;
+pub(crate) mod geolocation;
 pub(crate) mod hardware_info;
+pub(crate) mod net_interfaces;
+pub(crate) mod net_sockets;
 pub(crate) mod os_info;
 pub(crate) mod runtime_info;
+pub(crate) mod schedule;
+pub(crate) mod sent_properties;
+pub(crate) mod storage_usage;
+pub(crate) mod system_info;
+pub(crate) mod system_load;
 pub(crate) mod system_status;
+pub(crate) mod temperature;
+pub(crate) mod wifi_scan;
+
+/// Bulk telemetry periods are multiplied by this factor while on a metered connection, unless
+/// configured otherwise.
+pub const DEFAULT_METERED_PERIOD_FACTOR: u32 = 4;
+/// Period of `io.edgehog.devicemanager.SystemStatus`, unless set by the backend.
+pub const DEFAULT_SYSTEM_STATUS_PERIOD: Duration = Duration::from_secs(1);
+
+/// Periodically collects and publishes the telemetry datastreams.
+pub struct Telemetry {
+    clock: Arc<dyn Clock>,
+    system_status_period: Duration,
+    metered: watch::Receiver<bool>,
+    metered_period_factor: u32,
+    config: watch::Receiver<TelemetryConfig>,
+    /// Factor of the period set by the backoff, never changing unless enabled.
+    backoff: watch::Receiver<u32>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    random: Arc<dyn Random>,
+    liveness: Option<Arc<Liveness>>,
+    metrics: Option<Arc<Metrics>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Telemetry {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        system_status_period: Duration,
+        metered: watch::Receiver<bool>,
+        metered_period_factor: u32,
+        config: watch::Receiver<TelemetryConfig>,
+    ) -> Self {
+        let (_, backoff) = watch::channel(1);
+        let (_, shutdown) = watch::channel(false);
+
+        Telemetry {
+            clock,
+            system_status_period,
+            metered,
+            metered_period_factor,
+            config,
+            backoff,
+            schedule: None,
+            random: Arc::new(OsRandom),
+            liveness: None,
+            metrics: None,
+            shutdown,
+        }
+    }
+
+    /// Stretch the period by the factor of `backoff`, when enabled.
+    pub fn with_backoff(mut self, backoff: Option<watch::Receiver<u32>>) -> Self {
+        if let Some(backoff) = backoff {
+            self.backoff = backoff;
+        }
+        self
+    }
+
+    /// Keep the entry of `io.edgehog.devicemanager.SystemStatus` in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Report the progress of the runs to the watchdog heartbeat.
+    pub fn with_liveness(mut self, liveness: Arc<Liveness>) -> Self {
+        self.liveness = Some(liveness);
+        self
+    }
+
+    /// Count the system status sent in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Send `io.edgehog.devicemanager.SystemStatus` every period, until the shutdown.
+    pub async fn run(&self, publisher: &impl Publisher) {
+        let mut metered = self.metered.clone();
+        let mut config = self.config.clone();
+        let mut backoff = self.backoff.clone();
+        let mut shutdown = self.shutdown.clone();
+        let period = self.effective_period(*metered.borrow(), &config.borrow());
+        let delay = config::first_run_delay(
+            &config.borrow(),
+            SYSTEM_STATUS_INTERFACE,
+            period,
+            self.random.as_ref(),
+        );
+        let mut last_tick = config::wait_first_run(
+            self.clock.as_ref(),
+            self.schedule.as_deref(),
+            SYSTEM_STATUS_INTERFACE,
+            delay,
+        )
+        .await;
+
+        loop {
+            if self.enabled(&config.borrow()) {
+                if let Some(liveness) = &self.liveness {
+                    liveness.working(TELEMETRY_LOOP);
+                }
+                self.send_system_status(publisher).await;
+                if let Some(liveness) = &self.liveness {
+                    liveness.waiting(TELEMETRY_LOOP);
+                }
+            }
+
+            // the deadline, and its jitter, is recomputed whenever the metered state flips, the
+            // configuration or the backoff changes while waiting
+            loop {
+                let period = self.effective_period(*metered.borrow(), &config.borrow());
+                let deadline = last_tick
+                    + config::jittered_period(
+                        &config.borrow(),
+                        SYSTEM_STATUS_INTERFACE,
+                        period,
+                        self.random.as_ref(),
+                    );
+                self.report_schedule(&config.borrow(), period, deadline);
+
+                tokio::select! {
+                    _ = self.clock.sleep_until(deadline) => {
+                        last_tick = deadline;
+                        break;
+                    }
+                    _ = shutdown_requested(&mut shutdown) => return,
+                    changed = metered.changed() => {
+                        if changed.is_err() {
+                            self.clock.sleep_until(deadline).await;
+                            last_tick = deadline;
+                            break;
+                        }
+                        info!(
+                            "Telemetry period set to {:?}",
+                            self.effective_period(*metered.borrow(), &config.borrow())
+                        );
+                    }
+                    changed = config.changed(), if config.has_changed().is_ok() => {
+                        if changed.is_ok() {
+                            info!(
+                                "Telemetry period set to {:?}",
+                                self.effective_period(*metered.borrow(), &config.borrow())
+                            );
+                        }
+                    }
+                    changed = backoff.changed(), if backoff.has_changed().is_ok() => {
+                        if changed.is_ok() {
+                            info!(
+                                "Telemetry period set to {:?}",
+                                self.effective_period(*metered.borrow(), &config.borrow())
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn report_schedule(&self, config: &TelemetryConfig, period: Duration, deadline: Instant) {
+        let schedule = match &self.schedule {
+            Some(schedule) => schedule,
+            None => return,
+        };
+
+        let enabled = self.enabled(config);
+        let source = if config.contains_key(SYSTEM_STATUS_INTERFACE) {
+            ScheduleSource::Runtime
+        } else {
+            ScheduleSource::Default
+        };
+        let backoff_factor = (*self.backoff.borrow()).max(1);
+        let jitter = config::jitter(config, SYSTEM_STATUS_INTERFACE, period);
+        schedule.update(SYSTEM_STATUS_INTERFACE, |collector| {
+            collector.source = source;
+            collector.period_secs = period.as_secs();
+            collector.backoff_factor = backoff_factor;
+            collector.jitter_secs = jitter.as_secs();
+            collector.enabled = enabled;
+        });
+        schedule.next_run(
+            SYSTEM_STATUS_INTERFACE,
+            enabled.then(|| deadline.saturating_duration_since(self.clock.now_monotonic())),
+        );
+    }
+
+    fn enabled(&self, config: &TelemetryConfig) -> bool {
+        config
+            .get(SYSTEM_STATUS_INTERFACE)
+            .and_then(|config| config.enabled)
+            .unwrap_or(true)
+    }
+
+    /// The Bulk telemetry period, as configured by the server and stretched while the
+    /// connection is metered or the publishes keep failing.
+    fn effective_period(&self, metered: bool, config: &TelemetryConfig) -> Duration {
+        let period = config
+            .get(SYSTEM_STATUS_INTERFACE)
+            .and_then(|config| config.period_secs)
+            .filter(|period| *period > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(self.system_status_period);
+
+        let (period, _) = backoff::backed_off(period, Some(&self.backoff));
+        if metered {
+            period * self.metered_period_factor.max(1)
+        } else {
+            period
+        }
+    }
+
+    async fn send_system_status(&self, publisher: &impl Publisher) {
+        let collected = DateTime::<Utc>::from(self.clock.now_wall());
+        let system_status = match system_status::get_system_status() {
+            Ok(system_status) => system_status,
+            Err(err) => {
+                error!("Unable to collect system status: {:?}", err);
+                return;
+            }
+        };
+
+        match publisher
+            .send_object_with_timestamp(
+                SYSTEM_STATUS_INTERFACE,
+                "/systemStatus",
+                system_status,
+                collected,
+            )
+            .await
+        {
+            Ok(()) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.telemetry_sent();
+                }
+            }
+            Err(err) => error!("Unable to send system status: {:?}", err),
+        }
+    }
+}
+
+/// Tasks of the telemetry collectors, stopped between two runs by the shutdown.
+pub struct TelemetryTasks {
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TelemetryTasks {
+    pub fn new() -> Self {
+        let (shutdown, _) = watch::channel(false);
+
+        TelemetryTasks {
+            shutdown,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Shutdown to hand to the collectors, set by [`TelemetryTasks::shutdown`].
+    pub fn signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    pub fn spawn(&mut self, collector: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.push(tokio::spawn(collector));
+    }
+
+    /// Let the collectors complete the run in progress and stop, the ones still running after
+    /// `timeout` are aborted.
+    pub async fn shutdown(self, clock: &dyn Clock, timeout: Duration) {
+        self.shutdown.send(true).ok();
+
+        let deadline = clock.now_monotonic() + timeout;
+        for mut task in self.tasks {
+            tokio::select! {
+                _ = &mut task => {}
+                _ = clock.sleep_until(deadline) => {
+                    warn!("Telemetry collector still running after {:?}, aborted", timeout);
+                    task.abort();
+                }
+            }
+        }
+    }
+}
+
+impl Default for TelemetryTasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs of a collector publishing on `interface`, following the period, the enabling and the
+/// jitter set by the backend.
+pub(crate) struct CollectorRuns {
+    interface: &'static str,
+    /// Name of the collector in the logs.
+    name: &'static str,
+    clock: Arc<dyn Clock>,
+    config: watch::Receiver<TelemetryConfig>,
+    period: Duration,
+    /// Floor of the period set by the backend.
+    min_period: Duration,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    random: Arc<dyn Random>,
+    backoff: Option<watch::Receiver<u32>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl CollectorRuns {
+    pub(crate) fn new(
+        interface: &'static str,
+        name: &'static str,
+        clock: Arc<dyn Clock>,
+        config: watch::Receiver<TelemetryConfig>,
+        period: Duration,
+    ) -> Self {
+        let (_, shutdown) = watch::channel(false);
+
+        CollectorRuns {
+            interface,
+            name,
+            clock,
+            config,
+            period,
+            min_period: Duration::ZERO,
+            schedule: None,
+            random: Arc::new(OsRandom),
+            backoff: None,
+            shutdown,
+        }
+    }
+
+    /// Never run more often than every `min_period`, whatever the period set by the backend.
+    pub(crate) fn with_min_period(mut self, min_period: Duration) -> Self {
+        self.min_period = min_period;
+        self
+    }
+
+    /// Keep the entry of the collector in `schedule` up to date.
+    pub(crate) fn with_schedule(mut self, schedule: Option<Arc<TelemetrySchedule>>) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub(crate) fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Stretch the period by the factor of `backoff`, when enabled.
+    pub(crate) fn with_backoff(mut self, backoff: Option<watch::Receiver<u32>>) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub(crate) fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Period set in `config`, or the one of the collector.
+    pub(crate) fn period(&self, config: &TelemetryConfig) -> Duration {
+        config::period(config, self.interface)
+            .map_or(self.period, |period| period.max(self.min_period))
+    }
+
+    /// Call `collect` at every run with whether the collector is enabled, until the shutdown.
+    ///
+    /// The `state` is handed to `collect` and given back by its future, to be kept by the
+    /// collector from one run to the next.
+    pub(crate) async fn run<S, F, Fut>(self, mut state: S, mut collect: F)
+    where
+        F: FnMut(S, bool) -> Fut,
+        Fut: Future<Output = S>,
+    {
+        let mut config = self.config.clone();
+        let mut shutdown = self.shutdown.clone();
+        // the source set by the configuration file, restored when the backend unsets its own
+        let initial_source = self.schedule.as_ref().and_then(|schedule| {
+            schedule
+                .snapshot()
+                .get(self.interface)
+                .map(|collector| collector.source)
+        });
+        let period = self.period(&config.borrow());
+        let delay = config::first_run_delay(
+            &config.borrow(),
+            self.interface,
+            period,
+            self.random.as_ref(),
+        );
+        let mut last_tick = config::wait_first_run(
+            self.clock.as_ref(),
+            self.schedule.as_deref(),
+            self.interface,
+            delay,
+        )
+        .await;
+
+        loop {
+            let enabled = config::is_enabled(&config.borrow(), self.interface);
+            state = collect(state, enabled).await;
+
+            // a change of the configuration moves the pending run to the new period
+            loop {
+                let (period, backoff_factor) =
+                    backoff::backed_off(self.period(&config.borrow()), self.backoff.as_ref());
+                let deadline = last_tick
+                    + config::jittered_period(
+                        &config.borrow(),
+                        self.interface,
+                        period,
+                        self.random.as_ref(),
+                    );
+                if let Some(schedule) = &self.schedule {
+                    let enabled = config::is_enabled(&config.borrow(), self.interface);
+                    let source = if config.borrow().contains_key(self.interface) {
+                        ScheduleSource::Runtime
+                    } else {
+                        initial_source.unwrap_or(ScheduleSource::Default)
+                    };
+                    let jitter = config::jitter(&config.borrow(), self.interface, period);
+                    schedule.update(self.interface, |collector| {
+                        collector.source = source;
+                        collector.period_secs = period.as_secs();
+                        collector.jitter_secs = jitter.as_secs();
+                        collector.backoff_factor = backoff_factor;
+                        collector.enabled = enabled;
+                    });
+                    schedule.next_run(
+                        self.interface,
+                        enabled.then(|| {
+                            deadline.saturating_duration_since(self.clock.now_monotonic())
+                        }),
+                    );
+                }
+
+                tokio::select! {
+                    _ = self.clock.sleep_until(deadline) => {
+                        last_tick = deadline;
+                        break;
+                    }
+                    _ = shutdown_requested(&mut shutdown) => return,
+                    changed = config.changed(), if config.has_changed().is_ok() => {
+                        if changed.is_ok() {
+                            info!(
+                                "{} period set to {:?}",
+                                self.name,
+                                self.period(&config.borrow())
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::AstarteError;
+    use chrono::{DateTime, TimeZone, Utc};
+    use tokio::sync::{mpsc, watch};
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::SYSTEM_STATUS_INTERFACE;
+    use crate::metrics::Metrics;
+    use crate::repository::file_state_repository::FileStateRepository;
+    use crate::repository::StateRepository;
+    use crate::telemetry::config::{
+        MockRandom, TelemetryConfig, TelemetryConfigChange, TelemetryConfigEvent,
+        TelemetryConfigWorker, TelemetryInterfaceConfig,
+    };
+    use crate::telemetry::schedule::{CollectorSchedule, ScheduleSource, TelemetrySchedule};
+    use crate::telemetry::system_status::SystemStatus;
+    use crate::telemetry::{Telemetry, TelemetryTasks};
+    use crate::test_utils::{settle, ManualClock, MemoryStateRepository};
+
+    #[tokio::test]
+    async fn system_status_sent_every_period() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .withf(|interface: &str, path: &str, _: &SystemStatus, _| {
+                interface == SYSTEM_STATUS_INTERFACE && path == "/systemStatus"
+            })
+            .returning(move |_, _, _: SystemStatus, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let (_metered_tx, metered) = watch::channel(false);
+        let (_config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config);
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(9));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(25));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 4);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn collectors_stopped_on_shutdown() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(move |_, _, _: SystemStatus, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let mut tasks = TelemetryTasks::new();
+        let (_metered_tx, metered) = watch::channel(false);
+        let (_config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config)
+            .with_shutdown(tasks.signal());
+        tasks.spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        // waiting for the next run, the collector stops without waiting for the timeout
+        tasks.shutdown(clock.as_ref(), Duration::from_secs(5)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stuck_collector_aborted_on_shutdown() {
+        let clock = Arc::new(ManualClock::new());
+        let mut tasks = TelemetryTasks::new();
+        tasks.spawn(std::future::pending());
+
+        let shutdown = {
+            let clock = clock.clone();
+            tokio::spawn(
+                async move { tasks.shutdown(clock.as_ref(), Duration::from_secs(5)).await },
+            )
+        };
+        settle().await;
+        assert!(!shutdown.is_finished());
+
+        clock.advance(Duration::from_secs(5));
+        settle().await;
+        assert!(shutdown.is_finished());
+    }
+
+    #[tokio::test]
+    async fn delivered_system_status_counted() {
+        let clock = Arc::new(ManualClock::new());
+        let metrics = Arc::new(Metrics::default());
+
+        let mut publisher = MockPublisher::new();
+        let attempts = AtomicUsize::new(0);
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(move |_, _, _: SystemStatus, _| {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    1 => Err(AstarteError::SendError("broker unreachable".to_owned())),
+                    _ => Ok(()),
+                }
+            });
+
+        let (_metered_tx, metered) = watch::channel(false);
+        let (_config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config)
+            .with_metrics(metrics.clone());
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        for _ in 0..3 {
+            settle().await;
+            clock.advance(Duration::from_secs(10));
+        }
+        settle().await;
+
+        assert!(metrics
+            .render()
+            .contains("edgehog_telemetry_sends_total 3\n"));
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn system_status_dated_at_collection() {
+        let clock = Arc::new(ManualClock::new());
+        let timestamps = Arc::new(Mutex::new(Vec::<DateTime<Utc>>::new()));
+
+        let mut publisher = MockPublisher::new();
+        let recorded = timestamps.clone();
+        publisher.expect_send_object_with_timestamp().returning(
+            move |_, _, _: SystemStatus, timestamp| {
+                recorded.lock().unwrap().push(timestamp);
+                Ok(())
+            },
+        );
+
+        let (_metered_tx, metered) = watch::channel(false);
+        let (_config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config);
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+
+        assert_eq!(
+            *timestamps.lock().unwrap(),
+            vec![
+                Utc.timestamp_opt(1_650_000_000, 0).unwrap(),
+                Utc.timestamp_opt(1_650_000_010, 0).unwrap(),
+            ]
+        );
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn system_status_period_stretched_when_metered() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(move |_, _, _: SystemStatus, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let (metered_tx, metered) = watch::channel(true);
+        let (_config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 3, config);
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(20));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        // back to unmetered, the pending deadline shrinks to the base period
+        clock.advance(Duration::from_secs(5));
+        metered_tx.send(false).unwrap();
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(5));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn system_status_follows_server_config() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(move |_, _, _: SystemStatus, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let (_metered_tx, metered) = watch::channel(false);
+        let (config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config);
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        let configured = |enabled, period_secs| {
+            [(
+                SYSTEM_STATUS_INTERFACE.to_owned(),
+                TelemetryInterfaceConfig {
+                    enabled,
+                    period_secs,
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect::<TelemetryConfig>()
+        };
+
+        config_tx.send(configured(None, Some(60))).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(59));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        config_tx.send(configured(Some(false), Some(60))).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(120));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn old_period_dropped_on_change() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(move |_, _, _: SystemStatus, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let configured = |enabled, period_secs| {
+            [(
+                SYSTEM_STATUS_INTERFACE.to_owned(),
+                TelemetryInterfaceConfig {
+                    enabled,
+                    period_secs,
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect::<TelemetryConfig>()
+        };
+        let (_metered_tx, metered) = watch::channel(false);
+        let (config_tx, config) = watch::channel(configured(None, Some(1)));
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config);
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(1));
+            settle().await;
+        }
+        assert_eq!(sent.load(Ordering::SeqCst), 4);
+
+        // the single run loop moves to the new period, nothing is left at the old one
+        config_tx.send(configured(None, Some(5))).unwrap();
+        settle().await;
+        for _ in 0..4 {
+            clock.advance(Duration::from_secs(1));
+            settle().await;
+            assert_eq!(sent.load(Ordering::SeqCst), 4);
+        }
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 5);
+
+        // disabled right away, enabled again within one period
+        config_tx.send(configured(Some(false), Some(5))).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 5);
+        config_tx.send(configured(None, Some(5))).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(5));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 6);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn system_status_delayed_and_jittered() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(move |_, _, _: SystemStatus, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+        // always the latest run of the jitter range
+        let mut random = MockRandom::new();
+        random.expect_fraction().return_const(1.0);
+
+        let configured = |period_secs| {
+            [(
+                SYSTEM_STATUS_INTERFACE.to_owned(),
+                TelemetryInterfaceConfig {
+                    period_secs,
+                    initial_delay_secs: Some(5),
+                    jitter_percent: Some(20),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect::<TelemetryConfig>()
+        };
+        let schedule = Arc::new(TelemetrySchedule::new(clock.clone()));
+        schedule.register(
+            SYSTEM_STATUS_INTERFACE,
+            CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(10)),
+        );
+        let (_metered_tx, metered) = watch::channel(false);
+        let (config_tx, config) = watch::channel(configured(None));
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config)
+            .with_schedule(schedule.clone())
+            .with_random(Arc::new(random));
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        // the initial delay and up to 20% of the period
+        settle().await;
+        clock.advance(Duration::from_secs(6));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        // the period moved by up to 20% of it
+        clock.advance(Duration::from_secs(11));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        // a new period keeps the jitter
+        config_tx.send(configured(Some(20))).unwrap();
+        settle().await;
+        assert_eq!(schedule.snapshot()[SYSTEM_STATUS_INTERFACE].jitter_secs, 4);
+        clock.advance(Duration::from_secs(23));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn schedule_follows_every_change() {
+        let clock = Arc::new(ManualClock::new());
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(|_, _, _: SystemStatus, _| Ok(()));
+
+        let (worker, config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(MemoryStateRepository::new()),
+            Duration::from_millis(500),
+        );
+        let (events_tx, events) = mpsc::channel(8);
+        let worker = tokio::spawn(async move { worker.run(events).await });
+
+        let schedule = Arc::new(TelemetrySchedule::new(clock.clone()));
+        schedule.register(
+            SYSTEM_STATUS_INTERFACE,
+            CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(10)),
+        );
+        let (metered_tx, metered) = watch::channel(false);
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config)
+            .with_schedule(schedule.clone());
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+        settle().await;
+
+        let system_status = || schedule.snapshot()[SYSTEM_STATUS_INTERFACE].clone();
+        // the manual clock starts at 1_650_000_000
+        let at = |secs| Some(Utc.timestamp(1_650_000_000 + secs, 0));
+        assert_eq!(
+            system_status(),
+            CollectorSchedule {
+                next_run: at(10),
+                ..CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(10))
+            }
+        );
+
+        // applied once the changes are coalesced
+        let change = |change| TelemetryConfigEvent {
+            interface_name: SYSTEM_STATUS_INTERFACE.to_owned(),
+            change,
+        };
+        events_tx
+            .send(change(TelemetryConfigChange::Period(Some(60))))
+            .await
+            .unwrap();
+        settle().await;
+        assert_eq!(system_status().period_secs, 10);
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+        assert_eq!(
+            system_status(),
+            CollectorSchedule {
+                next_run: at(60),
+                ..CollectorSchedule::new(ScheduleSource::Runtime, Duration::from_secs(60))
+            }
+        );
+
+        metered_tx.send(true).unwrap();
+        settle().await;
+        assert_eq!(system_status().period_secs, 240);
+        assert_eq!(system_status().next_run, at(240));
+
+        events_tx
+            .send(change(TelemetryConfigChange::Enabled(Some(false))))
+            .await
+            .unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+        assert_eq!(
+            system_status(),
+            CollectorSchedule {
+                enabled: false,
+                next_run: None,
+                ..CollectorSchedule::new(ScheduleSource::Runtime, Duration::from_secs(240))
+            }
+        );
+
+        handle.abort();
+        drop(events_tx);
+        worker.await.unwrap();
+    }
+
+    /// Start the config worker over the store in `dir` and the SystemStatus collector, as at
+    /// the service start.
+    fn start_from_store(
+        clock: Arc<ManualClock>,
+        dir: &str,
+    ) -> (
+        mpsc::Sender<TelemetryConfigEvent>,
+        Arc<TelemetrySchedule>,
+        tokio::task::JoinHandle<()>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(|_, _, _: SystemStatus, _| Ok(()));
+
+        let (worker, config) = TelemetryConfigWorker::new(
+            clock.clone(),
+            Box::new(FileStateRepository::new(
+                dir.to_owned(),
+                "telemetry_config.json".to_owned(),
+            )),
+            Duration::from_millis(500),
+        );
+        let (events_tx, events) = mpsc::channel(8);
+        let worker = tokio::spawn(async move { worker.run(events).await });
+
+        let schedule = Arc::new(TelemetrySchedule::new(clock.clone()));
+        schedule.register(
+            SYSTEM_STATUS_INTERFACE,
+            CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(10)),
+        );
+        let (metered_tx, metered) = watch::channel(false);
+        let telemetry = Telemetry::new(clock, Duration::from_secs(10), metered, 4, config)
+            .with_schedule(schedule.clone());
+        let handle = tokio::spawn(async move {
+            // a closed metered channel would stop the config changes from being followed
+            let _metered_tx = metered_tx;
+            telemetry.run(&publisher).await
+        });
+
+        (events_tx, schedule, worker, handle)
+    }
+
+    #[tokio::test]
+    async fn server_config_survives_a_restart() {
+        let clock = Arc::new(ManualClock::new());
+        let store = tempfile::tempdir().unwrap();
+        let dir = store.path().to_str().unwrap();
+        let change = |change| TelemetryConfigEvent {
+            interface_name: SYSTEM_STATUS_INTERFACE.to_owned(),
+            change,
+        };
+        let applied = |schedule: &TelemetrySchedule| {
+            let collector = schedule.snapshot()[SYSTEM_STATUS_INTERFACE].clone();
+            (collector.source, collector.period_secs, collector.enabled)
+        };
+
+        let (events_tx, schedule, worker, handle) = start_from_store(clock.clone(), dir);
+        settle().await;
+        events_tx
+            .send(change(TelemetryConfigChange::Period(Some(60))))
+            .await
+            .unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+        assert_eq!(applied(&schedule), (ScheduleSource::Runtime, 60, true));
+        handle.abort();
+        drop(events_tx);
+        worker.await.unwrap();
+
+        // the override is applied on top of the defaults at the next start
+        let (events_tx, schedule, worker, handle) = start_from_store(clock.clone(), dir);
+        settle().await;
+        assert_eq!(applied(&schedule), (ScheduleSource::Runtime, 60, true));
+
+        // unset by the server, the default is back and stays after a restart
+        events_tx
+            .send(change(TelemetryConfigChange::Period(None)))
+            .await
+            .unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+        assert_eq!(applied(&schedule), (ScheduleSource::Default, 10, true));
+        handle.abort();
+        drop(events_tx);
+        worker.await.unwrap();
+
+        let repository =
+            FileStateRepository::new(dir.to_owned(), "telemetry_config.json".to_owned());
+        let persisted: TelemetryConfig = repository.read().unwrap();
+        assert!(persisted.is_empty());
+
+        let (events_tx, schedule, worker, handle) = start_from_store(clock.clone(), dir);
+        settle().await;
+        assert_eq!(applied(&schedule), (ScheduleSource::Default, 10, true));
+        handle.abort();
+        drop(events_tx);
+        worker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn partial_unset_keeps_the_other_override() {
+        let clock = Arc::new(ManualClock::new());
+        let store = tempfile::tempdir().unwrap();
+        let dir = store.path().to_str().unwrap();
+        let (events_tx, schedule, worker, handle) = start_from_store(clock.clone(), dir);
+        let apply = |changes: Vec<TelemetryConfigChange>| {
+            let events_tx = events_tx.clone();
+            let clock = clock.clone();
+            let schedule = schedule.clone();
+            async move {
+                for change in changes {
+                    let event = TelemetryConfigEvent {
+                        interface_name: SYSTEM_STATUS_INTERFACE.to_owned(),
+                        change,
+                    };
+                    events_tx.send(event).await.unwrap();
+                }
+                settle().await;
+                clock.advance(Duration::from_millis(500));
+                settle().await;
+
+                let collector = schedule.snapshot()[SYSTEM_STATUS_INTERFACE].clone();
+                (collector.source, collector.period_secs, collector.enabled)
+            }
+        };
+        settle().await;
+
+        let applied = apply(vec![
+            TelemetryConfigChange::Period(Some(60)),
+            TelemetryConfigChange::Enabled(Some(false)),
+        ])
+        .await;
+        assert_eq!(applied, (ScheduleSource::Runtime, 60, false));
+
+        // the default period, still disabled by the server
+        let applied = apply(vec![TelemetryConfigChange::Period(None)]).await;
+        assert_eq!(applied, (ScheduleSource::Runtime, 10, false));
+
+        let applied = apply(vec![TelemetryConfigChange::Enabled(None)]).await;
+        assert_eq!(applied, (ScheduleSource::Default, 10, true));
+
+        handle.abort();
+        drop(events_tx);
+        worker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn backoff_stretches_the_period_in_the_schedule() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .returning(move |_, _, _: SystemStatus, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let schedule = Arc::new(TelemetrySchedule::new(clock.clone()));
+        schedule.register(
+            SYSTEM_STATUS_INTERFACE,
+            CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(10)),
+        );
+        let (_metered_tx, metered) = watch::channel(false);
+        let (_config_tx, config) = watch::channel(TelemetryConfig::new());
+        let (backoff_tx, backoff) = watch::channel(1);
+        let telemetry = Telemetry::new(clock.clone(), Duration::from_secs(10), metered, 4, config)
+            .with_backoff(Some(backoff))
+            .with_schedule(schedule.clone());
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        let system_status = || schedule.snapshot()[SYSTEM_STATUS_INTERFACE].clone();
+        backoff_tx.send(4).unwrap();
+        settle().await;
+        assert_eq!(system_status().period_secs, 40);
+        assert_eq!(system_status().backoff_factor, 4);
+
+        clock.advance(Duration::from_secs(39));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        backoff_tx.send(1).unwrap();
+        settle().await;
+        assert_eq!(
+            system_status(),
+            CollectorSchedule {
+                next_run: system_status().next_run,
+                ..CollectorSchedule::new(ScheduleSource::Default, Duration::from_secs(10))
+            }
+        );
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,422 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Network interfaces of the device, published on
+//! `io.edgehog.devicemanager.NetworkInterfaceProperties`.
+//!
+//! The interfaces are listed in `/sys/class/net`. Each one is published under its name with its
+//! MAC address, its technology and whether its link is up. The interfaces are listed again every
+//! period, so that the hot-plugged adapters show up: only the properties that changed are sent
+//! and the ones of the interfaces gone are unset.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{debug, error};
+use serde::Deserialize;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::NETWORK_INTERFACES_INTERFACE;
+use crate::telemetry::schedule::TelemetrySchedule;
+
+pub const DEFAULT_NETWORK_INTERFACES_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+const SYSFS_NET: &str = "/sys/class/net";
+
+// link layer types of `/sys/class/net/<interface>/type`, from `linux/if_arp.h`
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_RAWIP: u16 = 519;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ARPHRD_NONE: u16 = 65534;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct NetworkInterfacesOptions {
+    /// Period of the refresh of the interfaces.
+    pub period_secs: Option<u64>,
+    /// Skip the interfaces without a device, e.g. the veth pairs and the container bridges.
+    #[serde(default)]
+    pub exclude_virtual: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Technology {
+    Ethernet,
+    WiFi,
+    Cellular,
+    Loopback,
+    Unknown,
+}
+
+impl Technology {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Technology::Ethernet => "Ethernet",
+            Technology::WiFi => "WiFi",
+            Technology::Cellular => "Cellular",
+            Technology::Loopback => "Loopback",
+            Technology::Unknown => "Unknown",
+        }
+    }
+}
+
+fn read_attribute(interface: &Path, attribute: &str) -> Option<String> {
+    std::fs::read_to_string(interface.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+/// Technology of the interface at `interface`, a directory of `/sys/class/net`.
+fn technology(interface: &Path) -> Technology {
+    let link_type = read_attribute(interface, "type").and_then(|value| value.parse().ok());
+    let device_type = read_attribute(interface, "uevent").and_then(|uevent| {
+        uevent
+            .lines()
+            .find_map(|line| line.strip_prefix("DEVTYPE="))
+            .map(str::to_owned)
+    });
+
+    if link_type == Some(ARPHRD_LOOPBACK) {
+        return Technology::Loopback;
+    }
+    if device_type.as_deref() == Some("wlan") || interface.join("wireless").exists() {
+        return Technology::WiFi;
+    }
+    // the modems are often raw IP, without a link layer
+    if device_type.as_deref() == Some("wwan")
+        || (matches!(link_type, Some(ARPHRD_RAWIP | ARPHRD_NONE))
+            && interface.join("device").exists())
+    {
+        return Technology::Cellular;
+    }
+
+    match link_type {
+        Some(ARPHRD_ETHER) => Technology::Ethernet,
+        _ => Technology::Unknown,
+    }
+}
+
+/// Whether the link of the interface at `interface` is up. The loopback and some tunnels don't
+/// report an operational state, their carrier tells instead.
+fn link_up(interface: &Path) -> bool {
+    match read_attribute(interface, "operstate").as_deref() {
+        Some("up") => true,
+        Some("unknown") => read_attribute(interface, "carrier").as_deref() == Some("1"),
+        _ => false,
+    }
+}
+
+/// Properties of the interfaces listed in `root`, by path.
+fn collect(root: &Path, exclude_virtual: bool) -> HashMap<String, AstarteType> {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Unable to list the network interfaces: {}", err);
+            return HashMap::new();
+        }
+    };
+
+    let mut properties = HashMap::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let interface = entry.path();
+
+        let technology = technology(&interface);
+        // only the interfaces backed by a device have a `device` link
+        if exclude_virtual
+            && technology != Technology::Loopback
+            && !interface.join("device").exists()
+        {
+            debug!("Skipping the virtual interface {name}");
+            continue;
+        }
+
+        let address = read_attribute(&interface, "address")
+            .filter(|address| !address.is_empty() && address != "00:00:00:00:00:00");
+        if let Some(address) = address {
+            properties.insert(format!("/{name}/macAddress"), AstarteType::String(address));
+        }
+        properties.insert(
+            format!("/{name}/technologyType"),
+            AstarteType::String(technology.as_str().to_owned()),
+        );
+        properties.insert(
+            format!("/{name}/up"),
+            AstarteType::Boolean(link_up(&interface)),
+        );
+    }
+
+    properties
+}
+
+pub struct NetworkInterfacesTelemetry {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    sysfs_root: PathBuf,
+    exclude_virtual: bool,
+    /// Properties set, by path.
+    published: Mutex<HashMap<String, AstarteType>>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+}
+
+impl NetworkInterfacesTelemetry {
+    pub fn new(clock: Arc<dyn Clock>, options: Option<&NetworkInterfacesOptions>) -> Self {
+        let options = options.cloned().unwrap_or_default();
+
+        NetworkInterfacesTelemetry {
+            clock,
+            period: options
+                .period_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_NETWORK_INTERFACES_PERIOD),
+            sysfs_root: PathBuf::from(SYSFS_NET),
+            exclude_virtual: options.exclude_virtual,
+            published: Mutex::new(HashMap::new()),
+            schedule: None,
+        }
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Refresh the interfaces every period, after the initial telemetry published them.
+    pub async fn run(&self, publisher: &impl Publisher) {
+        loop {
+            if let Some(schedule) = &self.schedule {
+                schedule.next_run(NETWORK_INTERFACES_INTERFACE, Some(self.period));
+            }
+            self.clock.sleep(self.period).await;
+
+            if let Err(err) = self.publish(publisher).await {
+                error!("Unable to publish the network interfaces: {:?}", err);
+            }
+        }
+    }
+
+    /// Send the properties changed since the last publish and unset the ones of the interfaces
+    /// gone.
+    pub async fn publish(&self, publisher: &impl Publisher) -> Result<(), DeviceManagerError> {
+        let current = collect(&self.sysfs_root, self.exclude_virtual);
+        let (changed, gone) = {
+            let published = self.published.lock().unwrap();
+            let changed: Vec<(String, AstarteType)> = current
+                .iter()
+                .filter(|(path, value)| published.get(*path) != Some(value))
+                .map(|(path, value)| (path.clone(), value.clone()))
+                .collect();
+            let gone: Vec<String> = published
+                .keys()
+                .filter(|path| !current.contains_key(*path))
+                .cloned()
+                .collect();
+
+            (changed, gone)
+        };
+
+        for (path, value) in changed {
+            publisher
+                .send(NETWORK_INTERFACES_INTERFACE, &path, value.clone())
+                .await?;
+            self.published.lock().unwrap().insert(path, value);
+        }
+        for path in gone {
+            publisher.unset(NETWORK_INTERFACES_INTERFACE, &path).await?;
+            self.published.lock().unwrap().remove(&path);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::clock::SystemClock;
+    use crate::data::MockPublisher;
+    use crate::interfaces::NETWORK_INTERFACES_INTERFACE;
+    use crate::telemetry::net_interfaces::{
+        collect, NetworkInterfacesOptions, NetworkInterfacesTelemetry,
+    };
+
+    /// Add the interface `name` to the sysfs fixture at `root`, with `attributes` and the
+    /// `directories`, e.g. `device`.
+    fn interface(root: &Path, name: &str, attributes: &[(&str, &str)], directories: &[&str]) {
+        let interface = root.join(name);
+        std::fs::create_dir_all(&interface).unwrap();
+        for (attribute, value) in attributes {
+            std::fs::write(interface.join(attribute), format!("{value}\n")).unwrap();
+        }
+        for directory in directories {
+            std::fs::create_dir(interface.join(directory)).unwrap();
+        }
+    }
+
+    fn sysfs() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        interface(
+            root.path(),
+            "lo",
+            &[
+                ("type", "772"),
+                ("address", "00:00:00:00:00:00"),
+                ("operstate", "unknown"),
+                ("carrier", "1"),
+            ],
+            &[],
+        );
+        interface(
+            root.path(),
+            "eth0",
+            &[
+                ("type", "1"),
+                ("address", "02:42:ac:11:00:02"),
+                ("operstate", "up"),
+            ],
+            &["device"],
+        );
+        interface(
+            root.path(),
+            "wlan0",
+            &[
+                ("type", "1"),
+                ("address", "b8:27:eb:00:00:01"),
+                ("operstate", "down"),
+                ("uevent", "DEVTYPE=wlan\nINTERFACE=wlan0"),
+            ],
+            &["device", "wireless"],
+        );
+        interface(
+            root.path(),
+            "wwan0",
+            &[
+                ("type", "65534"),
+                ("operstate", "unknown"),
+                ("carrier", "0"),
+            ],
+            &["device"],
+        );
+        interface(
+            root.path(),
+            "veth1a2b",
+            &[
+                ("type", "1"),
+                ("address", "9e:1f:00:00:00:03"),
+                ("operstate", "up"),
+            ],
+            &[],
+        );
+
+        root
+    }
+
+    fn sorted<V>(values: impl IntoIterator<Item = (String, V)>) -> BTreeMap<String, V> {
+        values.into_iter().collect()
+    }
+
+    #[test]
+    fn interfaces_classified() {
+        let root = sysfs();
+
+        let properties = sorted(collect(root.path(), false));
+        let value = |path: &str| properties[path].clone();
+        assert_eq!(value("/lo/technologyType"), "Loopback");
+        assert_eq!(value("/lo/up"), AstarteType::Boolean(true));
+        assert!(!properties.contains_key("/lo/macAddress"));
+        assert_eq!(value("/eth0/technologyType"), "Ethernet");
+        assert_eq!(value("/eth0/macAddress"), "02:42:ac:11:00:02");
+        assert_eq!(value("/eth0/up"), AstarteType::Boolean(true));
+        assert_eq!(value("/wlan0/technologyType"), "WiFi");
+        assert_eq!(value("/wlan0/up"), AstarteType::Boolean(false));
+        assert_eq!(value("/wwan0/technologyType"), "Cellular");
+        assert_eq!(value("/wwan0/up"), AstarteType::Boolean(false));
+        assert_eq!(value("/veth1a2b/technologyType"), "Ethernet");
+
+        let properties = collect(root.path(), true);
+        assert!(properties.contains_key("/lo/technologyType"));
+        assert!(properties.contains_key("/eth0/technologyType"));
+        assert!(!properties.contains_key("/veth1a2b/technologyType"));
+    }
+
+    #[tokio::test]
+    async fn only_changes_published() {
+        let root = sysfs();
+        let mut telemetry = NetworkInterfacesTelemetry::new(
+            Arc::new(SystemClock),
+            Some(&NetworkInterfacesOptions {
+                period_secs: None,
+                exclude_virtual: true,
+            }),
+        );
+        telemetry.sysfs_root = root.path().to_owned();
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let unset = Arc::new(Mutex::new(Vec::new()));
+        let mut publisher = MockPublisher::new();
+        let recorded = sent.clone();
+        publisher
+            .expect_send()
+            .withf(|interface, _, _| interface == NETWORK_INTERFACES_INTERFACE)
+            .returning(move |_, path, value| {
+                recorded.lock().unwrap().push((path.to_owned(), value));
+                Ok(())
+            });
+        let recorded = unset.clone();
+        publisher
+            .expect_unset()
+            .withf(|interface, _| interface == NETWORK_INTERFACES_INTERFACE)
+            .returning(move |_, path| {
+                recorded.lock().unwrap().push(path.to_owned());
+                Ok(())
+            });
+
+        telemetry.publish(&publisher).await.unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 10);
+        telemetry.publish(&publisher).await.unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 10);
+
+        // the adapter unplugged and the WiFi connected
+        std::fs::remove_dir_all(root.path().join("eth0")).unwrap();
+        std::fs::write(root.path().join("wlan0/operstate"), "up\n").unwrap();
+        sent.lock().unwrap().clear();
+        telemetry.publish(&publisher).await.unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![("/wlan0/up".to_owned(), AstarteType::Boolean(true))]
+        );
+        let mut unset = unset.lock().unwrap().clone();
+        unset.sort();
+        assert_eq!(
+            unset,
+            ["/eth0/macAddress", "/eth0/technologyType", "/eth0/up"]
+        );
+    }
+}
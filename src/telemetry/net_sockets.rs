@@ -0,0 +1,535 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Inventory of the listening sockets and of the established TCP connections.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error};
+use procfs::net::{TcpNetEntry, TcpState, UdpNetEntry, UdpState};
+use procfs::process::FDTarget;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::NETWORK_SOCKETS_INTERFACE;
+use crate::telemetry::backoff::backed_off;
+use crate::telemetry::schedule::TelemetrySchedule;
+
+pub const DEFAULT_NETWORK_SOCKETS_PERIOD: Duration = Duration::from_secs(600);
+
+/// Maximum number of listening sockets and of remote subnets reported, the remainder is only
+/// counted in the summary.
+const MAX_REPORTED_ENTRIES: usize = 32;
+
+const UNKNOWN_PROCESS: &str = "unknown";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListeningSocket {
+    pub protocol: String,
+    pub local_address: String,
+    pub port: i32,
+    pub process: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EstablishedConnections {
+    pub remote_subnet: String,
+    pub count: i32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SocketsSummary {
+    pub listening_count: i32,
+    pub established_count: i32,
+    pub omitted_listening: i32,
+    pub omitted_subnets: i32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketsSnapshot {
+    pub listening: Vec<ListeningSocket>,
+    pub established: Vec<EstablishedConnections>,
+    pub summary: SocketsSummary,
+}
+
+/// Socket tables read from `/proc/net`.
+#[derive(Debug, Default)]
+pub struct SocketTables {
+    pub tcp: Vec<(&'static str, TcpNetEntry)>,
+    pub udp: Vec<(&'static str, UdpNetEntry)>,
+}
+
+impl SocketTables {
+    fn read(proc_root: &Path) -> Self {
+        let mut tables = SocketTables::default();
+
+        for protocol in ["tcp", "tcp6"] {
+            match read_table(proc_root, protocol, procfs::net::read_tcp_table) {
+                Some(entries) => tables
+                    .tcp
+                    .extend(entries.into_iter().map(|entry| (protocol, entry))),
+                None => continue,
+            }
+        }
+
+        for protocol in ["udp", "udp6"] {
+            match read_table(proc_root, protocol, procfs::net::read_udp_table) {
+                Some(entries) => tables
+                    .udp
+                    .extend(entries.into_iter().map(|entry| (protocol, entry))),
+                None => continue,
+            }
+        }
+
+        tables
+    }
+}
+
+fn read_table<T>(
+    proc_root: &Path,
+    protocol: &str,
+    parse: impl Fn(BufReader<std::fs::File>) -> procfs::ProcResult<Vec<T>>,
+) -> Option<Vec<T>> {
+    let path = proc_root.join("net").join(protocol);
+
+    // the IPv6 tables are missing when IPv6 is disabled
+    let file = std::fs::File::open(&path)
+        .map_err(|err| debug!("Unable to open {}: {}", path.display(), err))
+        .ok()?;
+
+    parse(BufReader::new(file))
+        .map_err(|err| error!("Unable to parse {}: {:?}", path.display(), err))
+        .ok()
+}
+
+/// Map the socket inodes to the name of the owning process.
+///
+/// Processes whose file descriptors cannot be read, e.g. when not running as root, are skipped.
+fn socket_owners(proc_root: &Path) -> HashMap<u64, String> {
+    let processes = match procfs::process::all_processes_with_root(proc_root) {
+        Ok(processes) => processes,
+        Err(err) => {
+            error!("Unable to list processes: {:?}", err);
+            return HashMap::new();
+        }
+    };
+
+    let mut owners = HashMap::new();
+    for process in processes {
+        let fds = match process.fd() {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+
+        for fd in fds {
+            if let FDTarget::Socket(inode) = fd.target {
+                owners.insert(inode, process.stat.comm.clone());
+            }
+        }
+    }
+
+    owners
+}
+
+/// Network of the remote address, /24 for IPv4 and /64 for IPv6.
+fn remote_subnet(address: IpAddr) -> String {
+    let address = match address {
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            Some(v4) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v4),
+            _ => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    };
+
+    match address {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            format!("{}/64", Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+fn listening_socket(
+    protocol: &str,
+    local_address: SocketAddr,
+    inode: u64,
+    owners: &HashMap<u64, String>,
+) -> ListeningSocket {
+    ListeningSocket {
+        protocol: protocol.to_owned(),
+        local_address: local_address.ip().to_string(),
+        port: local_address.port() as i32,
+        process: owners
+            .get(&inode)
+            .cloned()
+            .unwrap_or_else(|| UNKNOWN_PROCESS.to_owned()),
+    }
+}
+
+/// Build the snapshot to publish, capping the reported entries to `max_entries`.
+pub fn snapshot(
+    tables: &SocketTables,
+    owners: &HashMap<u64, String>,
+    max_entries: usize,
+) -> SocketsSnapshot {
+    let mut listening: Vec<ListeningSocket> = tables
+        .tcp
+        .iter()
+        .filter(|(_, entry)| entry.state == TcpState::Listen)
+        .map(|(protocol, entry)| {
+            listening_socket(protocol, entry.local_address, entry.inode, owners)
+        })
+        .chain(
+            tables
+                .udp
+                .iter()
+                // bound and not connected
+                .filter(|(_, entry)| {
+                    entry.state == UdpState::Close && entry.remote_address.port() == 0
+                })
+                .map(|(protocol, entry)| {
+                    listening_socket(protocol, entry.local_address, entry.inode, owners)
+                }),
+        )
+        .collect();
+    listening.sort();
+    listening.dedup();
+
+    let mut subnets: BTreeMap<String, i32> = BTreeMap::new();
+    let mut established_count = 0;
+    for (_, entry) in tables
+        .tcp
+        .iter()
+        .filter(|(_, entry)| entry.state == TcpState::Established)
+    {
+        established_count += 1;
+        *subnets
+            .entry(remote_subnet(entry.remote_address.ip()))
+            .or_default() += 1;
+    }
+
+    let mut established: Vec<EstablishedConnections> = subnets
+        .into_iter()
+        .map(|(remote_subnet, count)| EstablishedConnections {
+            remote_subnet,
+            count,
+        })
+        .collect();
+    // the busiest subnets are the ones worth reporting, the sort is stable on the subnet
+    established.sort_by_key(|subnet| std::cmp::Reverse(subnet.count));
+
+    let summary = SocketsSummary {
+        listening_count: listening.len() as i32,
+        established_count,
+        omitted_listening: listening.len().saturating_sub(max_entries) as i32,
+        omitted_subnets: established.len().saturating_sub(max_entries) as i32,
+    };
+
+    listening.truncate(max_entries);
+    established.truncate(max_entries);
+
+    SocketsSnapshot {
+        listening,
+        established,
+        summary,
+    }
+}
+
+/// Periodically publishes the sockets inventory, only when it changed since the last publish.
+pub struct NetworkSocketsTelemetry {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    proc_root: PathBuf,
+    last_published: Option<SocketsSnapshot>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    backoff: Option<watch::Receiver<u32>>,
+}
+
+impl NetworkSocketsTelemetry {
+    pub fn new(clock: Arc<dyn Clock>, period: Duration) -> Self {
+        NetworkSocketsTelemetry {
+            clock,
+            period,
+            proc_root: PathBuf::from("/proc"),
+            last_published: None,
+            schedule: None,
+            backoff: None,
+        }
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Stretch the period by the factor of `backoff`, when enabled.
+    pub fn with_backoff(mut self, backoff: Option<watch::Receiver<u32>>) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub async fn run(mut self, publisher: &impl Publisher) {
+        loop {
+            let snapshot = snapshot(
+                &SocketTables::read(&self.proc_root),
+                &socket_owners(&self.proc_root),
+                MAX_REPORTED_ENTRIES,
+            );
+
+            self.publish_if_changed(publisher, snapshot).await;
+
+            let (period, backoff_factor) = backed_off(self.period, self.backoff.as_ref());
+            if let Some(schedule) = &self.schedule {
+                schedule.update(NETWORK_SOCKETS_INTERFACE, |collector| {
+                    collector.period_secs = period.as_secs();
+                    collector.backoff_factor = backoff_factor;
+                });
+                schedule.next_run(NETWORK_SOCKETS_INTERFACE, Some(period));
+            }
+            self.clock.sleep(period).await;
+        }
+    }
+
+    async fn publish_if_changed(&mut self, publisher: &impl Publisher, snapshot: SocketsSnapshot) {
+        if self.last_published.as_ref() == Some(&snapshot) {
+            return;
+        }
+
+        if let Err(err) = publish(publisher, &snapshot).await {
+            error!("Unable to publish network sockets: {:?}", err);
+            return;
+        }
+
+        self.last_published = Some(snapshot);
+    }
+}
+
+async fn publish(
+    publisher: &impl Publisher,
+    snapshot: &SocketsSnapshot,
+) -> Result<(), astarte_sdk::AstarteError> {
+    for listening in &snapshot.listening {
+        publisher
+            .send_object(NETWORK_SOCKETS_INTERFACE, "/listening", listening.clone())
+            .await?;
+    }
+
+    for established in &snapshot.established {
+        publisher
+            .send_object(
+                NETWORK_SOCKETS_INTERFACE,
+                "/established",
+                established.clone(),
+            )
+            .await?;
+    }
+
+    publisher
+        .send_object(
+            NETWORK_SOCKETS_INTERFACE,
+            "/summary",
+            snapshot.summary.clone(),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::BufReader;
+    use std::sync::Arc;
+
+    use crate::clock::SystemClock;
+    use crate::data::MockPublisher;
+    use crate::telemetry::net_sockets::{
+        remote_subnet, snapshot, EstablishedConnections, ListeningSocket, NetworkSocketsTelemetry,
+        SocketTables, SocketsSummary, DEFAULT_NETWORK_SOCKETS_PERIOD,
+    };
+
+    const PROC_NET_TCP: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 20481 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:0277 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 20990 1 0000000000000000 100 0 0 10 0
+   2: 0F02000A:0016 0102000A:D431 01 00000000:00000000 02:0008EC2F 00000000     0        0 31337 4 0000000000000000 20 4 31 10 -1
+   3: 0F02000A:0016 0202000A:D432 01 00000000:00000000 02:0008EC2F 00000000     0        0 31338 4 0000000000000000 20 4 31 10 -1
+   4: 0F02000A:B5A2 2214A8C0:01BB 01 00000000:00000000 02:0008EC2F 00000000  1000        0 31339 2 0000000000000000 20 4 30 10 -1
+   5: 0F02000A:B5A4 2214A8C0:01BB 06 00000000:00000000 03:00000A4E 00000000     0        0 0 3 0000000000000000
+";
+
+    const PROC_NET_TCP6: &str = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000000000000:0016 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 20483 1 0000000000000000 100 0 0 10 0
+   1: 0000000000000000FFFF00000F02000A:0050 0000000000000000FFFF00000302000A:C350 01 00000000:00000000 02:0008EC2F 00000000    33        0 31340 2 0000000000000000 20 4 30 10 -1
+";
+
+    const PROC_NET_UDP: &str = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  100: 00000000:0044 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 19356 2 0000000000000000 0
+  101: 0F02000A:9C40 08080808:0035 01 00000000:00000000 00:00000000 00000000   101        0 19400 2 0000000000000000 0
+";
+
+    fn tables() -> SocketTables {
+        let tcp = procfs::net::read_tcp_table(BufReader::new(PROC_NET_TCP.as_bytes())).unwrap();
+        let tcp6 = procfs::net::read_tcp_table(BufReader::new(PROC_NET_TCP6.as_bytes())).unwrap();
+        let udp = procfs::net::read_udp_table(BufReader::new(PROC_NET_UDP.as_bytes())).unwrap();
+
+        SocketTables {
+            tcp: tcp
+                .into_iter()
+                .map(|entry| ("tcp", entry))
+                .chain(tcp6.into_iter().map(|entry| ("tcp6", entry)))
+                .collect(),
+            udp: udp.into_iter().map(|entry| ("udp", entry)).collect(),
+        }
+    }
+
+    fn owners() -> HashMap<u64, String> {
+        HashMap::from([
+            (20481, "sshd".to_owned()),
+            (20483, "sshd".to_owned()),
+            (19356, "dhclient".to_owned()),
+        ])
+    }
+
+    fn listening(protocol: &str, address: &str, port: i32, process: &str) -> ListeningSocket {
+        ListeningSocket {
+            protocol: protocol.to_owned(),
+            local_address: address.to_owned(),
+            port,
+            process: process.to_owned(),
+        }
+    }
+
+    #[test]
+    fn snapshot_from_proc_net() {
+        let snapshot = snapshot(&tables(), &owners(), 32);
+
+        assert_eq!(
+            snapshot.listening,
+            vec![
+                listening("tcp", "0.0.0.0", 22, "sshd"),
+                listening("tcp", "127.0.0.1", 631, "unknown"),
+                listening("tcp6", "::", 22, "sshd"),
+                listening("udp", "0.0.0.0", 68, "dhclient"),
+            ]
+        );
+        assert_eq!(
+            snapshot.established,
+            vec![
+                EstablishedConnections {
+                    remote_subnet: "10.0.2.0/24".to_owned(),
+                    count: 3
+                },
+                EstablishedConnections {
+                    remote_subnet: "192.168.20.0/24".to_owned(),
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(
+            snapshot.summary,
+            SocketsSummary {
+                listening_count: 4,
+                established_count: 4,
+                omitted_listening: 0,
+                omitted_subnets: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn snapshot_capped() {
+        let snapshot = snapshot(&tables(), &HashMap::new(), 1);
+
+        assert_eq!(
+            snapshot.listening,
+            vec![listening("tcp", "0.0.0.0", 22, "unknown")]
+        );
+        assert_eq!(snapshot.established.len(), 1);
+        assert_eq!(snapshot.established[0].remote_subnet, "10.0.2.0/24");
+        assert_eq!(
+            snapshot.summary,
+            SocketsSummary {
+                listening_count: 4,
+                established_count: 4,
+                omitted_listening: 3,
+                omitted_subnets: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn subnets() {
+        assert_eq!(
+            remote_subnet("192.168.1.77".parse().unwrap()),
+            "192.168.1.0/24"
+        );
+        assert_eq!(
+            remote_subnet("::ffff:10.1.2.3".parse().unwrap()),
+            "10.1.2.0/24"
+        );
+        assert_eq!(
+            remote_subnet("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::/64"
+        );
+    }
+
+    #[tokio::test]
+    async fn published_only_on_change() {
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object::<ListeningSocket>()
+            .times(5)
+            .returning(|_, _, _| Ok(()));
+        publisher
+            .expect_send_object::<EstablishedConnections>()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        publisher
+            .expect_send_object::<SocketsSummary>()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+
+        let mut telemetry =
+            NetworkSocketsTelemetry::new(Arc::new(SystemClock), DEFAULT_NETWORK_SOCKETS_PERIOD);
+
+        let first = snapshot(&tables(), &owners(), 32);
+        telemetry
+            .publish_if_changed(&publisher, first.clone())
+            .await;
+        telemetry.publish_if_changed(&publisher, first).await;
+
+        let mut capped = snapshot(&tables(), &owners(), 1);
+        capped.established.clear();
+        telemetry.publish_if_changed(&publisher, capped).await;
+    }
+}
@@ -18,87 +18,185 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+//! The running OS, for `io.edgehog.devicemanager.OSInfo`.
+//!
+//! The name is the `NAME` of the os-release file, else its `PRETTY_NAME`; the version is its
+//! `VERSION_ID`, else `VERSION` or `BUILD_ID`. Without an os-release file, or without those
+//! fields, the kernel name and release given by `uname` are sent.
+
 use crate::error::DeviceManagerError;
 use astarte_sdk::types::AstarteType;
 use std::collections::HashMap;
+use std::path::Path;
+
+/// The os-release files, the first one readable is used.
+pub(crate) const OS_RELEASE_PATHS: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
 
 /// get structured data for `io.edgehog.devicemanager.OSInfo` interface
 pub fn get_os_info() -> Result<HashMap<String, AstarteType>, DeviceManagerError> {
-    let paths = ["/etc/os-release", "/usr/lib/os-release"];
+    let uname = nix::sys::utsname::uname();
+    let paths = OS_RELEASE_PATHS.map(Path::new);
 
-    let paths = paths.iter().filter(|f| std::path::Path::new(f).exists());
+    Ok(collect_os_info(
+        read_os_release(&paths).as_deref(),
+        &Uname {
+            sysname: uname.sysname().to_owned(),
+            release: uname.release().to_owned(),
+        },
+    ))
+}
 
-    if let Some(path) = paths.into_iter().next() {
-        let os = std::fs::read_to_string(path)?;
-        return parse_os_info(&os);
+/// The version of the running OS, if known.
+pub fn os_version() -> Option<String> {
+    match get_os_info().ok()?.remove("/osVersion") {
+        Some(AstarteType::String(version)) => Some(version),
+        _ => None,
     }
+}
 
-    Err(DeviceManagerError::FatalError(
-        "No os-release file found".to_owned(),
-    ))
+/// Contents of the first of the os-release `paths` readable.
+pub(crate) fn read_os_release(paths: &[&Path]) -> Option<String> {
+    paths
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
 }
 
-fn parse_key_value_line(line: &str) -> Option<(&str, &str)> {
-    let mut tokens = line.split('=');
+/// What the kernel says of itself, the last resort.
+struct Uname {
+    sysname: String,
+    release: String,
+}
 
-    let key = tokens.next()?;
-    let value = tokens.next()?.trim_matches('"');
+fn collect_os_info(os_release: Option<&str>, uname: &Uname) -> HashMap<String, AstarteType> {
+    let fields = os_release.map(parse_os_release).unwrap_or_default();
+    let first = |keys: &[&str]| keys.iter().find_map(|key| fields.get(*key).cloned());
 
-    Some((key, value))
+    let name = first(&["NAME", "PRETTY_NAME"]).unwrap_or_else(|| uname.sysname.clone());
+    let version =
+        first(&["VERSION_ID", "VERSION", "BUILD_ID"]).unwrap_or_else(|| uname.release.clone());
+
+    [("/osName", name), ("/osVersion", version)]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(path, value)| (path.to_owned(), AstarteType::String(value)))
+        .collect()
 }
 
-fn parse_os_info(os: &str) -> Result<HashMap<String, AstarteType>, DeviceManagerError> {
-    let mut ret: HashMap<String, AstarteType> = HashMap::new();
+/// The non empty fields of an os-release file, unquoted like a shell would.
+pub(crate) fn parse_os_release(os_release: &str) -> HashMap<String, String> {
+    os_release
+        .lines()
+        .filter_map(parse_key_value_line)
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect()
+}
 
-    let lines: HashMap<&str, &str> = os.lines().filter_map(parse_key_value_line).collect();
+/// `KEY=value` assignment of an os-release file, `None` for comments and malformed lines.
+fn parse_key_value_line(line: &str) -> Option<(&str, String)> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
 
-    if let Some(field) = lines.get("NAME") {
-        ret.insert("/osName".to_owned(), field.into());
+    let (key, value) = line.split_once('=')?;
+    let valid_key = !key.is_empty() && key.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_');
+    if !valid_key {
+        return None;
     }
 
-    if let Some(field) = lines.get("VERSION_ID") {
-        ret.insert("/osVersion".to_owned(), field.into());
-    } else if let Some(field) = lines.get("BUILD_ID") {
-        ret.insert("/osVersion".to_owned(), field.into());
+    Some((key, unquote(value)))
+}
+
+/// The value of an assignment without its quotes and escapes, an unterminated quote runs to the
+/// end of the line.
+fn unquote(value: &str) -> String {
+    let mut unquoted = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                    unquoted.push(c);
+                }
+            }
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        // only these are escaped between double quotes
+                        '\\' => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => unquoted.push(c),
+                            Some(c) => {
+                                unquoted.push('\\');
+                                unquoted.push(c);
+                            }
+                            None => unquoted.push('\\'),
+                        },
+                        c => unquoted.push(c),
+                    }
+                }
+            }
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
     }
-    Ok(ret)
+
+    unquoted
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::telemetry::os_info::{parse_key_value_line, parse_os_info};
+    use std::path::{Path, PathBuf};
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::telemetry::os_info::{
+        collect_os_info, parse_key_value_line, parse_os_release, read_os_release, unquote, Uname,
+    };
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/os_info")
+            .join(name)
+    }
+
+    fn uname() -> Uname {
+        Uname {
+            sysname: "Linux".to_owned(),
+            release: "5.15.32-v8+".to_owned(),
+        }
+    }
+
+    fn os_info(fixture_name: &str) -> (AstarteType, AstarteType) {
+        let os_release = read_os_release(&[&fixture(fixture_name)]);
+        let mut data = collect_os_info(os_release.as_deref(), &uname());
+        assert_eq!(data.len(), 2, "{data:?}");
+
+        (
+            data.remove("/osName").unwrap(),
+            data.remove("/osVersion").unwrap(),
+        )
+    }
+
+    fn string(value: &str) -> AstarteType {
+        AstarteType::String(value.to_owned())
+    }
 
     #[test]
     fn os_release_parsing() {
-        let file = r#"NAME="Arch Linux"
-PRETTY_NAME="Arch Linux"
-ID=arch
-BUILD_ID=rolling
-ANSI_COLOR="38;2;23;147;209"
-HOME_URL="https://archlinux.org/"
-DOCUMENTATION_URL="https://wiki.archlinux.org/"
-SUPPORT_URL="https://bbs.archlinux.org/"
-BUG_REPORT_URL="https://bugs.archlinux.org/"
-LOGO=archlinux-logo
-"#;
-
-        let data = parse_os_info(file).unwrap();
-        assert_eq!(data["/osName"], "Arch Linux");
-        assert_eq!(data["/osVersion"], "rolling");
-
-        let file = r#"PRETTY_NAME="Debian GNU/Linux 11 (bullseye)"
-NAME="Debian GNU/Linux"
-VERSION_ID="11"
-VERSION="11 (bullseye)"
-VERSION_CODENAME=bullseye
-ID=debian
-HOME_URL="https://www.debian.org/"
-SUPPORT_URL="https://www.debian.org/support"
-BUG_REPORT_URL="https://bugs.debian.org/""#;
-
-        let data = parse_os_info(file).unwrap();
-        assert_eq!(data["/osName"], "Debian GNU/Linux");
-        assert_eq!(data["/osVersion"], "11");
+        assert_eq!(
+            os_info("arch-os-release"),
+            (string("Arch Linux"), string("rolling"))
+        );
+        assert_eq!(
+            os_info("debian-os-release"),
+            (string("Debian GNU/Linux"), string("11"))
+        );
     }
 
     #[test]
@@ -107,25 +205,78 @@ BUG_REPORT_URL="https://bugs.debian.org/""#;
 
 VERSION_ID="11""#;
 
-        let data = parse_os_info(file).unwrap();
-        assert_eq!(data["/osName"], "Debian GNU/Linux");
-        assert_eq!(data["/osVersion"], "11");
+        let data = parse_os_release(file);
+        assert_eq!(data["NAME"], "Debian GNU/Linux");
+        assert_eq!(data["VERSION_ID"], "11");
+    }
+
+    #[test]
+    fn quoted_values() {
+        let data =
+            parse_os_release(&std::fs::read_to_string(fixture("quoted-os-release")).unwrap());
+
+        assert_eq!(data["NAME"], "Edgehog \"Gateway\" Linux");
+        assert_eq!(data["VERSION"], "2022.08 (it's $stable)");
+        assert_eq!(data["ID"], "edgehog");
+        assert_eq!(data["VERSION_ID"], "2022.08");
+        assert_eq!(data["HOME_URL"], "https://edgehog.io/#home");
+        assert!(!data.contains_key("VARIANT"));
+        assert!(!data.contains_key("BROKEN LINE"));
+    }
+
+    #[test]
+    fn pretty_name_and_version_as_fallbacks() {
+        assert_eq!(
+            os_info("busybox-os-release"),
+            (string("BusyBox v1.35.0"), string("1.35.0 (2022-08-01)"))
+        );
+    }
+
+    #[test]
+    fn uname_without_os_release() {
+        assert_eq!(
+            os_info("missing-os-release"),
+            (string("Linux"), string("5.15.32-v8+"))
+        );
     }
 
     #[test]
     fn os_release_with_only_name() {
         let file = r#"NAME="Arch Linux"#;
 
-        let data = parse_os_info(file).unwrap();
-        assert_eq!(data["/osName"], "Arch Linux");
-        assert!(!data.contains_key("/osVersion"));
+        let data = collect_os_info(Some(file), &uname());
+        assert_eq!(data["/osName"], string("Arch Linux"));
+        assert_eq!(data["/osVersion"], string("5.15.32-v8+"));
     }
 
     #[test]
     fn os_release_malformed() {
         let file = r#"NAM["Arch Linux"@@"#;
 
-        let data = parse_os_info(file).unwrap();
+        assert!(parse_os_release(file).is_empty());
+    }
+
+    #[test]
+    fn first_readable_os_release() {
+        let os_release = read_os_release(&[
+            &fixture("missing-os-release"),
+            &fixture("debian-os-release"),
+            &fixture("arch-os-release"),
+        ]);
+
+        assert!(os_release.unwrap().contains("VERSION_ID=\"11\""));
+    }
+
+    #[test]
+    fn empty_fields_skipped() {
+        let data = collect_os_info(
+            Some("NAME=\nVERSION_ID=''\n"),
+            &Uname {
+                sysname: String::new(),
+                release: String::new(),
+            },
+        );
+
         assert!(data.is_empty());
     }
 
@@ -139,10 +290,9 @@ VERSION_ID="11""#;
 
     #[test]
     fn parse_key_value_line_malformed() {
-        let line = r#"OS;"Arch"#;
-
-        let data = parse_key_value_line(line);
-        assert!(data.is_none());
+        for line in [r#"OS;"Arch"#, "=Arch", "# OS=Arch"] {
+            assert!(parse_key_value_line(line).is_none(), "{line}");
+        }
     }
 
     #[test]
@@ -153,4 +303,14 @@ VERSION_ID="11""#;
         assert_eq!(key, "OS");
         assert_eq!(value, "Arch");
     }
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(unquote("plain"), "plain");
+        assert_eq!(unquote(r#""a \"b\" \\ \$c \x""#), r#"a "b" \ $c \x"#);
+        assert_eq!(unquote(r#"'a \"b'"#), r#"a \"b"#);
+        assert_eq!(unquote(r#"a\ b"c d"'e'"#), "a bc de");
+        assert_eq!(unquote(r#""unterminated"#), "unterminated");
+        assert_eq!(unquote("'unterminated"), "unterminated");
+    }
 }
@@ -0,0 +1,268 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Effective schedule of the telemetry collectors.
+//!
+//! Every collector updates its entry each time it schedules its next run, so the schedule shows
+//! what the collectors are doing rather than what the configuration asks for. It is published
+//! on the `telemetry:schedule` command and served by the status API.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+
+pub const SCHEDULE_COMMAND: &str = "telemetry:schedule";
+
+/// Where the period of a collector comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ScheduleSource {
+    Default,
+    ConfigFile,
+    /// The telemetry config set by the backend.
+    Runtime,
+}
+
+impl Display for ScheduleSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleSource::Default => write!(f, "default"),
+            ScheduleSource::ConfigFile => write!(f, "config file"),
+            ScheduleSource::Runtime => write!(f, "runtime"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectorSchedule {
+    pub source: ScheduleSource,
+    /// Period in effect, stretched while the connection is metered or the publishes fail.
+    pub period_secs: u64,
+    /// Factor of the period set by the backoff of the failing telemetry, 1 when not backing off.
+    #[serde(default = "no_backoff")]
+    pub backoff_factor: u32,
+    /// Largest random shift of the runs, as set by the backend.
+    pub jitter_secs: u64,
+    /// Whether a run publishes only what changed since the last one.
+    pub send_on_change: bool,
+    pub enabled: bool,
+    /// Unset while the collector is disabled or not started yet.
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+impl CollectorSchedule {
+    /// Enabled collector running every `period`, sending at every run.
+    pub fn new(source: ScheduleSource, period: Duration) -> Self {
+        CollectorSchedule {
+            source,
+            period_secs: period.as_secs(),
+            backoff_factor: 1,
+            jitter_secs: 0,
+            send_on_change: false,
+            enabled: true,
+            next_run: None,
+        }
+    }
+}
+
+fn no_backoff() -> u32 {
+    1
+}
+
+/// Schedule of the collectors by interface name.
+pub type Schedule = BTreeMap<String, CollectorSchedule>;
+
+/// Print `schedule`, one collector per line.
+pub fn write_schedule(f: &mut fmt::Formatter<'_>, schedule: &Schedule) -> fmt::Result {
+    for (interface_name, collector) in schedule {
+        if !collector.enabled {
+            writeln!(f, "  {interface_name}: disabled ({})", collector.source)?;
+            continue;
+        }
+
+        let next_run = collector
+            .next_run
+            .map_or_else(|| "not scheduled".to_owned(), |at| at.to_rfc3339());
+        let backoff = if collector.backoff_factor > 1 {
+            format!(" backed off x{}", collector.backoff_factor)
+        } else {
+            String::new()
+        };
+        writeln!(
+            f,
+            "  {interface_name}: every {}s{}{backoff} ({}), next {next_run}",
+            collector.period_secs,
+            if collector.send_on_change {
+                " on change"
+            } else {
+                ""
+            },
+            collector.source
+        )?;
+    }
+
+    Ok(())
+}
+
+pub struct TelemetrySchedule {
+    clock: Arc<dyn Clock>,
+    collectors: Mutex<Schedule>,
+    requested: Notify,
+}
+
+impl TelemetrySchedule {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        TelemetrySchedule {
+            clock,
+            collectors: Mutex::new(Schedule::new()),
+            requested: Notify::new(),
+        }
+    }
+
+    pub fn register(&self, interface_name: &str, collector: CollectorSchedule) {
+        self.collectors
+            .lock()
+            .unwrap()
+            .insert(interface_name.to_owned(), collector);
+    }
+
+    /// Change the entry of a registered collector.
+    pub fn update(&self, interface_name: &str, change: impl FnOnce(&mut CollectorSchedule)) {
+        if let Some(collector) = self.collectors.lock().unwrap().get_mut(interface_name) {
+            change(collector);
+        }
+    }
+
+    /// Record that the next run of a collector is due `after` from now, or not scheduled.
+    pub fn next_run(&self, interface_name: &str, after: Option<Duration>) {
+        let next_run = after.map(|after| DateTime::<Utc>::from(self.clock.now_wall() + after));
+
+        self.update(interface_name, |collector| collector.next_run = next_run);
+    }
+
+    pub fn snapshot(&self) -> Schedule {
+        self.collectors.lock().unwrap().clone()
+    }
+
+    /// Ask [`TelemetrySchedule::run`] to publish the schedule.
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    /// Publish the schedule as JSON on `/telemetry/schedule` of the diagnostics interface every
+    /// time it is requested.
+    pub async fn run(&self, publisher: &impl Publisher) {
+        loop {
+            self.requested.notified().await;
+
+            let schedule = match serde_json::to_string(&self.snapshot()) {
+                Ok(schedule) => schedule,
+                Err(err) => {
+                    warn!("Unable to serialize the telemetry schedule: {:?}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    "/telemetry/schedule",
+                    AstarteType::String(schedule),
+                )
+                .await
+            {
+                warn!("Unable to publish the telemetry schedule: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use chrono::{TimeZone, Utc};
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::{DIAGNOSTICS_INTERFACE, NETWORK_SOCKETS_INTERFACE};
+    use crate::telemetry::schedule::{
+        CollectorSchedule, Schedule, ScheduleSource, TelemetrySchedule,
+    };
+    use crate::test_utils::{settle, ManualClock};
+
+    #[tokio::test]
+    async fn schedule_published_on_request() {
+        let clock = Arc::new(ManualClock::new());
+        let schedule = Arc::new(TelemetrySchedule::new(clock.clone()));
+        schedule.register(
+            NETWORK_SOCKETS_INTERFACE,
+            CollectorSchedule {
+                send_on_change: true,
+                ..CollectorSchedule::new(ScheduleSource::ConfigFile, Duration::from_secs(60))
+            },
+        );
+        // updates of unregistered collectors are dropped
+        schedule.next_run("io.edgehog.devicemanager.Unknown", Some(Duration::ZERO));
+        schedule.next_run(NETWORK_SOCKETS_INTERFACE, Some(Duration::from_secs(60)));
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .withf(|interface, path, data| {
+                let schedule: Schedule = match data {
+                    AstarteType::String(schedule) => serde_json::from_str(schedule).unwrap(),
+                    _ => return false,
+                };
+                let expected = CollectorSchedule {
+                    send_on_change: true,
+                    // 2022-04-15T05:20:00Z is the start of the manual clock
+                    next_run: Some(Utc.ymd(2022, 4, 15).and_hms(5, 21, 0)),
+                    ..CollectorSchedule::new(ScheduleSource::ConfigFile, Duration::from_secs(60))
+                };
+
+                interface == DIAGNOSTICS_INTERFACE
+                    && path == "/telemetry/schedule"
+                    && schedule.len() == 1
+                    && schedule.get(NETWORK_SOCKETS_INTERFACE) == Some(&expected)
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let running = schedule.clone();
+        let run = tokio::spawn(async move { running.run(&publisher).await });
+
+        schedule.request();
+        settle().await;
+
+        run.abort();
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Values of the initial telemetry properties last sent, persisted so that a restart only sends
+//! the values that changed or were never sent.
+//!
+//! Only the successful sends are recorded, a value whose send failed is sent again at the next
+//! start. The values are compared by their debug form, which includes their type.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use log::{debug, warn};
+
+use crate::data::Publisher;
+use crate::repository::StateRepository;
+
+/// Debug form of the values last sent, by interface and path.
+pub type SentValues = BTreeMap<String, BTreeMap<String, String>>;
+
+pub struct SentProperties {
+    repository: Box<dyn StateRepository<SentValues>>,
+    sent: Mutex<SentValues>,
+    force_resend: bool,
+}
+
+fn fingerprint(data: &AstarteType) -> String {
+    format!("{data:?}")
+}
+
+impl SentProperties {
+    pub fn new(repository: Box<dyn StateRepository<SentValues>>) -> Self {
+        let sent = repository
+            .exists()
+            .then(|| repository.read())
+            .and_then(|read| {
+                read.map_err(|err| warn!("Unable to read the sent properties: {:?}", err))
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        SentProperties {
+            repository,
+            sent: Mutex::new(sent),
+            force_resend: false,
+        }
+    }
+
+    /// Send every value, even the ones already sent, e.g. to recover a backend that lost them.
+    pub fn with_force_resend(mut self, force_resend: bool) -> Self {
+        self.force_resend = force_resend;
+        self
+    }
+
+    /// Send the values of `fields` that differ from the ones last sent on `interface_name`,
+    /// returns how many were sent.
+    pub async fn send_changed(
+        &self,
+        publisher: &impl Publisher,
+        interface_name: &str,
+        fields: HashMap<String, AstarteType>,
+    ) -> Result<usize, AstarteError> {
+        let changed: Vec<(String, AstarteType, String)> = {
+            let sent = self.sent.lock().unwrap();
+            let last = sent.get(interface_name);
+            fields
+                .into_iter()
+                .map(|(path, data)| {
+                    let fingerprint = fingerprint(&data);
+                    (path, data, fingerprint)
+                })
+                .filter(|(path, _, fingerprint)| {
+                    self.force_resend || last.and_then(|last| last.get(path)) != Some(fingerprint)
+                })
+                .collect()
+        };
+
+        let mut result = Ok(changed.len());
+        let mut recorded = false;
+        for (path, data, fingerprint) in changed {
+            if let Err(err) = publisher.send(interface_name, &path, data).await {
+                result = Err(err);
+                break;
+            }
+
+            self.sent
+                .lock()
+                .unwrap()
+                .entry(interface_name.to_owned())
+                .or_default()
+                .insert(path, fingerprint);
+            recorded = true;
+        }
+
+        if recorded {
+            let sent = self.sent.lock().unwrap().clone();
+            if let Err(err) = self.repository.write(&sent) {
+                warn!("Unable to persist the sent properties: {:?}", err);
+            }
+        }
+
+        if let Ok(count) = result {
+            debug!("{count} properties of {interface_name} changed since the last send");
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use astarte_sdk::types::AstarteType;
+    use astarte_sdk::AstarteError;
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::{HARDWARE_INFO_INTERFACE, OS_INFO_INTERFACE};
+    use crate::telemetry::sent_properties::{SentProperties, SentValues};
+    use crate::test_utils::MemoryStateRepository;
+
+    type Sent = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Publisher recording the sent interfaces and paths, failing the sends on `failing` paths.
+    fn recording_publisher(failing: &'static [&'static str]) -> (MockPublisher, Sent) {
+        let sent = Sent::default();
+        let recorded = sent.clone();
+
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send()
+            .returning(move |interface, path, _| {
+                if failing.iter().any(|failing| *failing == path) {
+                    return Err(AstarteError::SendError("broker unreachable".to_owned()));
+                }
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((interface.to_owned(), path.to_owned()));
+                Ok(())
+            });
+
+        (publisher, sent)
+    }
+
+    fn os_info(version: &str) -> HashMap<String, AstarteType> {
+        HashMap::from([
+            (
+                "/osName".to_owned(),
+                AstarteType::String("Linux".to_owned()),
+            ),
+            (
+                "/osVersion".to_owned(),
+                AstarteType::String(version.to_owned()),
+            ),
+        ])
+    }
+
+    fn hardware_info() -> HashMap<String, AstarteType> {
+        HashMap::from([("/cpu/totalCores".to_owned(), AstarteType::Integer(4))])
+    }
+
+    /// Send the OS and hardware info as a start of the runtime does, returns what was sent.
+    async fn start(
+        repository: &Arc<MemoryStateRepository<SentValues>>,
+        version: &str,
+        force_resend: bool,
+    ) -> Vec<(String, String)> {
+        let (publisher, sent) = recording_publisher(&[]);
+        let properties =
+            SentProperties::new(Box::new(repository.clone())).with_force_resend(force_resend);
+
+        properties
+            .send_changed(&publisher, OS_INFO_INTERFACE, os_info(version))
+            .await
+            .unwrap();
+        properties
+            .send_changed(&publisher, HARDWARE_INFO_INTERFACE, hardware_info())
+            .await
+            .unwrap();
+
+        let mut sent = sent.lock().unwrap().clone();
+        sent.sort();
+        sent
+    }
+
+    fn sent(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(interface, path)| (interface.to_string(), path.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_changes_sent_again() {
+        let repository = Arc::new(MemoryStateRepository::new());
+
+        assert_eq!(
+            start(&repository, "5.15", false).await,
+            sent(&[
+                (HARDWARE_INFO_INTERFACE, "/cpu/totalCores"),
+                (OS_INFO_INTERFACE, "/osName"),
+                (OS_INFO_INTERFACE, "/osVersion"),
+            ])
+        );
+        assert!(start(&repository, "5.15", false).await.is_empty());
+        assert_eq!(
+            start(&repository, "6.1", false).await,
+            sent(&[(OS_INFO_INTERFACE, "/osVersion")])
+        );
+    }
+
+    #[tokio::test]
+    async fn forced_resend_sends_everything() {
+        let repository = Arc::new(MemoryStateRepository::new());
+        start(&repository, "5.15", false).await;
+
+        assert_eq!(start(&repository, "5.15", true).await.len(), 3);
+        // the cache is still kept up to date
+        assert!(start(&repository, "5.15", false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_send_not_cached() {
+        let repository = Arc::new(MemoryStateRepository::new());
+        let properties = SentProperties::new(Box::new(repository.clone()));
+        let (publisher, _) = recording_publisher(&["/osVersion"]);
+
+        let fields = HashMap::from([(
+            "/osVersion".to_owned(),
+            AstarteType::String("5.15".to_owned()),
+        )]);
+        assert!(properties
+            .send_changed(&publisher, OS_INFO_INTERFACE, fields)
+            .await
+            .is_err());
+        assert_eq!(repository.value(), None);
+
+        assert_eq!(
+            start(&repository, "5.15", false).await,
+            sent(&[
+                (HARDWARE_INFO_INTERFACE, "/cpu/totalCores"),
+                (OS_INFO_INTERFACE, "/osName"),
+                (OS_INFO_INTERFACE, "/osVersion"),
+            ])
+        );
+    }
+}
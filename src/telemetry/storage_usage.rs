@@ -0,0 +1,665 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Usage of the storage areas and of the mounted filesystems, published on
+//! `io.edgehog.devicemanager.StorageUsage`.
+//!
+//! The configured areas are published under their label, the filesystems listed in
+//! `/proc/mounts` under a label made of their mount point, e.g. `media_usb` for `/media/usb` and
+//! `root` for `/`. The mounts are listed again at every run, so a filesystem unmounted is no
+//! longer published.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::disk_guard::{DiskUsage, SpaceProvider};
+use crate::interfaces::{DIAGNOSTICS_INTERFACE, STORAGE_USAGE_INTERFACE};
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig};
+use crate::telemetry::schedule::TelemetrySchedule;
+use crate::telemetry::CollectorRuns;
+
+pub const DEFAULT_STORAGE_USAGE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Percentage points the usage has to drop below the threshold to clear the warning.
+const WARNING_HYSTERESIS: f64 = 5.0;
+
+const MOUNTS_FILE: &str = "/proc/mounts";
+
+/// Filesystems without a storage of their own, not reported unless included.
+const PSEUDO_FILESYSTEMS: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "ramfs",
+    "rpc_pipefs",
+    "securityfs",
+    "selinuxfs",
+    "sysfs",
+    "tmpfs",
+    "tracefs",
+];
+
+/// Logical storage area, identified by a label.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageArea {
+    pub label: String,
+    pub path: PathBuf,
+    /// Usage percentage above which a warning is emitted
+    pub warning_percent: Option<u8>,
+}
+
+/// Reporting of the mounted filesystems.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StorageMountOptions {
+    /// Report the mounted filesystems, enabled when unset.
+    pub enabled: Option<bool>,
+    /// Pseudo filesystems reported anyway, e.g. `tmpfs`.
+    #[serde(default)]
+    pub include_filesystems: Vec<String>,
+}
+
+impl StorageMountOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageAreaUsage {
+    pub total_bytes: i64,
+    pub free_bytes: i64,
+}
+
+impl From<DiskUsage> for StorageAreaUsage {
+    fn from(usage: DiskUsage) -> Self {
+        StorageAreaUsage {
+            total_bytes: usage.total_bytes as i64,
+            free_bytes: usage.available_bytes as i64,
+        }
+    }
+}
+
+fn used_percent(usage: &DiskUsage) -> f64 {
+    if usage.total_bytes == 0 {
+        return 0.0;
+    }
+
+    100.0 * (usage.total_bytes - usage.available_bytes.min(usage.total_bytes)) as f64
+        / usage.total_bytes as f64
+}
+
+/// Label of the filesystem mounted at `mount_point`, usable as an Astarte path.
+fn mount_label(mount_point: &str) -> String {
+    let label: String = mount_point
+        .trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    if label.is_empty() {
+        "root".to_owned()
+    } else {
+        label
+    }
+}
+
+/// Undo the octal escapes of the spaces, tabs, newlines and backslashes in `/proc/mounts`.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// Mount points of `mounts` by label, skipping the pseudo filesystems not in `include`. A mount
+/// point listed twice is the last one mounted over it.
+fn parse_mounts(mounts: &str, include: &[String]) -> BTreeMap<String, PathBuf> {
+    let mut parsed = BTreeMap::new();
+
+    for line in mounts.lines() {
+        let (mount_point, filesystem) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [_, mount_point, filesystem, ..] => (unescape(mount_point), filesystem),
+            _ => continue,
+        };
+        if PSEUDO_FILESYSTEMS.contains(&filesystem)
+            && !include.iter().any(|included| included == filesystem)
+        {
+            continue;
+        }
+
+        parsed.insert(mount_label(&mount_point), PathBuf::from(mount_point));
+    }
+
+    parsed
+}
+
+#[derive(Debug, Default)]
+struct AreaState {
+    warning: bool,
+    missing_reported: bool,
+}
+
+pub struct StorageUsageTelemetry {
+    clock: Arc<dyn Clock>,
+    provider: Box<dyn SpaceProvider>,
+    areas: Vec<StorageArea>,
+    period: Duration,
+    states: HashMap<String, AreaState>,
+    /// File listing the mounted filesystems, `None` when they are not reported.
+    mounts_file: Option<PathBuf>,
+    include_filesystems: Vec<String>,
+    config: watch::Receiver<TelemetryConfig>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    backoff: Option<watch::Receiver<u32>>,
+    random: Arc<dyn Random>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl StorageUsageTelemetry {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        provider: Box<dyn SpaceProvider>,
+        areas: Vec<StorageArea>,
+        period: Duration,
+    ) -> Self {
+        let (_, config) = watch::channel(TelemetryConfig::new());
+        let (_, shutdown) = watch::channel(false);
+
+        StorageUsageTelemetry {
+            clock,
+            provider,
+            areas,
+            period,
+            states: HashMap::new(),
+            mounts_file: None,
+            include_filesystems: Vec::new(),
+            config,
+            schedule: None,
+            backoff: None,
+            random: Arc::new(OsRandom),
+            shutdown,
+        }
+    }
+
+    /// Report the mounted filesystems too, unless disabled in `options`.
+    pub fn with_mounts(mut self, options: Option<&StorageMountOptions>) -> Self {
+        let options = options.cloned().unwrap_or_default();
+        if options.is_enabled() {
+            self.mounts_file = Some(PathBuf::from(MOUNTS_FILE));
+            self.include_filesystems = options.include_filesystems;
+        }
+        self
+    }
+
+    /// Follow the period and the enabling set by the backend in `config`.
+    pub fn with_config(mut self, config: watch::Receiver<TelemetryConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Stretch the period by the factor of `backoff`, when enabled.
+    pub fn with_backoff(mut self, backoff: Option<watch::Receiver<u32>>) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub async fn run(self, publisher: &impl Publisher) {
+        let runs = CollectorRuns::new(
+            STORAGE_USAGE_INTERFACE,
+            "Storage usage",
+            self.clock.clone(),
+            self.config.clone(),
+            self.period,
+        )
+        .with_schedule(self.schedule.clone())
+        .with_random(self.random.clone())
+        .with_backoff(self.backoff.clone())
+        .with_shutdown(self.shutdown.clone());
+
+        runs.run(self, |mut telemetry, enabled| async move {
+            if enabled {
+                telemetry.collect(publisher).await;
+            }
+            telemetry
+        })
+        .await;
+    }
+
+    async fn collect(&mut self, publisher: &impl Publisher) {
+        for area in &self.areas {
+            let state = self.states.entry(area.label.clone()).or_default();
+
+            if !area.path.exists() {
+                if !state.missing_reported {
+                    warn!(
+                        "Storage area {} path {:?} does not exist",
+                        area.label, area.path
+                    );
+                    send_diagnostics(publisher, &area.label, "missing", true).await;
+                    state.missing_reported = true;
+                }
+                continue;
+            }
+            state.missing_reported = false;
+
+            let usage = match self.provider.usage(&area.path) {
+                Ok(usage) => usage,
+                Err(err) => {
+                    error!("Unable to read the usage of {}: {:?}", area.label, err);
+                    continue;
+                }
+            };
+            send_usage(publisher, &area.label, usage).await;
+
+            let threshold = match area.warning_percent {
+                Some(threshold) => f64::from(threshold),
+                None => continue,
+            };
+            let used = used_percent(&usage);
+
+            if !state.warning && used >= threshold {
+                warn!("Storage area {} is {used:.1}% full", area.label);
+                send_diagnostics(publisher, &area.label, "warning", true).await;
+                state.warning = true;
+            } else if state.warning && used < threshold - WARNING_HYSTERESIS {
+                send_diagnostics(publisher, &area.label, "warning", false).await;
+                state.warning = false;
+            }
+        }
+
+        for (label, path) in self.mounts() {
+            // unmounted since the mounts were listed
+            let usage = match self.provider.usage(&path) {
+                Ok(usage) if usage.total_bytes > 0 => usage,
+                Ok(_) => continue,
+                Err(err) => {
+                    debug!("Unable to read the usage of {}: {:?}", path.display(), err);
+                    continue;
+                }
+            };
+            send_usage(publisher, &label, usage).await;
+        }
+    }
+
+    /// Mounted filesystems not already reported as a storage area.
+    fn mounts(&self) -> BTreeMap<String, PathBuf> {
+        let mounts_file = match &self.mounts_file {
+            Some(mounts_file) => mounts_file,
+            None => return BTreeMap::new(),
+        };
+        let mounts = match std::fs::read_to_string(mounts_file) {
+            Ok(mounts) => mounts,
+            Err(err) => {
+                error!("Unable to list the mounted filesystems: {}", err);
+                return BTreeMap::new();
+            }
+        };
+
+        let mut mounts = parse_mounts(&mounts, &self.include_filesystems);
+        mounts.retain(|label, path| {
+            !self
+                .areas
+                .iter()
+                .any(|area| area.label == *label || area.path == *path)
+        });
+
+        mounts
+    }
+}
+
+async fn send_usage(publisher: &impl Publisher, label: &str, usage: DiskUsage) {
+    if let Err(err) = publisher
+        .send_object(
+            STORAGE_USAGE_INTERFACE,
+            &format!("/{label}"),
+            StorageAreaUsage::from(usage),
+        )
+        .await
+    {
+        error!("Unable to send the usage of {label}: {:?}", err);
+    }
+}
+
+async fn send_diagnostics(publisher: &impl Publisher, label: &str, event: &str, value: bool) {
+    if let Err(err) = publisher
+        .send(
+            DIAGNOSTICS_INTERFACE,
+            &format!("/storage/{label}/{event}"),
+            AstarteType::Boolean(value),
+        )
+        .await
+    {
+        error!("Unable to send the storage {event} of {label}: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+    use tokio::sync::watch;
+
+    use crate::clock::SystemClock;
+    use crate::disk_guard::tests::FakeSpace;
+    use crate::interfaces::{DIAGNOSTICS_INTERFACE, STORAGE_USAGE_INTERFACE};
+    use crate::telemetry::config::{TelemetryConfig, TelemetryInterfaceConfig};
+    use crate::telemetry::storage_usage::{
+        parse_mounts, StorageArea, StorageAreaUsage, StorageUsageTelemetry,
+    };
+    use crate::test_utils::{recording_publisher, settle, ManualClock, Recorded, Recording};
+
+    const MOUNTS: &str = "/dev/root / ext4 rw,relatime 0 0
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+tmpfs /run tmpfs rw,nosuid,nodev,mode=755 0 0
+/dev/mmcblk0p4 /data ext4 rw,relatime 0 0
+/dev/sda1 /media/usb\\040stick vfat rw,relatime 0 0
+/dev/sda2 /media/usb\\040stick vfat rw,relatime 0 0
+";
+
+    fn usages(recording: &Recording<StorageAreaUsage>) -> Vec<(String, StorageAreaUsage)> {
+        recording
+            .on(STORAGE_USAGE_INTERFACE)
+            .into_iter()
+            .filter_map(|(path, data)| match data {
+                Recorded::Object(usage) => Some((path, usage)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn events(recording: &Recording<StorageAreaUsage>) -> Vec<(String, bool)> {
+        recording
+            .values(DIAGNOSTICS_INTERFACE)
+            .into_iter()
+            .filter_map(|(path, value)| match value {
+                AstarteType::Boolean(value) => Some((path, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn usage_reported_per_label_with_hysteresis() {
+        let dir = tempfile::tempdir().unwrap();
+        let free = Arc::new(Mutex::new(500));
+        let mut telemetry = StorageUsageTelemetry::new(
+            Arc::new(SystemClock),
+            Box::new(FakeSpace(free.clone())),
+            vec![StorageArea {
+                label: "media".to_owned(),
+                path: dir.path().to_owned(),
+                warning_percent: Some(80),
+            }],
+            Duration::from_secs(60),
+        );
+
+        let (publisher, recording) = recording_publisher();
+
+        telemetry.collect(&publisher).await;
+        assert_eq!(
+            usages(&recording),
+            vec![(
+                "/media".to_owned(),
+                StorageAreaUsage {
+                    total_bytes: 1000,
+                    free_bytes: 500
+                }
+            )]
+        );
+        assert!(events(&recording).is_empty());
+
+        // crossing the threshold warns once
+        *free.lock().unwrap() = 150;
+        telemetry.collect(&publisher).await;
+        telemetry.collect(&publisher).await;
+        assert_eq!(
+            events(&recording),
+            vec![("/storage/media/warning".to_owned(), true)]
+        );
+
+        // back below the threshold, but within the hysteresis
+        *free.lock().unwrap() = 230;
+        telemetry.collect(&publisher).await;
+        assert_eq!(events(&recording).len(), 1);
+
+        *free.lock().unwrap() = 260;
+        telemetry.collect(&publisher).await;
+        assert_eq!(
+            events(&recording).last(),
+            Some(&("/storage/media/warning".to_owned(), false))
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_path_reported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut telemetry = StorageUsageTelemetry::new(
+            Arc::new(SystemClock),
+            Box::new(FakeSpace(Arc::new(Mutex::new(500)))),
+            vec![StorageArea {
+                label: "appdata".to_owned(),
+                path: dir.path().join("missing"),
+                warning_percent: None,
+            }],
+            Duration::from_secs(60),
+        );
+
+        let (publisher, recording) = recording_publisher();
+
+        telemetry.collect(&publisher).await;
+        telemetry.collect(&publisher).await;
+        assert!(usages(&recording).is_empty());
+        assert_eq!(
+            events(&recording),
+            vec![("/storage/appdata/missing".to_owned(), true)]
+        );
+
+        std::fs::create_dir(dir.path().join("missing")).unwrap();
+        telemetry.collect(&publisher).await;
+        assert_eq!(usages(&recording).len(), 1);
+    }
+
+    #[test]
+    fn mounts_parsed() {
+        assert_eq!(
+            parse_mounts(MOUNTS, &[]).into_iter().collect::<Vec<_>>(),
+            vec![
+                ("data".to_owned(), PathBuf::from("/data")),
+                (
+                    "media_usb_stick".to_owned(),
+                    PathBuf::from("/media/usb stick")
+                ),
+                ("root".to_owned(), PathBuf::from("/")),
+            ]
+        );
+
+        let mounts = parse_mounts(MOUNTS, &["tmpfs".to_owned()]);
+        assert_eq!(mounts.get("run"), Some(&PathBuf::from("/run")));
+        assert!(!mounts.contains_key("proc"));
+        assert!(parse_mounts("truncated line\n", &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn mounts_listed_at_every_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mounts_file = dir.path().join("mounts");
+        std::fs::write(&mounts_file, MOUNTS).unwrap();
+        let mut telemetry = StorageUsageTelemetry::new(
+            Arc::new(SystemClock),
+            Box::new(FakeSpace(Arc::new(Mutex::new(500)))),
+            vec![StorageArea {
+                label: "appdata".to_owned(),
+                path: dir.path().to_owned(),
+                warning_percent: None,
+            }],
+            Duration::from_secs(60),
+        )
+        .with_mounts(None);
+        telemetry.mounts_file = Some(mounts_file.clone());
+        // the mount of the area is reported under its label only
+        std::fs::write(
+            &mounts_file,
+            format!("{MOUNTS}/dev/sdb1 {} ext4 rw 0 0\n", dir.path().display()),
+        )
+        .unwrap();
+
+        let (publisher, recording) = recording_publisher();
+        let paths = || {
+            let paths = usages(&recording)
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>();
+            recording.clear();
+            paths
+        };
+
+        telemetry.collect(&publisher).await;
+        assert_eq!(paths(), ["/appdata", "/data", "/media_usb_stick", "/root"]);
+
+        std::fs::write(&mounts_file, "/dev/root / ext4 rw 0 0\n").unwrap();
+        telemetry.collect(&publisher).await;
+        assert_eq!(paths(), ["/appdata", "/root"]);
+
+        // not listed, the areas are still reported
+        std::fs::remove_file(&mounts_file).unwrap();
+        telemetry.collect(&publisher).await;
+        assert_eq!(paths(), ["/appdata"]);
+    }
+
+    #[tokio::test]
+    async fn period_follows_the_telemetry_config() {
+        let clock = Arc::new(ManualClock::new());
+        let dir = tempfile::tempdir().unwrap();
+        let (config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = StorageUsageTelemetry::new(
+            clock.clone(),
+            Box::new(FakeSpace(Arc::new(Mutex::new(500)))),
+            vec![StorageArea {
+                label: "media".to_owned(),
+                path: dir.path().to_owned(),
+                warning_percent: None,
+            }],
+            Duration::from_secs(60),
+        )
+        .with_config(config);
+
+        let (publisher, recording) = recording_publisher();
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+        let sent = || usages(&recording).len();
+
+        settle().await;
+        assert_eq!(sent(), 1);
+
+        let configured = |enabled, period_secs| {
+            [(
+                STORAGE_USAGE_INTERFACE.to_owned(),
+                TelemetryInterfaceConfig {
+                    enabled,
+                    period_secs,
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect::<TelemetryConfig>()
+        };
+        config_tx.send(configured(None, Some(10))).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert_eq!(sent(), 2);
+
+        config_tx.send(configured(Some(false), Some(10))).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(30));
+        settle().await;
+        assert_eq!(sent(), 2);
+
+        config_tx.send(TelemetryConfig::new()).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(sent(), 3);
+
+        handle.abort();
+    }
+}
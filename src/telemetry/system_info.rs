@@ -0,0 +1,271 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Serial number and part number of the device, for `io.edgehog.devicemanager.SystemInfo`.
+//!
+//! Each value is taken from the first source having it: the configuration file, then the
+//! `EDGEHOG_SERIAL_NUMBER` and `EDGEHOG_PART_NUMBER` environment variables, then the DMI of the
+//! firmware or the device tree. Blank values are skipped, a value found nowhere is not sent.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use astarte_sdk::types::AstarteType;
+use log::{debug, info};
+#[cfg(test)]
+use mockall::automock;
+use serde::Deserialize;
+
+pub(crate) const SERIAL_NUMBER_ENV: &str = "EDGEHOG_SERIAL_NUMBER";
+pub(crate) const PART_NUMBER_ENV: &str = "EDGEHOG_PART_NUMBER";
+const SERIAL_NUMBER_FILES: &[&str] = &[
+    "/sys/class/dmi/id/product_serial",
+    "/sys/class/dmi/id/board_serial",
+    "/proc/device-tree/serial-number",
+];
+const PART_NUMBER_FILES: &[&str] = &["/sys/class/dmi/id/product_sku"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SystemInfoOptions {
+    pub serial_number: Option<String>,
+    pub part_number: Option<String>,
+}
+
+/// Access to the environment and to the firmware files, the host ones outside of the tests.
+#[cfg_attr(test, automock)]
+pub trait SystemInfoReader {
+    fn env(&self, name: &str) -> Option<String>;
+    fn read(&self, path: &str) -> Option<String>;
+}
+
+pub struct HostReader;
+
+impl SystemInfoReader for HostReader {
+    fn env(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+
+    /// The DMI serials are only readable by root, they are missing otherwise.
+    fn read(&self, path: &str) -> Option<String> {
+        std::fs::read(path)
+            .map(|content| String::from_utf8_lossy(&content).into_owned())
+            .ok()
+    }
+}
+
+/// Where a value was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    Config,
+    Environment(&'static str),
+    File(&'static str),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Config => write!(f, "the configuration file"),
+            Source::Environment(name) => write!(f, "the {name} variable"),
+            Source::File(path) => write!(f, "{path}"),
+        }
+    }
+}
+
+/// The value without the surrounding blanks and the terminating NUL of the device tree, unless
+/// nothing is left.
+fn non_blank(value: &str) -> Option<String> {
+    let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+
+    (!value.is_empty()).then(|| value.to_owned())
+}
+
+/// The value of the first source having one.
+fn resolve(
+    reader: &dyn SystemInfoReader,
+    configured: Option<&str>,
+    env: &'static str,
+    files: &[&'static str],
+) -> Option<(String, Source)> {
+    if let Some(value) = configured.and_then(non_blank) {
+        return Some((value, Source::Config));
+    }
+    if let Some(value) = reader.env(env).as_deref().and_then(non_blank) {
+        return Some((value, Source::Environment(env)));
+    }
+
+    files.iter().find_map(|path| {
+        let value = reader.read(path).as_deref().and_then(non_blank)?;
+        Some((value, Source::File(*path)))
+    })
+}
+
+/// Data for the `io.edgehog.devicemanager.SystemInfo` interface.
+pub fn get_system_info(
+    options: Option<&SystemInfoOptions>,
+    reader: &dyn SystemInfoReader,
+) -> HashMap<String, AstarteType> {
+    let options = options.cloned().unwrap_or_default();
+    let values = [
+        (
+            "/serialNumber",
+            "Serial number",
+            resolve(
+                reader,
+                options.serial_number.as_deref(),
+                SERIAL_NUMBER_ENV,
+                SERIAL_NUMBER_FILES,
+            ),
+        ),
+        (
+            "/partNumber",
+            "Part number",
+            resolve(
+                reader,
+                options.part_number.as_deref(),
+                PART_NUMBER_ENV,
+                PART_NUMBER_FILES,
+            ),
+        ),
+    ];
+
+    let mut ret = HashMap::new();
+    for (path, name, value) in values {
+        match value {
+            Some((value, source)) => {
+                info!("{name} read from {source}");
+                ret.insert(path.to_owned(), AstarteType::String(value));
+            }
+            None => debug!("{name} not found"),
+        }
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::telemetry::system_info::{
+        get_system_info, resolve, MockSystemInfoReader, Source, SystemInfoOptions,
+    };
+
+    /// A reader with the `env` variables and the `files`, nothing else.
+    fn reader(env: &[(&str, &str)], files: &[(&str, &str)]) -> MockSystemInfoReader {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let files: HashMap<String, String> = files
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_string()))
+            .collect();
+
+        let mut reader = MockSystemInfoReader::new();
+        reader
+            .expect_env()
+            .returning(move |name| env.get(name).cloned());
+        reader
+            .expect_read()
+            .returning(move |path| files.get(path).cloned());
+        reader
+    }
+
+    const FILES: &[&str] = &[
+        "/sys/class/dmi/id/product_serial",
+        "/proc/device-tree/serial-number",
+    ];
+
+    #[test]
+    fn sources_in_priority_order() {
+        let all = reader(
+            &[("SERIAL", "ENV-1")],
+            &[
+                ("/sys/class/dmi/id/product_serial", "DMI-1\n"),
+                ("/proc/device-tree/serial-number", "DT-1\0"),
+            ],
+        );
+        assert_eq!(
+            resolve(&all, Some("CONF-1"), "SERIAL", FILES),
+            Some(("CONF-1".to_owned(), Source::Config))
+        );
+        assert_eq!(
+            resolve(&all, None, "SERIAL", FILES),
+            Some(("ENV-1".to_owned(), Source::Environment("SERIAL")))
+        );
+
+        let files = reader(&[], &[("/proc/device-tree/serial-number", "DT-1\0")]);
+        assert_eq!(
+            resolve(&files, None, "SERIAL", FILES),
+            Some((
+                "DT-1".to_owned(),
+                Source::File("/proc/device-tree/serial-number")
+            ))
+        );
+        assert_eq!(resolve(&reader(&[], &[]), None, "SERIAL", FILES), None);
+    }
+
+    #[test]
+    fn blank_values_skipped() {
+        let blank = reader(
+            &[("SERIAL", "  ")],
+            &[
+                ("/sys/class/dmi/id/product_serial", " \n"),
+                ("/proc/device-tree/serial-number", "DT-1\0"),
+            ],
+        );
+        assert_eq!(
+            resolve(&blank, Some("\t"), "SERIAL", FILES),
+            Some((
+                "DT-1".to_owned(),
+                Source::File("/proc/device-tree/serial-number")
+            ))
+        );
+    }
+
+    #[test]
+    fn system_info_sent() {
+        let reader = reader(
+            &[("EDGEHOG_PART_NUMBER", "PN-42")],
+            &[("/sys/class/dmi/id/product_serial", "SN-7\n")],
+        );
+
+        let data = get_system_info(None, &reader);
+        assert_eq!(data.len(), 2);
+        assert_eq!(
+            data["/serialNumber"],
+            AstarteType::String("SN-7".to_owned())
+        );
+        assert_eq!(data["/partNumber"], AstarteType::String("PN-42".to_owned()));
+
+        let options = SystemInfoOptions {
+            serial_number: Some("SN-1".to_owned()),
+            part_number: Some(" ".to_owned()),
+        };
+        let data = get_system_info(Some(&options), &reader);
+        assert_eq!(
+            data["/serialNumber"],
+            AstarteType::String("SN-1".to_owned())
+        );
+        assert_eq!(data["/partNumber"], AstarteType::String("PN-42".to_owned()));
+    }
+}
@@ -0,0 +1,310 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Load of the device, for `io.edgehog.devicemanager.SystemLoad`.
+//!
+//! The load averages and the process counts come from `/proc/loadavg`, the count of the CPU cores
+//! is sent along so the backend can compare the load of devices with a different number of
+//! cores.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::SYSTEM_LOAD_INTERFACE;
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig};
+use crate::telemetry::schedule::TelemetrySchedule;
+use crate::telemetry::CollectorRuns;
+
+pub const DEFAULT_SYSTEM_LOAD_PERIOD: Duration = Duration::from_secs(60);
+
+const LOADAVG_PATH: &str = "/proc/loadavg";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemLoad {
+    pub load_average_1m: f64,
+    pub load_average_5m: f64,
+    pub load_average_15m: f64,
+    /// Tasks currently runnable, the threads included.
+    pub running_processes: i32,
+    /// Tasks existing on the system, the threads included.
+    pub total_processes: i32,
+    pub cpu_count: i32,
+}
+
+/// Reads the current load, `/proc` outside of the tests.
+pub type SystemLoadReader = Box<dyn Fn() -> Result<SystemLoad, DeviceManagerError> + Send + Sync>;
+
+fn malformed(content: &str) -> DeviceManagerError {
+    DeviceManagerError::MalformedData {
+        path: LOADAVG_PATH.to_owned(),
+        reason: format!("{:?}", content.trim_end()),
+    }
+}
+
+/// Parse the content of `/proc/loadavg`, e.g. `0.20 0.18 0.12 1/80 11206`.
+fn parse_loadavg(content: &str, cpu_count: i32) -> Result<SystemLoad, DeviceManagerError> {
+    let fields: Vec<&str> = content.split_whitespace().collect();
+    let (averages, processes) = match fields.as_slice() {
+        [one, five, fifteen, processes, ..] => ([one, five, fifteen], processes),
+        _ => return Err(malformed(content)),
+    };
+
+    let mut load = [0.0; 3];
+    for (average, field) in load.iter_mut().zip(averages) {
+        *average = field
+            .parse::<f64>()
+            .ok()
+            .filter(|average| average.is_finite() && *average >= 0.0)
+            .ok_or_else(|| malformed(content))?;
+    }
+
+    let (running, total) = processes
+        .split_once('/')
+        .and_then(|(running, total)| Some((running.parse().ok()?, total.parse().ok()?)))
+        .filter(|(running, total)| running <= total)
+        .ok_or_else(|| malformed(content))?;
+
+    Ok(SystemLoad {
+        load_average_1m: load[0],
+        load_average_5m: load[1],
+        load_average_15m: load[2],
+        running_processes: running,
+        total_processes: total,
+        cpu_count,
+    })
+}
+
+/// Structured data for the `io.edgehog.devicemanager.SystemLoad` interface.
+pub fn get_system_load() -> Result<SystemLoad, DeviceManagerError> {
+    let cpu_count = procfs::CpuInfo::new()?.num_cores() as i32;
+
+    parse_loadavg(&std::fs::read_to_string(LOADAVG_PATH)?, cpu_count)
+}
+
+pub struct SystemLoadTelemetry {
+    clock: Arc<dyn Clock>,
+    reader: SystemLoadReader,
+    period: Duration,
+    config: watch::Receiver<TelemetryConfig>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    random: Arc<dyn Random>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl SystemLoadTelemetry {
+    pub fn new(clock: Arc<dyn Clock>, reader: SystemLoadReader, period: Duration) -> Self {
+        let (_, config) = watch::channel(TelemetryConfig::new());
+        let (_, shutdown) = watch::channel(false);
+
+        SystemLoadTelemetry {
+            clock,
+            reader,
+            period,
+            config,
+            schedule: None,
+            random: Arc::new(OsRandom),
+            shutdown,
+        }
+    }
+
+    /// Follow the period and the enabling set by the backend in `config`.
+    pub fn with_config(mut self, config: watch::Receiver<TelemetryConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(&self, publisher: &impl Publisher) {
+        let runs = CollectorRuns::new(
+            SYSTEM_LOAD_INTERFACE,
+            "System load",
+            self.clock.clone(),
+            self.config.clone(),
+            self.period,
+        )
+        .with_schedule(self.schedule.clone())
+        .with_random(self.random.clone())
+        .with_shutdown(self.shutdown.clone());
+
+        runs.run((), |(), enabled| async move {
+            if enabled {
+                self.send_system_load(publisher).await;
+            }
+        })
+        .await;
+    }
+
+    async fn send_system_load(&self, publisher: &impl Publisher) {
+        let collected = DateTime::<Utc>::from(self.clock.now_wall());
+        let system_load = match (self.reader)() {
+            Ok(system_load) => system_load,
+            Err(err) => {
+                warn!("Unable to read the system load: {:?}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = publisher
+            .send_object_with_timestamp(
+                SYSTEM_LOAD_INTERFACE,
+                "/systemLoad",
+                system_load,
+                collected,
+            )
+            .await
+        {
+            error!("Unable to send the system load: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::SYSTEM_LOAD_INTERFACE;
+    use crate::telemetry::config::{TelemetryConfig, TelemetryInterfaceConfig};
+    use crate::telemetry::system_load::{parse_loadavg, SystemLoad, SystemLoadTelemetry};
+    use crate::test_utils::{settle, ManualClock};
+
+    #[test]
+    fn loadavg_parsed() {
+        assert_eq!(
+            parse_loadavg("0.20 1.18 12.50 3/812 11206\n", 4).unwrap(),
+            SystemLoad {
+                load_average_1m: 0.2,
+                load_average_5m: 1.18,
+                load_average_15m: 12.5,
+                running_processes: 3,
+                total_processes: 812,
+                cpu_count: 4,
+            }
+        );
+        // the last pid is not needed
+        assert!(parse_loadavg("0.00 0.00 0.00 1/80", 1).is_ok());
+    }
+
+    #[test]
+    fn malformed_loadavg_refused() {
+        for content in [
+            "",
+            "0.20 0.18 0.12\n",
+            "0.20 0.18 0.12 1-80 11206\n",
+            "0.20 0.18 0.12 1/ 11206\n",
+            "0.20 0.18 0.12 81/80 11206\n",
+            "0.20 high 0.12 1/80 11206\n",
+            "-0.20 0.18 0.12 1/80 11206\n",
+            "NaN 0.18 0.12 1/80 11206\n",
+            "inf 0.18 0.12 1/80 11206\n",
+        ] {
+            assert!(parse_loadavg(content, 4).is_err(), "{content:?} parsed");
+        }
+    }
+
+    #[tokio::test]
+    async fn system_load_follows_server_config() {
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let mut publisher = MockPublisher::new();
+        let counter = sent.clone();
+        publisher
+            .expect_send_object_with_timestamp()
+            .withf(|interface: &str, path: &str, _: &SystemLoad, _| {
+                interface == SYSTEM_LOAD_INTERFACE && path == "/systemLoad"
+            })
+            .returning(move |_, _, _: SystemLoad, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let (config_tx, config) = watch::channel(TelemetryConfig::new());
+        let telemetry = SystemLoadTelemetry::new(
+            clock.clone(),
+            Box::new(|| parse_loadavg("0.20 0.18 0.12 1/80 11206", 2)),
+            Duration::from_secs(60),
+        )
+        .with_config(config);
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        let configured = |enabled| {
+            [(
+                SYSTEM_LOAD_INTERFACE.to_owned(),
+                TelemetryInterfaceConfig {
+                    enabled,
+                    period_secs: Some(10),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect::<TelemetryConfig>()
+        };
+        config_tx.send(configured(None)).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(10));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+        config_tx.send(configured(Some(false))).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+        handle.abort();
+    }
+}
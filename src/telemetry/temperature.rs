@@ -0,0 +1,403 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Temperature of the hwmon sensors, for `io.edgehog.devicemanager.SensorsTemperature`.
+//!
+//! Every `temp*_input` of the `/sys/class/hwmon/hwmon*` chips is sent on its own path, named
+//! after the `name` of the chip and the `_label` of the sensor, the sensor file name when it has
+//! no label. The hwmon numbering changes across boots, the names don't. The sensors are listed
+//! again at each run, those failing the read (some report `EAGAIN` while busy) are skipped.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::SENSORS_TEMPERATURE_INTERFACE;
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig};
+use crate::telemetry::schedule::TelemetrySchedule;
+use crate::telemetry::CollectorRuns;
+
+pub const DEFAULT_TEMPERATURE_PERIOD: Duration = Duration::from_secs(60);
+pub const HWMON_DIRECTORY: &str = "/sys/class/hwmon";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SensorTemperature {
+    /// Name of the chip followed by the label of the sensor.
+    pub label: String,
+    pub temperature_celsius: f64,
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let content = content.trim();
+
+    (!content.is_empty()).then(|| content.to_owned())
+}
+
+/// The number after `prefix` in `file_name`, e.g. 2 for `hwmon2` or `temp2_input`.
+fn index(file_name: &str, prefix: &str, suffix: &str) -> Option<u32> {
+    file_name
+        .strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+/// Entries of `directory` named `{prefix}{index}{suffix}`, by index.
+fn indexed_entries(directory: &Path, prefix: &str, suffix: &str) -> std::io::Result<Vec<u32>> {
+    let mut indexes: Vec<u32> = std::fs::read_dir(directory)?
+        .filter_map(|entry| index(entry.ok()?.file_name().to_str()?, prefix, suffix))
+        .collect();
+    indexes.sort_unstable();
+
+    Ok(indexes)
+}
+
+/// Identifier of a sensor in the interface paths, lowercase with `_` for the other characters.
+fn sensor_id(chip: &str, label: &str) -> String {
+    format!("{chip}_{label}")
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Temperature of every readable sensor under `hwmon_directory`, by sensor id.
+pub fn read_sensors(
+    hwmon_directory: &Path,
+) -> Result<BTreeMap<String, SensorTemperature>, DeviceManagerError> {
+    let mut sensors = BTreeMap::new();
+
+    for chip_index in indexed_entries(hwmon_directory, "hwmon", "")? {
+        let chip_path = hwmon_directory.join(format!("hwmon{chip_index}"));
+        let chip =
+            read_trimmed(&chip_path.join("name")).unwrap_or_else(|| format!("hwmon{chip_index}"));
+
+        let inputs = match indexed_entries(&chip_path, "temp", "_input") {
+            Ok(inputs) => inputs,
+            Err(err) => {
+                debug!(
+                    "Unable to list the sensors of {}: {}",
+                    chip_path.display(),
+                    err
+                );
+                continue;
+            }
+        };
+
+        for input in inputs {
+            let input_path = chip_path.join(format!("temp{input}_input"));
+            let millidegrees = match std::fs::read_to_string(&input_path)
+                .map(|content| content.trim().parse::<i64>())
+            {
+                Ok(Ok(millidegrees)) => millidegrees,
+                Ok(Err(err)) => {
+                    debug!("Skipping {}, malformed: {}", input_path.display(), err);
+                    continue;
+                }
+                Err(err) => {
+                    debug!("Skipping {}, unreadable: {}", input_path.display(), err);
+                    continue;
+                }
+            };
+
+            let label = read_trimmed(&chip_path.join(format!("temp{input}_label")))
+                .unwrap_or_else(|| format!("temp{input}"));
+
+            // two chips of the same kind get the same name
+            let mut id = sensor_id(&chip, &label);
+            let mut duplicate = 1;
+            while sensors.contains_key(&id) {
+                duplicate += 1;
+                id = format!("{}_{duplicate}", sensor_id(&chip, &label));
+            }
+
+            sensors.insert(
+                id,
+                SensorTemperature {
+                    label: format!("{chip} {label}"),
+                    temperature_celsius: millidegrees as f64 / 1000.0,
+                },
+            );
+        }
+    }
+
+    Ok(sensors)
+}
+
+pub struct TemperatureTelemetry {
+    clock: Arc<dyn Clock>,
+    hwmon_directory: PathBuf,
+    period: Duration,
+    config: watch::Receiver<TelemetryConfig>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    random: Arc<dyn Random>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl TemperatureTelemetry {
+    pub fn new(clock: Arc<dyn Clock>, hwmon_directory: PathBuf, period: Duration) -> Self {
+        let (_, config) = watch::channel(TelemetryConfig::new());
+        let (_, shutdown) = watch::channel(false);
+
+        TemperatureTelemetry {
+            clock,
+            hwmon_directory,
+            period,
+            config,
+            schedule: None,
+            random: Arc::new(OsRandom),
+            shutdown,
+        }
+    }
+
+    /// Follow the period and the enabling set by the backend in `config`.
+    pub fn with_config(mut self, config: watch::Receiver<TelemetryConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(&self, publisher: &impl Publisher) {
+        let runs = CollectorRuns::new(
+            SENSORS_TEMPERATURE_INTERFACE,
+            "Temperature",
+            self.clock.clone(),
+            self.config.clone(),
+            self.period,
+        )
+        .with_schedule(self.schedule.clone())
+        .with_random(self.random.clone())
+        .with_shutdown(self.shutdown.clone());
+
+        runs.run(BTreeSet::new(), |mut known, enabled| async move {
+            if enabled {
+                self.send_temperatures(publisher, &mut known).await;
+            }
+            known
+        })
+        .await;
+    }
+
+    /// Send the temperature of every sensor, logging the sensors appeared or gone since the
+    /// last run, as listed in `known`.
+    async fn send_temperatures(&self, publisher: &impl Publisher, known: &mut BTreeSet<String>) {
+        let collected = DateTime::<Utc>::from(self.clock.now_wall());
+        let sensors = match read_sensors(&self.hwmon_directory) {
+            Ok(sensors) => sensors,
+            Err(err) => {
+                warn!("Unable to read the temperature sensors: {:?}", err);
+                return;
+            }
+        };
+
+        let current: BTreeSet<String> = sensors.keys().cloned().collect();
+        if current != *known {
+            info!(
+                "Temperature sensors: {}",
+                current.iter().cloned().collect::<Vec<_>>().join(", ")
+            );
+            *known = current;
+        }
+
+        for (id, sensor) in sensors {
+            if let Err(err) = publisher
+                .send_object_with_timestamp(
+                    SENSORS_TEMPERATURE_INTERFACE,
+                    &format!("/{id}"),
+                    sensor,
+                    collected,
+                )
+                .await
+            {
+                error!("Unable to send the temperature of {id}: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::SENSORS_TEMPERATURE_INTERFACE;
+    use crate::telemetry::temperature::{
+        read_sensors, sensor_id, SensorTemperature, TemperatureTelemetry,
+    };
+    use crate::test_utils::{settle, ManualClock};
+
+    /// Write `files` under `directory`, creating their parents.
+    fn fixture(directory: &Path, files: &[(&str, &str)]) {
+        for (path, content) in files {
+            let path = directory.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    fn sensor(label: &str, temperature_celsius: f64) -> SensorTemperature {
+        SensorTemperature {
+            label: label.to_owned(),
+            temperature_celsius,
+        }
+    }
+
+    #[test]
+    fn sensor_ids_sanitized() {
+        assert_eq!(
+            sensor_id("coretemp", "Package id 0"),
+            "coretemp_package_id_0"
+        );
+        assert_eq!(sensor_id("nvme", "Sensor/1+"), "nvme_sensor_1_");
+    }
+
+    #[test]
+    fn hwmon_sensors_read() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(
+            dir.path(),
+            &[
+                ("hwmon0/name", "coretemp\n"),
+                ("hwmon0/temp1_input", "42000\n"),
+                ("hwmon0/temp1_label", "Package id 0\n"),
+                // no label
+                ("hwmon0/temp2_input", "-5500\n"),
+                ("hwmon0/temp3_input", "busy\n"),
+                ("hwmon1/name", "nvme\n"),
+                ("hwmon1/temp1_input", "38850\n"),
+                ("hwmon1/temp1_label", "Composite\n"),
+                ("hwmon1/fan1_input", "1200\n"),
+                ("hwmon2/name", "nvme\n"),
+                ("hwmon2/temp1_input", "40000\n"),
+                ("hwmon2/temp1_label", "Composite\n"),
+                ("hwmon3/name", "acpitz\n"),
+            ],
+        );
+        // an input failing the read, like the EAGAIN of a busy sensor
+        std::fs::create_dir(dir.path().join("hwmon3/temp1_input")).unwrap();
+
+        let sensors = read_sensors(dir.path()).unwrap();
+        assert_eq!(
+            sensors.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    "coretemp_package_id_0".to_owned(),
+                    sensor("coretemp Package id 0", 42.0)
+                ),
+                ("coretemp_temp2".to_owned(), sensor("coretemp temp2", -5.5)),
+                ("nvme_composite".to_owned(), sensor("nvme Composite", 38.85)),
+                (
+                    "nvme_composite_2".to_owned(),
+                    sensor("nvme Composite", 40.0)
+                ),
+            ]
+        );
+
+        assert!(read_sensors(&dir.path().join("missing")).is_err());
+    }
+
+    #[tokio::test]
+    async fn sensors_followed_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(
+            dir.path(),
+            &[
+                ("hwmon0/name", "coretemp\n"),
+                ("hwmon0/temp1_input", "42000\n"),
+            ],
+        );
+
+        let clock = Arc::new(ManualClock::new());
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let mut publisher = MockPublisher::new();
+        publisher.expect_send_object_with_timestamp().returning(
+            move |interface, path, sensor: SensorTemperature, _| {
+                assert_eq!(interface, SENSORS_TEMPERATURE_INTERFACE);
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((path.to_owned(), sensor.temperature_celsius));
+                Ok(())
+            },
+        );
+
+        let telemetry = TemperatureTelemetry::new(
+            clock.clone(),
+            dir.path().to_owned(),
+            Duration::from_secs(60),
+        );
+        let handle = tokio::spawn(async move { telemetry.run(&publisher).await });
+
+        settle().await;
+        assert_eq!(
+            sent.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![("/coretemp_temp1".to_owned(), 42.0)]
+        );
+
+        // a sensor appeared, the other one is gone
+        fixture(
+            dir.path(),
+            &[("hwmon1/name", "nvme\n"), ("hwmon1/temp1_input", "38000\n")],
+        );
+        std::fs::remove_file(dir.path().join("hwmon0/temp1_input")).unwrap();
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(
+            sent.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![("/nvme_temp1".to_owned(), 38.0)]
+        );
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,520 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! WiFi access points in range, scanned through NetworkManager.
+//!
+//! Every run asks NetworkManager for a new scan of the wireless devices, then sends each access
+//! point found on `/ap` of `io.edgehog.devicemanager.WiFiScanResults`. The scans keep the radio
+//! busy, so the period can't go below [`MIN_WIFI_SCAN_PERIOD`]. Devices without a wireless
+//! interface or without NetworkManager publish nothing.
+
+#[cfg(feature = "dbus")]
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error};
+#[cfg(test)]
+use mockall::automock;
+use serde::Serialize;
+use tokio::sync::watch;
+#[cfg(feature = "dbus")]
+use tokio::sync::OnceCell;
+#[cfg(feature = "dbus")]
+use zbus::dbus_proxy;
+#[cfg(feature = "dbus")]
+use zbus::zvariant::{OwnedObjectPath, Value};
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::error::DeviceManagerError;
+use crate::interfaces::WIFI_SCAN_RESULTS_INTERFACE;
+use crate::telemetry::config::{OsRandom, Random, TelemetryConfig};
+use crate::telemetry::schedule::TelemetrySchedule;
+use crate::telemetry::CollectorRuns;
+#[cfg(not(feature = "dbus"))]
+use crate::wrapper::platform::no_system_bus;
+#[cfg(feature = "dbus")]
+use crate::wrapper::platform::system_bus;
+
+pub const DEFAULT_WIFI_SCAN_PERIOD: Duration = Duration::from_secs(10 * 60);
+/// Shortest period of the scans, the configured and the backend ones included.
+pub const MIN_WIFI_SCAN_PERIOD: Duration = Duration::from_secs(30);
+
+/// `NM_DEVICE_TYPE_WIFI`.
+#[cfg(feature = "dbus")]
+const DEVICE_TYPE_WIFI: u32 = 2;
+/// Time NetworkManager is given to complete a scan.
+#[cfg(feature = "dbus")]
+const SCAN_WAIT: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    fn get_devices(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager.Device",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait Device {
+    #[dbus_proxy(property)]
+    fn device_type(&self) -> zbus::Result<u32>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager.Device.Wireless",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait Wireless {
+    /// Scan for the access points, refused when the last scan is too recent.
+    fn request_scan(&self, options: HashMap<&str, Value<'_>>) -> zbus::Result<()>;
+
+    /// The access points of the last scan, the hidden ones included.
+    fn get_all_access_points(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+
+    /// Path of the access point the device is connected to, `/` when it isn't.
+    #[dbus_proxy(property)]
+    fn active_access_point(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager.AccessPoint",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait AccessPoint {
+    #[dbus_proxy(property)]
+    fn ssid(&self) -> zbus::Result<Vec<u8>>;
+
+    /// The BSSID of the access point.
+    #[dbus_proxy(property)]
+    fn hw_address(&self) -> zbus::Result<String>;
+
+    /// Frequency in MHz.
+    #[dbus_proxy(property)]
+    fn frequency(&self) -> zbus::Result<u32>;
+
+    /// Signal quality percentage.
+    #[dbus_proxy(property)]
+    fn strength(&self) -> zbus::Result<u8>;
+}
+
+/// An access point found by a scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScannedAccessPoint {
+    pub essid: String,
+    pub bssid: String,
+    /// Frequency in MHz.
+    pub frequency: u32,
+    /// Signal strength in dBm.
+    pub rssi: i32,
+    /// Whether the device is connected to it.
+    pub connected: bool,
+}
+
+/// Access to the wireless devices, NetworkManager outside of the tests.
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait WifiSource: Send + Sync {
+    /// The access points in range, scanned again when `rescan` is set, `None` when there is no
+    /// wireless device.
+    async fn access_points(
+        &self,
+        rescan: bool,
+    ) -> Result<Option<Vec<ScannedAccessPoint>>, DeviceManagerError>;
+}
+
+/// The wireless devices of NetworkManager, connecting to the system bus on the first scan. They
+/// are never reachable when built without the `dbus` feature.
+#[derive(Default)]
+pub struct NetworkManagerWifi {
+    #[cfg(feature = "dbus")]
+    connection: OnceCell<zbus::Connection>,
+}
+
+impl NetworkManagerWifi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl WifiSource for NetworkManagerWifi {
+    #[cfg(not(feature = "dbus"))]
+    async fn access_points(
+        &self,
+        _rescan: bool,
+    ) -> Result<Option<Vec<ScannedAccessPoint>>, DeviceManagerError> {
+        Err(no_system_bus())
+    }
+
+    #[cfg(feature = "dbus")]
+    async fn access_points(
+        &self,
+        rescan: bool,
+    ) -> Result<Option<Vec<ScannedAccessPoint>>, DeviceManagerError> {
+        let connection = self.connection.get_or_try_init(system_bus).await?;
+
+        let mut wireless = Vec::new();
+        for device in NetworkManagerProxy::new(connection)
+            .await?
+            .get_devices()
+            .await?
+        {
+            let device_type = DeviceProxy::builder(connection)
+                .path(device.as_str())?
+                .build()
+                .await?
+                .device_type()
+                .await?;
+            if device_type == DEVICE_TYPE_WIFI {
+                wireless.push(
+                    WirelessProxy::builder(connection)
+                        .path(device.as_str().to_owned())?
+                        .build()
+                        .await?,
+                );
+            }
+        }
+        if wireless.is_empty() {
+            return Ok(None);
+        }
+
+        if rescan {
+            for device in &wireless {
+                // the results of the last scan are still reported
+                if let Err(err) = device.request_scan(HashMap::new()).await {
+                    debug!("WiFi scan not started: {err}");
+                }
+            }
+            tokio::time::sleep(SCAN_WAIT).await;
+        }
+
+        let mut access_points = Vec::new();
+        for device in &wireless {
+            let active = device.active_access_point().await?;
+            for path in device.get_all_access_points().await? {
+                let access_point = AccessPointProxy::builder(connection)
+                    .path(path.as_str())?
+                    .build()
+                    .await?;
+                access_points.push(ScannedAccessPoint {
+                    essid: String::from_utf8_lossy(&access_point.ssid().await?).into_owned(),
+                    bssid: access_point.hw_address().await?.to_lowercase(),
+                    frequency: access_point.frequency().await?,
+                    rssi: signal_dbm(access_point.strength().await?),
+                    connected: path == active,
+                });
+            }
+        }
+
+        Ok(Some(access_points))
+    }
+}
+
+/// The dBm NetworkManager derived its signal quality percentage from.
+#[cfg(any(test, feature = "dbus"))]
+fn signal_dbm(strength: u8) -> i32 {
+    i32::from(strength.min(100)) / 2 - 100
+}
+
+/// Channel of the frequency in MHz, 0 when it isn't a WiFi one.
+fn channel(frequency: u32) -> i32 {
+    let channel = match frequency {
+        2484 => 14,
+        2412..=2472 => (frequency - 2407) / 5,
+        5160..=5885 => (frequency - 5000) / 5,
+        5955..=7115 => (frequency - 5950) / 5,
+        _ => 0,
+    };
+
+    channel as i32
+}
+
+/// Object published on `io.edgehog.devicemanager.WiFiScanResults`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WifiScanResult {
+    pub channel: i32,
+    pub connected: bool,
+    pub essid: String,
+    pub mac_address: String,
+    pub rssi: i32,
+}
+
+impl From<&ScannedAccessPoint> for WifiScanResult {
+    fn from(access_point: &ScannedAccessPoint) -> Self {
+        WifiScanResult {
+            channel: channel(access_point.frequency),
+            connected: access_point.connected,
+            essid: access_point.essid.clone(),
+            mac_address: access_point.bssid.clone(),
+            rssi: access_point.rssi,
+        }
+    }
+}
+
+pub struct WifiScanTelemetry {
+    clock: Arc<dyn Clock>,
+    source: Box<dyn WifiSource>,
+    period: Duration,
+    config: watch::Receiver<TelemetryConfig>,
+    schedule: Option<Arc<TelemetrySchedule>>,
+    random: Arc<dyn Random>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl WifiScanTelemetry {
+    pub fn new(clock: Arc<dyn Clock>, source: Box<dyn WifiSource>, period: Duration) -> Self {
+        let (_, config) = watch::channel(TelemetryConfig::new());
+        let (_, shutdown) = watch::channel(false);
+
+        WifiScanTelemetry {
+            clock,
+            source,
+            period: period.max(MIN_WIFI_SCAN_PERIOD),
+            config,
+            schedule: None,
+            random: Arc::new(OsRandom),
+            shutdown,
+        }
+    }
+
+    /// Follow the period and the enabling set by the backend in `config`.
+    pub fn with_config(mut self, config: watch::Receiver<TelemetryConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep the next run in `schedule` up to date.
+    pub fn with_schedule(mut self, schedule: Arc<TelemetrySchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Draw the jitter of the runs from `random`.
+    pub fn with_random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Stop between two runs once `shutdown` is set.
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Runs of the scan, at most every [`MIN_WIFI_SCAN_PERIOD`].
+    fn runs(&self) -> CollectorRuns {
+        CollectorRuns::new(
+            WIFI_SCAN_RESULTS_INTERFACE,
+            "WiFi scan",
+            self.clock.clone(),
+            self.config.clone(),
+            self.period,
+        )
+        .with_min_period(MIN_WIFI_SCAN_PERIOD)
+        .with_schedule(self.schedule.clone())
+        .with_random(self.random.clone())
+        .with_shutdown(self.shutdown.clone())
+    }
+
+    pub async fn run(self, publisher: &impl Publisher) {
+        let telemetry = &self;
+        telemetry
+            .runs()
+            .run((), |(), enabled| async move {
+                if enabled {
+                    telemetry.scan(publisher).await;
+                }
+            })
+            .await;
+    }
+
+    /// Scan and send the access points in range.
+    async fn scan(&self, publisher: &impl Publisher) {
+        let access_points = match self.source.access_points(true).await {
+            Ok(Some(access_points)) => access_points,
+            Ok(None) => {
+                debug!("No wireless device, the WiFi scan is skipped");
+                return;
+            }
+            Err(err) => {
+                debug!("WiFi scan skipped, NetworkManager unavailable: {:?}", err);
+                return;
+            }
+        };
+
+        for access_point in &access_points {
+            if let Err(err) = publisher
+                .send_object(
+                    WIFI_SCAN_RESULTS_INTERFACE,
+                    "/ap",
+                    WifiScanResult::from(access_point),
+                )
+                .await
+            {
+                error!("Unable to send the WiFi scan result: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use mockall::predicate::eq;
+
+    use crate::clock::SystemClock;
+    use crate::data::MockPublisher;
+    use crate::error::DeviceManagerError;
+    use crate::interfaces::WIFI_SCAN_RESULTS_INTERFACE;
+    use crate::telemetry::config::{TelemetryConfig, TelemetryInterfaceConfig};
+    use crate::telemetry::wifi_scan::{
+        channel, signal_dbm, MockWifiSource, ScannedAccessPoint, WifiScanResult, WifiScanTelemetry,
+        MIN_WIFI_SCAN_PERIOD,
+    };
+
+    fn access_point(bssid: &str, frequency: u32, connected: bool) -> ScannedAccessPoint {
+        ScannedAccessPoint {
+            essid: "gateway".to_owned(),
+            bssid: bssid.to_owned(),
+            frequency,
+            rssi: -65,
+            connected,
+        }
+    }
+
+    #[test]
+    fn access_points_mapped() {
+        assert_eq!(channel(2412), 1);
+        assert_eq!(channel(2484), 14);
+        assert_eq!(channel(5180), 36);
+        assert_eq!(channel(5975), 5);
+        assert_eq!(channel(900), 0);
+        assert_eq!(signal_dbm(0), -100);
+        assert_eq!(signal_dbm(70), -65);
+        assert_eq!(signal_dbm(255), -50);
+
+        assert_eq!(
+            WifiScanResult::from(&access_point("01:23:45:67:89:ab", 2437, true)),
+            WifiScanResult {
+                channel: 6,
+                connected: true,
+                essid: "gateway".to_owned(),
+                mac_address: "01:23:45:67:89:ab".to_owned(),
+                rssi: -65,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn one_entry_per_access_point() {
+        let mut source = MockWifiSource::new();
+        source.expect_access_points().with(eq(true)).returning(|_| {
+            Ok(Some(vec![
+                access_point("01:23:45:67:89:ab", 2437, true),
+                access_point("01:23:45:67:89:ac", 5180, false),
+            ]))
+        });
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let mut publisher = MockPublisher::new();
+        publisher
+            .expect_send_object()
+            .withf(|interface, path, _: &WifiScanResult| {
+                interface == WIFI_SCAN_RESULTS_INTERFACE && path == "/ap"
+            })
+            .returning(move |_, _, result: WifiScanResult| {
+                recorded.lock().unwrap().push(result.mac_address);
+                Ok(())
+            });
+
+        WifiScanTelemetry::new(
+            Arc::new(SystemClock),
+            Box::new(source),
+            Duration::from_secs(60),
+        )
+        .scan(&publisher)
+        .await;
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["01:23:45:67:89:ab", "01:23:45:67:89:ac"]
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_skipped_without_wireless() {
+        let mut publisher = MockPublisher::new();
+        publisher.expect_send_object::<WifiScanResult>().never();
+
+        for result in [
+            Ok(None),
+            Err(DeviceManagerError::UpdateError(
+                "NetworkManager not running".to_owned(),
+            )),
+        ] {
+            let mut source = MockWifiSource::new();
+            source.expect_access_points().return_once(|_| result);
+            WifiScanTelemetry::new(
+                Arc::new(SystemClock),
+                Box::new(source),
+                Duration::from_secs(60),
+            )
+            .scan(&publisher)
+            .await;
+        }
+    }
+
+    #[test]
+    fn period_floored() {
+        let telemetry = WifiScanTelemetry::new(
+            Arc::new(SystemClock),
+            Box::new(MockWifiSource::new()),
+            Duration::from_secs(1),
+        );
+        assert_eq!(
+            telemetry.runs().period(&TelemetryConfig::new()),
+            MIN_WIFI_SCAN_PERIOD
+        );
+
+        let config = TelemetryConfig::from([(
+            WIFI_SCAN_RESULTS_INTERFACE.to_owned(),
+            TelemetryInterfaceConfig {
+                enabled: None,
+                period_secs: Some(5),
+                ..Default::default()
+            },
+        )]);
+        assert_eq!(telemetry.runs().period(&config), MIN_WIFI_SCAN_PERIOD);
+    }
+}
@@ -0,0 +1,364 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Scripted Astarte session, to test the runtime from the messages it receives to the
+//! messages it publishes without a broker.
+//!
+//! The test declares the clientbound messages, with the delay before each of them, and the
+//! failures to inject; every send, object send and unset is captured with the time it was
+//! attempted at.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::{Aggregation, AstarteError, Clientbound};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::properties::ReplayReport;
+use crate::data::{Publisher, Session, Subscriber};
+use crate::dispatch::{Dispatch, Dispatcher};
+use crate::interfaces::{
+    COMMANDS_INTERFACE, OTA_REQUEST_INTERFACE, OTA_RESPONSE_INTERFACE, TELEMETRY_CONFIG_INTERFACE,
+};
+use crate::ota::messages::{OtaRequest, OtaResponse};
+use crate::ota::worker::OtaWorker;
+use crate::test_utils::ManualClock;
+
+/// Payload of a captured publish.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Outbound {
+    Individual(AstarteType),
+    Object(serde_json::Value),
+    Unset,
+}
+
+/// A publish attempted by the code under test.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Sent {
+    /// Time elapsed since the session was built.
+    pub at: Duration,
+    pub interface: String,
+    pub path: String,
+    pub data: Outbound,
+    /// Whether the publish succeeded, false when a failure was injected.
+    pub delivered: bool,
+}
+
+enum Step {
+    Receive(Clientbound),
+    Error(AstarteError),
+}
+
+pub(crate) struct SessionBuilder {
+    clock: Arc<dyn Clock>,
+    steps: VecDeque<(Duration, Step)>,
+    send_failures: HashMap<usize, AstarteError>,
+    failing_interfaces: HashSet<String>,
+}
+
+impl SessionBuilder {
+    /// Receive `clientbound` right after the previous step.
+    pub(crate) fn receive(self, clientbound: Clientbound) -> Self {
+        self.receive_after(Duration::ZERO, clientbound)
+    }
+
+    /// Receive `clientbound` once `delay` elapsed from the previous step.
+    pub(crate) fn receive_after(mut self, delay: Duration, clientbound: Clientbound) -> Self {
+        self.steps.push_back((delay, Step::Receive(clientbound)));
+        self
+    }
+
+    /// Fail the poll with `err` once `delay` elapsed from the previous step.
+    pub(crate) fn poll_error_after(mut self, delay: Duration, err: AstarteError) -> Self {
+        self.steps.push_back((delay, Step::Error(err)));
+        self
+    }
+
+    /// Fail the publish attempted as `index`th, counting from zero, with `err`.
+    pub(crate) fn fail_send(mut self, index: usize, err: AstarteError) -> Self {
+        self.send_failures.insert(index, err);
+        self
+    }
+
+    /// Fail every publish on `interface`, like a broker rejecting it.
+    pub(crate) fn fail_sends_to(mut self, interface: &str) -> Self {
+        self.failing_interfaces.insert(interface.to_owned());
+        self
+    }
+
+    pub(crate) fn build(self) -> ScriptedSession {
+        ScriptedSession {
+            shared: Arc::new(Shared {
+                start: self.clock.now_monotonic(),
+                clock: self.clock,
+                steps: Mutex::new(self.steps),
+                send_failures: Mutex::new(self.send_failures),
+                failing_interfaces: self.failing_interfaces,
+                sent: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+}
+
+struct Shared {
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    steps: Mutex<VecDeque<(Duration, Step)>>,
+    send_failures: Mutex<HashMap<usize, AstarteError>>,
+    failing_interfaces: HashSet<String>,
+    sent: Mutex<Vec<Sent>>,
+}
+
+/// Stand-in for the Astarte session, clones share the script and the captured publishes.
+#[derive(Clone)]
+pub(crate) struct ScriptedSession {
+    shared: Arc<Shared>,
+}
+
+impl ScriptedSession {
+    pub(crate) fn builder(clock: Arc<dyn Clock>) -> SessionBuilder {
+        SessionBuilder {
+            clock,
+            steps: VecDeque::new(),
+            send_failures: HashMap::new(),
+            failing_interfaces: HashSet::new(),
+        }
+    }
+
+    /// Whether every scripted step was polled.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.shared.steps.lock().unwrap().is_empty()
+    }
+
+    /// The publishes attempted so far, in order.
+    pub(crate) fn sent(&self) -> Vec<Sent> {
+        self.shared.sent.lock().unwrap().clone()
+    }
+
+    /// The publishes attempted so far on `interface`.
+    pub(crate) fn sent_to(&self, interface: &str) -> Vec<Sent> {
+        self.sent()
+            .into_iter()
+            .filter(|sent| sent.interface == interface)
+            .collect()
+    }
+
+    /// The OTA responses published so far, in order.
+    pub(crate) fn ota_responses(&self) -> Vec<OtaResponse> {
+        self.sent_to(OTA_RESPONSE_INTERFACE)
+            .into_iter()
+            .filter_map(|sent| match sent.data {
+                Outbound::Object(data) => {
+                    Some(serde_json::from_value(data).expect("the response is an OtaResponse"))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Poll the whole script, dispatching each message like the device manager does.
+    pub(crate) async fn dispatch_all(
+        &mut self,
+        dispatcher: &Dispatcher,
+    ) -> Vec<Result<Dispatch, String>> {
+        let mut dispatched = Vec::new();
+        while !self.is_exhausted() {
+            let result = match self.poll().await {
+                Ok(clientbound) => Ok(dispatcher.dispatch(&clientbound).await),
+                Err(err) => Err(format!("{:?}", err)),
+            };
+            dispatched.push(result);
+        }
+
+        dispatched
+    }
+
+    fn record(&self, interface: &str, path: &str, data: Outbound) -> Result<(), AstarteError> {
+        let mut sent = self.shared.sent.lock().unwrap();
+        let failure = self
+            .shared
+            .send_failures
+            .lock()
+            .unwrap()
+            .remove(&sent.len())
+            .or_else(|| {
+                self.shared
+                    .failing_interfaces
+                    .contains(interface)
+                    .then(|| AstarteError::SendError(format!("{interface} rejected")))
+            });
+        sent.push(Sent {
+            at: self.shared.clock.now_monotonic() - self.shared.start,
+            interface: interface.to_owned(),
+            path: path.to_owned(),
+            data,
+            delivered: failure.is_none(),
+        });
+
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+#[async_trait]
+impl Subscriber for ScriptedSession {
+    /// The next scripted step, never completing once the script is exhausted.
+    async fn poll(&mut self) -> Result<Clientbound, AstarteError> {
+        let next = self.shared.steps.lock().unwrap().pop_front();
+        let (delay, step) = match next {
+            Some(next) => next,
+            None => std::future::pending().await,
+        };
+
+        self.shared.clock.sleep(delay).await;
+
+        match step {
+            Step::Receive(clientbound) => Ok(clientbound),
+            Step::Error(err) => Err(err),
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for ScriptedSession {
+    async fn send_object<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+    ) -> Result<(), AstarteError>
+    where
+        T: serde::Serialize + Send + 'static,
+    {
+        let data = serde_json::to_value(data).expect("the object is serializable");
+        self.record(interface_name, interface_path, Outbound::Object(data))
+    }
+
+    async fn send(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+    ) -> Result<(), AstarteError> {
+        self.record(interface_name, interface_path, Outbound::Individual(data))
+    }
+
+    async fn unset(&self, interface_name: &str, interface_path: &str) -> Result<(), AstarteError> {
+        self.record(interface_name, interface_path, Outbound::Unset)
+    }
+
+    async fn send_object_with_timestamp<T>(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: T,
+        _timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError>
+    where
+        T: serde::Serialize + Send + 'static,
+    {
+        self.send_object(interface_name, interface_path, data).await
+    }
+
+    async fn send_with_timestamp(
+        &self,
+        interface_name: &str,
+        interface_path: &str,
+        data: AstarteType,
+        _timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteError> {
+        self.send(interface_name, interface_path, data).await
+    }
+}
+
+/// The script goes on across the reconnections, no property is cached to be replayed.
+#[async_trait]
+impl Session for ScriptedSession {
+    type Subscriber = ScriptedSession;
+
+    fn subscriber(&self) -> ScriptedSession {
+        self.clone()
+    }
+
+    async fn reconnect(&self) -> Result<ScriptedSession, AstarteError> {
+        Ok(self.clone())
+    }
+
+    async fn replay_properties(&self) -> ReplayReport {
+        ReplayReport::default()
+    }
+}
+
+pub(crate) fn clientbound(interface: &str, path: &str, data: Aggregation) -> Clientbound {
+    Clientbound {
+        interface: interface.to_owned(),
+        path: path.to_owned(),
+        data,
+    }
+}
+
+pub(crate) fn ota_request_message(request: OtaRequest) -> Clientbound {
+    clientbound(
+        OTA_REQUEST_INTERFACE,
+        "/request",
+        Aggregation::Object(request.into()),
+    )
+}
+
+/// Server owned telemetry config property, `endpoint` is `enable` or `periodSeconds`.
+pub(crate) fn telemetry_config_message(
+    interface_name: &str,
+    endpoint: &str,
+    value: AstarteType,
+) -> Clientbound {
+    clientbound(
+        TELEMETRY_CONFIG_INTERFACE,
+        &format!("/request/{interface_name}/{endpoint}"),
+        Aggregation::Individual(value),
+    )
+}
+
+pub(crate) fn command_message(command: &str) -> Clientbound {
+    clientbound(
+        COMMANDS_INTERFACE,
+        "/request",
+        Aggregation::Individual(AstarteType::String(command.to_owned())),
+    )
+}
+
+/// OTA worker leaving the requests in the returned queue, `capacity` long, for the dispatcher.
+pub(crate) fn ota_worker(
+    capacity: usize,
+) -> (Arc<OtaWorker>, Receiver<HashMap<String, AstarteType>>) {
+    let queue = Arc::new(Mutex::new(None));
+    let handed = queue.clone();
+    let worker = OtaWorker::start(Arc::new(ManualClock::new()), capacity, move |requests| {
+        *handed.lock().unwrap() = Some(requests);
+        tokio::spawn(std::future::pending())
+    });
+    let requests = queue.lock().unwrap().take().expect("the worker is started");
+
+    (Arc::new(worker), requests)
+}
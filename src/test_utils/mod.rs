@@ -0,0 +1,310 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Helpers shared by the unit tests of the crate.
+
+use std::ffi::OsString;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use astarte_sdk::types::AstarteType;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::MockPublisher;
+use crate::error::DeviceManagerError;
+use crate::repository::StateRepository;
+
+pub(crate) mod harness;
+
+/// Clock that only moves forward when told to, waking up every sleeper whose deadline has
+/// been reached.
+pub(crate) struct ManualClock {
+    wall_start: SystemTime,
+    monotonic_start: Instant,
+    elapsed: Mutex<Duration>,
+    notify: Notify,
+}
+
+impl ManualClock {
+    pub(crate) fn new() -> Self {
+        ManualClock {
+            wall_start: SystemTime::UNIX_EPOCH + Duration::from_secs(1_650_000_000),
+            monotonic_start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub(crate) fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+        self.notify.notify_waiters();
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl Clock for ManualClock {
+    fn now_wall(&self) -> SystemTime {
+        self.wall_start + self.elapsed()
+    }
+
+    fn now_monotonic(&self) -> Instant {
+        self.monotonic_start + self.elapsed()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        loop {
+            let notified = self.notify.notified();
+            if self.now_monotonic() >= deadline {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Let every spawned task run until it blocks again.
+pub(crate) async fn settle() {
+    for _ in 0..50 {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Payload of a publish captured by [`recording_publisher`], `T` being the type of the objects.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Recorded<T> {
+    Individual(AstarteType),
+    Object(T),
+    Unset,
+}
+
+/// Publishes captured by [`recording_publisher`], in order, with their interface and path.
+pub(crate) struct Recording<T>(Arc<Mutex<Vec<(String, String, Recorded<T>)>>>);
+
+impl<T> Clone for Recording<T> {
+    fn clone(&self) -> Self {
+        Recording(self.0.clone())
+    }
+}
+
+impl<T: Clone> Recording<T> {
+    fn push(&self, interface: &str, path: &str, data: Recorded<T>) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((interface.to_owned(), path.to_owned(), data));
+    }
+
+    /// Forget the publishes captured so far.
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    /// Every publish, on any interface.
+    pub(crate) fn all(&self) -> Vec<(String, String, Recorded<T>)> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Publishes on `interface`, with their path.
+    pub(crate) fn on(&self, interface: &str) -> Vec<(String, Recorded<T>)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _, _)| name == interface)
+            .map(|(_, path, data)| (path.clone(), data.clone()))
+            .collect()
+    }
+
+    /// Objects sent on `interface`.
+    pub(crate) fn objects(&self, interface: &str) -> Vec<T> {
+        self.on(interface)
+            .into_iter()
+            .filter_map(|(_, data)| match data {
+                Recorded::Object(object) => Some(object),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Values sent on `interface`, with their path.
+    pub(crate) fn values(&self, interface: &str) -> Vec<(String, AstarteType)> {
+        self.on(interface)
+            .into_iter()
+            .filter_map(|(path, data)| match data {
+                Recorded::Individual(value) => Some((path, value)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Mock publisher accepting every publish and capturing it in the returned recording. The
+/// objects, sent with or without a timestamp, are captured as `T`.
+pub(crate) fn recording_publisher<T>() -> (MockPublisher, Recording<T>)
+where
+    T: Serialize + Clone + Send + 'static,
+{
+    let recording = Recording(Arc::new(Mutex::new(Vec::new())));
+    let mut publisher = MockPublisher::new();
+
+    let sent = recording.clone();
+    publisher
+        .expect_send_object()
+        .returning(move |interface, path, data: T| {
+            sent.push(interface, path, Recorded::Object(data));
+            Ok(())
+        });
+    let sent = recording.clone();
+    publisher
+        .expect_send_object_with_timestamp()
+        .returning(move |interface, path, data: T, _| {
+            sent.push(interface, path, Recorded::Object(data));
+            Ok(())
+        });
+    let sent = recording.clone();
+    publisher
+        .expect_send()
+        .returning(move |interface, path, data| {
+            sent.push(interface, path, Recorded::Individual(data));
+            Ok(())
+        });
+    let sent = recording.clone();
+    publisher
+        .expect_send_with_timestamp()
+        .returning(move |interface, path, data, _| {
+            sent.push(interface, path, Recorded::Individual(data));
+            Ok(())
+        });
+    let sent = recording.clone();
+    publisher.expect_unset().returning(move |interface, path| {
+        sent.push(interface, path, Recorded::Unset);
+        Ok(())
+    });
+
+    (publisher, recording)
+}
+
+/// State repository keeping the value in memory.
+pub(crate) struct MemoryStateRepository<T> {
+    value: Mutex<Option<T>>,
+}
+
+impl<T: Clone> MemoryStateRepository<T> {
+    pub(crate) fn new() -> Self {
+        MemoryStateRepository {
+            value: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn value(&self) -> Option<T> {
+        self.value.lock().unwrap().clone()
+    }
+}
+
+impl<T: Clone + Send + Sync> StateRepository<T> for MemoryStateRepository<T> {
+    fn write(&self, value: &T) -> Result<(), DeviceManagerError> {
+        *self.value.lock().unwrap() = Some(value.clone());
+        Ok(())
+    }
+
+    fn read(&self) -> Result<T, DeviceManagerError> {
+        self.value().ok_or_else(|| {
+            DeviceManagerError::IOError(std::io::Error::from(std::io::ErrorKind::NotFound))
+        })
+    }
+
+    fn exists(&self) -> bool {
+        self.value.lock().unwrap().is_some()
+    }
+
+    fn clear(&self) -> Result<(), DeviceManagerError> {
+        *self.value.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+/// Lets the tests keep inspecting a repository handed over to the code under test.
+impl<T: Send + Sync, R: StateRepository<T>> StateRepository<T> for std::sync::Arc<R> {
+    fn write(&self, value: &T) -> Result<(), DeviceManagerError> {
+        self.as_ref().write(value)
+    }
+
+    fn read(&self) -> Result<T, DeviceManagerError> {
+        self.as_ref().read()
+    }
+
+    fn exists(&self) -> bool {
+        self.as_ref().exists()
+    }
+
+    fn clear(&self) -> Result<(), DeviceManagerError> {
+        self.as_ref().clear()
+    }
+}
+
+/// Held by the tests reading or changing the environment of the process.
+static ENV_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Environment variables set until dropped, then restored. The tests holding one run one at a
+/// time, also when they only read the environment.
+pub(crate) struct ScopedEnv {
+    previous: Vec<(String, Option<OsString>)>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl ScopedEnv {
+    pub(crate) fn set(vars: &[(&str, &str)]) -> Self {
+        let lock = ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = vars
+            .iter()
+            .map(|(name, value)| {
+                let previous = std::env::var_os(name);
+                std::env::set_var(name, value);
+                (name.to_string(), previous)
+            })
+            .collect();
+
+        ScopedEnv {
+            previous,
+            _lock: lock,
+        }
+    }
+}
+
+impl Drop for ScopedEnv {
+    fn drop(&mut self) {
+        for (name, previous) in self.previous.drain(..).rev() {
+            match previous {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+}
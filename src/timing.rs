@@ -0,0 +1,249 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Timing of the runtime startup and shutdown phases.
+
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use astarte_sdk::types::AstarteType;
+use astarte_sdk::AstarteError;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::data::Publisher;
+use crate::interfaces::DIAGNOSTICS_INTERFACE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Phase {
+    pub name: &'static str,
+    pub duration: Duration,
+}
+
+/// Collects the duration of named phases, measured with the shared [`Clock`].
+pub(crate) struct TimingReport {
+    name: &'static str,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    phases: Mutex<Vec<Phase>>,
+}
+
+impl TimingReport {
+    pub fn new(name: &'static str, clock: Arc<dyn Clock>) -> Self {
+        TimingReport {
+            name,
+            started: clock.now_monotonic(),
+            clock,
+            phases: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Create a report that started `before` the current instant.
+    pub fn since(name: &'static str, clock: Arc<dyn Clock>, before: Duration) -> Self {
+        let now = clock.now_monotonic();
+        TimingReport {
+            name,
+            started: now.checked_sub(before).unwrap_or(now),
+            clock,
+            phases: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Await `future`, recording how long it took under the phase `name`.
+    pub async fn time<F: Future>(&self, name: &'static str, future: F) -> F::Output {
+        let start = self.clock.now_monotonic();
+        let output = future.await;
+        self.record(name, self.clock.now_monotonic() - start);
+
+        output
+    }
+
+    pub fn record(&self, name: &'static str, duration: Duration) {
+        self.phases.lock().unwrap().push(Phase { name, duration });
+    }
+
+    pub fn phases(&self) -> Vec<Phase> {
+        self.phases.lock().unwrap().clone()
+    }
+
+    /// Time elapsed since the report was created.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now_monotonic() - self.started
+    }
+
+    /// Format the phases as a table, closed by the total elapsed time.
+    pub fn table(&self) -> String {
+        let phases = self.phases();
+        let width = phases
+            .iter()
+            .map(|phase| phase.name.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+
+        let mut table = format!("{} timing:", self.name);
+        for phase in phases {
+            write!(
+                table,
+                "\n  {:width$}  {:>8} ms",
+                phase.name,
+                phase.duration.as_millis()
+            )
+            .ok();
+        }
+        write!(
+            table,
+            "\n  {:width$}  {:>8} ms",
+            "total",
+            self.elapsed().as_millis()
+        )
+        .ok();
+
+        table
+    }
+
+    /// Publish every phase, in milliseconds, on the diagnostics interface.
+    pub async fn publish(&self, publisher: &impl Publisher) -> Result<(), AstarteError> {
+        for phase in self.phases() {
+            publisher
+                .send(
+                    DIAGNOSTICS_INTERFACE,
+                    &format!("/{}/{}", self.name, phase.name),
+                    AstarteType::LongInteger(phase.duration.as_millis() as i64),
+                )
+                .await?;
+        }
+
+        publisher
+            .send(
+                DIAGNOSTICS_INTERFACE,
+                &format!("/{}/total", self.name),
+                AstarteType::LongInteger(self.elapsed().as_millis() as i64),
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use astarte_sdk::types::AstarteType;
+
+    use crate::data::MockPublisher;
+    use crate::interfaces::DIAGNOSTICS_INTERFACE;
+    use crate::test_utils::ManualClock;
+    use crate::timing::{Phase, TimingReport};
+
+    #[tokio::test]
+    async fn phases_are_timed_with_the_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let report = TimingReport::new("startup", clock.clone());
+
+        let value = report
+            .time("device_id", async {
+                clock.advance(Duration::from_millis(120));
+                42
+            })
+            .await;
+        report
+            .time("credentials", async {
+                clock.advance(Duration::from_millis(30));
+            })
+            .await;
+        clock.advance(Duration::from_millis(5));
+        report.record("pending_ota_response", Duration::from_millis(800));
+
+        assert_eq!(value, 42);
+        assert_eq!(
+            report.phases(),
+            vec![
+                Phase {
+                    name: "device_id",
+                    duration: Duration::from_millis(120)
+                },
+                Phase {
+                    name: "credentials",
+                    duration: Duration::from_millis(30)
+                },
+                Phase {
+                    name: "pending_ota_response",
+                    duration: Duration::from_millis(800)
+                },
+            ]
+        );
+        assert_eq!(report.elapsed(), Duration::from_millis(155));
+        assert_eq!(
+            report.table(),
+            "startup timing:\n\
+             \x20 device_id                  120 ms\n\
+             \x20 credentials                 30 ms\n\
+             \x20 pending_ota_response       800 ms\n\
+             \x20 total                      155 ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn elapsed_includes_the_time_before_the_report() {
+        let clock = Arc::new(ManualClock::new());
+        let report = TimingReport::since("startup", clock.clone(), Duration::from_millis(300));
+        clock.advance(Duration::from_millis(20));
+
+        assert_eq!(report.elapsed(), Duration::from_millis(320));
+    }
+
+    #[tokio::test]
+    async fn report_published_on_diagnostics() {
+        let clock = Arc::new(ManualClock::new());
+        let report = TimingReport::new("startup", clock.clone());
+        report
+            .time("sdk_connect", async {
+                clock.advance(Duration::from_millis(250));
+            })
+            .await;
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut publisher = MockPublisher::new();
+        let sent_cloned = sent.clone();
+        publisher
+            .expect_send()
+            .withf(|interface, _, _| interface == DIAGNOSTICS_INTERFACE)
+            .returning(move |_, path, data| {
+                sent_cloned.lock().unwrap().push((path.to_owned(), data));
+                Ok(())
+            });
+
+        report.publish(&publisher).await.unwrap();
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                (
+                    "/startup/sdk_connect".to_owned(),
+                    AstarteType::LongInteger(250)
+                ),
+                ("/startup/total".to_owned(), AstarteType::LongInteger(250)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Heartbeat of the service watchdog, sent while the main loops make progress.
+//!
+//! A loop waiting for its next message or period is healthy; one stuck in the same step since
+//! the last heartbeat is not, and the heartbeat is withheld until it moves on so that a wedged
+//! runtime is restarted by the init system.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+
+/// Loop dispatching the messages polled from Astarte.
+pub(crate) const MAIN_LOOP: &str = "main loop";
+/// Loop sending `io.edgehog.devicemanager.SystemStatus`.
+pub(crate) const TELEMETRY_LOOP: &str = "telemetry loop";
+
+/// Step each loop is running, with the time it started; the waiting loops are left out.
+pub(crate) struct Liveness {
+    clock: Arc<dyn Clock>,
+    working: Mutex<HashMap<&'static str, Instant>>,
+}
+
+impl Liveness {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Liveness {
+            clock,
+            working: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `name` started a step.
+    pub fn working(&self, name: &'static str) {
+        let now = self.clock.now_monotonic();
+        self.working.lock().unwrap().insert(name, now);
+    }
+
+    /// `name` completed its step and waits for the next one.
+    pub fn waiting(&self, name: &'static str) {
+        self.working.lock().unwrap().remove(name);
+    }
+
+    /// Loops running the same step since before `since`, sorted.
+    pub fn stalled(&self, since: Instant) -> Vec<&'static str> {
+        let mut stalled: Vec<_> = self
+            .working
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, started)| **started <= since)
+            .map(|(name, _)| *name)
+            .collect();
+        stalled.sort_unstable();
+
+        stalled
+    }
+}
+
+/// Call `notify` at half `interval` while no loop of `liveness` is stalled, never returns.
+pub(crate) async fn heartbeat<F>(
+    clock: &dyn Clock,
+    interval: Duration,
+    liveness: &Liveness,
+    notify: F,
+) where
+    F: Fn(),
+{
+    let period = interval / 2;
+    let mut last_heartbeat = clock.now_monotonic();
+    loop {
+        clock.sleep(period).await;
+
+        let stalled = liveness.stalled(last_heartbeat);
+        if stalled.is_empty() {
+            debug!("Watchdog heartbeat");
+            notify();
+            last_heartbeat = clock.now_monotonic();
+        } else {
+            warn!(
+                "Watchdog heartbeat withheld, stalled since {:?}: {}",
+                clock.now_monotonic().duration_since(last_heartbeat),
+                stalled.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::clock::Clock;
+    use crate::test_utils::{settle, ManualClock};
+    use crate::watchdog::{heartbeat, Liveness, MAIN_LOOP, TELEMETRY_LOOP};
+
+    const INTERVAL: Duration = Duration::from_secs(20);
+
+    /// Heartbeat of `liveness` counting its notifications.
+    fn start(clock: Arc<ManualClock>, liveness: Arc<Liveness>) -> Arc<AtomicU32> {
+        let beats = Arc::new(AtomicU32::new(0));
+        let counted = beats.clone();
+        tokio::spawn(async move {
+            heartbeat(clock.as_ref(), INTERVAL, &liveness, || {
+                counted.fetch_add(1, Ordering::SeqCst);
+            })
+            .await
+        });
+
+        beats
+    }
+
+    async fn advance(clock: &ManualClock, duration: Duration) {
+        settle().await;
+        clock.advance(duration);
+        settle().await;
+    }
+
+    #[tokio::test]
+    async fn heartbeat_at_half_interval() {
+        let clock = Arc::new(ManualClock::new());
+        let liveness = Arc::new(Liveness::new(clock.clone()));
+        let beats = start(clock.clone(), liveness.clone());
+
+        advance(&clock, Duration::from_secs(9)).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 0);
+        advance(&clock, Duration::from_secs(1)).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 1);
+
+        // steps completed between two heartbeats are progress
+        liveness.working(MAIN_LOOP);
+        advance(&clock, Duration::from_secs(5)).await;
+        liveness.waiting(MAIN_LOOP);
+        liveness.working(TELEMETRY_LOOP);
+        advance(&clock, Duration::from_secs(5)).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn loop_stalled_heartbeat_withheld() {
+        let clock = Arc::new(ManualClock::new());
+        let liveness = Arc::new(Liveness::new(clock.clone()));
+        let beats = start(clock.clone(), liveness.clone());
+
+        advance(&clock, Duration::from_secs(10)).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 1);
+
+        advance(&clock, Duration::from_secs(1)).await;
+        liveness.working(TELEMETRY_LOOP);
+        advance(&clock, Duration::from_secs(9)).await;
+        // the step started after the last heartbeat
+        assert_eq!(beats.load(Ordering::SeqCst), 2);
+        for _ in 0..3 {
+            advance(&clock, Duration::from_secs(10)).await;
+        }
+        assert_eq!(beats.load(Ordering::SeqCst), 2);
+        assert_eq!(liveness.stalled(clock.now_monotonic()), [TELEMETRY_LOOP]);
+
+        // the heartbeat resumes once the loop moves on
+        liveness.waiting(TELEMETRY_LOOP);
+        advance(&clock, Duration::from_secs(10)).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 3);
+    }
+}
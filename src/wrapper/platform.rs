@@ -0,0 +1,574 @@
+/*
+ * This file is part of Edgehog.
+ *
+ * Copyright 2022 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Backends of the init system and of the power management.
+//!
+//! The platform is detected at startup, unless configured: on systemd the logs are written for
+//! journald and the reboot goes through logind. Elsewhere (OpenRC, minimal images) the logs are
+//! timestamped and the reboot is requested directly to the kernel, which needs `CAP_SYS_BOOT`.
+//!
+//! The service status is notified with sd_notify when the service manager listens for it, on
+//! `NOTIFY_SOCKET`, and the runtime is built with the `systemd` feature; otherwise it is logged.
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use async_trait::async_trait;
+use log::{info, warn};
+#[cfg(test)]
+use mockall::automock;
+use nix::errno::Errno;
+use nix::sys::reboot::RebootMode;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+#[cfg(feature = "dbus")]
+use zbus::dbus_proxy;
+
+use crate::error::DeviceManagerError;
+use crate::logging::{self, LogSink};
+use crate::wrapper::systemd;
+
+/// Bit of `CAP_SYS_BOOT` in the capability sets.
+const CAP_SYS_BOOT: u32 = 22;
+const SYSTEM_BUS_SOCKET: &str = "run/dbus/system_bus_socket";
+
+static PLATFORM: OnceCell<Platform> = OnceCell::new();
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+pub(crate) trait Login1Manager {
+    /// Whether the caller may reboot: "yes", "no", "challenge" or "na".
+    fn can_reboot(&self) -> zbus::Result<String>;
+    fn reboot(&self, interactive: bool) -> zbus::Result<()>;
+    fn power_off(&self, interactive: bool) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlatformKind {
+    Systemd,
+    OpenRc,
+    /// No known init system, like in containers.
+    Minimal,
+}
+
+impl PlatformKind {
+    /// Detect the init system of the filesystem mounted at `root`, the way `sd_booted` does.
+    pub fn detect(root: &Path) -> Self {
+        if root.join("run/systemd/system").is_dir() {
+            PlatformKind::Systemd
+        } else if root.join("run/openrc").is_dir() {
+            PlatformKind::OpenRc
+        } else {
+            PlatformKind::Minimal
+        }
+    }
+
+    pub fn log_sink(&self) -> LogSink {
+        match self {
+            PlatformKind::Systemd => LogSink::Journal,
+            PlatformKind::OpenRc | PlatformKind::Minimal => LogSink::Stderr,
+        }
+    }
+}
+
+/// Whether a system bus is reachable, from its address or the default socket under `root`, only
+/// with the `dbus` feature.
+pub fn detect_system_bus(root: &Path, address: Option<&str>) -> bool {
+    cfg!(feature = "dbus")
+        && (address.map_or(false, |address| !address.is_empty())
+            || root.join(SYSTEM_BUS_SOCKET).exists())
+}
+
+/// Service status notifications to the init system.
+#[cfg_attr(test, automock)]
+pub trait ServiceNotifier: Send + Sync {
+    fn status(&self, status: &str);
+    fn ready(&self, status: &str);
+    fn stopping(&self, status: &str);
+    fn errno(&self, errno: i32, status: &str);
+    /// Heartbeat of the service watchdog.
+    fn watchdog(&self);
+}
+
+/// sd_notify, a no-op unless built with the `systemd` feature.
+pub struct SystemdNotifier;
+
+impl ServiceNotifier for SystemdNotifier {
+    fn status(&self, status: &str) {
+        systemd::systemd_notify_status(status);
+    }
+
+    fn ready(&self, status: &str) {
+        systemd::systemd_notify_ready_status(status);
+    }
+
+    fn stopping(&self, status: &str) {
+        systemd::systemd_notify_stopping_status(status);
+    }
+
+    fn errno(&self, errno: i32, status: &str) {
+        systemd::systemd_notify_errno_status(errno, status);
+    }
+
+    fn watchdog(&self) {
+        systemd::systemd_notify_watchdog();
+    }
+}
+
+/// Status logged, for when nobody listens for the notifications, like with OpenRC and in
+/// containers, or when built without the `systemd` feature.
+pub struct NoopNotifier;
+
+impl ServiceNotifier for NoopNotifier {
+    fn status(&self, status: &str) {
+        info!("Service status: {status}");
+    }
+
+    fn ready(&self, status: &str) {
+        info!("Service ready: {status}");
+    }
+
+    fn stopping(&self, status: &str) {
+        info!("Service stopping: {status}");
+    }
+
+    fn errno(&self, errno: i32, status: &str) {
+        info!("Service failed with errno {errno}: {status}");
+    }
+
+    fn watchdog(&self) {}
+}
+
+/// Whether sd_notify reaches a service manager listening on `notify_socket`, only with the
+/// `systemd` feature.
+fn notifies_systemd(notify_socket: Option<&OsStr>) -> bool {
+    cfg!(feature = "systemd") && notify_socket.map_or(false, |socket| !socket.is_empty())
+}
+
+/// sd_notify when it reaches a service manager, the logging notifier otherwise.
+pub fn select_notifier(notify_socket: Option<&OsStr>) -> Box<dyn ServiceNotifier> {
+    if notifies_systemd(notify_socket) {
+        Box::new(SystemdNotifier)
+    } else {
+        Box::new(NoopNotifier)
+    }
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait PowerBackend: Send + Sync {
+    /// Check, without side effects, whether the reboot is allowed.
+    async fn can_reboot(&self) -> Result<(), String>;
+    async fn reboot(&self) -> Result<(), DeviceManagerError>;
+    async fn power_off(&self) -> Result<(), DeviceManagerError>;
+}
+
+/// Reboot and power off requested to logind over D-Bus.
+#[cfg(feature = "dbus")]
+pub struct LogindPower;
+
+#[cfg(feature = "dbus")]
+impl LogindPower {
+    async fn manager(&self) -> Result<Login1ManagerProxy<'static>, DeviceManagerError> {
+        let connection = system_bus().await?;
+
+        Ok(Login1ManagerProxy::new(&connection).await?)
+    }
+}
+
+#[cfg(feature = "dbus")]
+#[async_trait]
+impl PowerBackend for LogindPower {
+    async fn can_reboot(&self) -> Result<(), String> {
+        let manager = self.manager().await.map_err(|err| err.to_string())?;
+
+        match manager
+            .can_reboot()
+            .await
+            .map_err(|err| err.to_string())?
+            .as_str()
+        {
+            "yes" => Ok(()),
+            "challenge" => Err("logind requires an interactive authorization".to_owned()),
+            answer => Err(format!("logind CanReboot answered {answer}")),
+        }
+    }
+
+    async fn reboot(&self) -> Result<(), DeviceManagerError> {
+        self.manager().await?.reboot(false).await?;
+        info!("Reboot requested to logind");
+
+        Ok(())
+    }
+
+    async fn power_off(&self) -> Result<(), DeviceManagerError> {
+        let requested = match self.manager().await {
+            Ok(manager) => manager.power_off(false).await.map_err(Into::into),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = requested {
+            warn!("logind power off failed, falling back to systemctl: {err}");
+
+            return systemctl_power_off().await;
+        }
+        info!("Power off requested to logind");
+
+        Ok(())
+    }
+}
+
+/// `systemctl poweroff`, for when logind is not reachable.
+#[cfg(feature = "dbus")]
+async fn systemctl_power_off() -> Result<(), DeviceManagerError> {
+    let status = tokio::process::Command::new("systemctl")
+        .arg("poweroff")
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(DeviceManagerError::CommandFailed(format!(
+            "systemctl poweroff failed with {status}"
+        )));
+    }
+    info!("Power off requested to systemctl");
+
+    Ok(())
+}
+
+/// System calls of the direct power backend.
+#[cfg_attr(test, automock)]
+pub trait Syscalls: Send + Sync {
+    /// Effective capability set of the process.
+    fn effective_capabilities(&self) -> Result<u64, String>;
+    fn sync(&self);
+    /// `reboot(2)`, only returning on failure.
+    fn reboot(&self, mode: RebootMode) -> Result<(), Errno>;
+}
+
+pub struct LinuxSyscalls;
+
+impl Syscalls for LinuxSyscalls {
+    fn effective_capabilities(&self) -> Result<u64, String> {
+        let status = std::fs::read_to_string("/proc/self/status")
+            .map_err(|err| format!("/proc/self/status is not readable: {err}"))?;
+
+        parse_effective_capabilities(&status)
+            .ok_or_else(|| "no CapEff in /proc/self/status".to_owned())
+    }
+
+    fn sync(&self) {
+        nix::unistd::sync();
+    }
+
+    fn reboot(&self, mode: RebootMode) -> Result<(), Errno> {
+        nix::sys::reboot::reboot(mode).map(|never| match never {})
+    }
+}
+
+fn parse_effective_capabilities(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+}
+
+/// Reboot and power off through `reboot(2)`, after flushing the filesystems.
+pub struct DirectPower<S> {
+    syscalls: S,
+}
+
+impl<S: Syscalls> DirectPower<S> {
+    pub fn new(syscalls: S) -> Self {
+        DirectPower { syscalls }
+    }
+
+    fn restart(&self, mode: RebootMode) -> Result<(), DeviceManagerError> {
+        self.syscalls.sync();
+
+        match self.syscalls.reboot(mode) {
+            Ok(()) => Ok(()),
+            Err(Errno::EPERM) => Err(DeviceManagerError::CapabilityDenied(
+                "reboot(2) needs CAP_SYS_BOOT".to_owned(),
+            )),
+            Err(errno) => Err(std::io::Error::from_raw_os_error(errno as i32).into()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Syscalls> PowerBackend for DirectPower<S> {
+    async fn can_reboot(&self) -> Result<(), String> {
+        let capabilities = self.syscalls.effective_capabilities()?;
+        if capabilities & (1 << CAP_SYS_BOOT) == 0 {
+            return Err("CAP_SYS_BOOT is not in the effective capabilities".to_owned());
+        }
+
+        Ok(())
+    }
+
+    async fn reboot(&self) -> Result<(), DeviceManagerError> {
+        self.restart(RebootMode::RB_AUTOBOOT)
+    }
+
+    async fn power_off(&self) -> Result<(), DeviceManagerError> {
+        self.restart(RebootMode::RB_POWER_OFF)
+    }
+}
+
+pub struct Platform {
+    kind: PlatformKind,
+    system_bus: bool,
+    notifier: Box<dyn ServiceNotifier>,
+    power: Box<dyn PowerBackend>,
+}
+
+impl Platform {
+    /// Select the backends for `kind`, logind is only used on systemd with a system bus. The
+    /// status is logged unless another notifier is set.
+    pub fn new(kind: PlatformKind, system_bus: bool) -> Self {
+        let power: Box<dyn PowerBackend> = match kind {
+            #[cfg(feature = "dbus")]
+            PlatformKind::Systemd if system_bus => Box::new(LogindPower),
+            _ => Box::new(DirectPower::new(LinuxSyscalls)),
+        };
+
+        Platform {
+            kind,
+            system_bus,
+            notifier: Box::new(NoopNotifier),
+            power,
+        }
+    }
+
+    /// Notify the service status through `notifier`.
+    pub fn with_notifier(mut self, notifier: Box<dyn ServiceNotifier>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    fn detected(configured: Option<PlatformKind>) -> Self {
+        let root = Path::new("/");
+        let kind = configured.unwrap_or_else(|| PlatformKind::detect(root));
+        let address = std::env::var("DBUS_SYSTEM_BUS_ADDRESS").ok();
+
+        let notify_socket = std::env::var_os("NOTIFY_SOCKET");
+
+        Platform::new(kind, detect_system_bus(root, address.as_deref()))
+            .with_notifier(select_notifier(notify_socket.as_deref()))
+    }
+
+    pub fn kind(&self) -> PlatformKind {
+        self.kind
+    }
+
+    /// Whether the D-Bus services and clients can be used.
+    pub fn has_system_bus(&self) -> bool {
+        self.system_bus
+    }
+
+    pub fn notifier(&self) -> &dyn ServiceNotifier {
+        self.notifier.as_ref()
+    }
+
+    pub fn power(&self) -> &dyn PowerBackend {
+        self.power.as_ref()
+    }
+}
+
+/// Configure the platform of the whole runtime, detecting it unless `configured`.
+///
+/// Only the first call has effect.
+pub fn init(configured: Option<PlatformKind>) {
+    let platform = PLATFORM.get_or_init(|| Platform::detected(configured));
+    if configured.map_or(false, |configured| configured != platform.kind) {
+        warn!("Platform already detected as {:?}", platform.kind);
+    }
+
+    logging::set_sink(platform.kind.log_sink());
+    info!(
+        "Platform {:?}, system bus {}",
+        platform.kind,
+        if platform.system_bus {
+            "available"
+        } else {
+            "not available"
+        }
+    );
+}
+
+/// The configured platform, detected when not configured.
+pub fn platform() -> &'static Platform {
+    PLATFORM.get_or_init(|| Platform::detected(None))
+}
+
+/// Error of the system bus clients when built without the `dbus` feature.
+#[cfg(not(feature = "dbus"))]
+pub fn no_system_bus() -> DeviceManagerError {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "built without D-Bus support",
+    )
+    .into()
+}
+
+/// Connect to the system bus, unless the platform has none.
+#[cfg(feature = "dbus")]
+pub async fn system_bus() -> zbus::Result<zbus::Connection> {
+    if !platform().has_system_bus() {
+        return Err(zbus::Error::Unsupported);
+    }
+
+    zbus::Connection::system().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use mockall::Sequence;
+    use nix::errno::Errno;
+    use nix::sys::reboot::RebootMode;
+
+    use crate::error::DeviceManagerError;
+    use crate::logging::LogSink;
+    use crate::wrapper::platform::{
+        detect_system_bus, notifies_systemd, parse_effective_capabilities, DirectPower,
+        MockServiceNotifier, MockSyscalls, Platform, PlatformKind, PowerBackend,
+    };
+
+    #[test]
+    fn init_system_detected() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(PlatformKind::detect(root.path()), PlatformKind::Minimal);
+
+        std::fs::create_dir_all(root.path().join("run/openrc")).unwrap();
+        assert_eq!(PlatformKind::detect(root.path()), PlatformKind::OpenRc);
+
+        // systemd wins, some images ship the OpenRC directories too
+        std::fs::create_dir_all(root.path().join("run/systemd/system")).unwrap();
+        assert_eq!(PlatformKind::detect(root.path()), PlatformKind::Systemd);
+        assert_eq!(PlatformKind::Systemd.log_sink(), LogSink::Journal);
+        assert_eq!(PlatformKind::OpenRc.log_sink(), LogSink::Stderr);
+    }
+
+    #[test]
+    fn configured_platform_parsed() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            platform: PlatformKind,
+        }
+
+        let config: Config = toml::from_str(r#"platform = "openrc""#).unwrap();
+        assert_eq!(config.platform, PlatformKind::OpenRc);
+        assert!(toml::from_str::<Config>(r#"platform = "sysvinit""#).is_err());
+    }
+
+    #[test]
+    fn system_bus_detected() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(!detect_system_bus(root.path(), None));
+        assert!(!detect_system_bus(root.path(), Some("")));
+        assert_eq!(
+            detect_system_bus(
+                root.path(),
+                Some("unix:path=/var/run/dbus/system_bus_socket")
+            ),
+            cfg!(feature = "dbus")
+        );
+
+        std::fs::create_dir_all(root.path().join("run/dbus")).unwrap();
+        std::fs::write(root.path().join("run/dbus/system_bus_socket"), "").unwrap();
+        assert_eq!(detect_system_bus(root.path(), None), cfg!(feature = "dbus"));
+    }
+
+    #[test]
+    fn notifier_selected_from_notify_socket() {
+        assert!(!notifies_systemd(None));
+        assert!(!notifies_systemd(Some(OsStr::new(""))));
+        assert_eq!(
+            notifies_systemd(Some(OsStr::new("/run/systemd/notify"))),
+            cfg!(feature = "systemd")
+        );
+    }
+
+    #[test]
+    fn notifier_injected() {
+        let mut notifier = MockServiceNotifier::new();
+        notifier
+            .expect_status()
+            .withf(|status| status == "Running")
+            .times(1)
+            .return_const(());
+
+        let platform =
+            Platform::new(PlatformKind::Minimal, false).with_notifier(Box::new(notifier));
+        platform.notifier().status("Running");
+    }
+
+    #[test]
+    fn effective_capabilities_parsed() {
+        let status = "Name:\tedgehog\nCapInh:\t0000000000000000\nCapEff:\t0000000000400000\n";
+        assert_eq!(parse_effective_capabilities(status), Some(1 << 22));
+        assert_eq!(parse_effective_capabilities("Name:\tedgehog\n"), None);
+    }
+
+    #[tokio::test]
+    async fn direct_reboot_syncs_first() {
+        let mut sequence = Sequence::new();
+        let mut syscalls = MockSyscalls::new();
+        syscalls
+            .expect_sync()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .return_const(());
+        syscalls
+            .expect_reboot()
+            .withf(|mode| *mode == RebootMode::RB_AUTOBOOT)
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Ok(()));
+
+        DirectPower::new(syscalls).reboot().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn direct_power_off_denied_without_capability() {
+        let mut syscalls = MockSyscalls::new();
+        syscalls.expect_sync().return_const(());
+        syscalls
+            .expect_reboot()
+            .withf(|mode| *mode == RebootMode::RB_POWER_OFF)
+            .returning(|_| Err(Errno::EPERM));
+        syscalls
+            .expect_effective_capabilities()
+            .returning(|| Ok(0x3000));
+        let power = DirectPower::new(syscalls);
+
+        assert!(matches!(
+            power.power_off().await,
+            Err(DeviceManagerError::CapabilityDenied(_))
+        ));
+        assert!(power.can_reboot().await.is_err());
+    }
+}
@@ -18,10 +18,35 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+use std::time::Duration;
+
 #[cfg(feature = "systemd")]
 use systemd::daemon;
 #[cfg(feature = "systemd")]
-use systemd::daemon::{STATE_ERRNO, STATE_READY, STATE_STATUS};
+use systemd::daemon::{STATE_ERRNO, STATE_READY, STATE_STATUS, STATE_STOPPING, STATE_WATCHDOG};
+
+/// Interval of the watchdog enabled by `WatchdogSec=`, `None` when disabled or meant for another
+/// process.
+pub fn systemd_watchdog_interval() -> Option<Duration> {
+    watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // like sd_watchdog_enabled, without a pid the variable is for this process
+    if pid.map_or(false, |pid| pid.parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+
+    usec?
+        .parse::<u64>()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
 
 #[allow(unused)]
 pub fn systemd_notify_status(service_status: &str) {
@@ -41,6 +66,15 @@ pub fn systemd_notify_ready_status(service_status: &str) {
     }
 }
 
+#[allow(unused)]
+pub fn systemd_notify_stopping_status(service_status: &str) {
+    #[cfg(feature = "systemd")]
+    {
+        let systemd_state_pairs = vec![(STATE_STOPPING, "1"), (STATE_STATUS, service_status)];
+        daemon::notify(false, systemd_state_pairs.iter());
+    }
+}
+
 #[allow(unused)]
 pub fn systemd_notify_errno_status(err_no: i32, service_status: &str) {
     #[cfg(feature = "systemd")]
@@ -52,3 +86,35 @@ pub fn systemd_notify_errno_status(err_no: i32, service_status: &str) {
         daemon::notify(false, systemd_state_pairs.iter());
     }
 }
+
+#[allow(unused)]
+pub fn systemd_notify_watchdog() {
+    #[cfg(feature = "systemd")]
+    {
+        let systemd_state_pairs = vec![(STATE_WATCHDOG, "1")];
+        daemon::notify(false, systemd_state_pairs.iter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::wrapper::systemd::watchdog_interval;
+
+    #[test]
+    fn watchdog_interval_read() {
+        assert_eq!(
+            watchdog_interval(Some("20000000"), None, 42),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            watchdog_interval(Some("20000000"), Some("42"), 42),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(watchdog_interval(Some("20000000"), Some("7"), 42), None);
+        assert_eq!(watchdog_interval(None, Some("42"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(Some("twenty"), None, 42), None);
+    }
+}